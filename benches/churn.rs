@@ -0,0 +1,107 @@
+//! Measures connection churn, counting the allocations made per connection
+//! with and without the session read buffers being pooled.
+//!
+//! cargo bench --bench churn
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use kave::client;
+use kave::proto::BufferPool;
+use kave::server::{load_certs, load_keys, ClientServer};
+use kave::store::MemoryStore;
+use tokio::io::{split, AsyncReadExt, AsyncWriteExt};
+
+const CONNECTIONS: usize = 200;
+
+/// Counts allocations, including reallocations, made by the whole process
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Open `CONNECTIONS` connections one after another, each sending a single ECHO,
+/// returning the time taken and the number of allocations made while doing so
+async fn run_connections(port: u16) -> (Duration, usize) {
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading certs");
+    let mut buf = [0; 7];
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..CONNECTIONS {
+        let stream = client::connect("localhost", port, certs.clone())
+            .await
+            .expect("error connecting");
+        let (mut reader, mut writer) = split(stream);
+        writer
+            .write_all(b"ECHO:4:done\n")
+            .await
+            .expect("error writing");
+        reader.read_exact(&mut buf).await.expect("error reading");
+        assert_eq!(&buf, b"4:done\n");
+        writer.shutdown().await.expect("error shutting down");
+    }
+    (
+        start.elapsed(),
+        ALLOCATIONS.load(Ordering::Relaxed) - allocations,
+    )
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("error building runtime");
+    runtime.block_on(async {
+        let certs = load_certs("certs/defaults/cert.pem").expect("error loading certs");
+        let keys = load_keys("certs/defaults/key.pem").expect("error loading keys");
+
+        // a pool of size 0 never keeps a buffer, so every session allocates its own
+        for (port, pooled, pool) in [
+            (7913, "without", BufferPool::new(0)),
+            (7914, "with", BufferPool::default()),
+        ] {
+            let (svr_shutdown_send, mut svr_shutdown_recv) =
+                tokio::sync::mpsc::unbounded_channel();
+            let (sig_shutdown_send, sig_shutdown_recv) = tokio::sync::mpsc::unbounded_channel();
+            let mut cs = ClientServer::new(
+                svr_shutdown_send,
+                sig_shutdown_recv,
+                certs.clone(),
+                keys.clone(),
+                MemoryStore::new(),
+            );
+            cs.set_addr(format!("127.0.0.1:{port}"))
+                .set_buffer_pool(pool);
+            tokio::spawn(cs.start());
+            tokio::time::sleep(Duration::from_millis(200)).await;
+
+            let (elapsed, allocations) = run_connections(port).await;
+            println!(
+                "{pooled} pooled buffers: {CONNECTIONS} connections in {elapsed:?} ({:.1} allocations/connection)",
+                allocations as f64 / CONNECTIONS as f64,
+            );
+
+            sig_shutdown_send.send(true).ok();
+            svr_shutdown_recv.recv().await;
+        }
+    });
+}
@@ -0,0 +1,115 @@
+//! Measures serving large pipelined batches, counting the allocations made
+//! per op, to catch the read buffer being reallocated on every read.
+//!
+//! cargo bench --bench pipeline
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use kave::client;
+use kave::server::{load_certs, load_keys, ClientServer};
+use kave::store::MemoryStore;
+use tokio::io::{split, AsyncReadExt, AsyncWriteExt};
+
+const BATCHES: usize = 50;
+const OPS_PER_BATCH: usize = 500;
+
+/// Counts allocations, including reallocations, made by the whole process
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// A batch of quiet SETs of `value_len` byte values, followed by an ECHO to wait on
+fn batch(value_len: usize) -> Vec<u8> {
+    let value = vec![b'v'; value_len];
+    let mut batch = vec![];
+    for i in 0..OPS_PER_BATCH {
+        let key = format!("key-{i:04}");
+        batch.extend_from_slice(format!("SETQ:{}:{key}:{value_len}:", key.len()).as_bytes());
+        batch.extend_from_slice(&value);
+        batch.push(b'\n');
+    }
+    batch.extend_from_slice(b"ECHO:4:done\n");
+    batch
+}
+
+/// Send `BATCHES` batches of `value_len` byte values, returning the time taken
+/// and the number of allocations made while doing so
+async fn run_batches(port: u16, value_len: usize) -> (Duration, usize) {
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading certs");
+    let stream = client::connect("localhost", port, certs)
+        .await
+        .expect("error connecting");
+    let (mut reader, mut writer) = split(stream);
+    let batch = batch(value_len);
+    let mut buf = [0; 7];
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..BATCHES {
+        writer.write_all(&batch).await.expect("error writing");
+        reader.read_exact(&mut buf).await.expect("error reading");
+        assert_eq!(&buf, b"4:done\n");
+    }
+    (
+        start.elapsed(),
+        ALLOCATIONS.load(Ordering::Relaxed) - allocations,
+    )
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("error building runtime");
+    runtime.block_on(async {
+        let port = 7912;
+        let certs = load_certs("certs/defaults/cert.pem").expect("error loading certs");
+        let keys = load_keys("certs/defaults/key.pem").expect("error loading keys");
+        let (svr_shutdown_send, mut svr_shutdown_recv) = tokio::sync::mpsc::unbounded_channel();
+        let (sig_shutdown_send, sig_shutdown_recv) = tokio::sync::mpsc::unbounded_channel();
+        let mut cs = ClientServer::new(
+            svr_shutdown_send,
+            sig_shutdown_recv,
+            certs,
+            keys,
+            MemoryStore::new(),
+        );
+        cs.set_addr(format!("127.0.0.1:{port}"));
+        tokio::spawn(cs.start());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // values smaller than, around and much larger than the server's read buffer
+        for value_len in [16, 200, 1000, 4000] {
+            let (elapsed, allocations) = run_batches(port, value_len).await;
+            let ops = (BATCHES * OPS_PER_BATCH) as f64;
+            println!(
+                "{value_len} byte values: {ops} ops in {elapsed:?} ({:.0} ops/s, {:.1} allocations/op)",
+                ops / elapsed.as_secs_f64(),
+                allocations as f64 / ops,
+            );
+        }
+
+        sig_shutdown_send.send(true).ok();
+        svr_shutdown_recv.recv().await;
+    });
+}
@@ -0,0 +1,98 @@
+//! Compares client-server throughput when clients are accepted on the server's
+//! multi-threaded runtime alone versus on several single threaded runtimes sharing
+//! the port via SO_REUSEPORT, as the server runs with REUSE_PORT and CLIENT_RUNTIMES.
+//!
+//! cargo bench --bench throughput
+use std::time::{Duration, Instant};
+
+use kave::client;
+use kave::server::{load_certs, load_keys, Server};
+use kave::store::MemoryStore;
+use tokio::io::{split, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+const CONNECTIONS: usize = 64;
+const OPS_PER_CONNECTION: usize = 200;
+
+/// Start a server with its client-server on `port`, on its own multi-threaded runtime
+/// as `main` runs it, accepting clients on `client_runtimes` runtimes
+fn start_server(
+    cluster_port: u16,
+    port: u16,
+    client_runtimes: usize,
+) -> (UnboundedSender<bool>, UnboundedReceiver<bool>) {
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading certs");
+    let keys = load_keys("certs/defaults/key.pem").expect("error loading keys");
+    let (svr_shutdown_send, svr_shutdown_recv) = tokio::sync::mpsc::unbounded_channel();
+    let (sig_shutdown_send, sig_shutdown_recv) = tokio::sync::mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .expect("error building runtime");
+        runtime.block_on(async move {
+            let store = MemoryStore::new();
+            let mut svr = Server::new(svr_shutdown_send, sig_shutdown_recv, certs, keys, store);
+            svr.set_addr(format!("127.0.0.1:{cluster_port}"))
+                .set_client_server_addr(format!("127.0.0.1:{port}"))
+                .set_client_runtimes(client_runtimes);
+            svr.start().await
+        });
+    });
+    (sig_shutdown_send, svr_shutdown_recv)
+}
+
+/// Drive `CONNECTIONS` concurrent clients each issuing `OPS_PER_CONNECTION` echos
+async fn run_load(port: u16) -> Duration {
+    let start = Instant::now();
+    let tasks = (0..CONNECTIONS).map(|_| {
+        tokio::spawn(async move {
+            let certs = load_certs("certs/defaults/cert.pem").expect("error loading certs");
+            let stream = client::connect("localhost", port, certs)
+                .await
+                .expect("error connecting");
+            let (mut reader, mut writer) = split(stream);
+            let mut buf = [0; 8];
+            for _ in 0..OPS_PER_CONNECTION {
+                writer
+                    .write_all(b"ECHO:5:hello\n")
+                    .await
+                    .expect("error writing");
+                reader.read_exact(&mut buf).await.expect("error reading");
+                assert_eq!(&buf, b"5:hello\n");
+            }
+        })
+    });
+    for task in futures::future::join_all(tasks).await {
+        task.expect("error running load task");
+    }
+    start.elapsed()
+}
+
+async fn bench(name: &str, cluster_port: u16, port: u16, client_runtimes: usize) {
+    let (shutdown_send, mut shutdown_recv) = start_server(cluster_port, port, client_runtimes);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let elapsed = run_load(port).await;
+    let ops = (CONNECTIONS * OPS_PER_CONNECTION) as f64;
+    println!(
+        "{name}: {client_runtimes} client runtime(s), {ops} ops in {elapsed:?} ({:.0} ops/s)",
+        ops / elapsed.as_secs_f64()
+    );
+    shutdown_send.send(true).ok();
+    shutdown_recv.recv().await;
+}
+
+fn main() {
+    let cores = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(4)
+        .max(2);
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("error building load runtime");
+    runtime.block_on(async {
+        bench("single-runtime", 7912, 7910, 1).await;
+        bench("multi-runtime", 7913, 7911, cores).await;
+    });
+}
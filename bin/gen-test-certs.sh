@@ -0,0 +1,28 @@
+#!/usr/bin/env bash
+
+# Generates the broken certs the tests use to check that misconfigured TLS
+# is caught at startup, and a second valid cert the tests reload certs with.
+# Requires openssl >= 3.4 for `-not_before`/`-not_after`.
+
+set -ex
+
+mkdir -p certs/test
+# a key that doesn't belong to certs/defaults/cert.pem
+openssl genrsa -traditional -out certs/test/other-key.pem 2048
+# a cert that expired long ago, for that same key
+openssl req -new -x509 -key certs/test/other-key.pem -out certs/test/expired-cert.pem \
+    -not_before 20200101000000Z -not_after 20210101000000Z \
+    -subj "/C=US" -extensions v3_ca -config certs/openssl.cnf
+# a valid cert for that same key, to reload the server's certs with
+openssl req -new -x509 -key certs/test/other-key.pem -out certs/test/other-cert.pem -days 3650 \
+    -subj "/C=US" -extensions v3_ca -config certs/openssl.cnf
+# a CA, and a client cert it issued, for mutual TLS
+openssl genrsa -traditional -out certs/test/ca-key.pem 2048
+openssl req -new -x509 -key certs/test/ca-key.pem -out certs/test/ca-cert.pem -days 3650 \
+    -subj "/C=US/CN=kave test ca" -extensions v3_ca -config certs/openssl.cnf
+openssl genrsa -traditional -out certs/test/client-key.pem 2048
+openssl req -new -key certs/test/client-key.pem -subj "/C=US/CN=kave test client" \
+    -config certs/openssl.cnf \
+    | openssl x509 -req -CA certs/test/ca-cert.pem -CAkey certs/test/ca-key.pem -set_serial 1 \
+        -days 3650 -out certs/test/client-cert.pem \
+        -extfile <(printf "basicConstraints=CA:FALSE\nkeyUsage=digitalSignature\nextendedKeyUsage=clientAuth\n")
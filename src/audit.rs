@@ -0,0 +1,134 @@
+/*!
+Audit records of mutating client commands
+
+Audit records are written to their own sink rather than through `tracing`
+so they aren't subject to log-level filtering.
+*/
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::{self, UnboundedSender};
+
+use crate::{utils, Result};
+
+/// A single mutating command performed by a client
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    // milliseconds since the unix epoch when the command completed
+    pub timestamp_ms: u64,
+    pub session: String,
+    pub peer: SocketAddr,
+    // identity the client authenticated as, if any
+    pub identity: Option<String>,
+    pub op: String,
+    pub key: String,
+    pub result: String,
+}
+impl AuditRecord {
+    pub fn new(
+        session: &str,
+        peer: SocketAddr,
+        identity: Option<String>,
+        op: &str,
+        key: &str,
+        result: &str,
+    ) -> Self {
+        Self {
+            timestamp_ms: utils::time_since_epoch().as_millis() as u64,
+            session: session.to_string(),
+            peer,
+            identity,
+            op: op.to_string(),
+            key: key.to_string(),
+            result: result.to_string(),
+        }
+    }
+}
+
+/// Destination for audit records. Implementations must not block
+/// since records are emitted from the session loop.
+pub trait AuditSink: Send + Sync + Debug {
+    fn record(&self, record: AuditRecord);
+}
+
+impl AuditSink for UnboundedSender<AuditRecord> {
+    fn record(&self, record: AuditRecord) {
+        if let Err(e) = self.send(record) {
+            tracing::error!("audit sink closed, dropping record: {:?}", e.0);
+        }
+    }
+}
+
+/// Appends audit records as json lines to a file
+#[derive(Debug)]
+pub struct FileAuditSink {
+    sender: UnboundedSender<AuditRecord>,
+}
+impl FileAuditSink {
+    /// Open `path` for appending and spawn a task that writes records to it
+    pub async fn spawn<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        let (sender, mut receiver) = mpsc::unbounded_channel::<AuditRecord>();
+        tokio::spawn(async move {
+            while let Some(record) = receiver.recv().await {
+                let mut line = match serde_json::to_vec(&record) {
+                    Ok(line) => line,
+                    Err(e) => {
+                        tracing::error!("error serializing audit record: {e}");
+                        continue;
+                    }
+                };
+                line.push(b'\n');
+                if let Err(e) = file.write_all(&line).await {
+                    tracing::error!(path = ?path, "error writing audit record: {e}");
+                }
+            }
+        });
+        Ok(Self { sender })
+    }
+}
+
+impl AuditSink for FileAuditSink {
+    fn record(&self, record: AuditRecord) {
+        self.sender.record(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, time::Duration};
+
+    use uuid::Uuid;
+
+    use super::{AuditRecord, AuditSink, FileAuditSink};
+    use crate::Result;
+
+    #[tokio::test]
+    async fn test_file_audit_sink() -> Result<()> {
+        let path = env::temp_dir().join(format!("audit_{}.log", Uuid::new_v4()));
+        let sink = FileAuditSink::spawn(&path).await?;
+        let peer = "127.0.0.1:1234".parse().unwrap();
+        let first = AuditRecord::new("a", peer, None, "SET", "foo", "created");
+        let second = AuditRecord::new("a", peer, None, "DEL", "foo", "deleted");
+        sink.record(first.clone());
+        sink.record(second.clone());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let contents = tokio::fs::read_to_string(&path).await?;
+        let records = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("invalid audit record"))
+            .collect::<Vec<AuditRecord>>();
+        assert_eq!(vec![first, second], records);
+        Ok(())
+    }
+}
@@ -0,0 +1,41 @@
+//! Prints the entries of SSTable segments for debugging, oldest segment first.
+//!
+//! Usage: `kave-segments <data_dir | segment.sst>...`
+use std::path::{Path, PathBuf};
+
+use kave::store::lsm::{LSMStore, SegmentIter, Value};
+use kave::Result;
+
+async fn print_segment(path: &Path, mut segment: SegmentIter) -> Result<()> {
+    println!("{}", path.display());
+    while let Some((key, value)) = segment.next_entry().await? {
+        let key = String::from_utf8_lossy(&key);
+        match value {
+            Value::Data(data) => println!("  {key:?} => {:?}", String::from_utf8_lossy(&data)),
+            Value::Tombstone => println!("  {key:?} => <tombstone>"),
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let paths = std::env::args()
+        .skip(1)
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
+    if paths.is_empty() {
+        return Err("usage: kave-segments <data_dir | segment.sst>...".into());
+    }
+    for path in paths {
+        if path.is_dir() {
+            for (path, segment) in LSMStore::iter_segments(&path).await? {
+                print_segment(&path, segment).await?;
+            }
+        } else {
+            let segment = LSMStore::iter_segment(&path).await?;
+            print_segment(&path, segment).await?;
+        }
+    }
+    Ok(())
+}
@@ -1,13 +1,22 @@
+pub mod pool;
+pub mod ring;
+
+use std::collections::HashMap;
 use std::net::IpAddr;
+use std::time::Duration;
 
+use self::ring::Ring;
 use crate::error::{Error, Result};
+use crate::proto::OVERLOADED;
+use tokio::io::{split, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
 use tokio_rustls::{
     client::TlsStream,
     rustls::{
         self,
         client::{ServerCertVerified, ServerCertVerifier, ServerName},
-        Certificate,
+        Certificate, PrivateKey,
     },
     TlsConnector,
 };
@@ -63,6 +72,9 @@ pub async fn resolve_dns(host: &str) -> Result<Option<IpAddr>> {
     Ok(ip)
 }
 
+/// A cert chain and its private key, presented to servers that require clients to have a cert
+pub type ClientCert = (Vec<Certificate>, PrivateKey);
+
 /// Create a new tls stream to a given address using
 /// an exact-cert verifier instead of a typical root cert verifier.
 /// This can only connect to servers exposing a certificate listed in `certs`
@@ -70,15 +82,41 @@ pub async fn connect(
     addr: &str,
     port: u16,
     certs: Vec<Certificate>,
+) -> Result<TlsStream<TcpStream>> {
+    connect_as(addr, port, certs, None).await
+}
+
+/// Create a new tls stream like `connect`, presenting `client_certs` and `client_key`
+/// to servers that require clients to have a cert
+pub async fn connect_with_client_cert(
+    addr: &str,
+    port: u16,
+    certs: Vec<Certificate>,
+    client_certs: Vec<Certificate>,
+    client_key: PrivateKey,
+) -> Result<TlsStream<TcpStream>> {
+    connect_as(addr, port, certs, Some((client_certs, client_key))).await
+}
+
+async fn connect_as(
+    addr: &str,
+    port: u16,
+    certs: Vec<Certificate>,
+    client_cert: Option<ClientCert>,
 ) -> Result<TlsStream<TcpStream>> {
     let addr = match resolve_dns(addr).await? {
         None => return Err(Error::DnsResolutionFailure(addr.into())),
         Some(ip_addr) => ip_addr,
     };
-    let mut config = rustls::ClientConfig::builder()
+    let builder = rustls::ClientConfig::builder()
         .with_safe_defaults()
-        .with_root_certificates(rustls::RootCertStore::empty())
-        .with_no_client_auth();
+        .with_root_certificates(rustls::RootCertStore::empty());
+    let mut config = match client_cert {
+        Some((client_certs, client_key)) => builder
+            .with_single_cert(client_certs, client_key)
+            .map_err(|e| format!("invalid client cert: {e}"))?,
+        None => builder.with_no_client_auth(),
+    };
     // configure the client to only check for an exact cert match instead
     // of checking the domain name and comparing against a cert chain.
     config
@@ -96,3 +134,733 @@ pub async fn connect(
     let stream = connector.connect(domain, stream).await?;
     Ok(stream)
 }
+
+/// A single result read from the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    /// `OK\n`
+    Ok,
+    /// `PONG\n`, answering a PING without a payload
+    Pong,
+    /// `null\n`, the key doesn't exist
+    NotFound,
+    /// `nil\n`, the key exists but what was asked for has no value
+    Null,
+    /// `ERR:<len>:<message>\n`
+    Error(String),
+    /// A single length-prefixed value, e.g. `5:hello\n`, which may be empty (`0:\n`)
+    Value(Vec<u8>),
+    /// Several length-prefixed fields, e.g. `1:5:7:created\n`
+    Fields(Vec<Vec<u8>>),
+    /// Several fields where some are `null`, e.g. `1:2:1:a:null\n`
+    Values(Vec<Option<Vec<u8>>>),
+}
+impl Response {
+    /// Decode the response at the start of `buf`, returning it along with the
+    /// number of bytes it spans, or `None` if `buf` doesn't hold a whole response yet.
+    pub fn decode(buf: &[u8]) -> Result<Option<(Response, usize)>> {
+        let literals = [
+            (&b"OK\n"[..], Response::Ok),
+            (b"PONG\n", Response::Pong),
+            (b"null\n", Response::NotFound),
+            (b"nil\n", Response::Null),
+        ];
+        for (literal, response) in literals {
+            if buf.starts_with(literal) {
+                return Ok(Some((response, literal.len())));
+            } else if literal.starts_with(buf) {
+                return Ok(None);
+            }
+        }
+        let (is_error, start) = match buf {
+            [b'E', ..] if buf.starts_with(b"ERR:") => (true, 4),
+            [b'E', ..] if b"ERR:".starts_with(buf) => return Ok(None),
+            [b'0'..=b'9', ..] => (false, 0),
+            _ => {
+                return Err(format!(
+                    "unexpected response from server: {:?}",
+                    String::from_utf8_lossy(buf)
+                )
+                .into())
+            }
+        };
+        let mut fields = vec![];
+        let mut ptr = start;
+        loop {
+            let field_end = if !is_error && ptr > 0 && buf[ptr..].starts_with(b"null") {
+                fields.push(None);
+                ptr + 4
+            } else {
+                let len_end = match buf[ptr..].iter().position(|b| *b == b':') {
+                    Some(n) => ptr + n,
+                    None => return Ok(None),
+                };
+                let len = std::str::from_utf8(&buf[ptr..len_end])
+                    .map_err(|e| format!("response field length is invalid utf8: {e}"))?
+                    .parse::<usize>()?;
+                let field_end = len_end + 1 + len;
+                if buf.len() < field_end {
+                    return Ok(None);
+                }
+                fields.push(Some(buf[len_end + 1..field_end].to_vec()));
+                field_end
+            };
+            // the separator or newline following the field
+            if buf.len() <= field_end {
+                return Ok(None);
+            }
+            ptr = field_end + 1;
+            match buf[field_end] {
+                b':' if !is_error => continue,
+                b'\n' => break,
+                b => {
+                    return Err(format!(
+                        "expected ':' or '\\n' after response field, found {:?}",
+                        b as char
+                    )
+                    .into())
+                }
+            }
+        }
+        let response = if fields.iter().any(Option::is_none) {
+            Response::Values(fields)
+        } else {
+            let mut fields = fields.into_iter().flatten().collect::<Vec<_>>();
+            if is_error {
+                Response::Error(String::from_utf8_lossy(&fields[0]).into_owned())
+            } else if fields.len() == 1 {
+                Response::Value(fields.remove(0))
+            } else {
+                Response::Fields(fields)
+            }
+        };
+        Ok(Some((response, ptr)))
+    }
+}
+
+/// A client connection that speaks the wire protocol described in `Proto::read`
+pub struct Client {
+    reader: ReadHalf<TlsStream<TcpStream>>,
+    writer: WriteHalf<TlsStream<TcpStream>>,
+    // bytes read from the server that haven't been decoded yet
+    buf: Vec<u8>,
+    // largest value the server accepts, as advertised by HELLO
+    max_value_size: Option<usize>,
+    // how often writes the server was too overloaded to take are retried,
+    // and how long to back off before the first retry, doubling for each after
+    overload_retries: usize,
+    overload_backoff: Duration,
+}
+impl Client {
+    /// Connect to a server and learn its capabilities with a HELLO handshake
+    pub async fn connect(addr: &str, port: u16, certs: Vec<Certificate>) -> Result<Self> {
+        Self::handshake(connect(addr, port, certs).await?).await
+    }
+
+    /// Connect to a server that requires clients to present a cert, see
+    /// `connect_with_client_cert`, and learn its capabilities like `connect`
+    pub async fn connect_with_client_cert(
+        addr: &str,
+        port: u16,
+        certs: Vec<Certificate>,
+        client_certs: Vec<Certificate>,
+        client_key: PrivateKey,
+    ) -> Result<Self> {
+        let stream = connect_with_client_cert(addr, port, certs, client_certs, client_key).await?;
+        Self::handshake(stream).await
+    }
+
+    async fn handshake(stream: TlsStream<TcpStream>) -> Result<Self> {
+        let (reader, writer) = split(stream);
+        let mut client = Self {
+            reader,
+            writer,
+            buf: Vec::new(),
+            max_value_size: None,
+            overload_retries: 5,
+            overload_backoff: Duration::from_millis(20),
+        };
+        client.hello().await?;
+        Ok(client)
+    }
+
+    /// Retry writes the server was too overloaded to take up to `retries` times,
+    /// backing off for `backoff` before the first retry and twice as long before each
+    /// retry after it. With no retries left, writes fail with `Error::Overloaded`.
+    pub fn set_overload_retries(&mut self, retries: usize, backoff: Duration) -> &mut Self {
+        self.overload_retries = retries;
+        self.overload_backoff = backoff;
+        self
+    }
+
+    /// The largest value the server accepts, if it advertised one
+    pub fn max_value_size(&self) -> Option<usize> {
+        self.max_value_size
+    }
+
+    /// Ask the server for its capabilities, caching the ones the client relies on
+    pub async fn hello(&mut self) -> Result<Vec<(String, Vec<u8>)>> {
+        let fields = match self.request(b"HELLO\n").await? {
+            Response::Fields(fields) if fields.len() % 2 == 0 => fields,
+            r => return Err(format!("unexpected HELLO response: {r:?}").into()),
+        };
+        let capabilities = fields
+            .chunks(2)
+            .map(|pair| {
+                (
+                    String::from_utf8_lossy(&pair[0]).into_owned(),
+                    pair[1].clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+        self.max_value_size = None;
+        for (name, value) in &capabilities {
+            if name == "max_value_size" {
+                let max = String::from_utf8_lossy(value).parse()?;
+                self.max_value_size = Some(max);
+            }
+        }
+        Ok(capabilities)
+    }
+
+    /// Set `key` to `value`, returning the number of bytes the server stored.
+    /// Values larger than the server's advertised `max_value_size` are rejected
+    /// without being sent.
+    pub async fn set(&mut self, key: &[u8], value: &[u8]) -> Result<usize> {
+        check_value_size(self.max_value_size, value)?;
+        let response = self.write_request(&set_request(key, value)).await?;
+        set_result(response)
+    }
+
+    /// Get the value of `key`, `None` if it doesn't exist
+    pub async fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        get_result(self.request(&get_request(key)).await?)
+    }
+
+    /// Have the server send `msg` back
+    pub async fn echo(&mut self, msg: &[u8]) -> Result<Vec<u8>> {
+        let mut req = format!("ECHO:{}:", msg.len()).into_bytes();
+        req.extend_from_slice(msg);
+        req.push(b'\n');
+        match self.request(&req).await? {
+            Response::Value(echoed) => Ok(echoed),
+            Response::Error(e) => Err(e.into()),
+            r => Err(format!("unexpected ECHO response: {r:?}").into()),
+        }
+    }
+
+    /// Check the connection is alive, and in step with the server
+    pub async fn ping(&mut self) -> Result<()> {
+        match self.request(b"PING\n").await? {
+            Response::Pong => Ok(()),
+            Response::Error(e) => Err(e.into()),
+            r => Err(format!("unexpected PING response: {r:?}").into()),
+        }
+    }
+
+    /// Get the value of `key` along with its version, for a later `set_if_version`
+    pub async fn get_versioned(&mut self, key: &[u8]) -> Result<Option<(Vec<u8>, String)>> {
+        let mut req = format!("GETV:{}:", key.len()).into_bytes();
+        req.extend_from_slice(key);
+        req.push(b'\n');
+        match self.request(&req).await? {
+            Response::Fields(mut fields) if fields.len() == 2 => {
+                let version = String::from_utf8_lossy(&fields.pop().unwrap()).into_owned();
+                Ok(Some((fields.pop().unwrap(), version)))
+            }
+            Response::NotFound => Ok(None),
+            Response::Error(e) => Err(e.into()),
+            r => Err(format!("unexpected GETV response: {r:?}").into()),
+        }
+    }
+
+    /// Set `key` to `value` only if it's still at `version` as returned by `get_versioned`,
+    /// or still absent if `version` is empty. Returns whether the value was set, `false`
+    /// meaning another write changed it since it was read.
+    pub async fn set_if_version(
+        &mut self,
+        key: &[u8],
+        version: &str,
+        value: &[u8],
+    ) -> Result<bool> {
+        check_value_size(self.max_value_size, value)?;
+        let mut req = format!("CASV:{}:", key.len()).into_bytes();
+        req.extend_from_slice(key);
+        req.extend_from_slice(format!(":{}:{version}:{}:", version.len(), value.len()).as_bytes());
+        req.extend_from_slice(value);
+        req.push(b'\n');
+        match self.write_request(&req).await? {
+            Response::Value(set) if set == b"1" => Ok(true),
+            Response::Value(set) if set == b"0" => Ok(false),
+            Response::Error(e) => Err(e.into()),
+            r => Err(format!("unexpected CASV response: {r:?}").into()),
+        }
+    }
+
+    /// Set `key` to `value` only if it currently holds `expected`, returning whether
+    /// it was set. An absent key never matches.
+    pub async fn compare_and_swap(
+        &mut self,
+        key: &[u8],
+        expected: &[u8],
+        value: &[u8],
+    ) -> Result<bool> {
+        check_value_size(self.max_value_size, value)?;
+        let mut req = format!("CAS:{}:", key.len()).into_bytes();
+        req.extend_from_slice(key);
+        req.extend_from_slice(format!(":{}:", expected.len()).as_bytes());
+        req.extend_from_slice(expected);
+        req.extend_from_slice(format!(":{}:", value.len()).as_bytes());
+        req.extend_from_slice(value);
+        req.push(b'\n');
+        match self.write_request(&req).await? {
+            Response::Value(set) if set == b"1" => Ok(true),
+            Response::Value(set) if set == b"0" => Ok(false),
+            Response::Error(e) => Err(e.into()),
+            r => Err(format!("unexpected CAS response: {r:?}").into()),
+        }
+    }
+
+    /// Set `key` to `value`, returning the value it replaced. Nothing can be written to
+    /// the key in between, so concurrent GETSETs each see the value of the one before.
+    pub async fn get_set(&mut self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        check_value_size(self.max_value_size, value)?;
+        let mut req = format!("GETSET:{}:", key.len()).into_bytes();
+        req.extend_from_slice(key);
+        req.extend_from_slice(format!(":{}:", value.len()).as_bytes());
+        req.extend_from_slice(value);
+        req.push(b'\n');
+        match self.write_request(&req).await? {
+            Response::Value(old) => Ok(Some(old)),
+            Response::NotFound => Ok(None),
+            Response::Error(e) => Err(e.into()),
+            r => Err(format!("unexpected GETSET response: {r:?}").into()),
+        }
+    }
+
+    /// Whether `key` holds a value, without the server sending it
+    pub async fn exists(&mut self, key: &[u8]) -> Result<bool> {
+        let mut req = format!("EXISTS:{}:", key.len()).into_bytes();
+        req.extend_from_slice(key);
+        req.push(b'\n');
+        match self.write_request(&req).await? {
+            Response::Value(exists) if exists == b"1" => Ok(true),
+            Response::Value(exists) if exists == b"0" => Ok(false),
+            Response::Error(e) => Err(e.into()),
+            r => Err(format!("unexpected EXISTS response: {r:?}").into()),
+        }
+    }
+
+    /// Expire `key` once `secs` have passed, until it's set again. Returns whether the
+    /// key exists, failing when the server's store can't expire keys.
+    pub async fn expire(&mut self, key: &[u8], secs: u64) -> Result<bool> {
+        let secs = secs.to_string();
+        let mut req = format!("EXPIRE:{}:", key.len()).into_bytes();
+        req.extend_from_slice(key);
+        req.extend_from_slice(format!(":{}:{secs}\n", secs.len()).as_bytes());
+        match self.write_request(&req).await? {
+            Response::Value(set) if set == b"1" => Ok(true),
+            Response::Value(set) if set == b"0" => Ok(false),
+            Response::Error(e) => Err(e.into()),
+            r => Err(format!("unexpected EXPIRE response: {r:?}").into()),
+        }
+    }
+
+    /// Get the values of `keys`, in the same order and `None` for absent keys. The
+    /// server reads every key at a single point in time, so related keys are
+    /// consistent with each other even while they're being written.
+    pub async fn mget_consistent(&mut self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        let count = keys.len().to_string();
+        let mut req = format!("MGET:{}:{count}", count.len()).into_bytes();
+        for key in keys {
+            req.extend_from_slice(format!(":{}:", key.len()).as_bytes());
+            req.extend_from_slice(key);
+        }
+        req.push(b'\n');
+        let values = match self.request(&req).await? {
+            Response::Value(count) => vec![Some(count)],
+            Response::Fields(fields) => fields.into_iter().map(Some).collect(),
+            Response::Values(values) => values,
+            Response::Error(e) => return Err(e.into()),
+            r => return Err(format!("unexpected MGET response: {r:?}").into()),
+        };
+        match values.split_first() {
+            Some((Some(count), values)) if *count == keys.len().to_string().as_bytes() => {
+                Ok(values.to_vec())
+            }
+            _ => Err(format!(
+                "unexpected MGET response for {} keys: {values:?}",
+                keys.len()
+            )
+            .into()),
+        }
+    }
+
+    /// Set every key to its value in a single transaction, so either all of them are
+    /// written or none are
+    pub async fn mset(&mut self, pairs: &[(&[u8], &[u8])]) -> Result<()> {
+        let count = pairs.len().to_string();
+        let mut req = format!("MSET:{}:{count}", count.len()).into_bytes();
+        for (key, value) in pairs {
+            check_value_size(self.max_value_size, value)?;
+            req.extend_from_slice(format!(":{}:", key.len()).as_bytes());
+            req.extend_from_slice(key);
+            req.extend_from_slice(format!(":{}:", value.len()).as_bytes());
+            req.extend_from_slice(value);
+        }
+        req.push(b'\n');
+        match self.write_request(&req).await? {
+            Response::Ok => Ok(()),
+            Response::Error(e) => Err(e.into()),
+            r => Err(format!("unexpected MSET response: {r:?}").into()),
+        }
+    }
+
+    /// Scan up to `count` keys from `cursor`, an empty one starting from the first key.
+    /// Returns the cursor to scan the next keys from, which is empty once every key
+    /// was scanned, and the keys in order. Keys written mid-scan may or may not be
+    /// returned, while the others are returned exactly once.
+    pub async fn scan(&mut self, cursor: &[u8], count: usize) -> Result<(Vec<u8>, Vec<Vec<u8>>)> {
+        let count = count.to_string();
+        let mut req = format!("SCAN:{}:", cursor.len()).into_bytes();
+        req.extend_from_slice(cursor);
+        req.extend_from_slice(format!(":{}:{count}\n", count.len()).as_bytes());
+        match self.request(&req).await? {
+            Response::Fields(fields) if fields.len() >= 2 => {
+                let mut fields = fields.into_iter().skip(1);
+                let cursor = fields.next().unwrap_or_default();
+                Ok((cursor, fields.collect()))
+            }
+            Response::Error(e) => Err(e.into()),
+            r => Err(format!("unexpected SCAN response: {r:?}").into()),
+        }
+    }
+
+    /// Share the connection between tasks, pipelining the requests they issue
+    /// concurrently, see `PipelinedClient`
+    pub fn pipelined(self) -> PipelinedClient {
+        PipelinedClient::new(self)
+    }
+
+    /// Send a single write and read its response, retrying it with exponential
+    /// backoff while the server is too overloaded to take it
+    async fn write_request(&mut self, req: &[u8]) -> Result<Response> {
+        let mut backoff = self.overload_backoff;
+        let mut retries = self.overload_retries;
+        loop {
+            let msg = match self.request(req).await? {
+                Response::Error(msg) => msg,
+                response => return Ok(response),
+            };
+            let overloaded = match msg.strip_prefix(OVERLOADED) {
+                Some(overloaded) => overloaded.trim_start(),
+                None => return Ok(Response::Error(msg)),
+            };
+            if retries == 0 {
+                let reason = overloaded
+                    .strip_prefix("overloaded: ")
+                    .unwrap_or(overloaded);
+                return Err(Error::Overloaded(reason.to_string()));
+            }
+            tracing::debug!("server overloaded, retrying in {backoff:?}: {overloaded}");
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+            retries -= 1;
+        }
+    }
+
+    /// Send a single command and read its response
+    pub(crate) async fn request(&mut self, req: &[u8]) -> Result<Response> {
+        self.writer.write_all(req).await?;
+        self.writer.flush().await?;
+        self.read_response().await
+    }
+
+    /// Send `count` commands at once and read their responses, in the same order
+    pub(crate) async fn requests(&mut self, reqs: &[u8], count: usize) -> Result<Vec<Response>> {
+        self.writer.write_all(reqs).await?;
+        self.writer.flush().await?;
+        let mut responses = Vec::with_capacity(count);
+        for _ in 0..count {
+            responses.push(self.read_response().await?);
+        }
+        Ok(responses)
+    }
+
+    async fn read_response(&mut self) -> Result<Response> {
+        loop {
+            if let Some((response, n)) = Response::decode(&self.buf)? {
+                self.buf.drain(..n);
+                return Ok(response);
+            }
+            if self.reader.read_buf(&mut self.buf).await? == 0 {
+                return Err("connection closed by server".into());
+            }
+        }
+    }
+}
+
+/// Values larger than the server's advertised max value size are rejected without being sent
+fn check_value_size(max_value_size: Option<usize>, value: &[u8]) -> Result<()> {
+    match max_value_size {
+        Some(max) if value.len() > max => Err(format!(
+            "value of {} bytes exceeds the server's max value size of {max} bytes",
+            value.len()
+        )
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+fn set_request(key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut req = format!("SET:{}:", key.len()).into_bytes();
+    req.extend_from_slice(key);
+    req.extend_from_slice(format!(":{}:", value.len()).as_bytes());
+    req.extend_from_slice(value);
+    req.push(b'\n');
+    req
+}
+
+fn get_request(key: &[u8]) -> Vec<u8> {
+    let mut req = format!("GET:{}:", key.len()).into_bytes();
+    req.extend_from_slice(key);
+    req.push(b'\n');
+    req
+}
+
+/// The value, from a GET response
+fn get_result(response: Response) -> Result<Option<Vec<u8>>> {
+    match response {
+        Response::Value(value) => Ok(Some(value)),
+        Response::NotFound => Ok(None),
+        Response::Error(e) => Err(e.into()),
+        r => Err(format!("unexpected GET response: {r:?}").into()),
+    }
+}
+
+/// The number of bytes stored, from a SET response
+fn set_result(response: Response) -> Result<usize> {
+    match response {
+        Response::Fields(fields) if !fields.is_empty() => {
+            Ok(String::from_utf8_lossy(&fields[0]).parse()?)
+        }
+        Response::Error(e) => Err(e.into()),
+        r => Err(format!("unexpected SET response: {r:?}").into()),
+    }
+}
+
+/// Most bytes of queued requests coalesced into a single write
+const MAX_PIPELINE_BYTES: usize = 64 * 1024;
+
+/// A request waiting to be written, and where its response is sent
+struct Queued {
+    req: Vec<u8>,
+    respond: oneshot::Sender<Result<Response>>,
+}
+
+/// A connection shared by every task holding a clone, made with `Client::pipelined`.
+/// Requests issued concurrently are coalesced into pipelined writes, and since the
+/// server answers a connection's commands in the order they were sent, each response
+/// is handed back to whoever sent its request in that same order.
+///
+/// Writes the server is too overloaded to take fail with its error rather than
+/// being retried, so that one caller's backoff doesn't hold up the others'.
+#[derive(Clone, Debug)]
+pub struct PipelinedClient {
+    requests: mpsc::UnboundedSender<Queued>,
+    max_value_size: Option<usize>,
+}
+impl PipelinedClient {
+    /// Take over `client`'s connection, writing requests from one task and reading
+    /// responses from another. Both end once every clone has been dropped.
+    fn new(client: Client) -> Self {
+        let Client {
+            mut reader,
+            mut writer,
+            mut buf,
+            max_value_size,
+            ..
+        } = client;
+        let (requests, mut queued) = mpsc::unbounded_channel::<Queued>();
+        // responses are awaited in the order their requests were written
+        let (awaiting, mut responses) = mpsc::unbounded_channel::<oneshot::Sender<_>>();
+        tokio::spawn(async move {
+            let mut batch = Vec::new();
+            while let Some(first) = queued.recv().await {
+                batch.clear();
+                let mut next = Some(first);
+                while let Some(Queued { req, respond }) = next.take() {
+                    batch.extend_from_slice(&req);
+                    if awaiting.send(respond).is_err() {
+                        return;
+                    }
+                    if batch.len() < MAX_PIPELINE_BYTES {
+                        next = queued.try_recv().ok();
+                    }
+                }
+                let written = async {
+                    writer.write_all(&batch).await?;
+                    writer.flush().await
+                };
+                if let Err(e) = written.await {
+                    tracing::debug!("error writing pipelined requests: {e}");
+                    return;
+                }
+            }
+            writer.shutdown().await.ok();
+        });
+        tokio::spawn(async move {
+            // once the connection fails every response still awaited fails with it
+            let mut failed: Option<String> = None;
+            while let Some(respond) = responses.recv().await {
+                let response = loop {
+                    if let Some(e) = &failed {
+                        break Err(e.as_str().into());
+                    }
+                    match Response::decode(&buf) {
+                        Ok(Some((response, n))) => {
+                            buf.drain(..n);
+                            break Ok(response);
+                        }
+                        Ok(None) => {}
+                        Err(e) => failed = Some(e.to_string()),
+                    }
+                    match reader.read_buf(&mut buf).await {
+                        Ok(0) => failed = Some("connection closed by server".to_string()),
+                        Ok(_) => {}
+                        Err(e) => failed = Some(format!("error reading response: {e}")),
+                    }
+                };
+                respond.send(response).ok();
+            }
+        });
+        Self {
+            requests,
+            max_value_size,
+        }
+    }
+
+    /// Set `key` to `value`, see `Client::set`
+    pub async fn set(&self, key: &[u8], value: &[u8]) -> Result<usize> {
+        check_value_size(self.max_value_size, value)?;
+        set_result(self.request(set_request(key, value)).await?)
+    }
+
+    /// Get the value of `key`, see `Client::get`
+    pub async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        get_result(self.request(get_request(key)).await?)
+    }
+
+    /// Queue a single command that's always answered, e.g. not SETQ, and wait for its response
+    async fn request(&self, req: Vec<u8>) -> Result<Response> {
+        let (respond, response) = oneshot::channel();
+        self.requests
+            .send(Queued { req, respond })
+            .map_err(|_| "pipelined connection closed")?;
+        response.await.map_err(|_| "pipelined connection closed")?
+    }
+}
+
+/// Routes every key to the node that owns it on a consistent-hashing `Ring`
+/// of `host:port` addresses, connecting to each node when it's first needed
+pub struct ClusterClient {
+    ring: Ring,
+    certs: Vec<Certificate>,
+    // connections to the nodes used so far
+    clients: HashMap<String, Client>,
+}
+impl ClusterClient {
+    pub fn new(ring: Ring, certs: Vec<Certificate>) -> Self {
+        Self {
+            ring,
+            certs,
+            clients: HashMap::new(),
+        }
+    }
+
+    pub fn ring(&self) -> &Ring {
+        &self.ring
+    }
+
+    /// Add a node to the ring, the keys it now owns are routed to it from then on
+    pub fn add_node(&mut self, node: &str) -> bool {
+        self.ring.add(node)
+    }
+
+    /// Remove a node from the ring, closing the connection to it
+    pub fn remove_node(&mut self, node: &str) -> bool {
+        self.clients.remove(node);
+        self.ring.remove(node)
+    }
+
+    /// The connection to the node owning `key`
+    pub async fn client_for(&mut self, key: &[u8]) -> Result<&mut Client> {
+        let node = self
+            .ring
+            .node_for(key)
+            .ok_or("no nodes to route keys to")?
+            .to_string();
+        if !self.clients.contains_key(&node) {
+            let (host, port) = node
+                .rsplit_once(':')
+                .ok_or_else(|| format!("expected a `host:port` node, found {node:?}"))?;
+            let client = Client::connect(host, port.parse()?, self.certs.clone()).await?;
+            self.clients.insert(node.clone(), client);
+        }
+        Ok(self.clients.get_mut(&node).expect("connected above"))
+    }
+
+    /// Set `key` to `value` on the node owning it, see `Client::set`
+    pub async fn set(&mut self, key: &[u8], value: &[u8]) -> Result<usize> {
+        self.client_for(key).await?.set(key, value).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Response;
+
+    #[test]
+    fn test_decode_response() {
+        let decode = |buf: &[u8]| Response::decode(buf).unwrap();
+        assert_eq!(Some((Response::Ok, 3)), decode(b"OK\n"));
+        assert_eq!(Some((Response::Pong, 5)), decode(b"PONG\n"));
+        assert_eq!(None, decode(b"PON"));
+        assert_eq!(Some((Response::NotFound, 5)), decode(b"null\nOK\n"));
+        assert_eq!(None, decode(b"nu"));
+        assert_eq!(Some((Response::Null, 4)), decode(b"nil\n"));
+        assert_eq!(None, decode(b"ni"));
+        assert_eq!(None, decode(b"n"));
+        assert_eq!(Some((Response::Value(vec![]), 3)), decode(b"0:\n"));
+        assert_eq!(
+            Some((Response::Error("bad".into()), 10)),
+            decode(b"ERR:3:bad\n")
+        );
+        assert_eq!(None, decode(b"ER"));
+        assert_eq!(None, decode(b"ERR:3:ba"));
+        assert_eq!(
+            Some((Response::Value(b"a:c".to_vec()), 6)),
+            decode(b"3:a:c\n")
+        );
+        assert_eq!(
+            Some((
+                Response::Fields(vec![b"5".to_vec(), b"created".to_vec()]),
+                14
+            )),
+            decode(b"1:5:7:created\n")
+        );
+        assert_eq!(None, decode(b"1:5:7:crea"));
+        assert_eq!(None, decode(b"1:5"));
+        assert_eq!(
+            Some((
+                Response::Values(vec![Some(b"2".to_vec()), None, Some(b"a".to_vec())]),
+                13
+            )),
+            decode(b"1:2:null:1:a\nOK\n")
+        );
+        assert_eq!(None, decode(b"1:2:null"));
+        assert_eq!(None, decode(b"1:2:nu"));
+        assert!(Response::decode(b"what\n").is_err());
+        assert!(Response::decode(b"1:ab").is_err());
+    }
+}
@@ -0,0 +1,153 @@
+//! A pool of `Client` connections to a single server, so requests don't each
+//! pay for a TLS handshake
+//!
+//! Connections are opened as they're first needed, up to the pool's size, and handed
+//! back to the pool when the `PooledConn` holding them is dropped. Callers wait for
+//! one to be handed back once every connection is in use. Idle connections are
+//! checked with a PING before they're reused, and replaced when they fail it.
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_rustls::rustls::Certificate;
+
+use super::{Client, ClientCert};
+use crate::error::Result;
+
+struct Shared {
+    addr: String,
+    port: u16,
+    certs: Vec<Certificate>,
+    // presented by every connection, when set
+    client_cert: Option<ClientCert>,
+    // connections handed back, the most recently used last
+    idle: Mutex<Vec<Client>>,
+    // a permit per connection that may be in use at once
+    slots: Arc<Semaphore>,
+    // connections opened so far, including any since closed
+    opened: AtomicUsize,
+}
+
+/// Connections to a server shared between tasks, see the module docs. Clones share
+/// the same connections.
+#[derive(Clone)]
+pub struct Pool {
+    shared: Arc<Shared>,
+}
+impl Pool {
+    /// A pool of at most `size` connections to `addr:port`, see `Client::connect`
+    pub fn new(addr: &str, port: u16, certs: Vec<Certificate>, size: usize) -> Self {
+        Self::with_client_cert(addr, port, certs, None, size)
+    }
+
+    /// A pool like `new` whose connections present `client_cert` when it's set,
+    /// see `Client::connect_with_client_cert`
+    pub fn with_client_cert(
+        addr: &str,
+        port: u16,
+        certs: Vec<Certificate>,
+        client_cert: Option<ClientCert>,
+        size: usize,
+    ) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                addr: addr.to_string(),
+                port,
+                certs,
+                client_cert,
+                idle: Mutex::new(Vec::with_capacity(size)),
+                slots: Arc::new(Semaphore::new(size)),
+                opened: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Take a connection, waiting for one to be handed back when they're all in use.
+    /// Idle connections that fail their health check are closed, and a new one opened
+    /// when none pass it.
+    pub async fn get_conn(&self) -> Result<PooledConn> {
+        let slot = self
+            .shared
+            .slots
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|_| "connection pool closed")?;
+        loop {
+            let idle = self.shared.idle.lock().expect("pool lock poisoned").pop();
+            let mut client = match idle {
+                Some(client) => client,
+                None => break,
+            };
+            match client.ping().await {
+                Ok(()) => {
+                    return Ok(PooledConn {
+                        client: Some(client),
+                        shared: self.shared.clone(),
+                        _slot: slot,
+                    })
+                }
+                Err(e) => tracing::debug!("closing pooled connection: {e}"),
+            }
+        }
+        let shared = &self.shared;
+        let certs = shared.certs.clone();
+        let client = match &shared.client_cert {
+            Some((client_certs, client_key)) => {
+                Client::connect_with_client_cert(
+                    &shared.addr,
+                    shared.port,
+                    certs,
+                    client_certs.clone(),
+                    client_key.clone(),
+                )
+                .await?
+            }
+            None => Client::connect(&shared.addr, shared.port, certs).await?,
+        };
+        shared.opened.fetch_add(1, Ordering::Relaxed);
+        Ok(PooledConn {
+            client: Some(client),
+            shared: shared.clone(),
+            _slot: slot,
+        })
+    }
+
+    /// The number of connections opened so far, including those since closed
+    pub fn opened(&self) -> usize {
+        self.shared.opened.load(Ordering::Relaxed)
+    }
+
+    /// The number of connections waiting to be reused
+    pub fn idle(&self) -> usize {
+        self.shared.idle.lock().expect("pool lock poisoned").len()
+    }
+}
+
+/// A connection taken from a `Pool`, handed back to it when dropped
+pub struct PooledConn {
+    client: Option<Client>,
+    shared: Arc<Shared>,
+    // given back after the connection is, so waiting callers find it idle
+    _slot: OwnedSemaphorePermit,
+}
+impl Deref for PooledConn {
+    type Target = Client;
+    fn deref(&self) -> &Client {
+        self.client.as_ref().expect("pooled connection taken")
+    }
+}
+impl DerefMut for PooledConn {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client.as_mut().expect("pooled connection taken")
+    }
+}
+impl Drop for PooledConn {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            let mut idle = self.shared.idle.lock().expect("pool lock poisoned");
+            idle.push(client);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! Consistent-hashing ring routing keys to the node that owns them
+//!
+//! Every node is hashed onto the ring at several points (virtual nodes) and a key
+//! is owned by the first node point at or after the key's own hash. Spreading each
+//! node over many points balances keys between nodes, and adding or removing a node
+//! only moves the keys between its points and their neighbours, roughly `1/n` of them.
+use std::collections::BTreeSet;
+
+use crate::crypto;
+
+/// Points each node is hashed to by default
+pub const DEFAULT_VNODES: usize = 160;
+
+#[derive(Clone, Debug)]
+pub struct Ring {
+    vnodes: usize,
+    // (hash, node) so nodes whose points collide each keep theirs
+    points: BTreeSet<(u64, String)>,
+}
+
+impl Ring {
+    /// An empty ring hashing each node to `vnodes` points
+    pub fn new(vnodes: usize) -> Self {
+        Self {
+            vnodes: vnodes.max(1),
+            points: BTreeSet::new(),
+        }
+    }
+
+    /// A ring of `nodes`, hashing each to `DEFAULT_VNODES` points
+    pub fn with_nodes<I, N>(nodes: I) -> Self
+    where
+        I: IntoIterator<Item = N>,
+        N: Into<String>,
+    {
+        let mut ring = Self::new(DEFAULT_VNODES);
+        for node in nodes {
+            ring.add(node);
+        }
+        ring
+    }
+
+    /// Add a node, returning whether it wasn't already on the ring
+    pub fn add<N: Into<String>>(&mut self, node: N) -> bool {
+        let node = node.into();
+        if self.contains(&node) {
+            return false;
+        }
+        for i in 0..self.vnodes {
+            self.points
+                .insert((hash(format!("{node}#{i}").as_bytes()), node.clone()));
+        }
+        true
+    }
+
+    /// Remove a node, returning whether it was on the ring
+    pub fn remove(&mut self, node: &str) -> bool {
+        let len = self.points.len();
+        self.points.retain(|(_, n)| n != node);
+        len != self.points.len()
+    }
+
+    pub fn contains(&self, node: &str) -> bool {
+        self.points.iter().any(|(_, n)| n == node)
+    }
+
+    /// The node that owns `key`, `None` when the ring is empty
+    pub fn node_for(&self, key: &[u8]) -> Option<&str> {
+        let h = hash(key);
+        self.points
+            .range((h, String::new())..)
+            .next()
+            // wrap around past the last point
+            .or_else(|| self.points.iter().next())
+            .map(|(_, node)| node.as_str())
+    }
+
+    /// Every node on the ring, sorted
+    pub fn nodes(&self) -> Vec<&str> {
+        let nodes = self
+            .points
+            .iter()
+            .map(|(_, node)| node.as_str())
+            .collect::<BTreeSet<_>>();
+        nodes.into_iter().collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+}
+
+/// Position on the ring, from a sha256 so every client agrees on it
+fn hash(bytes: &[u8]) -> u64 {
+    let digest = crypto::hash(bytes);
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::Ring;
+
+    fn keys() -> Vec<Vec<u8>> {
+        (0..10_000)
+            .map(|i| format!("key{i}").into_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn test_distribution() {
+        let ring = Ring::with_nodes(["a:7719", "b:7719", "c:7719", "d:7719"]);
+        let mut counts = HashMap::new();
+        for key in keys() {
+            *counts.entry(ring.node_for(&key).unwrap()).or_insert(0) += 1;
+        }
+        assert_eq!(4, counts.len());
+        // every node gets a fair share, 2500 would be perfectly even
+        for (node, count) in counts {
+            assert!((1500..3500).contains(&count), "{node} owns {count} keys");
+        }
+        // routing is stable
+        assert_eq!(ring.node_for(b"foo"), ring.clone().node_for(b"foo"));
+        assert_eq!(
+            None,
+            Ring::with_nodes(Vec::<String>::new()).node_for(b"foo")
+        );
+    }
+
+    #[test]
+    fn test_membership_changes() {
+        let mut ring = Ring::with_nodes(["a:7719", "b:7719", "c:7719", "d:7719"]);
+        let before = keys()
+            .into_iter()
+            .map(|key| (ring.node_for(&key).unwrap().to_string(), key))
+            .collect::<Vec<_>>();
+
+        // a fifth node takes roughly a fifth of the keys, all of them moving to it
+        assert!(ring.add("e:7719"));
+        assert!(!ring.add("e:7719"));
+        let mut moved = 0;
+        for (owner, key) in &before {
+            let now = ring.node_for(key).unwrap();
+            if now != owner {
+                assert_eq!("e:7719", now);
+                moved += 1;
+            }
+        }
+        assert!((1000..3000).contains(&moved), "{moved} keys moved");
+
+        // and removing it puts them back where they were
+        assert!(ring.remove("e:7719"));
+        assert!(!ring.remove("e:7719"));
+        for (owner, key) in &before {
+            assert_eq!(owner, ring.node_for(key).unwrap());
+        }
+
+        // removing a node only moves its own keys
+        ring.remove("a:7719");
+        assert_eq!(vec!["b:7719", "c:7719", "d:7719"], ring.nodes());
+        for (owner, key) in &before {
+            if owner != "a:7719" {
+                assert_eq!(owner, ring.node_for(key).unwrap());
+            }
+        }
+    }
+}
@@ -1,6 +1,11 @@
-use std::path::PathBuf;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
 
 use crate::error::Error;
+use crate::proto;
 
 fn get_env(k: &str) -> Option<String> {
     tracing::debug!("loading env var: {k:?}");
@@ -29,6 +34,137 @@ impl std::str::FromStr for LogFormat {
     }
 }
 
+/// What to do with a SET whose value is larger than the configured max value size.
+///
+/// - `Reject` leaves the store untouched and responds with an error. Writers always
+///   know whether their write happened, but have to handle the error and resend.
+/// - `Truncate` stores the first `max` bytes and flags the result as `truncated`.
+///   Writes never fail because of their size, but readers will see shortened data
+///   if the writer ignores the flag, so it only suits data where a prefix is still
+///   useful (e.g. logs or previews).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ValueLimitPolicy {
+    #[default]
+    Reject,
+    Truncate,
+}
+impl std::str::FromStr for ValueLimitPolicy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<ValueLimitPolicy, Error> {
+        match s.trim().to_lowercase().as_str() {
+            "" | "reject" => Ok(ValueLimitPolicy::Reject),
+            "truncate" => Ok(ValueLimitPolicy::Truncate),
+            s => Err(Error::from(format!(
+                "invalid VALUE_LIMIT_POLICY: {s}, expected one of (reject|truncate)"
+            ))),
+        }
+    }
+}
+
+/// What to do with a transaction when the max number of transactions are already in flight.
+///
+/// - `Queue` waits for a slot to free up, which smooths over bursts but adds latency.
+/// - `Reject` fails the command immediately with a retryable `busy` error so clients
+///   can back off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TransactionLimitPolicy {
+    #[default]
+    Queue,
+    Reject,
+}
+impl std::str::FromStr for TransactionLimitPolicy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<TransactionLimitPolicy, Error> {
+        match s.trim().to_lowercase().as_str() {
+            "" | "queue" => Ok(TransactionLimitPolicy::Queue),
+            "reject" => Ok(TransactionLimitPolicy::Reject),
+            s => Err(Error::from(format!(
+                "invalid TRANSACTION_LIMIT_POLICY: {s}, expected one of (queue|reject)"
+            ))),
+        }
+    }
+}
+
+/// What to do with a new client connection when the max number of connections are
+/// already open.
+///
+/// - `Queue` leaves it in the listen backlog until a session ends and frees its slot,
+///   so the client waits to be served (or until the backlog is full).
+/// - `Reject` accepts it only to answer with a retryable `busy` error and close it,
+///   before the TLS handshake, so TLS clients see their handshake fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionLimitPolicy {
+    #[default]
+    Queue,
+    Reject,
+}
+impl std::str::FromStr for ConnectionLimitPolicy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<ConnectionLimitPolicy, Error> {
+        match s.trim().to_lowercase().as_str() {
+            "" | "queue" => Ok(ConnectionLimitPolicy::Queue),
+            "reject" => Ok(ConnectionLimitPolicy::Reject),
+            s => Err(Error::from(format!(
+                "invalid CONNECTION_LIMIT_POLICY: {s}, expected one of (queue|reject)"
+            ))),
+        }
+    }
+}
+
+/// Whether the raw protocol bytes of client sessions are logged, as hex dumps at
+/// `trace` level under the `kave::wire` target (which the log level must also enable).
+///
+/// - `Off` logs nothing.
+/// - `Redacted` logs every command read with its value payloads replaced by their
+///   length, and only the length of responses that carry values. Safe to enable
+///   where values may hold secrets.
+/// - `Full` logs every byte read and written, exactly as it was on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WireTrace {
+    #[default]
+    Off,
+    Redacted,
+    Full,
+}
+impl std::str::FromStr for WireTrace {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<WireTrace, Error> {
+        match s.trim().to_lowercase().as_str() {
+            "" | "off" => Ok(WireTrace::Off),
+            "redacted" => Ok(WireTrace::Redacted),
+            "full" => Ok(WireTrace::Full),
+            s => Err(Error::from(format!(
+                "invalid WIRE_TRACE: {s}, expected one of (off|redacted|full)"
+            ))),
+        }
+    }
+}
+
+/// Which store the server keeps its data in, see `store::backend::StoreBackend`.
+///
+/// - `Memory` keeps everything in memory and loses it on shutdown, for testing.
+/// - `Lsm` persists data to `data_dir` with an `LSMStore`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreKind {
+    Memory,
+    #[default]
+    Lsm,
+}
+impl std::str::FromStr for StoreKind {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<StoreKind, Error> {
+        match s.trim().to_lowercase().as_str() {
+            "memory" => Ok(StoreKind::Memory),
+            "" | "lsm" => Ok(StoreKind::Lsm),
+            s => Err(Error::from(format!(
+                "invalid STORE_BACKEND: {s}, expected one of (memory|lsm)"
+            ))),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Config {
     // host to listen on for client request, defaults to 0.0.0.0:7719
@@ -37,14 +173,67 @@ pub struct Config {
     // addr to listen on for cluster request, defaults to 0.0.0.0:7720
     pub cluster_host: String,
     pub cluster_port: u16,
+    // whether clients are listened for over TLS at `client_host:client_port`, turned
+    // off to only listen on `client_unix_socket`
+    pub client_tcp: bool,
+    // path of a Unix socket to also listen for clients on, their sessions skip TLS.
+    // Not listened on when unset
+    pub client_unix_socket: Option<PathBuf>,
+
+    // number of worker threads for the server's runtime, defaults to one per core
+    pub runtime_workers: Option<usize>,
+    // whether listeners bind with SO_REUSEPORT so several runtimes
+    // (or processes) can accept connections on the same port
+    pub reuse_port: bool,
+    // single threaded runtimes the client-server accepts connections on when
+    // `reuse_port` is set, defaults to one per core
+    pub client_runtimes: Option<usize>,
+    // SO_SNDBUF and SO_RCVBUF of accepted client connections, left to the OS when unset.
+    // Worth raising for large values over links with a high bandwidth-delay product
+    pub socket_send_buffer_bytes: Option<usize>,
+    pub socket_recv_buffer_bytes: Option<usize>,
+    // how long a client may send nothing before its session is closed, so idle and
+    // half-open connections don't pile up. Disabled when unset
+    pub idle_timeout: Option<Duration>,
+    // most client connections open at once, unlimited when unset
+    pub max_connections: Option<usize>,
+    // how connections beyond `max_connections` are handled
+    pub connection_limit_policy: ConnectionLimitPolicy,
+    // most cluster connections open at once, followers included, unlimited when unset.
+    // Connections beyond it are handled by `connection_limit_policy` too
+    pub cluster_max_connections: Option<usize>,
+    // how long shutdown waits for sessions to finish the requests they're reading
+    // before closing them anyway
+    pub drain_timeout: Duration,
+    // address serving metrics over HTTP in the Prometheus text format, not served when unset
+    pub metrics_addr: Option<String>,
 
     // list of addresses to seed peer discovery
     pub seed_peers: Vec<String>,
+    // cluster address (host:port) of the leader this node follows, applying the writes
+    // it replicates. Unset for leaders
+    pub leader_addr: Option<String>,
+    // writes buffered for each follower that hasn't been sent them yet, when this node
+    // leads. Followers falling further behind are disconnected. Nothing is replicated when unset
+    pub replication_log_capacity: Option<usize>,
+    // client addresses (host:port) of every node keys are spread over, keys aren't
+    // routed between nodes when empty
+    pub shard_nodes: Vec<String>,
+    // this node's address among `shard_nodes`, its client address when unset
+    pub shard_node: Option<String>,
 
     // path to files containing certificates and private keys
     // that the server should use for ssl
     pub cert_path: String,
     pub key_path: String,
+    // whether clients must present a cert issued by one of the CAs in `client_ca_path`
+    // in their handshake, which is required when they must
+    pub require_client_certs: bool,
+    pub client_ca_path: Option<String>,
+    // files containing the cert and private key this node presents when it connects to
+    // other nodes, i.e. its leader and shard nodes, for when they require client certs
+    pub node_client_cert_path: Option<String>,
+    pub node_client_key_path: Option<String>,
 
     pub log_level: String,
     pub log_format: LogFormat,
@@ -55,6 +244,12 @@ pub struct Config {
     // key used for signing/hashing things
     pub signing_key: String,
 
+    // which store data is kept in, defaults to the lsm store
+    pub store_backend: StoreKind,
+    // most bytes of keys and values the memory store holds before evicting the least
+    // recently used keys. Unbounded when unset
+    pub memory_max_bytes: Option<usize>,
+
     // directory where data files should be stored
     pub data_dir: PathBuf,
 
@@ -62,8 +257,89 @@ pub struct Config {
     // in production, this should be on a different disk than the data_dir
     pub commit_log_path: PathBuf,
 
+    // how often the commit log is synced to disk, making the writes of async
+    // and batched durability durable. When unset they're only made durable by
+    // later fsync writes and memtable flushes
+    pub commit_log_sync_interval: Option<Duration>,
+
     // how big the memtable can get before being flushed to disk
     pub memtable_max_mb: usize,
+    // how big the memtable can get before writes are throttled to the rate memtable
+    // flushes are written to disk, for when flushes can't keep up. Disabled when unset
+    pub write_throttle_mb: Option<usize>,
+    // how big the memtable can get before writes are rejected with a retryable
+    // overloaded error, for when throttling isn't enough. Disabled when unset
+    pub write_reject_mb: Option<usize>,
+    // how long a memtable flush may take before it's logged as slow
+    pub slow_flush: Duration,
+    // how long a command may take before it's kept in the slow log, read with
+    // DEBUG SLOWLOG. Disabled when unset
+    pub slow_command: Option<Duration>,
+
+    // how often SSTables are compacted in the background, disabled when unset or 0
+    pub compaction_interval: Option<Duration>,
+    // how long the store must go without a write before a scheduled compaction runs,
+    // it's postponed until then so it doesn't compete with the writes for the disk
+    pub compaction_quiet_period: Duration,
+    // how many SSTables may pile up before a memtable flush starts a full
+    // compaction, only compacted on the interval when unset
+    pub compaction_trigger: Option<usize>,
+    // how many compactions may run at once, each merging a different run of
+    // similarly sized SSTables. Above 1, scheduled compactions merge those runs
+    // concurrently instead of merging every SSTable into one
+    pub compaction_parallelism: usize,
+    // how many SSTables may be read at once, each holding a file open, with reads
+    // beyond it waiting their turn. Unlimited when unset
+    pub max_disk_reads: Option<usize>,
+
+    // file where audit records of mutating commands are appended, disabled when unset
+    pub audit_log_path: Option<PathBuf>,
+
+    // largest value a client may SET, unlimited when unset
+    pub max_value_bytes: Option<usize>,
+    // how SETs larger than `max_value_bytes` are handled
+    pub value_limit_policy: ValueLimitPolicy,
+
+    // most value bytes a single response may carry, unlimited when unset.
+    // Commands whose response would be larger fail with an error instead
+    pub max_response_bytes: Option<usize>,
+
+    // max transactions in flight across all sessions, unlimited when unset
+    pub max_transactions: Option<usize>,
+    // how transactions beyond `max_transactions` are handled
+    pub transaction_limit_policy: TransactionLimitPolicy,
+
+    // whether unknown commands and malformed requests close the connection, otherwise
+    // they're answered with an error (unknown commands listing the known ones), which
+    // helps when typing commands by hand
+    pub strict_protocol: bool,
+    // whether FLUSHALL may clear the store, off by default since it removes every key
+    pub flushall: bool,
+    // directory SNAPSHOT writes the store to, SNAPSHOT is disabled when unset
+    pub snapshot_dir: Option<PathBuf>,
+    // longest key, and longest value, a request may carry. Longer requests end the
+    // session as soon as their length is read, unlike `max_value_bytes`
+    pub max_key_bytes: usize,
+    pub max_request_value_bytes: usize,
+    // most bytes a request may hold outside of its keys and values, e.g. a stream of
+    // bytes without the delimiters the protocol expects, before the session is ended
+    pub max_scan_bytes: usize,
+    // most bytes of keys and values a single request may buffer together, e.g. an MSET
+    // of many values, before the session is ended
+    pub max_request_bytes: usize,
+    // shortest SET value read off the socket in chunks instead of being buffered with
+    // its request, never streamed when unset. The store still takes it whole
+    pub stream_value_bytes: Option<usize>,
+    // top-level field of JSON values that FIND looks keys up by, see `store::index`.
+    // Values aren't indexed when unset
+    pub index_json_field: Option<String>,
+    // shortest value stored compressed with lz4, see `store::compression`. Values
+    // are stored as they are when unset. The data dir records which, and the server
+    // refuses to start over it with compression turned the other way
+    pub compress_value_bytes: Option<usize>,
+
+    // whether the protocol bytes of client sessions are logged, see `WireTrace`
+    pub wire_trace: WireTrace,
 }
 impl Config {
     pub fn load() -> Self {
@@ -74,19 +350,70 @@ impl Config {
             cluster_port: env_or("CLUSTER_PORT", "7720")
                 .parse()
                 .expect("invalid port"),
+            client_tcp: env_or("CLIENT_TCP", "true")
+                .parse()
+                .expect("invalid CLIENT_TCP"),
+            client_unix_socket: get_env("CLIENT_UNIX_SOCKET").map(PathBuf::from),
+            runtime_workers: get_env("RUNTIME_WORKERS")
+                .map(|n| n.parse().expect("invalid RUNTIME_WORKERS")),
+            reuse_port: env_or("REUSE_PORT", "false")
+                .parse()
+                .expect("invalid REUSE_PORT"),
+            client_runtimes: get_env("CLIENT_RUNTIMES")
+                .map(|n| n.parse().expect("invalid CLIENT_RUNTIMES")),
+            socket_send_buffer_bytes: get_env("SOCKET_SEND_BUFFER_BYTES")
+                .map(|n| n.parse().expect("invalid SOCKET_SEND_BUFFER_BYTES")),
+            socket_recv_buffer_bytes: get_env("SOCKET_RECV_BUFFER_BYTES")
+                .map(|n| n.parse().expect("invalid SOCKET_RECV_BUFFER_BYTES")),
+            idle_timeout: get_env("IDLE_TIMEOUT_SECS")
+                .map(|secs| Duration::from_secs(secs.parse().expect("invalid IDLE_TIMEOUT_SECS"))),
+            max_connections: get_env("MAX_CONNECTIONS")
+                .map(|n| n.parse().expect("invalid MAX_CONNECTIONS")),
+            connection_limit_policy: env_or("CONNECTION_LIMIT_POLICY", "queue")
+                .parse()
+                .expect("invalid CONNECTION_LIMIT_POLICY"),
+            cluster_max_connections: get_env("CLUSTER_MAX_CONNECTIONS")
+                .map(|n| n.parse().expect("invalid CLUSTER_MAX_CONNECTIONS")),
+            drain_timeout: Duration::from_secs(
+                env_or("DRAIN_TIMEOUT_SECS", "3")
+                    .parse()
+                    .expect("invalid DRAIN_TIMEOUT_SECS"),
+            ),
+            metrics_addr: get_env("METRICS_ADDR"),
             seed_peers: env_or("SEED_PEERS", "")
                 .trim()
                 .split(',')
                 .map(String::from)
                 .collect::<Vec<String>>(),
+            leader_addr: get_env("LEADER_ADDR"),
+            replication_log_capacity: get_env("REPLICATION_LOG_CAPACITY")
+                .map(|n| n.parse().expect("invalid REPLICATION_LOG_CAPACITY")),
+            shard_nodes: env_or("SHARD_NODES", "")
+                .split(',')
+                .map(str::trim)
+                .filter(|node| !node.is_empty())
+                .map(String::from)
+                .collect(),
+            shard_node: get_env("SHARD_NODE"),
             cert_path: env_or("CERT_PATH", "certs/cert.pem"),
             key_path: env_or("KEY_PATH", "certs/key.pem"),
+            require_client_certs: env_or("REQUIRE_CLIENT_CERTS", "false")
+                .parse()
+                .expect("invalid REQUIRE_CLIENT_CERTS"),
+            client_ca_path: get_env("CLIENT_CA_PATH"),
+            node_client_cert_path: get_env("NODE_CLIENT_CERT_PATH"),
+            node_client_key_path: get_env("NODE_CLIENT_KEY_PATH"),
             log_level: env_or("LOG_LEVEL", "info"),
             log_format: env_or("LOG_FORMAT", "pretty")
                 .parse()
                 .expect("invalid LOG_FORMAT"),
             encryption_key: env_or("ENCRYPTION_KEY", "01234567890123456789012345678901"),
             signing_key: env_or("SIGNING_KEY", "01234567890123456789012345678901"),
+            store_backend: env_or("STORE_BACKEND", "lsm")
+                .parse()
+                .expect("invalid STORE_BACKEND"),
+            memory_max_bytes: get_env("MEMORY_MAX_BYTES")
+                .map(|n| n.parse().expect("invalid MEMORY_MAX_BYTES")),
             data_dir: match get_env("DATA_DIR") {
                 Some(dir) => PathBuf::from(dir),
                 None => std::env::temp_dir(),
@@ -95,9 +422,91 @@ impl Config {
                 Some(path) => PathBuf::from(path),
                 None => std::env::temp_dir().join("commit_log"),
             },
+            commit_log_sync_interval: match env_or("COMMIT_LOG_SYNC_MS", "100")
+                .parse()
+                .expect("invalid COMMIT_LOG_SYNC_MS")
+            {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
             memtable_max_mb: env_or("MEMTABLE_MAX_MB", "256")
                 .parse()
                 .expect("Not a number"),
+            write_throttle_mb: match env_or("WRITE_THROTTLE_MB", "1024")
+                .parse()
+                .expect("invalid WRITE_THROTTLE_MB")
+            {
+                0 => None,
+                mb => Some(mb),
+            },
+            write_reject_mb: get_env("WRITE_REJECT_MB")
+                .map(|n| n.parse().expect("invalid WRITE_REJECT_MB")),
+            slow_flush: Duration::from_millis(
+                env_or("SLOW_FLUSH_MS", "1000")
+                    .parse()
+                    .expect("invalid SLOW_FLUSH_MS"),
+            ),
+            slow_command: get_env("SLOW_COMMAND_MS")
+                .map(|ms| Duration::from_millis(ms.parse().expect("invalid SLOW_COMMAND_MS"))),
+            compaction_interval: get_env("COMPACTION_INTERVAL_SECS")
+                .map(|secs| secs.parse().expect("invalid COMPACTION_INTERVAL_SECS"))
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            compaction_quiet_period: Duration::from_millis(
+                env_or("COMPACTION_QUIET_MS", "1000")
+                    .parse()
+                    .expect("invalid COMPACTION_QUIET_MS"),
+            ),
+            compaction_trigger: get_env("COMPACTION_TRIGGER_SSTABLES")
+                .map(|n| n.parse().expect("invalid COMPACTION_TRIGGER_SSTABLES")),
+            compaction_parallelism: env_or("COMPACTION_PARALLELISM", "1")
+                .parse::<NonZeroUsize>()
+                .expect("invalid COMPACTION_PARALLELISM")
+                .get(),
+            max_disk_reads: get_env("MAX_DISK_READS")
+                .map(|n| n.parse().expect("invalid MAX_DISK_READS")),
+            audit_log_path: get_env("AUDIT_LOG_PATH").map(PathBuf::from),
+            max_value_bytes: get_env("MAX_VALUE_BYTES")
+                .map(|n| n.parse().expect("invalid MAX_VALUE_BYTES")),
+            value_limit_policy: env_or("VALUE_LIMIT_POLICY", "reject")
+                .parse()
+                .expect("invalid VALUE_LIMIT_POLICY"),
+            max_response_bytes: get_env("MAX_RESPONSE_BYTES")
+                .map(|n| n.parse().expect("invalid MAX_RESPONSE_BYTES")),
+            max_transactions: get_env("MAX_TRANSACTIONS")
+                .map(|n| n.parse().expect("invalid MAX_TRANSACTIONS")),
+            transaction_limit_policy: env_or("TRANSACTION_LIMIT_POLICY", "queue")
+                .parse()
+                .expect("invalid TRANSACTION_LIMIT_POLICY"),
+            strict_protocol: env_or("STRICT_PROTOCOL", "true")
+                .parse()
+                .expect("invalid STRICT_PROTOCOL"),
+            flushall: env_or("ENABLE_FLUSHALL", "false")
+                .parse()
+                .expect("invalid ENABLE_FLUSHALL"),
+            snapshot_dir: get_env("SNAPSHOT_DIR").map(PathBuf::from),
+            max_key_bytes: get_env("MAX_KEY_BYTES").map_or(proto::DEFAULT_MAX_KEY_LEN, |n| {
+                n.parse().expect("invalid MAX_KEY_BYTES")
+            }),
+            max_request_value_bytes: get_env("MAX_REQUEST_VALUE_BYTES")
+                .map_or(proto::DEFAULT_MAX_VALUE_LEN, |n| {
+                    n.parse().expect("invalid MAX_REQUEST_VALUE_BYTES")
+                }),
+            max_scan_bytes: get_env("MAX_SCAN_BYTES").map_or(proto::DEFAULT_MAX_SCAN_LEN, |n| {
+                n.parse().expect("invalid MAX_SCAN_BYTES")
+            }),
+            max_request_bytes: get_env("MAX_REQUEST_BYTES")
+                .map_or(proto::DEFAULT_MAX_REQUEST_LEN, |n| {
+                    n.parse().expect("invalid MAX_REQUEST_BYTES")
+                }),
+            stream_value_bytes: get_env("STREAM_VALUE_BYTES")
+                .map(|n| n.parse().expect("invalid STREAM_VALUE_BYTES")),
+            index_json_field: get_env("INDEX_JSON_FIELD"),
+            compress_value_bytes: get_env("COMPRESS_VALUE_BYTES")
+                .map(|n| n.parse().expect("invalid COMPRESS_VALUE_BYTES")),
+            wire_trace: env_or("WIRE_TRACE", "off")
+                .parse()
+                .expect("invalid WIRE_TRACE"),
         }
     }
     pub fn get_cluster_addr(&self) -> String {
@@ -106,4 +515,80 @@ impl Config {
     pub fn get_client_addr(&self) -> String {
         format!("{}:{}", self.client_host, self.client_port)
     }
+    /// Runtimes the client-server accepts connections on: one unless `reuse_port` is
+    /// set, then `client_runtimes`, or one per core
+    pub fn get_client_runtimes(&self) -> usize {
+        match (self.reuse_port, self.client_runtimes) {
+            (false, _) => 1,
+            (true, Some(runtimes)) => runtimes,
+            (true, None) => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        }
+    }
+}
+
+/// Server settings read from a TOML file, see `Server::from_config`. Everything the
+/// file leaves out, e.g. the LSM store's tuning, is still configured by `Config`.
+///
+/// ```toml
+/// cluster_addr = "0.0.0.0:7720"
+/// client_addr = "0.0.0.0:7719"
+/// cert_path = "certs/cert.pem"
+/// key_path = "certs/key.pem"
+/// require_client_certs = true
+/// client_ca_path = "certs/client-ca.pem"
+/// node_client_cert_path = "certs/node-cert.pem"
+/// node_client_key_path = "certs/node-key.pem"
+/// max_connections = 1024
+/// connection_limit_policy = "reject"
+/// cluster_max_connections = 64
+/// store_backend = "lsm"
+/// data_dir = "/var/lib/kave"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    // addresses (host:port) to listen on for cluster and client requests
+    pub cluster_addr: String,
+    // the client-server isn't started when unset
+    pub client_addr: Option<String>,
+
+    // files containing the certificates and private key the server uses for ssl
+    pub cert_path: String,
+    pub key_path: String,
+    // whether clients must present a cert issued by one of the CAs in `client_ca_path`
+    #[serde(default)]
+    pub require_client_certs: bool,
+    pub client_ca_path: Option<String>,
+    // files containing the cert and private key presented when connecting to other nodes
+    pub node_client_cert_path: Option<String>,
+    pub node_client_key_path: Option<String>,
+
+    // most client connections open at once, unlimited when unset
+    pub max_connections: Option<usize>,
+    // how connections beyond `max_connections` are handled
+    #[serde(default)]
+    pub connection_limit_policy: ConnectionLimitPolicy,
+    // most cluster connections open at once, unlimited when unset
+    pub cluster_max_connections: Option<usize>,
+
+    // which store data is kept in, defaults to the lsm store
+    #[serde(default)]
+    pub store_backend: StoreKind,
+    // directory where the lsm store's data files are stored, `Config::data_dir` when unset
+    pub data_dir: Option<PathBuf>,
+}
+impl ServerConfig {
+    /// Read the settings in the TOML file at `path`, failing on any setting it
+    /// doesn't know and any required one that's missing
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let toml = std::fs::read_to_string(path)
+            .map_err(|e| format!("error reading server config {path:?}: {e}"))?;
+        Self::parse(&toml).map_err(|e| format!("invalid server config {path:?}: {e}").into())
+    }
+
+    /// Parse settings written as TOML, see `load`
+    pub fn parse(toml: &str) -> Result<Self, Error> {
+        toml::from_str(toml).map_err(|e| Error::from(e.to_string()))
+    }
 }
@@ -4,7 +4,7 @@ pub enum Error {
     E(String),
 
     #[error("io error: {0}")]
-    IO(#[from] std::io::Error),
+    IO(std::io::Error),
 
     #[error("parseint error: {0}")]
     ParseIntError(#[from] std::num::ParseIntError),
@@ -20,6 +20,44 @@ pub enum Error {
 
     #[error("failure resolving dns for: {0}")]
     DnsResolutionFailure(String),
+
+    // a value (or argument) didn't suit a transform, the value is left untouched
+    #[error("{0}")]
+    Transform(String),
+
+    // the store is under too much write pressure to take the write, which may be
+    // retried once it catches up. Sent to clients as `proto::OVERLOADED`
+    #[error("overloaded: {0}")]
+    Overloaded(String),
+
+    // a request didn't follow the protocol, e.g. an unknown op or a malformed length.
+    // `op` is the command it was for, `None` when it failed before its name was read.
+    // The reason is sent to the client, and ends the session when the proto is strict
+    #[error("{reason}")]
+    Protocol { op: Option<String>, reason: String },
+
+    // a request's argument was longer than `ProtoLimits` allow. It's refused once its
+    // length is read, so the rest of the request is never read and the session ends
+    #[error("limit exceeded: {0}")]
+    LimitExceeded(String),
+
+    // a request didn't match the checksum it was sent with, so none of it can be
+    // trusted, including the lengths framing it. The session ends after it's refused
+    #[error("frame corrupted: {0}")]
+    FrameCorrupted(String),
+
+    // the disk is out of space, nothing was written. The store stays readable and
+    // takes writes again once space is freed. Sent to clients as `proto::DISK_FULL`
+    #[error("disk full: {0}")]
+    DiskFull(String),
+}
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        match e.kind() {
+            std::io::ErrorKind::StorageFull => Error::DiskFull(e.to_string()),
+            _ => Error::IO(e),
+        }
+    }
 }
 impl From<&str> for Error {
     fn from(s: &str) -> Error {
@@ -2,6 +2,7 @@
 #[macro_use]
 pub mod utils;
 
+pub mod audit;
 pub mod client;
 pub mod config;
 pub mod crypto;
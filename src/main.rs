@@ -1,13 +1,17 @@
 use kave::{
-    config::LogFormat,
+    config::{LogFormat, StoreKind},
     get_config,
-    server::{load_certs, load_keys, Server},
-    Result,
+    server::{load_certs, load_keys, validate_tls, Server},
+    store::{
+        backend::StoreBackend,
+        compression::{self, Codec, Lz4},
+        replication::ReplicationLog,
+        Store,
+    },
+    Config, Result,
 };
 
 async fn run() -> Result<()> {
-    setup()?;
-
     let config = get_config();
     tracing::info!(
         "loading ssl certificates: {}, {}",
@@ -18,15 +22,53 @@ async fn run() -> Result<()> {
     let certs = load_certs(&config.cert_path)?;
     let keys = load_keys(&config.key_path)?;
     tracing::debug!("found {} certs, {} keys", certs.len(), keys.len());
+    validate_tls(&certs, &keys)?;
 
     tracing::info!("initializing");
     let (svr_shutdown_send, mut svr_shutdown_recv) = tokio::sync::mpsc::unbounded_channel();
     let (sig_shutdown_send, sig_shutdown_recv) = tokio::sync::mpsc::unbounded_channel();
     let (store_shutdown_send, store_shutdown_recv) = tokio::sync::mpsc::unbounded_channel();
 
-    let store =
-        kave::store::lsm::LSMStore::initialize_from_config(&config, store_shutdown_recv).await?;
-    let svr = Server::new(svr_shutdown_send, sig_shutdown_recv, certs, keys, store);
+    let mut store = StoreBackend::from_config(&config)
+        .build(store_shutdown_recv)
+        .await?;
+    if config.store_backend == StoreKind::Lsm {
+        // values stored one way read as garbage the other
+        let codec = config.compress_value_bytes.map(|_| &Lz4 as &dyn Codec);
+        let empty = store.scan_keys(b"", None, 1).await?.is_empty();
+        compression::check_format(&config.data_dir, codec, empty).await?;
+    }
+    let store = match config.compress_value_bytes {
+        Some(threshold) => {
+            tracing::info!("compressing values of at least {threshold} bytes");
+            store.compressed(Lz4, threshold)
+        }
+        None => store,
+    };
+    #[cfg(feature = "index")]
+    let store = match &config.index_json_field {
+        Some(field) => {
+            tracing::info!("indexing values by their {field:?} field");
+            store.indexed(kave::store::index::json_field(field)).await?
+        }
+        None => store,
+    };
+    #[cfg(not(feature = "index"))]
+    if config.index_json_field.is_some() {
+        return Err("INDEX_JSON_FIELD requires the `index` feature".into());
+    }
+    let replication = config.replication_log_capacity.map(ReplicationLog::new);
+    let store = match &replication {
+        Some(log) => {
+            tracing::info!("replicating writes to followers");
+            store.replicated(log.clone())
+        }
+        None => store,
+    };
+    let mut svr = Server::new(svr_shutdown_send, sig_shutdown_recv, certs, keys, store);
+    svr.set_replication_log(replication);
+    #[cfg(unix)]
+    reload_certs_on_hangup(svr.tls(), config.clone())?;
     tokio::spawn(async move { svr.start().await });
     tracing::info!("server spawned");
 
@@ -65,14 +107,55 @@ async fn run() -> Result<()> {
     Ok(())
 }
 
-#[tokio::main(flavor = "multi_thread")]
-async fn main() {
-    if let Err(e) = run().await {
+/// Reload the certs and key from their configured paths on every SIGHUP, so they
+/// can be rotated without a restart. Invalid ones are logged and the current ones kept
+#[cfg(unix)]
+fn reload_certs_on_hangup(tls: kave::server::TlsCerts, config: Config) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            tracing::info!(
+                "reloading ssl certificates: {}, {}",
+                config.cert_path,
+                config.key_path
+            );
+            if let Err(e) = tls.reload(&config.cert_path, &config.key_path) {
+                tracing::error!("error reloading ssl certificates, keeping the current ones: {e}");
+            }
+        }
+    });
+    Ok(())
+}
+
+fn main() {
+    // setup happens before building the runtime so that
+    // the runtime can be configured from the loaded config
+    let res = setup().and_then(|_| {
+        let runtime = build_runtime(&get_config())?;
+        runtime.block_on(run())
+    });
+    if let Err(e) = res {
         eprintln!("Error: {e}");
         std::process::exit(1);
     }
 }
 
+fn build_runtime(config: &Config) -> Result<tokio::runtime::Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(workers) = config.runtime_workers {
+        if workers == 0 {
+            return Err("RUNTIME_WORKERS must be greater than 0".into());
+        }
+        builder.worker_threads(workers);
+    }
+    if config.client_runtimes == Some(0) {
+        return Err("CLIENT_RUNTIMES must be greater than 0".into());
+    }
+    Ok(builder.build()?)
+}
+
 fn setup() -> Result<()> {
     // parse cli args
     let matches = build_app().get_matches();
@@ -85,7 +168,7 @@ fn setup() -> Result<()> {
         (Some(path.to_owned()), r.err())
     } else {
         let r = dotenv::dotenv();
-        (r.as_ref().ok().map(std::clone::Clone::clone), r.err())
+        (r.as_ref().ok().cloned(), r.err())
     };
 
     let config = get_config();
@@ -1,9 +1,16 @@
-use crate::error::Result;
+use crate::config::WireTrace;
+use crate::error::{Error, Result};
+use crate::store::Durability;
 use bytes::Buf;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
-use tokio::net::TcpStream;
+use std::borrow::Cow;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf};
 use tokio::sync::broadcast::Receiver;
-use tokio_rustls::server::TlsStream;
 
 macro_rules! write_stream_buf {
     ($id:expr, $writer:expr, $buf:expr, $addr:expr) => {
@@ -11,7 +18,10 @@ macro_rules! write_stream_buf {
         $writer
             .write_all_buf(&mut $buf)
             .await
-            .map_err(|e| format!("session={id} error writing to socket: {e}", id = $id))?;
+            .map_err(|e| {
+                let msg = format!("session={id} error writing to socket: {e}", id = $id);
+                std::io::Error::new(e.kind(), msg)
+            })?;
         tracing::debug!(
             session = %$id,
             "wrote {n} bytes to {peer_addr:?}",
@@ -26,7 +36,10 @@ macro_rules! flush_stream {
         $writer
             .flush()
             .await
-            .map_err(|e| format!("session={id} error flushing stream: {e}", id = $id))?;
+            .map_err(|e| {
+                let msg = format!("session={id} error flushing stream: {e}", id = $id);
+                std::io::Error::new(e.kind(), msg)
+            })?;
         tracing::debug!(
             session = %$id,
             "flushed stream to {peer_addr:?}",
@@ -37,205 +50,1968 @@ macro_rules! flush_stream {
 
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub enum ProtoOp {
-    Get { key: String },
-    Set { key: String, value: Vec<u8> },
-    Echo { msg: Vec<u8> },
+    Get {
+        key: Vec<u8>,
+    },
+    // reads every key at a single point in time
+    MGet {
+        keys: Vec<Vec<u8>>,
+    },
+    // sets every key in a single transaction, so either all of them are written or none
+    MSet {
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    },
+    // `noreply` writes are applied without sending a result back
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        noreply: bool,
+        // how durable the write must be before it's acknowledged, the server's default when unset
+        durability: Option<Durability>,
+    },
+    // a SET whose `len` byte value is left on the socket, for the caller to stream with
+    // `Proto::value_reader` rather than buffer along with the op, see `Proto::set_stream_values`.
+    // `value` is what the caller has read of it, and it's always empty when read.
+    // The durability argument following the value is only read by `Proto::read_value_end`
+    SetStream {
+        key: Vec<u8>,
+        len: usize,
+        value: Vec<u8>,
+        noreply: bool,
+        durability: Option<Durability>,
+    },
+    Del {
+        key: Vec<u8>,
+        noreply: bool,
+    },
+    Strlen {
+        key: Vec<u8>,
+    },
+    Exists {
+        key: Vec<u8>,
+    },
+    // returns the value along with its version, see `store::version`
+    GetVersioned {
+        key: Vec<u8>,
+    },
+    // sets the value only if it's still at `version`, for optimistic updates after GETV
+    SetIfVersion {
+        key: Vec<u8>,
+        version: String,
+        value: Vec<u8>,
+    },
+    // sets the value only if it's currently `expected`
+    Cas {
+        key: Vec<u8>,
+        expected: Vec<u8>,
+        value: Vec<u8>,
+    },
+    // sets the value and returns the one it replaced
+    GetSet {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    SetRange {
+        key: Vec<u8>,
+        offset: usize,
+        value: Vec<u8>,
+    },
+    Swap {
+        a: Vec<u8>,
+        b: Vec<u8>,
+    },
+    // expires the key after `secs`, until it's SET again
+    Expire {
+        key: Vec<u8>,
+        secs: u64,
+    },
+    // `transform` names one of the store's built-in transforms
+    Apply {
+        key: Vec<u8>,
+        transform: String,
+        arg: Vec<u8>,
+    },
+    // the keys whose values are indexed under `attr`, see `store::index`
+    Find {
+        attr: Vec<u8>,
+    },
+    // up to `limit` keys and values from `start`, and before `end` when there is one
+    Scan {
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: usize,
+    },
+    // up to `count` keys from `cursor`, the first key the previous batch left out,
+    // or from the first key without one
+    ScanCursor {
+        cursor: Option<Vec<u8>>,
+        count: usize,
+    },
+    // removes every key of every namespace, when the server allows it
+    FlushAll,
+    // writes every key of every namespace to `name` in the server's snapshot directory,
+    // when it has one and `name` isn't there yet. Refused in namespaced sessions
+    Snapshot {
+        name: String,
+    },
+    // SETs and DELs after BEGIN are queued by the session, and applied
+    // as a single transaction on COMMIT or dropped on DISCARD
+    Begin,
+    Commit,
+    Discard,
+    #[cfg(feature = "hash")]
+    HSet {
+        key: Vec<u8>,
+        field: String,
+        value: Vec<u8>,
+    },
+    #[cfg(feature = "hash")]
+    HGet {
+        key: Vec<u8>,
+        field: String,
+    },
+    // `by` is the increment as sent, parsed when the op is handled
+    #[cfg(feature = "hash")]
+    HIncr {
+        key: Vec<u8>,
+        field: String,
+        by: Vec<u8>,
+    },
+    #[cfg(feature = "hash")]
+    HGetAll {
+        key: Vec<u8>,
+    },
+    // optionally switches the encoding of the values in the session's responses,
+    // or turns on frame checksums
+    Hello {
+        option: Option<String>,
+    },
+    Time,
+    // lists every command the session accepts with its arity
+    Command,
+    // whether the server is ready to serve the store, it may still be recovering
+    Healthz,
+    // ends the session once acknowledged, anything sent after it is discarded
+    Quit,
+    // an empty namespace switches back to the default, un-prefixed keyspace
+    Use {
+        namespace: String,
+    },
+    Echo {
+        msg: Vec<u8>,
+    },
+    // answered with PONG, or with the payload when one is sent
+    Ping {
+        payload: Option<Vec<u8>>,
+    },
+    // waits up to `timeout_ms` for `replicas` replicas to acknowledge the session's writes
+    WaitRepl {
+        replicas: usize,
+        timeout_ms: u64,
+    },
+    DebugSleep {
+        ms: u64,
+    },
+    // returns the entries of one of the server's in-memory logs, or clears it
+    DebugLog {
+        log: DebugLog,
+        reset: bool,
+    },
+    // a command added by the session's `CommandHandler`, with its raw arguments
+    Custom {
+        name: &'static str,
+        args: Vec<Vec<u8>>,
+    },
+    // an unrecognized op, only produced when the proto isn't strict
+    Unknown {
+        name: String,
+    },
+    // a malformed request, e.g. a bad length or separator, only produced when the
+    // proto isn't strict. The rest of its line is skipped when reading the next op
+    Invalid {
+        reason: String,
+    },
+    // the client closed the connection cleanly, with a TLS close_notify
     SysClose,
+    // the connection went away without a close_notify, e.g. a TCP reset
+    // or a client that exited without shutting down its TLS session
+    Reset,
     Cancelled,
+    // nothing was read for longer than the proto's idle timeout
+    IdleTimeout,
+}
+
+impl ProtoOp {
+    /// The command's name, `None` for ops that aren't client commands
+    pub fn name(&self) -> Option<&'static str> {
+        let name = match self {
+            ProtoOp::Get { .. } => "GET",
+            ProtoOp::MGet { .. } => "MGET",
+            ProtoOp::MSet { .. } => "MSET",
+            ProtoOp::Set { noreply: false, .. } => "SET",
+            ProtoOp::Set { noreply: true, .. } => "SETQ",
+            ProtoOp::SetStream { noreply: false, .. } => "SET",
+            ProtoOp::SetStream { noreply: true, .. } => "SETQ",
+            ProtoOp::Del { noreply: false, .. } => "DEL",
+            ProtoOp::Del { noreply: true, .. } => "DELQ",
+            ProtoOp::Strlen { .. } => "STRLEN",
+            ProtoOp::Exists { .. } => "EXISTS",
+            ProtoOp::GetVersioned { .. } => "GETV",
+            ProtoOp::SetIfVersion { .. } => "CASV",
+            ProtoOp::Cas { .. } => "CAS",
+            ProtoOp::GetSet { .. } => "GETSET",
+            ProtoOp::SetRange { .. } => "SETRANGE",
+            ProtoOp::Swap { .. } => "SWAP",
+            ProtoOp::Expire { .. } => "EXPIRE",
+            ProtoOp::Apply { .. } => "APPLY",
+            ProtoOp::Find { .. } => "FIND",
+            ProtoOp::Scan { .. } | ProtoOp::ScanCursor { .. } => "SCAN",
+            ProtoOp::FlushAll => "FLUSHALL",
+            ProtoOp::Snapshot { .. } => "SNAPSHOT",
+            ProtoOp::Begin => "BEGIN",
+            ProtoOp::Commit => "COMMIT",
+            ProtoOp::Discard => "DISCARD",
+            #[cfg(feature = "hash")]
+            ProtoOp::HSet { .. } => "HSET",
+            #[cfg(feature = "hash")]
+            ProtoOp::HGet { .. } => "HGET",
+            #[cfg(feature = "hash")]
+            ProtoOp::HIncr { .. } => "HINCR",
+            #[cfg(feature = "hash")]
+            ProtoOp::HGetAll { .. } => "HGETALL",
+            ProtoOp::Hello { .. } => "HELLO",
+            ProtoOp::Use { .. } => "USE",
+            ProtoOp::Time => "TIME",
+            ProtoOp::Command => "COMMAND",
+            ProtoOp::Healthz => "HEALTHZ",
+            ProtoOp::Quit => "QUIT",
+            ProtoOp::WaitRepl { .. } => "WAITREPL",
+            ProtoOp::Echo { .. } => "ECHO",
+            ProtoOp::Ping { .. } => "PING",
+            ProtoOp::DebugSleep { .. } | ProtoOp::DebugLog { .. } => "DEBUG",
+            ProtoOp::Custom { name, .. } => name,
+            ProtoOp::Unknown { .. }
+            | ProtoOp::Invalid { .. }
+            | ProtoOp::SysClose
+            | ProtoOp::Reset
+            | ProtoOp::Cancelled
+            | ProtoOp::IdleTimeout => return None,
+        };
+        Some(name)
+    }
+
+    /// Rewrite every key the op refers to with `f`
+    pub fn map_keys<F: Fn(Vec<u8>) -> Vec<u8>>(self, f: F) -> Self {
+        match self {
+            ProtoOp::Get { key } => ProtoOp::Get { key: f(key) },
+            ProtoOp::MGet { keys } => ProtoOp::MGet {
+                keys: keys.into_iter().map(&f).collect(),
+            },
+            ProtoOp::MSet { pairs } => ProtoOp::MSet {
+                pairs: pairs.into_iter().map(|(k, v)| (f(k), v)).collect(),
+            },
+            ProtoOp::Set {
+                key,
+                value,
+                noreply,
+                durability,
+            } => ProtoOp::Set {
+                key: f(key),
+                value,
+                noreply,
+                durability,
+            },
+            ProtoOp::SetStream {
+                key,
+                len,
+                value,
+                noreply,
+                durability,
+            } => ProtoOp::SetStream {
+                key: f(key),
+                len,
+                value,
+                noreply,
+                durability,
+            },
+            ProtoOp::Del { key, noreply } => ProtoOp::Del {
+                key: f(key),
+                noreply,
+            },
+            ProtoOp::Strlen { key } => ProtoOp::Strlen { key: f(key) },
+            ProtoOp::Exists { key } => ProtoOp::Exists { key: f(key) },
+            ProtoOp::GetVersioned { key } => ProtoOp::GetVersioned { key: f(key) },
+            ProtoOp::SetIfVersion {
+                key,
+                version,
+                value,
+            } => ProtoOp::SetIfVersion {
+                key: f(key),
+                version,
+                value,
+            },
+            ProtoOp::Cas {
+                key,
+                expected,
+                value,
+            } => ProtoOp::Cas {
+                key: f(key),
+                expected,
+                value,
+            },
+            ProtoOp::GetSet { key, value } => ProtoOp::GetSet { key: f(key), value },
+            ProtoOp::SetRange { key, offset, value } => ProtoOp::SetRange {
+                key: f(key),
+                offset,
+                value,
+            },
+            ProtoOp::Swap { a, b } => ProtoOp::Swap { a: f(a), b: f(b) },
+            ProtoOp::Expire { key, secs } => ProtoOp::Expire { key: f(key), secs },
+            ProtoOp::Scan { start, end, limit } => ProtoOp::Scan {
+                start: f(start),
+                end: end.map(&f),
+                limit,
+            },
+            ProtoOp::ScanCursor { cursor, count } => ProtoOp::ScanCursor {
+                cursor: cursor.map(&f),
+                count,
+            },
+            ProtoOp::Apply {
+                key,
+                transform,
+                arg,
+            } => ProtoOp::Apply {
+                key: f(key),
+                transform,
+                arg,
+            },
+            #[cfg(feature = "hash")]
+            ProtoOp::HSet { key, field, value } => ProtoOp::HSet {
+                key: f(key),
+                field,
+                value,
+            },
+            #[cfg(feature = "hash")]
+            ProtoOp::HGet { key, field } => ProtoOp::HGet { key: f(key), field },
+            #[cfg(feature = "hash")]
+            ProtoOp::HIncr { key, field, by } => ProtoOp::HIncr {
+                key: f(key),
+                field,
+                by,
+            },
+            #[cfg(feature = "hash")]
+            ProtoOp::HGetAll { key } => ProtoOp::HGetAll { key: f(key) },
+            op => op,
+        }
+    }
+
+    /// The keys the op refers to, the ones `map_keys` maps
+    pub fn keys(&self) -> Vec<&[u8]> {
+        match self {
+            ProtoOp::Get { key }
+            | ProtoOp::Set { key, .. }
+            | ProtoOp::SetStream { key, .. }
+            | ProtoOp::Del { key, .. }
+            | ProtoOp::Strlen { key }
+            | ProtoOp::Exists { key }
+            | ProtoOp::GetVersioned { key }
+            | ProtoOp::SetIfVersion { key, .. }
+            | ProtoOp::Cas { key, .. }
+            | ProtoOp::GetSet { key, .. }
+            | ProtoOp::SetRange { key, .. }
+            | ProtoOp::Expire { key, .. }
+            | ProtoOp::Apply { key, .. } => vec![key],
+            #[cfg(feature = "hash")]
+            ProtoOp::HSet { key, .. }
+            | ProtoOp::HGet { key, .. }
+            | ProtoOp::HIncr { key, .. }
+            | ProtoOp::HGetAll { key } => vec![key],
+            ProtoOp::MGet { keys } => keys.iter().map(Vec::as_slice).collect(),
+            ProtoOp::MSet { pairs } => pairs.iter().map(|(k, _)| k.as_slice()).collect(),
+            ProtoOp::Swap { a, b } => vec![a, b],
+            ProtoOp::Scan { start, end, .. } => std::iter::once(start)
+                .chain(end)
+                .map(Vec::as_slice)
+                .collect(),
+            ProtoOp::ScanCursor { cursor, .. } => cursor.iter().map(Vec::as_slice).collect(),
+            _ => vec![],
+        }
+    }
+
+    /// The request an op on a single key is sent as, for forwarding it to the node
+    /// that owns the key, see `server::shards`. Writes that don't reply are sent as the
+    /// ones that do, so the forwarding node learns how they went. `None` for ops on no
+    /// key or several keys.
+    pub fn request(&self) -> Option<Vec<u8>> {
+        // arguments that aren't bytes already, sent as they're written
+        let number;
+        let durability;
+        let (name, args): (&str, Vec<&[u8]>) = match self {
+            ProtoOp::Get { key } => ("GET", vec![key]),
+            ProtoOp::Set {
+                key,
+                value,
+                durability: level,
+                ..
+            }
+            | ProtoOp::SetStream {
+                key,
+                value,
+                durability: level,
+                ..
+            } => {
+                durability = level.map(|level| level.name());
+                let mut args = vec![key.as_slice(), value];
+                args.extend(durability.map(str::as_bytes));
+                ("SET", args)
+            }
+            ProtoOp::Del { key, .. } => ("DEL", vec![key]),
+            ProtoOp::Strlen { key } => ("STRLEN", vec![key]),
+            ProtoOp::Exists { key } => ("EXISTS", vec![key]),
+            ProtoOp::GetVersioned { key } => ("GETV", vec![key]),
+            ProtoOp::SetIfVersion {
+                key,
+                version,
+                value,
+            } => ("CASV", vec![key, version.as_bytes(), value]),
+            ProtoOp::Cas {
+                key,
+                expected,
+                value,
+            } => ("CAS", vec![key, expected, value]),
+            ProtoOp::GetSet { key, value } => ("GETSET", vec![key, value]),
+            ProtoOp::SetRange {
+                key,
+                offset: at,
+                value,
+            } => {
+                number = at.to_string();
+                ("SETRANGE", vec![key, number.as_bytes(), value])
+            }
+            ProtoOp::Expire { key, secs } => {
+                number = secs.to_string();
+                ("EXPIRE", vec![key, number.as_bytes()])
+            }
+            ProtoOp::Apply {
+                key,
+                transform,
+                arg,
+            } => ("APPLY", vec![key, transform.as_bytes(), arg]),
+            #[cfg(feature = "hash")]
+            ProtoOp::HSet { key, field, value } => ("HSET", vec![key, field.as_bytes(), value]),
+            #[cfg(feature = "hash")]
+            ProtoOp::HGet { key, field } => ("HGET", vec![key, field.as_bytes()]),
+            #[cfg(feature = "hash")]
+            ProtoOp::HIncr { key, field, by } => ("HINCR", vec![key, field.as_bytes(), by]),
+            #[cfg(feature = "hash")]
+            ProtoOp::HGetAll { key } => ("HGETALL", vec![key]),
+            _ => return None,
+        };
+        Some(request(name, &args))
+    }
+
+    /// Total length of the keys the op refers to
+    pub fn key_len(&self) -> usize {
+        match self {
+            ProtoOp::Get { key }
+            | ProtoOp::Set { key, .. }
+            | ProtoOp::SetStream { key, .. }
+            | ProtoOp::Del { key, .. }
+            | ProtoOp::Strlen { key }
+            | ProtoOp::Exists { key }
+            | ProtoOp::GetVersioned { key }
+            | ProtoOp::SetIfVersion { key, .. }
+            | ProtoOp::Cas { key, .. }
+            | ProtoOp::GetSet { key, .. }
+            | ProtoOp::SetRange { key, .. }
+            | ProtoOp::Expire { key, .. }
+            | ProtoOp::Apply { key, .. } => key.len(),
+            #[cfg(feature = "hash")]
+            ProtoOp::HSet { key, .. }
+            | ProtoOp::HGet { key, .. }
+            | ProtoOp::HIncr { key, .. }
+            | ProtoOp::HGetAll { key } => key.len(),
+            ProtoOp::MGet { keys } => keys.iter().map(Vec::len).sum(),
+            ProtoOp::MSet { pairs } => pairs.iter().map(|(k, _)| k.len()).sum(),
+            ProtoOp::Swap { a, b } => a.len() + b.len(),
+            ProtoOp::Scan { start, end, .. } => start.len() + end.as_ref().map_or(0, Vec::len),
+            ProtoOp::ScanCursor { cursor, .. } => cursor.as_ref().map_or(0, Vec::len),
+            _ => 0,
+        }
+    }
+
+    /// Total length of the values the op sends to be written or echoed
+    pub fn value_len(&self) -> usize {
+        match self {
+            ProtoOp::Set { value, .. }
+            | ProtoOp::SetIfVersion { value, .. }
+            | ProtoOp::Cas { value, .. }
+            | ProtoOp::GetSet { value, .. }
+            | ProtoOp::SetRange { value, .. } => value.len(),
+            #[cfg(feature = "hash")]
+            ProtoOp::HSet { value, .. } => value.len(),
+            ProtoOp::SetStream { len, .. } => *len,
+            ProtoOp::MSet { pairs } => pairs.iter().map(|(_, v)| v.len()).sum(),
+            ProtoOp::Echo { msg } => msg.len(),
+            ProtoOp::Ping { payload } => payload.as_ref().map_or(0, Vec::len),
+            _ => 0,
+        }
+    }
+
+    /// Whether the op reads or writes the store, custom commands included
+    /// since their handlers may do either
+    pub fn uses_store(&self) -> bool {
+        match self {
+            ProtoOp::Get { .. }
+            | ProtoOp::MGet { .. }
+            | ProtoOp::Strlen { .. }
+            | ProtoOp::Exists { .. }
+            | ProtoOp::GetVersioned { .. }
+            | ProtoOp::Find { .. }
+            | ProtoOp::Scan { .. }
+            | ProtoOp::ScanCursor { .. }
+            | ProtoOp::Snapshot { .. } => true,
+            #[cfg(feature = "hash")]
+            ProtoOp::HGet { .. } | ProtoOp::HGetAll { .. } => true,
+            ProtoOp::Custom { .. } => true,
+            op => op.is_transaction(),
+        }
+    }
+
+    /// Whether the op writes to the store through a transaction
+    pub fn is_transaction(&self) -> bool {
+        match self {
+            ProtoOp::Set { .. }
+            | ProtoOp::SetStream { .. }
+            | ProtoOp::MSet { .. }
+            | ProtoOp::Del { .. }
+            | ProtoOp::SetRange { .. }
+            | ProtoOp::Swap { .. }
+            | ProtoOp::Expire { .. }
+            | ProtoOp::Apply { .. }
+            | ProtoOp::SetIfVersion { .. }
+            | ProtoOp::Cas { .. }
+            | ProtoOp::GetSet { .. }
+            | ProtoOp::FlushAll
+            | ProtoOp::Commit => true,
+            #[cfg(feature = "hash")]
+            ProtoOp::HSet { .. } | ProtoOp::HIncr { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// Signals broadcast to sessions when the server shuts down, in this order
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shutdown {
+    // finish reading the request that's been started, then end the session
+    Drain,
+    // end the session right away, even partway through a request
+    Kill,
 }
 
 #[derive(Clone, Eq, PartialEq, Debug)]
 enum ProtoRead {
     Read(usize),
+    // the client closed the connection cleanly, with a TLS close_notify
     Eof,
+    // the connection went away without a close_notify
+    Reset,
     Cancelled,
+    // nothing arrived within the idle timeout
+    Timeout,
+}
+
+/// The server's in-memory logs DEBUG commands read and clear, see `server::diagnostics`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugLog {
+    // error responses written to clients
+    Errors,
+    // commands that took longer than the slow command threshold
+    Slow,
+}
+
+/// How value bytes are written in responses. Encoded values are length-prefixed
+/// with their encoded length, so binary values can travel through line-oriented tools.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    #[default]
+    Raw,
+    Hex,
+    Base64,
+}
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Raw => "raw",
+            Encoding::Hex => "hex",
+            Encoding::Base64 => "base64",
+        }
+    }
+
+    pub fn encode<'a>(&self, data: &'a [u8]) -> Cow<'a, [u8]> {
+        match self {
+            Encoding::Raw => Cow::Borrowed(data),
+            Encoding::Hex => {
+                const DIGITS: &[u8; 16] = b"0123456789abcdef";
+                let mut hex = Vec::with_capacity(data.len() * 2);
+                for b in data {
+                    hex.push(DIGITS[(b >> 4) as usize]);
+                    hex.push(DIGITS[(b & 0xf) as usize]);
+                }
+                Cow::Owned(hex)
+            }
+            Encoding::Base64 => Cow::Owned(base64::encode(data).into_bytes()),
+        }
+    }
+}
+impl std::str::FromStr for Encoding {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Encoding> {
+        match s {
+            "" | "raw" => Ok(Encoding::Raw),
+            "hex" => Ok(Encoding::Hex),
+            "base64" => Ok(Encoding::Base64),
+            s => Err(Error::from(format!(
+                "unknown encoding {s:?}, expected one of (raw|hex|base64)"
+            ))),
+        }
+    }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+/// Longest arguments `Proto::read` accepts, alone and together. A longer one fails the
+/// read with `Error::LimitExceeded` as soon as its length prefix is read, before any of
+/// its bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtoLimits {
+    // longest key, or any other argument that isn't a value payload
+    pub max_key_len: usize,
+    // longest value payload, see `Op::is_value_arg`
+    pub max_value_len: usize,
+    // most digits in an argument's length prefix
+    pub max_len_digits: usize,
+    // most bytes a single op may spend outside of its arguments: its name, the
+    // length prefixes of its arguments, and whatever precedes the newline ending the
+    // previous op. Catches clients that never send the delimiters the parser expects
+    pub max_scan_len: usize,
+    // most bytes of arguments a single op may buffer, however many it has. Values
+    // streamed to the store, see `ProtoOp::SetStream`, aren't buffered so don't count
+    pub max_request_len: usize,
+}
+impl Default for ProtoLimits {
+    fn default() -> Self {
+        Self {
+            max_key_len: DEFAULT_MAX_KEY_LEN,
+            max_value_len: DEFAULT_MAX_VALUE_LEN,
+            max_len_digits: MAX_LEN_DIGITS,
+            max_scan_len: DEFAULT_MAX_SCAN_LEN,
+            max_request_len: DEFAULT_MAX_REQUEST_LEN,
+        }
+    }
+}
+
+/// Leads the message of errors answering writes the store was too overloaded to take,
+/// which can be retried after backing off. See `Error::Overloaded`
+pub const OVERLOADED: &str = "503";
+
+/// Leads the message of errors answering writes the store had no disk space left for.
+/// Nothing of the write was kept, so it can be retried once space is freed. See `Error::DiskFull`
+pub const DISK_FULL: &str = "507";
+
+/// Target of wire-trace events, see `WireTrace`
+pub const WIRE_TARGET: &str = "kave::wire";
+
+/// Target of the spans sessions handle each op in, which record its key and value
+/// lengths, the length of its response and how long it took, at `debug` level
+pub const OP_TARGET: &str = "kave::op";
+
+/// The HELLO option turning on frame checksums, see `Proto::set_frame_checksums`
+pub const FRAME_CHECKSUM: &str = "crc32";
+
+/// Names of every command the protocol understands
+#[cfg(not(feature = "hash"))]
+pub const COMMANDS: &[&str] = &[
+    "GET", "MGET", "GETV", "SET", "SETQ", "MSET", "CASV", "CAS", "GETSET", "SETRANGE", "DEL",
+    "DELQ", "STRLEN", "EXISTS", "SWAP", "EXPIRE", "APPLY", "FIND", "SCAN", "FLUSHALL", "SNAPSHOT",
+    "BEGIN", "COMMIT", "DISCARD", "HELLO", "USE", "TIME", "ECHO", "PING", "WAITREPL", "COMMAND",
+    "HEALTHZ", "QUIT", "DEBUG",
+];
+/// Names of every command the protocol understands
+#[cfg(feature = "hash")]
+pub const COMMANDS: &[&str] = &[
+    "GET", "MGET", "GETV", "SET", "SETQ", "MSET", "CASV", "CAS", "GETSET", "SETRANGE", "DEL",
+    "DELQ", "STRLEN", "EXISTS", "SWAP", "EXPIRE", "APPLY", "FIND", "SCAN", "FLUSHALL", "SNAPSHOT",
+    "BEGIN", "COMMIT", "DISCARD", "HSET", "HGET", "HGETALL", "HINCR", "HELLO", "USE", "TIME",
+    "ECHO", "PING", "WAITREPL", "COMMAND", "HEALTHZ", "QUIT", "DEBUG",
+];
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
 enum Op {
     Get,
+    MGet,
+    GetV,
     Set,
+    SetQ,
+    MSet,
+    CasV,
+    Cas,
+    GetSet,
+    Del,
+    DelQ,
+    Strlen,
+    Exists,
+    SetRange,
+    Swap,
+    Expire,
+    Apply,
+    Find,
+    Scan,
+    FlushAll,
+    Snapshot,
+    Begin,
+    Commit,
+    Discard,
+    #[cfg(feature = "hash")]
+    HSet,
+    #[cfg(feature = "hash")]
+    HGet,
+    #[cfg(feature = "hash")]
+    HGetAll,
+    #[cfg(feature = "hash")]
+    HIncr,
+    Hello,
+    // HELLO followed by an argument, see `Proto::read`
+    HelloWith,
+    Use,
+    Time,
     Echo,
+    Ping,
+    // PING followed by a payload, see `Proto::read`
+    PingWith,
+    WaitRepl,
+    Command,
+    Healthz,
+    Quit,
+    Debug,
+    // a command added with `Proto::set_custom_commands`
+    Custom {
+        name: &'static str,
+        arity: usize,
+    },
+}
+impl Op {
+    fn parse(name: &[u8]) -> Option<Op> {
+        match name {
+            b"GET" => Some(Op::Get),
+            b"MGET" => Some(Op::MGet),
+            b"GETV" => Some(Op::GetV),
+            b"SET" => Some(Op::Set),
+            b"SETQ" => Some(Op::SetQ),
+            b"MSET" => Some(Op::MSet),
+            b"CASV" => Some(Op::CasV),
+            b"CAS" => Some(Op::Cas),
+            b"GETSET" => Some(Op::GetSet),
+            b"DEL" => Some(Op::Del),
+            b"DELQ" => Some(Op::DelQ),
+            b"STRLEN" => Some(Op::Strlen),
+            b"EXISTS" => Some(Op::Exists),
+            b"SETRANGE" => Some(Op::SetRange),
+            b"SWAP" => Some(Op::Swap),
+            b"EXPIRE" => Some(Op::Expire),
+            b"APPLY" => Some(Op::Apply),
+            b"FIND" => Some(Op::Find),
+            b"SCAN" => Some(Op::Scan),
+            b"FLUSHALL" => Some(Op::FlushAll),
+            b"SNAPSHOT" => Some(Op::Snapshot),
+            b"BEGIN" => Some(Op::Begin),
+            b"COMMIT" => Some(Op::Commit),
+            b"DISCARD" => Some(Op::Discard),
+            #[cfg(feature = "hash")]
+            b"HSET" => Some(Op::HSet),
+            #[cfg(feature = "hash")]
+            b"HGET" => Some(Op::HGet),
+            #[cfg(feature = "hash")]
+            b"HGETALL" => Some(Op::HGetAll),
+            #[cfg(feature = "hash")]
+            b"HINCR" => Some(Op::HIncr),
+            b"HELLO" => Some(Op::Hello),
+            b"USE" => Some(Op::Use),
+            b"TIME" => Some(Op::Time),
+            b"ECHO" => Some(Op::Echo),
+            b"PING" => Some(Op::Ping),
+            b"WAITREPL" => Some(Op::WaitRepl),
+            b"COMMAND" => Some(Op::Command),
+            b"HEALTHZ" => Some(Op::Healthz),
+            b"QUIT" => Some(Op::Quit),
+            b"DEBUG" => Some(Op::Debug),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Op::Get => "GET",
+            Op::MGet => "MGET",
+            Op::GetV => "GETV",
+            Op::Set => "SET",
+            Op::SetQ => "SETQ",
+            Op::MSet => "MSET",
+            Op::CasV => "CASV",
+            Op::Cas => "CAS",
+            Op::GetSet => "GETSET",
+            Op::Del => "DEL",
+            Op::DelQ => "DELQ",
+            Op::Strlen => "STRLEN",
+            Op::Exists => "EXISTS",
+            Op::SetRange => "SETRANGE",
+            Op::Swap => "SWAP",
+            Op::Expire => "EXPIRE",
+            Op::Apply => "APPLY",
+            Op::Find => "FIND",
+            Op::Scan => "SCAN",
+            Op::FlushAll => "FLUSHALL",
+            Op::Snapshot => "SNAPSHOT",
+            Op::Begin => "BEGIN",
+            Op::Commit => "COMMIT",
+            Op::Discard => "DISCARD",
+            #[cfg(feature = "hash")]
+            Op::HSet => "HSET",
+            #[cfg(feature = "hash")]
+            Op::HGet => "HGET",
+            #[cfg(feature = "hash")]
+            Op::HGetAll => "HGETALL",
+            #[cfg(feature = "hash")]
+            Op::HIncr => "HINCR",
+            Op::Hello | Op::HelloWith => "HELLO",
+            Op::Use => "USE",
+            Op::Time => "TIME",
+            Op::Echo => "ECHO",
+            Op::Ping | Op::PingWith => "PING",
+            Op::WaitRepl => "WAITREPL",
+            Op::Command => "COMMAND",
+            Op::Healthz => "HEALTHZ",
+            Op::Quit => "QUIT",
+            Op::Debug => "DEBUG",
+            Op::Custom { name, .. } => name,
+        }
+    }
+
+    /// Whether the argument at `i` is a value payload, which may hold secrets
+    fn is_value_arg(&self, i: usize) -> bool {
+        match (self, i) {
+            (Op::Set | Op::SetQ, 1)
+            | (Op::CasV, 2)
+            | (Op::Cas, 1 | 2)
+            | (Op::GetSet, 1)
+            | (Op::SetRange, 2)
+            | (Op::Apply, 2)
+            | (Op::Find, 0)
+            | (Op::Echo, 0)
+            | (Op::PingWith, 0) => true,
+            #[cfg(feature = "hash")]
+            (Op::HSet, 2) => true,
+            // MSET's count is followed by each key and then its value
+            (Op::MSet, i) => i > 0 && i % 2 == 0,
+            // there's no telling which arguments of a custom command are values
+            (Op::Custom { .. }, _) => true,
+            _ => false,
+        }
+    }
+
+    /// The number of length-prefixed arguments following the op. The first argument of
+    /// MGET and MSET is the count of keys following it, see `Proto::read`
+    fn arity(&self) -> usize {
+        match self {
+            Op::Hello
+            | Op::Ping
+            | Op::Begin
+            | Op::Commit
+            | Op::Discard
+            | Op::Time
+            | Op::Command
+            | Op::Healthz
+            | Op::FlushAll
+            | Op::Quit => 0,
+            Op::HelloWith
+            | Op::PingWith
+            | Op::Get
+            | Op::MGet
+            | Op::MSet
+            | Op::GetV
+            | Op::Del
+            | Op::DelQ
+            | Op::Strlen
+            | Op::Exists
+            | Op::Find
+            | Op::Snapshot
+            | Op::Use
+            | Op::Echo => 1,
+            Op::Set
+            | Op::SetQ
+            | Op::GetSet
+            | Op::Swap
+            | Op::Expire
+            | Op::WaitRepl
+            | Op::Debug
+            | Op::Scan => 2,
+            Op::SetRange | Op::Apply | Op::CasV | Op::Cas => 3,
+            Op::Custom { arity, .. } => *arity,
+            #[cfg(feature = "hash")]
+            Op::HGetAll => 1,
+            #[cfg(feature = "hash")]
+            Op::HGet => 2,
+            #[cfg(feature = "hash")]
+            Op::HSet | Op::HIncr => 3,
+        }
+    }
+
+    /// The number of optional arguments that may follow the op's required ones. A
+    /// third argument to SCAN reads as a range rather than a cursor, see `Proto::read`
+    fn optional_args(&self) -> usize {
+        match self {
+            Op::Set | Op::SetQ | Op::Scan => 1,
+            _ => 0,
+        }
+    }
+
+    /// The arguments the op takes as listed by COMMAND: a count (`2`), a range
+    /// when some are optional (`2-3`), or a minimum when the count varies (`1+`)
+    fn arity_spec(&self) -> String {
+        match self {
+            // an argument following HELLO or PING reads as `HelloWith` or `PingWith`
+            Op::Hello | Op::Ping => "0-1".to_string(),
+            Op::MGet | Op::MSet => "1+".to_string(),
+            op if op.optional_args() > 0 => {
+                format!("{}-{}", op.arity(), op.arity() + op.optional_args())
+            }
+            op => op.arity().to_string(),
+        }
+    }
 }
 
 enum State {
+    // skipping what the caller didn't read of a streamed value, see `ProtoOp::SetStream`
+    SkipValue,
     Start,
     ReadOp,
-    ReadKeyLen,
-    ReadKey,
-    ReadEcho,
-    ReadValueLen,
-    ReadValue,
+    ReadArgLen,
+    ReadArg,
+    // checking whether an optional argument follows
+    MaybeArg,
+    // reading the `#` and checksum following the arguments, see `set_frame_checksums`
+    Checksum,
     Done,
 }
 
 const MIN_BUF_SIZE: usize = 4;
+// Longest op name we'll scan for before giving up on finding a delimiter
+const MAX_OP_LEN: usize = 8;
+// Digits in the longest argument length that fits in a usize
+const MAX_LEN_DIGITS: usize = 20;
+// A request's `#` and the 8 hex digits of its checksum, see `Proto::set_frame_checksums`
+const CHECKSUM_TRAILER_LEN: usize = 9;
+/// Longest key read by default, see `ProtoLimits`
+pub const DEFAULT_MAX_KEY_LEN: usize = 64 * 1024;
+/// Longest value read by default, see `ProtoLimits`
+pub const DEFAULT_MAX_VALUE_LEN: usize = 512 * 1024 * 1024;
+/// Most bytes scanned for delimiters by default, see `ProtoLimits`
+pub const DEFAULT_MAX_SCAN_LEN: usize = 1024 * 1024;
+/// Most bytes of arguments buffered for a single op by default, see `ProtoLimits`
+pub const DEFAULT_MAX_REQUEST_LEN: usize = 1024 * 1024 * 1024;
 const BUF_SIZE: usize = 256;
+// How many times larger than needed the read buffer may grow before it's shrunk
+const SHRINK_FACTOR: usize = 4;
+// the read buffer must be big enough to read the initial `Op` string
+const _: () = assert!(BUF_SIZE >= MIN_BUF_SIZE);
+/// Told of what a `Proto` reads and writes, e.g. to count a server's traffic or keep
+/// its recent errors. Each method does nothing unless it's implemented.
+pub trait ProtoObserver: Send + Sync {
+    /// `n` bytes were read from the client
+    fn read(&self, _n: usize) {}
+    /// `n` bytes were written to the client
+    fn written(&self, _n: usize) {}
+    /// The error response `msg` was written to the client of `session`
+    fn error(&self, _session: &str, _msg: &str) {}
+}
+
+// Most buffers a `BufferPool` keeps by default
+const POOLED_BUFS: usize = 1024;
+// Buffers grown past this, e.g. by large pipelined batches, are freed rather than pooled
+const MAX_POOLED_CAPACITY: usize = BUF_SIZE * 64;
 
-/// A basic wire protocol reader/writer.
-/// See `read` method below for more details.
-pub struct Proto {
+/// Read buffers recycled across sessions, so that connection churn doesn't allocate
+/// and free a buffer for every session. Clones share the same buffers.
+#[derive(Clone, Debug)]
+pub struct BufferPool {
+    bufs: Arc<Mutex<Vec<Vec<u8>>>>,
+    // most buffers kept for reuse, pooling is disabled at 0
+    max_bufs: usize,
+}
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(POOLED_BUFS)
+    }
+}
+impl BufferPool {
+    pub fn new(max_bufs: usize) -> Self {
+        Self {
+            bufs: Arc::new(Mutex::new(Vec::new())),
+            max_bufs,
+        }
+    }
+
+    /// The number of buffers waiting to be reused
+    pub fn len(&self) -> usize {
+        self.bufs.lock().expect("buffer pool lock poisoned").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Take an empty buffer, allocating one when none are pooled
+    fn take(&self) -> Vec<u8> {
+        self.bufs
+            .lock()
+            .expect("buffer pool lock poisoned")
+            .pop()
+            .unwrap_or_else(|| Vec::with_capacity(BUF_SIZE))
+    }
+
+    /// Give a buffer back for reuse. It's cleared first, so nothing a session
+    /// read is ever handed to the next one.
+    fn put(&self, mut buf: Vec<u8>) {
+        buf.clear();
+        if buf.capacity() < BUF_SIZE || buf.capacity() > MAX_POOLED_CAPACITY {
+            return;
+        }
+        let mut bufs = self.bufs.lock().expect("buffer pool lock poisoned");
+        if bufs.len() < self.max_bufs {
+            bufs.push(buf);
+        }
+    }
+}
+
+/// A client's connection, TLS over TCP or a Unix socket, see `ClientServer`
+pub trait Stream: AsyncRead + AsyncWrite + Send + Sync + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Sync + Unpin> Stream for T {}
+
+/// The read-half of a client's connection
+pub type Reader = ReadHalf<Box<dyn Stream>>;
+/// The write-half of a client's connection
+pub type Writer = WriteHalf<Box<dyn Stream>>;
+
+/// A basic wire protocol reader/writer, reading requests from `R` and writing
+/// responses to `W`. Sessions read and write the halves of a client's connection,
+/// anything else that's `AsyncRead`/`AsyncWrite`, e.g. a `tokio::io::duplex` pair,
+/// speaks the same protocol. See `read` method below for more details.
+pub struct Proto<R = Reader, W = Writer> {
     // The connection/session ID this proto is being used for
     id: String,
     // The peer/client's address
     addr: std::net::SocketAddr,
     // The read-half of the client's connection
-    reader: ReadHalf<TlsStream<TcpStream>>,
+    reader: R,
     // Internal buffer used to read into
     buf: Vec<u8>,
+    // Position in `self.buf` of the first byte not yet consumed by `read`.
+    // This is preserved across reads so that pipelined commands arriving
+    // in the same buffer are each parsed exactly once.
+    ptr: usize,
     // Flag denoting whether this proto is newly constructed
     // or whether is has been used to read before. This is
     // used to signal whether we want to preserve the existing
     // contents of `self.buf`
     fresh: bool,
     // Broadcast receiver to signal shutdown
-    kill: Receiver<bool>,
-}
-impl Proto {
-    pub fn new(
-        id: &str,
-        addr: std::net::SocketAddr,
-        reader: ReadHalf<TlsStream<TcpStream>>,
-        kill: Receiver<bool>,
-    ) -> Self {
-        let buf = Vec::with_capacity(BUF_SIZE);
-        // big enough to read the initial `Op` string
-        assert!(buf.capacity() >= MIN_BUF_SIZE);
+    kill: Receiver<Shutdown>,
+    // Whether `Shutdown::Drain` was received, so the session ends once the request
+    // being read is done
+    draining: bool,
+    // Whether unknown ops are an error, otherwise they're read as `ProtoOp::Unknown`
+    strict: bool,
+    // Whether the bytes read and written are logged
+    wire_trace: WireTrace,
+    // Commands read as `ProtoOp::Custom`, with the number of arguments each takes
+    custom: Vec<(&'static str, usize)>,
+    // Longest op name scanned for, which custom commands may lengthen
+    max_op_len: usize,
+    // Where `buf` came from and is given back to when the proto is dropped
+    pool: Option<BufferPool>,
+    // Longest arguments read before giving up on the request
+    limits: ProtoLimits,
+    // Number of error responses written, see `errors_written`
+    errors: AtomicU64,
+    // Number of bytes written, see `bytes_written`
+    written: AtomicU64,
+    // Told of the bytes read and written and the error responses, see `add_observer`
+    observers: Vec<Arc<dyn ProtoObserver>>,
+    // Whether every request and response ends with a checksum of its frame, turned on
+    // by the session's HELLO while responses are being written
+    checksums: AtomicBool,
+    // How long a read may wait for the client to send anything, forever when unset
+    idle_timeout: Option<Duration>,
+    // Shortest SET value left on the socket to be streamed, values are never streamed when unset
+    stream_min_len: Option<usize>,
+    // Bytes of the last streamed value that haven't been read yet, they're in `buf`
+    // from `ptr` onwards and then on the socket
+    unread_value: usize,
+    // Responses are written to a `W` passed to each write
+    writer: PhantomData<fn(&mut W)>,
+}
+impl<R, W> Drop for Proto<R, W> {
+    fn drop(&mut self) {
+        if let Some(pool) = &self.pool {
+            pool.put(std::mem::take(&mut self.buf));
+        }
+    }
+}
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> Proto<R, W> {
+    pub fn new(id: &str, addr: std::net::SocketAddr, reader: R, kill: Receiver<Shutdown>) -> Self {
         Self {
             id: id.to_string(),
             addr,
             reader,
-            buf,
+            // allocated by the first read, unless one is taken from a pool
+            buf: Vec::new(),
+            ptr: 0,
             fresh: true,
             kill,
+            draining: false,
+            strict: true,
+            wire_trace: WireTrace::Off,
+            custom: vec![],
+            max_op_len: MAX_OP_LEN,
+            pool: None,
+            limits: ProtoLimits::default(),
+            errors: AtomicU64::new(0),
+            written: AtomicU64::new(0),
+            observers: vec![],
+            checksums: AtomicBool::new(false),
+            idle_timeout: None,
+            stream_min_len: None,
+            unread_value: 0,
+            writer: PhantomData,
         }
     }
 
-    pub async fn flush(&self, writer: &mut WriteHalf<TlsStream<TcpStream>>) -> Result<()> {
+    /// Read into a buffer taken from `pool`, which it's given back to when the proto
+    /// is dropped. Must be set before the first read.
+    pub fn set_buffer_pool(&mut self, pool: BufferPool) -> &mut Self {
+        self.buf = pool.take();
+        self.pool = Some(pool);
+        self
+    }
+
+    pub fn set_wire_trace(&mut self, wire_trace: WireTrace) -> &mut Self {
+        self.wire_trace = wire_trace;
+        self
+    }
+
+    /// Read SETs whose values are at least `min_len` bytes long as `ProtoOp::SetStream`,
+    /// leaving their values to be streamed with `value_reader` instead of buffering them
+    pub fn set_stream_values(&mut self, min_len: Option<usize>) -> &mut Self {
+        self.stream_min_len = min_len;
+        self
+    }
+
+    /// Read the value of the `ProtoOp::SetStream` just read, first from the bytes already
+    /// buffered and then straight from the socket. Whatever isn't read of it is skipped
+    /// by the next `read`, so the value may be read partially, or not at all.
+    pub fn value_reader(&mut self) -> ValueReader<'_, R> {
+        let buffered = self.unread_value.min(self.buf.len() - self.ptr);
+        let socket = self.unread_value - buffered;
+        ValueReader {
+            buffered: &self.buf[self.ptr..self.ptr + buffered],
+            ptr: &mut self.ptr,
+            unread: &mut self.unread_value,
+            socket: (&mut self.reader).take(socket as u64),
+            observers: &self.observers,
+        }
+    }
+
+    /// Finish the `ProtoOp::SetStream` just read, once its caller has read what it keeps
+    /// of the value into `op`: the rest of the value is skipped, and the durability
+    /// argument that may follow it is read into the op. Any other op is returned as is.
+    pub async fn read_value_end(&mut self, op: ProtoOp) -> Result<ProtoOp> {
+        let ProtoOp::SetStream {
+            key,
+            len,
+            value,
+            noreply,
+            ..
+        } = op
+        else {
+            return Ok(op);
+        };
+        let end = loop {
+            let n = self.unread_value.min(self.buf.len() - self.ptr);
+            self.ptr += n;
+            self.unread_value -= n;
+            if self.unread_value == 0 {
+                let rest = &self.buf[self.ptr..];
+                if let Some(end) = rest.iter().position(|b| *b == b'\n') {
+                    break self.ptr + end;
+                }
+                // what's buffered of it is scanned again after every read
+                self.scan(&mut 0, rest.len())?;
+            }
+            // the newline ending the SET is left in the buffer, for the next read to skip
+            self.buf.drain(..self.ptr);
+            self.ptr = 0;
+            make_room(&mut self.buf);
+            match self.read_buf(true).await? {
+                ProtoRead::Read(_) => {}
+                _ => {
+                    let e = "connection closed before the end of a streamed SET";
+                    return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, e).into());
+                }
+            }
+        };
+        let op = if noreply { Op::SetQ } else { Op::Set };
+        match parse_value_end(&self.buf[self.ptr..end]) {
+            Ok(durability) => Ok(ProtoOp::SetStream {
+                key,
+                len,
+                value,
+                noreply,
+                durability,
+            }),
+            Err(e) => self.invalid(self.ptr, op, e),
+        }
+    }
+
+    fn count_read(&self, n: usize) {
+        for observer in &self.observers {
+            observer.read(n);
+        }
+    }
+
+    fn count_written(&self, n: usize) {
+        self.written.fetch_add(n as u64, Ordering::Relaxed);
+        for observer in &self.observers {
+            observer.written(n);
+        }
+    }
+
+    /// Log the bytes read from the socket, when fully wire-tracing
+    fn trace_read(&self, data: &[u8]) {
+        if self.wire_trace == WireTrace::Full {
+            tracing::trace!(target: WIRE_TARGET, session = %self.id, "read {}", hex_dump(data));
+        }
+    }
+
+    /// Log a command read from the socket with its value payloads redacted,
+    /// when wire-tracing with redaction
+    fn trace_frame(&self, op: Op, args: &[Vec<u8>]) {
+        if self.wire_trace != WireTrace::Redacted {
+            return;
+        }
+        let mut frame = hex_dump(op.name().as_bytes());
+        for (i, arg) in args.iter().enumerate() {
+            frame.push_str(&hex_dump(format!(":{}:", arg.len()).as_bytes()));
+            if op.is_value_arg(i) {
+                frame.push_str(&format!("[{} bytes redacted]", arg.len()));
+            } else {
+                frame.push_str(&hex_dump(arg));
+            }
+        }
+        frame.push_str(&hex_dump(b"\n"));
+        tracing::trace!(target: WIRE_TARGET, session = %self.id, "read {frame}");
+    }
+
+    /// Log the bytes about to be written to the socket. Writes carrying
+    /// a value `payload` only have their length logged when redacting.
+    fn trace_write<B: Buf>(&self, buf: &B, payload: bool) {
+        match self.wire_trace {
+            WireTrace::Off => {}
+            WireTrace::Redacted if payload => {
+                tracing::trace!(target: WIRE_TARGET, session = %self.id, "wrote [{} bytes redacted]", buf.remaining());
+            }
+            WireTrace::Redacted | WireTrace::Full => {
+                tracing::trace!(target: WIRE_TARGET, session = %self.id, "wrote {}", hex_dump_buf(buf));
+            }
+        }
+    }
+
+    /// End every response with a `#` and the CRC-32 of its bytes up to the `#`, as 8 hex
+    /// digits, and expect the same of every request, right after its last argument.
+    /// Requests that don't match their checksum fail the read with `Error::FrameCorrupted`.
+    /// Turned on by HELLO while its response is written, so takes `&self`. Values are
+    /// never streamed while it's on, since they're checked before the request is read.
+    pub fn set_frame_checksums(&self, on: bool) {
+        self.checksums.store(on, Ordering::Relaxed);
+    }
+
+    /// Whether requests and responses are checksummed, see `set_frame_checksums`
+    pub fn frame_checksums(&self) -> bool {
+        self.checksums.load(Ordering::Relaxed)
+    }
+
+    /// Read `ProtoOp::IdleTimeout` once a read has waited `timeout` for the client to
+    /// send anything, whether between requests or partway through one
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Tell `observer` of every byte read and written and every error response
+    pub fn add_observer(&mut self, observer: Arc<dyn ProtoObserver>) -> &mut Self {
+        self.observers.push(observer);
+        self
+    }
+
+    pub fn set_limits(&mut self, limits: ProtoLimits) -> &mut Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Read unknown ops as `ProtoOp::Unknown`, and malformed requests as
+    /// `ProtoOp::Invalid`, instead of failing, skipping the rest of their line so
+    /// the next command can be read
+    pub fn set_strict(&mut self, strict: bool) -> &mut Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Every command the proto reads, built-in commands first, each with the
+    /// arguments it takes (see `Op::arity_spec`)
+    pub fn commands(&self) -> Vec<(&'static str, String)> {
+        let builtin = COMMANDS
+            .iter()
+            .filter_map(|name| Op::parse(name.as_bytes()).map(|op| (*name, op.arity_spec())));
+        let custom = self
+            .custom
+            .iter()
+            .filter(|(name, _)| Op::parse(name.as_bytes()).is_none())
+            .map(|(name, arity)| (*name, arity.to_string()));
+        builtin.chain(custom).collect()
+    }
+
+    /// Read these commands, given with the number of arguments each takes, as
+    /// `ProtoOp::Custom`. Built-in commands take precedence over custom ones of the same name.
+    pub fn set_custom_commands(&mut self, custom: Vec<(&'static str, usize)>) -> &mut Self {
+        self.max_op_len = custom
+            .iter()
+            .map(|(name, _)| name.len())
+            .fold(MAX_OP_LEN, usize::max);
+        self.custom = custom;
+        self
+    }
+
+    /// Fail a read on a malformed request for `op` with `Error::Protocol`, or read it as
+    /// `ProtoOp::Invalid` when the proto isn't strict, so the next read skips from `ptr`
+    /// to the end of its line
+    fn invalid(&mut self, ptr: usize, op: Op, e: Error) -> Result<ProtoOp> {
+        let reason = match e {
+            Error::Protocol { reason, .. } => reason,
+            e => e.to_string(),
+        };
+        if self.strict {
+            return Err(Error::Protocol {
+                op: Some(op.name().to_string()),
+                reason,
+            });
+        }
+        tracing::debug!(session = %self.id, "invalid request: {reason}");
+        self.ptr = ptr;
+        Ok(ProtoOp::Invalid { reason })
+    }
+
+    /// Count `n` more bytes scanned for delimiters while reading an op, failing once
+    /// there are more than `ProtoLimits::max_scan_len`
+    fn scan(&self, scanned: &mut usize, n: usize) -> Result<()> {
+        *scanned += n;
+        if *scanned > self.limits.max_scan_len {
+            return Err(Error::LimitExceeded(format!(
+                "read more than {} bytes without finding the delimiters of an operation",
+                self.limits.max_scan_len
+            )));
+        }
+        Ok(())
+    }
+
+    fn parse_op(&self, name: &[u8]) -> Option<Op> {
+        Op::parse(name).or_else(|| {
+            self.custom
+                .iter()
+                .find(|(custom, _)| custom.as_bytes() == name)
+                .map(|(name, arity)| Op::Custom {
+                    name,
+                    arity: *arity,
+                })
+        })
+    }
+
+    pub fn addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
+    /// The number of error responses written so far, to tell whether a command
+    /// was answered with an error
+    pub fn errors_written(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// The number of bytes written so far, to tell how long a response was
+    pub fn bytes_written(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+
+    pub async fn flush(&self, writer: &mut W) -> Result<()> {
         flush_stream!(self.id, writer, self.addr);
         Ok(())
     }
 
-    pub async fn write_null(&self, writer: &mut WriteHalf<TlsStream<TcpStream>>) -> Result<()> {
+    pub async fn write_null(&self, writer: &mut W) -> Result<()> {
         tracing::trace!(session = %self.id, "writing null");
-        let mut bytes = b"null\n".reader();
-        write_stream_buf!(self.id, writer, bytes.get_mut(), self.addr);
-        Ok(())
+        self.write_frame(writer, &b"null\n"[..], false).await
     }
 
-    pub async fn write_echo(
-        &self,
-        writer: &mut WriteHalf<TlsStream<TcpStream>>,
-        data: &[u8],
-    ) -> Result<()> {
+    /// Write `nil\n`, for when a key exists but what was asked of it has no value.
+    /// Missing keys are written with `write_null`.
+    pub async fn write_nil(&self, writer: &mut W) -> Result<()> {
+        tracing::trace!(session = %self.id, "writing nil");
+        self.write_frame(writer, &b"nil\n"[..], false).await
+    }
+
+    /// Write an error message for the client, e.g. `ERR:9:bad thing\n`
+    pub async fn write_error(&self, writer: &mut W, msg: &str) -> Result<()> {
+        tracing::trace!(session = %self.id, "writing error");
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        for observer in &self.observers {
+            observer.error(&self.id, msg);
+        }
+        let msg_len = msg.len().to_string();
+        let bytes = Buf::chain(&b"ERR:"[..], msg_len.as_bytes())
+            .chain(&b":"[..])
+            .chain(msg.as_bytes())
+            .chain(&b"\n"[..]);
+        self.write_frame(writer, bytes, false).await
+    }
+
+    pub async fn write_ok(&self, writer: &mut W) -> Result<()> {
+        tracing::trace!(session = %self.id, "writing ok");
+        self.write_frame(writer, &b"OK\n"[..], false).await
+    }
+
+    /// Write `QUEUED\n`, answering a write queued by a transaction until its COMMIT
+    pub async fn write_queued(&self, writer: &mut W) -> Result<()> {
+        tracing::trace!(session = %self.id, "writing queued");
+        self.write_frame(writer, &b"QUEUED\n"[..], false).await
+    }
+
+    /// Write `PONG\n`, answering a PING without a payload
+    pub async fn write_pong(&self, writer: &mut W) -> Result<()> {
+        tracing::trace!(session = %self.id, "writing pong");
+        self.write_frame(writer, &b"PONG\n"[..], false).await
+    }
+
+    pub async fn write_echo(&self, writer: &mut W, data: &[u8]) -> Result<()> {
         tracing::trace!(session = %self.id, "writing echo");
         let data_len = data.len().to_string();
-        let mut bytes = Buf::chain(data_len.as_bytes(), &b":"[..])
+        let bytes = Buf::chain(data_len.as_bytes(), &b":"[..])
             .chain(data)
             .chain(&b"\n"[..]);
-        write_stream_buf!(self.id, writer, bytes, self.addr);
-        Ok(())
+        self.write_frame(writer, bytes, true).await
     }
 
-    pub async fn write_get_result(
-        &self,
-        writer: &mut WriteHalf<TlsStream<TcpStream>>,
-        data: &[u8],
-    ) -> Result<()> {
-        // todo: accept async reader instead of straight data
+    pub async fn write_get_result(&self, writer: &mut W, data: &[u8]) -> Result<()> {
         tracing::trace!(session = %self.id, "writing get result");
         let data_len = data.len().to_string();
-        let mut bytes = Buf::chain(data_len.as_bytes(), &b":"[..])
+        let bytes = Buf::chain(data_len.as_bytes(), &b":"[..])
             .chain(data)
             .chain(&b"\n"[..]);
+        self.write_frame(writer, bytes, true).await
+    }
+
+    /// Write the value a GETSET replaced, e.g. `3:old\n`, or `null\n` when there was none
+    pub async fn write_old_value(&self, writer: &mut W, old: Option<&[u8]>) -> Result<()> {
+        let old = match old {
+            Some(old) => old,
+            None => return self.write_null(writer).await,
+        };
+        tracing::trace!(session = %self.id, "writing old value");
+        let old_len = old.len().to_string();
+        let bytes = Buf::chain(old_len.as_bytes(), &b":"[..])
+            .chain(old)
+            .chain(&b"\n"[..]);
+        self.write_frame(writer, bytes, true).await
+    }
+
+    /// Write a GET result of `len` bytes copied from `value` as it produces them, e.g.
+    /// from a disk segment, rather than from a value held in memory. The framing is the
+    /// same as `write_get_result`'s, and a `value` ending before `len` bytes fails since
+    /// the result can't be framed once its length has been written.
+    pub async fn write_get_result_stream<V: AsyncRead + Unpin>(
+        &self,
+        writer: &mut W,
+        len: usize,
+        value: V,
+    ) -> Result<()> {
+        tracing::trace!(session = %self.id, "writing streamed get result");
+        let prefix = format!("{len}:");
+        let mut bytes = prefix.as_bytes();
+        self.count_written(bytes.len());
+        self.trace_write(&bytes, false);
+        write_stream_buf!(self.id, writer, bytes, self.addr);
+        let mut value = ChecksumReader {
+            inner: value.take(len as u64),
+            hasher: self.frame_checksums().then(|| {
+                let mut hasher = crc32fast::Hasher::new();
+                hasher.update(prefix.as_bytes());
+                hasher
+            }),
+        };
+        let copied = tokio::io::copy(&mut value, writer).await.map_err(|e| {
+            let msg = format!("session={} error streaming value: {e}", self.id);
+            std::io::Error::new(e.kind(), msg)
+        })?;
+        self.count_written(copied as usize);
+        if copied < len as u64 {
+            return Err(format!(
+                "session={} streamed value ended after {copied} of {len} bytes",
+                self.id
+            )
+            .into());
+        }
+        if self.wire_trace != WireTrace::Off {
+            tracing::trace!(target: WIRE_TARGET, session = %self.id, "wrote [{len} streamed bytes]");
+        }
+        let trailer = match value.hasher {
+            Some(hasher) => format!("#{:08x}\n", hasher.finalize()),
+            None => "\n".to_string(),
+        };
+        let mut bytes = trailer.as_bytes();
+        self.count_written(bytes.len());
+        self.trace_write(&bytes, false);
+        write_stream_buf!(self.id, writer, bytes, self.addr);
+        Ok(())
+    }
+
+    /// Write a whole response, ending it with its checksum when they're on
+    async fn write_frame<B: Buf>(&self, writer: &mut W, mut bytes: B, payload: bool) -> Result<()> {
+        if !self.frame_checksums() {
+            self.count_written(bytes.remaining());
+            self.trace_write(&bytes, payload);
+            write_stream_buf!(self.id, writer, bytes, self.addr);
+            return Ok(());
+        }
+        let mut frame = bytes.copy_to_bytes(bytes.remaining()).to_vec();
+        // the checksum goes before the trailing newline
+        frame.pop();
+        let checksum = crc32fast::hash(&frame);
+        frame.extend_from_slice(format!("#{checksum:08x}\n").as_bytes());
+        let mut bytes = frame.as_slice();
+        self.count_written(bytes.len());
+        self.trace_write(&bytes, payload);
         write_stream_buf!(self.id, writer, bytes, self.addr);
         Ok(())
     }
 
+    /// Write a length-prefixed integer, e.g. `3:123\n`
+    pub async fn write_int(&self, writer: &mut W, n: usize) -> Result<()> {
+        tracing::trace!(session = %self.id, "writing int");
+        let n = n.to_string();
+        let n_len = n.len().to_string();
+        let bytes = Buf::chain(n_len.as_bytes(), &b":"[..])
+            .chain(n.as_bytes())
+            .chain(&b"\n"[..]);
+        self.write_frame(writer, bytes, false).await
+    }
+
     pub async fn write_set_result(
         &self,
-        writer: &mut WriteHalf<TlsStream<TcpStream>>,
-        data: &[u8],
+        writer: &mut W,
+        len: usize,
+        existed: bool,
+        truncated: bool,
     ) -> Result<()> {
         tracing::trace!(session = %self.id, "writing set result");
-        let len_v = data.len().to_string();
+        let len_v = len.to_string();
         let len_v_len = len_v.len().to_string();
-        let mut bytes = Buf::chain(len_v_len.as_bytes(), &b":"[..])
+        let outcome: &[u8] = if existed { b"7:updated" } else { b"7:created" };
+        let truncated: &[u8] = if truncated { b":9:truncated" } else { b"" };
+        let bytes = Buf::chain(len_v_len.as_bytes(), &b":"[..])
             .chain(len_v.as_bytes())
+            .chain(&b":"[..])
+            .chain(outcome)
+            .chain(truncated)
             .chain(&b"\n"[..]);
-        write_stream_buf!(self.id, writer, bytes, self.addr);
-        Ok(())
+        self.write_frame(writer, bytes, false).await
+    }
+
+    /// Write a sequence of length-prefixed fields, e.g. `3:foo:5:hello\n`
+    pub async fn write_fields(&self, writer: &mut W, fields: &[&[u8]]) -> Result<()> {
+        tracing::trace!(session = %self.id, "writing fields");
+        let mut data = Vec::new();
+        for (i, field) in fields.iter().enumerate() {
+            if i > 0 {
+                data.push(b':');
+            }
+            data.extend_from_slice(field.len().to_string().as_bytes());
+            data.push(b':');
+            data.extend_from_slice(field);
+        }
+        data.push(b'\n');
+        self.write_frame(writer, data.as_slice(), true).await
     }
 
-    /// read to the internal buffer
-    async fn read_buf(&mut self) -> Result<ProtoRead> {
+    /// Write a count-prefixed list of values, `null` standing in for absent ones,
+    /// e.g. `1:2:5:hello:null\n`
+    pub async fn write_values<V: AsRef<[u8]>>(
+        &self,
+        writer: &mut W,
+        values: &[Option<V>],
+    ) -> Result<()> {
+        tracing::trace!(session = %self.id, "writing values");
+        let count = values.len().to_string();
+        let mut data = format!("{}:{count}", count.len()).into_bytes();
+        for value in values {
+            match value {
+                Some(value) => {
+                    let value = value.as_ref();
+                    data.extend_from_slice(format!(":{}:", value.len()).as_bytes());
+                    data.extend_from_slice(value);
+                }
+                None => data.extend_from_slice(b":null"),
+            }
+        }
+        data.push(b'\n');
+        self.write_frame(writer, data.as_slice(), true).await
+    }
+
+    /// read to the internal buffer. `mid_request` is whether part of a request has
+    /// been read already, which a draining shutdown lets the client finish sending
+    async fn read_buf(&mut self, mid_request: bool) -> Result<ProtoRead> {
         tracing::trace!(session = %self.id, "reading to buffer");
-        tokio::select! {
-            _ = self.kill.recv() => {
-                tracing::info!(session = %self.id, "connection cancelled by server shutdown");
-                Ok(ProtoRead::Cancelled)
+        loop {
+            if self.draining && !mid_request {
+                tracing::info!(session = %self.id, "connection drained for server shutdown");
+                return Ok(ProtoRead::Cancelled);
             }
-            res = self.reader.read_buf(&mut self.buf) => {
-                // match self.reader.read_buf(&mut self.buf).await {
-                match res {
-                    Ok(n) => Ok(ProtoRead::Read(n)),
-                    Err(e) => {
-                        use std::io::ErrorKind::*;
-                        match e.kind() {
-                            UnexpectedEof => Ok(ProtoRead::Eof),
-                            _ => Err(format!("session={} error reading from socket: {e}", self.id).into()),
-                        }
+            tokio::select! {
+                signal = self.kill.recv() => {
+                    // a lagged receiver missed the drain, and has been killed since
+                    if signal == Ok(Shutdown::Drain) {
+                        tracing::debug!(session = %self.id, mid_request, "draining for server shutdown");
+                        self.draining = true;
+                        continue;
                     }
+                    tracing::info!(session = %self.id, "connection cancelled by server shutdown");
+                    return Ok(ProtoRead::Cancelled);
+                }
+                _ = idle(self.idle_timeout) => {
+                    tracing::debug!(session = %self.id, "nothing read for {:?}", self.idle_timeout);
+                    return Ok(ProtoRead::Timeout);
+                }
+                res = self.reader.read_buf(&mut self.buf) => {
+                    return match res {
+                        // rustls only reports the end of the stream once it's seen a close_notify
+                        Ok(0) => Ok(ProtoRead::Eof),
+                        Ok(n) => {
+                            self.count_read(n);
+                            self.trace_read(&self.buf[self.buf.len() - n..]);
+                            Ok(ProtoRead::Read(n))
+                        }
+                        Err(e) => {
+                            use std::io::ErrorKind::*;
+                            match e.kind() {
+                                // rustls reports a TCP close without a close_notify as an unexpected EOF
+                                UnexpectedEof | ConnectionReset | ConnectionAborted | BrokenPipe => {
+                                    tracing::debug!(session = %self.id, "connection closed without close_notify: {e}");
+                                    Ok(ProtoRead::Reset)
+                                }
+                                _ => {
+                                    let msg = format!("session={} error reading from socket: {e}", self.id);
+                                    Err(std::io::Error::new(e.kind(), msg).into())
+                                }
+                            }
+                        }
+                    };
                 }
             }
         }
     }
 
-    /// Read from `self.reader` (into `self.buf`) to construct a single valid `ProtoOp`
-    /// TODO: Add max limits to number of bytes read for lengths/keys/values
+    /// Read from `self.reader` (into `self.buf`) to construct a single valid `ProtoOp`.
+    /// Arguments longer than the proto's `ProtoLimits` fail with `Error::LimitExceeded`.
     /// TODO: Better handling of client errors - malformed or malicious inputs
     ///
-    /// This is a really basic wire protocol to communicate utf8 keys and raw byte values.
-    /// There are 3 commands:
-    ///   GET key       => GET:3:key\n           => 9:the_value\n   ;; returning the found bytes
-    ///   SET key value => SET:3:key:5:value\n   => 1:5\n           ;; returning the number of bytes saved
-    ///   ECHO msg      => ECHO:7:message\n      => 7:message\n     ;; returning the bytes sent
+    /// This is a really basic wire protocol to communicate raw byte keys and values.
+    /// There are 34 commands, and 4 more for hashes with the `hash` feature:
+    ///   GET key        => GET:3:key\n           => 9:the_value\n   ;; returning the found bytes
+    ///   MGET count key...
+    ///                  => MGET:1:2:1:a:1:b\n    => 1:2:1:1:null\n ;; returning the count of keys, then each key's bytes
+    ///                                                             ;; or null, all read at a single point in time
+    ///   GETV key       => GETV:3:key\n          => 9:the_value:16:5d966ff4ab474785\n
+    ///                                                             ;; returning the found bytes and their version
+    ///   SET key value  => SET:3:key:5:value\n   => 1:5:7:created\n ;; returning the number of bytes saved
+    ///                                                             ;; and whether the key was `created` or `updated`
+    ///   SETQ key value => SETQ:3:key:5:value\n  =>                 ;; same as SET, but nothing is returned
+    ///   MSET count key value...
+    ///                  => MSET:1:2:1:a:1:x:1:b:1:y\n => OK\n     ;; setting every key in a single transaction, so
+    ///                                                             ;; either all of them are written or none are
+    ///   SET key value durability
+    ///                  => SET:3:key:5:value:5:async\n => 1:5:7:created\n
+    ///                                                             ;; also choosing how durable the write must be before
+    ///                                                             ;; it's returned: async, batched or fsync (the default)
+    ///   CASV key version value
+    ///                  => CASV:3:key:16:5d966ff4ab474785:5:value\n => 1:1\n
+    ///                                                             ;; setting the value only if it's still at the version
+    ///                                                             ;; read by GETV (or absent for an empty version),
+    ///                                                             ;; returning 1 if it was set and 0 if it changed since
+    ///   CAS key expected value
+    ///                  => CAS:3:key:3:old:3:new\n => 1:1\n      ;; setting the value only if it's currently `expected`,
+    ///                                                             ;; returning 1 if it was set and 0 if it wasn't
+    ///   GETSET key value
+    ///                  => GETSET:3:key:3:new\n => 3:old\n          ;; atomically setting the value like SET, returning the value
+    ///                                                             ;; it replaced or null
+    ///   DEL key        => DEL:3:key\n           => 1:1\n           ;; returning the number of keys deleted
+    ///   DELQ key       => DELQ:3:key\n          =>                 ;; same as DEL, but nothing is returned
+    ///   STRLEN key     => STRLEN:3:key\n        => 1:9\n           ;; returning the length of the stored value
+    ///   EXISTS key     => EXISTS:3:key\n        => 1:1\n           ;; returning 1 if the key holds a value and 0 if it doesn't,
+    ///                                                             ;; without reading the value when the store can avoid it
+    ///   SETRANGE key offset value
+    ///                  => SETRANGE:3:key:1:5:3:new\n => 1:8\n    ;; overwriting the value from an offset, returning its new length
+    ///   SWAP key key   => SWAP:1:a:1:b\n        => OK\n            ;; atomically exchanging the values of two keys
+    ///   EXPIRE key secs
+    ///                  => EXPIRE:3:key:2:60\n  => 1:1\n           ;; expiring the key after its TTL in seconds, until it's SET
+    ///                                                             ;; again. Returning 1 if the key exists and 0 if it doesn't.
+    ///                                                             ;; An error unless the store can expire keys
+    ///   APPLY key transform arg
+    ///                  => APPLY:3:key:3:add:1:5\n => 2:12\n       ;; atomically transforming a value, returning the new value
+    ///   FIND attr      => FIND:3:red\n          => 1:2:3:ada:2:cy\n ;; returning the count of keys whose values are indexed
+    ///                                                             ;; under the attribute, then each key, sorted. An error
+    ///                                                             ;; unless the server was started with an index
+    ///   SCAN start end limit
+    ///                  => SCAN:5:user::5:user;:2:10\n => 1:4:6:user:1:3:ada:6:user:2:2:cy\n
+    ///                                                             ;; returning the count of keys and values, then up to
+    ///                                                             ;; `limit` keys from `start` and before `end`, each
+    ///                                                             ;; followed by its value. An empty `end` scans to the last key
+    ///   SCAN cursor count
+    ///                  => SCAN:0::2:10\n       => 1:3:6:user:3:6:user:1:6:user:2\n
+    ///                                                             ;; returning the count of fields, the cursor to pass to
+    ///                                                             ;; the next SCAN, then up to `count` keys from where
+    ///                                                             ;; `cursor` left off. An empty cursor starts from the
+    ///                                                             ;; first key, and is returned once every key was scanned.
+    ///                                                             ;; Keys written mid-scan may or may not be returned
+    ///   FLUSHALL       => FLUSHALL\n            => OK\n            ;; removing every key of every namespace. An error unless
+    ///                                                             ;; the server was started allowing it
+    ///   SNAPSHOT name  => SNAPSHOT:6:backup\n   => 2:42\n           ;; writing every key and value of every namespace, as they
+    ///                                                             ;; are at that moment, to the file `name` in the server's
+    ///                                                             ;; snapshot directory, returning how many there were. An
+    ///                                                             ;; error unless the server was started with a directory,
+    ///                                                             ;; when `name` already exists, or in a session scoped
+    ///                                                             ;; with USE
+    ///   BEGIN          => BEGIN\n               => OK\n            ;; queuing the SETs and DELs that follow, each answered
+    ///                                                             ;; with QUEUED\n, until COMMIT or DISCARD
+    ///   COMMIT         => COMMIT\n              => OK\n            ;; applying the queued writes as a single transaction
+    ///   DISCARD        => DISCARD\n             => OK\n            ;; dropping the queued writes
+    ///   HSET key field value
+    ///                  => HSET:3:key:5:field:5:value\n => 1:1\n ;; setting a field of a hash, returning its number of fields
+    ///   HGET key field => HGET:3:key:5:field\n  => 5:value\n       ;; returning the field's value
+    ///   HGETALL key    => HGETALL:3:key\n       => 5:field:5:value\n ;; returning every field and value, sorted by field
+    ///   HINCR key field increment
+    ///                  => HINCR:3:key:5:count:1:5\n => 1:5\n   ;; atomically adding to a field's integer, returning its new value
+    ///   USE namespace  => USE:4:app1\n          => OK\n            ;; scoping the session's keys to a namespace
+    ///   TIME           => TIME\n                => 10:1700000000:6:123456\n
+    ///                                                             ;; returning the server's unix time in seconds and
+    ///                                                             ;; the microseconds into the current second
+    ///   COMMAND        => COMMAND\n             => 3:GET:1:1:4:MGET:2:1+...\n
+    ///                                                             ;; returning every command with the arguments it takes
+    ///   HEALTHZ        => HEALTHZ\n             => 6:status:5:ready\n ;; returning whether the store is `ready`, or
+    ///                                                             ;; `starting` while it recovers
+    ///   QUIT           => QUIT\n                => OK\n            ;; closing the session, discarding anything sent after it
+    ///   HELLO          => HELLO\n               => 7:version:5:0.1.0\n ;; returning the server's capabilities
+    ///   HELLO encoding => HELLO:3:hex\n         => 7:version:5:0.1.0:8:encoding:3:hex\n
+    ///                                                             ;; also switching how values are encoded
+    ///   HELLO crc32    => HELLO:5:crc32\n       => 7:version:5:0.1.0:8:checksum:5:crc32#2dab53b8\n
+    ///                                                             ;; also checksumming every frame from then on
+    ///   ECHO msg       => ECHO:7:message\n      => 7:message\n     ;; returning the bytes sent
+    ///   PING           => PING\n                => PONG\n          ;; checking the connection is alive
+    ///   PING payload   => PING:4:abcd\n         => 4:abcd\n        ;; also returning the payload, like ECHO
+    ///   WAITREPL replicas timeout
+    ///                  => WAITREPL:1:2:4:1000\n => 1:1\n       ;; waiting up to `timeout` ms for `replicas` replicas to
+    ///                                                             ;; acknowledge the session's writes, returning how many did
+    ///   DEBUG cmd arg  => DEBUG:5:SLEEP:3:100\n => OK\n            ;; debug/test helpers, disabled by default
+    ///                  => DEBUG:6:ERRORS:3:GET\n => 1:1:34:1700000000000 session=1 unknown op\n
+    ///                                                             ;; returning the server's recent error responses,
+    ///                                                             ;; or SLOWLOG its slow commands, oldest first.
+    ///                                                             ;; RESET clears them, returning how many there were
     ///
-    /// - `key`, `value`, `msg` denote variable length byte arguments
-    /// - `key` bytes must be a valid utf8 string
+    /// - `key`, `value`, `version`, `transform`, `msg`, `cmd`, `arg` denote variable length byte arguments
+    /// - `key` may be any bytes, while a hash `field` or a namespace must be a valid utf8 string
     /// - Every variable length byte argument is prefixed by a "length" surrounded by colons `:`
     ///   which denotes how many bytes must be read to consume the following argument.
     /// - Every command must end with a newline `\n`. These act as a secondary separator,
     ///   with the "lengths" being the primary means of separation. Any bytes found between
-    ///   the "end" of a "length" and the trailing newline are discarded.
+    ///   the "end" of the last argument and the trailing newline are discarded.
     /// - Every result has a trailing newline to denote the end of the result message.
-    /// - Lack of existence is represented by `null\n`
+    /// - Lack of existence is represented by `null\n`, while an existing key that has no
+    ///   value for what was asked of it is represented by `nil\n`. Empty values are
+    ///   returned as `0:\n`, so the three are never conflated.
+    /// - Errors the client can recover from are returned as `ERR:<len>:<message>\n`,
+    ///   even for `noreply` commands
+    /// - Writes the store is too overloaded to take are answered with an error whose
+    ///   message starts with the `OVERLOADED` code, e.g. `ERR:40:503 overloaded: ...\n`.
+    ///   They can be retried as is, after backing off to let the store catch up
+    /// - Unknown commands fail the read with `Error::Protocol`, which the server answers
+    ///   with an `ERR` before closing the connection, unless the proto isn't strict, in which
+    ///   case they're returned as `ProtoOp::Unknown` and the rest of their line is skipped
+    /// - Commands added with `set_custom_commands` follow the same framing and are
+    ///   returned as `ProtoOp::Custom` with their arguments left as raw bytes
+    /// - When the server truncates an oversized SET value, `:9:truncated` is appended
+    ///   to the SET result, e.g. `1:5:7:created:9:truncated\n`
+    /// - SETs whose values are at least as long as `set_stream_values` asks are returned
+    ///   as `ProtoOp::SetStream` once the value's length is read, leaving the value on the
+    ///   socket to be read with `value_reader`, and the durability argument following it
+    ///   with `read_value_end`
+    /// - Responses that would carry more value bytes than the server's max response
+    ///   size are answered with an `ERR` instead, asking the client to paginate
+    /// - Once a session's HELLO asks for `crc32`, every frame, request or response, has
+    ///   `#` and the CRC-32 of its bytes up to the `#` as 8 hex digits before its newline.
+    ///   A request that doesn't match its checksum is answered with a `frame corrupted`
+    ///   error and ends the session, see `set_frame_checksums`
     ///
     /// Examples:
     /// - Get non existent key:
-    ///     send=> GET:9:unset_key\n
-    ///     recv=> null\n
+    ///   send=> GET:9:unset_key\n
+    ///   recv=> null\n
     ///
     /// - Get an existing key:
-    ///     send=> GET:7:set_key\n
-    ///     recv=> 11:found_value\n
+    ///   send=> GET:7:set_key\n
+    ///   recv=> 11:found_value\n
+    ///
+    /// - Get several keys at once, consistent with each other even under concurrent
+    ///   writes, with misses as `null`:
+    ///   send=> MGET:1:3:7:set_key:9:unset_key:7:set_key\n
+    ///   recv=> 1:3:11:found_value:null:11:found_value\n
     ///
     /// - Set a key/value pair:
-    ///     send=> SET:6:my_key:8:my_value\n
-    ///     recv=> 1:8\n
+    ///   send=> SET:6:my_key:8:my_value\n
+    ///   recv=> 1:8:7:created\n
+    ///
+    /// - Set the same key again:
+    ///   send=> SET:6:my_key:5:value\n
+    ///   recv=> 1:5:7:updated\n
+    ///
+    /// - Set several keys at once, a value over the max value size failing every write:
+    ///   send=> MSET:1:2:1:a:1:x:1:b:1:y\n
+    ///   recv=> OK\n
+    ///
+    /// - Delete a key:
+    ///   send=> DEL:6:my_key\n
+    ///   recv=> 1:1\n
+    ///
+    /// - Bulk load without waiting on results, then confirm with a GET:
+    ///   send=> SETQ:1:a:1:1\nSETQ:1:b:1:2\nGET:1:b\n
+    ///   recv=> 1:2\n
+    ///
+    /// - Overwrite part of a value. Values shorter than the offset (or absent) are
+    ///   zero-padded up to it:
+    ///   send=> SET:3:key:5:hello\nSETRANGE:3:key:1:7:3:new\n
+    ///   recv=> 1:5:7:created\n2:10\n                  ;; the value is now `hello\0\0new`
+    ///
+    /// - Write several keys atomically, nothing being applied until COMMIT. Other writes
+    ///   can't be queued and are refused, while reads answer straight away, without
+    ///   seeing the queued writes. A transaction left open when the connection ends is
+    ///   dropped, as is one whose COMMIT fails, or that's sent an invalid request:
+    ///   send=> BEGIN\nSET:1:a:1:x\nDEL:1:b\nCOMMIT\n
+    ///   recv=> OK\nQUEUED\nQUEUED\nOK\n
+    ///
+    /// - Swap two keys, an absent key is treated as null so its partner is deleted:
+    ///   send=> SWAP:5:front:4:back\n
+    ///   recv=> OK\n
+    ///
+    /// - Read-modify-write a value without a round trip. The built-in transforms are
+    ///   `add` (to a decimal integer), `append`, `setbit` and `clearbit` (at a bit offset,
+    ///   0 being the first byte's most significant bit). Absent keys count as empty, or 0
+    ///   for `add`, and values that don't suit the transform are left untouched:
+    ///   send=> APPLY:7:counter:3:add:1:5\nAPPLY:3:foo:3:add:1:1\n
+    ///   recv=> 1:5\nERR:28:add: value is not an integer\n
+    ///
+    /// - Keep several named fields under one key with a hash. Fields missing from an
+    ///   existing hash are `nil`, deleting the key deletes every field, and hash commands
+    ///   on plain values fail without touching them:
+    ///   send=> HSET:4:user:4:name:3:ada\nHSET:4:user:4:lang:2:en\nHGET:4:user:4:name\nHGET:4:user:3:age\n
+    ///   recv=> 1:1\n1:2\n3:ada\nnil\n
+    ///   send=> HGETALL:4:user\n
+    ///   recv=> 4:lang:2:en:4:name:3:ada\n
+    ///   Counters can be grouped under one key too, with fields holding integers:
+    ///   send=> HINCR:5:stats:4:hits:1:5\nHINCR:5:stats:4:hits:2:-2\nHINCR:4:user:4:name:1:1\n
+    ///   recv=> 1:5\n1:3\nERR:36:hincr: field value is not an integer\n
+    ///
+    /// - Discover the server's capabilities, as alternating names and values. Optional
+    ///   capabilities like `max_value_size` and `max_response_size` are omitted when they
+    ///   don't apply:
+    ///   send=> HELLO\n
+    ///   recv=> 7:version:5:0.1.0:14:max_value_size:4:1024\n
+    ///
+    /// - Have values hex (or base64) encoded so binary data survives line-oriented tools.
+    ///   The length prefix is that of the encoded value, and `raw` switches back:
+    ///   send=> HELLO:3:hex\nGET:3:bin\n
+    ///   recv=> 7:version:5:0.1.0:8:encoding:3:hex\n4:00ff\n
+    ///
+    /// - Guard against corruption after TLS is terminated, e.g. by a proxy, by checksumming
+    ///   every frame, starting with HELLO's response:
+    ///   send=> HELLO:5:crc32\nSET:3:key:3:bar#1edc27e0\nGET:3:key#433af7ae\n
+    ///   recv=> 7:version:5:0.1.0:8:checksum:5:crc32#2dab53b8\n1:3:7:created#94d019c9\n3:bar#368b7836\n
+    ///
+    /// - Keep a dataset apart from others sharing the store by scoping the session to a
+    ///   namespace. Namespaced keys are stored as `\xff<namespace>:<key>`, so namespaces
+    ///   may not contain `:`, and keys of the default keyspace may not start with a
+    ///   `\xff` byte, keeping them apart. An empty namespace switches back to the
+    ///   default keyspace:
+    ///   send=> USE:4:app1\nSET:3:foo:3:bar\nUSE:0:\nGET:3:foo\nGET:8:app1:foo\n
+    ///   recv=> OK\n1:3:7:created\nOK\nnull\nnull\n
+    ///
+    /// - Measure the skew between the client's and server's clocks, e.g. before
+    ///   computing deadlines, from the server's unix time. The microseconds aren't padded:
+    ///   send=> TIME\n
+    ///   recv=> 10:1700000000:5:42000\n
     ///
     /// - Echo a message:
-    ///     send=> ECHO:11:hello world\n
-    ///     recv=> 11:hello world\n
+    ///   send=> ECHO:11:hello world\n
+    ///   recv=> 11:hello world\n
+    ///
+    /// - Check a connection is alive, e.g. before reusing a pooled one. It never touches
+    ///   the store, so it's answered even while the store recovers:
+    ///   send=> PING\nPING:4:abcd\n
+    ///   recv=> PONG\n4:abcd\n
+    ///
+    /// - Sleep for 100 milliseconds before responding (requires debug commands be enabled):
+    ///   send=> DEBUG:5:SLEEP:3:100\n
+    ///   recv=> OK\n
     ///
     pub async fn read(&mut self) -> Result<ProtoOp> {
         // --------
         // --- Starting defaults
         // --------
-        let mut state = State::Start;
+        let mut state = if self.unread_value > 0 {
+            State::SkipValue
+        } else {
+            State::Start
+        };
         let mut op = Op::Get;
+        // Number of arguments the op takes, which for MGET and MSET grows by the key
+        // count their first argument holds
+        let mut arity = 0;
         // Flag used when reading length integers
         let mut between_colons = false;
+        // Bytes read outside of the op's arguments, see `ProtoLimits::max_scan_len`
+        let mut scanned = 0;
+        // Bytes of the op's arguments buffered so far, see `ProtoLimits::max_request_len`
+        let mut buffered = 0usize;
+        // Whether the request ends with a checksum, see `set_frame_checksums`. The bytes
+        // of its frame are hashed up to `ptr` before each read drops them from `self.buf`,
+        // and the rest once its arguments are read, from `frame_start` onwards
+        let checksums = self.frame_checksums();
+        let mut frame = crc32fast::Hasher::new();
+        let mut frame_start = 0;
+        let mut hashing = false;
+        // The `#` and hex digits following the arguments
+        let mut trailer = Vec::with_capacity(CHECKSUM_TRAILER_LEN);
         // Whether a "read from socket" is required. This will clear
         // and refill the internal `self.buf`.
         // When a `fresh` Proto is being used, we want to start
@@ -244,62 +2020,67 @@ impl Proto {
         // we _don't_ want to start with a read since we want to
         // preserve whatever may be in the existing `self.buf`
         let mut needs_read = self.fresh;
-        // Pointer to the internal `self.buf` buffer
-        let mut ptr = 0;
+        // Pointer to the internal `self.buf` buffer, picking up
+        // wherever the previous `read` left off
+        let mut ptr = self.ptr;
 
         // --------
         // --- Buffers for reading distinct parts of the proto-op
         // --------
-        // Buf to read the key length integer, 8 chars should cover most numbers
-        let mut key_len_buf = Vec::with_capacity(8);
-        // Eventual parsed length in bytes of the key
-        let mut key_len = 0;
-        let mut key = Vec::with_capacity(BUF_SIZE);
-
-        // Buf to read message to be echo'd
-        let mut echo = Vec::with_capacity(BUF_SIZE);
-
-        // Buf to read the key length integer, 8 chars should cover most numbers
-        let mut value_len_buf = Vec::with_capacity(8);
-        // Eventual parsed length in bytes of the value
-        let mut value_len = 0;
-        let mut value = Vec::with_capacity(BUF_SIZE);
-
-        // Buf to hold residual bytes - these are bytes found
-        // in `self.buf` after an "end of message" newline.
-        // Any residual bytes will be prepended to `self.buf`
-        // after the next read.
-        let mut residual = Vec::with_capacity(BUF_SIZE);
+        // Buf to read an argument length integer, 8 chars should cover most numbers
+        let mut arg_len_buf = Vec::with_capacity(8);
+        // Eventual parsed length in bytes of the argument currently being read
+        let mut arg_len = 0;
+        let mut arg = Vec::with_capacity(BUF_SIZE);
+        // Every argument read so far, in the order they were sent
+        let mut args: Vec<Vec<u8>> = Vec::with_capacity(3);
 
         'state_loop: loop {
             if needs_read {
-                // Before reading, empty the read buffer and make sure
-                // it's sized to the expected BUF_SIZE.
-                // Clearing ensures there's space to fill, and shrinking
-                // ensures that the buffer hasn't grown due to previously
-                // prepended residual bytes.
-                self.buf.clear();
-                self.buf.shrink_to(BUF_SIZE);
-
-                match self.read_buf().await? {
+                // Residual bytes - bytes in `self.buf` that haven't been consumed
+                // yet when another read is required (e.g. a partially received op
+                // name following a newline) - are kept at the front of the buffer
+                // and the read appends to them.
+                if hashing {
+                    frame.update(&self.buf[frame_start..ptr]);
+                }
+                frame_start = 0;
+                self.buf.drain(..ptr);
+                make_room(&mut self.buf);
+
+                // between requests when nothing of the next op has arrived yet
+                let mid_request = match state {
+                    State::Start => false,
+                    State::ReadOp => !self.buf.is_empty(),
+                    _ => true,
+                };
+                match self.read_buf(mid_request).await? {
                     ProtoRead::Eof => return Ok(ProtoOp::SysClose),
+                    ProtoRead::Reset => return Ok(ProtoOp::Reset),
                     ProtoRead::Cancelled => return Ok(ProtoOp::Cancelled),
+                    ProtoRead::Timeout => return Ok(ProtoOp::IdleTimeout),
                     ProtoRead::Read(n) => {
                         tracing::debug!(session = %self.id, "read {} bytes", n);
                     }
                 }
-                if !residual.is_empty() {
-                    residual.append(&mut self.buf);
-                    std::mem::swap(&mut residual, &mut self.buf);
-                    // residual should now be empty and have self.buf's capacity
-                    assert!(residual.is_empty());
-                    assert!(residual.capacity() >= BUF_SIZE);
-                }
                 ptr = 0;
                 needs_read = false;
             }
 
             match state {
+                State::SkipValue => {
+                    tracing::debug!(session = %self.id, unread = %self.unread_value, "handling State::SkipValue");
+                    // the value may well hold newlines, so its bytes are counted off
+                    // before looking for the one ending its command
+                    let n = self.unread_value.min(self.buf.len() - ptr);
+                    ptr += n;
+                    self.unread_value -= n;
+                    if self.unread_value == 0 {
+                        state = State::Start;
+                    } else {
+                        needs_read = true;
+                    }
+                }
                 State::Start => {
                     tracing::debug!(session = %self.id, fresh= %self.fresh, "handling State::Start");
                     if self.fresh {
@@ -309,17 +2090,13 @@ impl Proto {
                     } else {
                         // This is an existing proto so there may be residual data in `self.buf`.
                         // Clear anything remaining on the stream up to and including a b'\n'.
-                        // If there's anything after that newline, then save it to the residual buffer.
+                        // Anything after that newline is the start of the next command.
                         while ptr < self.buf.len() {
                             tracing::trace!(session = %self.id, ptr=%ptr, "clearing residual bytes up to newline");
+                            self.scan(&mut scanned, 1)?;
                             if self.buf[ptr] == b'\n' {
                                 ptr += 1;
                                 state = State::ReadOp;
-                                if ptr < self.buf.len() {
-                                    // save the rest to a residual buffer that will be prepended
-                                    // to the next read buffer
-                                    residual.append(&mut self.buf[ptr..].to_vec());
-                                }
                                 continue 'state_loop;
                             } else {
                                 ptr += 1;
@@ -330,184 +2107,1203 @@ impl Proto {
                 }
                 State::ReadOp => {
                     tracing::debug!(session = %self.id, "handling State::ReadOp");
-                    let read_op_end_ptr = ptr + MIN_BUF_SIZE;
-                    if read_op_end_ptr > self.buf.len() {
-                        if ptr == 0 {
-                            // We're at the start of a read buffer and there's not enough bytes
-                            // so there must have been a malformed write from a client.
-                            // Note: This assumption isn't _really_ valid. It's _possible_
-                            //       that the client is slowly writing the initial "op" (GET/SET)
-                            //       bytes, and it might be better if we kept reading and
-                            //       prepending our current byes using the residual buffer.
-                            //       We can add that if we see this error happening...
-                            return Err(format!(
-                                "error reading start of operation, buffer-len {:?} shorter than expected {:?}",
-                                self.buf.len(),
-                                String::from_utf8(self.buf.clone()).unwrap_or_else(|_| format!("{:?}", &self.buf))
-                            )
-                            .into());
-                        } else {
-                            // we were previously clearing residual bytes and
-                            // are mid-buffer (ptr > 0). Instead of blowing up,
-                            // try reading more bytes (prepending the residual bytes)
-                            needs_read = true;
-                            continue 'state_loop;
-                        }
+                    if checksums {
+                        // the frame starts at the op's name
+                        frame = crc32fast::Hasher::new();
+                        frame_start = ptr;
+                        hashing = true;
                     }
-                    op = match &self.buf[ptr..read_op_end_ptr] {
-                        b"GET:" => {
-                            ptr = 3;
-                            Op::Get
+                    // The op name runs up to the `:` preceding its first argument, or
+                    // the checksum of an op without arguments
+                    let name_len = self.buf[ptr..]
+                        .iter()
+                        .take(self.max_op_len + 1)
+                        .position(|b| *b == b':' || *b == b'\n' || (checksums && *b == b'#'));
+                    let read_op_end_ptr = match name_len {
+                        Some(n) => {
+                            self.scan(&mut scanned, n)?;
+                            ptr + n
                         }
-                        b"SET:" => {
-                            ptr = 3;
-                            Op::Set
+                        None if self.buf.len() - ptr > self.max_op_len && !self.strict => {
+                            self.ptr = ptr + self.max_op_len;
+                            return Ok(ProtoOp::Unknown {
+                                name: String::from_utf8_lossy(&self.buf[ptr..self.ptr])
+                                    .into_owned(),
+                            });
                         }
-                        b"ECHO" => {
-                            ptr = 4;
-                            Op::Echo
+                        None if self.buf.len() - ptr > self.max_op_len => {
+                            return Err(malformed(format!(
+                                "error reading start of operation, unknown operation {:?}",
+                                String::from_utf8_lossy(&self.buf[ptr..ptr + self.max_op_len])
+                            )));
                         }
-                        _ => {
-                            return Err(format!(
+                        None => {
+                            // The op name was split across reads, e.g. a slow client sent
+                            // `GE` and then `T:3:key\n`, or it followed a previous op's
+                            // newline at the end of the buffer. Read more, keeping the
+                            // partial name as residual bytes to scan again from its start
+                            needs_read = true;
+                            continue 'state_loop;
+                        }
+                    };
+                    op = match self.parse_op(&self.buf[ptr..read_op_end_ptr]) {
+                        Some(op) => op,
+                        None if !self.strict => {
+                            // the rest of the line is skipped when reading the next op
+                            self.ptr = read_op_end_ptr;
+                            return Ok(ProtoOp::Unknown {
+                                name: String::from_utf8_lossy(&self.buf[ptr..read_op_end_ptr])
+                                    .into_owned(),
+                            });
+                        }
+                        None => {
+                            return Err(malformed(format!(
                                 "error reading start of operation, unknown operation {:?}",
                                 String::from_utf8(self.buf[ptr..read_op_end_ptr].to_vec())
                                     .unwrap_or_else(|_| format!(
                                         "{:?}",
                                         &self.buf[ptr..read_op_end_ptr]
                                     ))
-                            )
-                            .into())
+                            )))
                         }
                     };
+                    // HELLO's and PING's arguments are optional, so their arity depends
+                    // on whether one follows
+                    if self.buf[read_op_end_ptr] == b':' {
+                        op = match op {
+                            Op::Hello => Op::HelloWith,
+                            Op::Ping => Op::PingWith,
+                            op => op,
+                        };
+                    }
+                    ptr = read_op_end_ptr;
                     tracing::debug!(session = %self.id, "read op {:?}", op);
                     needs_read = false;
-                    // transition next to read-key-len, even if the op is `Echo`
-                    // since we need to read a length regardless
-                    state = State::ReadKeyLen;
+                    arity = op.arity();
+                    state = if arity == 0 {
+                        State::Done
+                    } else {
+                        State::ReadArgLen
+                    };
                 }
-                State::ReadKeyLen => {
-                    tracing::debug!(session = %self.id, ptr = %ptr, buf_len = %self.buf.len(), "handling State::ReadKeyLen");
+                State::ReadArgLen => {
+                    tracing::debug!(session = %self.id, ptr = %ptr, buf_len = %self.buf.len(), "handling State::ReadArgLen");
                     // read between `:` and `:`
                     while ptr < self.buf.len() {
+                        self.scan(&mut scanned, 1)?;
                         if !between_colons {
                             if self.buf[ptr] != b':' {
-                                return Err(format!(
-                                    "reading key_len, expected ':' found {:?}",
+                                let e = format!(
+                                    "reading argument {} length, expected ':' found {:?}",
+                                    args.len(),
                                     self.buf[ptr] as char
-                                )
-                                .into());
+                                );
+                                return self.invalid(ptr, op, malformed(e));
                             }
                             between_colons = true;
                             ptr += 1;
                         } else if self.buf[ptr] == b':' {
                             between_colons = false;
                             ptr += 1;
-                            key_len = std::str::from_utf8(&key_len_buf)
-                                .map_err(|e| format!("key length is invalid utf8: {e}"))?
-                                .parse::<usize>()?;
-
-                            // if we're echoing, then we want to read into the echo buffer
-                            if op == Op::Echo {
-                                state = State::ReadEcho;
+                            arg_len = match parse_len(&arg_len_buf) {
+                                Ok(len) => len,
+                                Err(e) => return self.invalid(ptr, op, e),
+                            };
+                            arg_len_buf.clear();
+                            let (what, max) = if op.is_value_arg(args.len()) {
+                                ("value", self.limits.max_value_len)
                             } else {
-                                state = State::ReadKey;
+                                ("key", self.limits.max_key_len)
+                            };
+                            if arg_len > max {
+                                return Err(Error::LimitExceeded(format!(
+                                    "{} argument {} is {arg_len} bytes, longer than the max {what} length of {max} bytes",
+                                    op.name(),
+                                    args.len()
+                                )));
+                            }
+                            if matches!(op, Op::Set | Op::SetQ)
+                                && args.len() == 1
+                                && !checksums
+                                && self.stream_min_len.is_some_and(|min| arg_len >= min)
+                            {
+                                self.trace_frame(op, &args);
+                                // the value is left for `value_reader`, and skipped by the next read
+                                self.ptr = ptr;
+                                self.unread_value = arg_len;
+                                return Ok(ProtoOp::SetStream {
+                                    key: args.remove(0),
+                                    len: arg_len,
+                                    value: vec![],
+                                    noreply: op == Op::SetQ,
+                                    durability: None,
+                                });
+                            }
+                            buffered = buffered.saturating_add(arg_len);
+                            if buffered > self.limits.max_request_len {
+                                return Err(Error::LimitExceeded(format!(
+                                    "{} arguments add up to more than the max request length of {} bytes",
+                                    op.name(),
+                                    self.limits.max_request_len
+                                )));
                             }
+                            state = State::ReadArg;
                             continue 'state_loop;
+                        } else if arg_len_buf.len() >= self.limits.max_len_digits {
+                            return Err(Error::LimitExceeded(format!(
+                                "reading argument {} length, longer than {} digits",
+                                args.len(),
+                                self.limits.max_len_digits
+                            )));
                         } else {
-                            key_len_buf.push(self.buf[ptr]);
+                            arg_len_buf.push(self.buf[ptr]);
                             ptr += 1;
                         }
                     }
                     needs_read = true;
                 }
-                State::ReadEcho => {
-                    tracing::debug!(session = %self.id, ptr = %ptr, buf_len = %self.buf.len(), "handling State::ReadEcho");
-                    while ptr < self.buf.len() && echo.len() < key_len {
-                        echo.push(self.buf[ptr]);
-                        ptr += 1;
-                    }
-                    if echo.len() >= key_len {
-                        state = State::Done;
-                        continue 'state_loop;
-                    }
-                    needs_read = true;
-                }
-                State::ReadKey => {
-                    tracing::debug!(session = %self.id, ptr = %ptr, buf_len = %self.buf.len(), "handling State::ReadKey");
-                    while ptr < self.buf.len() && key.len() < key_len {
-                        key.push(self.buf[ptr]);
-                        ptr += 1;
-                    }
-                    if key.len() >= key_len {
-                        match op {
-                            Op::Get => {
-                                state = State::Done;
-                            }
-                            Op::Set => {
-                                state = State::ReadValueLen;
-                            }
-                            Op::Echo => {
-                                unreachable!();
+                State::ReadArg => {
+                    tracing::debug!(session = %self.id, ptr = %ptr, buf_len = %self.buf.len(), "handling State::ReadArg");
+                    let n = (arg_len - arg.len()).min(self.buf.len() - ptr);
+                    arg.extend_from_slice(&self.buf[ptr..ptr + n]);
+                    ptr += n;
+                    if arg.len() >= arg_len {
+                        args.push(std::mem::take(&mut arg));
+                        if matches!(op, Op::MGet | Op::MSet) && args.len() == 1 {
+                            match parse_count(&args[0]) {
+                                // each of MSET's keys is followed by its value
+                                Ok(count) if op == Op::MSet => {
+                                    arity = arity.saturating_add(count.saturating_mul(2))
+                                }
+                                Ok(count) => arity = arity.saturating_add(count),
+                                Err(e) => return self.invalid(ptr, op, e),
                             }
                         }
+                        if args.len() < arity {
+                            state = State::ReadArgLen;
+                        } else if args.len() < arity + op.optional_args() {
+                            state = State::MaybeArg;
+                        } else {
+                            state = State::Done;
+                        }
                         continue 'state_loop;
                     }
                     needs_read = true;
                 }
-                State::ReadValueLen => {
-                    tracing::debug!(session = %self.id, ptr = %ptr, buf_len = %self.buf.len(), "handling State::ReadValueLen");
-                    // read between `:` and `:`
-                    while ptr < self.buf.len() {
-                        if !between_colons {
-                            if self.buf[ptr] != b':' {
-                                return Err(format!(
-                                    "reading value_len, expected ':' found {:?}",
-                                    &self.buf[ptr]
-                                )
-                                .into());
-                            }
-                            between_colons = true;
-                            ptr += 1;
-                        } else if self.buf[ptr] == b':' {
-                            between_colons = false;
-                            ptr += 1;
-                            value_len = std::str::from_utf8(&value_len_buf)
-                                .map_err(|e| format!("value length is invalid utf8: {e}"))?
-                                .parse::<usize>()?;
-                            state = State::ReadValue;
-                            continue 'state_loop;
-                        } else {
-                            value_len_buf.push(self.buf[ptr]);
-                            ptr += 1;
-                        }
+                State::MaybeArg => {
+                    tracing::debug!(session = %self.id, ptr = %ptr, buf_len = %self.buf.len(), "handling State::MaybeArg");
+                    // the byte after an argument is the `:` leading another one,
+                    // or else anything up to the command's trailing newline
+                    match self.buf.get(ptr) {
+                        Some(b':') => state = State::ReadArgLen,
+                        Some(_) => state = State::Done,
+                        None => needs_read = true,
                     }
-                    needs_read = true;
                 }
-                State::ReadValue => {
-                    tracing::debug!(session = %self.id, ptr = %ptr, buf_len = %self.buf.len(), "handling State::ReadValue");
-                    while ptr < self.buf.len() && value.len() < value_len {
-                        value.push(self.buf[ptr]);
+                State::Checksum => {
+                    tracing::debug!(session = %self.id, ptr = %ptr, buf_len = %self.buf.len(), "handling State::Checksum");
+                    while trailer.len() < CHECKSUM_TRAILER_LEN && ptr < self.buf.len() {
+                        self.scan(&mut scanned, 1)?;
+                        let b = self.buf[ptr];
+                        let valid = match trailer.is_empty() {
+                            true => b == b'#',
+                            false => b.is_ascii_hexdigit(),
+                        };
+                        if !valid {
+                            return Err(Error::FrameCorrupted(format!(
+                                "expected '#' and {} hex digits after the arguments of {}, found {:?}",
+                                CHECKSUM_TRAILER_LEN - 1,
+                                op.name(),
+                                String::from_utf8_lossy(&[&trailer[..], &[b]].concat())
+                            )));
+                        }
+                        trailer.push(b);
                         ptr += 1;
                     }
-                    if value.len() >= value_len {
-                        state = State::Done;
+                    if trailer.len() < CHECKSUM_TRAILER_LEN {
+                        needs_read = true;
                         continue 'state_loop;
                     }
-                    needs_read = true;
+                    let expected = frame.clone().finalize();
+                    let found = std::str::from_utf8(&trailer[1..])
+                        .ok()
+                        .and_then(|hex| u32::from_str_radix(hex, 16).ok());
+                    if found != Some(expected) {
+                        return Err(Error::FrameCorrupted(format!(
+                            "{} checksum is {}, expected {expected:08x}",
+                            op.name(),
+                            String::from_utf8_lossy(&trailer[1..])
+                        )));
+                    }
+                    state = State::Done;
                 }
                 State::Done => {
                     tracing::debug!(session = %self.id, ptr = %ptr, buf_len = %self.buf.len(), "handling State::Done");
-                    let key =
-                        String::from_utf8(key).map_err(|e| format!("key is invalid utf8: {e}"))?;
-                    tracing::debug!(session = %self.id, "handling State::Done: {:?} {:?}", op, key);
-                    match op {
-                        Op::Echo => return Ok(ProtoOp::Echo { msg: echo }),
-                        Op::Get => return Ok(ProtoOp::Get { key }),
-                        // todo: return a ProtoOp::Set that can stream the value from the socket reader
-                        Op::Set => return Ok(ProtoOp::Set { key, value }),
+                    if hashing {
+                        frame.update(&self.buf[frame_start..ptr]);
+                        hashing = false;
+                        state = State::Checksum;
+                        continue 'state_loop;
                     }
+                    tracing::debug!(session = %self.id, "handling State::Done: {:?} {} args", op, args.len());
+                    self.trace_frame(op, &args);
+                    self.ptr = ptr;
+                    return match parse_args(op, arity, args) {
+                        Ok(proto_op) => Ok(proto_op),
+                        Err(e) => self.invalid(ptr, op, e),
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// The value of a `ProtoOp::SetStream`, see `Proto::value_reader`. Reads end once
+/// the whole value has been read, and fail if the connection closes before then.
+pub struct ValueReader<'a, R = Reader> {
+    // value bytes read from the socket along with the op
+    buffered: &'a [u8],
+    // the proto's read position, moved past the buffered bytes as they're read
+    ptr: &'a mut usize,
+    // the proto's count of value bytes yet to be read
+    unread: &'a mut usize,
+    // the rest of the value, still on the socket
+    socket: tokio::io::Take<&'a mut R>,
+    // told of the bytes read from the socket
+    observers: &'a [Arc<dyn ProtoObserver>],
+}
+impl<R: AsyncRead + Unpin> AsyncRead for ValueReader<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        if !this.buffered.is_empty() {
+            let n = this.buffered.len().min(buf.remaining());
+            buf.put_slice(&this.buffered[..n]);
+            this.buffered = &this.buffered[n..];
+            *this.ptr += n;
+            *this.unread -= n;
+            return Poll::Ready(Ok(()));
+        }
+        if *this.unread == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.socket).poll_read(cx, buf))?;
+        let n = buf.filled().len() - filled;
+        if n == 0 && buf.remaining() > 0 {
+            return Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        *this.unread -= n;
+        for observer in this.observers {
+            observer.read(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Wait out an idle timeout, or forever when there's none
+async fn idle(timeout: Option<Duration>) {
+    match timeout {
+        Some(timeout) => tokio::time::sleep(timeout).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Reads a streamed value, adding what it reads to the response's checksum when they're on
+struct ChecksumReader<R> {
+    inner: R,
+    hasher: Option<crc32fast::Hasher>,
+}
+impl<R: AsyncRead + Unpin> AsyncRead for ChecksumReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(hasher) = &mut this.hasher {
+            hasher.update(&buf.filled()[filled..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Build the `ProtoOp` for `op` from its arguments, `arity` of them in total
+fn parse_args(op: Op, arity: usize, args: Vec<Vec<u8>>) -> Result<ProtoOp> {
+    let args_len = args.len();
+    let mut args = args.into_iter();
+    let mut next_arg = move || args.next().unwrap_or_default();
+    let proto_op = match op {
+        Op::Hello => ProtoOp::Hello { option: None },
+        Op::Time => ProtoOp::Time,
+        Op::Begin => ProtoOp::Begin,
+        Op::Commit => ProtoOp::Commit,
+        Op::Discard => ProtoOp::Discard,
+        Op::Command => ProtoOp::Command,
+        Op::Healthz => ProtoOp::Healthz,
+        Op::FlushAll => ProtoOp::FlushAll,
+        Op::Snapshot => ProtoOp::Snapshot {
+            name: utf8_key(next_arg())?,
+        },
+        Op::Quit => ProtoOp::Quit,
+        Op::HelloWith => ProtoOp::Hello {
+            option: Some(utf8_key(next_arg())?),
+        },
+        Op::Use => ProtoOp::Use {
+            namespace: utf8_key(next_arg())?,
+        },
+        Op::Echo => ProtoOp::Echo { msg: next_arg() },
+        Op::Ping => ProtoOp::Ping { payload: None },
+        Op::PingWith => ProtoOp::Ping {
+            payload: Some(next_arg()),
+        },
+        Op::WaitRepl => ProtoOp::WaitRepl {
+            replicas: std::str::from_utf8(&next_arg())
+                .map_err(|e| malformed(format!("replicas is invalid utf8: {e}")))?
+                .parse()?,
+            timeout_ms: std::str::from_utf8(&next_arg())
+                .map_err(|e| malformed(format!("timeout is invalid utf8: {e}")))?
+                .parse()?,
+        },
+        Op::Custom { name, arity } => ProtoOp::Custom {
+            name,
+            args: (0..arity).map(|_| next_arg()).collect(),
+        },
+        Op::Get => ProtoOp::Get { key: next_arg() },
+        Op::MGet => {
+            // skip the key count
+            next_arg();
+            ProtoOp::MGet {
+                keys: (1..arity).map(|_| next_arg()).collect(),
+            }
+        }
+        Op::MSet => {
+            // skip the key count
+            next_arg();
+            ProtoOp::MSet {
+                pairs: (1..arity)
+                    .step_by(2)
+                    .map(|_| (next_arg(), next_arg()))
+                    .collect(),
+            }
+        }
+        Op::Set | Op::SetQ => ProtoOp::Set {
+            key: next_arg(),
+            value: next_arg(),
+            noreply: op == Op::SetQ,
+            durability: match next_arg() {
+                durability if durability.is_empty() => None,
+                durability => Some(
+                    std::str::from_utf8(&durability)
+                        .map_err(|e| malformed(format!("durability is invalid utf8: {e}")))?
+                        .parse()?,
+                ),
+            },
+        },
+        Op::Del | Op::DelQ => ProtoOp::Del {
+            key: next_arg(),
+            noreply: op == Op::DelQ,
+        },
+        Op::Strlen => ProtoOp::Strlen { key: next_arg() },
+        Op::Exists => ProtoOp::Exists { key: next_arg() },
+        Op::GetV => ProtoOp::GetVersioned { key: next_arg() },
+        Op::CasV => ProtoOp::SetIfVersion {
+            key: next_arg(),
+            version: String::from_utf8_lossy(&next_arg()).into_owned(),
+            value: next_arg(),
+        },
+        Op::Cas => ProtoOp::Cas {
+            key: next_arg(),
+            expected: next_arg(),
+            value: next_arg(),
+        },
+        Op::GetSet => ProtoOp::GetSet {
+            key: next_arg(),
+            value: next_arg(),
+        },
+        Op::SetRange => ProtoOp::SetRange {
+            key: next_arg(),
+            offset: std::str::from_utf8(&next_arg())
+                .map_err(|e| malformed(format!("offset is invalid utf8: {e}")))?
+                .parse()?,
+            value: next_arg(),
+        },
+        Op::Swap => ProtoOp::Swap {
+            a: next_arg(),
+            b: next_arg(),
+        },
+        Op::Expire => ProtoOp::Expire {
+            key: next_arg(),
+            secs: std::str::from_utf8(&next_arg())
+                .map_err(|e| malformed(format!("ttl is invalid utf8: {e}")))?
+                .parse()?,
+        },
+        Op::Apply => ProtoOp::Apply {
+            key: next_arg(),
+            transform: String::from_utf8_lossy(&next_arg()).into_owned(),
+            arg: next_arg(),
+        },
+        Op::Find => ProtoOp::Find { attr: next_arg() },
+        Op::Scan if args_len > 2 => ProtoOp::Scan {
+            start: next_arg(),
+            end: Some(next_arg()).filter(|end| !end.is_empty()),
+            limit: std::str::from_utf8(&next_arg())
+                .map_err(|e| malformed(format!("scan limit is invalid utf8: {e}")))?
+                .parse()?,
+        },
+        Op::Scan => ProtoOp::ScanCursor {
+            cursor: Some(next_arg()).filter(|cursor| !cursor.is_empty()),
+            count: std::str::from_utf8(&next_arg())
+                .map_err(|e| malformed(format!("scan count is invalid utf8: {e}")))?
+                .parse()?,
+        },
+        #[cfg(feature = "hash")]
+        Op::HSet => ProtoOp::HSet {
+            key: next_arg(),
+            field: utf8_key(next_arg())?,
+            value: next_arg(),
+        },
+        #[cfg(feature = "hash")]
+        Op::HGet => ProtoOp::HGet {
+            key: next_arg(),
+            field: utf8_key(next_arg())?,
+        },
+        #[cfg(feature = "hash")]
+        Op::HGetAll => ProtoOp::HGetAll { key: next_arg() },
+        #[cfg(feature = "hash")]
+        Op::HIncr => ProtoOp::HIncr {
+            key: next_arg(),
+            field: utf8_key(next_arg())?,
+            by: next_arg(),
+        },
+        Op::Debug => {
+            let cmd = next_arg();
+            let arg = next_arg();
+            match cmd.as_slice() {
+                b"SLEEP" => {
+                    let ms = std::str::from_utf8(&arg)
+                        .map_err(|e| malformed(format!("sleep duration is invalid utf8: {e}")))?
+                        .parse::<u64>()?;
+                    ProtoOp::DebugSleep { ms }
+                }
+                b"ERRORS" | b"SLOWLOG" => ProtoOp::DebugLog {
+                    log: match cmd.as_slice() {
+                        b"ERRORS" => DebugLog::Errors,
+                        _ => DebugLog::Slow,
+                    },
+                    reset: match arg.as_slice() {
+                        b"GET" => false,
+                        b"RESET" => true,
+                        _ => {
+                            return Err(malformed(format!(
+                                "unknown debug log action {:?}, expected one of (GET|RESET)",
+                                String::from_utf8_lossy(&arg)
+                            )))
+                        }
+                    },
+                },
+                _ => {
+                    return Err(malformed(format!(
+                        "unknown debug command {:?}",
+                        String::from_utf8_lossy(&cmd)
+                    )))
                 }
             }
         }
+    };
+    Ok(proto_op)
+}
+
+/// Make sure `buf`, holding the residual bytes of a previous read, has at least
+/// `BUF_SIZE` bytes free to read into. Capacity grown by large residuals (e.g. from
+/// big pipelined batches) is kept for the following reads, and only given back once
+/// the buffer is `SHRINK_FACTOR` times larger than it needs to be, so a steady stream
+/// of large residuals doesn't reallocate the buffer on every read.
+fn make_room(buf: &mut Vec<u8>) {
+    let wanted = buf.len() + BUF_SIZE;
+    if buf.capacity() > wanted * SHRINK_FACTOR {
+        buf.shrink_to(wanted);
+    }
+    buf.reserve(BUF_SIZE);
+}
+
+fn hex_dump(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Hex dump the remaining bytes of `buf` without consuming them
+fn hex_dump_buf<B: Buf>(buf: &B) -> String {
+    // our writes chain far fewer chunks than this
+    let mut chunks = [std::io::IoSlice::new(&[]); 16];
+    let n = buf.chunks_vectored(&mut chunks);
+    chunks[..n].iter().map(|chunk| hex_dump(chunk)).collect()
+}
+
+/// The request for the op called `name` with `args`, as `Proto::read` reads it
+pub fn request(name: &str, args: &[&[u8]]) -> Vec<u8> {
+    let len = args.iter().map(|arg| arg.len() + 24).sum::<usize>();
+    let mut req = Vec::with_capacity(name.len() + len + 1);
+    req.extend_from_slice(name.as_bytes());
+    for arg in args {
+        req.extend_from_slice(format!(":{}:", arg.len()).as_bytes());
+        req.extend_from_slice(arg);
+    }
+    req.push(b'\n');
+    req
+}
+
+/// A malformed request, `Proto::invalid` records the op it was for
+fn malformed<R: Into<String>>(reason: R) -> Error {
+    Error::Protocol {
+        op: None,
+        reason: reason.into(),
+    }
+}
+
+/// Parse what follows a streamed SET's value up to its newline, the durability
+/// argument when it starts with a `:`, see `Proto::read_value_end`
+fn parse_value_end(end: &[u8]) -> Result<Option<Durability>> {
+    let Some(arg) = end.strip_prefix(b":") else {
+        return Ok(None);
+    };
+    let Some(colon) = arg.iter().position(|b| *b == b':') else {
+        return Err(malformed("reading durability length, expected ':'"));
+    };
+    let len = parse_len(&arg[..colon])?;
+    let durability = &arg[colon + 1..];
+    if durability.len() != len {
+        return Err(malformed(format!(
+            "durability is {} bytes, expected {len}",
+            durability.len()
+        )));
+    }
+    Ok(Some(
+        std::str::from_utf8(durability)
+            .map_err(|e| malformed(format!("durability is invalid utf8: {e}")))?
+            .parse()?,
+    ))
+}
+
+fn parse_count(count: &[u8]) -> Result<usize> {
+    Ok(std::str::from_utf8(count)
+        .map_err(|e| malformed(format!("key count is invalid utf8: {e}")))?
+        .parse::<usize>()?)
+}
+
+fn utf8_key(key: Vec<u8>) -> Result<String> {
+    String::from_utf8(key).map_err(|e| malformed(format!("key is invalid utf8: {e}")))
+}
+
+/// Parse an argument length, which must be canonical: decimal digits only,
+/// with no sign, whitespace or leading zeros (other than `0` itself)
+fn parse_len(len: &[u8]) -> Result<usize> {
+    let canonical = match len {
+        [] => false,
+        [b'0'] => true,
+        [b'0', ..] => false,
+        _ => len.iter().all(u8::is_ascii_digit),
+    };
+    let invalid = |reason: &str| {
+        format!(
+            "invalid argument length {:?}, {reason}",
+            String::from_utf8_lossy(len)
+        )
+    };
+    if !canonical {
+        return Err(malformed(invalid(
+            "lengths must be decimal digits without a sign or leading zeros",
+        )));
+    }
+    let len = std::str::from_utf8(len).expect("ascii digits are valid utf8");
+    // only digits remain, so this can only fail by overflowing
+    len.parse::<usize>()
+        .map_err(|e| malformed(invalid(&e.to_string())))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use assert_matches::assert_matches;
+    use tokio::io::{duplex, AsyncRead, AsyncReadExt, AsyncWriteExt, DuplexStream, ReadBuf};
+    use tokio::sync::broadcast;
+
+    use super::{
+        make_room, parse_len, BufferPool, DebugLog, Proto, ProtoOp, Shutdown, BUF_SIZE,
+        MAX_POOLED_CAPACITY, SHRINK_FACTOR,
+    };
+    use crate::error::Error;
+    use crate::store::Durability;
+
+    /// A proto reading what's written to the returned end of a duplex pair, without
+    /// a socket or TLS handshake in the way
+    fn duplex_proto() -> (
+        Proto<DuplexStream, DuplexStream>,
+        DuplexStream,
+        broadcast::Sender<Shutdown>,
+    ) {
+        let (client, server) = duplex(64 * 1024);
+        let (kill_send, kill) = broadcast::channel(2);
+        let addr = "127.0.0.1:7719".parse().unwrap();
+        (Proto::new("test", addr, server, kill), client, kill_send)
+    }
+
+    /// A request for every command, with the op it's read as
+    fn requests() -> Vec<(&'static [u8], ProtoOp)> {
+        let k = || b"foo".to_vec();
+        #[allow(unused_mut)]
+        let mut requests: Vec<(&'static [u8], ProtoOp)> = vec![
+            (b"GET:3:foo\n", ProtoOp::Get { key: k() }),
+            (
+                b"MGET:1:2:3:foo:3:bar\n",
+                ProtoOp::MGet {
+                    keys: vec![k(), b"bar".to_vec()],
+                },
+            ),
+            (
+                b"MSET:1:2:1:a:1:x:1:b:1:y\n",
+                ProtoOp::MSet {
+                    pairs: vec![
+                        (b"a".to_vec(), b"x".to_vec()),
+                        (b"b".to_vec(), b"y".to_vec()),
+                    ],
+                },
+            ),
+            (
+                b"SET:3:foo:3:bar\n",
+                ProtoOp::Set {
+                    key: k(),
+                    value: b"bar".to_vec(),
+                    noreply: false,
+                    durability: None,
+                },
+            ),
+            (
+                b"SET:3:foo:3:bar:5:fsync\n",
+                ProtoOp::Set {
+                    key: k(),
+                    value: b"bar".to_vec(),
+                    noreply: false,
+                    durability: Some(Durability::Fsync),
+                },
+            ),
+            (
+                b"SETQ:3:foo:0:\n",
+                ProtoOp::Set {
+                    key: k(),
+                    value: vec![],
+                    noreply: true,
+                    durability: None,
+                },
+            ),
+            (
+                b"DEL:3:foo\n",
+                ProtoOp::Del {
+                    key: k(),
+                    noreply: false,
+                },
+            ),
+            (
+                b"DELQ:3:foo\n",
+                ProtoOp::Del {
+                    key: k(),
+                    noreply: true,
+                },
+            ),
+            (b"STRLEN:3:foo\n", ProtoOp::Strlen { key: k() }),
+            (b"EXISTS:3:foo\n", ProtoOp::Exists { key: k() }),
+            (b"GETV:3:foo\n", ProtoOp::GetVersioned { key: k() }),
+            (
+                b"CASV:3:foo:2:v1:3:bar\n",
+                ProtoOp::SetIfVersion {
+                    key: k(),
+                    version: "v1".into(),
+                    value: b"bar".to_vec(),
+                },
+            ),
+            (
+                b"CAS:3:foo:3:bar:3:baz\n",
+                ProtoOp::Cas {
+                    key: k(),
+                    expected: b"bar".to_vec(),
+                    value: b"baz".to_vec(),
+                },
+            ),
+            (
+                b"GETSET:3:foo:3:bar\n",
+                ProtoOp::GetSet {
+                    key: k(),
+                    value: b"bar".to_vec(),
+                },
+            ),
+            (
+                b"SETRANGE:3:foo:1:2:2:ab\n",
+                ProtoOp::SetRange {
+                    key: k(),
+                    offset: 2,
+                    value: b"ab".to_vec(),
+                },
+            ),
+            (
+                b"SWAP:1:a:1:b\n",
+                ProtoOp::Swap {
+                    a: b"a".to_vec(),
+                    b: b"b".to_vec(),
+                },
+            ),
+            (
+                b"EXPIRE:3:foo:2:60\n",
+                ProtoOp::Expire { key: k(), secs: 60 },
+            ),
+            (
+                b"APPLY:3:foo:3:add:1:5\n",
+                ProtoOp::Apply {
+                    key: k(),
+                    transform: "add".into(),
+                    arg: b"5".to_vec(),
+                },
+            ),
+            (
+                b"FIND:4:blue\n",
+                ProtoOp::Find {
+                    attr: b"blue".to_vec(),
+                },
+            ),
+            (
+                b"SCAN:1:a:1:z:2:10\n",
+                ProtoOp::Scan {
+                    start: b"a".to_vec(),
+                    end: Some(b"z".to_vec()),
+                    limit: 10,
+                },
+            ),
+            (
+                b"SCAN:0::2:10\n",
+                ProtoOp::ScanCursor {
+                    cursor: None,
+                    count: 10,
+                },
+            ),
+            (b"FLUSHALL\n", ProtoOp::FlushAll),
+            (
+                b"SNAPSHOT:6:backup\n",
+                ProtoOp::Snapshot {
+                    name: "backup".into(),
+                },
+            ),
+            (b"BEGIN\n", ProtoOp::Begin),
+            (b"COMMIT\n", ProtoOp::Commit),
+            (b"DISCARD\n", ProtoOp::Discard),
+            (b"HELLO\n", ProtoOp::Hello { option: None }),
+            (
+                b"HELLO:3:hex\n",
+                ProtoOp::Hello {
+                    option: Some("hex".into()),
+                },
+            ),
+            (b"TIME\n", ProtoOp::Time),
+            (b"COMMAND\n", ProtoOp::Command),
+            (b"HEALTHZ\n", ProtoOp::Healthz),
+            (b"QUIT\n", ProtoOp::Quit),
+            (
+                b"USE:5:users\n",
+                ProtoOp::Use {
+                    namespace: "users".into(),
+                },
+            ),
+            (
+                b"ECHO:2:hi\n",
+                ProtoOp::Echo {
+                    msg: b"hi".to_vec(),
+                },
+            ),
+            (b"PING\n", ProtoOp::Ping { payload: None }),
+            (
+                b"PING:2:hi\n",
+                ProtoOp::Ping {
+                    payload: Some(b"hi".to_vec()),
+                },
+            ),
+            (
+                b"WAITREPL:1:2:3:500\n",
+                ProtoOp::WaitRepl {
+                    replicas: 2,
+                    timeout_ms: 500,
+                },
+            ),
+            (b"DEBUG:5:SLEEP:2:10\n", ProtoOp::DebugSleep { ms: 10 }),
+            (
+                b"DEBUG:6:ERRORS:5:RESET\n",
+                ProtoOp::DebugLog {
+                    log: DebugLog::Errors,
+                    reset: true,
+                },
+            ),
+        ];
+        #[cfg(feature = "hash")]
+        requests.extend([
+            (
+                &b"HSET:3:foo:4:name:3:ada\n"[..],
+                ProtoOp::HSet {
+                    key: k(),
+                    field: "name".into(),
+                    value: b"ada".to_vec(),
+                },
+            ),
+            (
+                b"HGET:3:foo:4:name\n",
+                ProtoOp::HGet {
+                    key: k(),
+                    field: "name".into(),
+                },
+            ),
+            (b"HGETALL:3:foo\n", ProtoOp::HGetAll { key: k() }),
+            (
+                b"HINCR:3:foo:4:hits:1:1\n",
+                ProtoOp::HIncr {
+                    key: k(),
+                    field: "hits".into(),
+                    by: b"1".to_vec(),
+                },
+            ),
+        ]);
+        requests
+    }
+
+    #[tokio::test]
+    async fn test_read_each_op() {
+        for (request, expected) in requests() {
+            let (mut proto, mut client, _kill) = duplex_proto();
+            client.write_all(request).await.unwrap();
+            let op = proto.read().await.unwrap();
+            assert_eq!(expected, op, "{}", String::from_utf8_lossy(request));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_requests() {
+        let mut forwarded = 0;
+        for (_, op) in requests() {
+            let Some(request) = op.request() else {
+                continue;
+            };
+            let (mut proto, mut client, _kill) = duplex_proto();
+            client.write_all(&request).await.unwrap();
+            // writes without a reply are forwarded as the ones with one
+            let expected = match op {
+                ProtoOp::Set {
+                    key,
+                    value,
+                    durability,
+                    ..
+                } => ProtoOp::Set {
+                    key,
+                    value,
+                    noreply: false,
+                    durability,
+                },
+                ProtoOp::Del { key, .. } => ProtoOp::Del {
+                    key,
+                    noreply: false,
+                },
+                op => op,
+            };
+            let op = proto.read().await.unwrap();
+            assert_eq!(expected, op, "{}", String::from_utf8_lossy(&request));
+            forwarded += 1;
+        }
+        assert!(forwarded >= 13, "{forwarded}");
+    }
+
+    #[tokio::test]
+    async fn test_read_pipelined_byte_by_byte() {
+        // every op split across as many reads as it has bytes, one after the other
+        let (mut proto, mut client, _kill) = duplex_proto();
+        let requests = requests();
+        let bytes: Vec<u8> = requests.iter().flat_map(|(r, _)| r.to_vec()).collect();
+        let writing = tokio::spawn(async move {
+            for b in bytes {
+                client.write_all(&[b]).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+            // a clean end of the stream
+        });
+        for (request, expected) in requests {
+            let op = proto.read().await.unwrap();
+            assert_eq!(expected, op, "{}", String::from_utf8_lossy(request));
+        }
+        writing.await.unwrap();
+        assert_eq!(ProtoOp::SysClose, proto.read().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_read_session_ops() {
+        // commands added by the session's handler
+        let (mut proto, mut client, _kill) = duplex_proto();
+        proto.set_custom_commands(vec![("GREETING", 2)]);
+        client.write_all(b"GREETING:3:ada:2:hi\n").await.unwrap();
+        assert_eq!(
+            ProtoOp::Custom {
+                name: "GREETING",
+                args: vec![b"ada".to_vec(), b"hi".to_vec()],
+            },
+            proto.read().await.unwrap()
+        );
+
+        // long values left on the stream, to be read with the value reader
+        let (mut proto, mut client, _kill) = duplex_proto();
+        proto.set_stream_values(Some(4));
+        client
+            .write_all(b"SET:3:foo:5:hello\nGET:3:foo\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            ProtoOp::SetStream {
+                key: b"foo".to_vec(),
+                len: 5,
+                value: vec![],
+                noreply: false,
+                durability: None,
+            },
+            proto.read().await.unwrap()
+        );
+        let mut value = vec![];
+        proto.value_reader().read_to_end(&mut value).await.unwrap();
+        assert_eq!(b"hello".to_vec(), value);
+        assert_eq!(
+            ProtoOp::Get {
+                key: b"foo".to_vec()
+            },
+            proto.read().await.unwrap()
+        );
+
+        // the durability following a streamed value is read once the value is, or skipped
+        let (mut proto, mut client, _kill) = duplex_proto();
+        proto.set_stream_values(Some(4));
+        client
+            .write_all(b"SET:3:foo:5:hello:5:fsync\nSET:3:bar:5:hello:4:nope\nGET:3:foo\n")
+            .await
+            .unwrap();
+        let op = proto.read().await.unwrap();
+        let mut value = vec![0; 2];
+        proto.value_reader().read_exact(&mut value).await.unwrap();
+        assert_eq!(
+            ProtoOp::SetStream {
+                key: b"foo".to_vec(),
+                len: 5,
+                value: vec![],
+                noreply: false,
+                durability: Some(Durability::Fsync),
+            },
+            proto.read_value_end(op).await.unwrap()
+        );
+        let op = proto.read().await.unwrap();
+        proto.set_strict(false);
+        assert!(matches!(
+            proto.read_value_end(op).await.unwrap(),
+            ProtoOp::Invalid { .. }
+        ));
+        assert_eq!(
+            ProtoOp::Get {
+                key: b"foo".to_vec()
+            },
+            proto.read().await.unwrap()
+        );
+
+        // unknown and malformed requests fail the read, unless the proto isn't strict
+        let (mut proto, mut client, _kill) = duplex_proto();
+        client.write_all(b"NOPE:1:a\n").await.unwrap();
+        assert!(proto.read().await.is_err());
+        let (mut proto, mut client, _kill) = duplex_proto();
+        proto.set_strict(false);
+        client
+            .write_all(b"NOPE:1:a\nGET:x:foo\nECHO:2:hi\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            ProtoOp::Unknown {
+                name: "NOPE".into()
+            },
+            proto.read().await.unwrap()
+        );
+        assert!(matches!(
+            proto.read().await.unwrap(),
+            ProtoOp::Invalid { .. }
+        ));
+        assert_eq!(
+            ProtoOp::Echo {
+                msg: b"hi".to_vec()
+            },
+            proto.read().await.unwrap()
+        );
+
+        // and the ends of a session
+        let (mut proto, _client, kill) = duplex_proto();
+        kill.send(Shutdown::Kill).unwrap();
+        assert_eq!(ProtoOp::Cancelled, proto.read().await.unwrap());
+        let (mut proto, _client, _kill) = duplex_proto();
+        proto.set_idle_timeout(Some(Duration::from_millis(10)));
+        assert_eq!(ProtoOp::IdleTimeout, proto.read().await.unwrap());
+        let (mut proto, client, _kill) = duplex_proto();
+        drop(client);
+        assert_eq!(ProtoOp::SysClose, proto.read().await.unwrap());
+    }
+
+    /// A stream whose reads fail the way a broken socket's would
+    struct FailingReader;
+    impl AsyncRead for FailingReader {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Err(std::io::Error::other("socket broke")))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_errors() {
+        // an unknown op fails before there's an op to blame
+        let (mut proto, mut client, _kill) = duplex_proto();
+        client.write_all(b"NOPE:1:a\n").await.unwrap();
+        assert_matches!(
+            proto.read().await,
+            Err(Error::Protocol { op: None, reason }) if reason.contains("unknown operation \"NOPE\"")
+        );
+
+        // a malformed request fails along with its op
+        let (mut proto, mut client, _kill) = duplex_proto();
+        client.write_all(b"GET:03:foo\n").await.unwrap();
+        assert_matches!(
+            proto.read().await,
+            Err(Error::Protocol { op: Some(op), reason })
+                if op == "GET" && reason.starts_with("invalid argument length \"03\"")
+        );
+        let (mut proto, mut client, _kill) = duplex_proto();
+        client.write_all(b"EXPIRE:3:foo:2:1h\n").await.unwrap();
+        assert_matches!(
+            proto.read().await,
+            Err(Error::Protocol { op: Some(op), .. }) if op == "EXPIRE"
+        );
+
+        // while the socket failing is still an io error
+        let (_kill, kill) = broadcast::channel(2);
+        let addr = "127.0.0.1:7719".parse().unwrap();
+        let mut proto: Proto<_, DuplexStream> = Proto::new("test", addr, FailingReader, kill);
+        assert_matches!(proto.read().await, Err(Error::IO(e)) if e.kind() == std::io::ErrorKind::Other);
+        let (proto, _client, _kill) = duplex_proto();
+        let (mut writer, responses) = duplex(1024);
+        drop(responses);
+        assert_matches!(
+            proto.write_null(&mut writer).await,
+            Err(Error::IO(e)) if e.kind() == std::io::ErrorKind::BrokenPipe
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_responses() {
+        let (proto, _client, _kill) = duplex_proto();
+        let (mut writer, mut responses) = duplex(1024);
+        proto.write_get_result(&mut writer, b"bar").await.unwrap();
+        proto.write_null(&mut writer).await.unwrap();
+        proto.write_error(&mut writer, "bad thing").await.unwrap();
+        proto.flush(&mut writer).await.unwrap();
+        drop(writer);
+        let mut written = vec![];
+        responses.read_to_end(&mut written).await.unwrap();
+        assert_eq!(
+            "3:bar\nnull\nERR:9:bad thing\n",
+            String::from_utf8(written).unwrap()
+        );
+        assert_eq!(1, proto.errors_written());
+    }
+
+    #[test]
+    fn test_buffer_pool() {
+        let pool = BufferPool::new(2);
+        let mut buf = pool.take();
+        assert!(buf.is_empty());
+        buf.extend_from_slice(b"SET:3:key:6:secret");
+        let ptr = buf.as_ptr();
+        pool.put(buf);
+        assert_eq!(1, pool.len());
+
+        // the same allocation comes back, without what the last session read
+        let buf = pool.take();
+        assert_eq!(ptr, buf.as_ptr());
+        assert!(buf.is_empty());
+        assert!(pool.is_empty());
+
+        // at most `max_bufs` are kept, and oversized buffers aren't kept at all
+        pool.put(Vec::with_capacity(MAX_POOLED_CAPACITY + 1));
+        assert!(pool.is_empty());
+        for _ in 0..3 {
+            pool.put(Vec::with_capacity(BUF_SIZE));
+        }
+        assert_eq!(2, pool.len());
+
+        let pool = BufferPool::new(0);
+        pool.put(buf);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn test_make_room() {
+        // an empty buffer gets room for a full read
+        let mut buf = Vec::new();
+        make_room(&mut buf);
+        assert!(buf.capacity() >= BUF_SIZE);
+
+        // residual bytes are kept, with room to read behind them
+        let mut buf = vec![7; BUF_SIZE * 3];
+        make_room(&mut buf);
+        assert_eq!(vec![7; BUF_SIZE * 3], buf);
+        assert!(buf.capacity() >= BUF_SIZE * 4);
+
+        // capacity grown by earlier residuals isn't given back for smaller ones...
+        let mut buf = Vec::with_capacity(BUF_SIZE * 8);
+        buf.extend_from_slice(&[1; BUF_SIZE]);
+        make_room(&mut buf);
+        assert!(buf.capacity() >= BUF_SIZE * 8);
+        let ptr = buf.as_ptr();
+        make_room(&mut buf);
+        assert_eq!(ptr, buf.as_ptr());
+
+        // ...until it's far more than needed
+        let mut buf = Vec::with_capacity(BUF_SIZE * SHRINK_FACTOR * 4);
+        buf.push(1);
+        make_room(&mut buf);
+        assert_eq!(vec![1], buf);
+        assert!(buf.capacity() < BUF_SIZE * SHRINK_FACTOR * 4);
+        assert!(buf.capacity() - buf.len() >= BUF_SIZE);
+    }
+
+    #[test]
+    fn test_parse_len() {
+        assert_eq!(0, parse_len(b"0").unwrap());
+        assert_eq!(5, parse_len(b"5").unwrap());
+        assert_eq!(100, parse_len(b"100").unwrap());
+        for len in [
+            &b""[..],
+            b"00",
+            b"05",
+            b"+5",
+            b"-5",
+            b" 5",
+            b"5 ",
+            b"0x10",
+            b"1e3",
+            b"99999999999999999999999",
+        ] {
+            let err = parse_len(len).expect_err(&String::from_utf8_lossy(len));
+            assert!(err.to_string().contains("invalid argument length"), "{err}");
+        }
     }
 }
@@ -1,32 +1,316 @@
-use crate::error::Result;
+use crate::audit::{AuditRecord, AuditSink, FileAuditSink};
+use crate::client::ring::Ring;
+use crate::client::{ClientCert, Response};
+use crate::config::{
+    Config, ConnectionLimitPolicy, TransactionLimitPolicy, ValueLimitPolicy, WireTrace,
+};
+use crate::error::{Error, Result};
 use crate::get_config;
 use crate::proto;
-use crate::store::{Operation, Store, Transaction};
+use crate::server::connections::{self, Admission, ConnectionSlots};
+use crate::server::diagnostics::RecentLog;
+use crate::server::events::{CloseReason, SessionEvent, EVENT_CAPACITY};
+use crate::server::handler::{Builtin, CommandContext, CommandHandler};
+use crate::server::metrics::{self, CommandMetrics, Outcome, TrafficMetrics};
+use crate::server::sessions::Sessions;
+use crate::server::shards::Shards;
+use crate::server::{
+    bind_listener, load_client_cas, load_node_client_cert, set_socket_buffers, TlsCerts,
+};
+use crate::store::replication::ReplicationLog;
+use crate::store::transform::Transform;
+use crate::store::{self, Durability, Operation, Store, Transaction};
+use crate::utils;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::io::split;
-use tokio::net::TcpListener;
+use std::time::{Duration, Instant};
+use tokio::io::{split, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
 use tokio::sync::broadcast;
 use tokio::sync::broadcast::Receiver;
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
-use tokio_rustls::rustls::{self, Certificate, PrivateKey};
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore, SemaphorePermit};
+use tokio_rustls::rustls::{Certificate, PrivateKey};
 use tokio_rustls::TlsAcceptor;
+use tracing::Instrument;
+
+/// Per-session behavior configured on the `ClientServer`
+#[derive(Clone, Debug, Default)]
+pub struct SessionOptions {
+    // whether DEBUG commands are accepted, these should only be enabled for testing
+    pub debug_commands: bool,
+    // whether FLUSHALL may clear the store, answered with an error otherwise
+    pub flushall: bool,
+    // directory SNAPSHOT writes its files to, answered with an error when unset
+    pub snapshot_dir: Option<PathBuf>,
+    // max duration a single command may take before the session is closed
+    pub command_timeout: Option<Duration>,
+    // how long a session may wait for its client to send anything before it's closed,
+    // sessions wait forever when unset
+    pub idle_timeout: Option<Duration>,
+    // where to record mutating commands, auditing is disabled when unset
+    pub audit: Option<Arc<dyn AuditSink>>,
+    // largest value a client may SET, unlimited when unset
+    pub max_value_len: Option<usize>,
+    // how SETs larger than `max_value_len` are handled
+    pub value_limit_policy: ValueLimitPolicy,
+    // most value bytes a single response may carry, unlimited when unset
+    pub max_response_len: Option<usize>,
+    // slots for transactions in flight, shared by every session. Unlimited when unset
+    pub transaction_slots: Option<Arc<Semaphore>>,
+    // how transactions are handled when there are no free slots
+    pub transaction_limit_policy: TransactionLimitPolicy,
+    // where session lifecycle events are published
+    pub events: Option<broadcast::Sender<SessionEvent>>,
+    // commands handled by every session, by outcome
+    pub metrics: CommandMetrics,
+    // connections accepted and bytes moved by every session
+    pub traffic: TrafficMetrics,
+    // recent error responses and session failures of every session, see DEBUG ERRORS
+    pub error_log: RecentLog,
+    // recent commands of every session that took at least `slow_command`, see DEBUG SLOWLOG
+    pub slow_log: RecentLog,
+    // how long a command may take before it's recorded in `slow_log`, disabled when unset
+    pub slow_command: Option<Duration>,
+    // whether unknown commands and malformed requests close the session instead of returning an error
+    pub strict_protocol: bool,
+    // longest arguments read from a request before the session is closed
+    pub proto_limits: proto::ProtoLimits,
+    // shortest SET value streamed off the socket rather than buffered with its request,
+    // values are always buffered when unset
+    pub stream_value_len: Option<usize>,
+    // whether the session's protocol bytes are logged
+    pub wire_trace: WireTrace,
+    // read buffers shared by every session
+    pub buffer_pool: proto::BufferPool,
+    // becomes true once the store has recovered, always ready when unset
+    pub ready: Option<watch::Receiver<bool>>,
+    // SO_SNDBUF and SO_RCVBUF of the session's connection, left to the OS when unset
+    pub send_buffer_size: Option<usize>,
+    pub recv_buffer_size: Option<usize>,
+    // the nodes keys are spread over, every key is this node's when unset
+    pub shards: Option<Shards>,
+    // whether writes are refused, on followers whose store only takes their leader's
+    pub read_only: bool,
+    // where the store's writes are published for followers, when this node leads
+    pub replication: Option<ReplicationLog>,
+}
+/// The byte namespaced keys are stored behind, which keys of the default keyspace may
+/// not start with, so no session reaches the keys of a namespace it isn't using
+pub const NAMESPACE_MARK: u8 = 0xff;
+
+/// Bytes a streamed SET value's buffer grows by as the value is read
+const STREAM_CHUNK_LEN: usize = 64 * 1024;
+
+/// Most writes a session may queue after BEGIN. Their keys and values may add up to
+/// no more than `ProtoLimits::max_request_len`, like the arguments of a single MSET.
+pub const MAX_QUEUED_OPS: usize = 10_000;
+
+/// The writes a session queued since BEGIN, applied as one transaction by its COMMIT
+#[derive(Clone, Debug, Default)]
+pub struct QueuedTransaction {
+    pub operations: Vec<Operation>,
+    // bytes of the queued keys and values
+    pub len: usize,
+    // the most durable of the queued writes', the default unless every SET asks for less
+    pub durability: Option<Durability>,
+    // commands sent since BEGIN, queued or not
+    pub commands: usize,
+}
+impl QueuedTransaction {
+    fn push(&mut self, operation: Operation, durability: Option<Durability>) {
+        self.len += match &operation {
+            Operation::Set(key, value) => key.len() + value.len(),
+            Operation::Delete(key) => key.len(),
+        };
+        let durability = durability.unwrap_or_default();
+        self.durability = Some(self.durability.map_or(durability, |d| d.max(durability)));
+        self.operations.push(operation);
+    }
+}
+
+/// State built up by the commands of a single session
+#[derive(Clone, Debug, Default)]
+pub struct SessionState {
+    // prefixed to every key the session uses, set with USE
+    pub namespace: Option<String>,
+    // how values are written in responses, set with HELLO
+    pub encoding: proto::Encoding,
+    // the writes queued since BEGIN, `None` outside of a transaction
+    pub transaction: Option<QueuedTransaction>,
+    // number of the last replication record published once the session's last write
+    // was, what WAITREPL waits for followers to apply
+    pub last_write: u64,
+}
+impl SessionState {
+    /// Scope the keys `op` refers to by the session's namespace
+    fn scope(&self, op: proto::ProtoOp) -> proto::ProtoOp {
+        match &self.namespace {
+            Some(_) => op.map_keys(|key| self.scoped_key(&key)),
+            None => op,
+        }
+    }
+
+    /// Scope a key by the session's namespace, for custom commands' keys
+    pub fn scoped_key(&self, key: &[u8]) -> Vec<u8> {
+        match self.key_prefix() {
+            Some(prefix) => [prefix.as_slice(), key].concat(),
+            None => key.to_vec(),
+        }
+    }
+
+    /// What the keys of the session's namespace are stored behind, `None` in the
+    /// default keyspace
+    fn key_prefix(&self) -> Option<Vec<u8>> {
+        let namespace = self.namespace.as_ref()?;
+        Some([&[NAMESPACE_MARK], namespace.as_bytes(), b":"].concat())
+    }
+
+    /// Where scans of the session's keys end, after the last key of its namespace or
+    /// before the first namespaced key of all
+    fn scan_end(&self) -> Vec<u8> {
+        match &self.namespace {
+            Some(namespace) => [&[NAMESPACE_MARK], namespace.as_bytes(), b";"].concat(),
+            None => vec![NAMESPACE_MARK],
+        }
+    }
+
+    /// Whether `key`, as it's stored, is one of the session's keys
+    fn in_keyspace(&self, key: &[u8]) -> bool {
+        match self.key_prefix() {
+            Some(prefix) => key.starts_with(&prefix),
+            None => key.first() != Some(&NAMESPACE_MARK),
+        }
+    }
+}
+
+impl SessionOptions {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            max_value_len: config.max_value_bytes,
+            value_limit_policy: config.value_limit_policy,
+            max_response_len: config.max_response_bytes,
+            transaction_slots: config.max_transactions.map(|n| Arc::new(Semaphore::new(n))),
+            transaction_limit_policy: config.transaction_limit_policy,
+            strict_protocol: config.strict_protocol,
+            flushall: config.flushall,
+            snapshot_dir: config.snapshot_dir.clone(),
+            proto_limits: proto::ProtoLimits {
+                max_key_len: config.max_key_bytes,
+                max_value_len: config.max_request_value_bytes,
+                max_scan_len: config.max_scan_bytes,
+                max_request_len: config.max_request_bytes,
+                ..proto::ProtoLimits::default()
+            },
+            stream_value_len: config.stream_value_bytes,
+            slow_command: config.slow_command,
+            idle_timeout: config.idle_timeout,
+            wire_trace: config.wire_trace,
+            send_buffer_size: config.socket_send_buffer_bytes,
+            recv_buffer_size: config.socket_recv_buffer_bytes,
+            ..Self::default()
+        }
+    }
+
+    /// Whether the store is ready to serve, sessions only answer ops that
+    /// don't touch it until it is
+    pub fn is_ready(&self) -> bool {
+        self.ready.as_ref().is_none_or(|ready| *ready.borrow())
+    }
+
+    /// Take a transaction slot, waiting for one to free up or failing with
+    /// a retryable error depending on the policy. `None` when transactions are unlimited.
+    async fn transaction_slot(&self) -> Result<Option<SemaphorePermit<'_>>> {
+        let slots = match &self.transaction_slots {
+            Some(slots) => slots,
+            None => return Ok(None),
+        };
+        let slot = match self.transaction_limit_policy {
+            TransactionLimitPolicy::Queue => slots
+                .acquire()
+                .await
+                .map_err(|e| format!("error acquiring transaction slot: {e}"))?,
+            TransactionLimitPolicy::Reject => slots
+                .try_acquire()
+                .map_err(|_| "busy: too many transactions in flight, retry later")?,
+        };
+        Ok(Some(slot))
+    }
+
+    /// Publish an event, only building it when someone is subscribed
+    fn emit<F: FnOnce() -> SessionEvent>(&self, event: F) {
+        if let Some(events) = &self.events {
+            if events.receiver_count() > 0 {
+                // subscribers may have gone away since checking
+                let _ = events.send(event());
+            }
+        }
+    }
+
+    /// The error to answer with instead of a response carrying `len` value bytes,
+    /// when that's more than a response may carry
+    fn response_too_large(&self, len: usize) -> Option<String> {
+        match self.max_response_len {
+            Some(max) if len > max => Some(format!(
+                "response of {len} bytes exceeds max response size of {max} bytes, use pagination"
+            )),
+            _ => None,
+        }
+    }
+
+    fn audit(&self, id: &str, peer: SocketAddr, op: &str, key: &[u8], result: &str) {
+        if let Some(audit) = &self.audit {
+            // todo: include the authenticated identity once clients can authenticate
+            let key = String::from_utf8_lossy(key);
+            audit.record(AuditRecord::new(id, peer, None, op, &key, result));
+        }
+    }
+}
+
+/// A client's connection, as accepted by one of the `ClientServer`'s listeners
+pub enum Incoming {
+    Tcp(TcpStream),
+    // its sessions skip TLS, the socket file's permissions are what keeps others out
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+impl Incoming {
+    /// The connection as it was accepted, before any TLS handshake
+    fn into_stream(self) -> Box<dyn proto::Stream> {
+        match self {
+            Self::Tcp(stream) => Box::new(stream),
+            #[cfg(unix)]
+            Self::Unix(stream) => Box::new(stream),
+        }
+    }
+}
 
 pub struct Connection<S> {
     id: String,
-    stream: tokio::net::TcpStream,
+    stream: Incoming,
     addr: std::net::SocketAddr,
     acceptor: TlsAcceptor,
     store: S,
-    kill: Receiver<bool>,
+    kill: Receiver<proto::Shutdown>,
+    options: SessionOptions,
+    handler: Arc<dyn CommandHandler<S>>,
 }
 impl<S: Store + Send + Sync + Clone + 'static> Connection<S> {
+    /// A session over `stream`, whose handshake is accepted by `acceptor` when it's
+    /// a TCP connection
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: String,
-        stream: tokio::net::TcpStream,
+        stream: Incoming,
         addr: std::net::SocketAddr,
         acceptor: TlsAcceptor,
         store: S,
-        kill: Receiver<bool>,
+        kill: Receiver<proto::Shutdown>,
+        options: SessionOptions,
+        handler: Arc<dyn CommandHandler<S>>,
     ) -> Self {
         Self {
             id,
@@ -35,56 +319,1509 @@ impl<S: Store + Send + Sync + Clone + 'static> Connection<S> {
             acceptor,
             store,
             kill,
+            options,
+            handler,
         }
     }
 
     pub async fn handle(mut self) -> Result<()> {
         let id = self.id;
-        let stream = self
-            .acceptor
-            .accept(self.stream)
-            .await
-            .map_err(|e| format!("session={id} error accepting stream: {e}"))?;
+        let stream: Box<dyn proto::Stream> = match self.stream {
+            Incoming::Tcp(stream) => Box::new(
+                self.acceptor
+                    .accept(stream)
+                    .await
+                    .map_err(|e| format!("session={id} error accepting stream: {e}"))?,
+            ),
+            #[cfg(unix)]
+            stream @ Incoming::Unix(_) => stream.into_stream(),
+        };
+
+        let peer = self.addr;
+        self.options.emit(|| SessionEvent::Opened {
+            session: id.clone(),
+            peer,
+        });
 
         let (reader, mut writer) = split(stream);
         let mut proto = proto::Proto::new(&id, self.addr, reader, self.kill);
-        loop {
-            let op = proto.read().await;
-            match op? {
-                proto::ProtoOp::SysClose => {
-                    tracing::debug!(session = %id, "EOF on socket, disconnecting");
-                    return Ok(());
+        proto
+            .set_strict(self.options.strict_protocol)
+            .set_limits(self.options.proto_limits)
+            .set_stream_values(self.options.stream_value_len)
+            .set_idle_timeout(self.options.idle_timeout)
+            .add_observer(Arc::new(self.options.error_log.clone()))
+            .add_observer(Arc::new(self.options.traffic.clone()))
+            .set_wire_trace(self.options.wire_trace)
+            .set_custom_commands(self.handler.commands())
+            .set_buffer_pool(self.options.buffer_pool.clone());
+        let mut state = SessionState::default();
+        let store = &mut self.store;
+        let options = &self.options;
+        let handler = &self.handler;
+        let served = async {
+            loop {
+                let op = match proto.read().await {
+                    Ok(proto::ProtoOp::SetStream {
+                        key, len, noreply, ..
+                    }) => {
+                        let value = Self::read_streamed_value(&mut proto, options, len).await?;
+                        let op = proto::ProtoOp::SetStream {
+                            key,
+                            len,
+                            value,
+                            noreply,
+                            durability: None,
+                        };
+                        proto.read_value_end(op).await
+                    }
+                    op => op,
+                };
+                let op = match op {
+                    Ok(op) => op,
+                    // the request is malformed, or its unread bytes can't be skipped or
+                    // can't be trusted, but the client is told why before the session ends
+                    Err(
+                        e @ (Error::Protocol { .. }
+                        | Error::LimitExceeded(_)
+                        | Error::FrameCorrupted(_)),
+                    ) => {
+                        proto.write_error(&mut writer, &e.to_string()).await?;
+                        proto.flush(&mut writer).await?;
+                        return Err(e);
+                    }
+                    Err(e) => return Err(e),
+                };
+                if op.uses_store() && !options.is_ready() {
+                    proto
+                        .write_error(
+                            &mut writer,
+                            "starting: the store is still recovering, retry later",
+                        )
+                        .await?;
+                    proto.flush(&mut writer).await?;
+                    continue;
                 }
-                proto::ProtoOp::Cancelled => {
-                    tracing::debug!(session = %id, "connection cancelled, disconnecting");
-                    return Ok(());
+                let name = op.name();
+                // only these ops end the session without an error
+                let closing = match op {
+                    proto::ProtoOp::SysClose => CloseReason::ClientDisconnected,
+                    proto::ProtoOp::Quit => CloseReason::ClientQuit,
+                    proto::ProtoOp::Reset => CloseReason::ConnectionReset,
+                    proto::ProtoOp::Cancelled => CloseReason::ServerShutdown,
+                    proto::ProtoOp::IdleTimeout => CloseReason::IdleTimeout,
+                    _ => CloseReason::Error(format!("unexpected end of session after {name:?}")),
+                };
+                let started = Instant::now();
+                let ctx = CommandContext {
+                    id: &id,
+                    store,
+                    options,
+                    state: &mut state,
+                    proto: &proto,
+                    writer: &mut writer,
+                };
+                let errors = proto.errors_written();
+                let written = proto.bytes_written();
+                let span = match name {
+                    Some(op) => tracing::debug_span!(
+                        target: proto::OP_TARGET,
+                        "op",
+                        session = %id,
+                        op,
+                        key_len = tracing::field::Empty,
+                        value_len = tracing::field::Empty,
+                        response_len = tracing::field::Empty,
+                        elapsed_us = tracing::field::Empty,
+                    ),
+                    None => tracing::Span::none(),
+                };
+                // the lengths are only worked out when the span is recorded
+                if !span.is_disabled() {
+                    span.record("key_len", &op.key_len());
+                    span.record("value_len", &op.value_len());
                 }
-                proto::ProtoOp::Echo { msg } => {
-                    proto.write_echo(&mut writer, &msg).await?;
-                    proto.flush(&mut writer).await?;
+                let handled = handler.handle(ctx, op).instrument(span.clone());
+                let (keep_going, timed_out) = match options.command_timeout {
+                    Some(timeout) => match tokio::time::timeout(timeout, handled).await {
+                        Ok(handled) => (handled, false),
+                        Err(_) => (
+                            Err(format!("session={id} command timed out after {timeout:?}").into()),
+                            true,
+                        ),
+                    },
+                    None => (handled.await, false),
+                };
+                let elapsed = started.elapsed();
+                if !span.is_disabled() {
+                    span.record("response_len", &(proto.bytes_written() - written));
+                    span.record("elapsed_us", &(elapsed.as_micros() as u64));
                 }
-                proto::ProtoOp::Get { key } => {
-                    let val = self.store.get(&key).await.unwrap();
-                    if let Some(val) = val {
-                        proto.write_get_result(&mut writer, &val).await?;
-                        proto.flush(&mut writer).await?;
+                drop(span);
+                if let (Some(op), Some(slow)) = (name, options.slow_command) {
+                    if elapsed >= slow {
+                        let msg = format!("{op} took {}ms", elapsed.as_millis());
+                        options.slow_log.push(&id, &msg);
+                    }
+                }
+                if let Some(op) = name {
+                    let outcome = if timed_out {
+                        Outcome::Timeout
+                    } else if keep_going.is_err() || proto.errors_written() > errors {
+                        Outcome::Error
                     } else {
-                        proto.write_null(&mut writer).await?;
+                        Outcome::Ok
+                    };
+                    options.metrics.record(op, outcome);
+                    options.emit(|| SessionEvent::Command {
+                        session: id.clone(),
+                        op,
+                        ok: keep_going.is_ok(),
+                        outcome,
+                        elapsed,
+                    });
+                }
+                // the store turned the write away before applying any of it,
+                // so the client can retry it once the store catches up
+                let keep_going = match keep_going {
+                    Err(e @ Error::Overloaded(_)) => {
+                        tracing::debug!(session = %id, "{e}");
+                        let msg = format!("{} {e}", proto::OVERLOADED);
+                        proto.write_error(&mut writer, &msg).await?;
+                        proto.flush(&mut writer).await?;
+                        Ok(true)
+                    }
+                    Err(e @ Error::DiskFull(_)) => {
+                        tracing::warn!(session = %id, "{e}");
+                        let msg = format!("{} {e}", proto::DISK_FULL);
+                        proto.write_error(&mut writer, &msg).await?;
                         proto.flush(&mut writer).await?;
+                        Ok(true)
                     }
+                    keep_going => keep_going,
+                };
+                if !keep_going? {
+                    return Ok(closing);
                 }
-                proto::ProtoOp::Set { key, value } => {
-                    self.store
-                        .transact(Transaction::with_random_id(vec![Operation::set(
-                            key,
-                            value.as_slice(),
-                        )]))
-                        .await
-                        .ok();
-                    proto.write_set_result(&mut writer, &value).await?;
-                    proto.flush(&mut writer).await?;
+            }
+        };
+        let served: Result<CloseReason> = served.await;
+        if let Err(e) = &served {
+            options.error_log.push(&id, &format!("session failed: {e}"));
+        }
+        options.emit(|| SessionEvent::Closed {
+            session: id.clone(),
+            reason: match &served {
+                Ok(reason) => reason.clone(),
+                Err(e) => CloseReason::Error(e.to_string()),
+            },
+        });
+        served.map(|_| ())
+    }
+
+    /// Apply a single built-in op read from the client and write its result,
+    /// returning whether the session should keep reading
+    pub(crate) async fn handle_op(
+        id: &str,
+        store: &mut S,
+        options: &SessionOptions,
+        state: &mut SessionState,
+        proto: &proto::Proto,
+        writer: &mut proto::Writer,
+        op: proto::ProtoOp,
+    ) -> Result<bool> {
+        if state.namespace.is_none() && !op.keys().iter().all(|key| state.in_keyspace(key)) {
+            let msg = "keys starting with a 0xff byte are reserved for namespaces";
+            proto.write_error(writer, msg).await?;
+            proto.flush(writer).await?;
+            return Ok(true);
+        }
+        // keys owned by other nodes are answered by them
+        let op = match &options.shards {
+            Some(shards) => match Self::route(options, shards, state, proto, writer, op).await? {
+                Some(op) => op,
+                None => return Ok(true),
+            },
+            None => op,
+        };
+        // a follower's writes would be lost to, or overwrite, the ones its leader sends
+        if options.read_only && op.is_transaction() {
+            let msg = format!(
+                "{}: this node is a read-only follower, send writes to its leader",
+                op.name().unwrap_or_default()
+            );
+            proto.write_error(writer, &msg).await?;
+            proto.flush(writer).await?;
+            return Ok(true);
+        }
+        let op = state.scope(op);
+        // writes sent after BEGIN wait for its COMMIT
+        let op = if state.transaction.is_some() {
+            match Self::queue(id, options, proto, writer, &mut state.transaction, op).await? {
+                Some(op) => op,
+                None => return Ok(true),
+            }
+        } else {
+            op
+        };
+        // held until the op is finished to bound the transactions in flight
+        let _slot = if op.is_transaction() {
+            match options.transaction_slot().await {
+                Ok(slot) => slot,
+                Err(e) => {
+                    proto.write_error(writer, &e.to_string()).await?;
+                    proto.flush(writer).await?;
+                    return Ok(true);
+                }
+            }
+        } else {
+            None
+        };
+        let writes = op.is_transaction();
+        match op {
+            proto::ProtoOp::SysClose => {
+                tracing::debug!(session = %id, "client closed the connection, disconnecting");
+                return Ok(false);
+            }
+            proto::ProtoOp::Reset => {
+                tracing::info!(session = %id, "connection reset by client, disconnecting");
+                return Ok(false);
+            }
+            proto::ProtoOp::Cancelled => {
+                tracing::debug!(session = %id, "connection cancelled, disconnecting");
+                return Ok(false);
+            }
+            proto::ProtoOp::IdleTimeout => {
+                tracing::info!(session = %id, "client idle for too long, disconnecting");
+                writer
+                    .shutdown()
+                    .await
+                    .map_err(|e| format!("session={id} error shutting down stream: {e}"))?;
+                return Ok(false);
+            }
+            proto::ProtoOp::Quit => {
+                // commands pipelined after QUIT are left unread in the proto's buffer,
+                // and dropped with it
+                tracing::debug!(session = %id, "client quit, disconnecting");
+                proto.write_ok(writer).await?;
+                proto.flush(writer).await?;
+                writer
+                    .shutdown()
+                    .await
+                    .map_err(|e| format!("session={id} error shutting down stream: {e}"))?;
+                return Ok(false);
+            }
+            proto::ProtoOp::Ping { payload } => {
+                match payload {
+                    Some(payload) => match options.response_too_large(payload.len()) {
+                        Some(msg) => proto.write_error(writer, &msg).await?,
+                        None => proto.write_echo(writer, &payload).await?,
+                    },
+                    None => proto.write_pong(writer).await?,
+                }
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::Echo { msg } => {
+                match options.response_too_large(msg.len()) {
+                    Some(msg) => proto.write_error(writer, &msg).await?,
+                    None => proto.write_echo(writer, &msg).await?,
+                }
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::Get { key } => {
+                let val = store.get(&key).await?;
+                if let Some(val) = val {
+                    let val = state.encoding.encode(&val);
+                    match options.response_too_large(val.len()) {
+                        Some(msg) => proto.write_error(writer, &msg).await?,
+                        None => proto.write_get_result(writer, &val).await?,
+                    }
+                    proto.flush(writer).await?;
+                } else {
+                    proto.write_null(writer).await?;
+                    proto.flush(writer).await?;
+                }
+            }
+            proto::ProtoOp::MGet { keys } => {
+                let keys = keys.iter().map(Vec::as_slice).collect::<Vec<_>>();
+                let values = store.get_many(&keys).await?;
+                let values = values
+                    .iter()
+                    .map(|val| val.as_deref().map(|val| state.encoding.encode(val)))
+                    .collect::<Vec<_>>();
+                let len = values.iter().flatten().map(|val| val.len()).sum();
+                match options.response_too_large(len) {
+                    Some(msg) => proto.write_error(writer, &msg).await?,
+                    None => proto.write_values(writer, &values).await?,
+                }
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::MSet { pairs } => {
+                // like CASV, values are never truncated, a truncated one would be written
+                // as if it were what the client sent, and one too large fails them all
+                let too_large = match options.max_value_len {
+                    Some(max) => {
+                        pairs
+                            .iter()
+                            .find(|(_, value)| value.len() > max)
+                            .map(|(_, value)| {
+                                format!(
+                                    "value of {} bytes exceeds max value size of {max} bytes",
+                                    value.len()
+                                )
+                            })
+                    }
+                    None => None,
+                };
+                match too_large {
+                    Some(msg) => {
+                        for (key, _) in &pairs {
+                            options.audit(id, proto.addr(), "MSET", key, "rejected");
+                        }
+                        proto.write_error(writer, &msg).await?;
+                    }
+                    None => {
+                        let keys = pairs.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>();
+                        let operations = pairs
+                            .into_iter()
+                            .map(|(key, value)| Operation::Set(key, value))
+                            .collect();
+                        match store
+                            .transact(Transaction::with_random_id(operations))
+                            .await
+                        {
+                            Ok(existed) => {
+                                for (key, existed) in keys.iter().zip(existed) {
+                                    let result = if existed { "updated" } else { "created" };
+                                    options.audit(id, proto.addr(), "MSET", key, result);
+                                }
+                                proto.write_ok(writer).await?;
+                            }
+                            Err(e) => {
+                                for key in &keys {
+                                    options.audit(id, proto.addr(), "MSET", key, "error");
+                                }
+                                return Err(e);
+                            }
+                        }
+                    }
+                }
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::Set {
+                key,
+                value,
+                noreply,
+                durability,
+            } => {
+                let len = value.len();
+                Self::set(
+                    id, store, options, proto, writer, key, value, len, noreply, durability,
+                )
+                .await?;
+            }
+            // the session has read as much of the value as the max value size keeps
+            proto::ProtoOp::SetStream {
+                key,
+                len,
+                value,
+                noreply,
+                durability,
+            } => {
+                Self::set(
+                    id, store, options, proto, writer, key, value, len, noreply, durability,
+                )
+                .await?;
+            }
+            proto::ProtoOp::Del { key, noreply } => {
+                let res = store
+                    .transact(Transaction::with_random_id(vec![Operation::delete(
+                        key.as_slice(),
+                    )]))
+                    .await;
+                let deleted = match res {
+                    Ok(existed) => existed.iter().filter(|existed| **existed).count(),
+                    Err(e) => {
+                        options.audit(id, proto.addr(), "DEL", &key, "error");
+                        return Err(e);
+                    }
+                };
+                let result = if deleted > 0 { "deleted" } else { "not_found" };
+                options.audit(id, proto.addr(), "DEL", &key, result);
+                if !noreply {
+                    proto.write_int(writer, deleted).await?;
+                    proto.flush(writer).await?;
+                }
+            }
+            proto::ProtoOp::Strlen { key } => {
+                match store.value_len(&key).await? {
+                    Some(len) => proto.write_int(writer, len).await?,
+                    None => proto.write_null(writer).await?,
+                }
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::Exists { key } => {
+                let exists = store.exists(&key).await?;
+                proto.write_int(writer, exists as usize).await?;
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::GetVersioned { key } => {
+                match store.get_versioned(&key).await? {
+                    Some((val, version)) => {
+                        let val = state.encoding.encode(&val);
+                        match options.response_too_large(val.len()) {
+                            Some(msg) => proto.write_error(writer, &msg).await?,
+                            None => {
+                                proto
+                                    .write_fields(writer, &[&val, version.as_bytes()])
+                                    .await?
+                            }
+                        }
+                    }
+                    None => proto.write_null(writer).await?,
+                }
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::SetIfVersion {
+                key,
+                version,
+                value,
+            } => {
+                // a truncated value would be written as if it were what the client read
+                match options.max_value_len {
+                    Some(max) if value.len() > max => {
+                        options.audit(id, proto.addr(), "CASV", &key, "rejected");
+                        let msg = format!(
+                            "value of {} bytes exceeds max value size of {max} bytes",
+                            value.len()
+                        );
+                        proto.write_error(writer, &msg).await?;
+                    }
+                    _ => match store.set_if_version(&key, &version, &value).await {
+                        Ok(set) => {
+                            let result = if set { "written" } else { "conflict" };
+                            options.audit(id, proto.addr(), "CASV", &key, result);
+                            proto.write_int(writer, set as usize).await?;
+                        }
+                        Err(e) => {
+                            options.audit(id, proto.addr(), "CASV", &key, "error");
+                            return Err(e);
+                        }
+                    },
+                }
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::Cas {
+                key,
+                expected,
+                value,
+            } => {
+                // like CASV, a truncated value would be written as if it were whole
+                match options.max_value_len {
+                    Some(max) if value.len() > max => {
+                        options.audit(id, proto.addr(), "CAS", &key, "rejected");
+                        let msg = format!(
+                            "value of {} bytes exceeds max value size of {max} bytes",
+                            value.len()
+                        );
+                        proto.write_error(writer, &msg).await?;
+                    }
+                    _ => match store.compare_and_swap(&key, &expected, &value).await {
+                        Ok(set) => {
+                            let result = if set { "written" } else { "conflict" };
+                            options.audit(id, proto.addr(), "CAS", &key, result);
+                            proto.write_int(writer, set as usize).await?;
+                        }
+                        Err(e) => {
+                            options.audit(id, proto.addr(), "CAS", &key, "error");
+                            return Err(e);
+                        }
+                    },
+                }
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::GetSet { key, value } => {
+                // like CAS, a truncated value would be written as if it were whole
+                match options.max_value_len {
+                    Some(max) if value.len() > max => {
+                        options.audit(id, proto.addr(), "GETSET", &key, "rejected");
+                        let msg = format!(
+                            "value of {} bytes exceeds max value size of {max} bytes",
+                            value.len()
+                        );
+                        proto.write_error(writer, &msg).await?;
+                    }
+                    _ => match store.get_set(&key, &value).await {
+                        Ok(old) => {
+                            let result = if old.is_some() { "updated" } else { "created" };
+                            options.audit(id, proto.addr(), "GETSET", &key, result);
+                            let old = old.as_deref().map(|old| state.encoding.encode(old));
+                            let too_large = old
+                                .as_ref()
+                                .and_then(|old| options.response_too_large(old.len()));
+                            match too_large {
+                                // the new value is stored all the same
+                                Some(msg) => proto.write_error(writer, &msg).await?,
+                                None => proto.write_old_value(writer, old.as_deref()).await?,
+                            }
+                        }
+                        Err(e) => {
+                            options.audit(id, proto.addr(), "GETSET", &key, "error");
+                            return Err(e);
+                        }
+                    },
+                }
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::Swap { a, b } => {
+                if let Err(e) = store.swap(&a, &b).await {
+                    options.audit(id, proto.addr(), "SWAP", &a, "error");
+                    options.audit(id, proto.addr(), "SWAP", &b, "error");
+                    return Err(e);
+                }
+                options.audit(id, proto.addr(), "SWAP", &a, "swapped");
+                options.audit(id, proto.addr(), "SWAP", &b, "swapped");
+                proto.write_ok(writer).await?;
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::Expire { key, secs } => {
+                match store.expire(&key, Duration::from_secs(secs)).await {
+                    Ok(Some(exists)) => {
+                        let result = if exists { "expiring" } else { "not_found" };
+                        options.audit(id, proto.addr(), "EXPIRE", &key, result);
+                        proto.write_int(writer, exists as usize).await?;
+                    }
+                    Ok(None) => {
+                        proto
+                            .write_error(writer, "EXPIRE: the store can't expire keys")
+                            .await?
+                    }
+                    Err(e) => {
+                        options.audit(id, proto.addr(), "EXPIRE", &key, "error");
+                        return Err(e);
+                    }
+                }
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::SetRange { key, offset, value } => {
+                // a prefix of the write would be meaningless, so these are never truncated
+                match options.max_value_len {
+                    Some(max) if offset.saturating_add(value.len()) > max => {
+                        options.audit(id, proto.addr(), "SETRANGE", &key, "rejected");
+                        let msg = format!(
+                            "value of at least {} bytes exceeds max value size of {max} bytes",
+                            offset.saturating_add(value.len())
+                        );
+                        proto.write_error(writer, &msg).await?;
+                    }
+                    _ => match store.set_range(&key, offset, &value).await {
+                        Ok(len) => {
+                            options.audit(id, proto.addr(), "SETRANGE", &key, "written");
+                            proto.write_int(writer, len).await?;
+                        }
+                        Err(Error::Transform(msg)) => {
+                            options.audit(id, proto.addr(), "SETRANGE", &key, "rejected");
+                            proto.write_error(writer, &msg).await?;
+                        }
+                        Err(e) => {
+                            options.audit(id, proto.addr(), "SETRANGE", &key, "error");
+                            return Err(e);
+                        }
+                    },
+                }
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::Apply {
+                key,
+                transform,
+                arg,
+            } => {
+                // the result is bounded like the values SET writes
+                let applied = match Transform::parse(&transform, &arg) {
+                    Ok(transform) => {
+                        let transform = transform.bounded(options.max_value_len);
+                        store.apply(&key, &transform).await
+                    }
+                    Err(e) => Err(e),
+                };
+                match applied {
+                    Ok(value) => {
+                        options.audit(id, proto.addr(), "APPLY", &key, "applied");
+                        let value = state.encoding.encode(&value);
+                        match options.response_too_large(value.len()) {
+                            Some(msg) => proto.write_error(writer, &msg).await?,
+                            None => proto.write_get_result(writer, &value).await?,
+                        }
+                    }
+                    // the value didn't suit the transform, the session can carry on
+                    Err(Error::Transform(msg)) => {
+                        options.audit(id, proto.addr(), "APPLY", &key, "rejected");
+                        proto.write_error(writer, &msg).await?;
+                    }
+                    Err(e) => {
+                        options.audit(id, proto.addr(), "APPLY", &key, "error");
+                        return Err(e);
+                    }
+                }
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::Scan { start, end, limit } => {
+                // a scan never runs past the end of the session's keyspace
+                let prefix = state.key_prefix();
+                let end = end.unwrap_or_else(|| state.scan_end());
+                // a client that disconnects isn't noticed while the page is built: it's
+                // a single store call, and the connection is only read between commands.
+                // The page is bounded by `limit` and the command timeout, and the session
+                // ends once it's written and the next command finds the connection closed
+                let entries = store.scan_entries(&start, Some(&end), limit).await?;
+                let entries = entries
+                    .iter()
+                    .map(|(key, value)| {
+                        let key = match &prefix {
+                            Some(prefix) => key.strip_prefix(prefix.as_slice()).unwrap_or(key),
+                            None => key.as_slice(),
+                        };
+                        (key, state.encoding.encode(value))
+                    })
+                    .collect::<Vec<_>>();
+                let len = entries.iter().map(|(_, value)| value.len()).sum();
+                match options.response_too_large(len) {
+                    Some(msg) => proto.write_error(writer, &msg).await?,
+                    None => {
+                        let fields = entries
+                            .iter()
+                            .flat_map(|(key, value)| [Some(*key), Some(value.as_ref())])
+                            .collect::<Vec<_>>();
+                        proto.write_values(writer, &fields).await?;
+                    }
+                }
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::ScanCursor { count: 0, .. } => {
+                proto
+                    .write_error(writer, "SCAN count must be at least 1")
+                    .await?;
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::ScanCursor { cursor, count } => {
+                // a scan starts at and never runs past the session's keyspace
+                let prefix = state.key_prefix();
+                let end = state.scan_end();
+                let start = cursor.or_else(|| prefix.clone()).unwrap_or_default();
+                // the key after the batch, if any, is where the next one starts
+                let mut keys = store
+                    .scan_keys(&start, Some(&end), count.saturating_add(1))
+                    .await?;
+                let next = match keys.len() > count {
+                    true => keys.pop().unwrap_or_default(),
+                    false => vec![],
+                };
+                let fields = std::iter::once(&next)
+                    .chain(&keys)
+                    .map(|key| match &prefix {
+                        Some(prefix) => key.strip_prefix(prefix.as_slice()).unwrap_or(key),
+                        None => key.as_slice(),
+                    })
+                    .collect::<Vec<_>>();
+                let len = fields.iter().map(|key| key.len()).sum();
+                match options.response_too_large(len) {
+                    Some(msg) => proto.write_error(writer, &msg).await?,
+                    None => {
+                        let fields = fields.into_iter().map(Some).collect::<Vec<_>>();
+                        proto.write_values(writer, &fields).await?;
+                    }
+                }
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::FlushAll => {
+                if !options.flushall {
+                    options.audit(id, proto.addr(), "FLUSHALL", b"", "rejected");
+                    proto
+                        .write_error(writer, "FLUSHALL is disabled on this server")
+                        .await?;
+                } else if let Err(e) = store.clear().await {
+                    options.audit(id, proto.addr(), "FLUSHALL", b"", "error");
+                    return Err(e);
+                } else {
+                    tracing::info!(session = %id, "store flushed");
+                    options.audit(id, proto.addr(), "FLUSHALL", b"", "flushed");
+                    proto.write_ok(writer).await?;
+                }
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::Snapshot { name } => {
+                let path = match &options.snapshot_dir {
+                    None => Err("SNAPSHOT is disabled on this server".to_string()),
+                    // a snapshot holds the keys of every namespace, not just the session's
+                    Some(_) if state.namespace.is_some() => {
+                        Err("SNAPSHOT: not allowed in a session scoped with USE".to_string())
+                    }
+                    Some(dir) => match Path::new(&name).components().collect::<Vec<_>>()[..] {
+                        // snapshots are never overwritten, a new name is needed for each
+                        [std::path::Component::Normal(_)] if dir.join(&name).exists() => {
+                            Err(format!("SNAPSHOT: snapshot {name:?} already exists"))
+                        }
+                        [std::path::Component::Normal(_)] => Ok(dir.join(&name)),
+                        _ => Err(format!("SNAPSHOT: invalid snapshot name {name:?}")),
+                    },
+                };
+                match path {
+                    Err(msg) => {
+                        options.audit(id, proto.addr(), "SNAPSHOT", name.as_bytes(), "rejected");
+                        proto.write_error(writer, &msg).await?;
+                    }
+                    Ok(path) => match store::snapshot_to_file(store, &path).await {
+                        Ok(n) => {
+                            tracing::info!(
+                                session = %id,
+                                path = %path.display(),
+                                entries = n,
+                                "store snapshotted"
+                            );
+                            options.audit(id, proto.addr(), "SNAPSHOT", name.as_bytes(), "written");
+                            proto.write_int(writer, n).await?;
+                        }
+                        Err(e) => {
+                            options.audit(id, proto.addr(), "SNAPSHOT", name.as_bytes(), "error");
+                            proto.write_error(writer, &format!("SNAPSHOT: {e}")).await?;
+                        }
+                    },
+                }
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::Begin => {
+                match state.transaction {
+                    Some(_) => {
+                        proto
+                            .write_error(writer, "BEGIN: a transaction is already open")
+                            .await?
+                    }
+                    None => {
+                        state.transaction = Some(QueuedTransaction::default());
+                        proto.write_ok(writer).await?;
+                    }
+                }
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::Discard => {
+                match state.transaction.take() {
+                    Some(_) => proto.write_ok(writer).await?,
+                    None => {
+                        proto
+                            .write_error(writer, "DISCARD: no transaction is open")
+                            .await?
+                    }
+                }
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::Commit => {
+                let queued = match state.transaction.take() {
+                    Some(queued) => queued,
+                    None => {
+                        proto
+                            .write_error(writer, "COMMIT: no transaction is open")
+                            .await?;
+                        proto.flush(writer).await?;
+                        return Ok(true);
+                    }
+                };
+                let writes = queued
+                    .operations
+                    .iter()
+                    .map(|operation| match operation {
+                        Operation::Set(key, _) => ("SET", key.clone()),
+                        Operation::Delete(key) => ("DEL", key.clone()),
+                    })
+                    .collect::<Vec<_>>();
+                let transaction = Transaction::with_random_id(queued.operations)
+                    .with_durability(queued.durability.unwrap_or_default());
+                match store.transact(transaction).await {
+                    Ok(existed) => {
+                        for ((op, key), existed) in writes.iter().zip(existed) {
+                            let result = match (*op, existed) {
+                                ("SET", true) => "updated",
+                                ("SET", false) => "created",
+                                (_, true) => "deleted",
+                                (_, false) => "not_found",
+                            };
+                            options.audit(id, proto.addr(), op, key, result);
+                        }
+                        proto.write_ok(writer).await?;
+                    }
+                    Err(e) => {
+                        for (op, key) in &writes {
+                            options.audit(id, proto.addr(), op, key, "error");
+                        }
+                        return Err(e);
+                    }
+                }
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::Find { attr } => {
+                match store.find(&attr).await? {
+                    Some(keys) => {
+                        // only the keys in the session's namespace, as the session names them
+                        let prefix = state.key_prefix();
+                        let keys = keys
+                            .iter()
+                            .filter(|key| state.in_keyspace(key))
+                            .map(|key| match &prefix {
+                                Some(prefix) => &key[prefix.len()..],
+                                None => key.as_slice(),
+                            })
+                            .map(Some)
+                            .collect::<Vec<_>>();
+                        proto.write_values(writer, &keys).await?;
+                    }
+                    None => {
+                        proto
+                            .write_error(writer, "FIND: the store has no secondary index")
+                            .await?
+                    }
+                }
+                proto.flush(writer).await?;
+            }
+            #[cfg(feature = "hash")]
+            proto::ProtoOp::HSet { key, field, value } => {
+                // the whole hash is bounded like the values SET writes, and like SETRANGE
+                // never truncated since a prefix of it would be meaningless
+                match store
+                    .hset(&key, &field, &value, options.max_value_len)
+                    .await
+                {
+                    Ok(len) => {
+                        options.audit(id, proto.addr(), "HSET", &key, "written");
+                        proto.write_int(writer, len).await?;
+                    }
+                    Err(Error::Transform(msg)) => {
+                        options.audit(id, proto.addr(), "HSET", &key, "rejected");
+                        proto.write_error(writer, &msg).await?;
+                    }
+                    Err(e) => {
+                        options.audit(id, proto.addr(), "HSET", &key, "error");
+                        return Err(e);
+                    }
+                }
+                proto.flush(writer).await?;
+            }
+            #[cfg(feature = "hash")]
+            proto::ProtoOp::HIncr { key, field, by } => {
+                let incremented = match std::str::from_utf8(&by).ok().and_then(|by| by.parse().ok())
+                {
+                    Some(by) => store.hincr(&key, &field, by).await,
+                    None => Err(Error::Transform(
+                        "hincr: increment is not an integer".to_string(),
+                    )),
+                };
+                match incremented {
+                    Ok(value) => {
+                        options.audit(id, proto.addr(), "HINCR", &key, "written");
+                        proto
+                            .write_get_result(writer, value.to_string().as_bytes())
+                            .await?;
+                    }
+                    Err(Error::Transform(msg)) => {
+                        options.audit(id, proto.addr(), "HINCR", &key, "rejected");
+                        proto.write_error(writer, &msg).await?;
+                    }
+                    Err(e) => {
+                        options.audit(id, proto.addr(), "HINCR", &key, "error");
+                        return Err(e);
+                    }
+                }
+                proto.flush(writer).await?;
+            }
+            #[cfg(feature = "hash")]
+            proto::ProtoOp::HGet { key, field } => {
+                match store.hgetall(&key).await {
+                    Ok(Some(hash)) => match hash.get(&field) {
+                        Some(value) => {
+                            let value = state.encoding.encode(value);
+                            match options.response_too_large(value.len()) {
+                                Some(msg) => proto.write_error(writer, &msg).await?,
+                                None => proto.write_get_result(writer, &value).await?,
+                            }
+                        }
+                        // the hash exists, but not the field
+                        None => proto.write_nil(writer).await?,
+                    },
+                    Ok(None) => proto.write_null(writer).await?,
+                    Err(Error::Transform(msg)) => proto.write_error(writer, &msg).await?,
+                    Err(e) => return Err(e),
+                }
+                proto.flush(writer).await?;
+            }
+            #[cfg(feature = "hash")]
+            proto::ProtoOp::HGetAll { key } => {
+                match store.hgetall(&key).await {
+                    Ok(Some(hash)) => {
+                        let values = hash
+                            .fields()
+                            .map(|(_, value)| state.encoding.encode(value))
+                            .collect::<Vec<_>>();
+                        let len = values.iter().map(|value| value.len()).sum();
+                        match options.response_too_large(len) {
+                            Some(msg) => proto.write_error(writer, &msg).await?,
+                            None => {
+                                // alternating field names and values, like HELLO
+                                let fields = hash
+                                    .fields()
+                                    .zip(&values)
+                                    .flat_map(|((field, _), value)| [field.as_bytes(), value])
+                                    .collect::<Vec<_>>();
+                                proto.write_fields(writer, &fields).await?;
+                            }
+                        }
+                    }
+                    Ok(None) => proto.write_null(writer).await?,
+                    Err(Error::Transform(msg)) => proto.write_error(writer, &msg).await?,
+                    Err(e) => return Err(e),
+                }
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::Use { namespace } => {
+                if namespace.contains(':') {
+                    proto
+                        .write_error(writer, "namespace may not contain ':'")
+                        .await?;
+                } else {
+                    tracing::debug!(session = %id, "using namespace {namespace:?}");
+                    state.namespace = Some(namespace).filter(|n| !n.is_empty());
+                    proto.write_ok(writer).await?;
+                }
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::Hello { option } => {
+                let encoding = option.as_ref().filter(|o| *o != proto::FRAME_CHECKSUM);
+                if let Some(encoding) = encoding {
+                    match encoding.parse() {
+                        Ok(encoding) => state.encoding = encoding,
+                        Err(e) => {
+                            proto.write_error(writer, &e.to_string()).await?;
+                            proto.flush(writer).await?;
+                            return Ok(true);
+                        }
+                    }
+                } else if option.is_some() {
+                    // checksummed from this response on
+                    proto.set_frame_checksums(true);
+                }
+                let max_value_size = options.max_value_len.map(|max| max.to_string());
+                let max_response_size = options.max_response_len.map(|max| max.to_string());
+                let mut fields: Vec<&[u8]> = vec![b"version", env!("CARGO_PKG_VERSION").as_bytes()];
+                if let Some(max) = &max_value_size {
+                    fields.extend([b"max_value_size".as_slice(), max.as_bytes()]);
+                }
+                if let Some(max) = &max_response_size {
+                    fields.extend([b"max_response_size".as_slice(), max.as_bytes()]);
+                }
+                if encoding.is_some() {
+                    fields.extend([b"encoding".as_slice(), state.encoding.name().as_bytes()]);
+                }
+                if proto.frame_checksums() {
+                    fields.extend([b"checksum".as_slice(), proto::FRAME_CHECKSUM.as_bytes()]);
+                }
+                proto.write_fields(writer, &fields).await?;
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::Time => {
+                let now = utils::time_since_epoch();
+                let secs = now.as_secs().to_string();
+                let micros = now.subsec_micros().to_string();
+                proto
+                    .write_fields(writer, &[secs.as_bytes(), micros.as_bytes()])
+                    .await?;
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::Healthz => {
+                let status: &[u8] = match options.is_ready() {
+                    true => b"ready",
+                    false => b"starting",
+                };
+                proto.write_fields(writer, &[b"status", status]).await?;
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::Command => {
+                let commands = proto.commands();
+                let fields: Vec<&[u8]> = commands
+                    .iter()
+                    .flat_map(|(name, arity)| [name.as_bytes(), arity.as_bytes()])
+                    .collect();
+                proto.write_fields(writer, &fields).await?;
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::Unknown { name } => {
+                tracing::debug!(session = %id, "unknown command {name:?}");
+                let msg = format!(
+                    "unknown command {name:?}, expected one of {}",
+                    proto::COMMANDS.join(", ")
+                );
+                proto.write_error(writer, &msg).await?;
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::Invalid { reason } => {
+                tracing::debug!(session = %id, "invalid request: {reason}");
+                proto.write_error(writer, &reason).await?;
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::Custom { name, .. } => {
+                // the handler that added the command should have handled it
+                tracing::warn!(session = %id, "no handler for custom command {name:?}");
+                proto
+                    .write_error(writer, &format!("unknown command {name:?}"))
+                    .await?;
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::WaitRepl {
+                replicas,
+                timeout_ms,
+            } => {
+                let timeout = Duration::from_millis(timeout_ms);
+                let acked = match &options.replication {
+                    Some(log) => log.wait_for(state.last_write, replicas, timeout).await,
+                    // without followers, like waiting on ones that are all down
+                    None => {
+                        if replicas > 0 {
+                            tokio::time::sleep(timeout).await;
+                        }
+                        0
+                    }
+                };
+                proto.write_int(writer, acked).await?;
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::DebugSleep { ms } => {
+                if !options.debug_commands {
+                    return Err(format!("session={id} DEBUG commands are disabled").into());
+                }
+                tracing::debug!(session = %id, "debug sleeping for {ms}ms");
+                tokio::time::sleep(Duration::from_millis(ms)).await;
+                proto.write_ok(writer).await?;
+                proto.flush(writer).await?;
+            }
+            proto::ProtoOp::DebugLog { log, reset } => {
+                if !options.debug_commands {
+                    return Err(format!("session={id} DEBUG commands are disabled").into());
+                }
+                let log = match log {
+                    proto::DebugLog::Errors => &options.error_log,
+                    proto::DebugLog::Slow => &options.slow_log,
+                };
+                if reset {
+                    proto.write_int(writer, log.clear()).await?;
+                } else {
+                    let entries = log.entries();
+                    let entries = entries.iter().map(Some).collect::<Vec<_>>();
+                    proto.write_values(writer, &entries).await?;
+                }
+                proto.flush(writer).await?;
+            }
+        }
+        if let Some(log) = options.replication.as_ref().filter(|_| writes) {
+            state.last_write = log.published();
+        }
+        Ok(true)
+    }
+
+    /// SET `value`, enforcing the max value size on a value sent `len` bytes long.
+    /// The value is moved into the write rather than copied, since it may be large.
+    #[allow(clippy::too_many_arguments)]
+    async fn set(
+        id: &str,
+        store: &mut S,
+        options: &SessionOptions,
+        proto: &proto::Proto,
+        writer: &mut proto::Writer,
+        key: Vec<u8>,
+        mut value: Vec<u8>,
+        len: usize,
+        noreply: bool,
+        durability: Option<Durability>,
+    ) -> Result<()> {
+        let mut truncated = false;
+        match options.max_value_len {
+            Some(max) if len > max => match options.value_limit_policy {
+                ValueLimitPolicy::Reject => {
+                    options.audit(id, proto.addr(), "SET", &key, "rejected");
+                    // errors are always returned, even for noreply writes
+                    let msg = format!("value of {len} bytes exceeds max value size of {max} bytes");
+                    proto.write_error(writer, &msg).await?;
+                    proto.flush(writer).await?;
+                    return Ok(());
+                }
+                ValueLimitPolicy::Truncate => {
+                    value.truncate(max);
+                    truncated = true;
+                }
+            },
+            _ => {}
+        }
+        let saved = value.len();
+        let res = store
+            .transact(
+                Transaction::with_random_id(vec![Operation::Set(key.clone(), value)])
+                    .with_durability(durability.unwrap_or_default()),
+            )
+            .await;
+        let existed = match res {
+            Ok(existed) => existed.first().copied().unwrap_or(false),
+            Err(e) => {
+                options.audit(id, proto.addr(), "SET", &key, "error");
+                return Err(e);
+            }
+        };
+        let result = if existed { "updated" } else { "created" };
+        options.audit(id, proto.addr(), "SET", &key, result);
+        if !noreply {
+            proto
+                .write_set_result(writer, saved, existed, truncated)
+                .await?;
+            proto.flush(writer).await?;
+        }
+        Ok(())
+    }
+
+    /// Forward an op on a single key owned by another node to it and relay its response,
+    /// see `shards`, and refuse ops on several keys when any of them is. The op's keys
+    /// are the ones the client sent, not yet scoped to the session's namespace. Returns
+    /// any other op back to be handled by this node.
+    async fn route(
+        options: &SessionOptions,
+        shards: &Shards,
+        state: &mut SessionState,
+        proto: &proto::Proto,
+        writer: &mut proto::Writer,
+        op: proto::ProtoOp,
+    ) -> Result<Option<proto::ProtoOp>> {
+        let keys = op.keys();
+        let owned = keys
+            .iter()
+            .find_map(|key| shards.owner(key).map(|node| (*key, node)));
+        let (key, node) = match owned {
+            Some(owned) => owned,
+            None => return Ok(Some(op)),
+        };
+        // a transaction commits to this node's store alone, so it can't take another
+        // node's keys, and one that can't commit whole isn't committed at all
+        if state.transaction.is_some() && op.is_transaction() {
+            state.transaction = None;
+            let msg = format!(
+                "transaction discarded, {}: key {:?} is owned by shard node {node}, a transaction may only write the entry node's keys",
+                op.name().unwrap_or_default(),
+                String::from_utf8_lossy(key)
+            );
+            proto.write_error(writer, &msg).await?;
+            proto.flush(writer).await?;
+            return Ok(None);
+        }
+        if let proto::ProtoOp::MGet { .. }
+        | proto::ProtoOp::MSet { .. }
+        | proto::ProtoOp::Swap { .. } = op
+        {
+            let msg = format!(
+                "{}: key {:?} is owned by shard node {node}, send its commands there",
+                op.name().unwrap_or_default(),
+                String::from_utf8_lossy(key)
+            );
+            proto.write_error(writer, &msg).await?;
+            proto.flush(writer).await?;
+            return Ok(None);
+        }
+        // scans and the like only ever see this node's store
+        let req = match op.request() {
+            Some(req) => req,
+            None => return Ok(Some(op)),
+        };
+        let (len, noreply) = match &op {
+            proto::ProtoOp::Set { value, noreply, .. } => (value.len(), *noreply),
+            proto::ProtoOp::SetStream { len, noreply, .. } => (*len, *noreply),
+            proto::ProtoOp::Del { noreply, .. } => (0, *noreply),
+            _ => (0, false),
+        };
+        // the owner would store a truncated value as if it were whole
+        if let Some(max) = options.max_value_len.filter(|max| len > *max) {
+            let msg = format!("value of {len} bytes exceeds max value size of {max} bytes");
+            proto.write_error(writer, &msg).await?;
+            proto.flush(writer).await?;
+            return Ok(None);
+        }
+        let namespace = state.namespace.as_deref();
+        match shards.forward(node, namespace, state.encoding, req).await {
+            // errors are always returned, even for noreply writes
+            Ok(Response::Error(msg)) => proto.write_error(writer, &msg).await?,
+            Ok(_) if noreply => return Ok(None),
+            Ok(response) => Self::write_response(proto, writer, response).await?,
+            Err(e) => {
+                let msg = format!("unavailable: error forwarding to shard node {node}: {e}");
+                proto.write_error(writer, &msg).await?
+            }
+        }
+        proto.flush(writer).await?;
+        Ok(None)
+    }
+
+    /// Write a response another node answered a forwarded op with, as it was written
+    async fn write_response(
+        proto: &proto::Proto,
+        writer: &mut proto::Writer,
+        response: Response,
+    ) -> Result<()> {
+        match response {
+            Response::Ok => proto.write_ok(writer).await,
+            Response::Pong => proto.write_pong(writer).await,
+            Response::NotFound => proto.write_null(writer).await,
+            Response::Null => proto.write_nil(writer).await,
+            Response::Error(msg) => proto.write_error(writer, &msg).await,
+            Response::Value(value) => proto.write_get_result(writer, &value).await,
+            Response::Fields(fields) => {
+                let fields = fields.iter().map(Vec::as_slice).collect::<Vec<_>>();
+                proto.write_fields(writer, &fields).await
+            }
+            // led by their count, which is written along with them
+            Response::Values(values) => {
+                proto
+                    .write_values(writer, values.get(1..).unwrap_or_default())
+                    .await
+            }
+        }
+    }
+
+    /// Queue a write sent inside a transaction for its COMMIT, enforcing the max value
+    /// size as SET does, and refuse writes that can't be queued. A write past the most
+    /// a transaction may queue, a value the max value size rejects, or an invalid
+    /// request discards it. Returns any other op back to be handled as usual.
+    async fn queue(
+        id: &str,
+        options: &SessionOptions,
+        proto: &proto::Proto,
+        writer: &mut proto::Writer,
+        transaction: &mut Option<QueuedTransaction>,
+        op: proto::ProtoOp,
+    ) -> Result<Option<proto::ProtoOp>> {
+        let commands = match transaction.as_mut() {
+            Some(queued) => {
+                queued.commands += 1;
+                queued.commands
+            }
+            None => return Ok(Some(op)),
+        };
+        let (key, mut value, len, noreply, durability) = match op {
+            proto::ProtoOp::Set {
+                key,
+                value,
+                noreply,
+                durability,
+            } => {
+                let len = value.len();
+                (key, value, len, noreply, durability)
+            }
+            proto::ProtoOp::SetStream {
+                key,
+                len,
+                value,
+                noreply,
+                durability,
+            } => (key, value, len, noreply, durability),
+            proto::ProtoOp::Del { key, noreply } => {
+                let operation = Operation::Delete(key);
+                return Self::push_queued(
+                    options,
+                    proto,
+                    writer,
+                    transaction,
+                    operation,
+                    None,
+                    noreply,
+                )
+                .await;
+            }
+            op @ proto::ProtoOp::Commit => return Ok(Some(op)),
+            // the client meant something else, committing the rest wouldn't do what it asked
+            proto::ProtoOp::Invalid { reason } => {
+                tracing::debug!(session = %id, "invalid request in a transaction: {reason}");
+                *transaction = None;
+                let msg = format!(
+                    "transaction discarded, command {commands} after BEGIN is invalid: {reason}"
+                );
+                proto.write_error(writer, &msg).await?;
+                proto.flush(writer).await?;
+                return Ok(None);
+            }
+            op if op.is_transaction() => {
+                let msg = format!(
+                    "{} can't be queued in a transaction, only SET and DEL can",
+                    op.name().unwrap_or_default()
+                );
+                proto.write_error(writer, &msg).await?;
+                proto.flush(writer).await?;
+                return Ok(None);
+            }
+            op => return Ok(Some(op)),
+        };
+        match options.max_value_len {
+            Some(max) if len > max => match options.value_limit_policy {
+                ValueLimitPolicy::Reject => {
+                    options.audit(id, proto.addr(), "SET", &key, "rejected");
+                    // committing the rest would apply only part of what the client queued
+                    *transaction = None;
+                    // errors are always returned, even for noreply writes
+                    let msg = format!(
+                        "transaction discarded, value of {len} bytes exceeds max value size of {max} bytes"
+                    );
+                    proto.write_error(writer, &msg).await?;
+                    proto.flush(writer).await?;
+                    return Ok(None);
                 }
+                ValueLimitPolicy::Truncate => value.truncate(max),
+            },
+            _ => {}
+        }
+        let operation = Operation::Set(key, value);
+        Self::push_queued(
+            options,
+            proto,
+            writer,
+            transaction,
+            operation,
+            durability,
+            noreply,
+        )
+        .await
+    }
+
+    /// Add a write to the open transaction, or discard the transaction when it can't
+    /// take another
+    async fn push_queued(
+        options: &SessionOptions,
+        proto: &proto::Proto,
+        writer: &mut proto::Writer,
+        transaction: &mut Option<QueuedTransaction>,
+        operation: Operation,
+        durability: Option<Durability>,
+        noreply: bool,
+    ) -> Result<Option<proto::ProtoOp>> {
+        let Some(queued) = transaction.as_mut() else {
+            return Ok(None);
+        };
+        queued.push(operation, durability);
+        let max_len = options.proto_limits.max_request_len;
+        if queued.operations.len() > MAX_QUEUED_OPS || queued.len > max_len {
+            *transaction = None;
+            // errors are always returned, even for noreply writes
+            let msg = format!(
+                "transaction discarded, it may queue at most {MAX_QUEUED_OPS} writes and {max_len} bytes of keys and values"
+            );
+            proto.write_error(writer, &msg).await?;
+            proto.flush(writer).await?;
+        } else if !noreply {
+            proto.write_queued(writer).await?;
+            proto.flush(writer).await?;
+        }
+        Ok(None)
+    }
+
+    /// Read the value of a `ProtoOp::SetStream` off the socket. Only what the max value
+    /// size keeps is read, none of it when the SET will be rejected, and the rest is
+    /// skipped by `Proto::read_value_end`. The buffer grows as the value's bytes arrive,
+    /// so a client announcing a long value can't make the session allocate it up front.
+    /// Only the read is chunked: the store takes values whole, so the value is still
+    /// held in memory in full before it's written, just not twice along with its request.
+    async fn read_streamed_value(
+        proto: &mut proto::Proto,
+        options: &SessionOptions,
+        len: usize,
+    ) -> Result<Vec<u8>> {
+        let kept = match options.max_value_len {
+            Some(max) if len > max => match options.value_limit_policy {
+                ValueLimitPolicy::Reject => 0,
+                ValueLimitPolicy::Truncate => max,
+            },
+            _ => len,
+        };
+        let mut value = Vec::with_capacity(kept.min(STREAM_CHUNK_LEN));
+        let mut reader = proto.value_reader();
+        while value.len() < kept {
+            let chunk = (kept - value.len()).min(STREAM_CHUNK_LEN);
+            let start = value.len();
+            value.resize(start + chunk, 0);
+            reader.read_exact(&mut value[start..]).await?;
+        }
+        Ok(value)
+    }
+}
+
+type Accepted = std::io::Result<(Incoming, SocketAddr)>;
+
+/// What the `ClientServer` listens for clients on, TCP, a Unix socket or both
+struct Listeners {
+    tcp: Option<TcpListener>,
+    #[cfg(unix)]
+    unix: Option<UnixSocket>,
+}
+impl Listeners {
+    /// The next connection accepted by either listener
+    async fn accept(&self) -> Accepted {
+        let tcp = async {
+            match &self.tcp {
+                Some(listener) => listener
+                    .accept()
+                    .await
+                    .map(|(stream, peer)| (Incoming::Tcp(stream), peer)),
+                None => std::future::pending().await,
+            }
+        };
+        #[cfg(unix)]
+        let unix = async {
+            match &self.unix {
+                // Unix peers have no address of their own, sessions see them as
+                // localhost port 0
+                Some(socket) => socket.listener.accept().await.map(|(stream, _)| {
+                    let peer = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+                    (Incoming::Unix(stream), peer)
+                }),
+                None => std::future::pending().await,
             }
+        };
+        #[cfg(not(unix))]
+        let unix = std::future::pending();
+        tokio::select! {
+            accepted = tcp => accepted,
+            accepted = unix => accepted,
+        }
+    }
+}
+
+/// A Unix socket clients connect to, its file removed once it's no longer listened on
+#[cfg(unix)]
+struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+#[cfg(unix)]
+impl UnixSocket {
+    /// Listen on `path`, replacing the socket a server that didn't shut down cleanly
+    /// left behind. Any other file there is left alone, and fails the bind
+    fn bind(path: &Path) -> Result<Self> {
+        use std::os::unix::fs::FileTypeExt;
+        let stale = std::fs::symlink_metadata(path)
+            .map(|meta| meta.file_type().is_socket())
+            .unwrap_or(false);
+        if stale {
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)
+            .map_err(|e| format!("error binding unix socket {path:?}: {e}"))?;
+        Ok(Self {
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+}
+#[cfg(unix)]
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            tracing::warn!("error removing unix socket {:?}: {e}", self.path);
         }
     }
 }
@@ -97,8 +1834,30 @@ pub struct ClientServer<S> {
     sig_shutdown_recv: UnboundedReceiver<bool>,
     certs: Vec<Certificate>,
     keys: Vec<PrivateKey>,
+    // what handshakes are accepted with, `certs` and `keys` unless reloaded
+    tls: TlsCerts,
+    // CAs whose certs clients must present, when they must
+    client_cas: Option<Vec<Certificate>>,
+    // presented when connecting to shard nodes, when they require client certs
+    node_client_cert: Option<ClientCert>,
     addr: Option<String>,
+    reuse_port: Option<bool>,
+    // whether clients are listened for over TLS at `addr`
+    tcp: bool,
+    // where clients are also listened for without TLS, not listened on when unset
+    unix_socket: Option<PathBuf>,
     store: S,
+    options: SessionOptions,
+    // sessions currently connected
+    sessions: Sessions,
+    // handles every op the sessions read
+    handler: Arc<dyn CommandHandler<S>>,
+    // sessions that may be connected at once, unlimited when unset
+    connection_slots: Option<ConnectionSlots>,
+    // how long shutdown waits for sessions to finish their requests
+    drain_timeout: Duration,
+    // where metrics are served over HTTP, not served when unset
+    metrics_addr: Option<String>,
 }
 impl<S: Store + Send + Sync + Clone + 'static> ClientServer<S> {
     pub fn new(
@@ -113,62 +1872,491 @@ impl<S: Store + Send + Sync + Clone + 'static> ClientServer<S> {
             sig_shutdown_recv,
             certs,
             keys,
+            tls: TlsCerts::default(),
+            client_cas: None,
+            node_client_cert: None,
             addr: None,
+            reuse_port: None,
+            tcp: get_config().client_tcp,
+            unix_socket: get_config().client_unix_socket.clone(),
             store,
+            options: SessionOptions {
+                events: Some(broadcast::channel(EVENT_CAPACITY).0),
+                ..SessionOptions::from_config(&get_config())
+            },
+            sessions: Sessions::default(),
+            handler: Arc::new(Builtin),
+            connection_slots: get_config()
+                .max_connections
+                .map(|n| ConnectionSlots::new(n, get_config().connection_limit_policy)),
+            drain_timeout: get_config().drain_timeout,
+            metrics_addr: get_config().metrics_addr.clone(),
         }
     }
 
+    /// The certs handshakes are accepted with, to reload them while the server runs.
+    /// Certs set before it starts are used instead of the ones it was made with
+    pub fn tls(&self) -> TlsCerts {
+        self.tls.clone()
+    }
+
+    /// Accept handshakes with certs shared with another server, e.g. the cluster server
+    pub fn set_tls(&mut self, tls: TlsCerts) -> &mut Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Require clients to present a cert issued by one of `client_cas`, turning away
+    /// any that don't in their handshake. Clients needn't present one when `None`,
+    /// unless the config requires it
+    pub fn set_client_cas(&mut self, client_cas: Option<Vec<Certificate>>) -> &mut Self {
+        self.client_cas = client_cas;
+        self
+    }
+
+    /// Present `node_client_cert` when connecting to the shard nodes keys are routed
+    /// to, for when they require client certs. Unless set, it's loaded from the config
+    pub fn set_node_client_cert(&mut self, node_client_cert: Option<ClientCert>) -> &mut Self {
+        self.node_client_cert = node_client_cert;
+        self
+    }
+
+    /// The sessions currently connected to this server
+    pub fn sessions(&self) -> Sessions {
+        self.sessions.clone()
+    }
+
+    /// Subscribe to the lifecycle events of client sessions
+    pub fn events(&mut self) -> broadcast::Receiver<SessionEvent> {
+        self.options
+            .events
+            .get_or_insert_with(|| broadcast::channel(EVENT_CAPACITY).0)
+            .subscribe()
+    }
+
+    /// Counters of the commands handled by every session, by command and outcome
+    pub fn metrics(&self) -> CommandMetrics {
+        self.options.metrics.clone()
+    }
+
+    /// Counters of the connections accepted and bytes moved by every session
+    pub fn traffic(&self) -> TrafficMetrics {
+        self.options.traffic.clone()
+    }
+
+    /// Serve the server's metrics over HTTP at `addr`, as `GET /metrics` in the
+    /// Prometheus text format. Not served when `None`
+    pub fn set_metrics_addr<A: Into<String>>(&mut self, addr: Option<A>) -> &mut Self {
+        self.metrics_addr = addr.map(Into::into);
+        self
+    }
+
+    /// Spread keys over the nodes of `shards`, forwarding the commands on keys other
+    /// nodes own to them. Every key is this node's when `None`
+    pub fn set_shards(&mut self, shards: Option<Shards>) -> &mut Self {
+        self.options.shards = shards;
+        self
+    }
+
+    /// Publish session lifecycle events to an existing channel
+    pub fn set_event_sender(&mut self, events: broadcast::Sender<SessionEvent>) -> &mut Self {
+        self.options.events = Some(events);
+        self
+    }
+
     pub fn set_addr<A: Into<String>>(&mut self, addr: A) -> &mut Self {
         self.addr = Some(addr.into());
         self
     }
 
+    /// Bind the TLS listener with SO_REUSEPORT, so `sibling`s on other runtimes can
+    /// listen on the same address, the OS spreading connections over them. Unless set,
+    /// it's taken from the config
+    pub fn set_reuse_port(&mut self, reuse_port: bool) -> &mut Self {
+        self.reuse_port = Some(reuse_port);
+        self
+    }
+
+    /// Another client-server for the same clients as this one, to start on another
+    /// runtime: listening on the same address with SO_REUSEPORT, which this one must
+    /// also set, and sharing its store, settings, connection and transaction limits, and
+    /// metrics. Only this one listens on the Unix socket and serves the metrics.
+    pub fn sibling(
+        &self,
+        svr_shutdown_send: UnboundedSender<bool>,
+        sig_shutdown_recv: UnboundedReceiver<bool>,
+    ) -> Self {
+        Self {
+            svr_shutdown_send,
+            sig_shutdown_recv,
+            certs: self.certs.clone(),
+            keys: self.keys.clone(),
+            tls: self.tls.clone(),
+            client_cas: self.client_cas.clone(),
+            node_client_cert: self.node_client_cert.clone(),
+            addr: self.addr.clone(),
+            reuse_port: Some(true),
+            tcp: true,
+            unix_socket: None,
+            store: self.store.clone(),
+            options: self.options.clone(),
+            sessions: Sessions::default(),
+            handler: self.handler.clone(),
+            connection_slots: self.connection_slots.clone(),
+            drain_timeout: self.drain_timeout,
+            metrics_addr: None,
+        }
+    }
+
+    /// Whether clients are listened for over TLS on the server's address. Turned off
+    /// to only listen on the Unix socket
+    pub fn set_tcp(&mut self, tcp: bool) -> &mut Self {
+        self.tcp = tcp;
+        self
+    }
+
+    /// Also listen for clients on a Unix socket at `path`, whose sessions skip TLS and
+    /// are only as private as the socket file's permissions. Not listened on when `None`
+    pub fn set_unix_socket<P: Into<PathBuf>>(&mut self, path: Option<P>) -> &mut Self {
+        self.unix_socket = path.map(Into::into);
+        self
+    }
+
+    /// Limit the number of clients connected at once, handling connections beyond it
+    /// by `policy`. Unlimited when `None`
+    pub fn set_max_connections(
+        &mut self,
+        max_connections: Option<usize>,
+        policy: ConnectionLimitPolicy,
+    ) -> &mut Self {
+        self.connection_slots = max_connections.map(|n| ConnectionSlots::new(n, policy));
+        self
+    }
+
+    pub fn set_audit_sink(&mut self, audit: Option<Arc<dyn AuditSink>>) -> &mut Self {
+        self.options.audit = audit;
+        self
+    }
+
+    pub fn set_max_value_len(&mut self, max_value_len: Option<usize>) -> &mut Self {
+        self.options.max_value_len = max_value_len;
+        self
+    }
+
+    /// Limit the value bytes a single response may carry. Larger responses are
+    /// answered with an error instead, so clients page through big results.
+    pub fn set_max_response_len(&mut self, max_response_len: Option<usize>) -> &mut Self {
+        self.options.max_response_len = max_response_len;
+        self
+    }
+
+    pub fn set_value_limit_policy(&mut self, policy: ValueLimitPolicy) -> &mut Self {
+        self.options.value_limit_policy = policy;
+        self
+    }
+
+    /// Limit the number of transactions in flight across all sessions
+    pub fn set_max_transactions(&mut self, max_transactions: Option<usize>) -> &mut Self {
+        self.options.transaction_slots = max_transactions.map(|n| Arc::new(Semaphore::new(n)));
+        self
+    }
+
+    pub fn set_transaction_limit_policy(&mut self, policy: TransactionLimitPolicy) -> &mut Self {
+        self.options.transaction_limit_policy = policy;
+        self
+    }
+
+    pub fn set_debug_commands(&mut self, debug_commands: bool) -> &mut Self {
+        self.options.debug_commands = debug_commands;
+        self
+    }
+
+    /// Refuse every write, as followers do so their store only takes their leader's
+    pub fn set_read_only(&mut self, read_only: bool) -> &mut Self {
+        self.options.read_only = read_only;
+        self
+    }
+
+    /// Let WAITREPL wait for followers to acknowledge the writes published to
+    /// `replication`, which the store should be publishing to. Without it no
+    /// follower ever does
+    pub fn set_replication_log(&mut self, replication: Option<ReplicationLog>) -> &mut Self {
+        self.options.replication = replication;
+        self
+    }
+
+    /// Let FLUSHALL clear the store, it's answered with an error otherwise
+    pub fn set_flushall(&mut self, flushall: bool) -> &mut Self {
+        self.options.flushall = flushall;
+        self
+    }
+
+    /// Let SNAPSHOT write the store to files in `dir`, it's answered with an error
+    /// otherwise
+    pub fn set_snapshot_dir<P: Into<PathBuf>>(&mut self, dir: Option<P>) -> &mut Self {
+        self.options.snapshot_dir = dir.map(Into::into);
+        self
+    }
+
+    /// Record commands taking at least `slow` in the slow log read by DEBUG SLOWLOG
+    pub fn set_slow_command(&mut self, slow: Option<Duration>) -> &mut Self {
+        self.options.slow_command = slow;
+        self
+    }
+
+    /// Whether unknown commands and malformed requests close the session, otherwise
+    /// they're answered with an error (unknown commands listing the known ones) and
+    /// the session carries on
+    pub fn set_strict_protocol(&mut self, strict: bool) -> &mut Self {
+        self.options.strict_protocol = strict;
+        self
+    }
+
+    /// Close sessions sending arguments longer than `limits`, see `ProtoLimits`
+    pub fn set_proto_limits(&mut self, limits: proto::ProtoLimits) -> &mut Self {
+        self.options.proto_limits = limits;
+        self
+    }
+
+    /// Stream SET values at least `min_len` bytes long off the socket, see
+    /// `Proto::set_stream_values`, instead of buffering them along with their request.
+    /// The store is still handed each value whole, see `read_streamed_value`
+    pub fn set_stream_values(&mut self, min_len: Option<usize>) -> &mut Self {
+        self.options.stream_value_len = min_len;
+        self
+    }
+
+    /// Log the protocol bytes of sessions, see `WireTrace`
+    pub fn set_wire_trace(&mut self, wire_trace: WireTrace) -> &mut Self {
+        self.options.wire_trace = wire_trace;
+        self
+    }
+
+    /// Recycle the sessions' read buffers through `pool`, e.g. to share one pool
+    /// between servers or to disable pooling with a pool of size 0
+    pub fn set_buffer_pool(&mut self, pool: proto::BufferPool) -> &mut Self {
+        self.options.buffer_pool = pool;
+        self
+    }
+
+    /// Size the OS send and receive buffers of accepted connections, see
+    /// `set_socket_buffers`. Either is left to the OS when `None`.
+    pub fn set_socket_buffer_sizes(
+        &mut self,
+        send: Option<usize>,
+        recv: Option<usize>,
+    ) -> &mut Self {
+        self.options.send_buffer_size = send;
+        self.options.recv_buffer_size = recv;
+        self
+    }
+
+    /// Hold off serving the store until `ready` becomes true, e.g. while it recovers
+    /// in the background. Until then ops that touch the store are answered with a
+    /// retryable `starting` error, while the rest, like HEALTHZ, are answered as usual.
+    pub fn set_readiness(&mut self, ready: watch::Receiver<bool>) -> &mut Self {
+        self.options.ready = Some(ready);
+        self
+    }
+
+    /// Handle the sessions' ops with `handler`, e.g. to add custom commands.
+    /// Defaults to `Builtin`, which handles the protocol's own commands.
+    pub fn set_command_handler(&mut self, handler: Arc<dyn CommandHandler<S>>) -> &mut Self {
+        self.handler = handler;
+        self
+    }
+
+    pub fn set_command_timeout(&mut self, command_timeout: Option<Duration>) -> &mut Self {
+        self.options.command_timeout = command_timeout;
+        self
+    }
+
+    /// Close sessions whose clients send nothing for `idle_timeout`, whether between
+    /// commands or partway through one. Sessions wait forever when unset
+    pub fn set_idle_timeout(&mut self, idle_timeout: Option<Duration>) -> &mut Self {
+        self.options.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Wait up to `drain_timeout` on shutdown for sessions to finish the requests
+    /// they've started reading, before closing any still connected
+    pub fn set_drain_timeout(&mut self, drain_timeout: Duration) -> &mut Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    /// Turn away a connection beyond the max connections, before its TLS handshake
+    async fn refuse(stream_peer_addr_res: Accepted) -> Result<()> {
+        let (stream, peer_addr) = stream_peer_addr_res?;
+        connections::refuse(stream.into_stream(), peer_addr).await
+    }
+
+    #[allow(clippy::too_many_arguments)]
     async fn handle_conn(
-        stream_peer_addr_res: std::result::Result<
-            (tokio::net::TcpStream, std::net::SocketAddr),
-            std::io::Error,
-        >,
+        stream_peer_addr_res: Accepted,
+        // held until the session ends, when connections are limited
+        _slot: Option<OwnedSemaphorePermit>,
         acceptor: TlsAcceptor,
         store: S,
-        kill: Receiver<bool>,
+        kill: Receiver<proto::Shutdown>,
+        options: SessionOptions,
+        sessions: Sessions,
+        handler: Arc<dyn CommandHandler<S>>,
     ) -> Result<()> {
         uuid_with_ident!(id);
         tracing::info!(session = id, "client connected");
-        let (stream, peer_addr) =
-            stream_peer_addr_res.map_err(|e| format!("session={id} error accepting tls: {e}"))?;
-        let conn = Connection::new(id.to_string(), stream, peer_addr, acceptor, store, kill);
+        let (stream, peer_addr) = stream_peer_addr_res
+            .map_err(|e| format!("session={id} error accepting connection: {e}"))?;
+        // sized before the TLS handshake, so it's sent with the configured buffers too
+        if let Incoming::Tcp(stream) = &stream {
+            set_socket_buffers(stream, options.send_buffer_size, options.recv_buffer_size)
+                .map_err(|e| format!("session={id} error sizing socket buffers: {e}"))?;
+        }
+        options.traffic.connected();
+        // deregisters the session however it ends, including panics and cancellation
+        let _registered = sessions.register(id, peer_addr);
+        let conn = Connection::new(
+            id.to_string(),
+            stream,
+            peer_addr,
+            acceptor,
+            store,
+            kill,
+            options,
+            handler,
+        );
         conn.handle().await
     }
 
     async fn server_start(&mut self) -> Result<()> {
-        let config = rustls::ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
-            .with_single_cert(self.certs.clone(), self.keys[0].clone())
-            .map_err(|err| format!("tls config error: {err}"))?;
-        let acceptor = TlsAcceptor::from(Arc::new(config));
+        if !self.tcp && self.unix_socket.is_none() {
+            return Err(
+                "the client-server has nothing to listen on, set a unix socket \
+                        or listen over tcp"
+                    .into(),
+            );
+        }
+        // fails before binding when the certs or key are misconfigured
+        if self.client_cas.is_none() {
+            let config = get_config();
+            self.client_cas = load_client_cas(
+                config.require_client_certs,
+                config.client_ca_path.as_deref(),
+            )?;
+        }
+        if !self.tls.is_set() {
+            self.tls.set_client_cas(self.client_cas.clone());
+            self.tls.set(&self.certs, &self.keys)?;
+        }
+        if self.node_client_cert.is_none() {
+            let config = get_config();
+            self.node_client_cert = load_node_client_cert(
+                config.node_client_cert_path.as_deref(),
+                config.node_client_key_path.as_deref(),
+            )?;
+        }
 
         let addr = self
             .addr
             .clone()
             .unwrap_or_else(|| get_config().get_client_addr());
-        tracing::info!("listening for client requests on {addr}");
-        let listener = TcpListener::bind(&addr).await?;
-        let (kill_send, _) = broadcast::channel(1);
+        let reuse_port = self.reuse_port.unwrap_or_else(|| get_config().reuse_port);
+        let tcp = match self.tcp {
+            true => {
+                tracing::info!("listening for client requests on {addr}, reuse_port={reuse_port}");
+                Some(bind_listener(&addr, reuse_port).await?)
+            }
+            false => None,
+        };
+        #[cfg(unix)]
+        let unix = match &self.unix_socket {
+            Some(path) => {
+                tracing::info!("listening for client requests on unix socket {path:?}");
+                Some(UnixSocket::bind(path)?)
+            }
+            None => None,
+        };
+        #[cfg(not(unix))]
+        if self.unix_socket.is_some() {
+            return Err("unix sockets aren't supported on this platform".into());
+        }
+        let listeners = Listeners {
+            tcp,
+            #[cfg(unix)]
+            unix,
+        };
+
+        if self.options.audit.is_none() {
+            if let Some(path) = &get_config().audit_log_path {
+                tracing::info!("writing audit records to {path:?}");
+                self.options.audit = Some(Arc::new(FileAuditSink::spawn(path).await?));
+            }
+        }
+        let shard_nodes = &get_config().shard_nodes;
+        if self.options.shards.is_none() && !shard_nodes.is_empty() {
+            let node = get_config()
+                .shard_node
+                .clone()
+                .unwrap_or_else(|| addr.clone());
+            tracing::info!("routing keys over shard nodes {shard_nodes:?} as {node}");
+            let ring = Ring::with_nodes(shard_nodes.iter().cloned());
+            self.options.shards = Some(Shards::with_client_cert(
+                node,
+                ring,
+                self.certs.clone(),
+                self.node_client_cert.clone(),
+            )?);
+        }
+        let metrics = match &self.metrics_addr {
+            Some(addr) => {
+                tracing::info!("serving metrics on {addr}");
+                let listener = TcpListener::bind(addr).await?;
+                let serve = metrics::serve(
+                    listener,
+                    self.options.metrics.clone(),
+                    self.options.traffic.clone(),
+                    self.sessions.clone(),
+                );
+                Some(tokio::spawn(serve))
+            }
+            None => None,
+        };
+        let (kill_send, _) = broadcast::channel(2);
 
         loop {
             tokio::select! {
                 _ = self.sig_shutdown_recv.recv() => {
                     tracing::info!("client-server received sigint shutdown signal");
-                    kill_send.send(true).expect("error broadcasting task kill");
                     break;
                 },
-                stream_peer_addr_res = listener.accept() => {
-                    let acceptor = acceptor.clone();
+                (stream_peer_addr_res, admission) = connections::accept(listeners.accept(), self.connection_slots.as_ref()) => {
+                    let slot = match admission {
+                        Admission::Unlimited => None,
+                        Admission::Admitted(slot) => Some(slot),
+                        Admission::Refused => {
+                            tokio::spawn(async move {
+                                if let Err(e) = Self::refuse(stream_peer_addr_res).await {
+                                    tracing::debug!("error refusing client connection {e}");
+                                }
+                            });
+                            continue;
+                        }
+                    };
+                    // reloaded certs are picked up by the next handshake
+                    let acceptor = match self.tls.acceptor() {
+                        Ok(acceptor) => acceptor,
+                        Err(e) => {
+                            tracing::error!("error accepting client connection {e}");
+                            continue;
+                        }
+                    };
                     let store = self.store.clone();
                     let kill = kill_send.subscribe();
+                    let options = self.options.clone();
+                    let sessions = self.sessions.clone();
+                    let handler = self.handler.clone();
                     tokio::spawn(async move {
-                        if let Err(e) = Self::handle_conn(stream_peer_addr_res, acceptor, store, kill).await {
+                        if let Err(e) = Self::handle_conn(stream_peer_addr_res, slot, acceptor, store, kill, options, sessions, handler).await {
                             tracing::error!("error handling client connection {e}");
                         }
                     });
@@ -178,9 +2366,36 @@ impl<S: Store + Send + Sync + Clone + 'static> ClientServer<S> {
                 // },
             }
         }
+        // stop accepting connections while the sessions drain
+        drop(listeners);
+        self.drain(&kill_send).await;
+        if let Some(metrics) = metrics {
+            metrics.abort();
+        }
         Ok(())
     }
 
+    /// Let sessions finish the requests they've started, for up to the drain timeout,
+    /// before killing any still connected
+    async fn drain(&self, kill_send: &broadcast::Sender<proto::Shutdown>) {
+        // these error when there are no sessions left to signal, which is fine
+        let _ = kill_send.send(proto::Shutdown::Drain);
+        let drained = tokio::time::timeout(self.drain_timeout, async {
+            while !self.sessions.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        if drained.is_err() {
+            tracing::warn!(
+                "{} sessions still connected after draining for {:?}, killing them",
+                self.sessions.len(),
+                self.drain_timeout
+            );
+        }
+        let _ = kill_send.send(proto::Shutdown::Kill);
+    }
+
     pub async fn start(mut self) {
         tracing::info!("starting client-server");
         if let Err(e) = self.server_start().await {
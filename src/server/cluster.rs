@@ -1,14 +1,44 @@
+use crate::client::ClientCert;
+use crate::config::{ConnectionLimitPolicy, ServerConfig};
 use crate::error::Result;
 use crate::get_config;
-use crate::server::ClientServer;
+use crate::server::connections::{self, Admission, ConnectionSlots};
+use crate::server::events::{SessionEvent, EVENT_CAPACITY};
+use crate::server::{
+    load_certs, load_client_cas, load_keys, load_node_client_cert, validate_tls, ClientServer,
+    TlsCerts, MAX_QUEUED_OPS,
+};
+use crate::store::backend::{BackendStore, StoreBackend};
+use crate::store::replication::{self, Record, ReplicationLog};
 use crate::store::Store;
-use std::sync::Arc;
-use tokio::io::{split, AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpListener;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
-use tokio_rustls::rustls::{self, Certificate, PrivateKey};
+use tokio::sync::{oneshot, OwnedSemaphorePermit};
+use tokio_rustls::rustls::{Certificate, PrivateKey};
 use tokio_rustls::TlsAcceptor;
 
+/// Sent by a follower as the first bytes of its cluster connection to its leader,
+/// which answers with the number of the last record it published, as a u64, a snapshot
+/// of its store holding every record up to it, then every write applied from then on,
+/// see `store::replication`. The follower sends back the number of each record it
+/// applies, the snapshot's once it's restored, as a u64.
+pub const REPLICATE: &[u8] = b"REPLICATE\n";
+
+// the number of the last record published before a follower subscribed, and the
+// records published since, see `ReplicationLog::follow`
+type Subscription = (u64, broadcast::Receiver<(u64, Record)>);
+
+type Accepted = std::io::Result<(TcpStream, SocketAddr)>;
+
+// how long a follower waits before reconnecting to its leader
+const FOLLOW_RETRY: Duration = Duration::from_secs(1);
+
 /// Main entry point
 /// Manages inter-node communication and
 /// separately spawns a server to handle client requests
@@ -19,10 +49,29 @@ pub struct Server<S> {
     sig_shutdown_recv: UnboundedReceiver<bool>,
     certs: Vec<Certificate>,
     keys: Vec<PrivateKey>,
+    // what handshakes are accepted with by both servers, `certs` and `keys` unless reloaded
+    tls: TlsCerts,
+    // CAs whose certs clients of both servers must present, when they must
+    client_cas: Option<Vec<Certificate>>,
+    // presented when connecting to the leader and shard nodes, when they require client certs
+    node_client_cert: Option<ClientCert>,
     addr: Option<String>,
+    // cluster connections that may be open at once, followers included, unlimited when unset
+    connection_slots: Option<ConnectionSlots>,
     client_svr_addr: Option<String>,
     start_client_server: bool,
+    // most clients connected to the client-server at once, and how any beyond it are handled
+    client_max_connections: Option<usize>,
+    client_connection_limit_policy: ConnectionLimitPolicy,
+    // runtimes the client-server accepts connections on, each with its own listener
+    client_runtimes: usize,
     store: S,
+    // where the store's writes are published for followers, when this node leads
+    replication: Option<ReplicationLog>,
+    // cluster address of the leader whose writes are applied to the store, when this node follows
+    leader_addr: Option<String>,
+    // lifecycle events of the client-server's sessions
+    client_events: broadcast::Sender<SessionEvent>,
 }
 
 impl<S: Store + Clone + Send + Sync + 'static> Server<S> {
@@ -38,34 +87,126 @@ impl<S: Store + Clone + Send + Sync + 'static> Server<S> {
             sig_shutdown_recv,
             certs,
             keys,
+            tls: TlsCerts::default(),
+            client_cas: None,
+            node_client_cert: None,
             addr: None,
+            connection_slots: get_config()
+                .cluster_max_connections
+                .map(|n| ConnectionSlots::new(n, get_config().connection_limit_policy)),
             client_svr_addr: None,
             start_client_server: true,
+            client_max_connections: get_config().max_connections,
+            client_connection_limit_policy: get_config().connection_limit_policy,
+            client_runtimes: get_config().get_client_runtimes(),
             store,
+            replication: None,
+            leader_addr: get_config().leader_addr.clone(),
+            client_events: broadcast::channel(EVENT_CAPACITY).0,
         }
     }
 
+    /// Subscribe to the lifecycle events of client sessions
+    pub fn client_events(&mut self) -> broadcast::Receiver<SessionEvent> {
+        self.client_events.subscribe()
+    }
+
+    /// The certs handshakes are accepted with by both the cluster and client-server,
+    /// to reload them while they run, see `ClientServer::tls`
+    pub fn tls(&self) -> TlsCerts {
+        self.tls.clone()
+    }
+
+    /// Require clients of both the cluster and client-server to present a cert issued
+    /// by one of `client_cas`, see `ClientServer::set_client_cas`
+    pub fn set_client_cas(&mut self, client_cas: Option<Vec<Certificate>>) -> &mut Self {
+        self.client_cas = client_cas;
+        self
+    }
+
+    /// Present `node_client_cert` when connecting to other nodes, i.e. the leader this
+    /// node follows and the shard nodes keys are routed to, for when they require
+    /// client certs. Unless set, it's loaded from the config
+    pub fn set_node_client_cert(&mut self, node_client_cert: Option<ClientCert>) -> &mut Self {
+        self.node_client_cert = node_client_cert;
+        self
+    }
+
     pub fn set_addr<A: Into<String>>(&mut self, addr: A) -> &mut Self {
         self.addr = Some(addr.into());
         self
     }
 
+    /// Limit the number of cluster connections open at once, followers' included,
+    /// handling connections beyond it by `policy`. Unlimited when `None`
+    pub fn set_max_connections(
+        &mut self,
+        max_connections: Option<usize>,
+        policy: ConnectionLimitPolicy,
+    ) -> &mut Self {
+        self.connection_slots = max_connections.map(|n| ConnectionSlots::new(n, policy));
+        self
+    }
+
     pub fn set_client_server_addr<A: Into<String>>(&mut self, addr: A) -> &mut Self {
         self.client_svr_addr = Some(addr.into());
         self
     }
 
+    /// Limit the number of clients connected to the client-server at once,
+    /// see `ClientServer::set_max_connections`
+    pub fn set_client_max_connections(
+        &mut self,
+        max_connections: Option<usize>,
+        policy: ConnectionLimitPolicy,
+    ) -> &mut Self {
+        self.client_max_connections = max_connections;
+        self.client_connection_limit_policy = policy;
+        self
+    }
+
+    /// Accept client connections on `runtimes` runtimes: the server's own and
+    /// `runtimes - 1` single threaded ones, each listening on the client-server's
+    /// address with SO_REUSEPORT, see `ClientServer::sibling`
+    pub fn set_client_runtimes(&mut self, runtimes: usize) -> &mut Self {
+        self.client_runtimes = runtimes.max(1);
+        self
+    }
+
+    /// Lead the cluster, streaming the writes published to `replication` to every
+    /// follower that connects. The server's store should be the `ReplicatedStore`
+    /// publishing to it
+    pub fn set_replication_log(&mut self, replication: Option<ReplicationLog>) -> &mut Self {
+        self.replication = replication;
+        self
+    }
+
+    /// Follow the leader at the cluster address `leader_addr` (as `host:port`),
+    /// applying every write it streams to the store
+    pub fn set_leader_addr<A: Into<String>>(&mut self, leader_addr: Option<A>) -> &mut Self {
+        self.leader_addr = leader_addr.map(Into::into);
+        self
+    }
+
     pub fn set_start_client_server(&mut self, start_client_server: bool) -> &mut Self {
         self.start_client_server = start_client_server;
         self
     }
 
+    /// Turn away a connection beyond the max connections, before its TLS handshake
+    async fn refuse(stream_peer_addr_res: Accepted) -> Result<()> {
+        let (stream, peer_addr) = stream_peer_addr_res?;
+        connections::refuse(stream, peer_addr).await
+    }
+
     async fn handle_conn(
-        stream_peer_addr_res: std::result::Result<
-            (tokio::net::TcpStream, std::net::SocketAddr),
-            std::io::Error,
-        >,
+        stream_peer_addr_res: Accepted,
+        // held until the connection closes, when connections are limited
+        _slot: Option<OwnedSemaphorePermit>,
         acceptor: TlsAcceptor,
+        store: S,
+        // subscribed when the connection was accepted, when this node leads
+        replication: Option<(ReplicationLog, Subscription)>,
     ) -> Result<()> {
         uuid_with_ident!(id);
         tracing::info!(session = id, "cluster connected");
@@ -97,6 +238,20 @@ impl<S: Store + Clone + Send + Sync + 'static> Server<S> {
                 }
             };
 
+            if buf.starts_with(REPLICATE) {
+                return match replication {
+                    Some((log, records)) => {
+                        let acks = buf.split_off(REPLICATE.len());
+                        let (reader, writer) = (&mut reader, &mut writer);
+                        let replicated =
+                            Self::replicate(id, reader, writer, store, &log, records, acks);
+                        let res = replicated.await;
+                        log.forget(id);
+                        res
+                    }
+                    None => Err(format!("session={id} follower connected, but not leading").into()),
+                };
+            }
             tracing::debug!(
                 session = id,
                 "CLUSTER_MESSAGE:::<{:?}>",
@@ -117,18 +272,184 @@ impl<S: Store + Clone + Send + Sync + 'static> Server<S> {
         Ok(())
     }
 
+    /// Stream a snapshot of `store` to a follower, then every record published to `log`
+    /// since `records` was subscribed, counting the records it acknowledges applying
+    /// until it disconnects. `acks` holds the bytes it sent after the handshake,
+    /// already read.
+    async fn replicate<R: AsyncRead + Unpin, W: AsyncWrite + Send + Unpin>(
+        id: &str,
+        reader: &mut R,
+        writer: &mut W,
+        mut store: S,
+        log: &ReplicationLog,
+        (published, mut records): Subscription,
+        mut acks: Vec<u8>,
+    ) -> Result<()> {
+        tracing::info!(session = id, "follower connected, sending a snapshot");
+        log.ack(id, 0);
+        writer
+            .write_u64(published)
+            .await
+            .map_err(|e| format!("session={id} error writing to follower: {e}"))?;
+        let entries = store
+            .snapshot(writer)
+            .await
+            .map_err(|e| format!("session={id} error sending a snapshot to follower: {e}"))?;
+        tracing::info!(
+            session = id,
+            "sent {entries} entries to follower, replicating"
+        );
+        loop {
+            // read_buf rather than read_u64, a partly read ack is kept when a record is sent
+            let received = tokio::select! {
+                received = records.recv() => received,
+                read = reader.read_buf(&mut acks) => {
+                    let n = match read {
+                        Ok(n) => n,
+                        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => 0,
+                        Err(e) => {
+                            return Err(format!("session={id} error reading from follower: {e}").into())
+                        }
+                    };
+                    if n == 0 {
+                        tracing::info!(session = id, "follower disconnected");
+                        return Ok(());
+                    }
+                    let whole = acks.len() - acks.len() % 8;
+                    if let Some(seq) = acks[..whole].chunks(8).last() {
+                        log.ack(id, u64::from_be_bytes(seq.try_into().unwrap()));
+                    }
+                    acks.drain(..whole);
+                    continue;
+                }
+            };
+            let (seq, record) = match received {
+                Ok(received) => received,
+                Err(RecvError::Lagged(n)) => {
+                    return Err(format!(
+                        "session={id} follower fell {n} transactions behind, disconnecting"
+                    )
+                    .into())
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            };
+            writer
+                .write_all(&replication::encode(seq, &record)?)
+                .await
+                .map_err(|e| format!("session={id} error writing to follower: {e}"))?;
+            writer
+                .flush()
+                .await
+                .map_err(|e| format!("session={id} error flushing stream: {e}"))?;
+        }
+    }
+
+    /// The longest frame a follower reads from its leader: a transaction of what one
+    /// client request may carry, or of what a transaction may queue
+    fn max_replication_frame_len() -> u64 {
+        let config = get_config();
+        let max_len = config.max_request_bytes.max(config.max_request_value_bytes);
+        // every operation a request carries takes at least a byte of length prefixes
+        let max_operations = config.max_scan_bytes.max(MAX_QUEUED_OPS);
+        replication::max_frame_len(max_len, max_operations)
+    }
+
+    /// Apply the writes the leader at `leader_addr` streams to `store`, reconnecting
+    /// whenever the connection to it ends
+    async fn follow(
+        leader_addr: String,
+        certs: Vec<Certificate>,
+        client_cert: Option<ClientCert>,
+        mut store: S,
+        max_frame_len: u64,
+    ) {
+        loop {
+            let followed = Self::follow_once(
+                &leader_addr,
+                &certs,
+                &client_cert,
+                &mut store,
+                max_frame_len,
+            );
+            match followed.await {
+                Ok(()) => tracing::warn!("leader {leader_addr} closed the replication stream"),
+                Err(e) => tracing::warn!("error following leader {leader_addr}: {e}"),
+            }
+            tokio::time::sleep(FOLLOW_RETRY).await;
+        }
+    }
+
+    async fn follow_once(
+        leader_addr: &str,
+        certs: &[Certificate],
+        client_cert: &Option<ClientCert>,
+        store: &mut S,
+        max_frame_len: u64,
+    ) -> Result<()> {
+        let (host, port) = leader_addr
+            .rsplit_once(':')
+            .ok_or_else(|| format!("invalid leader address {leader_addr:?}, expected host:port"))?;
+        let port = port
+            .parse()
+            .map_err(|e| format!("invalid leader port {port:?}: {e}"))?;
+        let stream = match client_cert {
+            Some((client_certs, client_key)) => {
+                crate::client::connect_with_client_cert(
+                    host,
+                    port,
+                    certs.to_vec(),
+                    client_certs.clone(),
+                    client_key.clone(),
+                )
+                .await?
+            }
+            None => crate::client::connect(host, port, certs.to_vec()).await?,
+        };
+        let (mut reader, mut writer) = split(stream);
+        writer.write_all(REPLICATE).await?;
+        writer.flush().await?;
+        // replaced by the leader's snapshot, dropping the keys it deleted while this
+        // node wasn't following
+        let published = reader.read_u64().await?;
+        store.clear().await?;
+        let entries = crate::store::restore_from(store, &mut reader, max_frame_len).await?;
+        writer.write_u64(published).await?;
+        writer.flush().await?;
+        tracing::info!("restored {entries} entries from leader {leader_addr}, following it");
+        while let Some((seq, record)) = replication::decode_from(&mut reader, max_frame_len).await?
+        {
+            record.apply_to(store).await?;
+            writer.write_u64(seq).await?;
+            writer.flush().await?;
+        }
+        Ok(())
+    }
+
     pub async fn server_start(
         &mut self,
         sig_client_shutdown_send: UnboundedSender<bool>,
         mut client_svr_shutdown_recv: UnboundedReceiver<bool>,
     ) -> Result<bool> {
         // initialize cluster server
-        let config = rustls::ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
-            .with_single_cert(self.certs.clone(), self.keys[0].clone())
-            .map_err(|err| format!("tls config error: {err}"))?;
-        let acceptor = TlsAcceptor::from(Arc::new(config));
+        // fails before binding when the certs or key are misconfigured
+        if self.client_cas.is_none() {
+            let config = get_config();
+            self.client_cas = load_client_cas(
+                config.require_client_certs,
+                config.client_ca_path.as_deref(),
+            )?;
+        }
+        if !self.tls.is_set() {
+            self.tls.set_client_cas(self.client_cas.clone());
+            self.tls.set(&self.certs, &self.keys)?;
+        }
+        if self.node_client_cert.is_none() {
+            let config = get_config();
+            self.node_client_cert = load_node_client_cert(
+                config.node_client_cert_path.as_deref(),
+                config.node_client_key_path.as_deref(),
+            )?;
+        }
 
         let addr = self
             .addr
@@ -137,6 +458,16 @@ impl<S: Store + Clone + Send + Sync + 'static> Server<S> {
         tracing::info!("listening for cluster requests on {addr}");
         let listener = TcpListener::bind(&addr).await?;
 
+        let follower = self.leader_addr.clone().map(|leader_addr| {
+            tokio::spawn(Self::follow(
+                leader_addr,
+                self.certs.clone(),
+                self.node_client_cert.clone(),
+                self.store.clone(),
+                Self::max_replication_frame_len(),
+            ))
+        });
+
         let client_server_initiated_shutdown = loop {
             tokio::select! {
                 _ = self.sig_shutdown_recv.recv() => {
@@ -146,10 +477,34 @@ impl<S: Store + Clone + Send + Sync + 'static> Server<S> {
                     }
                     break false;
                 },
-                stream_peer_addr_res = listener.accept() => {
-                    let acceptor = acceptor.clone();
+                (stream_peer_addr_res, admission) = connections::accept(listener.accept(), self.connection_slots.as_ref()) => {
+                    let slot = match admission {
+                        Admission::Unlimited => None,
+                        Admission::Admitted(slot) => Some(slot),
+                        Admission::Refused => {
+                            tokio::spawn(async move {
+                                if let Err(e) = Self::refuse(stream_peer_addr_res).await {
+                                    tracing::debug!("error refusing cluster connection {e}");
+                                }
+                            });
+                            continue;
+                        }
+                    };
+                    // reloaded certs are picked up by the next handshake
+                    let acceptor = match self.tls.acceptor() {
+                        Ok(acceptor) => acceptor,
+                        Err(e) => {
+                            tracing::error!("error accepting cluster connection {e}");
+                            continue;
+                        }
+                    };
+                    let replication = self.replication.clone().map(|log| {
+                        let records = log.follow();
+                        (log, records)
+                    });
+                    let store = self.store.clone();
                     tokio::spawn(async move {
-                        if let Err(e) = Self::handle_conn(stream_peer_addr_res, acceptor).await {
+                        if let Err(e) = Self::handle_conn(stream_peer_addr_res, slot, acceptor, store, replication).await {
                             tracing::error!("error handling cluster connection {e}");
                         }
                     });
@@ -164,24 +519,44 @@ impl<S: Store + Clone + Send + Sync + 'static> Server<S> {
                 },
             }
         };
+        if let Some(follower) = follower {
+            follower.abort();
+        }
 
         if !client_server_initiated_shutdown && self.start_client_server {
             tracing::info!("server shutdown initiated, waiting for client-server shutdown signal");
-            if tokio::time::timeout(
-                std::time::Duration::from_secs(5),
-                client_svr_shutdown_recv.recv(),
-            )
-            .await
-            .is_err()
+            // on top of the time the client-server's sessions are given to drain
+            let timeout = get_config().drain_timeout + std::time::Duration::from_secs(5);
+            if tokio::time::timeout(timeout, client_svr_shutdown_recv.recv())
+                .await
+                .is_err()
             {
                 tracing::error!(
-                    "client-server failed to shutdown within 5s timeout. continuing shutdown"
+                    "client-server failed to shutdown within {timeout:?} timeout. continuing shutdown"
                 );
             }
         }
         Ok(client_server_initiated_shutdown)
     }
 
+    /// Start a sibling of `client_svr` on a new single threaded runtime, on its own
+    /// thread, returning the channels to shut it down with
+    fn spawn_client_runtime(
+        client_svr: &ClientServer<S>,
+        runtime: usize,
+    ) -> Result<(UnboundedSender<bool>, UnboundedReceiver<bool>)> {
+        let (svr_shutdown_send, svr_shutdown_recv) = tokio::sync::mpsc::unbounded_channel();
+        let (sig_shutdown_send, sig_shutdown_recv) = tokio::sync::mpsc::unbounded_channel();
+        let sibling = client_svr.sibling(svr_shutdown_send, sig_shutdown_recv);
+        let built = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        std::thread::Builder::new()
+            .name(format!("kave-client-{runtime}"))
+            .spawn(move || built.block_on(sibling.start()))?;
+        Ok((sig_shutdown_send, svr_shutdown_recv))
+    }
+
     pub async fn start(mut self) {
         tracing::info!("starting server");
 
@@ -189,6 +564,8 @@ impl<S: Store + Clone + Send + Sync + 'static> Server<S> {
             tokio::sync::mpsc::unbounded_channel();
         let (sig_client_shutdown_send, sig_client_shutdown_recv) =
             tokio::sync::mpsc::unbounded_channel();
+        // shutdown channels of the client-servers on the other client runtimes
+        let mut siblings = vec![];
 
         if self.start_client_server {
             let mut client_svr = ClientServer::new(
@@ -198,9 +575,30 @@ impl<S: Store + Clone + Send + Sync + 'static> Server<S> {
                 self.keys.clone(),
                 self.store.clone(),
             );
+            client_svr.set_event_sender(self.client_events.clone());
+            // a follower's store only takes its leader's writes
+            client_svr.set_read_only(self.leader_addr.is_some());
+            client_svr.set_replication_log(self.replication.clone());
+            client_svr
+                .set_tls(self.tls.clone())
+                .set_client_cas(self.client_cas.clone())
+                .set_node_client_cert(self.node_client_cert.clone());
+            client_svr.set_max_connections(
+                self.client_max_connections,
+                self.client_connection_limit_policy,
+            );
             if let Some(ref client_svr_addr) = self.client_svr_addr {
                 client_svr.set_addr(client_svr_addr);
             }
+            if self.client_runtimes > 1 {
+                client_svr.set_reuse_port(true);
+                for runtime in 1..self.client_runtimes {
+                    match Self::spawn_client_runtime(&client_svr, runtime) {
+                        Ok(sibling) => siblings.push(sibling),
+                        Err(e) => tracing::error!("error spawning client runtime {runtime}: {e}"),
+                    }
+                }
+            }
             tracing::info!("spawning client-server");
             tokio::spawn(async move { client_svr.start().await });
             tracing::info!("client-server spawned");
@@ -212,6 +610,17 @@ impl<S: Store + Clone + Send + Sync + 'static> Server<S> {
         {
             tracing::error!("error starting cluster-server: {e}");
         }
+        // they're shut down along with the client-server they were made from
+        let timeout = get_config().drain_timeout + std::time::Duration::from_secs(5);
+        for (shutdown_send, mut shutdown_recv) in siblings {
+            if shutdown_send.send(true).is_ok()
+                && tokio::time::timeout(timeout, shutdown_recv.recv())
+                    .await
+                    .is_err()
+            {
+                tracing::error!("client runtime failed to shutdown within {timeout:?} timeout");
+            }
+        }
 
         tracing::info!("server sending shutdown signal");
         self.svr_shutdown_send
@@ -219,3 +628,48 @@ impl<S: Store + Clone + Send + Sync + 'static> Server<S> {
             .expect("error sending server shutdown signal");
     }
 }
+
+impl Server<BackendStore> {
+    /// A server set up by the `ServerConfig` file at `path`: listening on its addresses
+    /// with its certs and key, limiting client connections as it says, and keeping data
+    /// in the store it names. Every request received on `store_shutdown_recv` shuts the
+    /// store down, see `StoreBackend::build`.
+    pub async fn from_config<P: AsRef<Path>>(
+        path: P,
+        svr_shutdown_send: UnboundedSender<bool>,
+        sig_shutdown_recv: UnboundedReceiver<bool>,
+        store_shutdown_recv: UnboundedReceiver<oneshot::Sender<bool>>,
+    ) -> Result<Self> {
+        let file = ServerConfig::load(path)?;
+        let certs = load_certs(&file.cert_path)?;
+        let keys = load_keys(&file.key_path)?;
+        validate_tls(&certs, &keys)?;
+        let client_cas =
+            load_client_cas(file.require_client_certs, file.client_ca_path.as_deref())?;
+        let node_client_cert = load_node_client_cert(
+            file.node_client_cert_path.as_deref(),
+            file.node_client_key_path.as_deref(),
+        )?;
+
+        let mut config = get_config();
+        config.store_backend = file.store_backend;
+        if let Some(data_dir) = file.data_dir {
+            config.data_dir = data_dir;
+        }
+        let store = StoreBackend::from_config(&config)
+            .build(store_shutdown_recv)
+            .await?;
+
+        let mut svr = Self::new(svr_shutdown_send, sig_shutdown_recv, certs, keys, store);
+        svr.set_client_cas(client_cas)
+            .set_node_client_cert(node_client_cert)
+            .set_addr(file.cluster_addr)
+            .set_start_client_server(file.client_addr.is_some())
+            .set_max_connections(file.cluster_max_connections, file.connection_limit_policy)
+            .set_client_max_connections(file.max_connections, file.connection_limit_policy);
+        if let Some(client_addr) = file.client_addr {
+            svr.set_client_server_addr(client_addr);
+        }
+        Ok(svr)
+    }
+}
@@ -0,0 +1,69 @@
+//! Limits on how many connections a server has open at once, shared by the
+//! client-server and the cluster server
+use crate::config::ConnectionLimitPolicy;
+use crate::error::Result;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Slots bounding the connections open at once, see `ClientServer::set_max_connections`
+#[derive(Clone, Debug)]
+pub(crate) struct ConnectionSlots {
+    slots: Arc<Semaphore>,
+    policy: ConnectionLimitPolicy,
+}
+impl ConnectionSlots {
+    pub(crate) fn new(max_connections: usize, policy: ConnectionLimitPolicy) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(max_connections)),
+            policy,
+        }
+    }
+}
+
+// whether an accepted connection may be handled
+pub(crate) enum Admission {
+    Unlimited,
+    // holding a slot until the connection is done with
+    Admitted(OwnedSemaphorePermit),
+    Refused,
+}
+
+/// Accept the next connection with `accept`, along with a slot for it when connections
+/// are limited. Under the `Queue` policy nothing is accepted until a slot is free,
+/// while under `Reject` the connection is accepted without one when they're all taken.
+pub(crate) async fn accept<T, F: Future<Output = T>>(
+    accept: F,
+    slots: Option<&ConnectionSlots>,
+) -> (T, Admission) {
+    let slots = match slots {
+        Some(slots) => slots,
+        None => return (accept.await, Admission::Unlimited),
+    };
+    let slot = match slots.policy {
+        ConnectionLimitPolicy::Queue => slots.slots.clone().acquire_owned().await.ok(),
+        ConnectionLimitPolicy::Reject => None,
+    };
+    let accepted = accept.await;
+    let slot = slot.or_else(|| slots.slots.clone().try_acquire_owned().ok());
+    match slot {
+        Some(slot) => (accepted, Admission::Admitted(slot)),
+        None => (accepted, Admission::Refused),
+    }
+}
+
+/// Turn away a connection beyond the max connections, before its TLS handshake
+pub(crate) async fn refuse<W: AsyncWrite + Unpin>(
+    mut stream: W,
+    peer_addr: SocketAddr,
+) -> Result<()> {
+    tracing::warn!("refusing connection from {peer_addr}, too many connections");
+    let msg = "busy: too many connections, retry later";
+    stream
+        .write_all(format!("ERR:{}:{msg}\n", msg.len()).as_bytes())
+        .await?;
+    stream.shutdown().await?;
+    Ok(())
+}
@@ -0,0 +1,89 @@
+//! Recent errors and slow commands of every session, kept in memory so operators
+//! can inspect them with DEBUG commands without grepping the server's logs
+use crate::proto::ProtoObserver;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Entries each log keeps by default, older ones are dropped to make room
+pub const DEFAULT_LOG_LEN: usize = 128;
+
+/// The latest entries written to a log, up to its capacity. Clones share the
+/// same entries.
+#[derive(Clone, Debug)]
+pub struct RecentLog {
+    entries: Arc<Mutex<VecDeque<String>>>,
+    capacity: usize,
+}
+impl Default for RecentLog {
+    fn default() -> Self {
+        Self::new(DEFAULT_LOG_LEN)
+    }
+}
+impl RecentLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    /// Add an entry for `session`, stamped with the unix time in milliseconds
+    pub fn push(&self, session: &str, msg: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis());
+        let mut entries = self.entries.lock().expect("recent log lock poisoned");
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(format!("{millis} session={session} {msg}"));
+    }
+
+    /// Every entry kept, oldest first
+    pub fn entries(&self) -> Vec<String> {
+        let entries = self.entries.lock().expect("recent log lock poisoned");
+        entries.iter().cloned().collect()
+    }
+
+    /// Drop every entry, returning how many there were
+    pub fn clear(&self) -> usize {
+        let mut entries = self.entries.lock().expect("recent log lock poisoned");
+        let cleared = entries.len();
+        entries.clear();
+        cleared
+    }
+}
+
+impl ProtoObserver for RecentLog {
+    fn error(&self, session: &str, msg: &str) {
+        self.push(session, msg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RecentLog;
+
+    #[test]
+    fn test_recent_log() {
+        let log = RecentLog::new(2);
+        let shared = log.clone();
+        log.push("a", "first");
+        shared.push("b", "second");
+        log.push("c", "third");
+        let entries = shared.entries();
+        assert_eq!(2, entries.len());
+        assert!(entries[0].ends_with(" session=b second"), "{entries:?}");
+        assert!(entries[1].ends_with(" session=c third"), "{entries:?}");
+        assert_eq!(2, log.clear());
+        assert!(shared.entries().is_empty());
+
+        let disabled = RecentLog::new(0);
+        disabled.push("a", "first");
+        assert!(disabled.entries().is_empty());
+    }
+}
@@ -0,0 +1,46 @@
+//! Lifecycle events of client sessions, so applications embedding the
+//! server can react to them without parsing logs
+use std::net::SocketAddr;
+use std::time::Duration;
+
+pub use super::metrics::Outcome;
+
+/// How many events are buffered for each subscriber. Subscribers that
+/// fall further behind miss the oldest events rather than blocking sessions.
+pub const EVENT_CAPACITY: usize = 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SessionEvent {
+    /// A client connected and completed the TLS handshake
+    Opened { session: String, peer: SocketAddr },
+    /// A command was processed, `ok` is false when it ended the session with an error.
+    /// Its `outcome` is also an error when the command was answered with one.
+    Command {
+        session: String,
+        op: &'static str,
+        ok: bool,
+        outcome: Outcome,
+        elapsed: Duration,
+    },
+    /// A session ended
+    Closed {
+        session: String,
+        reason: CloseReason,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The client shut down its TLS session cleanly
+    ClientDisconnected,
+    /// The client ended the session with QUIT
+    ClientQuit,
+    /// The connection went away without the client shutting down its TLS session
+    ConnectionReset,
+    /// The server is shutting down
+    ServerShutdown,
+    /// The client sent nothing for longer than the server's idle timeout
+    IdleTimeout,
+    /// The session failed, e.g. the client broke the protocol
+    Error(String),
+}
@@ -0,0 +1,57 @@
+//! Dispatch of the commands read from a client session
+//!
+//! Every op a session reads is handed to the server's `CommandHandler`. `Builtin`
+//! handles the protocol's own commands, and embedders add commands of their own by
+//! implementing `CommandHandler`, listing their commands in `commands` so the protocol
+//! reads them as `ProtoOp::Custom`, and passing everything else on to `Builtin`.
+use async_trait::async_trait;
+
+use crate::error::Result;
+use crate::proto::{Proto, ProtoOp, Writer};
+use crate::server::client::{Connection, SessionOptions, SessionState};
+use crate::store::Store;
+
+/// What a handler has access to while handling a single op
+pub struct CommandContext<'a, S> {
+    // the session's id
+    pub id: &'a str,
+    pub store: &'a mut S,
+    pub options: &'a SessionOptions,
+    pub state: &'a mut SessionState,
+    // used to write results to `writer`
+    pub proto: &'a Proto,
+    pub writer: &'a mut Writer,
+}
+
+#[async_trait]
+pub trait CommandHandler<S: Send>: Send + Sync {
+    /// Commands this handler adds to the protocol, with the number of
+    /// length-prefixed arguments each takes
+    fn commands(&self) -> Vec<(&'static str, usize)> {
+        vec![]
+    }
+
+    /// Apply a single op read from the client and write its result,
+    /// returning whether the session should keep reading
+    async fn handle(&self, ctx: CommandContext<'_, S>, op: ProtoOp) -> Result<bool>;
+}
+
+/// Handles the built-in commands, answering custom ones with an error
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Builtin;
+
+#[async_trait]
+impl<S: Store + Send + Sync + Clone + 'static> CommandHandler<S> for Builtin {
+    async fn handle(&self, ctx: CommandContext<'_, S>, op: ProtoOp) -> Result<bool> {
+        Connection::handle_op(
+            ctx.id,
+            ctx.store,
+            ctx.options,
+            ctx.state,
+            ctx.proto,
+            ctx.writer,
+            op,
+        )
+        .await
+    }
+}
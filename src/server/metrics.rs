@@ -0,0 +1,282 @@
+//! Counters of the commands client sessions handle, by command and outcome,
+//! so operators can tell which commands are failing and how often, along with
+//! the connections and bytes they serve. `serve` exports them over HTTP in the
+//! Prometheus text format.
+use crate::error::Result;
+use crate::proto::ProtoObserver;
+use crate::server::sessions::Sessions;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+// longest HTTP request head read from a scraper
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// How handling a command ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Outcome {
+    /// The command was answered without an error
+    Ok,
+    /// The command was answered with an error, or failed and ended the session
+    Error,
+    /// The command ran past the server's command timeout and ended the session
+    Timeout,
+}
+impl Outcome {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Outcome::Ok => "ok",
+            Outcome::Error => "error",
+            Outcome::Timeout => "timeout",
+        }
+    }
+}
+
+/// Commands handled by every session of a server, counted by command name and
+/// outcome. Clones share the same counters.
+#[derive(Clone, Debug, Default)]
+pub struct CommandMetrics {
+    counts: Arc<Mutex<BTreeMap<(&'static str, Outcome), u64>>>,
+}
+impl CommandMetrics {
+    pub fn record(&self, op: &'static str, outcome: Outcome) {
+        let mut counts = self.counts.lock().expect("command metrics lock poisoned");
+        *counts.entry((op, outcome)).or_default() += 1;
+    }
+
+    /// The number of `op` commands that ended with `outcome`
+    pub fn count(&self, op: &str, outcome: Outcome) -> u64 {
+        let counts = self.counts.lock().expect("command metrics lock poisoned");
+        counts
+            .iter()
+            .find(|((name, o), _)| *name == op && *o == outcome)
+            .map_or(0, |(_, n)| *n)
+    }
+
+    /// Every count recorded so far, sorted by command name then outcome
+    pub fn snapshot(&self) -> Vec<(&'static str, Outcome, u64)> {
+        let counts = self.counts.lock().expect("command metrics lock poisoned");
+        counts
+            .iter()
+            .map(|((op, outcome), n)| (*op, *outcome, *n))
+            .collect()
+    }
+}
+
+/// Connections accepted, and bytes read and written, by every session of a server.
+/// Clones share the same counters.
+#[derive(Clone, Debug, Default)]
+pub struct TrafficMetrics {
+    counts: Arc<TrafficCounts>,
+}
+#[derive(Debug, Default)]
+struct TrafficCounts {
+    connections: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+}
+impl TrafficMetrics {
+    pub fn connected(&self) {
+        self.counts.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn read(&self, n: usize) {
+        self.counts
+            .bytes_read
+            .fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn written(&self, n: usize) {
+        self.counts
+            .bytes_written
+            .fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// The number of connections accepted so far
+    pub fn connections(&self) -> u64 {
+        self.counts.connections.load(Ordering::Relaxed)
+    }
+
+    /// The number of bytes read from clients so far, TLS framing aside
+    pub fn bytes_read(&self) -> u64 {
+        self.counts.bytes_read.load(Ordering::Relaxed)
+    }
+
+    /// The number of bytes written to clients so far, TLS framing aside
+    pub fn bytes_written(&self) -> u64 {
+        self.counts.bytes_written.load(Ordering::Relaxed)
+    }
+}
+
+impl ProtoObserver for TrafficMetrics {
+    fn read(&self, n: usize) {
+        TrafficMetrics::read(self, n);
+    }
+
+    fn written(&self, n: usize) {
+        TrafficMetrics::written(self, n);
+    }
+}
+
+/// Render the metrics of a server in the Prometheus text exposition format
+pub fn render(commands: &CommandMetrics, traffic: &TrafficMetrics, sessions: &Sessions) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, u64)>| {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        for (labels, value) in samples {
+            let _ = writeln!(out, "{name}{labels} {value}");
+        }
+    };
+    metric(
+        "kave_connections_total",
+        "counter",
+        "Client connections accepted.",
+        vec![(String::new(), traffic.connections())],
+    );
+    metric(
+        "kave_connections_open",
+        "gauge",
+        "Client sessions currently connected.",
+        vec![(String::new(), sessions.len() as u64)],
+    );
+    metric(
+        "kave_read_bytes_total",
+        "counter",
+        "Protocol bytes read from clients.",
+        vec![(String::new(), traffic.bytes_read())],
+    );
+    metric(
+        "kave_written_bytes_total",
+        "counter",
+        "Protocol bytes written to clients.",
+        vec![(String::new(), traffic.bytes_written())],
+    );
+    metric(
+        "kave_commands_total",
+        "counter",
+        "Commands handled, by command and outcome.",
+        commands
+            .snapshot()
+            .into_iter()
+            .map(|(op, outcome, n)| {
+                let labels = format!("{{command=\"{op}\",outcome=\"{}\"}}", outcome.name());
+                (labels, n)
+            })
+            .collect(),
+    );
+    out
+}
+
+/// Answer scrapes of `GET /metrics` on `listener` until the task is dropped
+pub async fn serve(
+    listener: TcpListener,
+    commands: CommandMetrics,
+    traffic: TrafficMetrics,
+    sessions: Sessions,
+) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                tracing::warn!("error accepting metrics connection {e}");
+                continue;
+            }
+        };
+        let body = render(&commands, &traffic, &sessions);
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, body).await {
+                tracing::debug!("error answering metrics scrape {e}");
+            }
+        });
+    }
+}
+
+/// Answer a single HTTP request, closing the connection after the response
+async fn respond(mut stream: TcpStream, body: String) -> Result<()> {
+    let mut head = Vec::with_capacity(1024);
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() >= MAX_REQUEST_HEAD || stream.read_buf(&mut head).await? == 0 {
+            break;
+        }
+    }
+    let request_line = head.split(|b| *b == b'\n').next().unwrap_or_default();
+    let (status, content_type, body) = if request_line.starts_with(b"GET /metrics ") {
+        ("200 OK", "text/plain; version=0.0.4", body)
+    } else {
+        ("404 Not Found", "text/plain", "not found\n".to_string())
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render, CommandMetrics, Outcome, TrafficMetrics};
+    use crate::server::sessions::Sessions;
+
+    #[test]
+    fn test_command_metrics() {
+        let metrics = CommandMetrics::default();
+        let shared = metrics.clone();
+        metrics.record("SET", Outcome::Ok);
+        shared.record("SET", Outcome::Ok);
+        shared.record("SET", Outcome::Error);
+        metrics.record("GET", Outcome::Timeout);
+        assert_eq!(2, metrics.count("SET", Outcome::Ok));
+        assert_eq!(1, metrics.count("SET", Outcome::Error));
+        assert_eq!(0, metrics.count("SET", Outcome::Timeout));
+        assert_eq!(0, metrics.count("DEL", Outcome::Ok));
+        assert_eq!(
+            vec![
+                ("GET", Outcome::Timeout, 1),
+                ("SET", Outcome::Ok, 2),
+                ("SET", Outcome::Error, 1),
+            ],
+            shared.snapshot()
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let commands = CommandMetrics::default();
+        commands.record("GET", Outcome::Ok);
+        commands.record("GET", Outcome::Error);
+        let traffic = TrafficMetrics::default();
+        traffic.connected();
+        traffic.read(12);
+        traffic.written(30);
+        traffic.written(4);
+        let sessions = Sessions::default();
+        let _session = sessions.register("a", "127.0.0.1:1234".parse().unwrap());
+        assert_eq!(
+            "\
+# HELP kave_connections_total Client connections accepted.
+# TYPE kave_connections_total counter
+kave_connections_total 1
+# HELP kave_connections_open Client sessions currently connected.
+# TYPE kave_connections_open gauge
+kave_connections_open 1
+# HELP kave_read_bytes_total Protocol bytes read from clients.
+# TYPE kave_read_bytes_total counter
+kave_read_bytes_total 12
+# HELP kave_written_bytes_total Protocol bytes written to clients.
+# TYPE kave_written_bytes_total counter
+kave_written_bytes_total 34
+# HELP kave_commands_total Commands handled, by command and outcome.
+# TYPE kave_commands_total counter
+kave_commands_total{command=\"GET\",outcome=\"ok\"} 1
+kave_commands_total{command=\"GET\",outcome=\"error\"} 1
+",
+            render(&commands, &traffic, &sessions)
+        );
+    }
+}
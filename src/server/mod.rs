@@ -1,14 +1,66 @@
-use crate::error::Result;
+use crate::client::ClientCert;
+use crate::error::{Error, Result};
+use socket2::SockRef;
 use std::fs::File;
 use std::io::BufReader;
 use std::path::Path;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
 use tokio_rustls::rustls::{Certificate, PrivateKey};
 
 mod client;
 mod cluster;
+mod connections;
+pub mod diagnostics;
+pub mod events;
+pub mod handler;
+pub mod metrics;
+pub mod sessions;
+pub mod shards;
+mod tls;
 
-pub use client::ClientServer;
+pub use client::{ClientServer, QueuedTransaction, SessionOptions, SessionState, MAX_QUEUED_OPS};
 pub use cluster::Server;
+pub use tls::{server_mtls_config, server_tls_config, validate_tls, TlsCerts};
+
+/// Bind a listener to `addr`, optionally with SO_REUSEPORT set so that
+/// multiple listeners (e.g. one per runtime) can share the same port.
+pub async fn bind_listener(addr: &str, reuse_port: bool) -> Result<TcpListener> {
+    if !reuse_port {
+        return Ok(TcpListener::bind(addr).await?);
+    }
+    let sock_addr = tokio::net::lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| Error::DnsResolutionFailure(addr.into()))?;
+    let socket = if sock_addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    #[cfg(unix)]
+    socket.set_reuseport(true)?;
+    socket.bind(sock_addr)?;
+    Ok(socket.listen(1024)?)
+}
+
+/// Size the OS send (SO_SNDBUF) and receive (SO_RCVBUF) buffers of an accepted
+/// connection, leaving either to the OS when `None`. Linux doubles the requested
+/// sizes to allow for its bookkeeping, and caps them at `net.core.[wr]mem_max`.
+pub fn set_socket_buffers(
+    stream: &TcpStream,
+    send: Option<usize>,
+    recv: Option<usize>,
+) -> Result<()> {
+    let socket = SockRef::from(stream);
+    if let Some(size) = send {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = recv {
+        socket.set_recv_buffer_size(size)?;
+    }
+    Ok(())
+}
 
 pub fn load_certs<P: AsRef<Path>>(p: P) -> Result<Vec<Certificate>> {
     let certs: Vec<Certificate> =
@@ -25,3 +77,82 @@ pub fn load_keys<P: AsRef<Path>>(p: P) -> Result<Vec<PrivateKey>> {
             .map(|mut keys| keys.drain(..).map(PrivateKey).collect())?;
     Ok(keys)
 }
+
+/// The CAs whose certs clients must present, loaded from `client_ca_path` when
+/// `require_client_certs` is set. `None` when clients needn't present any
+pub fn load_client_cas(
+    require_client_certs: bool,
+    client_ca_path: Option<&str>,
+) -> Result<Option<Vec<Certificate>>> {
+    if !require_client_certs {
+        return Ok(None);
+    }
+    let path = client_ca_path
+        .ok_or("tls config error: client certs are required, but no client CA path is set")?;
+    let cas = load_certs(path).map_err(|e| format!("error loading client CAs {path:?}: {e}"))?;
+    Ok(Some(cas))
+}
+
+/// The cert and key this node presents when it connects to other nodes, loaded from
+/// `cert_path` and `key_path`. `None` when neither is set
+pub fn load_node_client_cert(
+    cert_path: Option<&str>,
+    key_path: Option<&str>,
+) -> Result<Option<ClientCert>> {
+    let (cert_path, key_path) = match (cert_path, key_path) {
+        (None, None) => return Ok(None),
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        _ => {
+            return Err(
+                "tls config error: the node client cert and key must be set together".into(),
+            )
+        }
+    };
+    let certs = load_certs(cert_path)
+        .map_err(|e| format!("error loading node client cert {cert_path:?}: {e}"))?;
+    let key = load_keys(key_path)
+        .map_err(|e| format!("error loading node client key {key_path:?}: {e}"))?
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("tls config error: no private key in {key_path:?}"))?;
+    Ok(Some((certs, key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use socket2::SockRef;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::set_socket_buffers;
+
+    #[tokio::test]
+    async fn test_socket_buffers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_client, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let (stream, _) = accepted.unwrap();
+        let socket = SockRef::from(&stream);
+        let default_send = socket.send_buffer_size().unwrap();
+        let default_recv = socket.recv_buffer_size().unwrap();
+
+        // unset sizes are left alone
+        set_socket_buffers(&stream, None, None).unwrap();
+        assert_eq!(default_send, socket.send_buffer_size().unwrap());
+        assert_eq!(default_recv, socket.recv_buffer_size().unwrap());
+
+        // a bigger send buffer and a smaller receive buffer than the defaults,
+        // within the room Linux gives itself for bookkeeping
+        let (send, recv) = (default_send * 2, default_recv / 4);
+        set_socket_buffers(&stream, Some(send), Some(recv)).unwrap();
+        let applied_send = socket.send_buffer_size().unwrap();
+        let applied_recv = socket.recv_buffer_size().unwrap();
+        assert!(
+            (send..=send * 2).contains(&applied_send),
+            "{applied_send} for {send}"
+        );
+        assert!(
+            (recv..=recv * 2).contains(&applied_recv),
+            "{applied_recv} for {recv}"
+        );
+    }
+}
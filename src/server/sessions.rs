@@ -0,0 +1,84 @@
+//! Registry of the client sessions connected to a server
+//!
+//! Sessions register when they're accepted and get back a `SessionGuard`
+//! that deregisters them when dropped. There's no async `Drop`, so tying the
+//! cleanup to the guard's (sync) `Drop` is what makes sure it also happens
+//! when a session's task panics or is cancelled mid-command.
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+#[derive(Clone, Debug, Default)]
+pub struct Sessions {
+    inner: Arc<Mutex<Registry>>,
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    // peer address of every connected session, by session id
+    peers: HashMap<String, SocketAddr>,
+    // number of connected sessions from each ip
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+impl Sessions {
+    /// Register a connected session, which stays registered until the guard is dropped
+    pub fn register(&self, id: &str, peer: SocketAddr) -> SessionGuard {
+        let mut registry = self.lock();
+        registry.peers.insert(id.to_string(), peer);
+        *registry.per_ip.entry(peer.ip()).or_default() += 1;
+        SessionGuard {
+            sessions: self.clone(),
+            id: id.to_string(),
+            peer,
+        }
+    }
+
+    /// Number of connected sessions
+    pub fn len(&self) -> usize {
+        self.lock().peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of connected sessions from `ip`
+    pub fn connections_from(&self, ip: IpAddr) -> usize {
+        self.lock().per_ip.get(&ip).copied().unwrap_or_default()
+    }
+
+    /// Peer address of a connected session
+    pub fn peer(&self, id: &str) -> Option<SocketAddr> {
+        self.lock().peers.get(id).copied()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Registry> {
+        // the registry is never left half-updated, so it's still
+        // usable if a panic happened while it was locked
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Keeps a session registered, deregistering it when dropped
+#[derive(Debug)]
+pub struct SessionGuard {
+    sessions: Sessions,
+    id: String,
+    peer: SocketAddr,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        let mut registry = self.sessions.lock();
+        registry.peers.remove(&self.id);
+        let ip = self.peer.ip();
+        if let Some(count) = registry.per_ip.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                registry.per_ip.remove(&ip);
+            }
+        }
+        tracing::trace!(session = %self.id, "deregistered session");
+    }
+}
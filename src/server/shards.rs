@@ -0,0 +1,137 @@
+//! Spreading keys over several nodes' memory, routing each to the node that owns it
+//!
+//! Every node is given the same consistent-hashing `Ring` of the nodes' client
+//! addresses, along with its own address on it. A session answers the commands on a
+//! single key it owns from its own store, and forwards those on the keys it doesn't to
+//! their owner over a pooled connection, relaying its response. Keys are placed by
+//! the name the client gave them, and the connection is scoped to the session's
+//! namespace and value encoding before each forwarded command. Any node is an entry
+//! point for every key, as long as they're all given the same ring. It only goes so far:
+//! - commands on several keys (MGET, MSET, SWAP) are refused when a key is owned by
+//!   another node, and the rest (SCAN, FIND, FLUSHALL, ...) only see the entry node's store
+//! - a transaction only commits to the entry node's store, so one writing a key
+//!   owned by another node is discarded with an error, its reads are routed as usual
+//! - routed values larger than the entry node's max value size are always rejected,
+//!   never truncated
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use tokio_rustls::rustls::Certificate;
+
+use crate::client::pool::Pool;
+use crate::client::ring::Ring;
+use crate::client::{ClientCert, Response};
+use crate::error::Result;
+use crate::proto::{self, Encoding};
+
+/// Connections each node keeps to every other node, for the requests it forwards
+pub const POOL_SIZE: usize = 16;
+
+/// The nodes keys are spread over, and the connections to the ones keys are
+/// forwarded to. Clones share the same connections.
+#[derive(Clone)]
+pub struct Shards {
+    shared: Arc<Shared>,
+}
+struct Shared {
+    // this node's address on the ring
+    node: String,
+    ring: Ring,
+    // a pool per node other than this one
+    pools: HashMap<String, Pool>,
+}
+impl Shards {
+    /// Route the keys `ring` doesn't place on `node` to the node it does, trusting
+    /// `certs` when connecting to them. `node` needn't be on the ring, in which case
+    /// every key is forwarded.
+    pub fn new<N: Into<String>>(node: N, ring: Ring, certs: Vec<Certificate>) -> Result<Self> {
+        Self::with_client_cert(node, ring, certs, None)
+    }
+
+    /// Route keys like `new`, presenting `client_cert` when connecting to the other
+    /// nodes when it's set, for nodes that require clients to have a cert
+    pub fn with_client_cert<N: Into<String>>(
+        node: N,
+        ring: Ring,
+        certs: Vec<Certificate>,
+        client_cert: Option<ClientCert>,
+    ) -> Result<Self> {
+        let node = node.into();
+        let mut pools = HashMap::new();
+        for other in ring.nodes() {
+            if other == node {
+                continue;
+            }
+            let (host, port) = other
+                .rsplit_once(':')
+                .ok_or_else(|| format!("invalid shard node {other:?}, expected host:port"))?;
+            let port = port
+                .parse()
+                .map_err(|e| format!("invalid shard node {other:?}: {e}"))?;
+            let pool =
+                Pool::with_client_cert(host, port, certs.clone(), client_cert.clone(), POOL_SIZE);
+            pools.insert(other.to_string(), pool);
+        }
+        Ok(Self {
+            shared: Arc::new(Shared { node, ring, pools }),
+        })
+    }
+
+    /// This node's address on the ring
+    pub fn node(&self) -> &str {
+        &self.shared.node
+    }
+
+    pub fn ring(&self) -> &Ring {
+        &self.shared.ring
+    }
+
+    /// The node `key` is forwarded to, `None` when this node owns it
+    pub fn owner(&self, key: &[u8]) -> Option<&str> {
+        self.shared
+            .ring
+            .node_for(key)
+            .filter(|owner| *owner != self.shared.node)
+    }
+
+    /// Send `req`, the request of an op on a key `node` owns (see `ProtoOp::request`),
+    /// to it and return its response as is. The connection is first scoped to
+    /// `namespace`, the default keyspace when `None`, and set to write values with
+    /// `encoding`, so the response is the one the session forwarding `req` would have
+    /// written.
+    pub async fn forward(
+        &self,
+        node: &str,
+        namespace: Option<&str>,
+        encoding: Encoding,
+        req: Vec<u8>,
+    ) -> Result<Response> {
+        let mut reqs = proto::request("USE", &[namespace.unwrap_or_default().as_bytes()]);
+        reqs.extend(proto::request("HELLO", &[encoding.name().as_bytes()]));
+        reqs.extend(req);
+        let pool = self
+            .shared
+            .pools
+            .get(node)
+            .ok_or_else(|| format!("no connections to shard node {node:?}"))?;
+        let mut conn = pool.get_conn().await?;
+        let mut responses = conn.requests(&reqs, 3).await?;
+        let response = responses.pop().expect("a response per request");
+        match responses
+            .into_iter()
+            .find(|r| matches!(r, Response::Error(_)))
+        {
+            Some(Response::Error(msg)) => Err(format!("scoping the connection: {msg}").into()),
+            _ => Ok(response),
+        }
+    }
+}
+impl fmt::Debug for Shards {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Shards")
+            .field("node", &self.shared.node)
+            .field("nodes", &self.shared.ring.nodes())
+            .finish()
+    }
+}
@@ -0,0 +1,327 @@
+//! Checks of the server's TLS certificates and key, so that a misconfiguration
+//! fails at startup with a clear error instead of on the first handshake, and
+//! the `TlsCerts` servers take them from so they can be swapped while running
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use ring::signature::{KeyPair, RsaKeyPair};
+use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
+use tokio_rustls::rustls::{self, Certificate, PrivateKey};
+use tokio_rustls::TlsAcceptor;
+
+use crate::error::Result;
+use crate::server::{load_certs, load_keys};
+
+/// The TLS config new handshakes are accepted with. Reloading it only affects
+/// handshakes from then on, connections already made keep the certs they were made
+/// with. Clones share the same config, so a server's listeners can share one.
+#[derive(Clone, Default)]
+pub struct TlsCerts {
+    state: Arc<RwLock<TlsState>>,
+}
+#[derive(Default)]
+struct TlsState {
+    // unset until the server starts, or certs are first loaded
+    config: Option<Arc<rustls::ServerConfig>>,
+    // CAs whose certs clients must present, clients needn't present any when unset
+    client_cas: Option<Vec<Certificate>>,
+}
+impl TlsCerts {
+    /// Accept handshakes with `certs` and `keys` from now on, once they're validated
+    /// like they are at startup. The current ones are kept when they're invalid.
+    pub fn set(&self, certs: &[Certificate], keys: &[PrivateKey]) -> Result<()> {
+        let mut state = self.state.write().expect("tls certs lock poisoned");
+        let config = match &state.client_cas {
+            Some(client_cas) => server_mtls_config(certs, keys, client_cas)?,
+            None => server_tls_config(certs, keys)?,
+        };
+        state.config = Some(Arc::new(config));
+        Ok(())
+    }
+
+    /// Require clients to present a cert issued by one of `client_cas` in their
+    /// handshakes, or stop requiring one when `None`. Takes effect from the next time
+    /// the certs are set or reloaded, which servers first do when they start.
+    pub fn set_client_cas(&self, client_cas: Option<Vec<Certificate>>) {
+        self.state
+            .write()
+            .expect("tls certs lock poisoned")
+            .client_cas = client_cas;
+    }
+
+    /// Load the certs and key in the files at `cert_path` and `key_path`, then
+    /// accept handshakes with them as `set` does
+    pub fn reload<C: AsRef<Path>, K: AsRef<Path>>(&self, cert_path: C, key_path: K) -> Result<()> {
+        let certs = load_certs(cert_path.as_ref())
+            .map_err(|e| format!("error loading certs {:?}: {e}", cert_path.as_ref()))?;
+        let keys = load_keys(key_path.as_ref())
+            .map_err(|e| format!("error loading keys {:?}: {e}", key_path.as_ref()))?;
+        self.set(&certs, &keys)
+    }
+
+    pub fn is_set(&self) -> bool {
+        let state = self.state.read().expect("tls certs lock poisoned");
+        state.config.is_some()
+    }
+
+    /// An acceptor for the next handshake, with the certs set most recently
+    pub fn acceptor(&self) -> Result<TlsAcceptor> {
+        let state = self.state.read().expect("tls certs lock poisoned");
+        let config = state
+            .config
+            .clone()
+            .ok_or("tls config error: no certificates loaded")?;
+        Ok(TlsAcceptor::from(config))
+    }
+}
+
+/// Check that there's a cert and key, that the key is the private key of the first
+/// (leaf) cert, and that every cert is within its validity period
+pub fn validate_tls(certs: &[Certificate], keys: &[PrivateKey]) -> Result<()> {
+    validate_tls_at(certs, keys, Utc::now())
+}
+
+/// Validate the certs and key, then build the config the server's TLS sessions use
+pub fn server_tls_config(
+    certs: &[Certificate],
+    keys: &[PrivateKey],
+) -> Result<rustls::ServerConfig> {
+    build_server_tls_config(certs, keys, None)
+}
+
+/// Build the config the server's TLS sessions use like `server_tls_config`, only
+/// requiring clients to present a cert issued by one of `client_cas` in their handshake
+pub fn server_mtls_config(
+    certs: &[Certificate],
+    keys: &[PrivateKey],
+    client_cas: &[Certificate],
+) -> Result<rustls::ServerConfig> {
+    build_server_tls_config(certs, keys, Some(client_cas))
+}
+
+fn build_server_tls_config(
+    certs: &[Certificate],
+    keys: &[PrivateKey],
+    client_cas: Option<&[Certificate]>,
+) -> Result<rustls::ServerConfig> {
+    validate_tls(certs, keys)?;
+    let builder = rustls::ServerConfig::builder().with_safe_defaults();
+    let builder = match client_cas {
+        Some(client_cas) => {
+            if client_cas.is_empty() {
+                return Err("tls config error: no client CA certificates found".into());
+            }
+            let mut roots = rustls::RootCertStore::empty();
+            for (i, ca) in client_cas.iter().enumerate() {
+                roots.add(ca).map_err(|e| {
+                    format!("tls config error: client CA certificate {i} is invalid: {e}")
+                })?;
+            }
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(certs.to_vec(), keys[0].clone())
+        .map_err(|err| format!("tls config error: {err}"))?;
+    Ok(config)
+}
+
+fn validate_tls_at(certs: &[Certificate], keys: &[PrivateKey], now: DateTime<Utc>) -> Result<()> {
+    if certs.is_empty() {
+        return Err("tls config error: no certificates found".into());
+    }
+    let key = keys
+        .first()
+        .ok_or("tls config error: no private keys found")?;
+    for (i, cert) in certs.iter().enumerate() {
+        let info = CertInfo::parse(&cert.0)
+            .map_err(|e| format!("tls config error: certificate {i} is invalid: {e}"))?;
+        if now < info.not_before {
+            return Err(format!(
+                "tls config error: certificate {i} is not valid until {}",
+                info.not_before
+            )
+            .into());
+        }
+        if now > info.not_after {
+            return Err(format!(
+                "tls config error: certificate {i} expired at {}",
+                info.not_after
+            )
+            .into());
+        }
+        if i == 0 {
+            let key_pair = RsaKeyPair::from_der(&key.0)
+                .map_err(|e| format!("tls config error: invalid RSA private key: {e}"))?;
+            if key_pair.public_key().as_ref() != info.public_key {
+                return Err(
+                    "tls config error: the private key doesn't match the certificate".into(),
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The parts of an X.509 certificate that are checked at startup
+struct CertInfo<'a> {
+    not_before: DateTime<Utc>,
+    not_after: DateTime<Utc>,
+    // the subject's public key, which for RSA is a DER `RSAPublicKey`
+    public_key: &'a [u8],
+}
+
+impl<'a> CertInfo<'a> {
+    /// Parse the fields we need from a DER certificate. See RFC 5280 4.1:
+    ///
+    /// Certificate ::= SEQUENCE { tbsCertificate, signatureAlgorithm, signatureValue }
+    /// TBSCertificate ::= SEQUENCE { [0] version OPTIONAL, serialNumber, signature,
+    ///     issuer, validity, subject, subjectPublicKeyInfo, ... }
+    fn parse(der: &'a [u8]) -> std::result::Result<Self, String> {
+        let (cert, _) = der_expect(der, SEQUENCE)?;
+        let (tbs, _) = der_expect(cert, SEQUENCE)?;
+        let mut fields = tbs;
+        if fields.first() == Some(&VERSION) {
+            fields = der_next(fields)?.2;
+        }
+        // serial number, signature algorithm and issuer
+        for _ in 0..3 {
+            fields = der_next(fields)?.2;
+        }
+        let (validity, fields) = der_expect(fields, SEQUENCE)?;
+        let (tag, not_before, validity) = der_next(validity)?;
+        let not_before = parse_time(tag, not_before)?;
+        let (tag, not_after, _) = der_next(validity)?;
+        let not_after = parse_time(tag, not_after)?;
+        // subject
+        let fields = der_next(fields)?.2;
+        let (spki, _) = der_expect(fields, SEQUENCE)?;
+        // algorithm, then the key as a bit string led by its count of unused bits
+        let (_, spki) = der_expect(spki, SEQUENCE)?;
+        let (public_key, _) = der_expect(spki, BIT_STRING)?;
+        let public_key = match public_key {
+            [0, key @ ..] => key,
+            _ => return Err("unexpected public key encoding".to_string()),
+        };
+        Ok(Self {
+            not_before,
+            not_after,
+            public_key,
+        })
+    }
+}
+
+const SEQUENCE: u8 = 0x30;
+const BIT_STRING: u8 = 0x03;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+// context specific [0], explicitly tagged
+const VERSION: u8 = 0xa0;
+
+/// Split the next DER element off `data`, as its tag, its contents and the bytes following it
+fn der_next(data: &[u8]) -> std::result::Result<(u8, &[u8], &[u8]), String> {
+    let truncated = || "truncated DER".to_string();
+    let (&tag, rest) = data.split_first().ok_or_else(truncated)?;
+    let (&len, mut rest) = rest.split_first().ok_or_else(truncated)?;
+    let len = if len & 0x80 == 0 {
+        len as usize
+    } else {
+        // long form, the low bits count the big-endian length bytes that follow
+        let n = (len & 0x7f) as usize;
+        if n == 0 || n > 4 || rest.len() < n {
+            return Err("invalid DER length".to_string());
+        }
+        let len = rest[..n].iter().fold(0, |len, b| len << 8 | *b as usize);
+        rest = &rest[n..];
+        len
+    };
+    if rest.len() < len {
+        return Err(truncated());
+    }
+    Ok((tag, &rest[..len], &rest[len..]))
+}
+
+/// Like `der_next`, failing unless the element is tagged `tag`
+fn der_expect(data: &[u8], tag: u8) -> std::result::Result<(&[u8], &[u8]), String> {
+    match der_next(data)? {
+        (found, contents, rest) if found == tag => Ok((contents, rest)),
+        (found, _, _) => Err(format!("expected DER tag {tag:#04x}, found {found:#04x}")),
+    }
+}
+
+fn parse_time(tag: u8, time: &[u8]) -> std::result::Result<DateTime<Utc>, String> {
+    let time = std::str::from_utf8(time).map_err(|e| format!("invalid time: {e}"))?;
+    let time = match tag {
+        // two digit years from 50 are in the 1900s, see RFC 5280 4.1.2.5.1
+        UTC_TIME if time.get(..2).is_some_and(|yy| yy >= "50") => format!("19{time}"),
+        UTC_TIME => format!("20{time}"),
+        GENERALIZED_TIME => time.to_string(),
+        _ => return Err(format!("expected a time, found DER tag {tag:#04x}")),
+    };
+    let time = NaiveDateTime::parse_from_str(&time, "%Y%m%d%H%M%SZ")
+        .map_err(|e| format!("invalid time {time:?}: {e}"))?;
+    Ok(DateTime::from_utc(time, Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::{validate_tls, validate_tls_at, CertInfo};
+    use crate::server::{load_certs, load_keys};
+
+    #[test]
+    fn test_valid() {
+        let certs = load_certs("certs/defaults/cert.pem").unwrap();
+        let keys = load_keys("certs/defaults/key.pem").unwrap();
+        validate_tls(&certs, &keys).unwrap();
+
+        let info = CertInfo::parse(&certs[0].0).unwrap();
+        assert_eq!(Utc.ymd(2022, 4, 20).and_hms(0, 3, 12), info.not_before);
+        assert_eq!(Utc.ymd(2032, 4, 17).and_hms(0, 3, 12), info.not_after);
+
+        // the same pair outside of its validity period
+        let err = validate_tls_at(&certs, &keys, Utc.ymd(2022, 1, 1).and_hms(0, 0, 0))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            "tls config error: certificate 0 is not valid until 2022-04-20 00:03:12 UTC",
+            err
+        );
+        assert!(validate_tls_at(&certs, &keys, Utc.ymd(2033, 1, 1).and_hms(0, 0, 0)).is_err());
+    }
+
+    #[test]
+    fn test_mismatched_key() {
+        let certs = load_certs("certs/defaults/cert.pem").unwrap();
+        let keys = load_keys("certs/test/other-key.pem").unwrap();
+        let err = validate_tls(&certs, &keys).unwrap_err().to_string();
+        assert_eq!(
+            "tls config error: the private key doesn't match the certificate",
+            err
+        );
+    }
+
+    #[test]
+    fn test_expired() {
+        let certs = load_certs("certs/test/expired-cert.pem").unwrap();
+        let keys = load_keys("certs/test/other-key.pem").unwrap();
+        let err = validate_tls(&certs, &keys).unwrap_err().to_string();
+        assert_eq!(
+            "tls config error: certificate 0 expired at 2021-01-01 00:00:00 UTC",
+            err
+        );
+    }
+
+    #[test]
+    fn test_missing() {
+        let certs = load_certs("certs/defaults/cert.pem").unwrap();
+        let keys = load_keys("certs/defaults/key.pem").unwrap();
+        let err = validate_tls(&[], &keys).unwrap_err().to_string();
+        assert_eq!("tls config error: no certificates found", err);
+        let err = validate_tls(&certs, &[]).unwrap_err().to_string();
+        assert_eq!("tls config error: no private keys found", err);
+    }
+}
@@ -0,0 +1,303 @@
+//! Selecting the store a server runs on from its config, so operators pick a
+//! backend by name (`STORE_BACKEND`) instead of by the server's type parameter
+use std::path::PathBuf;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::io::AsyncWrite;
+use tokio::sync::{mpsc, oneshot};
+
+use super::compression::{Codec, CompressedStore};
+#[cfg(feature = "index")]
+use super::index::{Extractor, IndexedStore};
+use super::lsm::LSMStore;
+use super::replication::{ReplicatedStore, ReplicationLog};
+use super::transform::Transform;
+use super::{MemoryStore, Store, Transaction};
+use crate::config::StoreKind;
+use crate::{Config, Result};
+
+type ShutdownReceiver = mpsc::UnboundedReceiver<oneshot::Sender<bool>>;
+
+// How often a memory store reclaims the keys that expired since it last did
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A store backend along with the options it's built with
+#[derive(Clone, Debug)]
+pub enum StoreBackend {
+    /// Nothing is persisted, everything is lost on shutdown. With `max_bytes`, the
+    /// least recently used keys are evicted to keep the store within it
+    Memory { max_bytes: Option<usize> },
+    /// An `LSMStore` keeping its data files in `path`, configured by `config`
+    Lsm { path: PathBuf, config: Box<Config> },
+}
+
+impl StoreBackend {
+    /// The backend `config.store_backend` names, with LSM data kept in `config.data_dir`
+    pub fn from_config(config: &Config) -> Self {
+        match config.store_backend {
+            StoreKind::Memory => StoreBackend::Memory {
+                max_bytes: config.memory_max_bytes,
+            },
+            StoreKind::Lsm => StoreBackend::Lsm {
+                path: config.data_dir.clone(),
+                config: Box::new(config.clone()),
+            },
+        }
+    }
+
+    /// Build and initialize the backend's store. Every request received on
+    /// `shutdown_receiver` shuts the store down, answering once it's done.
+    pub async fn build(self, shutdown_receiver: ShutdownReceiver) -> Result<BackendStore> {
+        match self {
+            StoreBackend::Memory { max_bytes } => {
+                // there's nothing to flush, so shutdowns are acknowledged straight away
+                let mut shutdown_receiver = shutdown_receiver;
+                tokio::spawn(async move {
+                    while let Some(done) = shutdown_receiver.recv().await {
+                        let _ = done.send(true);
+                    }
+                });
+                let store = match max_bytes {
+                    Some(max_bytes) => MemoryStore::with_capacity(max_bytes),
+                    None => MemoryStore::new(),
+                };
+                store.reap_every(REAP_INTERVAL);
+                Ok(BackendStore::Memory(store))
+            }
+            StoreBackend::Lsm { path, config } => {
+                tracing::info!("using lsm store in {path:?}");
+                let config = Config {
+                    data_dir: path,
+                    ..*config
+                };
+                let store = LSMStore::initialize_from_config(&config, shutdown_receiver).await?;
+                Ok(BackendStore::Lsm(Box::new(store)))
+            }
+        }
+    }
+}
+
+/// The store built by a `StoreBackend`
+#[derive(Clone)]
+pub enum BackendStore {
+    Memory(MemoryStore),
+    // boxed, it's much bigger than a `MemoryStore`
+    Lsm(Box<LSMStore>),
+    // another backend with a secondary index, see `BackendStore::indexed`
+    #[cfg(feature = "index")]
+    Indexed(Box<IndexedStore<BackendStore>>),
+    // another backend publishing its writes to followers, see `BackendStore::replicated`
+    Replicated(Box<ReplicatedStore<BackendStore>>),
+    // another backend storing large values compressed, see `BackendStore::compressed`
+    Compressed(Box<CompressedStore<BackendStore>>),
+}
+impl BackendStore {
+    /// Index the store's values by the attribute `extract` finds in them, including the
+    /// ones it already holds
+    #[cfg(feature = "index")]
+    pub async fn indexed(self, extract: Extractor) -> Result<Self> {
+        let store = IndexedStore::new(self, extract).await?;
+        Ok(BackendStore::Indexed(Box::new(store)))
+    }
+
+    /// Publish the store's writes to `log`, for the cluster server to replicate
+    pub fn replicated(self, log: ReplicationLog) -> Self {
+        BackendStore::Replicated(Box::new(ReplicatedStore::new(self, log)))
+    }
+
+    /// Compress the store's values of at least `threshold` bytes with `codec`. Must wrap
+    /// an empty store, and be wrapped by any index or replication
+    pub fn compressed<C: Codec + 'static>(self, codec: C, threshold: usize) -> Self {
+        BackendStore::Compressed(Box::new(CompressedStore::new(self, codec, threshold)))
+    }
+}
+
+#[async_trait]
+impl Store for BackendStore {
+    async fn get(&mut self, k: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self {
+            BackendStore::Memory(store) => store.get(k).await,
+            BackendStore::Lsm(store) => store.get(k).await,
+            #[cfg(feature = "index")]
+            BackendStore::Indexed(store) => store.get(k).await,
+            BackendStore::Replicated(store) => store.get(k).await,
+            BackendStore::Compressed(store) => store.get(k).await,
+        }
+    }
+
+    async fn exists(&mut self, k: &[u8]) -> Result<bool> {
+        match self {
+            BackendStore::Memory(store) => store.exists(k).await,
+            BackendStore::Lsm(store) => store.exists(k).await,
+            #[cfg(feature = "index")]
+            BackendStore::Indexed(store) => store.exists(k).await,
+            BackendStore::Replicated(store) => store.exists(k).await,
+            BackendStore::Compressed(store) => store.exists(k).await,
+        }
+    }
+
+    async fn get_many(&mut self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        match self {
+            BackendStore::Memory(store) => store.get_many(keys).await,
+            BackendStore::Lsm(store) => store.get_many(keys).await,
+            #[cfg(feature = "index")]
+            BackendStore::Indexed(store) => store.get_many(keys).await,
+            BackendStore::Replicated(store) => store.get_many(keys).await,
+            BackendStore::Compressed(store) => store.get_many(keys).await,
+        }
+    }
+
+    async fn value_len(&mut self, k: &[u8]) -> Result<Option<usize>> {
+        match self {
+            BackendStore::Memory(store) => store.value_len(k).await,
+            BackendStore::Lsm(store) => store.value_len(k).await,
+            #[cfg(feature = "index")]
+            BackendStore::Indexed(store) => store.value_len(k).await,
+            BackendStore::Replicated(store) => store.value_len(k).await,
+            BackendStore::Compressed(store) => store.value_len(k).await,
+        }
+    }
+
+    async fn scan(&mut self, from_inclusive: &[u8], to_exclusive: &[u8]) -> Result<Vec<Vec<u8>>> {
+        match self {
+            BackendStore::Memory(store) => store.scan(from_inclusive, to_exclusive).await,
+            BackendStore::Lsm(store) => store.scan(from_inclusive, to_exclusive).await,
+            #[cfg(feature = "index")]
+            BackendStore::Indexed(store) => store.scan(from_inclusive, to_exclusive).await,
+            BackendStore::Replicated(store) => store.scan(from_inclusive, to_exclusive).await,
+            BackendStore::Compressed(store) => store.scan(from_inclusive, to_exclusive).await,
+        }
+    }
+
+    async fn scan_entries(
+        &mut self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        match self {
+            BackendStore::Memory(store) => store.scan_entries(start, end, limit).await,
+            BackendStore::Lsm(store) => store.scan_entries(start, end, limit).await,
+            #[cfg(feature = "index")]
+            BackendStore::Indexed(store) => store.scan_entries(start, end, limit).await,
+            BackendStore::Replicated(store) => store.scan_entries(start, end, limit).await,
+            BackendStore::Compressed(store) => store.scan_entries(start, end, limit).await,
+        }
+    }
+
+    async fn scan_keys(
+        &mut self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>> {
+        match self {
+            BackendStore::Memory(store) => store.scan_keys(start, end, limit).await,
+            BackendStore::Lsm(store) => store.scan_keys(start, end, limit).await,
+            #[cfg(feature = "index")]
+            BackendStore::Indexed(store) => store.scan_keys(start, end, limit).await,
+            BackendStore::Replicated(store) => store.scan_keys(start, end, limit).await,
+            BackendStore::Compressed(store) => store.scan_keys(start, end, limit).await,
+        }
+    }
+
+    async fn snapshot(&mut self, writer: &mut (dyn AsyncWrite + Send + Unpin)) -> Result<usize> {
+        match self {
+            BackendStore::Memory(store) => store.snapshot(writer).await,
+            BackendStore::Lsm(store) => store.snapshot(writer).await,
+            #[cfg(feature = "index")]
+            BackendStore::Indexed(store) => store.snapshot(writer).await,
+            BackendStore::Replicated(store) => store.snapshot(writer).await,
+            BackendStore::Compressed(store) => store.snapshot(writer).await,
+        }
+    }
+
+    async fn clear(&mut self) -> Result<()> {
+        match self {
+            BackendStore::Memory(store) => store.clear().await,
+            BackendStore::Lsm(store) => store.clear().await,
+            #[cfg(feature = "index")]
+            BackendStore::Indexed(store) => store.clear().await,
+            BackendStore::Replicated(store) => store.clear().await,
+            BackendStore::Compressed(store) => store.clear().await,
+        }
+    }
+
+    async fn expire(&mut self, k: &[u8], ttl: Duration) -> Result<Option<bool>> {
+        match self {
+            BackendStore::Memory(store) => store.expire(k, ttl).await,
+            BackendStore::Lsm(store) => store.expire(k, ttl).await,
+            #[cfg(feature = "index")]
+            BackendStore::Indexed(store) => store.expire(k, ttl).await,
+            BackendStore::Replicated(store) => store.expire(k, ttl).await,
+            BackendStore::Compressed(store) => store.expire(k, ttl).await,
+        }
+    }
+
+    async fn transact(&mut self, transaction: Transaction) -> Result<Vec<bool>> {
+        match self {
+            BackendStore::Memory(store) => store.transact(transaction).await,
+            BackendStore::Lsm(store) => store.transact(transaction).await,
+            #[cfg(feature = "index")]
+            BackendStore::Indexed(store) => store.transact(transaction).await,
+            BackendStore::Replicated(store) => store.transact(transaction).await,
+            BackendStore::Compressed(store) => store.transact(transaction).await,
+        }
+    }
+
+    async fn swap(&mut self, a: &[u8], b: &[u8]) -> Result<()> {
+        match self {
+            BackendStore::Memory(store) => store.swap(a, b).await,
+            BackendStore::Lsm(store) => store.swap(a, b).await,
+            #[cfg(feature = "index")]
+            BackendStore::Indexed(store) => store.swap(a, b).await,
+            BackendStore::Replicated(store) => store.swap(a, b).await,
+            BackendStore::Compressed(store) => store.swap(a, b).await,
+        }
+    }
+
+    async fn apply(&mut self, k: &[u8], transform: &Transform) -> Result<Vec<u8>> {
+        match self {
+            BackendStore::Memory(store) => store.apply(k, transform).await,
+            BackendStore::Lsm(store) => store.apply(k, transform).await,
+            #[cfg(feature = "index")]
+            BackendStore::Indexed(store) => store.apply(k, transform).await,
+            BackendStore::Replicated(store) => store.apply(k, transform).await,
+            BackendStore::Compressed(store) => store.apply(k, transform).await,
+        }
+    }
+
+    async fn get_set(&mut self, k: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self {
+            BackendStore::Memory(store) => store.get_set(k, value).await,
+            BackendStore::Lsm(store) => store.get_set(k, value).await,
+            #[cfg(feature = "index")]
+            BackendStore::Indexed(store) => store.get_set(k, value).await,
+            BackendStore::Replicated(store) => store.get_set(k, value).await,
+            BackendStore::Compressed(store) => store.get_set(k, value).await,
+        }
+    }
+
+    async fn find(&mut self, attr: &[u8]) -> Result<Option<Vec<Vec<u8>>>> {
+        match self {
+            BackendStore::Memory(store) => store.find(attr).await,
+            BackendStore::Lsm(store) => store.find(attr).await,
+            #[cfg(feature = "index")]
+            BackendStore::Indexed(store) => store.find(attr).await,
+            BackendStore::Replicated(store) => store.find(attr).await,
+            BackendStore::Compressed(store) => store.find(attr).await,
+        }
+    }
+
+    async fn set_range(&mut self, k: &[u8], offset: usize, bytes: &[u8]) -> Result<usize> {
+        match self {
+            BackendStore::Memory(store) => store.set_range(k, offset, bytes).await,
+            BackendStore::Lsm(store) => store.set_range(k, offset, bytes).await,
+            #[cfg(feature = "index")]
+            BackendStore::Indexed(store) => store.set_range(k, offset, bytes).await,
+            BackendStore::Replicated(store) => store.set_range(k, offset, bytes).await,
+            BackendStore::Compressed(store) => store.set_range(k, offset, bytes).await,
+        }
+    }
+}
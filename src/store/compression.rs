@@ -0,0 +1,420 @@
+//! Compressing large values before they're stored, and decompressing them on reads
+//!
+//! `CompressedStore` wraps any store and compresses each value of at least its
+//! threshold with a `Codec`, keeping it as is when compressing doesn't make it smaller.
+//! Every stored value is led by a one-byte header, `RAW` or the id of the codec it was
+//! compressed with, so compressed and uncompressed values live side by side. Reads,
+//! scans and versions all see the values as they were written. It isn't free:
+//! - values written to the store before it was wrapped have no header, so it must
+//!   start out empty, and stay wrapped with the same codec. `check_format` records
+//!   how a data dir's values are stored, refusing to open it any other way
+//! - writes through the same `CompressedStore` are serialized, so transforms can
+//!   decompress, transform and compress a value without another write interleaving
+//! - a secondary index or replication log must wrap the compressed store, not be
+//!   wrapped by it, to see the values uncompressed
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::sync::Mutex;
+
+use super::transform::Transform;
+use super::{Operation, Store, Transaction};
+use crate::Result;
+
+/// Header of values stored as they were written
+pub const RAW: u8 = 0;
+
+/// The file in a data dir recording how its values are stored, see `check_format`
+pub const FORMAT_FILE: &str = "value_format";
+
+/// Make sure the values in `data_dir` are stored the way they'll be read, compressed
+/// with `codec` or as they are without one, and record it for the next start when it
+/// isn't yet. A data dir without the record that isn't `empty` was written before
+/// values were ever compressed, so it's taken to hold them as they are.
+pub async fn check_format(data_dir: &Path, codec: Option<&dyn Codec>, empty: bool) -> Result<()> {
+    let wanted = codec.map_or("raw", |codec| codec.name());
+    let path = data_dir.join(FORMAT_FILE);
+    let found = match tokio::fs::read_to_string(&path).await {
+        Ok(found) => found.trim().to_string(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && empty => {
+            tokio::fs::write(&path, wanted).await?;
+            return Ok(());
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => "raw".to_string(),
+        Err(e) => return Err(e.into()),
+    };
+    if found != wanted {
+        return Err(format!(
+            "values in {} are stored {}, but would be read {}. Compression can't be turned \
+             on, off or changed over an existing data dir",
+            data_dir.display(),
+            describe(&found),
+            describe(wanted)
+        )
+        .into());
+    }
+    if !path.exists() {
+        tokio::fs::write(&path, wanted).await?;
+    }
+    Ok(())
+}
+
+fn describe(format: &str) -> String {
+    match format {
+        "raw" => "uncompressed".to_string(),
+        codec => format!("compressed with {codec}"),
+    }
+}
+
+/// A compression algorithm values can be stored with
+pub trait Codec: Send + Sync {
+    /// The header byte of values compressed with the codec, anything but `RAW`. It's
+    /// stored with every value, so it mustn't change once values were written with it
+    fn id(&self) -> u8;
+    fn name(&self) -> &'static str;
+    fn compress(&self, value: &[u8]) -> Vec<u8>;
+    fn decompress(&self, compressed: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// LZ4 block compression, fast enough to sit in the path of every write
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Lz4;
+impl Codec for Lz4 {
+    fn id(&self) -> u8 {
+        1
+    }
+
+    fn name(&self) -> &'static str {
+        "lz4"
+    }
+
+    fn compress(&self, value: &[u8]) -> Vec<u8> {
+        lz4_flex::compress_prepend_size(value)
+    }
+
+    fn decompress(&self, compressed: &[u8]) -> Result<Vec<u8>> {
+        lz4_flex::decompress_size_prepended(compressed)
+            .map_err(|e| format!("error decompressing lz4 value: {e}").into())
+    }
+}
+
+/// A store compressing values of at least `threshold` bytes with `codec`, see the
+/// module docs for what it costs
+#[derive(Clone)]
+pub struct CompressedStore<S> {
+    inner: S,
+    codec: Arc<dyn Codec>,
+    threshold: usize,
+    // held while a write is applied
+    writing: Arc<Mutex<()>>,
+}
+impl<S> CompressedStore<S> {
+    pub fn new<C: Codec + 'static>(inner: S, codec: C, threshold: usize) -> Self {
+        assert_ne!(RAW, codec.id(), "a codec's id can't be the RAW header");
+        Self {
+            inner,
+            codec: Arc::new(codec),
+            threshold,
+            writing: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// The wrapped store, holding values as they're stored, headers included
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// The header and bytes `value` is stored as
+    fn encode(&self, value: &[u8]) -> Vec<u8> {
+        if value.len() >= self.threshold {
+            let compressed = self.codec.compress(value);
+            if compressed.len() < value.len() {
+                return [&[self.codec.id()], compressed.as_slice()].concat();
+            }
+        }
+        [&[RAW], value].concat()
+    }
+
+    /// The value `stored` was written as
+    fn decode(&self, stored: Vec<u8>) -> Result<Vec<u8>> {
+        match stored.split_first() {
+            Some((&RAW, _)) => Ok(stored[1..].to_vec()),
+            Some((&id, compressed)) if id == self.codec.id() => self.codec.decompress(compressed),
+            Some((id, _)) => Err(format!(
+                "value compressed with an unknown codec {id}, expected {}",
+                self.codec.name()
+            )
+            .into()),
+            None => Err("stored value is missing its compression header".into()),
+        }
+    }
+
+    fn decode_option(&self, stored: Option<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        stored.map(|stored| self.decode(stored)).transpose()
+    }
+}
+impl<S> fmt::Debug for CompressedStore<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CompressedStore")
+            .field("codec", &self.codec.name())
+            .field("threshold", &self.threshold)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl<S: Store + Send + Sync> Store for CompressedStore<S> {
+    async fn get(&mut self, k: &[u8]) -> Result<Option<Vec<u8>>> {
+        let stored = self.inner.get(k).await?;
+        self.decode_option(stored)
+    }
+
+    async fn exists(&mut self, k: &[u8]) -> Result<bool> {
+        self.inner.exists(k).await
+    }
+
+    async fn get_many(&mut self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        let stored = self.inner.get_many(keys).await?;
+        stored
+            .into_iter()
+            .map(|stored| self.decode_option(stored))
+            .collect()
+    }
+
+    async fn scan(&mut self, from_inclusive: &[u8], to_exclusive: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let stored = self.inner.scan(from_inclusive, to_exclusive).await?;
+        stored
+            .into_iter()
+            .map(|stored| self.decode(stored))
+            .collect()
+    }
+
+    async fn scan_entries(
+        &mut self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let stored = self.inner.scan_entries(start, end, limit).await?;
+        stored
+            .into_iter()
+            .map(|(k, stored)| Ok((k, self.decode(stored)?)))
+            .collect()
+    }
+
+    async fn scan_keys(
+        &mut self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>> {
+        self.inner.scan_keys(start, end, limit).await
+    }
+
+    async fn clear(&mut self) -> Result<()> {
+        self.inner.clear().await
+    }
+
+    async fn expire(&mut self, k: &[u8], ttl: Duration) -> Result<Option<bool>> {
+        self.inner.expire(k, ttl).await
+    }
+
+    async fn transact(&mut self, transaction: Transaction) -> Result<Vec<bool>> {
+        let operations = transaction
+            .operations
+            .into_iter()
+            .map(|op| match op {
+                Operation::Set(k, value) => Operation::Set(k, self.encode(&value)),
+                op => op,
+            })
+            .collect();
+        let _writing = self.writing.lock().await;
+        self.inner
+            .transact(Transaction {
+                operations,
+                ..transaction
+            })
+            .await
+    }
+
+    async fn swap(&mut self, a: &[u8], b: &[u8]) -> Result<()> {
+        // stored values move along with their headers
+        let _writing = self.writing.lock().await;
+        self.inner.swap(a, b).await
+    }
+
+    async fn apply(&mut self, k: &[u8], transform: &Transform) -> Result<Vec<u8>> {
+        let _writing = self.writing.lock().await;
+        let stored = self.inner.get_versioned(k).await?;
+        let (stored, version) = match stored {
+            Some((stored, version)) => (Some(stored), version),
+            None => (None, String::new()),
+        };
+        let value = transform.apply(self.decode_option(stored)?.as_deref())?;
+        // a versioned write is itself a transform, so it keeps the key's TTL where a
+        // SET would clear it. No other write went through since the read, so it lands
+        let written = self
+            .inner
+            .set_if_version(k, &version, &self.encode(&value))
+            .await?;
+        if !written {
+            return Err(format!(
+                "{} was written while it was transformed",
+                String::from_utf8_lossy(k)
+            )
+            .into());
+        }
+        Ok(value)
+    }
+
+    async fn get_set(&mut self, k: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        let _writing = self.writing.lock().await;
+        let old = self.inner.get_set(k, &self.encode(value)).await?;
+        self.decode_option(old)
+    }
+
+    async fn find(&mut self, attr: &[u8]) -> Result<Option<Vec<Vec<u8>>>> {
+        self.inner.find(attr).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{check_format, Codec, CompressedStore, Lz4, FORMAT_FILE, RAW};
+    use crate::store::expiry::ManualClock;
+    use crate::store::transform::Transform;
+    use crate::store::{conformance, version, MemoryStore, Operation, Store, Transaction};
+    use crate::Result;
+
+    #[tokio::test]
+    async fn test_compressed_values() -> Result<()> {
+        let mut store = CompressedStore::new(MemoryStore::new(), Lz4, 64);
+        let json = r#"{"name":"ada","tags":["a","b","c"]}"#.repeat(1000).into_bytes();
+        let small = br#"{"name":"cy"}"#.to_vec();
+        store
+            .transact(Transaction::with_random_id(vec![
+                Operation::set("json", &json),
+                Operation::set("small", &small),
+                Operation::set("empty", b""),
+            ]))
+            .await?;
+
+        // large values are stored compressed and smaller, others as they are
+        let stored = store.inner().clone().get(b"json").await?.unwrap();
+        assert_eq!(Lz4.id(), stored[0]);
+        assert!(stored.len() < json.len() / 10, "{}", stored.len());
+        let stored = store.inner().clone().get(b"small").await?.unwrap();
+        assert_eq!([&[RAW], small.as_slice()].concat(), stored);
+
+        // and read back identical, whatever they were stored as
+        assert_eq!(Some(json.clone()), store.get(b"json").await?);
+        assert_eq!(Some(small.clone()), store.get(b"small").await?);
+        assert_eq!(Some(vec![]), store.get(b"empty").await?);
+        assert_eq!(Some(json.len()), store.value_len(b"json").await?);
+        assert_eq!(
+            Some((json.clone(), version(&json))),
+            store.get_versioned(b"json").await?
+        );
+        assert_eq!(
+            vec![Some(json.clone()), None, Some(small.clone())],
+            store
+                .get_many(&[&b"json"[..], b"missing", b"small"])
+                .await?
+        );
+        assert_eq!(
+            vec![(b"json".to_vec(), json.clone())],
+            store.scan_entries(b"j", None, 1).await?
+        );
+
+        // transforms see the value uncompressed, and a value crossing the threshold
+        // is compressed from then on
+        let appended = store
+            .apply(b"small", &Transform::Append(small.repeat(10)))
+            .await?;
+        assert_eq!(small.repeat(11), appended);
+        assert_eq!(
+            Lz4.id(),
+            store.inner().clone().get(b"small").await?.unwrap()[0]
+        );
+        assert_eq!(Some(appended), store.get(b"small").await?);
+        assert!(store.apply(b"json", &Transform::Add(1)).await.is_err());
+        assert_eq!(Some(json.clone()), store.get(b"json").await?);
+        store.swap(b"json", b"moved").await?;
+        assert_eq!(Some(json), store.get(b"moved").await?);
+
+        // values with an unknown header fail to read rather than read as garbage
+        let mut inner = store.inner().clone();
+        inner
+            .transact(Transaction::with_random_id(vec![Operation::set(
+                "foreign", b"\x07abc",
+            )]))
+            .await?;
+        assert!(store.get(b"foreign").await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compressed_transforms_keep_ttl() -> Result<()> {
+        let clock = ManualClock::default();
+        let inner = MemoryStore::new().with_clock(clock.clone());
+        let mut store = CompressedStore::new(inner, Lz4, 0);
+        store
+            .transact(Transaction::with_random_id(vec![Operation::set("a", b"1")]))
+            .await?;
+        assert_eq!(
+            Some(true),
+            store.expire(b"a", Duration::from_secs(10)).await?
+        );
+        assert_eq!(b"2".to_vec(), store.apply(b"a", &Transform::Add(1)).await?);
+        assert_eq!(2, store.set_range(b"a", 1, b"0").await?);
+        assert_eq!(Some(b"20".to_vec()), store.get(b"a").await?);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(None, store.get(b"a").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_check_format() -> Result<()> {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        tokio::fs::create_dir(&dir).await?;
+
+        // an empty data dir takes whichever format it's first opened with
+        check_format(&dir, Some(&Lz4), true).await?;
+        check_format(&dir, Some(&Lz4), false).await?;
+        let res = check_format(&dir, None, false).await;
+        assert!(res.is_err(), "{res:?}");
+        let res = check_format(&dir, None, true).await;
+        assert!(res.is_err(), "{res:?}");
+
+        // and one holding data from before the format was recorded holds it as is
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        tokio::fs::create_dir(&dir).await?;
+        let res = check_format(&dir, Some(&Lz4), false).await;
+        assert!(res.is_err(), "{res:?}");
+        check_format(&dir, None, false).await?;
+        assert_eq!(
+            "raw",
+            tokio::fs::read_to_string(dir.join(FORMAT_FILE)).await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compressed_conformance() -> Result<()> {
+        let mut store = CompressedStore::new(MemoryStore::new(), Lz4, 0);
+        for transaction in conformance::lifecycle_batches() {
+            store.transact(transaction).await?;
+        }
+        conformance::assert_entry_semantics(&mut store).await?;
+        let mut store = CompressedStore::new(MemoryStore::new(), Lz4, 0);
+        for transaction in conformance::unordered_batches() {
+            store.transact(transaction).await?;
+        }
+        conformance::assert_scans_sorted(&mut store).await
+    }
+}
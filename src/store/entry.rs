@@ -0,0 +1,75 @@
+//! Entries stored under a key, and what they mean to reads and writes
+//!
+//! Backends that keep deletions around, like the LSM's tombstones shadowing older
+//! SSTables, store a `Value` per key and go through its helpers to decide whether the
+//! key exists, what a read returns and what version it's at. That way every backend
+//! agrees with the others, which `conformance::assert_entry_semantics` checks.
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Data(Vec<u8>),
+    /// The key was deleted, shadowing any older entry for it
+    Tombstone,
+}
+
+impl Value {
+    /// Whether the key holds a value, a tombstoned key doesn't exist
+    pub fn exists(&self) -> bool {
+        matches!(self, Value::Data(_))
+    }
+
+    /// What a read of the key returns
+    pub fn as_option(&self) -> Option<Vec<u8>> {
+        match self {
+            Value::Data(data) => Some(data.to_vec()),
+            Value::Tombstone => None,
+        }
+    }
+
+    pub fn into_option(self) -> Option<Vec<u8>> {
+        match self {
+            Value::Data(data) => Some(data),
+            Value::Tombstone => None,
+        }
+    }
+
+    /// The entry's version, see `store::version`. A tombstone has none, like an absent key.
+    pub fn version(&self) -> Option<String> {
+        match self {
+            Value::Data(data) => Some(super::version(data)),
+            Value::Tombstone => None,
+        }
+    }
+}
+
+/// Whether the key existed given the `previous` entry a write replaced, if any
+pub fn existed(previous: Option<&Value>) -> bool {
+    previous.is_some_and(Value::exists)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{existed, Value};
+
+    #[test]
+    fn test_tombstone_is_absent() {
+        let data = Value::Data(b"value".to_vec());
+        assert!(data.exists());
+        assert_eq!(Some(b"value".to_vec()), data.as_option());
+        assert_eq!(Some(crate::store::version(b"value")), data.version());
+        assert!(existed(Some(&data)));
+
+        let tombstone = Value::Tombstone;
+        assert!(!tombstone.exists());
+        assert_eq!(None, tombstone.as_option());
+        assert_eq!(None, tombstone.version());
+        assert!(!existed(Some(&tombstone)));
+        assert!(!existed(None));
+
+        // an empty value still exists, and has a version
+        let empty = Value::Data(vec![]);
+        assert!(empty.exists());
+        assert_eq!(Some(vec![]), empty.into_option());
+    }
+}
@@ -0,0 +1,122 @@
+//! Policies choosing which keys a `MemoryStore` with a capacity evicts to make room,
+//! see `MemoryStore::with_capacity`
+use std::collections::{BTreeMap, HashMap};
+
+/// Tracks the keys of a store to choose which to evict. The store tells the policy
+/// about every key it writes or reads, and every key it removes, and asks it for
+/// victims until its keys and values fit its capacity again.
+pub trait Eviction: Send {
+    /// `key` was written or read
+    fn touch(&mut self, key: &[u8]);
+    /// `key` was deleted, or moved away by a SWAP
+    fn remove(&mut self, key: &[u8]);
+    /// The next key to evict, which the policy forgets. `None` when it tracks no keys
+    fn victim(&mut self) -> Option<Vec<u8>>;
+}
+
+/// Evicts the least recently used key first
+#[derive(Debug, Default)]
+pub struct Lru {
+    // the tick each key was last used at, and the keys by those ticks
+    ticks: HashMap<Vec<u8>, u64>,
+    keys: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+}
+impl Eviction for Lru {
+    fn touch(&mut self, key: &[u8]) {
+        self.tick += 1;
+        if let Some(tick) = self.ticks.insert(key.to_vec(), self.tick) {
+            self.keys.remove(&tick);
+        }
+        self.keys.insert(self.tick, key.to_vec());
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(tick) = self.ticks.remove(key) {
+            self.keys.remove(&tick);
+        }
+    }
+
+    fn victim(&mut self) -> Option<Vec<u8>> {
+        let (_, key) = self.keys.pop_first()?;
+        self.ticks.remove(&key);
+        Some(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Eviction, Lru};
+    use crate::store::{MemoryStore, Operation, Store, Transaction};
+    use crate::Result;
+
+    fn set(k: &str, v: &[u8]) -> Transaction {
+        Transaction::with_random_id(vec![Operation::set(k, v)])
+    }
+
+    #[test]
+    fn test_lru() {
+        let mut lru = Lru::default();
+        for key in ["a", "b", "c", "d"] {
+            lru.touch(key.as_bytes());
+        }
+        lru.touch(b"a");
+        lru.remove(b"c");
+        assert_eq!(Some(b"b".to_vec()), lru.victim());
+        assert_eq!(Some(b"d".to_vec()), lru.victim());
+        assert_eq!(Some(b"a".to_vec()), lru.victim());
+        assert_eq!(None, lru.victim());
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_capacity() -> Result<()> {
+        // room for 4 keys of 1 byte with 9 byte values
+        let mut store = MemoryStore::with_capacity(40);
+        for key in ["a", "b", "c", "d"] {
+            store.transact(set(key, b"012345678")).await?;
+        }
+        assert_eq!(40, store.bytes().await);
+
+        // reads count as uses, so the oldest key left untouched is evicted first
+        store.get(b"a").await?;
+        store.get_many(&[b"b"]).await?;
+        store.transact(set("e", b"012345678")).await?;
+        assert_eq!(None, store.get(b"c").await?);
+        for key in ["a", "b", "d", "e"] {
+            assert!(
+                store.get(key.as_bytes()).await?.is_some(),
+                "{key} was evicted"
+            );
+        }
+
+        // as many keys are evicted as it takes to fit a bigger value
+        store.transact(set("f", b"0123456789012345678")).await?;
+        assert_eq!(None, store.get(b"a").await?);
+        assert_eq!(None, store.get(b"b").await?);
+        for key in ["d", "e", "f"] {
+            assert!(
+                store.get(key.as_bytes()).await?.is_some(),
+                "{key} was evicted"
+            );
+        }
+        assert_eq!(40, store.bytes().await);
+
+        // deletes free their room without evicting anything
+        store
+            .transact(Transaction::with_random_id(vec![Operation::delete("d")]))
+            .await?;
+        store.transact(set("g", b"012345678")).await?;
+        for key in ["e", "f", "g"] {
+            assert!(
+                store.get(key.as_bytes()).await?.is_some(),
+                "{key} was evicted"
+            );
+        }
+
+        // and writes that could never fit are refused, leaving the store as it was
+        assert!(store.transact(set("h", &[0; 40])).await.is_err());
+        assert_eq!(None, store.get(b"h").await?);
+        assert_eq!(40, store.bytes().await);
+        Ok(())
+    }
+}
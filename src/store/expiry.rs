@@ -0,0 +1,120 @@
+//! Keys that expire once their time to live has passed, for stores used as caches
+//!
+//! Stores keep the deadline of each key given a TTL in `Expiries`, reading the time
+//! from a `Clock` so tests can move it forward instead of sleeping. An expired key reads
+//! as absent straight away, but its memory is only reclaimed once the store reaps it.
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Where a store reads the current time from
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The system's wall clock
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when it's advanced, for testing expiry without sleeping.
+/// Clones share the same time.
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    now: Arc<Mutex<SystemTime>>,
+}
+impl ManualClock {
+    pub fn new(now: SystemTime) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().expect("clock lock poisoned") += by;
+    }
+}
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new(SystemTime::UNIX_EPOCH)
+    }
+}
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().expect("clock lock poisoned")
+    }
+}
+
+/// The deadlines of the keys with a TTL
+#[derive(Default)]
+pub(crate) struct Expiries {
+    deadlines: HashMap<Vec<u8>, SystemTime>,
+    // the same deadlines ordered by when they pass, to find the expired keys
+    queue: BTreeSet<(SystemTime, Vec<u8>)>,
+}
+impl Expiries {
+    /// Expire `key` at `at`, replacing any deadline it had
+    pub fn set(&mut self, key: &[u8], at: SystemTime) {
+        self.clear(key);
+        self.deadlines.insert(key.to_vec(), at);
+        self.queue.insert((at, key.to_vec()));
+    }
+
+    /// Drop the deadline of `key`, returning it if it had one
+    pub fn clear(&mut self, key: &[u8]) -> Option<SystemTime> {
+        let at = self.deadlines.remove(key)?;
+        self.queue.remove(&(at, key.to_vec()));
+        Some(at)
+    }
+
+    /// Remove the keys whose deadline is at or before `now`, returning them
+    pub fn expired(&mut self, now: SystemTime) -> Vec<Vec<u8>> {
+        let mut keys = vec![];
+        while let Some((at, _)) = self.queue.first() {
+            if *at > now {
+                break;
+            }
+            if let Some((_, key)) = self.queue.pop_first() {
+                self.deadlines.remove(&key);
+                keys.push(key);
+            }
+        }
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Clock, Expiries, ManualClock};
+
+    #[test]
+    fn test_expired_in_deadline_order() {
+        let clock = ManualClock::default();
+        let at = |secs| clock.now() + Duration::from_secs(secs);
+        let mut expiries = Expiries::default();
+        expiries.set(b"c", at(3));
+        expiries.set(b"a", at(1));
+        expiries.set(b"b", at(5));
+        // a new deadline replaces the old one
+        expiries.set(b"b", at(2));
+        expiries.set(b"d", at(4));
+        assert_eq!(Some(at(4)), expiries.clear(b"d"));
+        assert_eq!(None, expiries.clear(b"d"));
+
+        assert!(expiries.expired(clock.now()).is_empty());
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(
+            vec![b"a".to_vec(), b"b".to_vec()],
+            expiries.expired(clock.now())
+        );
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(vec![b"c".to_vec()], expiries.expired(clock.now()));
+        assert!(expiries.expired(clock.now()).is_empty());
+    }
+}
@@ -0,0 +1,93 @@
+//! Hash values, holding several named fields under a single key
+//!
+//! A hash is stored as a regular value, encoded with a leading marker so it's never
+//! mistaken for a plain value. Deleting the key deletes every field along with it.
+use std::collections::BTreeMap;
+
+use crate::error::{Error, Result};
+
+// Leads every encoded hash, a plain value starting with it would be read as a broken hash
+const MARKER: &[u8] = b"\0kave:hash\0";
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Hash {
+    fields: BTreeMap<String, Vec<u8>>,
+}
+
+impl Hash {
+    /// Decode a stored value for the command `name`, an absent value being an empty
+    /// hash. Plain values fail with `Error::Transform` so the client can recover.
+    pub fn decode(name: &str, value: Option<&[u8]>) -> Result<Self> {
+        let value = match value {
+            Some(value) => value,
+            None => return Ok(Self::default()),
+        };
+        let fields = value
+            .strip_prefix(MARKER)
+            .and_then(|fields| bincode::deserialize(fields).ok())
+            .ok_or_else(|| Error::Transform(format!("{name}: value is not a hash")))?;
+        Ok(Self { fields })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut value = MARKER.to_vec();
+        bincode::serialize_into(&mut value, &self.fields).expect("error encoding hash");
+        value
+    }
+
+    pub fn get(&self, field: &str) -> Option<&[u8]> {
+        self.fields.get(field).map(Vec::as_slice)
+    }
+
+    /// Set a field, returning whether it's new to the hash
+    pub fn insert(&mut self, field: String, value: Vec<u8>) -> bool {
+        self.fields.insert(field, value).is_none()
+    }
+
+    /// Every field with its value, sorted by field name
+    pub fn fields(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.fields
+            .iter()
+            .map(|(field, value)| (field.as_str(), value.as_slice()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Hash;
+    use crate::error::Error;
+
+    #[test]
+    fn test_round_trip() {
+        let mut hash = Hash::decode("hset", None).unwrap();
+        assert!(hash.is_empty());
+        assert!(hash.insert("b".to_string(), b"2".to_vec()));
+        assert!(hash.insert("a".to_string(), b"".to_vec()));
+        assert!(!hash.insert("b".to_string(), b"3".to_vec()));
+
+        let hash = Hash::decode("hget", Some(&hash.encode())).unwrap();
+        assert_eq!(2, hash.len());
+        assert_eq!(Some(&b"3"[..]), hash.get("b"));
+        assert_eq!(None, hash.get("c"));
+        assert_eq!(
+            vec![("a", &b""[..]), ("b", &b"3"[..])],
+            hash.fields().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_plain_value() {
+        match Hash::decode("hget", Some(b"plain")) {
+            Err(Error::Transform(msg)) => assert_eq!("hget: value is not a hash", msg),
+            res => panic!("unexpected result {res:?}"),
+        }
+    }
+}
@@ -0,0 +1,344 @@
+//! A secondary index from an attribute of values back to the keys holding them
+//!
+//! `IndexedStore` wraps any store and keeps, for every key written through it, the
+//! attribute its `Extractor` finds in the key's value, so `find` returns the keys
+//! whose values share an attribute without scanning the store. It isn't free:
+//! - every write runs the extractor over the written values, e.g. parsing them as JSON
+//! - writes hold the index lock while the wrapped store applies them, so the index
+//!   always matches the store, but writes through the same `IndexedStore` are serialized
+//! - SWAPs read both values back after swapping them to re-index them
+//! - the index lives in memory, an entry per indexed key, and is rebuilt whenever an
+//!   `IndexedStore` is created, by scanning every value the wrapped store holds
+//! - keys that expire stay indexed, so `find` reads the keys it found back to drop the
+//!   ones that are gone
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::io::AsyncWrite;
+use tokio::sync::Mutex;
+
+use super::transform::Transform;
+use super::{Operation, Store, Transaction};
+use crate::Result;
+
+// keys and values read at once while the index is rebuilt
+const REBUILD_BATCH: usize = 1024;
+
+/// Finds the attribute a value is indexed by, `None` leaving the value unindexed
+pub type Extractor = Arc<dyn Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync>;
+
+/// Index JSON object values by the top-level `field`. String fields are indexed by
+/// their contents and numbers and booleans as written, e.g. `42` or `true`.
+pub fn json_field(field: &str) -> Extractor {
+    let field = field.to_string();
+    Arc::new(move |value| {
+        let value: serde_json::Value = serde_json::from_slice(value).ok()?;
+        match value.get(&field)? {
+            serde_json::Value::String(s) => Some(s.clone().into_bytes()),
+            v @ (serde_json::Value::Number(_) | serde_json::Value::Bool(_)) => {
+                Some(v.to_string().into_bytes())
+            }
+            _ => None,
+        }
+    })
+}
+
+#[derive(Default)]
+struct Index {
+    keys: BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>>,
+    // the attribute each indexed key is under, to unindex it when it's written again
+    attrs: HashMap<Vec<u8>, Vec<u8>>,
+}
+impl Index {
+    /// Index `key` under `attr`, or unindex it when it has none
+    fn update(&mut self, key: &[u8], attr: Option<Vec<u8>>) {
+        if let Some(previous) = self.attrs.remove(key) {
+            if let Some(keys) = self.keys.get_mut(&previous) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.keys.remove(&previous);
+                }
+            }
+        }
+        if let Some(attr) = attr {
+            self.keys
+                .entry(attr.clone())
+                .or_default()
+                .insert(key.to_vec());
+            self.attrs.insert(key.to_vec(), attr);
+        }
+    }
+}
+
+/// A store whose values are indexed by an attribute, see the module docs for what it costs
+#[derive(Clone)]
+pub struct IndexedStore<S> {
+    inner: S,
+    extract: Extractor,
+    index: Arc<Mutex<Index>>,
+}
+impl<S: Store + Send + Sync> IndexedStore<S> {
+    /// Index the values `inner` already holds, in a single scan, and every one written
+    /// through the returned store. Fails for stores that can't list their keys.
+    pub async fn new(mut inner: S, extract: Extractor) -> Result<Self> {
+        let mut index = Index::default();
+        let mut start = vec![];
+        loop {
+            let entries = inner.scan_entries(&start, None, REBUILD_BATCH).await?;
+            let done = entries.len() < REBUILD_BATCH;
+            if let Some((last, _)) = entries.last() {
+                // the smallest key after the last one read
+                start = [last.as_slice(), &[0]].concat();
+            }
+            for (k, v) in entries {
+                index.update(&k, extract(&v));
+            }
+            if done {
+                break;
+            }
+        }
+        Ok(Self {
+            inner,
+            extract,
+            index: Arc::new(Mutex::new(index)),
+        })
+    }
+}
+
+#[async_trait]
+impl<S: Store + Send + Sync> Store for IndexedStore<S> {
+    async fn get(&mut self, k: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get(k).await
+    }
+
+    async fn exists(&mut self, k: &[u8]) -> Result<bool> {
+        self.inner.exists(k).await
+    }
+
+    async fn get_versioned(&mut self, k: &[u8]) -> Result<Option<(Vec<u8>, String)>> {
+        self.inner.get_versioned(k).await
+    }
+
+    async fn value_len(&mut self, k: &[u8]) -> Result<Option<usize>> {
+        self.inner.value_len(k).await
+    }
+
+    async fn get_many(&mut self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.inner.get_many(keys).await
+    }
+
+    async fn scan(&mut self, from_inclusive: &[u8], to_exclusive: &[u8]) -> Result<Vec<Vec<u8>>> {
+        self.inner.scan(from_inclusive, to_exclusive).await
+    }
+
+    async fn scan_entries(
+        &mut self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.inner.scan_entries(start, end, limit).await
+    }
+
+    async fn scan_keys(
+        &mut self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>> {
+        self.inner.scan_keys(start, end, limit).await
+    }
+
+    async fn snapshot(&mut self, writer: &mut (dyn AsyncWrite + Send + Unpin)) -> Result<usize> {
+        self.inner.snapshot(writer).await
+    }
+
+    async fn clear(&mut self) -> Result<()> {
+        let mut index = self.index.lock().await;
+        self.inner.clear().await?;
+        *index = Index::default();
+        Ok(())
+    }
+
+    async fn expire(&mut self, k: &[u8], ttl: Duration) -> Result<Option<bool>> {
+        self.inner.expire(k, ttl).await
+    }
+
+    async fn transact(&mut self, transaction: Transaction) -> Result<Vec<bool>> {
+        let updates = transaction
+            .operations
+            .iter()
+            .map(|op| match op {
+                Operation::Set(k, v) => (k.clone(), (self.extract)(v)),
+                Operation::Delete(k) => (k.clone(), None),
+            })
+            .collect::<Vec<_>>();
+        let mut index = self.index.lock().await;
+        let existed = self.inner.transact(transaction).await?;
+        for (k, attr) in updates {
+            index.update(&k, attr);
+        }
+        Ok(existed)
+    }
+
+    async fn swap(&mut self, a: &[u8], b: &[u8]) -> Result<()> {
+        let mut index = self.index.lock().await;
+        self.inner.swap(a, b).await?;
+        for k in [a, b] {
+            let value = self.inner.get(k).await?;
+            index.update(k, value.and_then(|v| (self.extract)(&v)));
+        }
+        Ok(())
+    }
+
+    async fn apply(&mut self, k: &[u8], transform: &Transform) -> Result<Vec<u8>> {
+        let mut index = self.index.lock().await;
+        let value = self.inner.apply(k, transform).await?;
+        index.update(k, (self.extract)(&value));
+        Ok(value)
+    }
+
+    async fn get_set(&mut self, k: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut index = self.index.lock().await;
+        let old = self.inner.get_set(k, value).await?;
+        index.update(k, (self.extract)(value));
+        Ok(old)
+    }
+
+    async fn find(&mut self, attr: &[u8]) -> Result<Option<Vec<Vec<u8>>>> {
+        let index = self.index.lock().await;
+        let keys = index
+            .keys
+            .get(attr)
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        let lookup = keys.iter().map(|k| k.as_slice()).collect::<Vec<_>>();
+        let values = self.inner.get_many(&lookup).await?;
+        let keys = keys
+            .into_iter()
+            .zip(values)
+            .filter(|(_, value)| value.is_some())
+            .map(|(k, _)| k.clone())
+            .collect();
+        Ok(Some(keys))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{json_field, IndexedStore, REBUILD_BATCH};
+    use crate::store::lsm::LSMStore;
+    use crate::store::transform::Transform;
+    use crate::store::{MemoryStore, Operation, Store, Transaction};
+    use crate::Result;
+
+    fn set(k: &str, v: &str) -> Transaction {
+        Transaction::with_random_id(vec![Operation::set(k, v.as_bytes())])
+    }
+
+    #[tokio::test]
+    async fn test_find() -> Result<()> {
+        let mut store = IndexedStore::new(MemoryStore::new(), json_field("team")).await?;
+        store.transact(set("ada", r#"{"team":"red"}"#)).await?;
+        store.transact(set("bob", r#"{"team":"blue"}"#)).await?;
+        store.transact(set("cy", r#"{"team":"red"}"#)).await?;
+        store.transact(set("dee", "not json")).await?;
+        assert_eq!(
+            Some(vec!["ada".into(), "cy".into()]),
+            store.find(b"red").await?
+        );
+        assert_eq!(Some(vec!["bob".into()]), store.find(b"blue").await?);
+        assert_eq!(Some(vec![]), store.find(b"green").await?);
+
+        // updates move keys between attributes, and deletes drop them
+        store.transact(set("ada", r#"{"team":"blue"}"#)).await?;
+        store
+            .transact(Transaction::with_random_id(vec![Operation::delete("cy")]))
+            .await?;
+        assert_eq!(Some(vec![]), store.find(b"red").await?);
+        assert_eq!(
+            Some(vec!["ada".into(), "bob".into()]),
+            store.find(b"blue").await?
+        );
+
+        // as do swaps and transforms
+        store.transact(set("cy", r#"{"team":7}"#)).await?;
+        store.swap(b"cy", b"bob").await?;
+        assert_eq!(
+            Some(vec!["ada".into(), "cy".into()]),
+            store.find(b"blue").await?
+        );
+        assert_eq!(Some(vec!["bob".into()]), store.find(b"7").await?);
+        store
+            .apply(b"bob", &Transform::Append(b"!".to_vec()))
+            .await?;
+        assert_eq!(Some(vec![]), store.find(b"7").await?);
+
+        // failed writes leave the index as it was
+        assert!(store.apply(b"cy", &Transform::Add(1)).await.is_err());
+        assert_eq!(
+            Some(vec!["ada".into(), "cy".into()]),
+            store.find(b"blue").await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_find_skips_expired() -> Result<()> {
+        let mut store = IndexedStore::new(MemoryStore::new(), json_field("team")).await?;
+        store.transact(set("ada", r#"{"team":"red"}"#)).await?;
+        store.transact(set("cy", r#"{"team":"red"}"#)).await?;
+        store.expire(b"ada", Duration::ZERO).await?;
+        assert_eq!(Some(vec!["cy".into()]), store.find(b"red").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_index_rebuilt_on_restart() -> Result<()> {
+        let data_dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        tokio::fs::create_dir(&data_dir).await?;
+        {
+            let lsm = LSMStore::recover(&data_dir).await?;
+            let mut store = IndexedStore::new(lsm, json_field("team")).await?;
+            store.transact(set("ada", r#"{"team":"red"}"#)).await?;
+            store.transact(set("bob", r#"{"team":"blue"}"#)).await?;
+            store.transact(set("cy", r#"{"team":"red"}"#)).await?;
+            store
+                .transact(Transaction::with_random_id(vec![Operation::delete("cy")]))
+                .await?;
+            // dropped without a flush, the keys are recovered from the commit log
+        }
+        let lsm = LSMStore::recover(&data_dir).await?;
+        let mut store = IndexedStore::new(lsm, json_field("team")).await?;
+        assert_eq!(Some(vec!["ada".into()]), store.find(b"red").await?);
+        assert_eq!(Some(vec!["bob".into()]), store.find(b"blue").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_pages_through_the_store() -> Result<()> {
+        let mut inner = MemoryStore::new();
+        let keys = (0..REBUILD_BATCH * 2 + 1)
+            .map(|n| format!("{n:05}"))
+            .collect::<Vec<_>>();
+        for k in &keys {
+            inner.transact(set(k, r#"{"team":"red"}"#)).await?;
+        }
+        let mut store = IndexedStore::new(inner, json_field("team")).await?;
+        let found = store.find(b"red").await?.unwrap();
+        assert_eq!(keys.len(), found.len());
+        assert_eq!(keys.last().unwrap().as_bytes(), found.last().unwrap());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unindexed_store() -> Result<()> {
+        assert_eq!(None, MemoryStore::new().find(b"red").await?);
+        Ok(())
+    }
+}
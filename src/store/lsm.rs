@@ -2,8 +2,11 @@
 mod commit_log;
 mod sstable;
 
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{btree_map, BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::iter::Peekable;
 use std::mem;
+use std::ops::{Bound, RangeBounds};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
@@ -11,28 +14,41 @@ use std::time::Duration;
 use async_trait::async_trait;
 use growable_bloom_filter::GrowableBloom;
 use itertools::Itertools;
-use serde::{Deserialize, Serialize};
 use tokio::fs::{self, OpenOptions};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::{broadcast, mpsc, oneshot, RwLock};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{
+    broadcast, mpsc, oneshot, Mutex, OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock,
+    RwLockWriteGuard, Semaphore, SemaphorePermit,
+};
 use uuid::Uuid;
 
 use self::commit_log::CommitLog;
-use self::sstable::SSTable;
+pub use self::sstable::SegmentIter;
+use self::sstable::{Disk, LocalDisk, RangeIter, SSTable};
 use self::Value::{Data, Tombstone};
 
+use super::entry;
+pub use super::entry::Value;
+use super::transform::Transform;
 use super::Operation::{Delete, Set};
-use super::{Store, Transaction};
-use crate::Result;
+use super::{scan_bounds, KeyBounds, SnapshotWriter, Store, Transaction};
 use crate::{utils, Config};
+use crate::{Error, Result};
 
 type Shared<T> = Arc<RwLock<T>>;
 type ShutdownResponder<T> = oneshot::Sender<T>;
 type ShutdownReceiver<T> = mpsc::UnboundedReceiver<ShutdownResponder<T>>;
 
-// TODO what are the optimal values for these bloom filter parameters?
+// chance an SSTable's bloom filter holds a key the SSTable doesn't, so a read opens
+// it for nothing. Each filter is sized for its SSTable's keys, see `bloom_filter`
 const BLOOM_ERROR_PROB: f64 = 0.01;
-const BLOOM_EST_INSERTIONS: usize = 128;
+
+// SSTables up to this size are in the smallest compaction tier, each tier up
+// holds SSTables `COMPACTION_TIER_RATIO` times bigger than the one below
+const COMPACTION_TIER_BASE_BYTES: u64 = 1_000_000;
+const COMPACTION_TIER_RATIO: u64 = 4;
+// most SSTables merged by a single tier compaction
+const COMPACTION_MAX_INPUTS: usize = 8;
 
 /// A store backed by a [log-structured merge tree](http://www.benstopford.com/2015/02/14/log-structured-merge-trees).
 #[derive(Clone)]
@@ -46,11 +62,45 @@ pub struct LSMStore {
     event_sender: broadcast::Sender<LSMEvent>,
     shutdown_receiver: Shared<ShutdownReceiver<bool>>,
     state: Shared<LSMState>,
+    // held exclusively for the duration of a full compaction, which merges every
+    // SSTable, and shared by tier compactions, which only merge the ones they claim
+    compaction: Arc<RwLock<()>>,
+    // SSTables claimed by running tier compactions, so none is part of two at once
+    compacting: Arc<Mutex<HashSet<PathBuf>>>,
+    // bounds how many tier compactions run at once
+    compaction_slots: Arc<Semaphore>,
+    // how often to compact in the background, disabled when `None`
+    compaction_interval: Option<Duration>,
+    // how long the store must go without a write before a scheduled compaction runs
+    compaction_quiet_period: Duration,
+    // SSTable count beyond which a flush starts a full compaction, disabled when `None`
+    compaction_trigger: Option<usize>,
+    // how often to sync the commit log in the background, disabled when `None`
+    commit_log_sync_interval: Option<Duration>,
+    // memtable size beyond which writes are throttled, disabled when `None`
+    write_throttle_bytes: Option<usize>,
+    // memtable size beyond which writes are rejected as overloaded, disabled when `None`
+    write_reject_bytes: Option<usize>,
+    // how long a memtable flush may take before it's logged as slow
+    slow_flush: Duration,
+    // bounds how many SSTables are read at once, each holding a file open,
+    // unlimited when `None`
+    disk_reads: Option<Arc<Semaphore>>,
+    // where SSTables are written
+    disk: Arc<dyn Disk>,
 }
 
 struct LSMData {
-    memtable: BTreeMap<String, Value>,
+    memtable: BTreeMap<Vec<u8>, Value>,
+    // approximate size of the memtable's keys and values
+    memtable_bytes: usize,
+    // size of the writes being logged, which the memtable is about to take
+    reserved_bytes: usize,
     tx_ids: Vec<Uuid>,
+    // bytes per second the last memtable flush was written at, `None` until one is measured
+    flush_rate: Option<f64>,
+    // when the last transaction was applied, `None` until one is
+    last_write: Option<tokio::time::Instant>,
 }
 
 struct LSMState {
@@ -61,21 +111,9 @@ struct LSMState {
 #[derive(Clone, Debug)]
 pub enum LSMEvent {
     WriteSSTable(PathBuf),
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
-pub enum Value {
-    Data(Vec<u8>),
-    Tombstone,
-}
-
-impl Value {
-    fn as_option(&self) -> Option<Vec<u8>> {
-        match self {
-            Data(data) => Some(data.to_vec()),
-            Tombstone => None,
-        }
-    }
+    // a compaction merged SSTables into the contained one,
+    // which is `None` when nothing survived the merge
+    Compacted(Option<PathBuf>),
 }
 
 impl LSMStore {
@@ -90,7 +128,11 @@ impl LSMStore {
         Self {
             data: Arc::new(RwLock::new(LSMData {
                 memtable: BTreeMap::new(),
+                memtable_bytes: 0,
+                reserved_bytes: 0,
                 tx_ids: Vec::new(),
+                flush_rate: None,
+                last_write: None,
             })),
             commit_log: Arc::new(RwLock::new(commit_log)),
             data_dir: data_dir.to_path_buf(),
@@ -100,16 +142,38 @@ impl LSMStore {
             event_sender: event_tx,
             shutdown_receiver: Arc::new(RwLock::new(shutdown_receiver)),
             state: Arc::new(RwLock::new(LSMState { is_shutdown: false })),
+            compaction: Arc::new(RwLock::new(())),
+            compacting: Arc::new(Mutex::new(HashSet::new())),
+            compaction_slots: Arc::new(Semaphore::new(1)),
+            compaction_interval: None,
+            compaction_quiet_period: Duration::from_secs(1),
+            compaction_trigger: None,
+            commit_log_sync_interval: None,
+            write_throttle_bytes: None,
+            write_reject_bytes: None,
+            slow_flush: Duration::from_secs(1),
+            disk_reads: None,
+            disk: Arc::new(LocalDisk),
         }
     }
 
     fn from_config(config: &Config, shutdown_receiver: ShutdownReceiver<bool>) -> Self {
-        Self::new(
+        let mut store = Self::new(
             config.data_dir.as_path(),
             config.commit_log_path.as_path(),
             config.memtable_max_mb * 1_000_000,
             shutdown_receiver,
-        )
+        );
+        store.compaction_interval = config.compaction_interval;
+        store.compaction_quiet_period = config.compaction_quiet_period;
+        store.compaction_trigger = config.compaction_trigger;
+        store.compaction_slots = Arc::new(Semaphore::new(config.compaction_parallelism));
+        store.commit_log_sync_interval = config.commit_log_sync_interval;
+        store.write_throttle_bytes = config.write_throttle_mb.map(|mb| mb * 1_000_000);
+        store.write_reject_bytes = config.write_reject_mb.map(|mb| mb * 1_000_000);
+        store.slow_flush = config.slow_flush;
+        store.disk_reads = config.max_disk_reads.map(|n| Arc::new(Semaphore::new(n)));
+        store
     }
 
     pub fn events(&mut self) -> broadcast::Receiver<LSMEvent> {
@@ -125,6 +189,23 @@ impl LSMStore {
         Ok(store)
     }
 
+    /// Opens the store in `data_dir`, with its commit log alongside its SSTables,
+    /// replaying every transaction the commit log holds that was never flushed, e.g.
+    /// after a crash. Memtables are flushed at the default 256MB, see
+    /// `initialize_from_config` to configure the store otherwise.
+    pub async fn recover(data_dir: &Path) -> Result<Self> {
+        // there's no one to ask the store to shut down
+        let (_, shutdown_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut store = Self::new(
+            data_dir,
+            data_dir.join("commit_log").as_path(),
+            256 * 1_000_000,
+            shutdown_receiver,
+        );
+        store.initialize().await?;
+        Ok(store)
+    }
+
     async fn initialize(&mut self) -> Result<()> {
         self.restore_bloom_map().await?;
         self.restore_previous_txs().await?;
@@ -135,6 +216,7 @@ impl LSMStore {
     async fn shutdown(
         data: Shared<LSMData>,
         data_dir: &Path,
+        disk: &dyn Disk,
         bloom_map: Shared<HashMap<PathBuf, GrowableBloom>>,
         bloom_map_path: &Path,
         commit_log: Shared<CommitLog>,
@@ -142,7 +224,7 @@ impl LSMStore {
     ) -> Result<()> {
         let mut state = state.write().await;
         state.is_shutdown = true;
-        Self::write_sstable(data.clone(), data_dir, bloom_map.clone(), commit_log).await?;
+        Self::write_sstable(data.clone(), data_dir, disk, bloom_map.clone(), commit_log).await?;
         Self::write_bloom_map(bloom_map.clone(), bloom_map_path).await?;
         Ok(())
     }
@@ -169,11 +251,7 @@ impl LSMStore {
         for path in self.get_sstables_asc().await? {
             let sstable = SSTable::new(path.as_path());
             let keys = sstable.keys().await?;
-            let mut bloom = GrowableBloom::new(BLOOM_ERROR_PROB, BLOOM_EST_INSERTIONS);
-            for key in keys {
-                bloom.insert(key);
-            }
-            bloom_map.insert(path, bloom);
+            bloom_map.insert(path, bloom_filter(keys.iter()));
         }
         Ok(bloom_map)
     }
@@ -192,12 +270,15 @@ impl LSMStore {
     fn start_background_tasks(&self) {
         let data = self.data.clone();
         let data_dir = self.data_dir.clone();
+        let disk = self.disk.clone();
         let bloom_map = self.bloom_map.clone();
         let bloom_map_path = self.bloom_map_path.clone();
         let commit_log = self.commit_log.clone();
         let memtable_max_bytes = self.memtable_max_bytes;
+        let slow_flush = self.slow_flush;
         let event_sender = self.event_sender.clone();
         let state = self.state.clone();
+        let store = self.clone();
 
         tokio::spawn(async move {
             let mut interval = tokio::time::interval(Duration::from_secs(1));
@@ -211,19 +292,38 @@ impl LSMStore {
                     .expect("Failed to size memtable")
                 {
                     tracing::debug!("Flushing memtable to disk...");
-                    if let Some(path) = Self::write_sstable(
+                    let started = tokio::time::Instant::now();
+                    let flushed = Self::write_sstable(
                         data.clone(),
                         data_dir.clone().as_path(),
+                        &*disk,
                         bloom_map.clone(),
                         commit_log.clone(),
                     )
-                    .await
-                    .expect("Failed to flush memtable")
-                    {
+                    .await;
+                    // the memtable is kept until it's flushed, so it stays readable
+                    // and the flush is retried, e.g. once disk space is freed
+                    let flushed = match flushed {
+                        Ok(flushed) => flushed,
+                        Err(e) => {
+                            tracing::error!("Failed to flush memtable, retrying: {e}");
+                            continue;
+                        }
+                    };
+                    if let Some(path) = flushed {
+                        let elapsed = started.elapsed();
+                        if elapsed >= slow_flush {
+                            tracing::warn!(
+                                path = ?path.as_path(),
+                                elapsed_ms = elapsed.as_millis() as u64,
+                                "Slow memtable flush, writes may be throttled"
+                            );
+                        }
                         event_sender
                             .send(LSMEvent::WriteSSTable(path))
                             .expect("Failed to send memtable flush event");
                         tracing::debug!("Flushed memtable");
+                        store.compact_if_triggered().await;
                     }
                 };
             }
@@ -231,6 +331,7 @@ impl LSMStore {
 
         let data = self.data.clone();
         let data_dir = self.data_dir.clone();
+        let disk = self.disk.clone();
         let bloom_map = self.bloom_map.clone();
         let commit_log = self.commit_log.clone();
         let state = self.state.clone();
@@ -242,6 +343,7 @@ impl LSMStore {
                 Self::shutdown(
                     data.clone(),
                     data_dir.as_path(),
+                    &*disk,
                     bloom_map.clone(),
                     bloom_map_path.as_path(),
                     commit_log.clone(),
@@ -254,7 +356,323 @@ impl LSMStore {
                     .expect("Failed to send shutdown confirmation");
             }
         });
-        // TODO implement segment compaction
+
+        if let Some(interval) = self.commit_log_sync_interval {
+            let commit_log = self.commit_log.clone();
+            let state = self.state.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(interval);
+                loop {
+                    interval.tick().await;
+                    if state.read().await.is_shutdown {
+                        break;
+                    };
+                    if let Err(e) = commit_log.write().await.sync().await {
+                        tracing::error!("Failed to sync commit log, retrying: {e}");
+                    }
+                }
+            });
+        }
+
+        if let Some(interval) = self.compaction_interval {
+            let store = self.clone();
+            // with room for more than one compaction at once, merge size tiers
+            // concurrently rather than everything into a single SSTable
+            let tiered = self.compaction_slots.available_permits() > 1;
+            tokio::spawn(async move {
+                let start = tokio::time::Instant::now() + interval;
+                let mut interval = tokio::time::interval_at(start, interval);
+                // a run postponed past the next tick doesn't make up for it with another
+                interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                loop {
+                    interval.tick().await;
+                    while let Some(quiet) = store.quiet_at().await {
+                        tracing::debug!("Store is taking writes, postponing scheduled compaction");
+                        tokio::time::sleep_until(quiet).await;
+                    }
+                    if store.state.read().await.is_shutdown {
+                        break;
+                    };
+                    tracing::debug!("Running scheduled compaction...");
+                    // skip this run if a conflicting compaction is already underway,
+                    // it'll have done the same work
+                    let compacted = if tiered {
+                        match store.compaction.clone().try_read_owned() {
+                            Ok(guard) => store.compact_tiers_locked(guard).await.map(|_| ()),
+                            Err(_) => {
+                                tracing::debug!("Compaction in progress, skipping scheduled run");
+                                continue;
+                            }
+                        }
+                    } else {
+                        match store.compaction.clone().try_write_owned() {
+                            Ok(guard) => store.compact_locked(guard).await,
+                            Err(_) => {
+                                tracing::debug!("Compaction in progress, skipping scheduled run");
+                                continue;
+                            }
+                        }
+                    };
+                    // the inputs are left in place, so a failed compaction can be retried
+                    if let Err(e) = compacted {
+                        tracing::error!("Failed to compact SSTables: {e}");
+                    }
+                }
+            });
+        }
+    }
+
+    /// When the store will have gone the compaction quiet period without a write, or
+    /// `None` if it already has
+    async fn quiet_at(&self) -> Option<tokio::time::Instant> {
+        let quiet = self.data.read().await.last_write? + self.compaction_quiet_period;
+        (quiet > tokio::time::Instant::now()).then_some(quiet)
+    }
+
+    /// Writes the memtable to a new SSTable and clears it, along with the commit log
+    /// entries it held, without waiting for it to fill up. Returns the new SSTable,
+    /// or `None` when the memtable was empty. On failure the memtable is kept as is.
+    pub async fn flush(&self) -> Result<Option<PathBuf>> {
+        let flushed = Self::write_sstable(
+            self.data.clone(),
+            self.data_dir.as_path(),
+            &*self.disk,
+            self.bloom_map.clone(),
+            self.commit_log.clone(),
+        )
+        .await?;
+        if let Some(path) = &flushed {
+            // there may well be no one listening for events
+            self.event_sender
+                .send(LSMEvent::WriteSSTable(path.clone()))
+                .ok();
+            self.compact_if_triggered().await;
+        }
+        Ok(flushed)
+    }
+
+    /// Starts a full compaction in the background once there are more SSTables
+    /// than the configured trigger, unless a compaction is already underway.
+    async fn compact_if_triggered(&self) {
+        let trigger = match self.compaction_trigger {
+            Some(trigger) => trigger,
+            None => return,
+        };
+        match self.get_sstables_asc().await {
+            Ok(sstables) if sstables.len() > trigger => {}
+            Ok(_) => return,
+            Err(e) => {
+                tracing::error!("Failed to count SSTables: {e}");
+                return;
+            }
+        }
+        // the running compaction will merge the new SSTable, or the next flush will
+        let guard = match self.compaction.clone().try_write_owned() {
+            Ok(guard) => guard,
+            Err(_) => {
+                tracing::debug!("Compaction in progress, skipping triggered run");
+                return;
+            }
+        };
+        tracing::debug!("Running triggered compaction...");
+        let store = self.clone();
+        tokio::spawn(async move {
+            // the inputs are left in place, so a failed compaction can be retried
+            if let Err(e) = store.compact_locked(guard).await {
+                tracing::error!("Failed to compact SSTables: {e}");
+            }
+        });
+    }
+
+    /// Merges every SSTable into a single new SSTable, keeping only the newest
+    /// value of each key. Tombstones are dropped since there are no older
+    /// SSTables left for them to shadow. Waits for any running compaction to finish.
+    pub async fn compact(&self) -> Result<()> {
+        let guard = self.compaction.clone().write_owned().await;
+        self.compact_locked(guard).await
+    }
+
+    async fn compact_locked(&self, _guard: OwnedRwLockWriteGuard<()>) -> Result<()> {
+        let inputs = self.get_sstables_asc().await?;
+        if inputs.len() < 2 {
+            return Ok(());
+        }
+        self.merge(&inputs, true).await
+    }
+
+    /// Merges runs of similarly sized SSTables, up to `COMPACTION_MAX_INPUTS` at a
+    /// time, with as many merges running at once as the configured compaction
+    /// parallelism allows. Each merge claims its SSTables first, so concurrent calls
+    /// split the work between them rather than merging any SSTable twice. Returns
+    /// how many merges ran. Waits for any running full compaction to finish.
+    pub async fn compact_tiers(&self) -> Result<usize> {
+        let guard = self.compaction.clone().read_owned().await;
+        self.compact_tiers_locked(guard).await
+    }
+
+    async fn compact_tiers_locked(&self, guard: OwnedRwLockReadGuard<()>) -> Result<usize> {
+        let guard = Arc::new(guard);
+        let mut merges = Vec::new();
+        while let Ok(slot) = self.compaction_slots.clone().try_acquire_owned() {
+            let (inputs, oldest) = match self.claim_tier().await? {
+                Some(claimed) => claimed,
+                None => break,
+            };
+            let store = self.clone();
+            let guard = guard.clone();
+            merges.push(tokio::spawn(async move {
+                // tombstones only shadow older SSTables, keep them unless there are none
+                let merged = store.merge(&inputs, oldest).await;
+                let mut compacting = store.compacting.lock().await;
+                for path in &inputs {
+                    compacting.remove(path);
+                }
+                drop((slot, guard));
+                merged
+            }));
+        }
+        let count = merges.len();
+        for merge in merges {
+            merge
+                .await
+                .map_err(|e| format!("compaction failed: {e}"))??;
+        }
+        Ok(count)
+    }
+
+    /// Claims the oldest run of unclaimed SSTables that are next to each other in age
+    /// and in the same size tier, returning them oldest first along with whether the
+    /// first of them is the oldest SSTable of all. Only SSTables next to each other in
+    /// age may be merged, so the merged SSTable takes their place in the search order.
+    async fn claim_tier(&self) -> Result<Option<(Vec<PathBuf>, bool)>> {
+        let mut compacting = self.compacting.lock().await;
+        let sstables = self.get_sstables_asc().await?;
+        let mut run: Vec<(usize, u32)> = Vec::new();
+        for (i, path) in sstables.iter().enumerate() {
+            if compacting.contains(path) {
+                if run.len() >= 2 {
+                    break;
+                }
+                run.clear();
+                continue;
+            }
+            let tier = compaction_tier(fs::metadata(path).await?.len());
+            if run.last().is_some_and(|(_, last)| *last != tier) {
+                if run.len() >= 2 {
+                    break;
+                }
+                run.clear();
+            }
+            run.push((i, tier));
+            if run.len() == COMPACTION_MAX_INPUTS {
+                break;
+            }
+        }
+        if run.len() < 2 {
+            return Ok(None);
+        }
+        let oldest = run[0].0 == 0;
+        let inputs: Vec<PathBuf> = run.iter().map(|(i, _)| sstables[*i].clone()).collect();
+        compacting.extend(inputs.iter().cloned());
+        Ok(Some((inputs, oldest)))
+    }
+
+    /// Merges `inputs`, given oldest first, into a single new SSTable in their place,
+    /// keeping only the newest value of each key. Tombstones are dropped when
+    /// `drop_tombstones` is set, which is only safe if no SSTable is older than `inputs`.
+    async fn merge(&self, inputs: &[PathBuf], drop_tombstones: bool) -> Result<()> {
+        // Name the output after the newest input so that it sorts before any
+        // SSTable flushed while we were merging
+        let newest = inputs[inputs.len() - 1]
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.split('-').next())
+            .ok_or("invalid SSTable file name")?
+            .to_string();
+        let path = self.data_dir.join(format!(
+            "{newest}-{}.sst",
+            utils::time_since_epoch().as_millis()
+        ));
+        let keys = SSTable::new(path.clone())
+            .write_merged(inputs, drop_tombstones, &*self.disk)
+            .await?;
+        let output = if keys.is_empty() { None } else { Some(path) };
+
+        // Hold the data lock while swapping files so no reads are in flight
+        let _data = self.data.write().await;
+        let mut bloom_map = self.bloom_map.write().await;
+        if let Some(path) = &output {
+            bloom_map.insert(path.clone(), bloom_filter(keys.iter()));
+        }
+        for path in inputs {
+            bloom_map.remove(path);
+            fs::remove_file(path).await?;
+        }
+        // the persisted bloom map references the removed SSTables,
+        // it'll be reconstructed from the remaining ones on restart
+        if self.bloom_map_path.exists() {
+            fs::remove_file(&self.bloom_map_path).await?;
+        }
+        tracing::debug!(inputs = inputs.len(), output = ?output, "Compacted SSTables");
+        // nobody may be listening for events
+        let _ = self.event_sender.send(LSMEvent::Compacted(output));
+        Ok(())
+    }
+
+    /// Imports `entries` straight into a new SSTable, skipping the memtable and
+    /// commit log, which makes it much faster than transacting them for an initial
+    /// import. `entries` must be sorted by key without duplicates. The SSTable is
+    /// written under a temporary name and only becomes visible, all at once, when
+    /// it's complete, so a crash mid-import leaves the store as it was.
+    ///
+    /// Bulk-loaded data is newer than everything already on disk, so this must only
+    /// be used on an otherwise-idle store: it fails if the memtable holds unflushed
+    /// writes, and holds the data lock for the whole import to block other access.
+    pub async fn bulk_load<I>(&self, entries: I) -> Result<Option<PathBuf>>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        let _compaction = self.compaction.write().await;
+        let data = self.data.write().await;
+        if !data.memtable.is_empty() {
+            return Err(
+                "bulk load requires an idle store, the memtable has unflushed writes".into(),
+            );
+        }
+        let mut sorted = BTreeMap::new();
+        let mut last: Option<Vec<u8>> = None;
+        for (k, v) in entries {
+            if last.as_ref().is_some_and(|last| *last >= k) {
+                return Err(format!(
+                    "bulk load entries must be sorted without duplicates, got {:?} after {:?}",
+                    String::from_utf8_lossy(&k),
+                    last.as_deref().map(String::from_utf8_lossy),
+                )
+                .into());
+            }
+            last = Some(k.clone());
+            sorted.insert(k, Data(v));
+        }
+        if sorted.is_empty() {
+            return Ok(None);
+        }
+
+        let path = self
+            .data_dir
+            .join(format!("{}.sst", utils::time_since_epoch().as_millis()));
+        SSTable::new(&path).write(&sorted, &*self.disk).await?;
+        let mut bloom_map = self.bloom_map.write().await;
+        bloom_map.insert(path.clone(), bloom_filter(sorted.keys()));
+        // the persisted bloom map doesn't know about the new SSTable and the
+        // commit log is untouched, so drop it to have it reconstructed on restart
+        if self.bloom_map_path.exists() {
+            fs::remove_file(&self.bloom_map_path).await?;
+        }
+        drop(data);
+        tracing::debug!(path = ?path, entries = sorted.len(), "Bulk loaded SSTable");
+        // nobody may be listening for events
+        let _ = self.event_sender.send(LSMEvent::WriteSSTable(path.clone()));
+        Ok(Some(path))
     }
 
     /// Whether the memtable has grown big enough to flush to disk.
@@ -263,13 +681,34 @@ impl LSMStore {
         memtable_max_bytes: usize,
     ) -> Result<bool> {
         let data = shared_data.read().await;
-        Ok(!data.memtable.is_empty() && mem::size_of_val(&data.memtable) >= memtable_max_bytes)
+        Ok(!data.memtable.is_empty() && data.memtable_bytes >= memtable_max_bytes)
     }
 
     /// Returns a vector of SSTable paths, ordered from oldest to newest.
     async fn get_sstables_asc(&self) -> Result<Vec<PathBuf>> {
+        Self::sstables_asc(&self.data_dir).await
+    }
+
+    /// Opens every SSTable segment in `data_dir`, oldest first, to read their entries
+    /// (including tombstones) in stored order. Segments are read straight from disk,
+    /// so this is safe to use for inspecting a data directory without starting a store.
+    pub async fn iter_segments(data_dir: &Path) -> Result<Vec<(PathBuf, SegmentIter)>> {
+        let mut segments = Vec::new();
+        for path in Self::sstables_asc(data_dir).await? {
+            let segment = Self::iter_segment(&path).await?;
+            segments.push((path, segment));
+        }
+        Ok(segments)
+    }
+
+    /// Opens a single SSTable segment file, see `iter_segments`.
+    pub async fn iter_segment(path: &Path) -> Result<SegmentIter> {
+        SSTable::new(path).iter().await
+    }
+
+    async fn sstables_asc(data_dir: &Path) -> Result<Vec<PathBuf>> {
         let mut sstables = Vec::new();
-        let mut dir = fs::read_dir(&self.data_dir).await?;
+        let mut dir = fs::read_dir(data_dir).await?;
         while let Some(file) = dir.next_entry().await? {
             if let Some(ext) = file.path().extension() {
                 if ext == "sst" {
@@ -281,21 +720,53 @@ impl LSMStore {
         Ok(sstables)
     }
 
-    async fn sstables_for_key(&self, key: &str) -> Vec<PathBuf> {
-        let key = key.to_string();
+    async fn sstables_for_key(&self, key: &[u8]) -> Vec<PathBuf> {
+        self.sstables_for_keys(&[key]).await.remove(0)
+    }
+
+    /// The SSTables whose bloom filters may hold each of `keys`, newest first, all
+    /// checked under a single read of the bloom map
+    async fn sstables_for_keys(&self, keys: &[&[u8]]) -> Vec<Vec<PathBuf>> {
         let bloom_map = self.bloom_map.read().await;
-        bloom_map
+        let newest_first = bloom_map
             .iter()
-            .filter(|(_, bloom)| bloom.contains(&key))
-            .map(|(path, _)| path.clone())
-            .sorted_by(|a, b| b.cmp(a))
+            .sorted_by(|(a, _), (b, _)| b.cmp(a))
+            .collect::<Vec<_>>();
+        keys.iter()
+            .map(|key| {
+                newest_first
+                    .iter()
+                    .filter(|(_, bloom)| bloom.contains(BloomKey(key)))
+                    .map(|(path, _)| (*path).clone())
+                    .collect()
+            })
             .collect()
     }
 
-    async fn search_sstables(&self, key: &str) -> Result<Option<Value>> {
-        for path in self.sstables_for_key(key).await {
-            let sstable = SSTable::new(&path);
-            let v = sstable.search(key.to_owned()).await?;
+    /// Waits for a turn to read an SSTable when the number of concurrent reads is
+    /// limited, holding the returned permit for the read keeps others waiting.
+    async fn disk_read_slot(&self) -> Result<Option<SemaphorePermit<'_>>> {
+        match &self.disk_reads {
+            Some(slots) => {
+                Ok(Some(slots.acquire().await.map_err(|e| {
+                    format!("error acquiring disk read slot: {e}")
+                })?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn search_sstables(&self, key: &[u8]) -> Result<Option<Value>> {
+        let sstables = self.sstables_for_key(key).await;
+        self.search_in(&sstables, key).await
+    }
+
+    /// Searches `sstables` for `key` in turn, returning the first entry found
+    async fn search_in(&self, sstables: &[PathBuf], key: &[u8]) -> Result<Option<Value>> {
+        for path in sstables {
+            let sstable = SSTable::new(path);
+            let _slot = self.disk_read_slot().await?;
+            let v = sstable.search(key).await?;
             if v.is_some() {
                 return Ok(v);
             };
@@ -303,26 +774,39 @@ impl LSMStore {
         Ok(None)
     }
 
-    async fn scan_sstables(
+    async fn scan_sstables<R: RangeBounds<Vec<u8>> + Clone>(
         &self,
-        from_inclusive: &str,
-        to_exclusive: &str,
-    ) -> Result<Vec<(String, Value)>> {
+        range: R,
+    ) -> Result<Vec<(Vec<u8>, Value)>> {
         let mut scan_kvs = BTreeMap::new();
         for path in self.get_sstables_asc().await? {
             let sstable = SSTable::new(&path);
-            for (k, v) in sstable.scan(from_inclusive, to_exclusive).await? {
+            let _slot = self.disk_read_slot().await?;
+            for (k, v) in sstable.scan(range.clone()).await? {
                 scan_kvs.insert(k, v);
             }
         }
         Ok(scan_kvs.into_iter().collect())
     }
 
+    /// Opens the SSTables, newest first, to read their entries in `bounds` lazily, see
+    /// `MergedRange`. Taking `data` means its lock is held while they're opened, so
+    /// they're the SSTables its memtable is newer than. They're read as they were even
+    /// once a compaction replaces them.
+    async fn open_sstables(&self, _data: &LSMData, bounds: &KeyBounds) -> Result<Vec<RangeIter>> {
+        let mut sstables = Vec::new();
+        for path in self.get_sstables_asc().await?.iter().rev() {
+            sstables.push(SSTable::new(path).range_iter(bounds.clone()).await?);
+        }
+        Ok(sstables)
+    }
+
     /// Writes the current memtable to disk as an SStable then clears
     /// the memtable.
     async fn write_sstable(
         shared_data: Shared<LSMData>,
         data_dir: &Path,
+        disk: &dyn Disk,
         bloom_map: Shared<HashMap<PathBuf, GrowableBloom>>,
         commit_log: Shared<CommitLog>,
     ) -> Result<Option<PathBuf>> {
@@ -330,29 +814,28 @@ impl LSMStore {
         if data.memtable.is_empty() {
             return Ok(None);
         }
+        let started = tokio::time::Instant::now();
         let mut bloom_map = bloom_map.write().await;
         let path = data_dir.join(format!("{}.sst", utils::time_since_epoch().as_millis()));
         let sstable = SSTable::new(path.clone());
-        sstable.write(&data.memtable).await?;
+        sstable.write(&data.memtable, disk).await?;
         tracing::debug!(
             path = ?path.as_path(),
             "Wrote SSTable file"
         );
-        bloom_map.insert(
-            path.clone(),
-            GrowableBloom::new(BLOOM_ERROR_PROB, BLOOM_EST_INSERTIONS),
-        );
-        let keys = data.memtable.keys().clone();
-        for key in keys {
-            let bloom = bloom_map.get_mut(&path).unwrap();
-            bloom.insert(key.clone());
-        }
+        bloom_map.insert(path.clone(), bloom_filter(data.memtable.keys()));
         data.memtable = BTreeMap::new();
         let mut commit_log = commit_log.write().await;
         for tx_id in &data.tx_ids {
             commit_log.end_transaction(tx_id).await?;
         }
+        // the log is correct as it is, only bigger than it needs to be
+        if let Err(e) = commit_log.rotate().await {
+            tracing::warn!(path = ?commit_log.path(), "Failed to rotate commit log: {e}");
+        }
         data.tx_ids = Vec::new();
+        let flushed = mem::take(&mut data.memtable_bytes);
+        data.flush_rate = Some(flushed as f64 / started.elapsed().as_secs_f64().max(1e-6));
         Ok(Some(path))
     }
 
@@ -400,87 +883,540 @@ impl LSMStore {
         Ok(Some(bloom_map))
     }
 
-    async fn do_transact(&mut self, transaction: Transaction, log_commit: bool) -> Result<()> {
+    async fn do_transact(
+        &mut self,
+        transaction: Transaction,
+        log_commit: bool,
+    ) -> Result<Vec<bool>> {
+        // the SSTables are read before anything's logged, so nothing can fail once it is
+        let existed = self.existed(&transaction).await?;
+        let bytes = write_bytes(&transaction);
         if log_commit {
-            let mut commit_log = self.commit_log.write().await;
-            commit_log.begin_transaction(&transaction).await?;
+            // checked before logging, a rejected write mustn't be replayed. Replayed
+            // writes were already acknowledged, so they're never rejected
+            self.reserve(bytes).await?;
+            let logged = self
+                .commit_log
+                .write()
+                .await
+                .begin_transaction(&transaction)
+                .await;
+            if let Err(e) = logged {
+                self.data.write().await.reserved_bytes -= bytes;
+                return Err(e);
+            }
         }
         let mut data = self.data.write().await;
-        let tx_ids = &mut data.tx_ids;
-        tx_ids.push(transaction.id);
-        for instruction in transaction.operations {
-            match instruction {
-                Set(key, value) => {
-                    data.memtable
-                        .insert(key.to_string(), Value::Data(value.to_vec()));
-                }
-                Delete(key) => {
-                    data.memtable.insert(key.to_string(), Value::Tombstone);
+        if log_commit {
+            data.reserved_bytes -= bytes;
+        }
+        let existed = Self::apply_transaction(&mut data, transaction, existed);
+        self.throttle(data, bytes).await;
+        Ok(existed)
+    }
+
+    /// Whether each key `transaction` writes exists, worked out before the transaction
+    /// takes the data lock so writes don't wait on the SSTables being read: from the
+    /// memtable's entry for the key when it has one, the SSTables otherwise. The
+    /// memtable's entry when the transaction is applied wins, so only a key another
+    /// write sets and a flush moves to disk in between is reported as it was read here.
+    async fn existed(&self, transaction: &Transaction) -> Result<Vec<bool>> {
+        let keys = transaction
+            .operations
+            .iter()
+            .map(|operation| match operation {
+                Set(key, _) | Delete(key) => key.as_slice(),
+            })
+            .collect::<Vec<_>>();
+        let mut existed = Vec::with_capacity(keys.len());
+        // keys the memtable has no entry for, by their index in `keys`
+        let mut missed = vec![];
+        {
+            let data = self.data.read().await;
+            for (i, key) in keys.iter().enumerate() {
+                match data.memtable.get(*key) {
+                    Some(v) => existed.push(v.exists()),
+                    None => {
+                        existed.push(false);
+                        missed.push(i);
+                    }
                 }
-            };
+            }
+        }
+        let missed_keys = missed.iter().map(|i| keys[*i]).collect::<Vec<_>>();
+        let sstables = self.sstables_for_keys(&missed_keys).await;
+        for (i, sstables) in missed.into_iter().zip(sstables) {
+            existed[i] = entry::existed(self.search_in(&sstables, keys[i]).await?.as_ref());
+        }
+        Ok(existed)
+    }
+
+    /// Logs and applies `transaction` under the data lock its caller read the values it
+    /// writes under, holding it across the read and the write so no other write can
+    /// interleave. `existed` is whether each key it writes existed, as the caller read it.
+    async fn commit_locked(
+        &self,
+        mut data: RwLockWriteGuard<'_, LSMData>,
+        transaction: Transaction,
+        existed: Vec<bool>,
+    ) -> Result<()> {
+        self.commit_log
+            .write()
+            .await
+            .begin_transaction(&transaction)
+            .await?;
+        let bytes = write_bytes(&transaction);
+        Self::apply_transaction(&mut data, transaction, existed);
+        self.throttle(data, bytes).await;
+        Ok(())
+    }
+
+    /// Rejects a write with `Error::Overloaded` while the memtable, with the writes
+    /// it's about to take, is over the reject size, which happens when flushes fall so
+    /// far behind that throttling can't keep up. Clients are expected to back off and
+    /// retry.
+    fn check_pressure(&self, data: &LSMData) -> Result<()> {
+        let bytes = data.memtable_bytes + data.reserved_bytes;
+        match self.write_reject_bytes {
+            Some(limit) if bytes >= limit => Err(Error::Overloaded(format!(
+                "{bytes} bytes are waiting to be flushed, over the limit of {limit}"
+            ))),
+            _ => Ok(()),
         }
+    }
+
+    /// Reserves `bytes` of the memtable for a write while it's logged, once
+    /// `check_pressure` lets it through. Checking and reserving under the same lock
+    /// keeps concurrent writes from all being let through before any is applied.
+    async fn reserve(&self, bytes: usize) -> Result<()> {
+        let mut data = self.data.write().await;
+        self.check_pressure(&data)?;
+        data.reserved_bytes += bytes;
         Ok(())
     }
+
+    /// Delays acknowledging a write while the memtable is over the throttle size, by
+    /// as long as the last flush took to write as many bytes as the memtable grew by.
+    /// Writes then slow to the rate flushes keep up with, instead of the memtable
+    /// growing without bound when the disk is slow.
+    async fn throttle(&self, data: RwLockWriteGuard<'_, LSMData>, bytes: usize) {
+        let delay = match (self.write_throttle_bytes, data.flush_rate) {
+            (Some(limit), Some(rate)) if data.memtable_bytes >= limit => {
+                Duration::from_secs_f64(bytes as f64 / rate)
+            }
+            _ => return,
+        };
+        drop(data);
+        tracing::debug!(delay_ms = delay.as_millis() as u64, "Throttling write");
+        tokio::time::sleep(delay).await;
+    }
+
+    /// Applies `transaction` to the memtable, the caller is responsible for logging it.
+    /// It can't fail, so a logged transaction is always applied. Returns whether each
+    /// key existed, by its previous entry in the memtable, or by `existed` when it had none.
+    fn apply_transaction(
+        data: &mut LSMData,
+        transaction: Transaction,
+        existed: Vec<bool>,
+    ) -> Vec<bool> {
+        data.tx_ids.push(transaction.id);
+        data.last_write = Some(tokio::time::Instant::now());
+        let operations = transaction.operations.into_iter().zip(existed);
+        operations
+            .map(|(instruction, existed)| {
+                let (key, value) = match instruction {
+                    Set(key, value) => (key, Value::Data(value)),
+                    Delete(key) => (key, Value::Tombstone),
+                };
+                data.memtable_bytes += entry_bytes(&key, &value);
+                match data.memtable.insert(key.clone(), value) {
+                    Some(previous) => {
+                        data.memtable_bytes -= entry_bytes(&key, &previous);
+                        previous.exists()
+                    }
+                    None => existed,
+                }
+            })
+            .collect()
+    }
+
+    /// Looks up `k` in the memtable, falling back to the SSTables when the
+    /// memtable has no entry for it. A tombstone in the memtable means the key
+    /// was deleted, so older values in the SSTables must not be resurrected.
+    async fn lookup(&self, data: &LSMData, k: &[u8]) -> Result<Option<Vec<u8>>> {
+        match data.memtable.get(k) {
+            Some(v) => Ok(v.as_option()),
+            None => Ok(self.search_sstables(k).await?.and_then(Value::into_option)),
+        }
+    }
 }
 
 #[async_trait]
 impl Store for LSMStore {
-    async fn get(&mut self, k: &str) -> Result<Option<Vec<u8>>> {
+    async fn get(&mut self, k: &[u8]) -> Result<Option<Vec<u8>>> {
         let store = self.data.read().await;
-        let mut result = store
-            .memtable
-            .get(&k.to_string())
-            .and_then(|v| v.as_option());
-        if result.is_none() {
-            result = self.search_sstables(k).await?.and_then(|v| v.as_option());
+        self.lookup(&store, k).await
+    }
+
+    async fn exists(&mut self, k: &[u8]) -> Result<bool> {
+        let store = self.data.read().await;
+        if let Some(v) = store.memtable.get(k) {
+            return Ok(v.exists());
+        }
+        // only SSTables whose bloom filter may hold the key are read, often none
+        Ok(self.search_sstables(k).await?.is_some_and(|v| v.exists()))
+    }
+
+    async fn get_many(&mut self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        // writers need the data lock, so every key is read at the same point in time
+        let store = self.data.read().await;
+        let mut values = Vec::with_capacity(keys.len());
+        // keys the memtable has no entry for, by their index in `keys`
+        let mut missed = vec![];
+        for (i, k) in keys.iter().enumerate() {
+            match store.memtable.get(*k) {
+                Some(v) => values.push(v.as_option()),
+                None => {
+                    values.push(None);
+                    missed.push(i);
+                }
+            }
+        }
+        let missed_keys = missed.iter().map(|i| keys[*i]).collect::<Vec<_>>();
+        let sstables = self.sstables_for_keys(&missed_keys).await;
+        for (i, sstables) in missed.into_iter().zip(sstables) {
+            values[i] = self
+                .search_in(&sstables, keys[i])
+                .await?
+                .and_then(Value::into_option);
         }
-        Ok(result)
+        Ok(values)
     }
 
-    async fn scan(&mut self, from_inclusive: &str, to_exclusive: &str) -> Result<Vec<Vec<u8>>> {
+    async fn scan(&mut self, from_inclusive: &[u8], to_exclusive: &[u8]) -> Result<Vec<Vec<u8>>> {
         let store = self.data.read().await;
         let mut scan_result = BTreeMap::new();
-        for (k, v) in self.scan_sstables(from_inclusive, to_exclusive).await? {
+        let range = from_inclusive.to_vec()..to_exclusive.to_vec();
+        for (k, v) in self.scan_sstables(range).await? {
             scan_result.insert(k, v);
         }
         for (k, v) in store
             .memtable
-            .range(from_inclusive.to_string()..to_exclusive.to_string())
+            .range(from_inclusive.to_vec()..to_exclusive.to_vec())
         {
             scan_result.insert(k.to_owned(), v.to_owned());
         }
         Ok(scan_result
-            .iter()
-            .filter_map(|(_, v)| match v.clone() {
-                Data(data) => Some(data),
-                Tombstone => None,
-            })
+            .into_values()
+            .filter_map(Value::into_option)
             .collect())
     }
 
-    async fn transact(&mut self, transaction: Transaction) -> Result<()> {
+    async fn scan_entries(
+        &mut self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let bounds = match scan_bounds(start, end) {
+            Some(bounds) => bounds,
+            None => return Ok(vec![]),
+        };
+        let _slot = self.disk_read_slot().await?;
+        let store = self.data.read().await;
+        // tombstones shadow older entries but aren't returned, so they don't count
+        // towards the limit. Values are read up to the last entry returned
+        let sstables = self.open_sstables(&store, &bounds).await?;
+        let mut merged = MergedRange::new(store.memtable.range(bounds), sstables);
+        let mut entries = Vec::new();
+        while entries.len() < limit {
+            match merged.next_entry().await? {
+                Some((k, Data(value))) => entries.push((k, value)),
+                Some((_, Tombstone)) => continue,
+                None => break,
+            }
+        }
+        Ok(entries)
+    }
+
+    async fn scan_keys(
+        &mut self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>> {
+        let bounds = match scan_bounds(start, end) {
+            Some(bounds) => bounds,
+            None => return Ok(vec![]),
+        };
+        let _slot = self.disk_read_slot().await?;
+        let store = self.data.read().await;
+        // only the SSTables' indexes are read, and only up to the last key returned
+        let sstables = self.open_sstables(&store, &bounds).await?;
+        let mut entries = MergedRange::new(store.memtable.range(bounds), sstables);
+        let mut keys = Vec::new();
+        while keys.len() < limit {
+            match entries.next_key() {
+                Some((k, true)) => keys.push(k),
+                Some((_, false)) => continue,
+                None => break,
+            }
+        }
+        Ok(keys)
+    }
+
+    async fn snapshot(&mut self, writer: &mut (dyn AsyncWrite + Send + Unpin)) -> Result<usize> {
+        let _slot = self.disk_read_slot().await?;
+        let bounds = (Bound::Unbounded, Bound::Unbounded);
+        // the memtable is copied and the SSTables opened under the data lock, then read
+        // as they were without holding up writes, one entry at a time
+        let (memtable, sstables) = {
+            let data = self.data.read().await;
+            let sstables = self.open_sstables(&data, &bounds).await?;
+            (data.memtable.clone(), sstables)
+        };
+        let mut entries = MergedRange::new(memtable.range(bounds), sstables);
+        let mut snapshot = SnapshotWriter::start(writer).await?;
+        while let Some((k, value)) = entries.next_entry().await? {
+            if let Data(value) = value {
+                snapshot.entry(&k, &value).await?;
+            }
+        }
+        snapshot.finish().await
+    }
+
+    async fn clear(&mut self) -> Result<()> {
+        // no compaction may be merging the SSTables being removed, and no read in flight
+        let _compaction = self.compaction.write().await;
+        let mut data = self.data.write().await;
+        let mut bloom_map = self.bloom_map.write().await;
+        let mut commit_log = self.commit_log.write().await;
+        // the memtable's transactions mustn't be replayed once it's cleared
+        for tx_id in &data.tx_ids {
+            commit_log.end_transaction(tx_id).await?;
+        }
+        if let Err(e) = commit_log.rotate().await {
+            tracing::warn!(path = ?commit_log.path(), "Failed to rotate commit log: {e}");
+        }
+        data.memtable = BTreeMap::new();
+        data.memtable_bytes = 0;
+        data.tx_ids = Vec::new();
+        for path in self.get_sstables_asc().await? {
+            fs::remove_file(&path).await?;
+        }
+        bloom_map.clear();
+        if self.bloom_map_path.exists() {
+            fs::remove_file(&self.bloom_map_path).await?;
+        }
+        tracing::info!(data_dir = ?self.data_dir, "Cleared store");
+        Ok(())
+    }
+
+    async fn transact(&mut self, transaction: Transaction) -> Result<Vec<bool>> {
         self.do_transact(transaction, true).await
     }
+
+    async fn swap(&mut self, a: &[u8], b: &[u8]) -> Result<()> {
+        let data = self.data.write().await;
+        self.check_pressure(&data)?;
+        let value_a = self.lookup(&data, a).await?;
+        let value_b = self.lookup(&data, b).await?;
+        let existed = vec![value_a.is_some(), value_b.is_some()];
+        let operation = |key: &[u8], value: Option<Vec<u8>>| match value {
+            Some(value) => Set(key.to_vec(), value),
+            None => Delete(key.to_vec()),
+        };
+        let transaction =
+            Transaction::with_random_id(vec![operation(a, value_b), operation(b, value_a)]);
+        self.commit_locked(data, transaction, existed).await
+    }
+
+    async fn apply(&mut self, k: &[u8], transform: &Transform) -> Result<Vec<u8>> {
+        let data = self.data.write().await;
+        self.check_pressure(&data)?;
+        let old = self.lookup(&data, k).await?;
+        let value = transform.apply(old.as_deref())?;
+        let transaction = Transaction::with_random_id(vec![Set(k.to_vec(), value.clone())]);
+        self.commit_locked(data, transaction, vec![old.is_some()])
+            .await?;
+        Ok(value)
+    }
+
+    async fn get_set(&mut self, k: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        let data = self.data.write().await;
+        self.check_pressure(&data)?;
+        let old = self.lookup(&data, k).await?;
+        let transaction = Transaction::with_random_id(vec![Set(k.to_vec(), value.to_vec())]);
+        self.commit_locked(data, transaction, vec![old.is_some()])
+            .await?;
+        Ok(old)
+    }
+}
+
+/// The entries of a range of keys, merged lazily from the memtable's and the SSTables'
+/// in key order, each key's newest entry shadowing the rest. Only the SSTables' indexes
+/// are read up front, see `LSMStore::open_sstables`.
+struct MergedRange<'a> {
+    memtable: Peekable<btree_map::Range<'a, Vec<u8>, Value>>,
+    // newest first
+    sstables: Vec<RangeIter>,
+}
+
+/// Where the newest entry of a key reached by `MergedRange` is
+enum Newest<'a> {
+    Memtable(&'a Value),
+    // the SSTable at this index of `MergedRange::sstables`, its entry left unread
+    SSTable(usize),
+}
+
+impl<'a> MergedRange<'a> {
+    fn new(memtable: btree_map::Range<'a, Vec<u8>, Value>, sstables: Vec<RangeIter>) -> Self {
+        Self {
+            memtable: memtable.peekable(),
+            sstables,
+        }
+    }
+
+    /// Takes the smallest key left, skipping the older entries of it, and returns it
+    /// along with where its newest entry is
+    fn take_key(&mut self) -> Option<(Vec<u8>, Newest<'a>)> {
+        let memtable = self.memtable.peek().copied();
+        let key = self
+            .sstables
+            .iter()
+            .filter_map(RangeIter::peek_key)
+            .chain(memtable.map(|(k, _)| k.as_slice()))
+            .min()?
+            .to_vec();
+        let mut newest = None;
+        if let Some((_, value)) = memtable.filter(|(k, _)| **k == key) {
+            self.memtable.next();
+            newest = Some(Newest::Memtable(value));
+        }
+        for (i, sstable) in self.sstables.iter_mut().enumerate() {
+            if sstable.peek_key() != Some(key.as_slice()) {
+                continue;
+            }
+            match newest {
+                Some(_) => {
+                    sstable.skip_entry();
+                }
+                None => newest = Some(Newest::SSTable(i)),
+            }
+        }
+        newest.map(|newest| (key, newest))
+    }
+
+    /// The next key along with whether its newest entry holds data, reading no values
+    fn next_key(&mut self) -> Option<(Vec<u8>, bool)> {
+        let (key, newest) = self.take_key()?;
+        let exists = match newest {
+            Newest::Memtable(value) => value.exists(),
+            Newest::SSTable(i) => self.sstables[i].skip_entry().unwrap_or(false),
+        };
+        Some((key, exists))
+    }
+
+    /// The next key along with its newest entry, which may be a tombstone
+    async fn next_entry(&mut self) -> Result<Option<(Vec<u8>, Value)>> {
+        let (key, newest) = match self.take_key() {
+            Some(next) => next,
+            None => return Ok(None),
+        };
+        let value = match newest {
+            Newest::Memtable(value) => value.clone(),
+            Newest::SSTable(i) => {
+                let (_, value) = self.sstables[i]
+                    .next_entry()
+                    .await?
+                    .ok_or("SSTable ended before its peeked entry")?;
+                value
+            }
+        };
+        Ok(Some((key, value)))
+    }
+}
+
+/// The compaction tier of an SSTable of `bytes`, see `LSMStore::compact_tiers`.
+fn compaction_tier(bytes: u64) -> u32 {
+    (bytes / COMPACTION_TIER_BASE_BYTES)
+        .max(1)
+        .ilog(COMPACTION_TIER_RATIO)
+}
+
+/// A key as it's hashed into bloom filters, the way `str` hashes so bloom maps
+/// persisted from when keys were strings still find their keys
+struct BloomKey<'a>(&'a [u8]);
+impl Hash for BloomKey<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write(self.0);
+        state.write_u8(0xff);
+    }
+}
+
+/// A bloom filter of an SSTable holding `keys`, sized for all of them up front. A
+/// filter outgrowing its estimated insertions adds a sub-filter each time it does, and
+/// each one adds to its false-positive rate, so a filter of many keys estimated at a
+/// few would let through several times `BLOOM_ERROR_PROB` of the keys it doesn't hold.
+fn bloom_filter<'a, I>(keys: I) -> GrowableBloom
+where
+    I: ExactSizeIterator<Item = &'a Vec<u8>>,
+{
+    let mut bloom = GrowableBloom::new(BLOOM_ERROR_PROB, keys.len().max(1));
+    for key in keys {
+        bloom.insert(BloomKey(key));
+    }
+    bloom
+}
+
+/// Approximate size of a memtable entry
+fn entry_bytes(key: &[u8], value: &Value) -> usize {
+    match value {
+        Data(data) => key.len() + data.len(),
+        Tombstone => key.len(),
+    }
+}
+
+/// Approximate number of bytes `transaction` writes to the memtable
+fn write_bytes(transaction: &Transaction) -> usize {
+    transaction
+        .operations
+        .iter()
+        .map(|op| match op {
+            Set(key, value) => key.len() + value.len(),
+            Delete(key) => key.len(),
+        })
+        .sum()
 }
 
 #[cfg(test)]
 mod tests {
     use std::{
+        collections::HashSet,
         env,
         path::{Path, PathBuf},
+        sync::Arc,
         time::Duration,
     };
 
     use assert_matches::assert_matches;
-    use tokio::{fs::DirBuilder, sync::mpsc, time::timeout};
+    use growable_bloom_filter::GrowableBloom;
+    use tokio::{
+        fs::{self, DirBuilder},
+        sync::{mpsc, Semaphore},
+        time::timeout,
+    };
     use uuid::Uuid;
 
     use crate::{
-        store::{Operation, Store, Transaction},
-        Result,
+        store::{conformance, transform::Transform, Durability, Operation, Store, Transaction},
+        Error, Result,
     };
 
-    use super::{LSMEvent, LSMStore};
+    use super::{
+        compaction_tier, BloomKey, LSMEvent, LSMStore, BLOOM_ERROR_PROB,
+        COMPACTION_TIER_BASE_BYTES, COMPACTION_TIER_RATIO,
+    };
 
     async fn test_data_dir() -> Result<PathBuf> {
         let data_dir = env::temp_dir().join(Uuid::new_v4().to_string());
@@ -511,14 +1447,18 @@ mod tests {
             .await?;
         assert_eq!(
             b"foobar".to_vec(),
-            store.get("foo").await?.expect("Could not find key")
+            store.get(b"foo").await?.expect("Could not find key")
         );
-        let LSMEvent::WriteSSTable(sstable_path) = timeout(Duration::from_secs(2), events.recv())
+        let sstable_path = match timeout(Duration::from_secs(2), events.recv())
             .await?
-            .expect("Error receiving event from LSM store");
+            .expect("Error receiving event from LSM store")
+        {
+            LSMEvent::WriteSSTable(path) => path,
+            e => panic!("unexpected event {e:?}"),
+        };
         assert_eq!(
             b"foobar".to_vec(),
-            store.get("foo").await?.expect("Could not find key")
+            store.get(b"foo").await?.expect("Could not find key")
         );
         let mut dir = tokio::fs::read_dir(store.data_dir.as_path()).await?;
         let mut sst_files = Vec::new();
@@ -549,42 +1489,1433 @@ mod tests {
         store.initialize().await?;
         assert_eq!(
             b"bar".to_vec(),
-            store.get("foo").await?.expect("Could not find key")
+            store.get(b"foo").await?.expect("Could not find key")
         );
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_shutdown_recovery() -> Result<()> {
+    async fn test_recover() -> Result<()> {
         let data_dir = self::test_data_dir().await?;
         {
-            let mut store = self::setup_db(data_dir.as_path(), 1000);
+            let mut store = LSMStore::recover(data_dir.as_path()).await?;
             store
                 .transact(Transaction::with_random_id(vec![
-                    (Operation::set("foo", b"bar")),
+                    Operation::set("flushed", b"old"),
+                    Operation::set("deleted", b"value"),
                 ]))
                 .await?;
-            LSMStore::shutdown(
-                store.data.clone(),
-                store.data_dir.as_path(),
-                store.bloom_map.clone(),
-                store.bloom_map_path.as_path(),
-                store.commit_log.clone(),
-                store.state.clone(),
-            )
-            .await?;
+            tokio::time::sleep(Duration::from_millis(2)).await;
+            store.flush().await?;
+            // the flush dropped the transactions it held from the commit log
+            assert!(store
+                .commit_log
+                .read()
+                .await
+                .get_unfinished_transactions()
+                .await?
+                .is_empty());
+            store
+                .transact(Transaction::with_random_id(vec![
+                    Operation::set("flushed", b"new"),
+                    Operation::set("unflushed", b"value"),
+                    Operation::delete("deleted"),
+                ]))
+                .await?;
+            // dropped without a flush or a shutdown, as if it crashed
+        }
+        let mut store = LSMStore::recover(data_dir.as_path()).await?;
+        assert_eq!(Some(b"new".to_vec()), store.get(b"flushed").await?);
+        assert_eq!(Some(b"value".to_vec()), store.get(b"unflushed").await?);
+        assert_eq!(None, store.get(b"deleted").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_durability_crash_recovery() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        let set = |k: &str, durability| {
+            Transaction::with_random_id(vec![Operation::set(k, b"bar")]).with_durability(durability)
+        };
+        {
+            // the store is never initialized, so the commit log is never synced
+            let mut store = self::setup_db(data_dir.as_path(), 1000);
+            store.transact(set("fsync", Durability::Fsync)).await?;
+            store.transact(set("batched", Durability::Batched)).await?;
+            store.transact(set("async", Durability::Async)).await?;
+        }
+        let mut store = self::setup_db(data_dir.as_path(), 1000);
+        store.initialize().await?;
+        assert_eq!(Some(b"bar".to_vec()), store.get(b"fsync").await?);
+        // batched writes are written to the log, only their fsync is delayed
+        assert_eq!(Some(b"bar".to_vec()), store.get(b"batched").await?);
+        assert_eq!(None, store.get(b"async").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_commit_log_sync() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        {
+            let mut store = self::setup_db(data_dir.as_path(), 1000);
+            store.commit_log_sync_interval = Some(Duration::from_millis(10));
+            store.initialize().await?;
+            store
+                .transact(
+                    Transaction::with_random_id(vec![Operation::set("async", b"bar")])
+                        .with_durability(Durability::Async),
+                )
+                .await?;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let mut store = self::setup_db(data_dir.as_path(), 1000);
+        store.initialize().await?;
+        assert_eq!(Some(b"bar".to_vec()), store.get(b"async").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_throttle() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        let mut store = self::setup_db(data_dir.as_path(), 1000);
+        store.write_throttle_bytes = Some(1000);
+        let set =
+            |k: String, len| Transaction::with_random_id(vec![Operation::set(k, &vec![0; len])]);
+
+        // flushes measure how fast the disk is
+        store.transact(set("a".to_string(), 99)).await?;
+        LSMStore::write_sstable(
+            store.data.clone(),
+            store.data_dir.as_path(),
+            &*store.disk,
+            store.bloom_map.clone(),
+            store.commit_log.clone(),
+        )
+        .await?;
+        assert!(store.data.read().await.flush_rate.is_some());
+        assert_eq!(0, store.data.read().await.memtable_bytes);
+        // then the disk slows down to 10KB/s
+        store.data.write().await.flush_rate = Some(10_000.0);
+
+        // the paused clock only advances past the delays writes sleep for
+        tokio::time::pause();
+
+        // writes under the throttle size aren't delayed
+        let start = tokio::time::Instant::now();
+        for i in 0..9 {
+            store.transact(set(i.to_string(), 99)).await?;
+        }
+        assert_eq!(900, store.data.read().await.memtable_bytes);
+        assert_eq!(Duration::ZERO, start.elapsed());
+
+        // past it, each is delayed by the 10ms the disk takes to flush its 100 bytes
+        let start = tokio::time::Instant::now();
+        for i in 0..10 {
+            store.transact(set(format!("a{i}"), 98)).await?;
+        }
+        // give or take the timer rounding each delay up to the next millisecond
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(100), "{elapsed:?}");
+        assert!(elapsed <= Duration::from_millis(110), "{elapsed:?}");
+        // overwrites replace the size of the value they overwrite
+        store.transact(set("a0".to_string(), 8)).await?;
+        assert_eq!(1810, store.data.read().await.memtable_bytes);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_reject() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        let mut store = self::setup_db(data_dir.as_path(), 10_000);
+        store.write_reject_bytes = Some(1000);
+        let set = |k: &str| Transaction::with_random_id(vec![Operation::set(k, &[0; 499])]);
+
+        store.transact(set("a")).await?;
+        store.transact(set("b")).await?;
+        // once the memtable reaches the limit every kind of write is turned away
+        assert_matches!(store.transact(set("c")).await, Err(Error::Overloaded(_)));
+        assert_matches!(store.swap(b"a", b"b").await, Err(Error::Overloaded(_)));
+        let add = Transform::parse("add", b"1")?;
+        assert_matches!(store.apply(b"n", &add).await, Err(Error::Overloaded(_)));
+        assert_eq!(None, store.get(b"c").await?);
+
+        // and taken again once a flush catches up
+        self::flush(&store).await?;
+        store.transact(set("c")).await?;
+        assert_eq!(Some(vec![0; 499]), store.get(b"c").await?);
+
+        // concurrent writes can't all be let through before any of them is applied
+        self::flush(&store).await?;
+        let writes = (0..10).map(|i| {
+            let mut store = store.clone();
+            tokio::spawn(async move { store.transact(set(&format!("w{i}"))).await })
+        });
+        let accepted = futures::future::join_all(writes)
+            .await
+            .into_iter()
+            .filter(|write| write.as_ref().unwrap().is_ok())
+            .count();
+        assert_eq!(2, accepted);
+        assert_eq!(2 * 501, store.data.read().await.memtable_bytes);
+        assert_eq!(0, store.data.read().await.reserved_bytes);
+
+        // rejected writes were never logged, so they aren't replayed on restart
+        drop(store);
+        let mut store = self::setup_db(data_dir.as_path(), 10_000);
+        store.initialize().await?;
+        assert_eq!(None, store.get(b"n").await?);
+        assert_eq!(Some(vec![0; 499]), store.get(b"c").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_recovery() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        {
+            let mut store = self::setup_db(data_dir.as_path(), 1000);
+            store
+                .transact(Transaction::with_random_id(vec![
+                    (Operation::set("foo", b"bar")),
+                ]))
+                .await?;
+            LSMStore::shutdown(
+                store.data.clone(),
+                store.data_dir.as_path(),
+                &*store.disk,
+                store.bloom_map.clone(),
+                store.bloom_map_path.as_path(),
+                store.commit_log.clone(),
+                store.state.clone(),
+            )
+            .await?;
         }
         let mut store = self::setup_db(data_dir.as_path(), 1000);
         store.initialize().await?;
         assert_eq!(
             b"bar".to_vec(),
-            store.get("foo").await?.expect("Could not find key")
+            store.get(b"foo").await?.expect("Could not find key")
         );
         let reconstructed_bloom = store.reconstruct_bloom_map_from_sstables().await?;
         assert_eq!(1, reconstructed_bloom.len());
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_binary_keys() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        let key = b"a\xff\x00b";
+        {
+            let mut store = self::setup_db(data_dir.as_path(), 1000);
+            store
+                .transact(Transaction::with_random_id(vec![
+                    Operation::set(key.as_slice(), b"bar"),
+                    Operation::set(b"a\xff".as_slice(), b"prefix"),
+                ]))
+                .await?;
+            self::flush(&store).await?;
+            assert_eq!(1, store.sstables_for_key(key).await.len());
+        }
+        // the key survives being written to an SSTable and read back on restart
+        let mut store = self::setup_db(data_dir.as_path(), 1000);
+        store.initialize().await?;
+        assert_eq!(Some(b"bar".to_vec()), store.get(key).await?);
+        assert_eq!(None, store.get(b"a\xff\x00").await?);
+        assert_eq!(
+            vec![
+                (b"a\xff".to_vec(), b"prefix".to_vec()),
+                (key.to_vec(), b"bar".to_vec())
+            ],
+            store.scan_entries(b"a\xff", None, 10).await?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_bloom_key_hashes_like_str() {
+        // bloom maps persisted while keys were strings must still find them
+        let mut bloom = GrowableBloom::new(BLOOM_ERROR_PROB, 1);
+        bloom.insert("foo".to_string());
+        assert!(bloom.contains(BloomKey(b"foo")));
+        assert!(!bloom.contains(b"foo".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_bloom_false_positive_rate() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        let mut store = self::setup_db(data_dir.as_path(), usize::MAX);
+        let n = 50_000;
+        let sets = (0..n).map(|i| Operation::set(format!("key:{i}"), b"1"));
+        store
+            .transact(Transaction::with_random_id(sets.collect()))
+            .await?;
+        self::flush(&store).await?;
+
+        // the SSTable's filter holds every key it does, and lets through about as many
+        // of the keys it doesn't as it's meant to, however many keys there are. As is
+        // the one rebuilt from the SSTable on restart.
+        let rebuilt = store.reconstruct_bloom_map_from_sstables().await?;
+        for bloom_map in [store.bloom_map.read().await.clone(), rebuilt] {
+            assert_eq!(1, bloom_map.len());
+            let bloom = bloom_map.values().next().unwrap();
+            assert!((0..n).all(|i| bloom.contains(BloomKey(format!("key:{i}").as_bytes()))));
+            let false_positives = (0..n)
+                .filter(|i| bloom.contains(BloomKey(format!("missing:{i}").as_bytes())))
+                .count();
+            let rate = false_positives as f64 / n as f64;
+            assert!(rate < BLOOM_ERROR_PROB * 1.5, "{rate}");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transact_existing_keys() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        let mut store = self::setup_db(data_dir.as_path(), 1000);
+        assert_eq!(
+            vec![false],
+            store
+                .transact(Transaction::with_random_id(vec![Operation::set(
+                    "foo", b"bar"
+                )]))
+                .await?
+        );
+        assert_eq!(
+            vec![true],
+            store
+                .transact(Transaction::with_random_id(vec![Operation::set(
+                    "foo", b"baz"
+                )]))
+                .await?
+        );
+        LSMStore::write_sstable(
+            store.data.clone(),
+            store.data_dir.as_path(),
+            &*store.disk,
+            store.bloom_map.clone(),
+            store.commit_log.clone(),
+        )
+        .await?;
+        // keys that only exist in an sstable still count as existing
+        assert_eq!(
+            vec![true, false],
+            store
+                .transact(Transaction::with_random_id(vec![
+                    Operation::set("foo", b"qux"),
+                    Operation::set("bar", b"qux"),
+                ]))
+                .await?
+        );
+        // a tombstoned key no longer exists
+        assert_eq!(
+            vec![true, false],
+            store
+                .transact(Transaction::with_random_id(vec![
+                    Operation::delete("foo"),
+                    Operation::delete("foo"),
+                ]))
+                .await?
+        );
+
+        // an SSTable that can't be read fails the write before it's logged or applied
+        let path = store.flush().await?.expect("nothing was flushed");
+        fs::remove_file(&path).await?;
+        let failed = store
+            .transact(Transaction::with_random_id(vec![Operation::set(
+                "bar", b"lost",
+            )]))
+            .await;
+        assert!(failed.is_err());
+        assert!(store.data.read().await.memtable.is_empty());
+        assert!(store
+            .commit_log
+            .read()
+            .await
+            .get_unfinished_transactions()
+            .await?
+            .is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_swap() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        let mut store = self::setup_db(data_dir.as_path(), 1000);
+        store
+            .transact(Transaction::with_random_id(vec![Operation::set(
+                "foo", b"first",
+            )]))
+            .await?;
+        LSMStore::write_sstable(
+            store.data.clone(),
+            store.data_dir.as_path(),
+            &*store.disk,
+            store.bloom_map.clone(),
+            store.commit_log.clone(),
+        )
+        .await?;
+        store
+            .transact(Transaction::with_random_id(vec![Operation::set(
+                "bar", b"second",
+            )]))
+            .await?;
+        // values are swapped whether they live in the memtable or an sstable
+        store.swap(b"foo", b"bar").await?;
+        assert_eq!(Some(b"second".to_vec()), store.get(b"foo").await?);
+        assert_eq!(Some(b"first".to_vec()), store.get(b"bar").await?);
+        // an absent key is treated as null
+        store.swap(b"bar", b"baz").await?;
+        assert_eq!(None, store.get(b"bar").await?);
+        assert_eq!(Some(b"first".to_vec()), store.get(b"baz").await?);
+        // swapping a key with itself is a no-op
+        store.swap(b"foo", b"foo").await?;
+        assert_eq!(Some(b"second".to_vec()), store.get(b"foo").await?);
+        // swaps are logged so they survive a crash
+        let mut store = self::setup_db(data_dir.as_path(), 1000);
+        store.initialize().await?;
+        assert_eq!(Some(b"second".to_vec()), store.get(b"foo").await?);
+        assert_eq!(None, store.get(b"bar").await?);
+        assert_eq!(Some(b"first".to_vec()), store.get(b"baz").await?);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_swap_atomic() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        let mut store = self::setup_db(data_dir.as_path(), 1000);
+        store
+            .transact(Transaction::with_random_id(vec![
+                Operation::set("a", b"x"),
+                Operation::set("b", b"y"),
+            ]))
+            .await?;
+        let mut swapper = store.clone();
+        let swaps = tokio::spawn(async move {
+            for _ in 0..500 {
+                swapper.swap(b"a", b"b").await?;
+            }
+            Result::Ok(())
+        });
+        // a reader never sees both keys holding the same value
+        let swapped = vec![b"y".to_vec(), b"x".to_vec()];
+        let unswapped = vec![b"x".to_vec(), b"y".to_vec()];
+        for _ in 0..500 {
+            let values = store.scan(b"a", b"c").await?;
+            assert!(values == swapped || values == unswapped, "{values:?}");
+        }
+        swaps.await.expect("swap task panicked")?;
+        assert_eq!(unswapped, store.scan(b"a", b"c").await?);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_apply() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        let mut store = self::setup_db(data_dir.as_path(), 1000);
+        let add = Transform::Add(1);
+        // concurrent adds never lose an update
+        let mut adders = vec![];
+        for _ in 0..2 {
+            let mut adder = store.clone();
+            let add = add.clone();
+            adders.push(tokio::spawn(async move {
+                for _ in 0..250 {
+                    adder.apply(b"n", &add).await?;
+                }
+                Result::Ok(())
+            }));
+        }
+        for adder in adders {
+            adder.await.expect("add task panicked")?;
+        }
+        assert_eq!(Some(b"500".to_vec()), store.get(b"n").await?);
+
+        // transforms apply to flushed values too, and a failed one writes nothing
+        store
+            .transact(Transaction::with_random_id(vec![Operation::set(
+                "s", b"foo",
+            )]))
+            .await?;
+        self::flush(&store).await?;
+        assert_matches!(store.apply(b"s", &add).await, Err(Error::Transform(_)));
+        assert_eq!(
+            b"foobar".to_vec(),
+            store
+                .apply(b"s", &Transform::Append(b"bar".to_vec()))
+                .await?
+        );
+        assert_eq!(Some(b"foobar".to_vec()), store.get(b"s").await?);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_get_set() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        let mut store = self::setup_db(data_dir.as_path(), 1000);
+        // concurrent GETSETs chain one after another, so the absent value and every value
+        // written are each replaced exactly once, except the last one standing
+        let mut setters = vec![];
+        for setter_id in 0..2 {
+            let mut setter = store.clone();
+            setters.push(tokio::spawn(async move {
+                let mut replaced = vec![];
+                for i in 0..250 {
+                    let value = format!("{setter_id}:{i}").into_bytes();
+                    replaced.push(setter.get_set(b"n", &value).await?);
+                }
+                Result::Ok(replaced)
+            }));
+        }
+        let mut replaced = vec![];
+        for setter in setters {
+            replaced.extend(setter.await.expect("get_set task panicked")?);
+        }
+        replaced.push(store.get(b"n").await?);
+        replaced.sort();
+        let mut written = (0..2)
+            .flat_map(|setter_id| {
+                (0..250).map(move |i| Some(format!("{setter_id}:{i}").into_bytes()))
+            })
+            .collect::<Vec<_>>();
+        written.push(None);
+        written.sort();
+        assert_eq!(written, replaced);
+
+        // the replaced value is read from the SSTables once flushed, and tombstones too
+        self::flush(&store).await?;
+        let last = store.get(b"n").await?;
+        assert_eq!(last, store.get_set(b"n", b"new").await?);
+        store
+            .transact(Transaction::with_random_id(vec![Operation::delete("n")]))
+            .await?;
+        self::flush(&store).await?;
+        assert_eq!(None, store.get_set(b"n", b"again").await?);
+        assert_eq!(Some(b"again".to_vec()), store.get(b"n").await?);
+        Ok(())
+    }
+
+    async fn flush(store: &LSMStore) -> Result<()> {
+        // SSTables are named by millisecond, make sure back-to-back flushes don't collide
+        std::thread::sleep(Duration::from_millis(2));
+        LSMStore::write_sstable(
+            store.data.clone(),
+            store.data_dir.as_path(),
+            &*store.disk,
+            store.bloom_map.clone(),
+            store.commit_log.clone(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_flush_round_trip() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        // large enough that only explicit flushes write SSTables
+        let mut store = self::setup_db(data_dir.as_path(), usize::MAX);
+        let mut events = store.events();
+        for i in 0..500 {
+            store
+                .transact(Transaction::with_random_id(vec![Operation::set(
+                    format!("key{i:03}"),
+                    format!("value{i}").as_bytes(),
+                )]))
+                .await?;
+        }
+        let path = store.flush().await?.expect("memtable wasn't flushed");
+        assert!(matches!(events.try_recv(), Ok(LSMEvent::WriteSSTable(p)) if p == path));
+        assert!(store.data.read().await.memtable.is_empty());
+        assert_eq!(None, store.flush().await?);
+        for i in 0..500 {
+            let key = format!("key{i:03}");
+            assert_eq!(
+                vec![path.clone()],
+                store.sstables_for_key(key.as_bytes()).await
+            );
+            assert_eq!(
+                Some(format!("value{i}").into_bytes()),
+                store.get(key.as_bytes()).await?
+            );
+        }
+        // and the SSTable's keys are sorted, as the memtable kept them
+        let mut segment = LSMStore::iter_segment(&path).await?;
+        let mut keys = vec![];
+        while let Some((key, _)) = segment.next_entry().await? {
+            keys.push(key);
+        }
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(500, keys.len());
+        assert_eq!(sorted, keys);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_disk_full_flush() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        let mut store = self::setup_db(data_dir.as_path(), 1000);
+        let disk = super::sstable::tests::FullDisk::default();
+        store.disk = Arc::new(disk.clone());
+        store
+            .transact(Transaction::with_random_id(vec![Operation::set(
+                "foo", b"bar",
+            )]))
+            .await?;
+        disk.full.store(true, std::sync::atomic::Ordering::SeqCst);
+        let res = self::flush(&store).await;
+        assert!(matches!(res, Err(Error::DiskFull(_))), "{res:?}");
+        // no partial SSTable was left behind, and the memtable still serves reads
+        let mut entries = tokio::fs::read_dir(&data_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            assert!(!name.contains(".sst"), "{name}");
+        }
+        assert_eq!(Some(b"bar".to_vec()), store.get(b"foo").await?);
+
+        // once space is freed the same memtable flushes, and writes are taken again
+        disk.full.store(false, std::sync::atomic::Ordering::SeqCst);
+        self::flush(&store).await?;
+        assert_eq!(1, store.sstables_for_key(b"foo").await.len());
+        store
+            .transact(Transaction::with_random_id(vec![Operation::set(
+                "baz", b"qux",
+            )]))
+            .await?;
+        assert_eq!(Some(b"bar".to_vec()), store.get(b"foo").await?);
+        assert_eq!(Some(b"qux".to_vec()), store.get(b"baz").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_unflushed() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        let mut store = self::setup_db(data_dir.as_path(), 1000);
+        store
+            .transact(Transaction::with_random_id(vec![Operation::set(
+                "foo", b"bar",
+            )]))
+            .await?;
+        store
+            .transact(Transaction::with_random_id(vec![Operation::delete("foo")]))
+            .await?;
+        assert_eq!(None, store.get(b"foo").await?);
+        // the tombstone was never flushed, so no SSTable knows about it
+        assert!(store.sstables_for_key(b"foo").await.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_flushed() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        let mut store = self::setup_db(data_dir.as_path(), 1000);
+        store
+            .transact(Transaction::with_random_id(vec![Operation::set(
+                "foo", b"bar",
+            )]))
+            .await?;
+        self::flush(&store).await?;
+        assert_eq!(1, store.sstables_for_key(b"foo").await.len());
+        store
+            .transact(Transaction::with_random_id(vec![Operation::delete("foo")]))
+            .await?;
+        // the memtable tombstone shadows the value in the SSTable
+        assert_eq!(None, store.get(b"foo").await?);
+        assert_eq!(Vec::<Vec<u8>>::new(), store.scan(b"a", b"z").await?);
+
+        // and so does the flushed tombstone, even though the bloom filters
+        // of both SSTables contain the key
+        self::flush(&store).await?;
+        assert_eq!(2, store.sstables_for_key(b"foo").await.len());
+        assert_eq!(None, store.get(b"foo").await?);
+        assert_eq!(Vec::<Vec<u8>>::new(), store.scan(b"a", b"z").await?);
+
+        // until the key is set again
+        store
+            .transact(Transaction::with_random_id(vec![Operation::set(
+                "foo", b"baz",
+            )]))
+            .await?;
+        assert_eq!(Some(b"baz".to_vec()), store.get(b"foo").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_iter_segments() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        let mut store = self::setup_db(data_dir.as_path(), 1000);
+        store
+            .transact(Transaction::with_random_id(vec![
+                Operation::set("foo", b"bar"),
+                Operation::set("baz", b""),
+                Operation::delete("qux"),
+            ]))
+            .await?;
+        self::flush(&store).await?;
+        store
+            .transact(Transaction::with_random_id(vec![Operation::delete("foo")]))
+            .await?;
+        self::flush(&store).await?;
+
+        let mut segments = vec![];
+        for (_, mut segment) in LSMStore::iter_segments(&data_dir).await? {
+            let mut entries = vec![];
+            while let Some(entry) = segment.next_entry().await? {
+                entries.push(entry);
+            }
+            segments.push(entries);
+        }
+        assert_eq!(
+            vec![
+                vec![
+                    (b"baz".to_vec(), super::Data(vec![])),
+                    (b"foo".to_vec(), super::Data(b"bar".to_vec())),
+                    (b"qux".to_vec(), super::Tombstone),
+                ],
+                vec![(b"foo".to_vec(), super::Tombstone)],
+            ],
+            segments
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compact() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        let mut store = self::setup_db(data_dir.as_path(), 1000);
+        store
+            .transact(Transaction::with_random_id(vec![
+                Operation::set("foo", b"old"),
+                Operation::set("bar", b"old"),
+                Operation::set("baz", b"old"),
+            ]))
+            .await?;
+        self::flush(&store).await?;
+        store
+            .transact(Transaction::with_random_id(vec![
+                Operation::set("foo", b"new"),
+                Operation::delete("bar"),
+            ]))
+            .await?;
+        self::flush(&store).await?;
+        store.compact().await?;
+
+        let sstables = store.get_sstables_asc().await?;
+        assert_eq!(1, sstables.len());
+        let entries = super::SSTable::new(&sstables[0]).scan(..).await?;
+        // the newest values survive and the tombstone is dropped
+        assert_eq!(
+            vec![
+                (b"baz".to_vec(), super::Data(b"old".to_vec())),
+                (b"foo".to_vec(), super::Data(b"new".to_vec())),
+            ],
+            entries
+        );
+        assert_eq!(Some(b"new".to_vec()), store.get(b"foo").await?);
+        assert_eq!(Some(b"old".to_vec()), store.get(b"baz").await?);
+        assert_eq!(1, store.bloom_map.read().await.len());
+        Ok(())
+    }
+
+    /// Total size of the store's SSTables on disk
+    async fn sstable_bytes(store: &LSMStore) -> Result<u64> {
+        let mut bytes = 0;
+        for path in store.get_sstables_asc().await? {
+            bytes += tokio::fs::metadata(path).await?.len();
+        }
+        Ok(bytes)
+    }
+
+    #[tokio::test]
+    async fn test_triggered_compaction() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        let mut store = self::setup_db(data_dir.as_path(), usize::MAX);
+        store.compaction_trigger = Some(4);
+        let mut events = store.events();
+        // each round rewrites every key and deletes the previous round's extra key,
+        // so all but the last round's SSTable is garbage
+        for round in 0..4 {
+            let mut ops = (0..50)
+                .map(|i| Operation::set(format!("key{i:02}"), format!("value{round}").as_bytes()))
+                .collect::<Vec<_>>();
+            ops.push(Operation::set(format!("extra{round}"), b"value"));
+            if round > 0 {
+                ops.push(Operation::delete(format!("extra{}", round - 1)));
+            }
+            store.transact(Transaction::with_random_id(ops)).await?;
+            // SSTables are named by millisecond, make sure back-to-back flushes don't collide
+            tokio::time::sleep(Duration::from_millis(2)).await;
+            store.flush().await?;
+        }
+        // up to the trigger, nothing is compacted
+        assert_eq!(4, store.get_sstables_asc().await?.len());
+        let before = self::sstable_bytes(&store).await?;
+
+        // and past it, the flush merges every SSTable
+        store
+            .transact(Transaction::with_random_id(vec![Operation::delete(
+                "extra3",
+            )]))
+            .await?;
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        store.flush().await?;
+        let compacted = loop {
+            match timeout(Duration::from_secs(5), events.recv()).await {
+                Ok(Ok(LSMEvent::Compacted(path))) => break path,
+                Ok(Ok(LSMEvent::WriteSSTable(_))) => continue,
+                e => panic!("no compaction: {e:?}"),
+            }
+        };
+        assert_eq!(
+            compacted.into_iter().collect::<Vec<_>>(),
+            store.get_sstables_asc().await?
+        );
+        let after = self::sstable_bytes(&store).await?;
+        assert!(after * 3 < before, "{before} bytes compacted to {after}");
+
+        for i in 0..50 {
+            let key = format!("key{i:02}");
+            assert_eq!(Some(b"value3".to_vec()), store.get(key.as_bytes()).await?);
+        }
+        for round in 0..4 {
+            assert_eq!(None, store.get(format!("extra{round}").as_bytes()).await?);
+        }
+        // the tombstones were dropped along with the values they shadowed
+        let entries = super::SSTable::new(&store.get_sstables_asc().await?[0])
+            .scan(..)
+            .await?;
+        assert_eq!(50, entries.len());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_compact_tiers() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        let mut store = self::setup_db(data_dir.as_path(), usize::MAX);
+        store.compaction_slots = Arc::new(Semaphore::new(4));
+        // the band of sizes SSTables of `tier` hold
+        let band = |tier: u32| {
+            let lower = match tier {
+                0 => 0,
+                _ => COMPACTION_TIER_BASE_BYTES * COMPACTION_TIER_RATIO.pow(tier),
+            };
+            lower..COMPACTION_TIER_BASE_BYTES * COMPACTION_TIER_RATIO.pow(tier + 1)
+        };
+        // values just over the base size, so merging 4 of them makes an SSTable a
+        // tier up
+        let value = vec![b'v'; COMPACTION_TIER_BASE_BYTES as usize + 100_000];
+        // 32 SSTables of the same tier, each overwriting the shared keys
+        // and deleting the previous one's own key
+        for i in 0..32 {
+            let mut ops = vec![
+                Operation::set(format!("shared{}", i % 4), format!("{i}").as_bytes()),
+                Operation::set(format!("own{i}"), &value),
+            ];
+            if i % 2 == 1 {
+                ops.push(Operation::delete(format!("own{}", i - 1)));
+            }
+            store.transact(Transaction::with_random_id(ops)).await?;
+            self::flush(&store).await?;
+        }
+        for path in store.get_sstables_asc().await? {
+            assert_eq!(0, compaction_tier(fs::metadata(&path).await?.len()));
+        }
+        let mut events = store.events();
+
+        // concurrent calls split the SSTables between them instead of merging any
+        // twice. Merging at most 8 at a time takes at least 4 merges, more when a
+        // merged SSTable is claimed again by a call that's still running
+        let (a, b) = tokio::join!(store.compact_tiers(), store.compact_tiers());
+        let mut merges = a? + b?;
+        assert!(merges >= 4, "{merges} merges");
+        // and once there's nothing left to merge, no two SSTables next to each other
+        // share a tier, and each one's size is in its tier's band, every one of them
+        // a tier up from the SSTables merged into it
+        loop {
+            match store.compact_tiers().await? {
+                0 => break,
+                n => merges += n,
+            }
+        }
+        for _ in 0..merges {
+            assert_matches!(events.try_recv(), Ok(LSMEvent::Compacted(Some(_))));
+        }
+        assert!(store.compacting.lock().await.is_empty());
+        let sstables = store.get_sstables_asc().await?;
+        assert!(sstables.len() <= 4, "{} SSTables", sstables.len());
+        let mut tiers = vec![];
+        for path in &sstables {
+            let bytes = fs::metadata(path).await?.len();
+            let tier = compaction_tier(bytes);
+            assert!(tier >= 1, "{path:?} of {bytes} bytes");
+            assert!(band(tier).contains(&bytes), "{path:?} of {bytes} bytes");
+            tiers.push(tier);
+        }
+        assert!(tiers.windows(2).all(|w| w[0] != w[1]), "{tiers:?}");
+        assert_eq!(
+            sstables.iter().cloned().collect::<HashSet<_>>(),
+            store.bloom_map.read().await.keys().cloned().collect()
+        );
+
+        let value = &value;
+        let check = |mut store: LSMStore| async move {
+            for i in 0..4 {
+                let expected = format!("{}", 28 + i);
+                let key = format!("shared{i}");
+                assert_eq!(
+                    Some(expected.into_bytes()),
+                    store.get(key.as_bytes()).await?
+                );
+            }
+            for i in 0..32 {
+                let expected = (i % 2 == 1).then(|| value.clone());
+                assert_eq!(
+                    expected,
+                    store.get(format!("own{i}").as_bytes()).await?,
+                    "own{i}"
+                );
+            }
+            Ok::<_, Error>(())
+        };
+        check(store.clone()).await?;
+        // tombstones outside the oldest tier are kept until a full compaction
+        store.compact().await?;
+        assert_eq!(1, store.get_sstables_asc().await?.len());
+        check(store).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bulk_load() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        let entries = (0..1000)
+            .map(|i| {
+                (
+                    format!("key{i:04}").into_bytes(),
+                    format!("value{i}").into_bytes(),
+                )
+            })
+            .collect::<Vec<_>>();
+        {
+            let mut store = self::setup_db(data_dir.as_path(), 1000);
+            let path = store.bulk_load(entries.clone()).await?;
+            assert_eq!(
+                store.get_sstables_asc().await?,
+                path.into_iter().collect::<Vec<_>>()
+            );
+            for (k, v) in &entries {
+                assert_eq!(Some(v.clone()), store.get(k).await?);
+            }
+            // keys must be sorted
+            let unsorted = vec![
+                (b"b".to_vec(), b"b".to_vec()),
+                (b"a".to_vec(), b"a".to_vec()),
+            ];
+            assert!(store.bulk_load(unsorted).await.is_err());
+
+            store
+                .transact(Transaction::with_random_id(vec![
+                    Operation::set("key0001", b"updated"),
+                    Operation::set("new", b"value"),
+                ]))
+                .await?;
+            assert_eq!(Some(b"updated".to_vec()), store.get(b"key0001").await?);
+            assert_eq!(Some(b"value".to_vec()), store.get(b"new").await?);
+            // the store is no longer idle
+            assert!(store.bulk_load(entries.clone()).await.is_err());
+        }
+        // bulk loaded data survives a restart without going through the commit log
+        let mut store = self::setup_db(data_dir.as_path(), 1000);
+        store.initialize().await?;
+        assert_eq!(Some(b"value999".to_vec()), store.get(b"key0999").await?);
+        assert_eq!(Some(b"updated".to_vec()), store.get(b"key0001").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_compaction() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        let mut store = self::setup_db(data_dir.as_path(), 1000);
+        let cadence = Duration::from_secs(600);
+        store.compaction_interval = Some(cadence);
+        for value in [b"first", b"secnd"] {
+            store
+                .transact(Transaction::with_random_id(vec![Operation::set(
+                    "foo", value,
+                )]))
+                .await?;
+            self::flush(&store).await?;
+        }
+        let mut events = store.events();
+        store.initialize().await?;
+        tokio::time::pause();
+        let start = tokio::time::Instant::now();
+
+        // nothing runs before the first interval has passed
+        tokio::time::advance(cadence - Duration::from_secs(1)).await;
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert!(events.try_recv().is_err());
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_matches!(events.recv().await, Ok(LSMEvent::Compacted(Some(_))));
+        assert!(tokio::time::Instant::now() >= start + cadence);
+        assert_eq!(1, store.get_sstables_asc().await?.len());
+        assert_eq!(Some(b"secnd".to_vec()), store.get(b"foo").await?);
+
+        // and it keeps running at the configured cadence
+        store
+            .transact(Transaction::with_random_id(vec![Operation::set(
+                "foo", b"third",
+            )]))
+            .await?;
+        self::flush(&store).await?;
+        tokio::time::advance(cadence).await;
+        assert_matches!(events.recv().await, Ok(LSMEvent::Compacted(Some(_))));
+        assert!(tokio::time::Instant::now() >= start + cadence * 2);
+        assert_eq!(1, store.get_sstables_asc().await?.len());
+        assert_eq!(Some(b"third".to_vec()), store.get(b"foo").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_compaction_postponed() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        let mut store = self::setup_db(data_dir.as_path(), 1000);
+        let cadence = Duration::from_secs(600);
+        let quiet = Duration::from_secs(5);
+        store.compaction_interval = Some(cadence);
+        store.compaction_quiet_period = quiet;
+        for value in [b"first", b"secnd"] {
+            store
+                .transact(Transaction::with_random_id(vec![Operation::set(
+                    "foo", value,
+                )]))
+                .await?;
+            self::flush(&store).await?;
+        }
+        let mut events = store.events();
+        store.initialize().await?;
+        tokio::time::pause();
+        let start = tokio::time::Instant::now();
+
+        // a write just before the interval passes postpones the run
+        tokio::time::advance(cadence - Duration::from_secs(1)).await;
+        store
+            .transact(Transaction::with_random_id(vec![Operation::set(
+                "bar", b"third",
+            )]))
+            .await?;
+        tokio::time::advance(Duration::from_secs(1)).await;
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert!(events.try_recv().is_err());
+
+        // until the store has gone the quiet period without one
+        assert_matches!(events.recv().await, Ok(LSMEvent::Compacted(Some(_))));
+        assert!(tokio::time::Instant::now() >= start + cadence - Duration::from_secs(1) + quiet);
+        assert_eq!(1, store.get_sstables_asc().await?.len());
+        assert_eq!(Some(b"secnd".to_vec()), store.get(b"foo").await?);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_cas_races() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        let mut store = self::setup_db(data_dir.as_path(), 1000);
+        conformance::assert_cas_races(&mut store).await
+    }
+
+    #[tokio::test]
+    async fn test_scan_sorted() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        let mut store = self::setup_db(data_dir.as_path(), 1000);
+        // spread the keys across SSTables and the memtable
+        let batches = conformance::unordered_batches();
+        let unflushed = batches.len() - 2;
+        for (i, transaction) in batches.into_iter().enumerate() {
+            store.transact(transaction).await?;
+            if i < unflushed {
+                self::flush(&store).await?;
+            }
+        }
+        conformance::assert_scans_sorted(&mut store).await?;
+        store.compact().await?;
+        conformance::assert_scans_sorted(&mut store).await
+    }
+
+    #[tokio::test]
+    async fn test_entry_semantics() -> Result<()> {
+        // tombstones in the memtable
+        let data_dir = self::test_data_dir().await?;
+        let mut store = self::setup_db(data_dir.as_path(), 1000);
+        for transaction in conformance::lifecycle_batches() {
+            store.transact(transaction).await?;
+        }
+        conformance::assert_entry_semantics(&mut store).await?;
+
+        // and flushed to SSTables, each batch shadowing the ones before it
+        let data_dir = self::test_data_dir().await?;
+        let mut store = self::setup_db(data_dir.as_path(), 1000);
+        for transaction in conformance::lifecycle_batches() {
+            store.transact(transaction).await?;
+            self::flush(&store).await?;
+        }
+        conformance::assert_entry_semantics(&mut store).await
+    }
+
+    #[tokio::test]
+    async fn test_disk_read_limit() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        let mut store = self::setup_db(data_dir.as_path(), 1000);
+        let slots = Arc::new(Semaphore::new(4));
+        store.disk_reads = Some(slots.clone());
+        for i in 0..8 {
+            store
+                .transact(Transaction::with_random_id(vec![Operation::set(
+                    format!("disk{i}"),
+                    &[b'x'; 64 * 1024],
+                )]))
+                .await?;
+            self::flush(&store).await?;
+        }
+        store
+            .transact(Transaction::with_random_id(vec![Operation::set(
+                "memory", b"value",
+            )]))
+            .await?;
+
+        // with every slot taken reads from disk wait their turn, while reads
+        // served from the memtable don't touch a file and don't have to
+        let taken = slots.clone().acquire_many_owned(4).await.unwrap();
+        let mut reader = store.clone();
+        let mut read = tokio::spawn(async move { reader.get(b"disk0").await });
+        assert_eq!(Some(b"value".to_vec()), store.get(b"memory").await?);
+        assert!(timeout(Duration::from_millis(50), &mut read).await.is_err());
+        drop(taken);
+        let value = timeout(Duration::from_secs(1), read)
+            .await
+            .unwrap()
+            .unwrap()?;
+        assert_eq!(Some(vec![b'x'; 64 * 1024]), value);
+
+        // far more concurrent reads than slots all complete, at most 4 at a time
+        let reads = (0..256)
+            .map(|i| {
+                let mut store = store.clone();
+                tokio::spawn(async move { store.get(format!("disk{}", i % 8).as_bytes()).await })
+            })
+            .collect::<Vec<_>>();
+        for read in reads {
+            assert_eq!(Some(vec![b'x'; 64 * 1024]), read.await.unwrap()?);
+        }
+        assert_eq!(4, slots.available_permits());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_scan_entries() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        let mut store = self::setup_db(data_dir.as_path(), usize::MAX);
+        let entry = |k: &str, v: &[u8]| (k.as_bytes().to_vec(), v.to_vec());
+        store
+            .transact(Transaction::with_random_id(vec![
+                Operation::set("user:1", b"ada"),
+                Operation::set("user:2", b"bob"),
+                Operation::set("user:3", b"cy"),
+                Operation::set("users", b"other"),
+                Operation::set("team:1", b"red"),
+            ]))
+            .await?;
+        self::flush(&store).await?;
+        // the memtable's writes and deletes shadow the flushed entries
+        store
+            .transact(Transaction::with_random_id(vec![
+                Operation::delete("user:2"),
+                Operation::set("user:3", b"dee"),
+                Operation::set("user:4", b"eve"),
+            ]))
+            .await?;
+
+        // a prefix, up to the next key that doesn't share it
+        assert_eq!(
+            vec![
+                entry("user:1", b"ada"),
+                entry("user:3", b"dee"),
+                entry("user:4", b"eve")
+            ],
+            store
+                .scan_entries(b"user:", Some(b"user;".as_slice()), 10)
+                .await?
+        );
+        // the limit counts live keys, the deleted one doesn't take up a place
+        assert_eq!(
+            vec![entry("user:1", b"ada"), entry("user:3", b"dee")],
+            store
+                .scan_entries(b"user:", Some(b"user;".as_slice()), 2)
+                .await?
+        );
+        assert_eq!(
+            vec![entry("user:4", b"eve"), entry("users", b"other")],
+            store.scan_entries(b"user:4", None, 10).await?
+        );
+        // empty ranges, and ones ending before they start
+        assert!(store
+            .scan_entries(b"user:2", Some(b"user:3".as_slice()), 10)
+            .await?
+            .is_empty());
+        assert!(store.scan_entries(b"zz", None, 10).await?.is_empty());
+        assert!(store
+            .scan_entries(b"b", Some(b"a".as_slice()), 10)
+            .await?
+            .is_empty());
+        assert!(store.scan_entries(b"", None, 0).await?.is_empty());
+
+        // values are only read up to the limit, garbling the last one flushed goes
+        // unnoticed until it's reached
+        let path = store.get_sstables_asc().await?.remove(0);
+        let mut contents = fs::read(&path).await?;
+        let len = contents.len();
+        // the tag of `users`' value, which is followed by its length and 5 bytes
+        contents[len - 17..len - 13].fill(0xff);
+        fs::write(&path, contents).await?;
+        assert_eq!(
+            vec![
+                entry("user:1", b"ada"),
+                entry("user:3", b"dee"),
+                entry("user:4", b"eve")
+            ],
+            store.scan_entries(b"user:", None, 3).await?
+        );
+        assert!(store.scan_entries(b"user:", None, 4).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_clear() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        {
+            let mut store = LSMStore::recover(data_dir.as_path()).await?;
+            store
+                .transact(Transaction::with_random_id(vec![
+                    Operation::set("flushed", b"1"),
+                    Operation::set("shadowed", b"1"),
+                ]))
+                .await?;
+            store.flush().await?;
+            store
+                .transact(Transaction::with_random_id(vec![
+                    Operation::set("unflushed", b"2"),
+                    Operation::set("shadowed", b"2"),
+                ]))
+                .await?;
+            store.clear().await?;
+            for k in [&b"flushed"[..], b"shadowed", b"unflushed"] {
+                assert_eq!(None, store.get(k).await?);
+            }
+            assert!(store.get_sstables_asc().await?.is_empty());
+            assert!(store.scan_keys(b"", None, 10).await?.is_empty());
+            store
+                .transact(Transaction::with_random_id(vec![Operation::set(
+                    "after", b"3",
+                )]))
+                .await?;
+        }
+
+        // neither the removed SSTables nor the cleared memtable's commit log come back
+        let mut store = LSMStore::recover(data_dir.as_path()).await?;
+        assert_eq!(
+            vec![b"after".to_vec()],
+            store.scan_keys(b"", None, 10).await?
+        );
+        assert_eq!(Some(b"3".to_vec()), store.get(b"after").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_scan_keys() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        let mut store = self::setup_db(data_dir.as_path(), usize::MAX);
+        let key = |i: usize| format!("key:{i:04}").into_bytes();
+        let flushed = (0..1000).map(|i| Operation::set(key(i), b"v")).collect();
+        store.transact(Transaction::with_random_id(flushed)).await?;
+        self::flush(&store).await?;
+        // every third key is deleted in the memtable, and keys are added between them
+        let mut operations = (0..1000)
+            .step_by(3)
+            .map(|i| Operation::delete(key(i)))
+            .collect::<Vec<_>>();
+        operations.extend((1000..1200).map(|i| Operation::set(key(i), b"v")));
+        store
+            .transact(Transaction::with_random_id(operations))
+            .await?;
+        // some of those tombstones are flushed to an SSTable of their own, shadowing the
+        // first, and others are shadowed in turn by the memtable
+        self::flush(&store).await?;
+        let operations = (1..1000)
+            .step_by(3)
+            .map(|i| Operation::delete(key(i)))
+            .chain((0..1000).step_by(3).map(|i| Operation::set(key(i), b"v")))
+            .collect();
+        store
+            .transact(Transaction::with_random_id(operations))
+            .await?;
+
+        // resuming after the last key of each batch lists every live key once, in order
+        let mut scanned = vec![];
+        let mut start = vec![];
+        loop {
+            let keys = store.scan_keys(&start, None, 7).await?;
+            match keys.last() {
+                Some(last) => start = [last.as_slice(), &[0]].concat(),
+                None => break,
+            }
+            scanned.extend(keys);
+        }
+        let expected = (0..1200).filter(|i| *i >= 1000 || i % 3 != 1).map(key);
+        assert_eq!(expected.collect::<Vec<_>>(), scanned);
+        assert_eq!(
+            vec![key(0), key(2), key(3), key(5)],
+            store.scan_keys(b"key:0000", Some(b"key:0006"), 10).await?
+        );
+        assert!(store.scan_keys(b"key:2", None, 10).await?.is_empty());
+
+        // no values are read, garbling every SSTable's leaves their keys listable
+        for path in store.get_sstables_asc().await? {
+            let mut contents = fs::read(&path).await?;
+            let index_len = u64::from_be_bytes(contents[..8].try_into().unwrap());
+            contents[8 + index_len as usize..].fill(0xff);
+            fs::write(&path, contents).await?;
+        }
+        assert_eq!(scanned, store.scan_keys(&[], None, usize::MAX).await?);
+        assert!(store.scan_entries(&[], None, usize::MAX).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_exists() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        let mut store = self::setup_db(data_dir.as_path(), usize::MAX);
+        store
+            .transact(Transaction::with_random_id(vec![
+                Operation::set("present", b"v"),
+                Operation::set("flushed_delete", b"v"),
+                Operation::set("memtable_delete", b"v"),
+            ]))
+            .await?;
+        self::flush(&store).await?;
+        store
+            .transact(Transaction::with_random_id(vec![Operation::delete(
+                "flushed_delete",
+            )]))
+            .await?;
+        self::flush(&store).await?;
+        store
+            .transact(Transaction::with_random_id(vec![
+                Operation::delete("memtable_delete"),
+                Operation::set("memtable_only", b""),
+            ]))
+            .await?;
+
+        assert!(store.exists(b"present").await?);
+        assert!(store.exists(b"memtable_only").await?);
+        // tombstones shadow the older values, whether flushed or still in the memtable
+        assert!(!store.exists(b"flushed_delete").await?);
+        assert!(!store.exists(b"memtable_delete").await?);
+        // no bloom filter holds a key that was never written, so no SSTable is read
+        assert!(store.sstables_for_key(b"never_written").await.is_empty());
+        assert!(!store.exists(b"never_written").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_many() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        let mut store = self::setup_db(data_dir.as_path(), usize::MAX);
+        let key = |i: usize| format!("key:{i:03}").into_bytes();
+        // keys in two SSTables and the memtable, some deleted, every other one missing
+        let older = (0..100).step_by(2).map(|i| Operation::set(key(i), b"old"));
+        store
+            .transact(Transaction::with_random_id(older.collect()))
+            .await?;
+        self::flush(&store).await?;
+        let newer = (0..100).step_by(4).map(|i| Operation::set(key(i), b"new"));
+        store
+            .transact(Transaction::with_random_id(newer.collect()))
+            .await?;
+        self::flush(&store).await?;
+        let latest = (0..100).step_by(6).map(|i| match i % 12 {
+            0 => Operation::delete(key(i)),
+            _ => Operation::set(key(i), b"latest"),
+        });
+        store
+            .transact(Transaction::with_random_id(latest.collect()))
+            .await?;
+
+        // values come back in the order of the keys asked for, duplicates and misses
+        // included, just as they're read one at a time
+        let keys = (0..100).rev().chain([6, 7, 6]).map(key).collect::<Vec<_>>();
+        let keys = keys.iter().map(Vec::as_slice).collect::<Vec<_>>();
+        let values = store.get_many(&keys).await?;
+        assert_eq!(keys.len(), values.len());
+        for (k, value) in keys.iter().zip(&values) {
+            assert_eq!(
+                &store.get(k).await?,
+                value,
+                "{}",
+                String::from_utf8_lossy(k)
+            );
+        }
+        assert_eq!(
+            vec![Some(b"latest".to_vec()), None, Some(b"latest".to_vec())],
+            values[100..]
+        );
+        assert_eq!(
+            vec![Some(b"old".to_vec()), None, Some(b"new".to_vec()), None],
+            store
+                .get_many(&[&key(2)[..], &key(3), &key(4), &key(12)])
+                .await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_restore() -> Result<()> {
+        let data_dir = self::test_data_dir().await?;
+        let mut store = self::setup_db(data_dir.as_path(), usize::MAX);
+        let key = |i: usize| format!("key:{i:03}").into_bytes();
+        // keys in an SSTable and the memtable, some of them deleted since
+        let older = (0..100).map(|i| Operation::set(key(i), b"old"));
+        store
+            .transact(Transaction::with_random_id(older.collect()))
+            .await?;
+        self::flush(&store).await?;
+        let newer = (0..150).step_by(3).map(|i| match i % 2 {
+            0 => Operation::delete(key(i)),
+            _ => Operation::set(key(i), b"new"),
+        });
+        store
+            .transact(Transaction::with_random_id(newer.collect()))
+            .await?;
+
+        let mut snapshot = vec![];
+        let len = store.snapshot(&mut snapshot).await?;
+        let entries = store.scan_entries(&[], None, usize::MAX).await?;
+        assert_eq!(entries.len(), len);
+        // 17 of the old keys were deleted, 8 new ones beyond them set
+        assert_eq!(100 - 17 + 8, len);
+
+        // a fresh store restored from it holds the same entries, none of the tombstones
+        let restored_dir = self::test_data_dir().await?;
+        let mut restored = self::setup_db(restored_dir.as_path(), usize::MAX);
+        assert_eq!(len, restored.restore(&mut snapshot.as_slice()).await?);
+        assert_eq!(entries, restored.scan_entries(&[], None, usize::MAX).await?);
+        assert_eq!(None, restored.get(&key(0)).await?);
+        assert_eq!(Some(b"new".to_vec()), restored.get(&key(3)).await?);
+        assert_eq!(Some(b"old".to_vec()), restored.get(&key(4)).await?);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_scan() -> Result<()> {
         let data_dir = self::test_data_dir().await?;
@@ -602,13 +2933,13 @@ mod tests {
             .await?;
         assert_eq!(
             vec![b"second".to_vec(), b"third".to_vec(), b"fourth".to_vec()],
-            store.scan("b", "dd").await?
+            store.scan(b"b", b"dd").await?
         );
         let empty: Vec<Vec<u8>> = vec![];
-        assert_eq!(empty, store.scan("ee", "f").await?);
+        assert_eq!(empty, store.scan(b"ee", b"f").await?);
         assert_eq!(
             vec![b"fourth".to_vec(), b"fifth".to_vec()],
-            store.scan("cc", "z").await?
+            store.scan(b"cc", b"z").await?
         );
         let event = timeout(Duration::from_secs(2), events.recv())
             .await?
@@ -616,7 +2947,7 @@ mod tests {
         assert_matches!(event, LSMEvent::WriteSSTable(_));
         assert_eq!(
             vec![b"second".to_vec(), b"third".to_vec(), b"fourth".to_vec()],
-            store.scan("b", "dd").await?
+            store.scan(b"b", b"dd").await?
         );
         store
             .transact(Transaction::with_random_id(vec![
@@ -634,7 +2965,7 @@ mod tests {
                 b"fifth".to_vec(),
                 b"sixth".to_vec()
             ],
-            store.scan("0", "z").await?
+            store.scan(b"0", b"z").await?
         );
         let event = timeout(Duration::from_secs(2), events.recv())
             .await?
@@ -650,7 +2981,7 @@ mod tests {
                 b"fifth".to_vec(),
                 b"sixth".to_vec()
             ],
-            store.scan("0", "z").await?
+            store.scan(b"0", b"z").await?
         );
         Ok(())
     }
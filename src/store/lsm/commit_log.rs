@@ -16,7 +16,10 @@ use tokio::{
 use uuid::Uuid;
 
 use self::CommitLogLine::{BeginTx, EndTx};
-use crate::{store::Transaction, Error, Result};
+use crate::{
+    store::{Durability, Transaction},
+    Error, Result,
+};
 
 #[derive(Serialize, Deserialize, Debug)]
 enum CommitLogLine {
@@ -37,13 +40,14 @@ impl CommitLogLine {
             Ok(s) => Ok(Some(s)),
             Err(e) => match e.kind() {
                 ErrorKind::UnexpectedEof => Ok(None),
-                _ => Err(Error::IO(e)),
+                _ => Err(Error::from(e)),
             },
         }?;
         match size {
             Some(s) => {
-                let mut buf = Vec::new();
-                reader.take(s).read_buf(&mut buf).await?;
+                // read the whole line, a single read may come up short for larger transactions
+                let mut buf = vec![0; s as usize];
+                reader.read_exact(&mut buf).await?;
                 match bincode::deserialize(buf.as_slice()) {
                     Ok(c) => Ok(Some(c)),
                     Err(e) => Err(Error::BincodeError(e)),
@@ -57,6 +61,12 @@ impl CommitLogLine {
 pub struct CommitLog {
     log_path: PathBuf,
     logfile: Option<File>,
+    // encoded lines of `Durability::Async` transactions, written out with the next sync
+    pending: Vec<u8>,
+    // whether lines were written since the log file was last fsync'd
+    unsynced: bool,
+    // length of the log file up to the last whole line written, see `write`
+    len: u64,
 }
 
 impl CommitLog {
@@ -64,6 +74,9 @@ impl CommitLog {
         Self {
             log_path: log_path.to_path_buf(),
             logfile: None,
+            pending: Vec::new(),
+            unsynced: false,
+            len: 0,
         }
     }
 
@@ -80,6 +93,7 @@ impl CommitLog {
                 .create(true)
                 .open(&self.log_path)
                 .await?;
+            self.len = file.metadata().await?.len();
             self.logfile = Some(file);
         }
         Ok(self.logfile.as_mut().unwrap())
@@ -91,32 +105,90 @@ impl CommitLog {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(&self.log_path)
             .await?;
         Ok(file)
     }
 
-    /// Writes a begin_transaction line to the commit log.
+    /// Writes a begin_transaction line to the commit log, as durably as the
+    /// transaction asks for. Weaker durabilities are made durable by `sync`.
     pub async fn begin_transaction(&mut self, tx: &Transaction) -> Result<()> {
         let line = BeginTx(tx.clone());
         let bytes = line.encode()?;
-        let logfile = self.get_write_handle().await?;
-        logfile.write_all(bytes.as_slice()).await?;
-        // TODO this is expensive. Should we relax the durability guarantee a bit,
-        // say by syncing the logfile every n seconds or something?
-        logfile.sync_all().await?;
-        Ok(())
+        match tx.durability() {
+            Durability::Async => {
+                self.pending.extend_from_slice(&bytes);
+                Ok(())
+            }
+            Durability::Batched => self.write(&bytes, false).await,
+            Durability::Fsync => self.write(&bytes, true).await,
+        }
     }
 
     /// Writes an end_transaction line to the commit log.
     pub async fn end_transaction(&mut self, tx_id: &Uuid) -> Result<()> {
         let line = EndTx(*tx_id);
         let bytes = line.encode()?;
+        self.write(&bytes, true).await
+    }
+
+    /// Writes out any pending lines and fsyncs the log file,
+    /// making every transaction logged so far durable.
+    pub async fn sync(&mut self) -> Result<()> {
+        if self.pending.is_empty() && !self.unsynced {
+            return Ok(());
+        }
+        self.write(&[], true).await
+    }
+
+    /// Appends `bytes` to the log file, after any pending lines so that the log
+    /// keeps the order transactions were applied in. When the append fails, e.g. with
+    /// the disk full, whatever part of it was written is truncated away so later lines
+    /// don't follow a torn one, and pending lines are kept for the next write.
+    async fn write(&mut self, bytes: &[u8], sync: bool) -> Result<()> {
+        let pending = std::mem::take(&mut self.pending);
+        let len = self.len;
         let logfile = self.get_write_handle().await?;
-        logfile.write_all(bytes.as_slice()).await?;
-        // TODO this is expensive. Should we relax the durability guarantee a bit,
-        // say by syncing the logfile every n seconds or something?
-        logfile.sync_all().await?;
+        let written = async {
+            logfile.write_all(&pending).await?;
+            logfile.write_all(bytes).await?;
+            if sync {
+                logfile.sync_all().await?;
+            }
+            Ok::<_, std::io::Error>(())
+        };
+        if let Err(e) = written.await {
+            if let Err(truncate) = logfile.set_len(len).await {
+                tracing::warn!(path = ?self.log_path, "Failed to truncate torn commit log line: {truncate}");
+            }
+            self.pending = pending;
+            return Err(e.into());
+        }
+        self.len += (pending.len() + bytes.len()) as u64;
+        self.unsynced = !sync;
+        Ok(())
+    }
+
+    /// Rewrites the log with only its unfinished transactions, e.g. once a memtable
+    /// flush has ended the ones it held, so the log doesn't grow without bound. The
+    /// rewritten log is fsync'd before it replaces the old one, so a crash leaves
+    /// one or the other.
+    pub async fn rotate(&mut self) -> Result<()> {
+        self.sync().await?;
+        let mut bytes = vec![];
+        for tx in self.get_unfinished_transactions().await? {
+            bytes.extend(BeginTx(tx).encode()?);
+        }
+        let mut tmp_path = self.log_path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut file = File::create(&tmp_path).await?;
+        file.write_all(&bytes).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp_path, &self.log_path).await?;
+        // the next write opens the rotated log
+        self.logfile = None;
+        self.len = bytes.len() as u64;
         Ok(())
     }
 
@@ -156,10 +228,11 @@ impl CommitLog {
 
 #[cfg(test)]
 mod tests {
+    use itertools::Itertools;
     use uuid::Uuid;
 
     use crate::{
-        store::{Operation, Transaction},
+        store::{Durability, Operation, Transaction},
         Result,
     };
     use std::env;
@@ -189,6 +262,60 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_durability() -> Result<()> {
+        let mut commit_log = self::get_commit_log();
+        let tx = |durability| {
+            Transaction::with_random_id(vec![Operation::set("foo", b"bar")])
+                .with_durability(durability)
+        };
+        let (tx1, tx2, tx3) = (
+            tx(Durability::Async),
+            tx(Durability::Batched),
+            tx(Durability::Async),
+        );
+        // the durability isn't logged, so only compare ids
+        let unfinished_ids = |txs: Vec<Transaction>| txs.into_iter().map(|tx| tx.id).collect_vec();
+        commit_log.begin_transaction(&tx1).await?;
+        assert!(commit_log.get_unfinished_transactions().await?.is_empty());
+        // pending lines are written ahead of the next one written out
+        commit_log.begin_transaction(&tx2).await?;
+        commit_log.begin_transaction(&tx3).await?;
+        let unfinished_txs = commit_log.get_unfinished_transactions().await?;
+        assert_eq!(vec![tx1.id, tx2.id], unfinished_ids(unfinished_txs));
+        commit_log.sync().await?;
+        let unfinished_txs = commit_log.get_unfinished_transactions().await?;
+        assert_eq!(vec![tx1.id, tx2.id, tx3.id], unfinished_ids(unfinished_txs));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rotate() -> Result<()> {
+        let mut commit_log = self::get_commit_log();
+        let txs = (0..100)
+            .map(|i| Transaction::with_random_id(vec![Operation::set(format!("k{i}"), b"v")]))
+            .collect_vec();
+        for tx in &txs {
+            commit_log.begin_transaction(tx).await?;
+        }
+        for tx in &txs[..99] {
+            commit_log.end_transaction(&tx.id).await?;
+        }
+        let len = std::fs::metadata(commit_log.path())?.len();
+        commit_log.rotate().await?;
+        assert!(std::fs::metadata(commit_log.path())?.len() * 100 < len);
+        assert_eq!(
+            vec![txs[99].clone()],
+            commit_log.get_unfinished_transactions().await?
+        );
+        // and the rotated log is appended to as before
+        commit_log.end_transaction(&txs[99].id).await?;
+        let tx = Transaction::with_random_id(vec![Operation::set("foo", b"bar")]);
+        commit_log.begin_transaction(&tx).await?;
+        assert_eq!(vec![tx], commit_log.get_unfinished_transactions().await?);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_empty_log() -> Result<()> {
         let commit_log = self::get_commit_log();
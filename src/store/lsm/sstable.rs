@@ -3,28 +3,34 @@
 //! # File Format
 //! The files consist of:
 //!   1. A u64 representing the size of the index block
-//!   2. The index block, which is a bincode-serialized BTreeMap<String, IndexEntry>
+//!   2. The index block, which is a bincode-serialized BTreeMap<Vec<u8>, IndexEntry>
 //!   3. The data block, consisting of concatenated bincode-serialized Value objects
 //!
 //! To find a value, the index is loaded into memory, then searched to
 //! yield the offset and size of the Value associated with the
 //! searched-for key.
 
-use std::{collections::BTreeMap, io::SeekFrom, mem, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    io::SeekFrom,
+    mem,
+    ops::RangeBounds,
+    path::{Path, PathBuf},
+};
 
 use crate::{Error, Result};
-use bytes::BytesMut;
+use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use tokio::{
     fs::{self, File, OpenOptions},
-    io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWriteExt, BufReader},
 };
 
 use super::Value;
 
-type Index = BTreeMap<String, IndexEntry>;
+type Index = BTreeMap<Vec<u8>, IndexEntry>;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 struct IndexEntry {
     // Byte offset from the beginning of the SSTable file where the value is stored
     offset: u64,
@@ -32,6 +38,30 @@ struct IndexEntry {
     size: u64,
 }
 
+/// Where SSTables are written, the local filesystem outside of tests, which may
+/// stand in a disk that fails writes, e.g. as if it were full
+#[async_trait]
+pub(super) trait Disk: Send + Sync {
+    /// Creates the file at `path` to write an SSTable to, truncating any left there
+    async fn create(&self, path: &Path) -> std::io::Result<File> {
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .await
+    }
+
+    /// Syncs a written SSTable's file to disk, the last step before it's moved into place
+    async fn sync(&self, file: &File) -> std::io::Result<()> {
+        file.sync_all().await
+    }
+}
+
+/// The local filesystem, see `Disk`
+pub(super) struct LocalDisk;
+impl Disk for LocalDisk {}
+
 #[derive(Debug)]
 pub struct SSTable {
     filepath: PathBuf,
@@ -49,50 +79,150 @@ impl SSTable {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(&self.filepath)
             .await
         {
             Ok(f) => Ok(f),
-            Err(e) => Err(Error::IO(e)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes the memtable to `disk` as an SSTable. It's written to a temporary file that's
+    /// only renamed into place once complete, so a failed write (e.g. with the disk full)
+    /// never leaves a partial SSTable behind for reads or compactions to find.
+    pub async fn write(&self, memtable: &BTreeMap<Vec<u8>, Value>, disk: &dyn Disk) -> Result<()> {
+        self.check_new().await?;
+        let mut sizes = Vec::with_capacity(memtable.len());
+        for (key, val) in memtable.iter() {
+            sizes.push((key.clone(), bincode::serialized_size(val)?));
         }
+        let (index_size, index) = build_index(sizes)?;
+        let tmp_path = self.filepath.with_extension("sst.tmp");
+        let written = async {
+            let mut file = disk.create(&tmp_path).await?;
+            file.write_u64(index_size).await?;
+            file.write_all(bincode::serialize(&index)?.as_slice())
+                .await?;
+            for val in memtable.values() {
+                file.write_all(bincode::serialize(val)?.as_slice()).await?;
+            }
+            file.flush().await?;
+            disk.sync(&file).await?;
+            Ok::<_, Error>(())
+        };
+        self.move_into_place(&tmp_path, written.await).await
     }
 
-    /// Writes the memtable to disk as an SSTable
-    pub async fn write(&self, memtable: &BTreeMap<String, Value>) -> Result<()> {
+    /// Merges the SSTables `inputs`, given oldest first, into this one, keeping only the
+    /// newest value of each key and dropping tombstones when `drop_tombstones` is set.
+    /// The inputs' sorted indexes are merged, then each value is copied across as is,
+    /// so only their keys are held in memory. Written like `write`, unless nothing
+    /// survives the merge, when no file is. Returns the keys written.
+    pub async fn write_merged(
+        &self,
+        inputs: &[PathBuf],
+        drop_tombstones: bool,
+        disk: &dyn Disk,
+    ) -> Result<Vec<Vec<u8>>> {
+        self.check_new().await?;
+        let mut readers = Vec::with_capacity(inputs.len());
+        let mut indexes = Vec::with_capacity(inputs.len());
+        for path in inputs {
+            let file = OpenOptions::new().read(true).open(path).await?;
+            let mut reader = BufReader::new(file);
+            let index = self.read_index(&mut reader).await?;
+            let pos = mem::size_of::<u64>() as u64 + bincode::serialized_size(&index)?;
+            readers.push((reader, pos));
+            indexes.push(index.into_iter().peekable());
+        }
+        let mut merged = Vec::new();
+        loop {
+            // the smallest key left in any input, taken from the newest input holding it
+            let key = indexes
+                .iter_mut()
+                .filter_map(|index| index.peek().map(|(key, _)| key))
+                .min()
+                .cloned();
+            let key = match key {
+                Some(key) => key,
+                None => break,
+            };
+            let mut newest = None;
+            for (input, index) in indexes.iter_mut().enumerate() {
+                if let Some((_, entry)) = index.next_if(|(k, _)| *k == key) {
+                    newest = Some((input, entry));
+                }
+            }
+            let (input, entry) = newest.expect("the smallest key is in an input");
+            if drop_tombstones && entry.size == tombstone_size() {
+                continue;
+            }
+            merged.push((key, input, entry));
+        }
+        if merged.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let sizes = merged
+            .iter()
+            .map(|(key, _, entry)| (key.clone(), entry.size));
+        let (index_size, index) = build_index(sizes)?;
+        let tmp_path = self.filepath.with_extension("sst.tmp");
+        let written = async {
+            let mut file = disk.create(&tmp_path).await?;
+            file.write_u64(index_size).await?;
+            file.write_all(bincode::serialize(&index)?.as_slice())
+                .await?;
+            let mut buf = Vec::new();
+            for (_, input, entry) in &merged {
+                // values are read in key order, so the reader only seeks past shadowed ones
+                let (reader, pos) = &mut readers[*input];
+                if entry.offset != *pos {
+                    reader.seek(SeekFrom::Start(entry.offset)).await?;
+                }
+                buf.resize(self::u64_to_usize(entry.size), 0);
+                reader.read_exact(&mut buf).await?;
+                *pos = entry.offset + entry.size;
+                file.write_all(&buf).await?;
+            }
+            file.flush().await?;
+            disk.sync(&file).await?;
+            Ok::<_, Error>(())
+        };
+        self.move_into_place(&tmp_path, written.await).await?;
+        Ok(index.into_keys().collect())
+    }
+
+    /// Fails if the SSTable's file already exists, it's never overwritten
+    async fn check_new(&self) -> Result<()> {
         match fs::metadata(&self.filepath).await {
             Ok(_) => Err(Error::E(format!(
                 "File {} already exists",
                 &self.filepath.to_str().unwrap()
             ))),
             Err(_) => Ok(()),
-        }?;
-        let mut offset = 0;
-        let mut index = BTreeMap::new();
-        for (key, val) in memtable.iter() {
-            let size = bincode::serialized_size(val)?;
-            index.insert(key.clone(), IndexEntry { offset, size });
-            offset += size;
-        }
-        let index_size = bincode::serialized_size(&index)?;
-        for (_, val) in index.iter_mut() {
-            val.offset += mem::size_of::<u64>() as u64 + index_size;
         }
-        let buf = bincode::serialize(&index)?;
-        let mut file = self.file_handle().await?;
-        file.write_u64(index_size).await?;
-        file.write_all(buf.as_slice()).await?;
-        for val in memtable.values() {
-            file.write_all(bincode::serialize(val)?.as_slice()).await?;
+    }
+
+    /// Renames the temporary file at `tmp_path` into place once it's `written`, or
+    /// removes it when writing it failed
+    async fn move_into_place(&self, tmp_path: &Path, written: Result<()>) -> Result<()> {
+        if let Err(e) = written {
+            if let Err(rm) = fs::remove_file(tmp_path).await {
+                tracing::warn!(path = ?tmp_path, "Failed to remove partial SSTable: {rm}");
+            }
+            return Err(e);
         }
-        file.sync_all().await?;
+        fs::rename(tmp_path, &self.filepath).await?;
         Ok(())
     }
 
     async fn read_index<R: AsyncRead + Unpin>(&self, reader: &mut R) -> Result<Index> {
         let index_size = reader.read_u64().await?;
-        let mut buf = BytesMut::with_capacity(self::u64_to_usize(index_size));
-        reader.read_buf(&mut buf).await?;
-        let index = bincode::deserialize(buf.as_ref())?;
+        let mut buf = vec![0; self::u64_to_usize(index_size)];
+        reader.read_exact(&mut buf).await?;
+        let index = bincode::deserialize(&buf)?;
         Ok(index)
     }
 
@@ -102,18 +232,18 @@ impl SSTable {
         index_entry: &IndexEntry,
     ) -> Result<Value> {
         let IndexEntry { offset, size } = index_entry;
-        let mut buf = BytesMut::with_capacity(self::u64_to_usize(*size));
+        let mut buf = vec![0; self::u64_to_usize(*size)];
         reader.seek(SeekFrom::Start(*offset)).await?;
-        reader.read_buf(&mut buf).await?;
-        let val = bincode::deserialize(buf.as_ref())?;
+        reader.read_exact(&mut buf).await?;
+        let val = bincode::deserialize(&buf)?;
         Ok(val)
     }
 
     /// Returns the value associated with the key if it exists in the SSTable.
-    pub async fn search(&self, key: String) -> Result<Option<Value>> {
+    pub async fn search(&self, key: &[u8]) -> Result<Option<Value>> {
         let mut file = self.file_handle().await?;
         let index = self.read_index(&mut file).await?;
-        match index.get(&key) {
+        match index.get(key) {
             Some(index_entry) => self
                 .read_value(&mut file, index_entry)
                 .await
@@ -122,46 +252,219 @@ impl SSTable {
         }
     }
 
-    pub async fn scan(
-        &self,
-        from_inclusive: &str,
-        to_exclusive: &str,
-    ) -> Result<Vec<(String, Value)>> {
+    /// Returns the keys and values in `range`, in key order.
+    pub async fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<Vec<(Vec<u8>, Value)>> {
         let mut file = self.file_handle().await?;
         let index = self.read_index(&mut file).await?;
         let mut result = Vec::new();
-        for (key, index_entry) in index.range(from_inclusive.to_string()..to_exclusive.to_string())
-        {
+        for (key, index_entry) in index.range(range) {
             let val = self.read_value(&mut file, index_entry).await?;
             result.push((key.clone(), val));
         }
         Ok(result)
     }
 
-    pub async fn keys(&self) -> Result<Vec<String>> {
+    /// Opens the SSTable to read its entries one at a time, in stored order.
+    /// The index is validated against the file's layout up front so corrupt
+    /// segments are reported before any entries are read.
+    pub async fn iter(&self) -> Result<SegmentIter> {
+        let path = self.filepath.display();
+        // open read-only, inspecting a missing segment shouldn't create it
+        let file = OpenOptions::new().read(true).open(&self.filepath).await?;
+        let file_len = file.metadata().await?.len();
+        let mut reader = BufReader::new(file);
+        let index_size = reader.read_u64().await?;
+        let header_size = mem::size_of::<u64>() as u64;
+        if index_size > file_len.saturating_sub(header_size) {
+            return Err(format!(
+                "corrupt segment {path}: index of {index_size} bytes overruns the {file_len} byte file"
+            )
+            .into());
+        }
+        let mut buf = vec![0; self::u64_to_usize(index_size)];
+        reader.read_exact(&mut buf).await?;
+        let index: Index = bincode::deserialize(&buf)
+            .map_err(|e| format!("corrupt segment {path}: invalid index: {e}"))?;
+        let data_start = header_size + index_size;
+
+        let mut entries = index.into_iter().collect::<Vec<_>>();
+        entries.sort_by_key(|(_, entry)| entry.offset);
+        let mut expected_offset = data_start;
+        for (key, entry) in &entries {
+            if entry.offset != expected_offset {
+                return Err(format!(
+                    "corrupt segment {path}: value of {:?} at offset {} instead of {expected_offset}",
+                    String::from_utf8_lossy(key),
+                    entry.offset
+                )
+                .into());
+            }
+            expected_offset += entry.size;
+        }
+        if expected_offset != file_len {
+            return Err(format!(
+                "corrupt segment {path}: index covers {expected_offset} of {file_len} bytes"
+            )
+            .into());
+        }
+        Ok(SegmentIter {
+            reader,
+            entries: entries.into_iter(),
+        })
+    }
+
+    /// Opens the SSTable to read the entries in `range` one at a time, in key order.
+    /// Only the index is read up front, each value is read once it's reached and
+    /// never for the entries skipped past. The file is held open, so the entries can
+    /// still be read once the SSTable is removed, e.g. by a compaction.
+    pub async fn range_iter<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<RangeIter> {
+        let file = OpenOptions::new().read(true).open(&self.filepath).await?;
+        let mut reader = BufReader::new(file);
+        let index = self.read_index(&mut reader).await?;
+        let pos = mem::size_of::<u64>() as u64 + bincode::serialized_size(&index)?;
+        let entries = index
+            .into_iter()
+            .filter(|(key, _)| range.contains(key))
+            .collect();
+        Ok(RangeIter {
+            reader,
+            pos,
+            entries,
+            next: 0,
+        })
+    }
+
+    pub async fn keys(&self) -> Result<Vec<Vec<u8>>> {
         let mut file = self.file_handle().await?;
         let index = self.read_index(&mut file).await?;
         Ok(index.into_keys().collect())
     }
 }
 
+/// Reads the entries of a single SSTable in stored order, see `SSTable::iter`
+pub struct SegmentIter {
+    reader: BufReader<File>,
+    entries: std::vec::IntoIter<(Vec<u8>, IndexEntry)>,
+}
+impl SegmentIter {
+    /// Returns the next key and value, or `None` once every entry has been read.
+    pub async fn next_entry(&mut self) -> Result<Option<(Vec<u8>, Value)>> {
+        let (key, entry) = match self.entries.next() {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let mut buf = vec![0; self::u64_to_usize(entry.size)];
+        self.reader.read_exact(&mut buf).await?;
+        let value = bincode::deserialize(&buf)
+            .map_err(|e| format!("corrupt segment: invalid value of {key:?}: {e}"))?;
+        Ok(Some((key, value)))
+    }
+}
+
+/// Reads the entries of a range of an SSTable in key order, see `SSTable::range_iter`
+pub struct RangeIter {
+    reader: BufReader<File>,
+    // offset the reader is at, so it only seeks past values that are skipped
+    pos: u64,
+    entries: Vec<(Vec<u8>, IndexEntry)>,
+    // index of the next entry in `entries`
+    next: usize,
+}
+impl RangeIter {
+    /// The key of the next entry, `None` once every entry has been read
+    pub fn peek_key(&self) -> Option<&[u8]> {
+        self.entries.get(self.next).map(|(key, _)| key.as_slice())
+    }
+
+    /// Skips the next entry without reading its value, returning whether it holds data
+    /// rather than a tombstone, which is told apart by its size. `None` once every
+    /// entry has been read
+    pub fn skip_entry(&mut self) -> Option<bool> {
+        let (_, entry) = self.entries.get(self.next)?;
+        self.next += 1;
+        Some(entry.size != tombstone_size())
+    }
+
+    /// Returns the next key and value, or `None` once every entry has been read.
+    pub async fn next_entry(&mut self) -> Result<Option<(Vec<u8>, Value)>> {
+        let (key, entry) = match self.entries.get_mut(self.next) {
+            Some((key, entry)) => (mem::take(key), *entry),
+            None => return Ok(None),
+        };
+        self.next += 1;
+        if entry.offset != self.pos {
+            self.reader.seek(SeekFrom::Start(entry.offset)).await?;
+        }
+        let mut buf = vec![0; self::u64_to_usize(entry.size)];
+        self.reader.read_exact(&mut buf).await?;
+        self.pos = entry.offset + entry.size;
+        Ok(Some((key, bincode::deserialize(&buf)?)))
+    }
+}
+
+/// The index of an SSTable holding values of the given sizes, in key order, along with
+/// its serialized size. Values are laid out in key order right after the index.
+fn build_index<I: IntoIterator<Item = (Vec<u8>, u64)>>(sizes: I) -> Result<(u64, Index)> {
+    let mut offset = 0;
+    let mut index = BTreeMap::new();
+    for (key, size) in sizes {
+        index.insert(key, IndexEntry { offset, size });
+        offset += size;
+    }
+    // the offsets are fixed size, so the index's size doesn't depend on them
+    let index_size = bincode::serialized_size(&index)?;
+    for (_, val) in index.iter_mut() {
+        val.offset += mem::size_of::<u64>() as u64 + index_size;
+    }
+    Ok((index_size, index))
+}
+
+/// How many bytes a tombstone takes in the data block, values take more
+fn tombstone_size() -> u64 {
+    bincode::serialized_size(&Value::Tombstone).expect("tombstones always serialize")
+}
+
 fn u64_to_usize(input: u64) -> usize {
     // Annoyingly, bincode::deserialized_size returns a u64 but
-    // BytesMut::with_capacity expects a usize. This is a bad way to
+    // buffers are sized with a usize. This is a bad way to
     // solve that problem so hopefully we'll come up with something
     // better at some point
     usize::try_from(input).expect("32 bit architecture not supported")
 }
 
 #[cfg(test)]
-mod tests {
-    use std::{env, path::PathBuf};
+pub(super) mod tests {
+    use std::{
+        env,
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+    };
 
-    use crate::{store::lsm::Value, Result};
+    use crate::{store::lsm::Value, Error, Result};
+    use async_trait::async_trait;
     use maplit::btreemap;
+    use tokio::fs::File;
     use uuid::Uuid;
 
-    use super::SSTable;
+    use super::{Disk, LocalDisk, SSTable};
+
+    /// A disk whose SSTable writes fail as if it were out of space while it's full
+    #[derive(Clone, Default)]
+    pub(in crate::store::lsm) struct FullDisk {
+        pub full: Arc<AtomicBool>,
+    }
+    #[async_trait]
+    impl Disk for FullDisk {
+        async fn sync(&self, file: &File) -> std::io::Result<()> {
+            if self.full.load(Ordering::SeqCst) {
+                return Err(std::io::ErrorKind::StorageFull.into());
+            }
+            file.sync_all().await
+        }
+    }
 
     fn test_data_file() -> PathBuf {
         env::temp_dir().join(format!("{}.sst", Uuid::new_v4()))
@@ -172,37 +475,142 @@ mod tests {
         let path = self::test_data_file();
         let sstable = SSTable::new(path);
         let memtable = btreemap! {
-            "bar".to_string() => Value::Data(b"qux".to_vec()),
-            "foo".to_string() => Value::Data(b"bar".to_vec()),
-            "qux".to_string() => Value::Data(b"boom".to_vec()),
-            "zip".to_string() => Value::Tombstone,
+            b"bar".to_vec() => Value::Data(b"qux".to_vec()),
+            b"foo".to_vec() => Value::Data(b"bar".to_vec()),
+            b"qux".to_vec() => Value::Data(b"boom".to_vec()),
+            b"zip".to_vec() => Value::Tombstone,
         };
-        sstable.write(&memtable).await?;
+        sstable.write(&memtable, &LocalDisk).await?;
         assert_eq!(
             Some(Value::Data(b"qux".to_vec())),
-            sstable.search("bar".to_string()).await?
+            sstable.search(b"bar").await?
         );
+        assert_eq!(Some(Value::Tombstone), sstable.search(b"zip").await?);
+        assert_eq!(None, sstable.search(b"missing").await?);
         assert_eq!(
-            Some(Value::Tombstone),
-            sstable.search("zip".to_string()).await?
+            vec![
+                (b"bar".to_vec(), Value::Data(b"qux".to_vec())),
+                (b"foo".to_vec(), Value::Data(b"bar".to_vec())),
+                (b"qux".to_vec(), Value::Data(b"boom".to_vec()))
+            ],
+            sstable.scan(b"bar".to_vec()..b"quxx".to_vec()).await?
         );
-        assert_eq!(None, sstable.search("missing".to_string()).await?);
         assert_eq!(
             vec![
-                ("bar".to_string(), Value::Data(b"qux".to_vec())),
-                ("foo".to_string(), Value::Data(b"bar".to_vec())),
-                ("qux".to_string(), Value::Data(b"boom".to_vec()))
+                b"bar".to_vec(),
+                b"foo".to_vec(),
+                b"qux".to_vec(),
+                b"zip".to_vec()
             ],
-            sstable.scan("bar", "quxx").await?
+            sstable.keys().await?
+        );
+
+        // ranges are read lazily, values skipped past are never read
+        let mut range = sstable.range_iter(b"foo".to_vec()..).await?;
+        assert_eq!(Some(&b"foo"[..]), range.peek_key());
+        assert_eq!(Some(true), range.skip_entry());
+        assert_eq!(
+            Some((b"qux".to_vec(), Value::Data(b"boom".to_vec()))),
+            range.next_entry().await?
+        );
+        assert_eq!(Some(false), range.skip_entry());
+        assert_eq!(None, range.peek_key());
+        assert_eq!(None, range.next_entry().await?);
+        let mut range = sstable.range_iter(..b"foo".to_vec()).await?;
+        assert_eq!(
+            Some((b"bar".to_vec(), Value::Data(b"qux".to_vec()))),
+            range.next_entry().await?
+        );
+        assert_eq!(None, range.skip_entry());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_merged() -> Result<()> {
+        let older = self::test_data_file();
+        SSTable::new(older.clone())
+            .write(
+                &btreemap! {
+                    b"bar".to_vec() => Value::Data(b"old".to_vec()),
+                    b"baz".to_vec() => Value::Data(b"old".to_vec()),
+                    b"foo".to_vec() => Value::Data(b"old".to_vec()),
+                },
+                &LocalDisk,
+            )
+            .await?;
+        let newer = self::test_data_file();
+        SSTable::new(newer.clone())
+            .write(
+                &btreemap! {
+                    b"baz".to_vec() => Value::Tombstone,
+                    b"foo".to_vec() => Value::Data(b"new".to_vec()),
+                    b"qux".to_vec() => Value::Data(b"new".to_vec()),
+                },
+                &LocalDisk,
+            )
+            .await?;
+        let inputs = [older, newer];
+
+        // the newest value of each key wins, tombstones kept unless they're dropped
+        let merged = SSTable::new(self::test_data_file());
+        let keys = merged.write_merged(&inputs, false, &LocalDisk).await?;
+        assert_eq!(4, keys.len());
+        assert_eq!(Some(Value::Tombstone), merged.search(b"baz").await?);
+        let merged = SSTable::new(self::test_data_file());
+        let keys = merged.write_merged(&inputs, true, &LocalDisk).await?;
+        assert_eq!(
+            vec![b"bar".to_vec(), b"foo".to_vec(), b"qux".to_vec()],
+            keys
         );
         assert_eq!(
             vec![
-                "bar".to_string(),
-                "foo".to_string(),
-                "qux".to_string(),
-                "zip".to_string()
+                (b"bar".to_vec(), Value::Data(b"old".to_vec())),
+                (b"foo".to_vec(), Value::Data(b"new".to_vec())),
+                (b"qux".to_vec(), Value::Data(b"new".to_vec())),
             ],
-            sstable.keys().await?
+            merged.scan(..).await?
+        );
+        // the merged file is laid out just like a written one
+        assert_eq!(3, merged.iter().await?.entries.len());
+
+        // nothing is written when nothing survives
+        let tombstones = self::test_data_file();
+        SSTable::new(tombstones.clone())
+            .write(
+                &btreemap! { b"foo".to_vec() => Value::Tombstone },
+                &LocalDisk,
+            )
+            .await?;
+        let path = self::test_data_file();
+        let keys = SSTable::new(path.clone())
+            .write_merged(&[tombstones], true, &LocalDisk)
+            .await?;
+        assert!(keys.is_empty());
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_disk_full() -> Result<()> {
+        let dir = env::temp_dir().join(Uuid::new_v4().to_string());
+        tokio::fs::create_dir(&dir).await?;
+        let path = dir.join("1.sst");
+        let memtable = btreemap! {
+            b"foo".to_vec() => Value::Data(b"bar".to_vec())
+        };
+        let disk = FullDisk::default();
+        disk.full.store(true, Ordering::SeqCst);
+        let res = SSTable::new(path.clone()).write(&memtable, &disk).await;
+        assert!(matches!(res, Err(Error::DiskFull(_))), "{res:?}");
+        // nothing is left behind, not even the partly written temporary file
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        assert!(entries.next_entry().await?.is_none());
+
+        disk.full.store(false, Ordering::SeqCst);
+        SSTable::new(path.clone()).write(&memtable, &disk).await?;
+        assert_eq!(
+            Some(Value::Data(b"bar".to_vec())),
+            SSTable::new(path).search(b"foo").await?
         );
         Ok(())
     }
@@ -212,11 +620,11 @@ mod tests {
         let path = self::test_data_file();
         let sstable_one = SSTable::new(path.clone());
         let memtable = btreemap! {
-            "foo".to_string() => Value::Data(b"bar".to_vec())
+            b"foo".to_vec() => Value::Data(b"bar".to_vec())
         };
-        sstable_one.write(&memtable).await?;
+        sstable_one.write(&memtable, &LocalDisk).await?;
         let sstable_two = SSTable::new(path.clone());
-        let res = sstable_two.write(&memtable).await;
+        let res = sstable_two.write(&memtable, &LocalDisk).await;
         assert!(res.is_err());
         assert_eq!(
             format!("File {} already exists", path.to_str().unwrap()),
@@ -224,4 +632,33 @@ mod tests {
         );
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_iter_corrupt() -> Result<()> {
+        let path = self::test_data_file();
+        let sstable = SSTable::new(path.clone());
+        let memtable = btreemap! {
+            b"foo".to_vec() => Value::Data(b"bar".to_vec()),
+        };
+        sstable.write(&memtable, &LocalDisk).await?;
+        let mut contents = tokio::fs::read(&path).await?;
+
+        // truncated values
+        tokio::fs::write(&path, &contents[..contents.len() - 1]).await?;
+        let err = sstable.iter().await.err().expect("expected an error");
+        assert!(err.to_string().contains("corrupt segment"), "{err}");
+
+        // trailing garbage
+        contents.push(0);
+        tokio::fs::write(&path, &contents).await?;
+        let err = sstable.iter().await.err().expect("expected an error");
+        assert!(err.to_string().contains("corrupt segment"), "{err}");
+
+        // an index size larger than the file
+        contents[..8].copy_from_slice(&u64::MAX.to_be_bytes());
+        tokio::fs::write(&path, &contents).await?;
+        let err = sstable.iter().await.err().expect("expected an error");
+        assert!(err.to_string().contains("corrupt segment"), "{err}");
+        Ok(())
+    }
 }
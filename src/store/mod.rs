@@ -1,96 +1,1265 @@
 //! Persistent disk storage
+pub mod backend;
+pub mod compression;
+pub mod entry;
+pub mod eviction;
+pub mod expiry;
+#[cfg(feature = "hash")]
+pub mod hash;
+#[cfg(feature = "index")]
+pub mod index;
 pub mod lsm;
+pub mod replication;
+pub mod transform;
 
+use self::eviction::{Eviction, Lru};
+use self::expiry::{Clock, Expiries, SystemClock};
+#[cfg(feature = "hash")]
+use self::hash::Hash;
+use self::transform::Transform;
 use self::Operation::{Delete, Set};
 use crate::Result;
 use async_trait::async_trait;
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
-use std::{collections::BTreeMap, sync::Arc};
-use tokio::sync::Mutex;
+use std::path::Path;
+use std::time::Duration;
+use std::{collections::BTreeMap, ops::Bound, sync::Arc};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::sync::{Mutex, MutexGuard};
 use uuid::Uuid;
 
+/// A range of keys, as passed to `BTreeMap::range`
+pub(crate) type KeyBounds = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+/// The bounds of `Store::scan_entries`, `None` when the range is empty since
+/// `BTreeMap::range` panics when `end` comes before `start`
+pub(crate) fn scan_bounds(start: &[u8], end: Option<&[u8]>) -> Option<KeyBounds> {
+    match end {
+        Some(end) if end < start => None,
+        Some(end) => Some((
+            Bound::Included(start.to_vec()),
+            Bound::Excluded(end.to_vec()),
+        )),
+        None => Some((Bound::Included(start.to_vec()), Bound::Unbounded)),
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Eq, PartialEq, Clone)]
 pub enum Operation {
-    Set(String, Vec<u8>),
-    Delete(String),
+    Set(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
 }
 
 impl Operation {
-    pub fn set<K: Into<String>>(key: K, value: &[u8]) -> Self {
+    pub fn set<K: Into<Vec<u8>>>(key: K, value: &[u8]) -> Self {
         Set(key.into(), value.to_vec())
     }
 
-    pub fn delete<K: Into<String>>(key: K) -> Self {
+    pub fn delete<K: Into<Vec<u8>>>(key: K) -> Self {
         Delete(key.into())
     }
 }
 
+/// How durable a transaction must be before it's acknowledged, for stores that log
+/// their transactions to disk. Weaker levels trade what may be lost in a crash for
+/// fewer fsyncs on the write path. Levels are ordered from the least durable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Durability {
+    /// Logged in memory and written out with the next commit log sync,
+    /// so the transaction is lost if the server crashes before then
+    Async,
+    /// Written to the commit log before it's acknowledged, and fsync'd with the next
+    /// commit log sync. Survives the server crashing, but not the machine.
+    Batched,
+    /// Written to the commit log and fsync'd before it's acknowledged
+    #[default]
+    Fsync,
+}
+impl Durability {
+    /// The durability as it's written in a SET
+    pub fn name(&self) -> &'static str {
+        match self {
+            Durability::Async => "async",
+            Durability::Batched => "batched",
+            Durability::Fsync => "fsync",
+        }
+    }
+}
+impl std::str::FromStr for Durability {
+    type Err = crate::Error;
+    fn from_str(s: &str) -> Result<Durability> {
+        match s {
+            "async" => Ok(Durability::Async),
+            "batch" | "batched" => Ok(Durability::Batched),
+            "fsync" => Ok(Durability::Fsync),
+            s => Err(
+                format!("invalid durability: {s:?}, expected one of (async|batched|fsync)").into(),
+            ),
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Eq, PartialEq, Clone)]
 pub struct Transaction {
     id: Uuid,
     operations: Vec<Operation>,
+    // only decides how the transaction is logged, so it's never logged itself
+    #[serde(skip)]
+    durability: Durability,
 }
 
 impl Transaction {
     pub fn new(id: Uuid, operations: Vec<Operation>) -> Self {
-        Self { id, operations }
+        Self {
+            id,
+            operations,
+            durability: Durability::default(),
+        }
     }
 
     pub fn with_random_id(operations: Vec<Operation>) -> Self {
-        Self {
-            id: Uuid::new_v4(),
-            operations,
+        Self::new(Uuid::new_v4(), operations)
+    }
+
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+}
+
+/// Snapshots start with these bytes, followed by each key and value bincode encoded as
+/// `Some((key, value))`, and end with a `None`. See `Store::snapshot`
+const SNAPSHOT_MAGIC: [u8; 8] = *b"KAVESNP1";
+/// Entries `Store::restore` sets in each transaction
+const RESTORE_BATCH: usize = 1024;
+
+/// Snapshot `store` to a new file at `path`, returning how many entries it holds. The
+/// snapshot is streamed and fsync'd to a temporary file that's only linked in at `path`
+/// once it's whole, so a crash never leaves part of one there. Fails rather than
+/// replace a file already at `path`.
+pub async fn snapshot_to_file<S: Store + Send>(store: &mut S, path: &Path) -> Result<usize> {
+    if path.exists() {
+        return Err(format!("{} already exists", path.display()).into());
+    }
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(format!(".{}.tmp", Uuid::new_v4()));
+    let written = async {
+        let file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)
+            .await?;
+        let mut writer = BufWriter::new(file);
+        let len = store.snapshot(&mut writer).await?;
+        writer.get_ref().sync_all().await?;
+        // unlike renaming, linking never replaces a file written at `path` meanwhile
+        tokio::fs::hard_link(&tmp_path, path)
+            .await
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::AlreadyExists => {
+                    format!("{} already exists", path.display()).into()
+                }
+                _ => crate::Error::from(e),
+            })?;
+        Ok::<_, crate::Error>(len)
+    }
+    .await;
+    if let Err(e) = tokio::fs::remove_file(&tmp_path).await {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            tracing::warn!(path = ?tmp_path, "Failed to remove temporary snapshot: {e}");
+        }
+    }
+    written
+}
+
+/// Sets every key and value of a snapshot read off `reader` as it arrives, like
+/// `Store::restore` does from a file, returning how many there were. It's read up to
+/// its end, leaving anything sent after it unread. Fails without reading keys or
+/// values longer than `max_len`.
+pub async fn restore_from<S, R>(store: &mut S, reader: &mut R, max_len: u64) -> Result<usize>
+where
+    S: Store + Send,
+    R: AsyncRead + Unpin,
+{
+    let mut magic = [0; SNAPSHOT_MAGIC.len()];
+    reader.read_exact(&mut magic).await?;
+    if magic != SNAPSHOT_MAGIC {
+        return Err("not a snapshot, it's missing the snapshot header".into());
+    }
+    let mut restored = 0;
+    loop {
+        let mut operations = Vec::with_capacity(RESTORE_BATCH);
+        let mut ended = false;
+        while operations.len() < RESTORE_BATCH {
+            // bincode's tag of the entry's `Option`
+            match reader.read_u8().await? {
+                0 => {
+                    ended = true;
+                    break;
+                }
+                1 => {
+                    let k = read_snapshot_slice(reader, max_len).await?;
+                    operations.push(Set(k, read_snapshot_slice(reader, max_len).await?));
+                }
+                tag => return Err(format!("invalid snapshot entry tag {tag}").into()),
+            }
         }
+        restored += operations.len();
+        if !operations.is_empty() {
+            store
+                .transact(Transaction::with_random_id(operations))
+                .await?;
+        }
+        if ended {
+            return Ok(restored);
+        }
+    }
+}
+
+/// Read a key or value of a snapshot entry, bincode encoded as its length, a little
+/// endian u64, followed by its bytes
+async fn read_snapshot_slice<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_len: u64,
+) -> Result<Vec<u8>> {
+    let len = reader.read_u64_le().await?;
+    if len > max_len {
+        return Err(crate::Error::LimitExceeded(format!(
+            "snapshot entry of {len} bytes is longer than the max of {max_len} bytes"
+        )));
+    }
+    let mut buf = vec![0; len as usize];
+    reader.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// Writes a snapshot's entries as they're read, see `Store::snapshot`
+struct SnapshotWriter<'a> {
+    writer: &'a mut (dyn AsyncWrite + Send + Unpin),
+    // entries written so far
+    len: usize,
+}
+impl<'a> SnapshotWriter<'a> {
+    async fn start(writer: &'a mut (dyn AsyncWrite + Send + Unpin)) -> Result<Self> {
+        writer.write_all(&SNAPSHOT_MAGIC).await?;
+        Ok(Self { writer, len: 0 })
+    }
+
+    async fn entry(&mut self, k: &[u8], value: &[u8]) -> Result<()> {
+        let entry = bincode::serialize(&Some((k, value)))?;
+        self.writer.write_all(&entry).await?;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Ends the snapshot, returning how many entries it holds
+    async fn finish(self) -> Result<usize> {
+        let end = bincode::serialize(&None::<(&[u8], &[u8])>)?;
+        self.writer.write_all(&end).await?;
+        self.writer.flush().await?;
+        Ok(self.len)
     }
 }
 
+/// The version of a stored value, for reading a value and later writing it back only if
+/// nobody else changed it in between. Versions are digests of the value's bytes, so
+/// rewriting the same bytes keeps the version, and an absent value has no version.
+pub fn version(value: &[u8]) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, value);
+    digest.as_ref()[..8]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
 #[async_trait]
 pub trait Store {
-    async fn get(&mut self, k: &str) -> Result<Option<Vec<u8>>>;
-    /// Returns all values with keys from `from` (inclusive) to `to` (exclusive).
-    async fn scan(&mut self, from_inclusive: &str, to_exclusive: &str) -> Result<Vec<Vec<u8>>>;
-    async fn transact(&mut self, transaction: Transaction) -> Result<()>;
+    async fn get(&mut self, k: &[u8]) -> Result<Option<Vec<u8>>>;
+    /// Returns whether `k` holds a value. Backends that can tell without fetching the
+    /// value should, the default reads it.
+    async fn exists(&mut self, k: &[u8]) -> Result<bool> {
+        Ok(self.get(k).await?.is_some())
+    }
+    /// Returns the value stored at `k` along with its `version`
+    async fn get_versioned(&mut self, k: &[u8]) -> Result<Option<(Vec<u8>, String)>> {
+        Ok(self.get(k).await?.map(|value| {
+            let version = version(&value);
+            (value, version)
+        }))
+    }
+    /// Returns the length in bytes of the value stored at `k`.
+    /// Backends that track value sizes separately can avoid fetching the value.
+    async fn value_len(&mut self, k: &[u8]) -> Result<Option<usize>> {
+        Ok(self.get(k).await?.map(|v| v.len()))
+    }
+    /// Returns the values of `keys`, in the same order. Backends should read them all
+    /// at a single point in time, so no write is seen by some keys and not others.
+    /// The default reads each key in turn, which is only consistent without writers.
+    async fn get_many(&mut self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        let mut values = Vec::with_capacity(keys.len());
+        for k in keys {
+            values.push(self.get(k).await?);
+        }
+        Ok(values)
+    }
+    /// Returns all values with keys from `from` (inclusive) to `to` (exclusive),
+    /// ordered by key. Every backend must return them in this order, however the
+    /// keys were written or wherever they're stored, which `conformance` checks.
+    async fn scan(&mut self, from_inclusive: &[u8], to_exclusive: &[u8]) -> Result<Vec<Vec<u8>>>;
+    /// Returns up to `limit` keys and values from `start` (inclusive) to `end`
+    /// (exclusive), or to the last key without an `end`, ordered by key. Fails for
+    /// stores that can't list their keys.
+    async fn scan_entries(
+        &mut self,
+        _start: &[u8],
+        _end: Option<&[u8]>,
+        _limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        Err("SCAN: the store can't list its keys".into())
+    }
+    /// Returns up to `limit` keys from `start` (inclusive) to `end` (exclusive), like
+    /// `scan_entries` without their values, so a scan can be resumed from the key
+    /// after the last one it returned. Backends holding keys in order should avoid
+    /// reading the values.
+    async fn scan_keys(
+        &mut self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>> {
+        let entries = self.scan_entries(start, end, limit).await?;
+        Ok(entries.into_iter().map(|(k, _)| k).collect())
+    }
+    /// Removes every key, their TTLs included. Fails for stores that can't be cleared.
+    async fn clear(&mut self) -> Result<()> {
+        Err("FLUSHALL: the store can't be cleared".into())
+    }
+    /// Expires `k` once `ttl` has passed, after which it reads as absent. Setting the
+    /// key again clears its TTL, while transforms keep it. Returns whether `k` exists,
+    /// or `None` when the store can't expire keys.
+    async fn expire(&mut self, _k: &[u8], _ttl: Duration) -> Result<Option<bool>> {
+        Ok(None)
+    }
+    /// Applies every operation in `transaction`, returning whether each operation's key
+    /// held a value beforehand (in the same order as the transaction's operations).
+    async fn transact(&mut self, transaction: Transaction) -> Result<Vec<bool>>;
+    /// Atomically exchanges the values of `a` and `b`. An absent key is treated as null,
+    /// so swapping with an absent key moves the value over and deletes the source.
+    async fn swap(&mut self, a: &[u8], b: &[u8]) -> Result<()>;
+    /// Atomically replaces the value of `k` with the result of `transform`, returning
+    /// the new value. Nothing is written when the transform fails.
+    async fn apply(&mut self, k: &[u8], transform: &Transform) -> Result<Vec<u8>>;
+    /// Atomically sets `k` to `value`, clearing its TTL like a SET, and returns the value
+    /// it held before. The default retries a versioned write until no other write
+    /// interleaves, which keeps the TTL, so backends that expire keys override it.
+    async fn get_set(&mut self, k: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        loop {
+            let old = self.get_versioned(k).await?;
+            let version = old.as_ref().map_or("", |(_, version)| version.as_str());
+            if self.set_if_version(k, version, value).await? {
+                return Ok(old.map(|(old, _)| old));
+            }
+        }
+    }
+    /// Atomically overwrites the bytes of `k` starting at `offset`, zero-padding the
+    /// value up to `offset` if it's shorter (or absent). Returns the new length.
+    async fn set_range(&mut self, k: &[u8], offset: usize, bytes: &[u8]) -> Result<usize> {
+        let transform = Transform::SetRange {
+            offset,
+            bytes: bytes.to_vec(),
+        };
+        Ok(self.apply(k, &transform).await?.len())
+    }
+    /// Atomically sets `k` to `value` if its current `version` is still `version`, an empty
+    /// version only matching an absent key. Returns whether the value was written.
+    async fn set_if_version(&mut self, k: &[u8], version: &str, value: &[u8]) -> Result<bool> {
+        let transform = Transform::SetIfVersion {
+            version: version.to_string(),
+            value: value.to_vec(),
+        };
+        match self.apply(k, &transform).await {
+            Ok(_) => Ok(true),
+            Err(crate::Error::Transform(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+    /// Atomically sets `k` to `value` if it currently holds `expected`, which an absent key
+    /// never does. Returns whether the value was written.
+    async fn compare_and_swap(&mut self, k: &[u8], expected: &[u8], value: &[u8]) -> Result<bool> {
+        let transform = Transform::SetIfEqual {
+            expected: expected.to_vec(),
+            value: value.to_vec(),
+        };
+        match self.apply(k, &transform).await {
+            Ok(_) => Ok(true),
+            Err(crate::Error::Transform(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+    /// Returns the keys whose values are indexed under `attr`, sorted, or `None` when
+    /// the store has no secondary index. See `index::IndexedStore`.
+    async fn find(&mut self, _attr: &[u8]) -> Result<Option<Vec<Vec<u8>>>> {
+        Ok(None)
+    }
+    /// Writes every key and value to `writer` as they were at a single point in time,
+    /// returning how many there were. TTLs aren't written, so restored keys never
+    /// expire. The default takes its view with a single `scan_entries`, which backends
+    /// answer under one lock, holding every entry in memory while they're written.
+    /// Backends that can stream them instead should. Fails for stores that can't list
+    /// their keys.
+    async fn snapshot(&mut self, writer: &mut (dyn AsyncWrite + Send + Unpin)) -> Result<usize> {
+        let entries = self.scan_entries(&[], None, usize::MAX).await?;
+        let mut snapshot = SnapshotWriter::start(writer).await?;
+        for (k, value) in &entries {
+            snapshot.entry(k, value).await?;
+        }
+        snapshot.finish().await
+    }
+    /// Sets every key and value of a snapshot written by `snapshot`, returning how many
+    /// there were. Keys the snapshot doesn't hold are left as they are, so it's meant to
+    /// fill an empty store. They're set a batch at a time, so a restore failing partway
+    /// leaves the batches before it restored.
+    async fn restore(&mut self, reader: &mut (dyn std::io::Read + Send)) -> Result<usize> {
+        let mut magic = [0; SNAPSHOT_MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if magic != SNAPSHOT_MAGIC {
+            return Err("not a snapshot, it's missing the snapshot header".into());
+        }
+        let mut restored = 0;
+        loop {
+            let mut operations = Vec::with_capacity(RESTORE_BATCH);
+            let mut ended = false;
+            while operations.len() < RESTORE_BATCH {
+                match bincode::deserialize_from(&mut *reader)? {
+                    Some((k, value)) => operations.push(Set(k, value)),
+                    None => {
+                        ended = true;
+                        break;
+                    }
+                }
+            }
+            restored += operations.len();
+            if !operations.is_empty() {
+                self.transact(Transaction::with_random_id(operations))
+                    .await?;
+            }
+            if ended {
+                return Ok(restored);
+            }
+        }
+    }
+    /// Atomically sets `field` of the hash at `k` to `value`, creating the hash if it's
+    /// absent. Fails with `Error::Transform` when the hash, with every field and value,
+    /// would be longer than `max_len` bytes. Returns the number of fields in the hash.
+    #[cfg(feature = "hash")]
+    async fn hset(
+        &mut self,
+        k: &[u8],
+        field: &str,
+        value: &[u8],
+        max_len: Option<usize>,
+    ) -> Result<usize> {
+        let transform = Transform::HSet {
+            field: field.to_string(),
+            value: value.to_vec(),
+        }
+        .bounded(max_len);
+        let hash = self.apply(k, &transform).await?;
+        Ok(Hash::decode("hset", Some(&hash))?.len())
+    }
+    /// Atomically adds `by` to the integer in `field` of the hash at `k`, an absent hash
+    /// or field counting as 0. Returns the field's new value.
+    #[cfg(feature = "hash")]
+    async fn hincr(&mut self, k: &[u8], field: &str, by: i64) -> Result<i64> {
+        let transform = Transform::HIncr {
+            field: field.to_string(),
+            by,
+        };
+        let hash = self.apply(k, &transform).await?;
+        let value = Hash::decode("hincr", Some(&hash))?
+            .get(field)
+            .and_then(|value| std::str::from_utf8(value).ok()?.parse().ok())
+            .expect("hincr wrote an integer field");
+        Ok(value)
+    }
+    /// Returns the hash at `k`, failing with `Error::Transform` when it holds a plain value
+    #[cfg(feature = "hash")]
+    async fn hgetall(&mut self, k: &[u8]) -> Result<Option<Hash>> {
+        match self.get(k).await? {
+            Some(value) => Ok(Some(Hash::decode("hget", Some(&value))?)),
+            None => Ok(None),
+        }
+    }
 }
 
-/// A basic in memory store for testing
-#[derive(Clone, Default)]
+/// A basic in memory store for testing, unbounded unless it's given a capacity
+#[derive(Clone)]
 pub struct MemoryStore {
-    data: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+    data: Arc<Mutex<MemoryData>>,
+    // what keys given a TTL expire by
+    clock: Arc<dyn Clock>,
+}
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self {
+            data: Arc::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
 }
 impl MemoryStore {
     pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A store holding up to `max_bytes` of keys and values, evicting the least
+    /// recently used keys to make room for writes
+    pub fn with_capacity(max_bytes: usize) -> Self {
+        Self::with_eviction(max_bytes, Lru::default())
+    }
+
+    /// A store holding up to `max_bytes` of keys and values, evicting the keys
+    /// `eviction` chooses to make room for writes
+    pub fn with_eviction<E: Eviction + 'static>(max_bytes: usize, eviction: E) -> Self {
+        let data = MemoryData {
+            capacity: Some((max_bytes, Box::new(eviction))),
+            ..MemoryData::default()
+        };
         Self {
-            data: Arc::new(Mutex::new(BTreeMap::new())),
+            data: Arc::new(Mutex::new(data)),
+            ..Self::default()
+        }
+    }
+
+    /// Expire keys by `clock` rather than the system clock
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Total size of the stored keys and values
+    pub async fn bytes(&self) -> usize {
+        self.lock().await.bytes
+    }
+
+    /// Reap expired keys every `interval` in the background, so their memory is
+    /// reclaimed even when nothing reads them. Stops once the store is dropped.
+    pub fn reap_every(&self, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let data = Arc::downgrade(&self.data);
+        let clock = self.clock.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                let data = match data.upgrade() {
+                    Some(data) => data,
+                    None => break,
+                };
+                data.lock().await.reap(clock.now());
+            }
+        })
+    }
+
+    /// Lock the store's data, reaping expired keys first so they're never seen
+    async fn lock(&self) -> MutexGuard<'_, MemoryData> {
+        let mut data = self.data.lock().await;
+        data.reap(self.clock.now());
+        data
+    }
+}
+
+#[derive(Default)]
+struct MemoryData {
+    values: BTreeMap<Vec<u8>, Vec<u8>>,
+    // size of every key and value in `values`
+    bytes: usize,
+    // most bytes held, and the policy evicting keys beyond them. Unbounded when `None`
+    capacity: Option<(usize, Box<dyn Eviction>)>,
+    // deadlines of the keys with a TTL, every one of them in `values`
+    expiries: Expiries,
+}
+impl MemoryData {
+    fn get(&mut self, k: &[u8]) -> Option<Vec<u8>> {
+        let value = self.values.get(k).cloned();
+        if let (Some(_), Some((_, eviction))) = (&value, &mut self.capacity) {
+            eviction.touch(k);
+        }
+        value
+    }
+
+    fn insert(&mut self, k: &[u8], value: Vec<u8>) -> Option<Vec<u8>> {
+        self.bytes += k.len() + value.len();
+        if let Some((_, eviction)) = &mut self.capacity {
+            eviction.touch(k);
+        }
+        let previous = self.values.insert(k.to_vec(), value);
+        if let Some(previous) = &previous {
+            self.bytes -= k.len() + previous.len();
+        }
+        previous
+    }
+
+    fn remove(&mut self, k: &[u8]) -> Option<Vec<u8>> {
+        let previous = self.values.remove(k)?;
+        self.bytes -= k.len() + previous.len();
+        if let Some((_, eviction)) = &mut self.capacity {
+            eviction.remove(k);
+        }
+        self.expiries.clear(k);
+        Some(previous)
+    }
+
+    /// Removes every key, forgetting their TTLs and what the eviction policy knew of them
+    fn clear(&mut self) {
+        for k in std::mem::take(&mut self.values).into_keys() {
+            if let Some((_, eviction)) = &mut self.capacity {
+                eviction.remove(&k);
+            }
+        }
+        self.bytes = 0;
+        self.expiries = Expiries::default();
+    }
+
+    /// Removes the keys that expired by `now`
+    fn reap(&mut self, now: std::time::SystemTime) {
+        for key in self.expiries.expired(now) {
+            if self.remove(&key).is_some() {
+                tracing::debug!(key = %String::from_utf8_lossy(&key), "Expired key");
+            }
+        }
+    }
+
+    /// Fails when the keys and values `operations` set couldn't fit even with
+    /// every other key evicted
+    fn check_fits(&self, operations: &[Operation]) -> Result<()> {
+        if let Some((max_bytes, _)) = &self.capacity {
+            let bytes = operations
+                .iter()
+                .map(|op| match op {
+                    Set(key, value) => key.len() + value.len(),
+                    Delete(_) => 0,
+                })
+                .sum::<usize>();
+            if bytes > *max_bytes {
+                return Err(format!(
+                    "writing {bytes} bytes, more than the store's capacity of {max_bytes} bytes"
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Evicts keys until the store is back within its capacity
+    fn evict(&mut self) {
+        while let Some((max_bytes, eviction)) = &mut self.capacity {
+            if self.bytes <= *max_bytes {
+                break;
+            }
+            let key = match eviction.victim() {
+                Some(key) => key,
+                None => break,
+            };
+            if let Some(value) = self.values.remove(&key) {
+                self.bytes -= key.len() + value.len();
+                self.expiries.clear(&key);
+                tracing::debug!(key = %String::from_utf8_lossy(&key), "Evicted key");
+            }
         }
     }
 }
 
 #[async_trait]
 impl Store for MemoryStore {
-    async fn get(&mut self, k: &str) -> Result<Option<Vec<u8>>> {
-        let data = self.data.lock().await;
-        Ok(data.get(&k.to_string()).cloned())
+    async fn get(&mut self, k: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut data = self.lock().await;
+        Ok(data.get(k))
+    }
+
+    async fn exists(&mut self, k: &[u8]) -> Result<bool> {
+        let mut data = self.lock().await;
+        let exists = data.values.contains_key(k);
+        if let (true, Some((_, eviction))) = (exists, &mut data.capacity) {
+            eviction.touch(k);
+        }
+        Ok(exists)
+    }
+
+    async fn get_many(&mut self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        let mut data = self.lock().await;
+        Ok(keys.iter().map(|k| data.get(k)).collect())
     }
 
-    async fn scan(&mut self, from_inclusive: &str, to_exclusive: &str) -> Result<Vec<Vec<u8>>> {
-        let data = self.data.lock().await;
+    async fn scan(&mut self, from_inclusive: &[u8], to_exclusive: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let data = self.lock().await;
         let result = data
-            .range(from_inclusive.to_string()..to_exclusive.to_string())
-            .into_iter()
+            .values
+            .range(from_inclusive.to_vec()..to_exclusive.to_vec())
             .map(|(_, v)| v.to_owned())
             .collect_vec();
         Ok(result)
     }
 
-    async fn transact(&mut self, transaction: Transaction) -> Result<()> {
-        let mut data = self.data.lock().await;
+    async fn scan_entries(
+        &mut self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let data = self.lock().await;
+        let bounds = match scan_bounds(start, end) {
+            Some(bounds) => bounds,
+            None => return Ok(vec![]),
+        };
+        let result = data
+            .values
+            .range(bounds)
+            .take(limit)
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect_vec();
+        Ok(result)
+    }
+
+    async fn scan_keys(
+        &mut self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>> {
+        let data = self.lock().await;
+        let bounds = match scan_bounds(start, end) {
+            Some(bounds) => bounds,
+            None => return Ok(vec![]),
+        };
+        Ok(data
+            .values
+            .range(bounds)
+            .take(limit)
+            .map(|(k, _)| k.clone())
+            .collect())
+    }
+
+    async fn clear(&mut self) -> Result<()> {
+        self.lock().await.clear();
+        Ok(())
+    }
+
+    async fn transact(&mut self, transaction: Transaction) -> Result<Vec<bool>> {
+        let mut data = self.lock().await;
+        data.check_fits(&transaction.operations)?;
+        let mut existed = Vec::with_capacity(transaction.operations.len());
         for instruction in transaction.operations {
-            match instruction {
-                Set(key, value) => data.insert(key.to_string(), value.to_vec()),
-                Delete(key) => data.remove(&key.to_string()),
+            let previous = match instruction {
+                Set(key, value) => {
+                    data.expiries.clear(&key);
+                    data.insert(&key, value)
+                }
+                Delete(key) => data.remove(&key),
             };
+            existed.push(previous.is_some());
+        }
+        data.evict();
+        Ok(existed)
+    }
+
+    async fn swap(&mut self, a: &[u8], b: &[u8]) -> Result<()> {
+        let mut data = self.lock().await;
+        // TTLs move along with the values
+        let expiry_a = data.expiries.clear(a);
+        let expiry_b = data.expiries.clear(b);
+        let value_a = data.remove(a);
+        let value_b = data.remove(b);
+        if let Some(value) = value_b {
+            data.insert(a, value);
+        }
+        if let Some(value) = value_a {
+            data.insert(b, value);
+        }
+        if let Some(at) = expiry_b {
+            data.expiries.set(a, at);
+        }
+        if let Some(at) = expiry_a {
+            data.expiries.set(b, at);
         }
         Ok(())
     }
+
+    async fn expire(&mut self, k: &[u8], ttl: Duration) -> Result<Option<bool>> {
+        let mut data = self.lock().await;
+        if !data.values.contains_key(k) {
+            return Ok(Some(false));
+        }
+        let now = self.clock.now();
+        // a TTL past what the clock can represent never comes due
+        match now.checked_add(ttl) {
+            Some(at) => data.expiries.set(k, at),
+            None => {
+                data.expiries.clear(k);
+            }
+        }
+        // a TTL of 0 expires the key straight away
+        data.reap(now);
+        Ok(Some(true))
+    }
+
+    async fn apply(&mut self, k: &[u8], transform: &Transform) -> Result<Vec<u8>> {
+        let mut data = self.lock().await;
+        let value = transform.apply(data.values.get(k).map(Vec::as_slice))?;
+        data.check_fits(&[Operation::set(k, &value)])?;
+        data.insert(k, value.clone());
+        data.evict();
+        Ok(value)
+    }
+
+    async fn get_set(&mut self, k: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut data = self.lock().await;
+        data.check_fits(&[Operation::set(k, value)])?;
+        data.expiries.clear(k);
+        let old = data.insert(k, value.to_vec());
+        data.evict();
+        Ok(old)
+    }
+}
+
+/// Checks every `Store` backend must pass, run from each backend's tests
+#[cfg(test)]
+pub(crate) mod conformance {
+    use std::collections::BTreeMap;
+
+    use itertools::Itertools;
+
+    use super::{version, Operation, Store, Transaction};
+    use crate::Result;
+
+    /// Batches of writes, each a transaction, with keys out of order within and
+    /// across batches. Each key is set to itself, and a few are deleted later.
+    pub(crate) fn unordered_batches() -> Vec<Transaction> {
+        let batches: [&[&str]; 4] = [
+            &["m", "b", "zz", "a0"],
+            &["10", "B", "z", "a", "9"],
+            &["ab", "aa", "-m", "b", "~"],
+            &["y", "1", "a00", "A"],
+        ];
+        let mut transactions: Vec<Transaction> = batches
+            .iter()
+            .map(|keys| {
+                Transaction::with_random_id(
+                    keys.iter()
+                        .map(|k| Operation::set(*k, k.as_bytes()))
+                        .collect(),
+                )
+            })
+            .collect();
+        transactions.push(Transaction::with_random_id(vec![
+            Operation::delete("zz"),
+            Operation::delete("a0"),
+            Operation::set("0", b"0"),
+        ]));
+        transactions
+    }
+
+    /// Asserts that scans over the keys written by `unordered_batches`, whole
+    /// and in part, return every live value and only those, ordered by key.
+    pub(crate) async fn assert_scans_sorted<S: Store>(store: &mut S) -> Result<()> {
+        let mut expected = BTreeMap::new();
+        for transaction in unordered_batches() {
+            for op in transaction.operations {
+                match op {
+                    Operation::Set(k, v) => expected.insert(k, v),
+                    Operation::Delete(k) => expected.remove(&k),
+                };
+            }
+        }
+        for (from, to) in [("", "~~"), ("a", "b"), ("1", "a0"), ("B", "z")] {
+            let scanned = store.scan(from.as_bytes(), to.as_bytes()).await?;
+            let wanted: Vec<Vec<u8>> = expected
+                .range(from.as_bytes().to_vec()..to.as_bytes().to_vec())
+                .map(|(_, v)| v.clone())
+                .collect();
+            assert_eq!(wanted, scanned, "scan from {from:?} to {to:?}");
+        }
+        Ok(())
+    }
+
+    /// Batches of writes leaving `live` set, `deleted` deleted after it was set,
+    /// `revived` set again after a delete, and `never` deleted without ever being set
+    pub(crate) fn lifecycle_batches() -> Vec<Transaction> {
+        vec![
+            Transaction::with_random_id(vec![
+                Operation::set("live", b"live"),
+                Operation::set("deleted", b"deleted"),
+                Operation::set("revived", b"revived"),
+            ]),
+            Transaction::with_random_id(vec![
+                Operation::delete("deleted"),
+                Operation::delete("revived"),
+                Operation::delete("never"),
+            ]),
+            Transaction::with_random_id(vec![Operation::set("revived", b"")]),
+        ]
+    }
+
+    /// Asserts that reads, versions and writes agree on which keys written by
+    /// `lifecycle_batches` exist, a deleted key being no different from an absent one.
+    pub(crate) async fn assert_entry_semantics<S: Store + Send>(store: &mut S) -> Result<()> {
+        assert_eq!(
+            vec![Some(b"live".to_vec()), None, Some(vec![]), None],
+            store
+                .get_many(&[&b"live"[..], b"deleted", b"revived", b"never"])
+                .await?
+        );
+        assert_eq!(None, store.get(b"deleted").await?);
+        assert_eq!(None, store.value_len(b"deleted").await?);
+        assert_eq!(Some(0), store.value_len(b"revived").await?);
+        assert_eq!(
+            Some((b"live".to_vec(), version(b"live"))),
+            store.get_versioned(b"live").await?
+        );
+        assert_eq!(None, store.get_versioned(b"deleted").await?);
+        assert_eq!(
+            Some((vec![], version(b""))),
+            store.get_versioned(b"revived").await?
+        );
+
+        // a deleted key holds nothing to compare, unlike an empty value
+        assert!(!store.compare_and_swap(b"deleted", b"", b"x").await?);
+        assert!(store.compare_and_swap(b"revived", b"", b"").await?);
+
+        // an empty version matches deleted keys just like absent ones
+        assert!(!store.set_if_version(b"revived", "", b"x").await?);
+        assert!(store.set_if_version(b"deleted", "", b"x").await?);
+        assert!(
+            !store
+                .set_if_version(b"live", &version(b"old"), b"x")
+                .await?
+        );
+        assert!(
+            store
+                .set_if_version(b"live", &version(b"live"), b"x")
+                .await?
+        );
+
+        let existed = store
+            .transact(Transaction::with_random_id(vec![
+                Operation::delete("revived"),
+                Operation::delete("revived"),
+                Operation::set("never", b"x"),
+                Operation::delete("deleted"),
+            ]))
+            .await?;
+        assert_eq!(vec![true, false, false, true], existed);
+        Ok(())
+    }
+
+    /// Asserts that of concurrent compare-and-swaps expecting the same value, exactly
+    /// one wins, and its value is the one stored
+    pub(crate) async fn assert_cas_races<S: Store + Clone + Send + 'static>(
+        store: &mut S,
+    ) -> Result<()> {
+        store
+            .transact(Transaction::with_random_id(vec![Operation::set(
+                "cas", b"0",
+            )]))
+            .await?;
+        for round in 0..100 {
+            let current = store.get(b"cas").await?.expect("cas key was set");
+            let racers = (0..2)
+                .map(|racer| {
+                    let mut store = store.clone();
+                    let current = current.clone();
+                    let new = format!("{round}/{racer}").into_bytes();
+                    tokio::spawn(async move {
+                        let won = store.compare_and_swap(b"cas", &current, &new).await?;
+                        Result::Ok(won.then_some(new))
+                    })
+                })
+                .collect_vec();
+            let mut winners = vec![];
+            for racer in racers {
+                winners.extend(racer.await.expect("cas task panicked")?);
+            }
+            assert_eq!(1, winners.len(), "round {round}");
+            assert_eq!(winners.pop(), store.get(b"cas").await?);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::expiry::ManualClock;
+    use super::transform::Transform;
+    use super::{conformance, MemoryStore, Operation, Store, Transaction};
+    use crate::Result;
+
+    fn set(k: &str, v: &str) -> Transaction {
+        Transaction::with_random_id(vec![Operation::set(k, v.as_bytes())])
+    }
+
+    #[tokio::test]
+    async fn test_memory_scan_sorted() -> Result<()> {
+        let mut store = MemoryStore::new();
+        for transaction in conformance::unordered_batches() {
+            store.transact(transaction).await?;
+        }
+        conformance::assert_scans_sorted(&mut store).await
+    }
+
+    #[tokio::test]
+    async fn test_memory_entry_semantics() -> Result<()> {
+        let mut store = MemoryStore::new();
+        for transaction in conformance::lifecycle_batches() {
+            store.transact(transaction).await?;
+        }
+        conformance::assert_entry_semantics(&mut store).await
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_memory_cas_races() -> Result<()> {
+        conformance::assert_cas_races(&mut MemoryStore::new()).await
+    }
+
+    #[tokio::test]
+    async fn test_memory_expire() -> Result<()> {
+        let clock = ManualClock::default();
+        let mut store = MemoryStore::new().with_clock(clock.clone());
+        store.transact(set("a", "1")).await?;
+        store.transact(set("b", "2")).await?;
+        store.transact(set("c", "3")).await?;
+        let secs = Duration::from_secs;
+        assert_eq!(Some(true), store.expire(b"a", secs(10)).await?);
+        assert_eq!(Some(false), store.expire(b"missing", secs(10)).await?);
+
+        // setting a key clears its TTL, transforming it doesn't
+        assert_eq!(Some(true), store.expire(b"b", secs(10)).await?);
+        store.transact(set("b", "22")).await?;
+        assert_eq!(Some(true), store.expire(b"c", secs(10)).await?);
+        store.apply(b"c", &Transform::Add(1)).await?;
+
+        clock.advance(secs(9));
+        assert_eq!(Some(b"1".to_vec()), store.get(b"a").await?);
+        clock.advance(secs(1));
+        assert_eq!(None, store.get(b"a").await?);
+        assert_eq!(Some(b"22".to_vec()), store.get(b"b").await?);
+        assert_eq!(None, store.get(b"c").await?);
+        assert_eq!(vec![b"22".to_vec()], store.scan(b"a", b"z").await?);
+        assert_eq!(3, store.bytes().await);
+
+        // an expired key is gone, so expiring it again finds nothing
+        assert_eq!(Some(false), store.expire(b"a", secs(10)).await?);
+        // and a TTL of 0 expires a key straight away
+        assert_eq!(Some(true), store.expire(b"b", secs(0)).await?);
+        assert_eq!(None, store.get(b"b").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_memory_swap_moves_ttl() -> Result<()> {
+        let clock = ManualClock::default();
+        let mut store = MemoryStore::new().with_clock(clock.clone());
+        store.transact(set("a", "1")).await?;
+        store.transact(set("b", "2")).await?;
+        store.expire(b"a", Duration::from_secs(10)).await?;
+        store.swap(b"a", b"b").await?;
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(Some(b"2".to_vec()), store.get(b"a").await?);
+        assert_eq!(None, store.get(b"b").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_memory_clear() -> Result<()> {
+        let clock = ManualClock::default();
+        let mut store = MemoryStore::with_capacity(8).with_clock(clock.clone());
+        store.transact(set("a", "1")).await?;
+        store.transact(set("b", "2")).await?;
+        store.expire(b"a", Duration::from_secs(10)).await?;
+        store.clear().await?;
+        assert_eq!(None, store.get(b"a").await?);
+        assert_eq!(None, store.get(b"b").await?);
+        assert_eq!(0, store.bytes().await);
+
+        // keys set again don't inherit the TTLs of the cleared ones, and evict as usual
+        store.transact(set("a", "1")).await?;
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(Some(b"1".to_vec()), store.get(b"a").await?);
+        for k in ["b", "c", "d", "e"] {
+            store.transact(set(k, "1")).await?;
+        }
+        assert_eq!(None, store.get(b"a").await?);
+        assert_eq!(8, store.bytes().await);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_memory_get_set() -> Result<()> {
+        let clock = ManualClock::default();
+        let mut store = MemoryStore::with_capacity(8).with_clock(clock.clone());
+        assert_eq!(None, store.get_set(b"a", b"1").await?);
+        store.expire(b"a", Duration::from_secs(10)).await?;
+        assert_eq!(Some(b"1".to_vec()), store.get_set(b"a", b"22").await?);
+        // like a SET, it clears the TTL
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(Some(b"22".to_vec()), store.get(b"a").await?);
+
+        // values beyond the capacity are refused, keeping the old one
+        assert!(store.get_set(b"a", b"123456789").await.is_err());
+        assert_eq!(Some(b"22".to_vec()), store.get(b"a").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_memory_snapshot_restore() -> Result<()> {
+        let clock = ManualClock::default();
+        let mut store = MemoryStore::new().with_clock(clock.clone());
+        // enough keys for several restore batches
+        let operations = (0..3000)
+            .map(|i| Operation::set(format!("k{i:04}"), format!("v{i}").as_bytes()))
+            .collect();
+        store
+            .transact(Transaction::with_random_id(operations))
+            .await?;
+        store.transact(set("gone", "1")).await?;
+        store.expire(b"gone", Duration::from_secs(10)).await?;
+        clock.advance(Duration::from_secs(10));
+        store
+            .transact(Transaction::with_random_id(vec![Operation::Delete(
+                b"k0000".to_vec(),
+            )]))
+            .await?;
+
+        // deleted and expired keys aren't in the snapshot
+        let mut snapshot = vec![];
+        assert_eq!(2999, store.snapshot(&mut snapshot).await?);
+        let mut restored = MemoryStore::new();
+        assert_eq!(2999, restored.restore(&mut snapshot.as_slice()).await?);
+        assert_eq!(
+            store.scan_entries(&[], None, usize::MAX).await?,
+            restored.scan_entries(&[], None, usize::MAX).await?
+        );
+        assert_eq!(None, restored.get(b"gone").await?);
+
+        // anything else is refused before a key is set
+        let mut empty = MemoryStore::new();
+        assert!(empty.restore(&mut &b"KAVE"[..]).await.is_err());
+        assert!(empty.restore(&mut &snapshot[1..]).await.is_err());
+        assert!(empty.scan_keys(&[], None, 1).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_restore_from_stream() -> Result<()> {
+        let mut store = MemoryStore::new();
+        let operations = (0..1500)
+            .map(|i| Operation::set(format!("k{i:04}"), format!("v{i}").as_bytes()))
+            .collect();
+        store
+            .transact(Transaction::with_random_id(operations))
+            .await?;
+        let mut snapshot = vec![];
+        assert_eq!(1500, store.snapshot(&mut snapshot).await?);
+
+        // the snapshot is read up to its end, leaving what follows it
+        let mut streamed = snapshot.clone();
+        streamed.extend(b"rest");
+        let mut reader = streamed.as_slice();
+        let mut restored = MemoryStore::new();
+        assert_eq!(
+            1500,
+            super::restore_from(&mut restored, &mut reader, 8).await?
+        );
+        assert_eq!(b"rest", reader);
+        assert_eq!(
+            store.scan_entries(&[], None, usize::MAX).await?,
+            restored.scan_entries(&[], None, usize::MAX).await?
+        );
+
+        // and keys or values longer than the max are refused
+        let mut empty = MemoryStore::new();
+        let e = super::restore_from(&mut empty, &mut snapshot.as_slice(), 4)
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("longer than the max"), "{e}");
+        assert!(super::restore_from(&mut empty, &mut &b"KAVE"[..], 8)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_to_file() -> Result<()> {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        tokio::fs::create_dir(&dir).await?;
+        let path = dir.join("backup");
+        let mut store = MemoryStore::new();
+        store.transact(set("a", "1")).await?;
+        assert_eq!(1, super::snapshot_to_file(&mut store, &path).await?);
+        let snapshot = tokio::fs::read(&path).await?;
+
+        // an existing file is never replaced
+        store.transact(set("b", "2")).await?;
+        assert!(super::snapshot_to_file(&mut store, &path).await.is_err());
+        assert_eq!(snapshot, tokio::fs::read(&path).await?);
+        let mut restored = MemoryStore::new();
+        assert_eq!(1, restored.restore(&mut snapshot.as_slice()).await?);
+        // and no temporary file is left behind
+        assert_eq!(1, std::fs::read_dir(&dir)?.count());
+        tokio::fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_memory_snapshot_consistent() -> Result<()> {
+        let store = MemoryStore::new();
+        // a writer keeps setting both keys to the same value in one transaction
+        let mut writer = store.clone();
+        let writes = tokio::spawn(async move {
+            for i in 0..2000 {
+                let v = i.to_string();
+                writer
+                    .transact(Transaction::with_random_id(vec![
+                        Operation::set("a", v.as_bytes()),
+                        Operation::set("b", v.as_bytes()),
+                    ]))
+                    .await?;
+            }
+            Result::Ok(())
+        });
+        // so every snapshot taken meanwhile holds them equal
+        for _ in 0..200 {
+            let mut snapshot = vec![];
+            store.clone().snapshot(&mut snapshot).await?;
+            let mut restored = MemoryStore::new();
+            restored.restore(&mut snapshot.as_slice()).await?;
+            assert_eq!(restored.get(b"a").await?, restored.get(b"b").await?);
+            tokio::task::yield_now().await;
+        }
+        writes.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_memory_reaps_in_background() -> Result<()> {
+        tokio::time::pause();
+        let clock = ManualClock::default();
+        let mut store = MemoryStore::new().with_clock(clock.clone());
+        store.transact(set("a", "1")).await?;
+        store.expire(b"a", Duration::from_secs(10)).await?;
+        store.reap_every(Duration::from_secs(1));
+
+        clock.advance(Duration::from_secs(10));
+        // untouched by reads, the key is still held until the reaper runs
+        assert_eq!(2, store.data.lock().await.bytes);
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let data = store.data.lock().await;
+        assert!(data.values.is_empty());
+        assert_eq!(0, data.bytes);
+        Ok(())
+    }
 }
@@ -0,0 +1,424 @@
+//! Publishing the writes applied to a leader's store, for followers to apply to theirs
+//!
+//! `ReplicatedStore` wraps any store and publishes every write made through it to a
+//! `ReplicationLog`, as the `Transaction` the write amounts to, in the order they were
+//! applied. Clearing the store is published as a `Record::Clear`. Each record is
+//! numbered by the order it was published in. The cluster `Server` streams the log to
+//! followers, which apply each record to their own store and acknowledge its number
+//! back, so a client can wait for its writes to reach them with WAITREPL. A follower
+//! connecting, or reconnecting, is first sent a snapshot of the leader's store taken
+//! once it's subscribed, which it replaces its own with, so it never misses a write
+//! the log published before. Like the secondary index, it isn't free:
+//! - writes through the same `ReplicatedStore` are serialized, so they're published
+//!   in the order they're applied
+//! - transforms (APPLY, SETRANGE, CAS, ...) are published as a SET of the value they
+//!   wrote, and SWAPs read both values back after swapping them
+//! - TTLs aren't replicated, keys that expire on the leader stay on its followers
+//! - followers refuse writes from their own clients, so they never diverge from it
+//! - a follower that falls more than the log's capacity behind is disconnected, and
+//!   caught back up by the snapshot it's sent when it reconnects. Its store is cleared
+//!   before the snapshot's restored, so its clients read its keys missing until then
+//! - records sent after the snapshot may already be in it, which is fine since
+//!   applying them again in order leaves the same values
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::sync::{broadcast, Mutex, Notify};
+
+use super::transform::Transform;
+use super::{Operation, Store, Transaction};
+use crate::{Error, Result};
+
+/// A write published by a leader, for its followers to apply in the same order
+#[derive(Deserialize, Serialize, Debug, Eq, PartialEq, Clone)]
+pub enum Record {
+    Transaction(Transaction),
+    /// The leader's store was cleared
+    Clear,
+}
+impl Record {
+    /// Apply the write to a follower's `store`
+    pub async fn apply_to<S: Store + Send>(self, store: &mut S) -> Result<()> {
+        match self {
+            Record::Transaction(transaction) => store.transact(transaction).await.map(|_| ()),
+            Record::Clear => store.clear().await,
+        }
+    }
+}
+
+/// Where a leader's applied writes are published to its followers, and where they
+/// acknowledge applying them. Clones publish to the same followers.
+#[derive(Clone, Debug)]
+pub struct ReplicationLog {
+    sender: broadcast::Sender<(u64, Record)>,
+    // number of the last record published, held while it's sent so numbers stay in order
+    published: Arc<std::sync::Mutex<u64>>,
+    // number of the last record each connected follower acknowledged applying
+    acked: Arc<std::sync::Mutex<HashMap<String, u64>>>,
+    // notified whenever a follower acknowledges a record or disconnects
+    acks: Arc<Notify>,
+}
+impl ReplicationLog {
+    /// A log buffering up to `capacity` transactions a follower hasn't been sent yet
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            sender: broadcast::channel(capacity).0,
+            published: Arc::new(std::sync::Mutex::new(0)),
+            acked: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            acks: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Receive every record published from now on, along with its number
+    pub fn subscribe(&self) -> broadcast::Receiver<(u64, Record)> {
+        self.sender.subscribe()
+    }
+
+    /// Receive every record published from now on, along with the number of the last
+    /// one published before, which a snapshot of the store taken afterwards holds
+    pub fn follow(&self) -> (u64, broadcast::Receiver<(u64, Record)>) {
+        let published = self.published.lock().unwrap();
+        (*published, self.sender.subscribe())
+    }
+
+    /// The number of the last record published, 0 before any is
+    pub fn published(&self) -> u64 {
+        *self.published.lock().unwrap()
+    }
+
+    fn publish(&self, record: Record) {
+        let mut published = self.published.lock().unwrap();
+        *published += 1;
+        // this errors when no follower is connected, which is fine
+        let _ = self.sender.send((*published, record));
+    }
+
+    /// Record that `follower` applied every record up to number `seq`
+    pub fn ack(&self, follower: &str, seq: u64) {
+        let mut acked = self.acked.lock().unwrap();
+        let last = acked.entry(follower.to_string()).or_default();
+        *last = (*last).max(seq);
+        self.acks.notify_waiters();
+    }
+
+    /// Stop counting `follower`'s acknowledgements, once it's disconnected
+    pub fn forget(&self, follower: &str) {
+        self.acked.lock().unwrap().remove(follower);
+        self.acks.notify_waiters();
+    }
+
+    /// The number of connected followers that applied every record up to number `seq`
+    pub fn acked(&self, seq: u64) -> usize {
+        let acked = self.acked.lock().unwrap();
+        acked.values().filter(|last| **last >= seq).count()
+    }
+
+    /// Wait up to `timeout` for `replicas` followers to apply every record up to number
+    /// `seq`, returning how many had when it returns
+    pub async fn wait_for(&self, seq: u64, replicas: usize, timeout: Duration) -> usize {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            // created before counting, so an ack arriving in between isn't missed
+            let notified = self.acks.notified();
+            let acked = self.acked(seq);
+            if acked >= replicas {
+                return acked;
+            }
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                return self.acked(seq);
+            }
+        }
+    }
+
+    fn publish_transaction(&self, operations: Vec<Operation>) {
+        self.publish(Record::Transaction(Transaction::with_random_id(operations)));
+    }
+}
+
+// bincode bytes framing a record besides its operations: its number, its variant,
+// the transaction's id and the number of operations
+const RECORD_OVERHEAD: usize = 8 + 4 + 8 + 16 + 8;
+// bincode bytes framing each operation besides its key and value: its variant and
+// their lengths
+const OPERATION_OVERHEAD: usize = 4 + 8 + 8;
+
+/// The longest frame a follower reads, for transactions of up to `max_operations`
+/// operations whose keys and values add up to at most `max_len` bytes. Longer ones
+/// can't have come from a leader taking the same limits.
+pub fn max_frame_len(max_len: usize, max_operations: usize) -> u64 {
+    let overhead = OPERATION_OVERHEAD.saturating_mul(max_operations);
+    max_len
+        .saturating_add(RECORD_OVERHEAD)
+        .saturating_add(overhead) as u64
+}
+
+/// Frame record number `seq` to be sent to a follower, prefixed by its length like the
+/// lines of the commit log
+pub fn encode(seq: u64, record: &Record) -> Result<Vec<u8>> {
+    let size = bincode::serialized_size(&(seq, record))?;
+    let mut buf = size.to_be_bytes().to_vec();
+    buf.append(&mut bincode::serialize(&(seq, record))?);
+    Ok(buf)
+}
+
+/// Read the next record framed by `encode` and its number, `None` once the stream ends
+/// between frames. Fails without reading frames longer than `max_len`, see `max_frame_len`.
+pub async fn decode_from<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_len: u64,
+) -> Result<Option<(u64, Record)>> {
+    let size = match reader.read_u64().await {
+        Ok(size) => size,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(Error::from(e)),
+    };
+    if size > max_len {
+        return Err(Error::LimitExceeded(format!(
+            "replication frame of {size} bytes is longer than the max of {max_len} bytes"
+        )));
+    }
+    let mut buf = vec![0; size as usize];
+    reader.read_exact(&mut buf).await?;
+    Ok(Some(bincode::deserialize(buf.as_slice())?))
+}
+
+/// A store publishing its writes to a `ReplicationLog`, see the module docs for what it costs
+#[derive(Clone)]
+pub struct ReplicatedStore<S> {
+    inner: S,
+    log: ReplicationLog,
+    // held while a write is applied and published
+    writing: Arc<Mutex<()>>,
+}
+impl<S> ReplicatedStore<S> {
+    pub fn new(inner: S, log: ReplicationLog) -> Self {
+        Self {
+            inner,
+            log,
+            writing: Arc::new(Mutex::new(())),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Store + Send + Sync> Store for ReplicatedStore<S> {
+    async fn get(&mut self, k: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get(k).await
+    }
+
+    async fn exists(&mut self, k: &[u8]) -> Result<bool> {
+        self.inner.exists(k).await
+    }
+
+    async fn get_versioned(&mut self, k: &[u8]) -> Result<Option<(Vec<u8>, String)>> {
+        self.inner.get_versioned(k).await
+    }
+
+    async fn value_len(&mut self, k: &[u8]) -> Result<Option<usize>> {
+        self.inner.value_len(k).await
+    }
+
+    async fn get_many(&mut self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.inner.get_many(keys).await
+    }
+
+    async fn scan(&mut self, from_inclusive: &[u8], to_exclusive: &[u8]) -> Result<Vec<Vec<u8>>> {
+        self.inner.scan(from_inclusive, to_exclusive).await
+    }
+
+    async fn scan_entries(
+        &mut self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.inner.scan_entries(start, end, limit).await
+    }
+
+    async fn scan_keys(
+        &mut self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>> {
+        self.inner.scan_keys(start, end, limit).await
+    }
+
+    async fn snapshot(&mut self, writer: &mut (dyn AsyncWrite + Send + Unpin)) -> Result<usize> {
+        self.inner.snapshot(writer).await
+    }
+
+    async fn clear(&mut self) -> Result<()> {
+        let _writing = self.writing.lock().await;
+        self.inner.clear().await?;
+        self.log.publish(Record::Clear);
+        Ok(())
+    }
+
+    async fn expire(&mut self, k: &[u8], ttl: Duration) -> Result<Option<bool>> {
+        self.inner.expire(k, ttl).await
+    }
+
+    async fn transact(&mut self, transaction: Transaction) -> Result<Vec<bool>> {
+        let _writing = self.writing.lock().await;
+        let existed = self.inner.transact(transaction.clone()).await?;
+        self.log.publish(Record::Transaction(transaction));
+        Ok(existed)
+    }
+
+    async fn swap(&mut self, a: &[u8], b: &[u8]) -> Result<()> {
+        let _writing = self.writing.lock().await;
+        self.inner.swap(a, b).await?;
+        let mut operations = Vec::with_capacity(2);
+        for k in [a, b] {
+            operations.push(match self.inner.get(k).await? {
+                Some(value) => Operation::Set(k.to_vec(), value),
+                None => Operation::Delete(k.to_vec()),
+            });
+        }
+        self.log.publish_transaction(operations);
+        Ok(())
+    }
+
+    async fn apply(&mut self, k: &[u8], transform: &Transform) -> Result<Vec<u8>> {
+        let _writing = self.writing.lock().await;
+        let value = self.inner.apply(k, transform).await?;
+        self.log
+            .publish_transaction(vec![Operation::set(k, &value)]);
+        Ok(value)
+    }
+
+    async fn get_set(&mut self, k: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        let _writing = self.writing.lock().await;
+        let old = self.inner.get_set(k, value).await?;
+        self.log.publish_transaction(vec![Operation::set(k, value)]);
+        Ok(old)
+    }
+
+    async fn find(&mut self, attr: &[u8]) -> Result<Option<Vec<Vec<u8>>>> {
+        self.inner.find(attr).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{decode_from, encode, max_frame_len, Record, ReplicatedStore, ReplicationLog};
+    use crate::store::transform::Transform;
+    use crate::store::{MemoryStore, Operation, Store, Transaction};
+    use crate::Result;
+
+    #[tokio::test]
+    async fn test_replicated_writes() -> Result<()> {
+        let log = ReplicationLog::new(16);
+        let mut published = log.subscribe();
+        let mut store = ReplicatedStore::new(MemoryStore::new(), log);
+        let set =
+            Transaction::with_random_id(vec![Operation::set("a", b"1"), Operation::set("b", b"2")]);
+        store.transact(set.clone()).await?;
+        store.swap(b"a", b"c").await?;
+        store.apply(b"b", &Transform::Add(40)).await?;
+        store.apply(b"b", &Transform::Append(b"!".to_vec())).await?;
+        store.swap(b"x", b"y").await?;
+        // failed writes aren't published
+        assert!(store.apply(b"b", &Transform::Add(1)).await.is_err());
+
+        // a follower applying what's published ends up with the same values
+        let mut follower = MemoryStore::new();
+        assert_eq!(
+            (1, Record::Transaction(set.clone())),
+            published.recv().await.unwrap()
+        );
+        follower.transact(set).await?;
+        let mut n = 1;
+        while let Ok((seq, record)) = published.try_recv() {
+            n += 1;
+            assert_eq!(n, seq);
+            let mut framed = encode(seq, &record)?;
+            framed.extend(encode(seq, &record)?);
+            let mut reader = framed.as_slice();
+            let max_len = max_frame_len(64, 2);
+            assert_eq!(
+                Some((seq, record.clone())),
+                decode_from(&mut reader, max_len).await?
+            );
+            assert_eq!(
+                Some((seq, record.clone())),
+                decode_from(&mut reader, max_len).await?
+            );
+            assert_eq!(None, decode_from(&mut reader, max_len).await?);
+            record.apply_to(&mut follower).await?;
+        }
+        assert_eq!(5, n);
+        for k in [&b"a"[..], b"b", b"c", b"x", b"y"] {
+            assert_eq!(store.get(k).await?, follower.get(k).await?);
+        }
+        assert_eq!(Some(b"42!".to_vec()), follower.get(b"b").await?);
+
+        // and none of them once the leader's store is cleared
+        store.clear().await?;
+        let (seq, record) = published.recv().await.unwrap();
+        assert_eq!((6, Record::Clear), (seq, record.clone()));
+        record.apply_to(&mut follower).await?;
+        assert!(follower.scan_keys(b"", None, 10).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_frame_len_bounded() -> Result<()> {
+        // the largest transaction within the limits is read
+        let operations = vec![Operation::set("k", b"v1"), Operation::set("", b"v2")];
+        let record = Record::Transaction(Transaction::with_random_id(operations));
+        let framed = encode(u64::MAX, &record)?;
+        let max_len = max_frame_len(5, 2);
+        assert_eq!(max_len, framed.len() as u64 - 8);
+        assert_eq!(
+            Some((u64::MAX, record)),
+            decode_from(&mut framed.as_slice(), max_len).await?
+        );
+
+        // and anything longer fails without allocating the length it claims
+        let mut framed = u64::MAX.to_be_bytes().to_vec();
+        framed.extend(b"garbage");
+        let e = decode_from(&mut framed.as_slice(), max_len)
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("longer than the max"), "{e}");
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_wait_for_acks() -> Result<()> {
+        let log = ReplicationLog::new(16);
+        let mut store = ReplicatedStore::new(MemoryStore::new(), log.clone());
+        for k in ["a", "b"] {
+            let set = Transaction::with_random_id(vec![Operation::set(k, b"1")]);
+            store.transact(set).await?;
+        }
+        assert_eq!(2, log.published());
+
+        // followers count once they've applied the record waited on
+        log.ack("f1", 1);
+        assert_eq!(1, log.wait_for(1, 1, Duration::from_secs(10)).await);
+        let waiting = tokio::spawn({
+            let log = log.clone();
+            async move { log.wait_for(2, 2, Duration::from_secs(10)).await }
+        });
+        tokio::task::yield_now().await;
+        log.ack("f2", 2);
+        log.ack("f1", 2);
+        assert_eq!(2, waiting.await.unwrap());
+
+        // and stop counting once they disconnect, whatever they applied
+        log.forget("f1");
+        let start = tokio::time::Instant::now();
+        assert_eq!(1, log.wait_for(2, 2, Duration::from_millis(200)).await);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        Ok(())
+    }
+}
@@ -0,0 +1,376 @@
+//! Built-in transforms that `Store::apply` uses to read-modify-write a value atomically
+#[cfg(feature = "hash")]
+use super::hash::Hash;
+use crate::error::{Error, Result};
+
+/// Names of every built-in transform that can be looked up with `Transform::parse`
+pub const TRANSFORMS: &[&str] = &["add", "append", "setbit", "clearbit"];
+
+// Longest a transform may grow a value to by writing at an offset
+const MAX_GROWN_LEN: usize = 512 * 1024 * 1024;
+// Largest bit offset for `setbit`/`clearbit`
+const MAX_BIT_OFFSET: usize = MAX_GROWN_LEN * 8 - 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transform {
+    /// Adds to a value holding a decimal integer, an absent value counts as 0
+    Add(i64),
+    /// Appends bytes to the value, an absent value counts as empty
+    Append(Vec<u8>),
+    /// Sets the bit at an offset, growing the value with zero bytes as needed.
+    /// Bit 0 is the most significant bit of the first byte.
+    SetBit(usize),
+    /// Clears the bit at an offset, like `SetBit`
+    ClearBit(usize),
+    /// Overwrites the bytes from an offset onwards, zero-padding the value up to the
+    /// offset as needed. Only used by `Store::set_range` since it takes two arguments.
+    SetRange { offset: usize, bytes: Vec<u8> },
+    /// Sets a field of a hash value, an absent value counts as an empty hash.
+    /// Only used by `Store::hset`.
+    #[cfg(feature = "hash")]
+    HSet { field: String, value: Vec<u8> },
+    /// Adds to a field of a hash holding a decimal integer, an absent hash or field
+    /// counts as 0. Only used by `Store::hincr`.
+    #[cfg(feature = "hash")]
+    HIncr { field: String, by: i64 },
+    /// Replaces the value when its current version is `version`, an empty version
+    /// matching an absent value. Only used by `Store::set_if_version`.
+    SetIfVersion { version: String, value: Vec<u8> },
+    /// Replaces the value when it's currently `expected`, an absent value never matching.
+    /// Only used by `Store::compare_and_swap`.
+    SetIfEqual { expected: Vec<u8>, value: Vec<u8> },
+    /// Applies a transform, failing when its result is longer than `max_len` so it's
+    /// never stored. See `Transform::bounded`.
+    Bounded {
+        transform: Box<Transform>,
+        max_len: usize,
+    },
+}
+
+impl Transform {
+    /// Look up the transform called `name`, parsing its argument
+    pub fn parse(name: &str, arg: &[u8]) -> Result<Self> {
+        match name {
+            "add" => Ok(Transform::Add(parse_int(name, "argument", arg)?)),
+            "append" => Ok(Transform::Append(arg.to_vec())),
+            "setbit" | "clearbit" => {
+                let offset = parse_int(name, "argument", arg)?;
+                if offset > MAX_BIT_OFFSET {
+                    return Err(Error::Transform(format!(
+                        "{name}: bit offset is larger than {MAX_BIT_OFFSET}"
+                    )));
+                }
+                Ok(match name {
+                    "setbit" => Transform::SetBit(offset),
+                    _ => Transform::ClearBit(offset),
+                })
+            }
+            _ => Err(Error::Transform(format!(
+                "unknown transform {name:?}, expected one of ({})",
+                TRANSFORMS.join("|")
+            ))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Transform::Add(_) => "add",
+            Transform::Append(_) => "append",
+            Transform::SetBit(_) => "setbit",
+            Transform::ClearBit(_) => "clearbit",
+            Transform::SetRange { .. } => "setrange",
+            #[cfg(feature = "hash")]
+            Transform::HSet { .. } => "hset",
+            #[cfg(feature = "hash")]
+            Transform::HIncr { .. } => "hincr",
+            Transform::SetIfVersion { .. } => "casv",
+            Transform::SetIfEqual { .. } => "cas",
+            Transform::Bounded { transform, .. } => transform.name(),
+        }
+    }
+
+    /// Bound the values the transform may produce to `max_len` bytes, as the values
+    /// SET writes are, unbounded when `None`
+    pub fn bounded(self, max_len: Option<usize>) -> Self {
+        match max_len {
+            Some(max_len) => Transform::Bounded {
+                transform: Box::new(self),
+                max_len,
+            },
+            None => self,
+        }
+    }
+
+    /// Compute the new value from the current one, failing with `Error::Transform`
+    /// when the current value doesn't suit the transform
+    pub fn apply(&self, stored: Option<&[u8]>) -> Result<Vec<u8>> {
+        let current = stored.unwrap_or_default();
+        match self {
+            Transform::Add(n) => {
+                let value: i64 = if current.is_empty() {
+                    0
+                } else {
+                    parse_int(self.name(), "value", current)?
+                };
+                let sum = value
+                    .checked_add(*n)
+                    .ok_or_else(|| Error::Transform("add: result would overflow".to_string()))?;
+                Ok(sum.to_string().into_bytes())
+            }
+            Transform::Append(suffix) => Ok([current, suffix].concat()),
+            Transform::SetBit(offset) | Transform::ClearBit(offset) => {
+                let mut value = current.to_vec();
+                let (byte, mask) = (offset / 8, 0x80 >> (offset % 8));
+                if value.len() <= byte {
+                    value.resize(byte + 1, 0);
+                }
+                if matches!(self, Transform::SetBit(_)) {
+                    value[byte] |= mask;
+                } else {
+                    value[byte] &= !mask;
+                }
+                Ok(value)
+            }
+            Transform::SetRange { offset, bytes } => {
+                let end = offset
+                    .checked_add(bytes.len())
+                    .filter(|end| *end <= MAX_GROWN_LEN)
+                    .ok_or_else(|| {
+                        Error::Transform(format!(
+                            "setrange: value would be longer than {MAX_GROWN_LEN} bytes"
+                        ))
+                    })?;
+                let mut value = current.to_vec();
+                if value.len() < end {
+                    value.resize(end, 0);
+                }
+                value[*offset..end].copy_from_slice(bytes);
+                Ok(value)
+            }
+            #[cfg(feature = "hash")]
+            Transform::HSet { field, value } => {
+                let mut hash = Hash::decode(self.name(), stored)?;
+                hash.insert(field.clone(), value.clone());
+                Ok(hash.encode())
+            }
+            #[cfg(feature = "hash")]
+            Transform::HIncr { field, by } => {
+                let mut hash = Hash::decode(self.name(), stored)?;
+                let value: i64 = match hash.get(field) {
+                    Some(value) if !value.is_empty() => {
+                        parse_int(self.name(), "field value", value)?
+                    }
+                    _ => 0,
+                };
+                let sum = value
+                    .checked_add(*by)
+                    .ok_or_else(|| Error::Transform("hincr: result would overflow".to_string()))?;
+                hash.insert(field.clone(), sum.to_string().into_bytes());
+                Ok(hash.encode())
+            }
+            Transform::SetIfVersion { version, value } => {
+                if stored.map(super::version).unwrap_or_default() != *version {
+                    return Err(Error::Transform("casv: version mismatch".to_string()));
+                }
+                Ok(value.clone())
+            }
+            Transform::SetIfEqual { expected, value } => {
+                if stored != Some(expected.as_slice()) {
+                    return Err(Error::Transform("cas: value mismatch".to_string()));
+                }
+                Ok(value.clone())
+            }
+            Transform::Bounded { transform, max_len } => {
+                let value = transform.apply(stored)?;
+                if value.len() > *max_len {
+                    return Err(Error::Transform(format!(
+                        "{}: value of {} bytes exceeds max value size of {max_len} bytes",
+                        self.name(),
+                        value.len()
+                    )));
+                }
+                Ok(value)
+            }
+        }
+    }
+}
+
+fn parse_int<T: std::str::FromStr>(name: &str, what: &str, bytes: &[u8]) -> Result<T> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| Error::Transform(format!("{name}: {what} is not an integer")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Transform;
+    use crate::error::Error;
+
+    #[test]
+    fn test_add() {
+        let add = Transform::parse("add", b"5").unwrap();
+        assert_eq!(b"5".to_vec(), add.apply(None).unwrap());
+        assert_eq!(b"7".to_vec(), add.apply(Some(b"2")).unwrap());
+        let sub = Transform::parse("add", b"-10").unwrap();
+        assert_eq!(b"-8".to_vec(), sub.apply(Some(b"2")).unwrap());
+        let max = i64::MAX.to_string();
+        assert!(matches!(
+            add.apply(Some(max.as_bytes())),
+            Err(Error::Transform(_))
+        ));
+    }
+
+    #[test]
+    fn test_append() {
+        let append = Transform::parse("append", b"bar").unwrap();
+        assert_eq!(b"bar".to_vec(), append.apply(None).unwrap());
+        assert_eq!(b"foobar".to_vec(), append.apply(Some(b"foo")).unwrap());
+    }
+
+    #[test]
+    fn test_bounded() {
+        let append = Transform::parse("append", b"bar").unwrap().bounded(Some(6));
+        assert_eq!("append", append.name());
+        assert_eq!(b"foobar".to_vec(), append.apply(Some(b"foo")).unwrap());
+        assert!(matches!(
+            append.apply(Some(b"fooo")),
+            Err(Error::Transform(msg)) if msg == "append: value of 7 bytes exceeds max value size of 6 bytes"
+        ));
+        let unbounded = Transform::parse("append", b"bar").unwrap().bounded(None);
+        assert_eq!(Transform::Append(b"bar".to_vec()), unbounded);
+    }
+
+    #[test]
+    fn test_bits() {
+        let set = Transform::parse("setbit", b"9").unwrap();
+        assert_eq!(vec![0x00, 0x40], set.apply(None).unwrap());
+        assert_eq!(
+            vec![0xff, 0x40, 0x01],
+            set.apply(Some(&[0xff, 0x00, 0x01])).unwrap()
+        );
+        let clear = Transform::parse("clearbit", b"0").unwrap();
+        assert_eq!(vec![0x7f], clear.apply(Some(&[0xff])).unwrap());
+        // clearing past the end still grows the value
+        let clear = Transform::parse("clearbit", b"8").unwrap();
+        assert_eq!(vec![0xff, 0x00], clear.apply(Some(&[0xff])).unwrap());
+    }
+
+    #[test]
+    fn test_set_if_version() {
+        let set = |version: &str| Transform::SetIfVersion {
+            version: version.to_string(),
+            value: b"new".to_vec(),
+        };
+        assert_eq!(b"new".to_vec(), set("").apply(None).unwrap());
+        assert!(matches!(set("").apply(Some(b"")), Err(Error::Transform(_))));
+        let version = crate::store::version(b"old");
+        assert_eq!(b"new".to_vec(), set(&version).apply(Some(b"old")).unwrap());
+        assert!(matches!(
+            set(&version).apply(Some(b"older")),
+            Err(Error::Transform(_))
+        ));
+        assert!(matches!(
+            set(&version).apply(None),
+            Err(Error::Transform(_))
+        ));
+    }
+
+    #[test]
+    fn test_set_if_equal() {
+        let cas = |expected: &[u8]| Transform::SetIfEqual {
+            expected: expected.to_vec(),
+            value: b"new".to_vec(),
+        };
+        assert_eq!(b"new".to_vec(), cas(b"old").apply(Some(b"old")).unwrap());
+        assert_eq!(b"new".to_vec(), cas(b"").apply(Some(b"")).unwrap());
+        assert!(matches!(
+            cas(b"old").apply(Some(b"older")),
+            Err(Error::Transform(_))
+        ));
+        assert!(matches!(cas(b"").apply(None), Err(Error::Transform(_))));
+    }
+
+    #[test]
+    fn test_set_range() {
+        let set_range = |offset, bytes: &[u8]| Transform::SetRange {
+            offset,
+            bytes: bytes.to_vec(),
+        };
+        // overwriting within the value
+        assert_eq!(
+            b"hello kaved".to_vec(),
+            set_range(6, b"kave").apply(Some(b"hello world")).unwrap()
+        );
+        assert_eq!(
+            b"Hello world".to_vec(),
+            set_range(0, b"H").apply(Some(b"hello world")).unwrap()
+        );
+        // past the end is zero-padded, including absent values
+        assert_eq!(
+            b"ab\0\0cd".to_vec(),
+            set_range(4, b"cd").apply(Some(b"ab")).unwrap()
+        );
+        assert_eq!(b"\0\0cd".to_vec(), set_range(2, b"cd").apply(None).unwrap());
+        assert!(matches!(
+            set_range(usize::MAX, b"x").apply(None),
+            Err(Error::Transform(_))
+        ));
+    }
+
+    #[cfg(feature = "hash")]
+    #[test]
+    fn test_hincr() {
+        use crate::store::hash::Hash;
+
+        let incr = |field: &str, by| Transform::HIncr {
+            field: field.to_string(),
+            by,
+        };
+        let hash = incr("count", 5).apply(None).unwrap();
+        let hash = incr("count", -7).apply(Some(&hash)).unwrap();
+        let hash = incr("other", 1).apply(Some(&hash)).unwrap();
+        let decoded = Hash::decode("hget", Some(&hash)).unwrap();
+        assert_eq!(Some(&b"-2"[..]), decoded.get("count"));
+        assert_eq!(Some(&b"1"[..]), decoded.get("other"));
+
+        let set = Transform::HSet {
+            field: "name".to_string(),
+            value: b"ada".to_vec(),
+        };
+        let hash = set.apply(Some(&hash)).unwrap();
+        match incr("name", 1).apply(Some(&hash)) {
+            Err(Error::Transform(msg)) => assert_eq!("hincr: field value is not an integer", msg),
+            res => panic!("unexpected result {res:?}"),
+        }
+        match incr("count", i64::MAX).apply(Some(&incr("count", 1).apply(None).unwrap())) {
+            Err(Error::Transform(msg)) => assert_eq!("hincr: result would overflow", msg),
+            res => panic!("unexpected result {res:?}"),
+        }
+        match incr("count", 1).apply(Some(b"plain")) {
+            Err(Error::Transform(msg)) => assert_eq!("hincr: value is not a hash", msg),
+            res => panic!("unexpected result {res:?}"),
+        }
+    }
+
+    #[test]
+    fn test_type_mismatch() {
+        let add = Transform::parse("add", b"1").unwrap();
+        match add.apply(Some(b"one")) {
+            Err(Error::Transform(msg)) => assert_eq!("add: value is not an integer", msg),
+            res => panic!("unexpected result {res:?}"),
+        }
+        assert!(matches!(
+            Transform::parse("add", b"one"),
+            Err(Error::Transform(_))
+        ));
+        assert!(matches!(
+            Transform::parse("setbit", b"-1"),
+            Err(Error::Transform(_))
+        ));
+        assert!(matches!(
+            Transform::parse("rot13", b""),
+            Err(Error::Transform(_))
+        ));
+    }
+}
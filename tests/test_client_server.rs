@@ -1,8 +1,25 @@
+use std::sync::Arc;
 use std::time::Duration;
 
-use kave::server::{load_certs, load_keys, ClientServer};
-use kave::store::MemoryStore;
-use tokio::io::{split, AsyncWriteExt};
+use async_trait::async_trait;
+use kave::audit::AuditRecord;
+use kave::client::pool::Pool;
+use kave::client::ring::Ring;
+use kave::client::{Client, ClusterClient, Response};
+use kave::config::{
+    ConnectionLimitPolicy, StoreKind, TransactionLimitPolicy, ValueLimitPolicy, WireTrace,
+};
+use kave::proto::{BufferPool, ProtoLimits, ProtoOp, COMMANDS};
+use kave::server::events::{CloseReason, SessionEvent};
+use kave::server::handler::{Builtin, CommandContext, CommandHandler};
+use kave::server::metrics::Outcome;
+use kave::server::shards::Shards;
+use kave::server::{load_certs, load_keys, ClientServer, MAX_QUEUED_OPS};
+use kave::store::backend::{BackendStore, StoreBackend};
+use kave::store::transform::Transform;
+use kave::store::{Durability, MemoryStore, Store, Transaction};
+use kave::Config;
+use tokio::io::{split, AsyncReadExt, AsyncWriteExt};
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use tokio::time::sleep;
 
@@ -13,6 +30,16 @@ fn new_client_server() -> (
     UnboundedSender<bool>,
     UnboundedReceiver<bool>,
     ClientServer<MemoryStore>,
+) {
+    new_client_server_with_store(MemoryStore::new())
+}
+
+fn new_client_server_with_store<S: Store + Send + Sync + Clone + 'static>(
+    store: S,
+) -> (
+    UnboundedSender<bool>,
+    UnboundedReceiver<bool>,
+    ClientServer<S>,
 ) {
     let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
     let keys = load_keys("certs/defaults/key.pem").expect("error loading default test keys");
@@ -26,7 +53,7 @@ fn new_client_server() -> (
         sig_client_shutdown_recv,
         certs,
         keys,
-        MemoryStore::new(),
+        store,
     );
     (
         sig_client_shutdown_send,
@@ -38,8 +65,13 @@ fn new_client_server() -> (
 /// create a new client server and wait for it start
 macro_rules! start_client_server {
     ($addr:expr) => {{
+        start_client_server!($addr, |_| {})
+    }};
+    ($addr:expr, $configure:expr) => {{
         let (shutdown_send, shutdown_recv, mut cs) = new_client_server();
         cs.set_addr($addr);
+        let configure: fn(&mut ClientServer<MemoryStore>) = $configure;
+        configure(&mut cs);
         tokio::spawn(async move { cs.start().await });
         tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
         (shutdown_send, shutdown_recv)
@@ -51,24 +83,23 @@ async fn test_client_server_basic() {
     init!();
     let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7310");
 
-    let stream = utils::connect("localhost:7310")
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
+    let mut client = Client::connect("localhost", 7310, certs.clone())
         .await
         .expect("error connecting to test addr");
-    let (mut reader, mut writer) = split(stream);
-
-    write_all!(writer, b"ECHO:10:working!!!\n");
-    let buf = read_buf!(reader, 10);
-    assert_eq!(std::str::from_utf8(&buf).unwrap(), "10:working!!!\n");
+    assert_eq!(
+        b"working!!!".to_vec(),
+        client.echo(b"working!!!").await.unwrap()
+    );
 
     // also works connection to 127.0.0.1
-    let stream = utils::connect("127.0.0.1:7310")
+    let mut client = Client::connect("127.0.0.1", 7310, certs)
         .await
         .expect("error connecting to test addr");
-    let (mut reader, mut writer) = split(stream);
-
-    write_all!(writer, b"ECHO:10:working!!!\n");
-    let buf = read_buf!(reader, 10);
-    assert_eq!(std::str::from_utf8(&buf).unwrap(), "10:working!!!\n");
+    assert_eq!(
+        b"working!!!".to_vec(),
+        client.echo(b"working!!!").await.unwrap()
+    );
 
     // send shutdown and assert that it actually shuts down
     shutdown_send
@@ -99,8 +130,8 @@ async fn test_client_server_get_set() {
         writer,
         b"SET:5:abcde:30:012345678901234567890123456789-a-this-should-be-ignored\n"
     );
-    let buf = read_buf!(reader, 4);
-    assert_eq!(std::str::from_utf8(&buf).unwrap(), "2:30\n");
+    let buf = read_buf!(reader, 14);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "2:30:7:created\n");
 
     // get previously set key
     write_all!(writer, b"GET:5:abcde-b-this-should-be-ignored\n");
@@ -149,8 +180,8 @@ async fn test_client_server_partial_writes() {
         writer,
         b"678901234567890123456789-a-this-should-be-ignored\n"
     );
-    let buf = read_buf!(reader, 4);
-    assert_eq!(std::str::from_utf8(&buf).unwrap(), "2:30\n");
+    let buf = read_buf!(reader, 14);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "2:30:7:created\n");
 
     // get previously set key
     write_all!(writer, b"GET:5:abcde-b-this-should-be-ignored\n");
@@ -168,3 +199,5009 @@ async fn test_client_server_partial_writes() {
         .await
         .expect("client-server failed to shutdown");
 }
+
+#[tokio::test]
+async fn test_client_server_debug_sleep_command_timeout() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7313", |cs| {
+        cs.set_debug_commands(true)
+            .set_command_timeout(Some(Duration::from_millis(200)));
+    });
+
+    let stream = utils::connect("localhost:7313")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    // sleeping within the timeout responds normally
+    write_all!(writer, b"DEBUG:5:SLEEP:2:50\n");
+    let buf = read_buf!(reader, 3);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "OK\n");
+
+    // sleeping past the timeout closes the session without a response
+    write_all!(writer, b"DEBUG:5:SLEEP:4:1000\n");
+    let mut buf = vec![];
+    let res = tokio::time::timeout(Duration::from_millis(500), reader.read_buf(&mut buf))
+        .await
+        .expect("command timeout did not fire");
+    assert!(matches!(res, Ok(0) | Err(_)));
+    assert!(buf.is_empty());
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_debug_commands_disabled() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7314");
+
+    let stream = utils::connect("localhost:7314")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    write_all!(writer, b"DEBUG:5:SLEEP:2:10\n");
+    let mut buf = vec![];
+    let res = tokio::time::timeout(Duration::from_secs(1), reader.read_buf(&mut buf))
+        .await
+        .expect("session was not closed");
+    assert!(matches!(res, Ok(0) | Err(_)));
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_set_created_updated() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7315");
+
+    let stream = utils::connect("localhost:7315")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    // first set of a key creates it
+    write_all!(writer, b"SET:3:foo:3:bar\n");
+    let buf = read_buf!(reader, 14);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "1:3:7:created\n");
+
+    // setting the same key again updates it
+    write_all!(writer, b"SET:3:foo:4:barr\n");
+    let buf = read_buf!(reader, 14);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "1:4:7:updated\n");
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+/// start a client server with SO_REUSEPORT on its own single threaded runtime
+fn spawn_reuse_port_runtime(
+    addr: &'static str,
+    store: MemoryStore,
+) -> (UnboundedSender<bool>, UnboundedReceiver<bool>) {
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
+    let keys = load_keys("certs/defaults/key.pem").expect("error loading default test keys");
+    let (svr_shutdown_send, svr_shutdown_recv) = tokio::sync::mpsc::unbounded_channel();
+    let (sig_shutdown_send, sig_shutdown_recv) = tokio::sync::mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("error building runtime");
+        runtime.block_on(async move {
+            let mut cs =
+                ClientServer::new(svr_shutdown_send, sig_shutdown_recv, certs, keys, store);
+            cs.set_addr(addr).set_reuse_port(true);
+            cs.start().await
+        });
+    });
+    (sig_shutdown_send, svr_shutdown_recv)
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_client_server_reuse_port_multiple_runtimes() {
+    init!();
+    let store = MemoryStore::new();
+    let servers = vec![
+        spawn_reuse_port_runtime("127.0.0.1:7316", store.clone()),
+        spawn_reuse_port_runtime("127.0.0.1:7316", store.clone()),
+    ];
+    sleep(Duration::from_millis(100)).await;
+
+    let tasks = (0..16).map(|i| {
+        tokio::spawn(async move {
+            let stream = utils::connect("localhost:7316")
+                .await
+                .expect("error connecting to test addr");
+            let (mut reader, mut writer) = split(stream);
+            let key = format!("key-{i:02}");
+            let set = format!("SET:{}:{key}:{}:{i}\n", key.len(), i.to_string().len());
+            write_all!(writer, set.as_bytes());
+            let buf = read_buf!(reader, 14);
+            assert!(std::str::from_utf8(&buf).unwrap().ends_with(":7:created\n"));
+        })
+    });
+    for task in futures::future::join_all(tasks).await {
+        task.expect("error running client task");
+    }
+
+    // every write is visible regardless of which runtime accepted the connection
+    let stream = utils::connect("localhost:7316")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    for i in 0..16 {
+        let key = format!("key-{i:02}");
+        write_all!(writer, format!("GET:{}:{key}\n", key.len()).as_bytes());
+        let expected = format!("{}:{i}\n", i.to_string().len());
+        let buf = read_buf!(reader, expected.len());
+        assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+    }
+
+    for (shutdown_send, mut shutdown_recv) in servers {
+        shutdown_send
+            .send(true)
+            .expect("error sending client-server shutdown");
+        tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+            .await
+            .expect("client-server failed to shutdown");
+    }
+}
+
+#[tokio::test]
+async fn test_client_server_noreply_bulk_load() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7317");
+
+    let stream = utils::connect("localhost:7317")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    // pipeline many quiet writes, overwriting some keys, then confirm with a single GET
+    let mut batch = vec![];
+    for i in 0..500 {
+        let key = format!("key-{:03}", i % 250);
+        batch.extend_from_slice(
+            format!("SETQ:{}:{key}:{}:{i}\n", key.len(), i.to_string().len()).as_bytes(),
+        );
+    }
+    batch.extend_from_slice(b"GET:7:key-249\n");
+    write_all!(writer, &batch);
+    let buf = read_buf!(reader, 4);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "3:499\n");
+
+    // every write landed, with later writes winning
+    let mut gets = vec![];
+    let mut expected = String::new();
+    for i in 0..250 {
+        let key = format!("key-{i:03}");
+        gets.extend_from_slice(format!("GET:{}:{key}\n", key.len()).as_bytes());
+        expected.push_str(&format!("3:{}\n", i + 250));
+    }
+    write_all!(writer, &gets);
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // quiet deletes are applied in order too
+    write_all!(
+        writer,
+        b"DELQ:7:key-000\nSETQ:7:key-001:3:new\nDELQ:7:key-001\nGET:7:key-001\n"
+    );
+    let buf = read_buf!(reader, 4);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "null\n");
+    write_all!(writer, b"GET:7:key-000\n");
+    let buf = read_buf!(reader, 4);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "null\n");
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_del() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7318");
+
+    let stream = utils::connect("localhost:7318")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    write_all!(writer, b"SET:3:foo:3:bar\n");
+    let buf = read_buf!(reader, 14);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "1:3:7:created\n");
+
+    write_all!(writer, b"DEL:3:foo\n");
+    let buf = read_buf!(reader, 4);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "1:1\n");
+
+    write_all!(writer, b"DEL:3:foo\n");
+    let buf = read_buf!(reader, 4);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "1:0\n");
+
+    write_all!(writer, b"GET:3:foo\n");
+    let buf = read_buf!(reader, 4);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "null\n");
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_strlen() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7319");
+
+    let stream = utils::connect("localhost:7319")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    // absent key
+    write_all!(writer, b"STRLEN:3:foo\n");
+    let buf = read_buf!(reader, 4);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "null\n");
+
+    // present key
+    write_all!(writer, b"SET:3:foo:12:hello world!\n");
+    let buf = read_buf!(reader, 14);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "2:12:7:created\n");
+    write_all!(writer, b"STRLEN:3:foo\n");
+    let buf = read_buf!(reader, 5);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "2:12\n");
+
+    // empty value
+    write_all!(writer, b"SET:5:empty:0:\n");
+    let buf = read_buf!(reader, 14);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "1:0:7:created\n");
+    write_all!(writer, b"STRLEN:5:empty\n");
+    let buf = read_buf!(reader, 4);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "1:0\n");
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_exists() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7395");
+
+    let stream = utils::connect("localhost:7395")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    // absent key
+    write_all!(writer, b"EXISTS:3:foo\n");
+    let buf = read_buf!(reader, 4);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "1:0\n");
+
+    // present key, even with an empty value
+    write_all!(writer, b"SET:3:foo:0:\nEXISTS:3:foo\n");
+    let buf = read_buf!(reader, 18);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "1:0:7:created\n1:1\n");
+
+    // deleted key
+    write_all!(writer, b"DEL:3:foo\nEXISTS:3:foo\n");
+    let buf = read_buf!(reader, 8);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "1:1\n1:0\n");
+
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
+    let mut client = Client::connect("localhost", 7395, certs)
+        .await
+        .expect("error connecting to test addr");
+    client.set(b"bar", b"baz").await.expect("error setting bar");
+    assert!(client.exists(b"bar").await.expect("error checking bar"));
+    assert!(!client.exists(b"foo").await.expect("error checking foo"));
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_getset() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7396");
+
+    let stream = utils::connect("localhost:7396")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    // an absent key replaces null, then each GETSET returns the value before it
+    write_all!(writer, b"GETSET:1:n:1:0\nGETSET:1:n:2:10\nGET:1:n\n");
+    let buf = read_buf!(reader, 14);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "null\n1:0\n2:10\n");
+
+    // and it can't be queued in a transaction
+    write_all!(writer, b"BEGIN\nGETSET:1:n:1:1\nDISCARD\n");
+    let expected = "OK\nERR:61:GETSET can't be queued in a transaction, only SET and DEL can\nOK\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // concurrent GETSETs chain one after another, so every value is replaced exactly once,
+    // except the last one standing
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
+    let mut setters = vec![];
+    for setter_id in 0..3 {
+        let mut client = Client::connect("localhost", 7396, certs.clone())
+            .await
+            .expect("error connecting to test addr");
+        setters.push(tokio::spawn(async move {
+            let mut replaced = vec![];
+            for i in 0..4 {
+                let value = format!("{setter_id}:{i}").into_bytes();
+                let old = client.get_set(b"n", &value).await.expect("error in GETSET");
+                replaced.push(old.expect("n has a value"));
+            }
+            replaced
+        }));
+    }
+    let mut replaced = vec![];
+    for setter in setters {
+        replaced.extend(setter.await.expect("GETSET task panicked"));
+    }
+    let mut client = Client::connect("localhost", 7396, certs)
+        .await
+        .expect("error connecting to test addr");
+    replaced.push(client.get(b"n").await.unwrap().expect("n has a value"));
+    replaced.sort();
+    let mut written = (0..3)
+        .flat_map(|setter_id| (0..4).map(move |i| format!("{setter_id}:{i}").into_bytes()))
+        .collect::<Vec<_>>();
+    written.push(b"10".to_vec());
+    written.sort();
+    assert_eq!(written, replaced);
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_audit_records() {
+    init!();
+    let (audit_send, mut audit_recv) = tokio::sync::mpsc::unbounded_channel::<AuditRecord>();
+    let (shutdown_send, mut shutdown_recv, mut cs) = new_client_server();
+    cs.set_addr("127.0.0.1:7320")
+        .set_audit_sink(Some(Arc::new(audit_send)));
+    tokio::spawn(async move { cs.start().await });
+    sleep(Duration::from_millis(100)).await;
+
+    let stream = utils::connect("localhost:7320")
+        .await
+        .expect("error connecting to test addr");
+    let local_addr = stream.get_ref().0.local_addr().unwrap();
+    let (mut reader, mut writer) = split(stream);
+
+    write_all!(
+        writer,
+        b"SET:3:foo:3:bar\nSET:3:foo:3:baz\nGET:3:foo\nDEL:3:foo\nDELQ:3:foo\nECHO:2:hi\n"
+    );
+    let expected = "1:3:7:created\n1:3:7:updated\n3:baz\n1:1\n2:hi\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // only mutating commands are audited, in the order they were applied
+    let mut records = vec![];
+    while let Ok(Some(record)) =
+        tokio::time::timeout(Duration::from_millis(100), audit_recv.recv()).await
+    {
+        records.push(record);
+    }
+    let summary = records
+        .iter()
+        .map(|r| (r.op.as_str(), r.key.as_str(), r.result.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        vec![
+            ("SET", "foo", "created"),
+            ("SET", "foo", "updated"),
+            ("DEL", "foo", "deleted"),
+            ("DEL", "foo", "not_found"),
+        ],
+        summary
+    );
+    for record in &records {
+        assert_eq!(record.peer, local_addr);
+        assert_eq!(record.session, records[0].session);
+        assert_eq!(record.identity, None);
+        assert!(record.timestamp_ms > 0);
+    }
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_value_limit_reject() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7321", |cs| {
+        cs.set_max_value_len(Some(5))
+            .set_value_limit_policy(ValueLimitPolicy::Reject);
+    });
+
+    let stream = utils::connect("localhost:7321")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    // over-limit writes are rejected, even when noreply, and leave the store untouched
+    write_all!(
+        writer,
+        b"SET:3:foo:6:abcdef\nSETQ:3:foo:6:abcdef\nGET:3:foo\n"
+    );
+    let expected = "ERR:50:value of 6 bytes exceeds max value size of 5 bytes\n\
+                    ERR:50:value of 6 bytes exceeds max value size of 5 bytes\n\
+                    null\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // values at the limit are accepted
+    write_all!(writer, b"SET:3:foo:5:abcde\nGET:3:foo\n");
+    let expected = "1:5:7:created\n5:abcde\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // and one queued in a transaction discards it, so COMMIT writes nothing
+    write_all!(
+        writer,
+        b"BEGIN\nSET:3:bar:1:a\nSET:3:baz:6:abcdef\nCOMMIT\nMGET:1:2:3:bar:3:baz\n"
+    );
+    let expected = "OK\nQUEUED\n\
+                    ERR:73:transaction discarded, value of 6 bytes exceeds max value size of 5 bytes\n\
+                    ERR:30:COMMIT: no transaction is open\n\
+                    1:2:null:null\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[cfg(feature = "hash")]
+#[tokio::test]
+async fn test_client_server_hash_limits() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7405", |cs| {
+        cs.set_max_value_len(Some(64));
+    });
+
+    let stream = utils::connect("localhost:7405")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    // fields that each fit the max value size are rejected once the hash holding them
+    // doesn't, leaving the hash untouched
+    let value = "v".repeat(20);
+    let request = format!("HSET:1:h:2:f1:20:{value}\nHSET:1:h:2:f2:20:{value}\nHGETALL:1:h\n");
+    write_all!(writer, request.as_bytes());
+    assert_eq!("1:1\n", read_line(&mut reader).await);
+    let msg = "hset: value of 95 bytes exceeds max value size of 64 bytes";
+    assert_eq!(
+        format!("ERR:{}:{msg}\n", msg.len()),
+        read_line(&mut reader).await
+    );
+    assert_eq!(format!("2:f1:20:{value}\n"), read_line(&mut reader).await);
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_apply_limits() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7402", |cs| {
+        cs.set_max_value_len(Some(5)).set_max_response_len(Some(4));
+    });
+
+    let stream = utils::connect("localhost:7402")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    // appending past the max value size is rejected and leaves the value untouched
+    write_all!(
+        writer,
+        b"SET:3:foo:3:abc\nAPPLY:3:foo:6:append:3:def\nGET:3:foo\n"
+    );
+    let expected =
+        "1:3:7:created\nERR:58:append: value of 6 bytes exceeds max value size of 5 bytes\n3:abc\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // a result at the max value size is stored, but the response is still bounded
+    write_all!(writer, b"APPLY:3:foo:6:append:2:de\nSTRLEN:3:foo\n");
+    let expected =
+        "ERR:72:response of 5 bytes exceeds max response size of 4 bytes, use pagination\n1:5\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_value_limit_truncate() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7322", |cs| {
+        cs.set_max_value_len(Some(5))
+            .set_value_limit_policy(ValueLimitPolicy::Truncate);
+    });
+
+    let stream = utils::connect("localhost:7322")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    // over-limit writes store exactly `max` bytes and say so
+    write_all!(writer, b"SET:3:foo:6:abcdef\nSTRLEN:3:foo\nGET:3:foo\n");
+    let expected = "1:5:7:created:9:truncated\n1:5\n5:abcde\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // values at the limit aren't flagged
+    write_all!(writer, b"SET:3:foo:5:vwxyz\n");
+    let expected = "1:5:7:updated\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_swap() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7323");
+
+    let stream = utils::connect("localhost:7323")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    write_all!(writer, b"SETQ:5:front:1:1\nSETQ:4:back:1:2\n");
+    write_all!(writer, b"SWAP:5:front:4:back\nGET:5:front\nGET:4:back\n");
+    let expected = "OK\n1:2\n1:1\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // an absent key is treated as null
+    write_all!(
+        writer,
+        b"SWAP:5:front:6:absent\nGET:5:front\nGET:6:absent\n"
+    );
+    let expected = "OK\nnull\n1:2\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // swapping two absent keys leaves them absent
+    write_all!(writer, b"SWAP:1:x:1:y\nGET:1:x\nGET:1:y\n");
+    let expected = "OK\nnull\nnull\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_client_max_value_size() {
+    init!();
+    let (audit_send, mut audit_recv) = tokio::sync::mpsc::unbounded_channel::<AuditRecord>();
+    let (shutdown_send, mut shutdown_recv, mut cs) = new_client_server();
+    cs.set_addr("127.0.0.1:7324")
+        .set_max_value_len(Some(5))
+        .set_audit_sink(Some(Arc::new(audit_send)));
+    tokio::spawn(async move { cs.start().await });
+    sleep(Duration::from_millis(100)).await;
+
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
+    let mut client = Client::connect("localhost", 7324, certs)
+        .await
+        .expect("error connecting to test addr");
+    assert_eq!(Some(5), client.max_value_size());
+
+    // the over-limit value is rejected locally, so the server never sees it
+    let err = client.set(b"foo", b"abcdef").await.unwrap_err();
+    assert!(err.to_string().contains("exceeds"), "{err}");
+    assert_eq!(5, client.set(b"bar", b"abcde").await.unwrap());
+    let mut records = vec![];
+    while let Ok(Some(record)) =
+        tokio::time::timeout(Duration::from_millis(100), audit_recv.recv()).await
+    {
+        records.push((record.op, record.key, record.result));
+    }
+    assert_eq!(
+        vec![("SET".to_string(), "bar".to_string(), "created".to_string())],
+        records
+    );
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_absent_empty_error() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7325", |cs| {
+        cs.set_max_value_len(Some(5));
+    });
+
+    let stream = utils::connect("localhost:7325")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    write_all!(writer, b"SETQ:5:empty:0:\n");
+    let cases: [(&[u8], Response); 5] = [
+        (b"GET:6:absent\n", Response::NotFound),
+        (b"GET:5:empty\n", Response::Value(vec![])),
+        (b"STRLEN:6:absent\n", Response::NotFound),
+        (b"STRLEN:5:empty\n", Response::Value(b"0".to_vec())),
+        (
+            b"SET:3:big:6:abcdef\n",
+            Response::Error("value of 6 bytes exceeds max value size of 5 bytes".into()),
+        ),
+    ];
+    for (cmd, expected) in cases {
+        write_all!(writer, cmd);
+        let mut buf = vec![];
+        let response = loop {
+            reader
+                .read_buf(&mut buf)
+                .await
+                .expect("error reading response");
+            if let Some((response, n)) = Response::decode(&buf).expect("invalid response") {
+                assert_eq!(n, buf.len());
+                break response;
+            }
+        };
+        assert_eq!(expected, response, "{}", String::from_utf8_lossy(cmd));
+    }
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+/// A store whose transactions take a while, to keep them in flight
+#[derive(Clone, Default)]
+struct SlowStore {
+    inner: MemoryStore,
+}
+
+#[async_trait]
+impl Store for SlowStore {
+    async fn get(&mut self, k: &[u8]) -> kave::Result<Option<Vec<u8>>> {
+        self.inner.get(k).await
+    }
+
+    async fn scan(&mut self, from: &[u8], to: &[u8]) -> kave::Result<Vec<Vec<u8>>> {
+        self.inner.scan(from, to).await
+    }
+
+    async fn transact(&mut self, transaction: Transaction) -> kave::Result<Vec<bool>> {
+        sleep(Duration::from_millis(300)).await;
+        self.inner.transact(transaction).await
+    }
+
+    async fn swap(&mut self, a: &[u8], b: &[u8]) -> kave::Result<()> {
+        sleep(Duration::from_millis(300)).await;
+        self.inner.swap(a, b).await
+    }
+
+    async fn apply(&mut self, k: &[u8], transform: &Transform) -> kave::Result<Vec<u8>> {
+        sleep(Duration::from_millis(300)).await;
+        self.inner.apply(k, transform).await
+    }
+}
+
+/// Send a SET on two connections at once to a server allowing a single
+/// transaction in flight, returning each connection's response and when it arrived
+async fn concurrent_sets(addr: &str, policy: TransactionLimitPolicy) -> Vec<(String, Duration)> {
+    let (shutdown_send, mut shutdown_recv, mut cs) =
+        new_client_server_with_store(SlowStore::default());
+    cs.set_addr(addr)
+        .set_max_transactions(Some(1))
+        .set_transaction_limit_policy(policy);
+    tokio::spawn(async move { cs.start().await });
+    sleep(Duration::from_millis(100)).await;
+
+    let start = tokio::time::Instant::now();
+    let mut sessions = vec![];
+    for key in ["first", "secnd"] {
+        let stream = utils::connect(&addr.replace("127.0.0.1", "localhost"))
+            .await
+            .expect("error connecting to test addr");
+        sessions.push(tokio::spawn(async move {
+            let (mut reader, mut writer) = split(stream);
+            write_all!(writer, format!("SET:5:{key}:3:bar\n").as_bytes());
+            let buf = read_buf!(reader, 14);
+            (String::from_utf8(buf).unwrap(), start.elapsed())
+        }));
+        sleep(Duration::from_millis(50)).await;
+    }
+    let mut results = vec![];
+    for session in sessions {
+        results.push(session.await.expect("session panicked"));
+    }
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+    results
+}
+
+#[tokio::test]
+async fn test_client_server_max_transactions_reject() {
+    init!();
+    let results = concurrent_sets("127.0.0.1:7326", TransactionLimitPolicy::Reject).await;
+    assert_eq!(results[0].0, "1:3:7:created\n");
+    // the second transaction is turned away while the first is in flight
+    assert_eq!(
+        results[1].0,
+        "ERR:50:busy: too many transactions in flight, retry later\n"
+    );
+    assert!(results[1].1 < results[0].1);
+}
+
+#[tokio::test]
+async fn test_client_server_max_transactions_queue() {
+    init!();
+    let results = concurrent_sets("127.0.0.1:7327", TransactionLimitPolicy::Queue).await;
+    assert_eq!(results[0].0, "1:3:7:created\n");
+    // the second transaction waits for the first to finish
+    assert_eq!(results[1].0, "1:3:7:created\n");
+    assert!(results[1].1 >= results[0].1 + Duration::from_millis(250));
+}
+
+#[tokio::test]
+async fn test_client_server_namespaces() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7328");
+
+    let stream = utils::connect("localhost:7328")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    let stream = utils::connect("localhost:7328")
+        .await
+        .expect("error connecting to test addr");
+    let (mut other_reader, mut other_writer) = split(stream);
+
+    // the same key in different namespaces doesn't collide
+    write_all!(writer, b"USE:4:app1\nSET:3:foo:3:one\nGET:3:foo\n");
+    let expected = "OK\n1:3:7:created\n3:one\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+    write_all!(other_writer, b"USE:4:app2\nGET:3:foo\nSET:3:foo:3:two\n");
+    let expected = "OK\nnull\n1:3:7:created\n";
+    let buf = read_buf!(other_reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+    write_all!(writer, b"GET:3:foo\nSWAP:3:foo:3:bar\nGET:3:bar\n");
+    let expected = "3:one\nOK\n3:one\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // nor does the default namespace reach them, however it names them
+    write_all!(
+        other_writer,
+        b"USE:0:\nGET:3:foo\nGET:8:app1:bar\nSET:8:app2:foo:3:new\nSCAN:0::2:10\n"
+    );
+    let expected = "OK\nnull\nnull\n1:3:7:created\n1:2:0::8:app2:foo\n";
+    let buf = read_buf!(other_reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+    write_all!(
+        other_writer,
+        b"GET:9:\xffapp1:bar\nDEL:9:\xffapp2:foo\nUSE:4:app2\nGET:3:foo\n"
+    );
+    let expected = "ERR:58:keys starting with a 0xff byte are reserved for namespaces\n\
+                    ERR:58:keys starting with a 0xff byte are reserved for namespaces\n\
+                    OK\n3:two\n";
+    let buf = read_buf!(other_reader, expected.len());
+    assert_eq!(&buf, expected.as_bytes());
+
+    // namespaces can't contain the separator
+    write_all!(writer, b"USE:3:a:b\nGET:3:bar\n");
+    let expected = "ERR:29:namespace may not contain ':'\n3:one\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_session_events() {
+    init!();
+    let (shutdown_send, mut shutdown_recv, mut cs) = new_client_server();
+    cs.set_addr("127.0.0.1:7329");
+    let mut events = cs.events();
+    tokio::spawn(async move { cs.start().await });
+    sleep(Duration::from_millis(100)).await;
+
+    let stream = utils::connect("localhost:7329")
+        .await
+        .expect("error connecting to test addr");
+    let local_addr = stream.get_ref().0.local_addr().unwrap();
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"ECHO:2:hi\nSETQ:3:foo:3:bar\n");
+    let buf = read_buf!(reader, 5);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "2:hi\n");
+    writer.shutdown().await.expect("error shutting down");
+
+    let mut received = vec![];
+    while let Ok(Ok(event)) = tokio::time::timeout(Duration::from_secs(1), events.recv()).await {
+        let closed = matches!(event, SessionEvent::Closed { .. });
+        received.push(event);
+        if closed {
+            break;
+        }
+    }
+    let session = match &received[0] {
+        SessionEvent::Opened { session, peer } => {
+            assert_eq!(*peer, local_addr);
+            session.clone()
+        }
+        e => panic!("expected an opened event, found {e:?}"),
+    };
+    let summary = received[1..]
+        .iter()
+        .map(|event| match event {
+            SessionEvent::Command {
+                session: s, op, ok, ..
+            } => {
+                assert_eq!(s, &session);
+                format!("{op} ok={ok}")
+            }
+            SessionEvent::Closed { session: s, reason } => {
+                assert_eq!(s, &session);
+                format!("closed {reason:?}")
+            }
+            e => panic!("unexpected event {e:?}"),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        vec![
+            "ECHO ok=true",
+            "SETQ ok=true",
+            &format!("closed {:?}", CloseReason::ClientDisconnected)
+        ],
+        summary
+    );
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_non_canonical_length() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7330");
+
+    for cmd in [
+        &b"SET:3:foo:03:bar\n"[..],
+        b"SET:3:foo:+3:bar\n",
+        b"SET:3:foo: 3:bar\n",
+        b"SET:3:foo:0x3:bar\n",
+        b"GET:00:\n",
+        b"SET:3:foo:111111111111111111111:bar\n",
+    ] {
+        let stream = utils::connect("localhost:7330")
+            .await
+            .expect("error connecting to test addr");
+        let (mut reader, mut writer) = split(stream);
+        write_all!(writer, cmd);
+        // the session is closed without a result
+        let mut buf = vec![];
+        let n = reader.read_to_end(&mut buf).await.unwrap_or(0);
+        assert_eq!(0, n, "{}", String::from_utf8_lossy(cmd));
+    }
+
+    // and nothing was stored
+    let stream = utils::connect("localhost:7330")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"GET:3:foo\n");
+    let buf = read_buf!(reader, 5);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "null\n");
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_unknown_command() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7331", |cs| {
+        cs.set_strict_protocol(false);
+    });
+
+    let stream = utils::connect("localhost:7331")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    // the known commands depend on the enabled features
+    let error = |name: &str| {
+        let msg = format!(
+            "unknown command {name:?}, expected one of {}",
+            COMMANDS.join(", ")
+        );
+        format!("ERR:{}:{msg}\n", msg.len())
+    };
+    write_all!(writer, b"FOO:3:bar\n");
+    let expected = error("FOO");
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // op names longer than any known command are cut short
+    write_all!(writer, b"NOTACOMMAND\n");
+    let expected = error("NOTACOMM");
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // and the connection is still usable
+    write_all!(writer, b"SET:3:foo:3:bar\n");
+    let buf = read_buf!(reader, 14);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "1:3:7:created\n");
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+/// Store that panics when swapping the `boom` key
+#[derive(Clone, Default)]
+struct PanicStore {
+    inner: MemoryStore,
+}
+
+#[async_trait]
+impl Store for PanicStore {
+    async fn get(&mut self, k: &[u8]) -> kave::Result<Option<Vec<u8>>> {
+        self.inner.get(k).await
+    }
+
+    async fn scan(&mut self, from: &[u8], to: &[u8]) -> kave::Result<Vec<Vec<u8>>> {
+        self.inner.scan(from, to).await
+    }
+
+    async fn transact(&mut self, transaction: Transaction) -> kave::Result<Vec<bool>> {
+        self.inner.transact(transaction).await
+    }
+
+    async fn swap(&mut self, a: &[u8], b: &[u8]) -> kave::Result<()> {
+        if a == b"boom" || b == b"boom" {
+            panic!("boom");
+        }
+        self.inner.swap(a, b).await
+    }
+
+    async fn apply(&mut self, k: &[u8], transform: &Transform) -> kave::Result<Vec<u8>> {
+        self.inner.apply(k, transform).await
+    }
+}
+
+#[tokio::test]
+async fn test_client_server_session_cleanup_on_panic() {
+    init!();
+    let (shutdown_send, mut shutdown_recv, mut cs) =
+        new_client_server_with_store(PanicStore::default());
+    // a leaked transaction slot would reject every later SET
+    cs.set_addr("127.0.0.1:7332")
+        .set_max_transactions(Some(1))
+        .set_transaction_limit_policy(TransactionLimitPolicy::Reject);
+    let sessions = cs.sessions();
+    tokio::spawn(async move { cs.start().await });
+    sleep(Duration::from_millis(100)).await;
+    let localhost = "127.0.0.1".parse().unwrap();
+    assert!(sessions.is_empty());
+
+    let panicking = utils::connect("localhost:7332")
+        .await
+        .expect("error connecting to test addr");
+    let healthy = utils::connect("localhost:7332")
+        .await
+        .expect("error connecting to test addr");
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(2, sessions.len());
+    assert_eq!(2, sessions.connections_from(localhost));
+
+    // the session's task panics mid-transaction
+    let (mut reader, mut writer) = split(panicking);
+    write_all!(writer, b"SWAP:4:boom:3:foo\n");
+    let mut buf = vec![];
+    let n = reader.read_to_end(&mut buf).await.unwrap_or(0);
+    assert_eq!(0, n);
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(1, sessions.len());
+    assert_eq!(1, sessions.connections_from(localhost));
+
+    // and released its transaction slot
+    let (mut reader, mut writer) = split(healthy);
+    write_all!(writer, b"SET:3:foo:3:bar\n");
+    let buf = read_buf!(reader, 14);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "1:3:7:created\n");
+
+    drop((reader, writer));
+    sleep(Duration::from_millis(50)).await;
+    assert!(sessions.is_empty());
+    assert_eq!(0, sessions.connections_from(localhost));
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_hello_encoding() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7333");
+
+    let stream = utils::connect("localhost:7333")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    // newlines and colons would trip up line-oriented tools
+    let value = b"\x00\xff\n:bin";
+    write_all!(writer, b"SET:3:bin:7:");
+    write_all!(writer, value);
+    write_all!(writer, b"\n");
+    let buf = read_buf!(reader, 14);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "1:7:7:created\n");
+
+    let version = env!("CARGO_PKG_VERSION");
+    write_all!(writer, b"HELLO:3:hex\nGET:3:bin\n");
+    let expected = format!(
+        "7:version:{}:{version}:8:encoding:3:hex\n14:00ff0a3a62696e\n",
+        version.len()
+    );
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+    let encoded = &buf[buf.len() - 15..buf.len() - 1];
+    let decoded = (0..encoded.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(std::str::from_utf8(&encoded[i..i + 2]).unwrap(), 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .unwrap();
+    assert_eq!(value.to_vec(), decoded);
+
+    write_all!(writer, b"HELLO:6:base64\nGET:3:bin\n");
+    let expected = format!(
+        "7:version:{}:{version}:8:encoding:6:base64\n12:AP8KOmJpbg==\n",
+        version.len()
+    );
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // unknown encodings are rejected and the session's encoding is left alone
+    write_all!(writer, b"HELLO:3:rot\nHELLO:3:raw\nGET:3:bin\n");
+    let error = "unknown encoding \"rot\", expected one of (raw|hex|base64)";
+    let expected = format!(
+        "ERR:{}:{error}\n7:version:{}:{version}:8:encoding:3:raw\n7:",
+        error.len(),
+        version.len()
+    );
+    let buf = read_buf!(reader, expected.len() + value.len() + 1);
+    assert_eq!(&buf[..expected.len()], expected.as_bytes());
+    assert_eq!(&buf[expected.len()..], b"\x00\xff\n:bin\n");
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_apply() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7334");
+
+    let stream = utils::connect("localhost:7334")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    // each transform returns the new value
+    for (cmd, expected) in [
+        (&b"APPLY:7:counter:3:add:1:5\n"[..], &b"1:5\n"[..]),
+        (b"APPLY:7:counter:3:add:2:-7\n", b"2:-2\n"),
+        (b"APPLY:3:str:6:append:3:foo\n", b"3:foo\n"),
+        (b"APPLY:3:str:6:append:3:bar\n", b"6:foobar\n"),
+        (b"APPLY:4:bits:6:setbit:1:6\n", b"1:\x02\n"),
+        (b"APPLY:4:bits:6:setbit:1:0\n", b"1:\x82\n"),
+        (b"APPLY:4:bits:8:clearbit:1:6\n", b"1:\x80\n"),
+    ] {
+        write_all!(writer, cmd);
+        let buf = read_buf!(reader, expected.len());
+        assert_eq!(buf, expected, "{}", String::from_utf8_lossy(cmd));
+    }
+
+    // values that don't suit the transform are rejected and left untouched
+    write_all!(writer, b"APPLY:3:str:3:add:1:1\nGET:3:str\n");
+    let expected = "ERR:28:add: value is not an integer\n6:foobar\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    write_all!(writer, b"APPLY:3:str:5:rot13:0:\n");
+    let expected =
+        "ERR:71:unknown transform \"rot13\", expected one of (add|append|setbit|clearbit)\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+/// Collects everything written by a tracing subscriber
+#[derive(Clone, Default)]
+struct Captured(Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Captured {
+    fn take(&self) -> String {
+        String::from_utf8(std::mem::take(&mut *self.0.lock().unwrap())).unwrap()
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[tokio::test]
+async fn test_client_server_wire_trace() {
+    // capture this test's logs, the servers' tasks run on this thread too
+    let captured = Captured::default();
+    let writer = captured.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::new("kave::wire=trace"))
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let mut shutdowns = vec![];
+    shutdowns.push(start_client_server!("127.0.0.1:7335", |cs| {
+        cs.set_wire_trace(WireTrace::Full);
+    }));
+    shutdowns.push(start_client_server!("127.0.0.1:7336", |cs| {
+        cs.set_wire_trace(WireTrace::Redacted);
+    }));
+    shutdowns.push(start_client_server!("127.0.0.1:7337", |cs| {
+        cs.set_wire_trace(WireTrace::Off);
+    }));
+
+    async fn session(port: u16) {
+        let stream = utils::connect(&format!("localhost:{port}"))
+            .await
+            .expect("error connecting to test addr");
+        let (mut reader, mut writer) = split(stream);
+        write_all!(writer, b"SET:3:foo:6:secret\n");
+        read_buf!(reader, 14);
+        write_all!(writer, b"GET:3:foo\n");
+        read_buf!(reader, 9);
+        sleep(Duration::from_millis(50)).await;
+    }
+
+    // every byte is logged, framed as it was sent
+    session(7335).await;
+    let logs = captured.take();
+    assert!(
+        logs.contains(&format!("read {}", hex(b"SET:3:foo:6:secret\n"))),
+        "{logs}"
+    );
+    assert!(
+        logs.contains(&format!("wrote {}", hex(b"1:6:7:created\n"))),
+        "{logs}"
+    );
+    assert!(
+        logs.contains(&format!("read {}", hex(b"GET:3:foo\n"))),
+        "{logs}"
+    );
+    assert!(
+        logs.contains(&format!("wrote {}", hex(b"6:secret\n"))),
+        "{logs}"
+    );
+
+    // values are left out
+    session(7336).await;
+    let logs = captured.take();
+    let set = format!(
+        "read {}[6 bytes redacted]{}",
+        hex(b"SET:3:foo:6:"),
+        hex(b"\n")
+    );
+    assert!(logs.contains(&set), "{logs}");
+    assert!(
+        logs.contains(&format!("wrote {}", hex(b"1:6:7:created\n"))),
+        "{logs}"
+    );
+    assert!(
+        logs.contains(&format!("read {}", hex(b"GET:3:foo\n"))),
+        "{logs}"
+    );
+    assert!(logs.contains("wrote [9 bytes redacted]"), "{logs}");
+    assert!(!logs.contains(&hex(b"secret")), "{logs}");
+
+    // and nothing is logged when disabled
+    session(7337).await;
+    assert_eq!("", captured.take());
+
+    for (shutdown_send, mut shutdown_recv) in shutdowns {
+        shutdown_send
+            .send(true)
+            .expect("error sending client-server shutdown");
+        tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+            .await
+            .expect("client-server failed to shutdown");
+    }
+}
+
+#[tokio::test]
+async fn test_client_server_close_notify() {
+    init!();
+    let (shutdown_send, mut shutdown_recv, mut cs) = new_client_server();
+    cs.set_addr("127.0.0.1:7338");
+    let mut events = cs.events();
+    tokio::spawn(async move { cs.start().await });
+    sleep(Duration::from_millis(100)).await;
+
+    async fn next_close(
+        events: &mut tokio::sync::broadcast::Receiver<SessionEvent>,
+    ) -> CloseReason {
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(1), events.recv())
+                .await
+                .expect("timed out waiting for the session to close")
+                .expect("error receiving event");
+            if let SessionEvent::Closed { reason, .. } = event {
+                return reason;
+            }
+        }
+    }
+
+    // a TLS close_notify is a clean close
+    let stream = utils::connect("localhost:7338")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"ECHO:2:hi\n");
+    read_buf!(reader, 5);
+    writer.shutdown().await.expect("error shutting down");
+    assert_eq!(
+        CloseReason::ClientDisconnected,
+        next_close(&mut events).await
+    );
+
+    // even straight after connecting
+    let stream = utils::connect("localhost:7338")
+        .await
+        .expect("error connecting to test addr");
+    let (_reader, mut writer) = split(stream);
+    writer.shutdown().await.expect("error shutting down");
+    assert_eq!(
+        CloseReason::ClientDisconnected,
+        next_close(&mut events).await
+    );
+
+    // while dropping the connection without one is told apart
+    let stream = utils::connect("localhost:7338")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"ECHO:2:hi\n");
+    read_buf!(reader, 5);
+    drop((reader, writer));
+    assert_eq!(CloseReason::ConnectionReset, next_close(&mut events).await);
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_time() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7339");
+
+    let stream = utils::connect("localhost:7339")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    let before = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap();
+    write_all!(writer, b"TIME\n");
+    let buf = read_buf!(reader, 16);
+    let after = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap();
+
+    let (time, rest) = match Response::decode(&buf).expect("invalid time") {
+        Some((Response::Fields(fields), n)) => (fields, &buf[n..]),
+        r => panic!("unexpected response {r:?}"),
+    };
+    assert!(rest.is_empty());
+    let secs: u64 = std::str::from_utf8(&time[0]).unwrap().parse().unwrap();
+    let micros: u32 = std::str::from_utf8(&time[1]).unwrap().parse().unwrap();
+    assert!(micros < 1_000_000);
+    let time = Duration::new(secs, micros * 1000);
+    // truncated to the microsecond, but never outside of the request's round trip
+    assert!(
+        before - Duration::from_micros(1) <= time && time <= after,
+        "{before:?} <= {time:?} <= {after:?}"
+    );
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_set_range() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7340", |cs| {
+        cs.set_max_value_len(Some(16));
+    });
+
+    let stream = utils::connect("localhost:7340")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    for (cmd, expected) in [
+        // overwriting within the value
+        (&b"SET:3:foo:11:hello world\n"[..], &b"2:11:7:created\n"[..]),
+        (b"SETRANGE:3:foo:1:6:4:kave\n", b"2:11\n"),
+        (b"GET:3:foo\n", b"11:hello kaved\n"),
+        // past the end is zero-padded
+        (b"SETRANGE:3:foo:2:13:2:!!\n", b"2:15\n"),
+        (b"GET:3:foo\n", b"15:hello kaved\0\0!!\n"),
+        // absent keys are created
+        (b"SETRANGE:3:bar:1:2:2:hi\n", b"1:4\n"),
+        (b"GET:3:bar\n", b"4:\0\0hi\n"),
+        // and values can't grow past the max value size
+        (
+            b"SETRANGE:3:bar:2:15:2:hi\n",
+            b"ERR:61:value of at least 17 bytes exceeds max value size of 16 bytes\n",
+        ),
+        (b"GET:3:bar\n", b"4:\0\0hi\n"),
+    ] {
+        write_all!(writer, cmd);
+        let buf = read_buf!(reader, expected.len());
+        assert_eq!(
+            String::from_utf8_lossy(&buf),
+            String::from_utf8_lossy(expected),
+            "{}",
+            String::from_utf8_lossy(cmd)
+        );
+    }
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_cluster_client() {
+    init!();
+    let nodes = ["localhost:7341", "localhost:7342"];
+    let mut shutdowns = vec![
+        start_client_server!("127.0.0.1:7341"),
+        start_client_server!("127.0.0.1:7342"),
+    ];
+
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
+    let mut client = ClusterClient::new(Ring::with_nodes(nodes), certs);
+    let keys = (0..20).map(|i| format!("key{i}")).collect::<Vec<_>>();
+    for key in &keys {
+        client
+            .set(key.as_bytes(), key.as_bytes())
+            .await
+            .expect("error setting");
+    }
+
+    // every key landed on its owner, and only there
+    let mut owned = vec![0; nodes.len()];
+    for (i, node) in nodes.iter().enumerate() {
+        let stream = utils::connect(node)
+            .await
+            .expect("error connecting to test addr");
+        let (mut reader, mut writer) = split(stream);
+        for key in &keys {
+            write_all!(writer, format!("GET:{}:{key}\n", key.len()).as_bytes());
+            let buf = read_buf!(reader, 5);
+            let found = std::str::from_utf8(&buf).unwrap();
+            if client.ring().node_for(key.as_bytes()) == Some(*node) {
+                assert_eq!(format!("{}:{key}\n", key.len()), found);
+                owned[i] += 1;
+            } else {
+                assert_eq!("null\n", found);
+            }
+        }
+    }
+    assert!(owned.iter().all(|n| *n > 0), "{owned:?}");
+
+    for (shutdown_send, mut shutdown_recv) in shutdowns.drain(..) {
+        shutdown_send
+            .send(true)
+            .expect("error sending client-server shutdown");
+        tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+            .await
+            .expect("client-server failed to shutdown");
+    }
+}
+
+/// Adds an `UPPERCASE:<len>:<key>\n` command returning the key's value uppercased
+struct UppercaseHandler;
+
+#[async_trait]
+impl CommandHandler<MemoryStore> for UppercaseHandler {
+    fn commands(&self) -> Vec<(&'static str, usize)> {
+        vec![("UPPERCASE", 1)]
+    }
+
+    async fn handle(
+        &self,
+        ctx: CommandContext<'_, MemoryStore>,
+        op: ProtoOp,
+    ) -> kave::error::Result<bool> {
+        match op {
+            ProtoOp::Custom {
+                name: "UPPERCASE",
+                args,
+            } => {
+                let key = ctx.state.scoped_key(&args[0]);
+                match ctx.store.get(&key).await? {
+                    Some(value) => {
+                        let value = value.to_ascii_uppercase();
+                        ctx.proto.write_get_result(ctx.writer, &value).await?;
+                    }
+                    None => ctx.proto.write_null(ctx.writer).await?,
+                }
+                ctx.proto.flush(ctx.writer).await?;
+                Ok(true)
+            }
+            op => Builtin.handle(ctx, op).await,
+        }
+    }
+}
+
+/// Adds a `BLOB:<len>:<n>\n` command streaming back `n` bytes that aren't held in memory
+struct BlobHandler;
+
+#[async_trait]
+impl CommandHandler<MemoryStore> for BlobHandler {
+    fn commands(&self) -> Vec<(&'static str, usize)> {
+        vec![("BLOB", 1)]
+    }
+
+    async fn handle(
+        &self,
+        ctx: CommandContext<'_, MemoryStore>,
+        op: ProtoOp,
+    ) -> kave::error::Result<bool> {
+        match op {
+            ProtoOp::Custom { name: "BLOB", args } => {
+                let len = String::from_utf8_lossy(&args[0]).parse()?;
+                // ends with a `!` so the client can tell the value's tail made it through
+                let value = tokio::io::repeat(b'x').take(len as u64 - 1);
+                let value = value.chain(&b"!"[..]);
+                ctx.proto
+                    .write_get_result_stream(ctx.writer, len, value)
+                    .await?;
+                ctx.proto.flush(ctx.writer).await?;
+                Ok(true)
+            }
+            op => Builtin.handle(ctx, op).await,
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_client_server_command_handler() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7343", |cs| {
+        cs.set_command_handler(Arc::new(UppercaseHandler));
+    });
+
+    let stream = utils::connect("localhost:7343")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    // built-in commands are passed through to the default handler
+    write_all!(writer, b"SET:3:foo:3:bar\n");
+    let buf = read_buf!(reader, 14);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "1:3:7:created\n");
+
+    // the custom command is read and dispatched, even with a name longer than the built-ins
+    write_all!(writer, b"UPPERCASE:3:foo\nUPPERCASE:3:baz\n");
+    let buf = read_buf!(reader, 11);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "3:BAR\nnull\n");
+
+    // custom commands can scope their keys by the session's namespace
+    write_all!(writer, b"USE:2:ns\nSET:3:foo:3:ham\nUPPERCASE:3:foo\n");
+    let buf = read_buf!(reader, 23);
+    assert_eq!(
+        std::str::from_utf8(&buf).unwrap(),
+        "OK\n1:3:7:created\n3:HAM\n"
+    );
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_large_pipelined_batches() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7344");
+
+    let stream = utils::connect("localhost:7344")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    // values of every size around and well past the read buffer's, so ops
+    // straddle reads and leave residuals both smaller and larger than it
+    let value = |i: usize| vec![b'a' + (i % 26) as u8; i * 37];
+    for round in 0..3 {
+        let mut batch = vec![];
+        for i in 0..120 {
+            let key = format!("key-{round}-{i:03}");
+            batch.extend_from_slice(format!("SETQ:{}:{key}:{}:", key.len(), i * 37).as_bytes());
+            batch.extend_from_slice(&value(i));
+            batch.push(b'\n');
+        }
+        batch.extend_from_slice(b"ECHO:4:done\n");
+        write_all!(writer, &batch);
+        let buf = read_buf!(reader, 7);
+        assert_eq!(std::str::from_utf8(&buf).unwrap(), "4:done\n");
+
+        let mut gets = vec![];
+        let mut expected = vec![];
+        for i in 0..120 {
+            let key = format!("key-{round}-{i:03}");
+            gets.extend_from_slice(format!("GET:{}:{key}\n", key.len()).as_bytes());
+            expected.extend_from_slice(format!("{}:", i * 37).as_bytes());
+            expected.extend_from_slice(&value(i));
+            expected.push(b'\n');
+        }
+        write_all!(writer, &gets);
+        let buf = read_buf!(reader, expected.len());
+        assert_eq!(buf, expected);
+    }
+
+    // small ops still work once the batches are done
+    write_all!(writer, b"ECHO:5:hello\n");
+    let buf = read_buf!(reader, 8);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "5:hello\n");
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_lsm_backend_from_config() {
+    init!();
+    let data_dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+    std::fs::create_dir_all(&data_dir).expect("error creating data dir");
+    let config = Config {
+        store_backend: StoreKind::Lsm,
+        commit_log_path: data_dir.join("commit_log"),
+        data_dir: data_dir.clone(),
+        ..Config::load()
+    };
+    let backend = StoreBackend::from_config(&config);
+    assert!(matches!(&backend, StoreBackend::Lsm { path, .. } if *path == data_dir));
+
+    let (store_shutdown_send, store_shutdown_recv) = tokio::sync::mpsc::unbounded_channel();
+    let store = backend
+        .clone()
+        .build(store_shutdown_recv)
+        .await
+        .expect("error building store");
+    assert!(matches!(store, BackendStore::Lsm(_)));
+    let (shutdown_send, mut shutdown_recv, mut cs) = new_client_server_with_store(store);
+    cs.set_addr("127.0.0.1:7345");
+    tokio::spawn(async move { cs.start().await });
+    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+    let stream = utils::connect("localhost:7345")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"SET:3:foo:3:bar\n");
+    let buf = read_buf!(reader, 14);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "1:3:7:created\n");
+
+    // shut down the server and the store, which flushes it to disk
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+    let (done_send, done_recv) = tokio::sync::oneshot::channel();
+    store_shutdown_send
+        .send(done_send)
+        .expect("error sending store shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), done_recv)
+        .await
+        .expect("store failed to shutdown")
+        .expect("store dropped shutdown");
+
+    // a store built from the same config finds the value
+    let (_store_shutdown_send, store_shutdown_recv) = tokio::sync::mpsc::unbounded_channel();
+    let mut store = backend
+        .build(store_shutdown_recv)
+        .await
+        .expect("error rebuilding store");
+    assert_eq!(Some(b"bar".to_vec()), store.get(b"foo").await.unwrap());
+
+    // while the memory backend keeps nothing
+    let config = Config {
+        store_backend: "memory".parse().unwrap(),
+        ..config
+    };
+    let (_store_shutdown_send, store_shutdown_recv) = tokio::sync::mpsc::unbounded_channel();
+    let mut store = StoreBackend::from_config(&config)
+        .build(store_shutdown_recv)
+        .await
+        .expect("error building store");
+    assert_eq!(None, store.get(b"foo").await.unwrap());
+
+    std::fs::remove_dir_all(&data_dir).ok();
+}
+
+#[tokio::test]
+async fn test_client_server_max_response_size() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7346", |cs| {
+        cs.set_max_response_len(Some(10));
+    });
+
+    let stream = utils::connect("localhost:7346")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    // the limit is advertised
+    let version = env!("CARGO_PKG_VERSION");
+    let hello = format!(
+        "7:version:{}:{version}:17:max_response_size:2:10",
+        version.len()
+    );
+    write_all!(writer, b"HELLO\n");
+    let expected = format!("{hello}\n");
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // values up to the limit are returned, larger ones fail without closing the session
+    write_all!(
+        writer,
+        b"SET:5:small:10:0123456789\nSET:5:large:11:0123456789a\nGET:5:small\nGET:5:large\n"
+    );
+    let expected = "2:10:7:created\n2:11:7:created\n10:0123456789\n\
+                    ERR:74:response of 11 bytes exceeds max response size of 10 bytes, use pagination\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // the limit applies to the encoded value
+    write_all!(
+        writer,
+        b"HELLO:3:hex\nGET:5:small\nHELLO:3:raw\nGET:5:small\n"
+    );
+    let expected = format!(
+        "{hello}:8:encoding:3:hex\n\
+         ERR:74:response of 20 bytes exceeds max response size of 10 bytes, use pagination\n\
+         {hello}:8:encoding:3:raw\n\
+         10:0123456789\n"
+    );
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    write_all!(writer, b"ECHO:11:hello world\nECHO:5:hello\n");
+    let expected =
+        "ERR:74:response of 11 bytes exceeds max response size of 10 bytes, use pagination\n\
+                    5:hello\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_set_durability() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7349");
+
+    let stream = utils::connect("localhost:7349")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    // the durability is optional, and may be split from the rest of the command
+    write_all!(
+        writer,
+        b"SET:1:a:1:1:5:async\nSET:1:b:1:2:7:batched\nSETQ:1:c:1:3:5:fsync\nSET:1:d:1:4"
+    );
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    write_all!(writer, b":5:fsync\nSET:1:e:1:5\nGET:1:c\n");
+    let expected = "1:1:7:created\n1:1:7:created\n1:1:7:created\n1:1:7:created\n1:3\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // an unknown durability closes the session without writing
+    write_all!(writer, b"SET:1:f:1:6:6:always\n");
+    let mut buf = vec![];
+    let n = reader.read_to_end(&mut buf).await.unwrap_or(0);
+    assert_eq!(0, n, "{}", String::from_utf8_lossy(&buf));
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_waitrepl() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7350");
+
+    let stream = utils::connect("localhost:7350")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    // without replicas, waiting on none returns straight away
+    write_all!(writer, b"SET:1:a:1:1\nWAITREPL:1:0:5:10000\n");
+    let expected = "1:1:7:created\n1:0\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // and waiting on any times out with none acknowledging
+    let start = tokio::time::Instant::now();
+    write_all!(writer, b"WAITREPL:1:1:3:200\n");
+    let expected = "1:0\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_mget_consistent() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7351");
+
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
+    let mut client = Client::connect("localhost", 7351, certs)
+        .await
+        .expect("error connecting to test addr");
+    client.set(b"a", b"x").await.unwrap();
+    client.set(b"b", b"y").await.unwrap();
+    assert_eq!(
+        vec![None, Some(b"x".to_vec()), Some(b"y".to_vec())],
+        client
+            .mget_consistent(&[b"c".as_slice(), b"a", b"b"])
+            .await
+            .unwrap()
+    );
+    assert!(client.mget_consistent(&[]).await.unwrap().is_empty());
+
+    // the values of `a` and `b` are swapped back and forth while they're read, so a
+    // consistent read always finds one of each and never the same value twice
+    let (done_send, mut done_recv) = tokio::sync::oneshot::channel();
+    let writer = tokio::spawn(async move {
+        let stream = utils::connect("localhost:7351")
+            .await
+            .expect("error connecting to test addr");
+        let (mut reader, mut writer) = split(stream);
+        for _ in 0..100 {
+            write_all!(writer, &b"SWAP:1:a:1:b\n".repeat(10));
+            read_buf!(reader, 30);
+        }
+        done_send.send(()).unwrap();
+    });
+    let mut reads = 0;
+    while done_recv.try_recv().is_err() {
+        let values = client
+            .mget_consistent(&[b"a".as_slice(), b"b"])
+            .await
+            .unwrap();
+        assert!(
+            values == [Some(b"x".to_vec()), Some(b"y".to_vec())]
+                || values == [Some(b"y".to_vec()), Some(b"x".to_vec())],
+            "{values:?}"
+        );
+        reads += 1;
+    }
+    writer.await.unwrap();
+    assert!(reads > 1);
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_buffer_pool() {
+    init!();
+    let pool = BufferPool::new(4);
+    let (shutdown_send, mut shutdown_recv, mut cs) = new_client_server();
+    cs.set_addr("127.0.0.1:7352").set_buffer_pool(pool.clone());
+    tokio::spawn(async move { cs.start().await });
+    sleep(Duration::from_millis(100)).await;
+
+    // the first session leaves the start of a command in its buffer when it goes away
+    let stream = utils::connect("localhost:7352")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"ECHO:2:hi\nECHO");
+    let buf = read_buf!(reader, 5);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "2:hi\n");
+    writer.shutdown().await.expect("error shutting down");
+    drop((reader, writer));
+    let start = tokio::time::Instant::now();
+    while pool.is_empty() {
+        assert!(
+            start.elapsed() < Duration::from_secs(2),
+            "buffer never pooled"
+        );
+        sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(1, pool.len());
+
+    // the next session reuses the buffer without seeing any of it, so the rest of
+    // that command is just a malformed one closing the session
+    let stream = utils::connect("localhost:7352")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b":3:bye\n");
+    let mut buf = vec![];
+    let res = tokio::time::timeout(Duration::from_secs(1), reader.read_to_end(&mut buf)).await;
+    let msg = "error reading start of operation, unknown operation \"\"";
+    assert_eq!(
+        format!("ERR:{}:{msg}\n", msg.len()),
+        String::from_utf8_lossy(&buf)
+    );
+    assert!(res.is_ok(), "session was not closed");
+
+    let stream = utils::connect("localhost:7352")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"ECHO:2:ok\n");
+    let buf = read_buf!(reader, 5);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "2:ok\n");
+    assert!(pool.is_empty());
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_invalid_tls_fails_at_startup() {
+    init!();
+    let certs = load_certs("certs/test/expired-cert.pem").expect("error loading expired cert");
+    let keys = load_keys("certs/test/other-key.pem").expect("error loading key");
+    let (client_svr_shutdown_send, mut client_svr_shutdown_recv) =
+        tokio::sync::mpsc::unbounded_channel();
+    let (_sig_client_shutdown_send, sig_client_shutdown_recv) =
+        tokio::sync::mpsc::unbounded_channel();
+    let mut cs = ClientServer::new(
+        client_svr_shutdown_send,
+        sig_client_shutdown_recv,
+        certs,
+        keys,
+        MemoryStore::new(),
+    );
+    cs.set_addr("127.0.0.1:7347");
+    tokio::spawn(async move { cs.start().await });
+
+    // the server gives up straight away, without ever listening
+    tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        client_svr_shutdown_recv.recv(),
+    )
+    .await
+    .expect("client-server failed to shutdown");
+    assert!(tokio::net::TcpStream::connect("127.0.0.1:7347")
+        .await
+        .is_err());
+}
+
+#[cfg(feature = "hash")]
+#[tokio::test]
+async fn test_client_server_hash() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7348");
+
+    let stream = utils::connect("localhost:7348")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    // fields are set and read individually
+    write_all!(
+        writer,
+        b"HSET:4:user:4:name:3:ada\nHSET:4:user:4:lang:2:en\nHGET:4:user:4:name\nHGET:4:user:4:lang\n"
+    );
+    let expected = "1:1\n1:2\n3:ada\n2:en\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // overwriting a field leaves the others alone
+    write_all!(
+        writer,
+        b"HSET:4:user:4:lang:2:fr\nHGETALL:4:user\nHGET:4:user:3:age\nHGET:5:other:4:name\n"
+    );
+    let expected = "1:2\n4:lang:2:fr:4:name:3:ada\nnil\nnull\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // deleting the key deletes every field
+    write_all!(
+        writer,
+        b"DEL:4:user\nHGET:4:user:4:name\nHGETALL:4:user\nHSET:4:user:4:name:3:bob\nHGETALL:4:user\n"
+    );
+    let expected = "1:1\nnull\nnull\n1:1\n4:name:3:bob\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // plain values aren't hashes, and are left untouched
+    write_all!(
+        writer,
+        b"SET:5:plain:3:foo\nHSET:5:plain:1:a:1:b\nHGET:5:plain:1:a\nGET:5:plain\n"
+    );
+    let expected = "1:3:7:created\nERR:25:hset: value is not a hash\n\
+                    ERR:25:hget: value is not a hash\n3:foo\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // hashes are scoped by the session's namespace like any other key
+    write_all!(
+        writer,
+        b"USE:2:ns\nHSET:4:user:4:name:3:eve\nHGETALL:4:user\nUSE:0:\nHGETALL:4:user\n"
+    );
+    let expected = "OK\n1:1\n4:name:3:eve\nOK\n4:name:3:bob\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_command() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7353");
+
+    let stream = utils::connect("localhost:7353")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    write_all!(writer, b"COMMAND\n");
+    let mut buf = vec![];
+    let fields = loop {
+        reader
+            .read_buf(&mut buf)
+            .await
+            .expect("error reading response");
+        match Response::decode(&buf).expect("invalid response") {
+            Some((Response::Fields(fields), n)) => {
+                assert_eq!(n, buf.len());
+                break fields;
+            }
+            Some(r) => panic!("unexpected response {r:?}"),
+            None => continue,
+        }
+    };
+    let commands: Vec<(String, String)> = fields
+        .chunks(2)
+        .map(|pair| {
+            (
+                String::from_utf8(pair[0].clone()).unwrap(),
+                String::from_utf8(pair[1].clone()).unwrap(),
+            )
+        })
+        .collect();
+    let names: Vec<&str> = commands.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, COMMANDS);
+
+    let arity = |name: &str| {
+        commands
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, arity)| arity.as_str())
+    };
+    let mut expected = vec![
+        ("GET", "1"),
+        ("MGET", "1+"),
+        ("GETV", "1"),
+        ("SET", "2-3"),
+        ("SETQ", "2-3"),
+        ("MSET", "1+"),
+        ("CASV", "3"),
+        ("CAS", "3"),
+        ("GETSET", "2"),
+        ("SETRANGE", "3"),
+        ("DEL", "1"),
+        ("DELQ", "1"),
+        ("STRLEN", "1"),
+        ("EXISTS", "1"),
+        ("SWAP", "2"),
+        ("EXPIRE", "2"),
+        ("APPLY", "3"),
+        ("FIND", "1"),
+        ("SCAN", "2-3"),
+        ("FLUSHALL", "0"),
+        ("SNAPSHOT", "1"),
+        ("BEGIN", "0"),
+        ("COMMIT", "0"),
+        ("DISCARD", "0"),
+        ("HELLO", "0-1"),
+        ("USE", "1"),
+        ("TIME", "0"),
+        ("ECHO", "1"),
+        ("PING", "0-1"),
+        ("WAITREPL", "2"),
+        ("COMMAND", "0"),
+        ("HEALTHZ", "0"),
+        ("QUIT", "0"),
+        ("DEBUG", "2"),
+    ];
+    if cfg!(feature = "hash") {
+        expected.extend([
+            ("HSET", "3"),
+            ("HGET", "2"),
+            ("HGETALL", "1"),
+            ("HINCR", "3"),
+        ]);
+    }
+    assert_eq!(expected.len(), COMMANDS.len());
+    for (name, expected) in expected {
+        assert_eq!(arity(name), Some(expected), "{name}");
+    }
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_readiness() {
+    init!();
+    let (ready_send, ready_recv) = tokio::sync::watch::channel(false);
+    let (shutdown_send, mut shutdown_recv, mut cs) = new_client_server();
+    cs.set_addr("127.0.0.1:7354").set_readiness(ready_recv);
+    tokio::spawn(async move { cs.start().await });
+    sleep(Duration::from_millis(100)).await;
+
+    let stream = utils::connect("localhost:7354")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    // while the store recovers, ops touching it are turned away without being applied
+    write_all!(writer, b"SET:1:a:1:1\nGET:1:a\nHEALTHZ\nECHO:2:hi\n");
+    let msg = "starting: the store is still recovering, retry later";
+    let starting = format!("ERR:{}:{msg}\n", msg.len());
+    let expected = format!("{starting}{starting}6:status:8:starting\n2:hi\n");
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    ready_send.send(true).expect("error signalling readiness");
+    write_all!(writer, b"HEALTHZ\nGET:1:a\nSET:1:a:1:1\nGET:1:a\n");
+    let expected = "6:status:5:ready\nnull\n1:1:7:created\n1:1\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[cfg(feature = "hash")]
+#[tokio::test]
+async fn test_client_server_hincr() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7355");
+
+    // sessions incrementing the same field concurrently never lose an increment
+    let sessions = (1..=8)
+        .map(|by: usize| {
+            tokio::spawn(async move {
+                let stream = utils::connect("localhost:7355")
+                    .await
+                    .expect("error connecting to test addr");
+                let (mut reader, mut writer) = split(stream);
+                let by = by.to_string();
+                let cmd = format!("HINCR:5:stats:4:hits:{}:{by}\n", by.len());
+                for _ in 0..50 {
+                    write_all!(writer, cmd.as_bytes());
+                    let mut buf = vec![];
+                    while !buf.ends_with(b"\n") {
+                        reader.read_buf(&mut buf).await.expect("error reading");
+                    }
+                    assert!(!buf.starts_with(b"ERR"), "{buf:?}");
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for session in sessions {
+        session.await.expect("error incrementing");
+    }
+
+    let stream = utils::connect("localhost:7355")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    // 50 increments by each of 1 through 8
+    let sum = (50 * 36).to_string();
+    write_all!(writer, b"HGET:5:stats:4:hits\n");
+    let expected = format!("{}:{sum}\n", sum.len());
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // fields that don't hold integers, and increments that aren't, are left untouched
+    write_all!(
+        writer,
+        b"HSET:5:stats:4:name:3:ada\nHINCR:5:stats:4:name:1:1\nHINCR:5:stats:4:hits:3:one\nHINCR:5:stats:4:hits:2:-1\n"
+    );
+    let expected = format!(
+        "1:2\nERR:36:hincr: field value is not an integer\n\
+        ERR:34:hincr: increment is not an integer\n4:{}\n",
+        50 * 36 - 1
+    );
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+/// A store under write pressure, turning away its next `rejections` transactions,
+/// and every one while its disk is full. Its reads fail while `read_errors` is set
+#[derive(Clone, Default)]
+struct PressuredStore {
+    inner: MemoryStore,
+    rejections: Arc<std::sync::atomic::AtomicUsize>,
+    disk_full: Arc<std::sync::atomic::AtomicBool>,
+    read_errors: Arc<std::sync::atomic::AtomicBool>,
+}
+
+#[async_trait]
+impl Store for PressuredStore {
+    async fn get(&mut self, k: &[u8]) -> kave::Result<Option<Vec<u8>>> {
+        if self.read_errors.load(std::sync::atomic::Ordering::SeqCst) {
+            return Err(std::io::Error::other("error reading sstable").into());
+        }
+        self.inner.get(k).await
+    }
+
+    async fn scan(&mut self, from: &[u8], to: &[u8]) -> kave::Result<Vec<Vec<u8>>> {
+        self.inner.scan(from, to).await
+    }
+
+    async fn transact(&mut self, transaction: Transaction) -> kave::Result<Vec<bool>> {
+        use std::sync::atomic::Ordering;
+        let rejected = self
+            .rejections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok();
+        if rejected {
+            return Err(kave::Error::Overloaded("flushes are behind".to_string()));
+        }
+        if self.disk_full.load(Ordering::SeqCst) {
+            return Err(kave::Error::DiskFull("no space left on device".to_string()));
+        }
+        self.inner.transact(transaction).await
+    }
+
+    async fn swap(&mut self, a: &[u8], b: &[u8]) -> kave::Result<()> {
+        self.inner.swap(a, b).await
+    }
+
+    async fn apply(&mut self, k: &[u8], transform: &Transform) -> kave::Result<Vec<u8>> {
+        self.inner.apply(k, transform).await
+    }
+}
+
+#[tokio::test]
+async fn test_client_server_overloaded() {
+    use std::sync::atomic::Ordering;
+    init!();
+    let store = PressuredStore::default();
+    let rejections = store.rejections.clone();
+    let (shutdown_send, mut shutdown_recv, mut cs) = new_client_server_with_store(store);
+    cs.set_addr("127.0.0.1:7356");
+    tokio::spawn(async move { cs.start().await });
+    sleep(Duration::from_millis(100)).await;
+
+    // overloaded writes get the retryable code and the session carries on
+    let stream = utils::connect("localhost:7356")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    rejections.store(1, Ordering::SeqCst);
+    write_all!(writer, b"SET:1:a:1:1\nGET:1:a\nSET:1:a:1:1\nGET:1:a\n");
+    let msg = "503 overloaded: flushes are behind";
+    let expected = format!("ERR:{}:{msg}\nnull\n1:1:7:created\n1:1\n", msg.len());
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // the client backs off and retries until the store catches up
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
+    let mut client = Client::connect("localhost", 7356, certs)
+        .await
+        .expect("error connecting to test addr");
+    client.set_overload_retries(3, Duration::from_millis(10));
+    rejections.store(3, Ordering::SeqCst);
+    let start = tokio::time::Instant::now();
+    assert_eq!(1, client.set(b"b", b"2").await.unwrap());
+    // 10ms, then 20ms, then 40ms
+    assert!(start.elapsed() >= Duration::from_millis(70));
+    assert_eq!(0, rejections.load(Ordering::SeqCst));
+
+    // giving up once it runs out of retries
+    rejections.store(4, Ordering::SeqCst);
+    match client.set(b"c", b"3").await {
+        Err(kave::Error::Overloaded(msg)) => assert_eq!("flushes are behind", msg),
+        res => panic!("unexpected result {res:?}"),
+    }
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_read_error() {
+    use std::sync::atomic::Ordering;
+    init!();
+    let store = PressuredStore::default();
+    store.read_errors.store(true, Ordering::SeqCst);
+    let (shutdown_send, mut shutdown_recv, mut cs) = new_client_server_with_store(store);
+    cs.set_addr("127.0.0.1:7406");
+    let mut events = cs.events();
+    tokio::spawn(async move { cs.start().await });
+    sleep(Duration::from_millis(100)).await;
+
+    // a GET the store fails to read ends the session with the error
+    let stream = utils::connect("localhost:7406")
+        .await
+        .expect("error connecting to test addr");
+    let (_reader, mut writer) = split(stream);
+    write_all!(writer, b"GET:1:a\n");
+    let reason = loop {
+        let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .expect("timed out waiting for the session to close")
+            .expect("error receiving event");
+        if let SessionEvent::Closed { reason, .. } = event {
+            break reason;
+        }
+    };
+    assert_eq!(
+        CloseReason::Error("io error: error reading sstable".into()),
+        reason
+    );
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_disk_full() {
+    use std::sync::atomic::Ordering;
+    init!();
+    let store = PressuredStore::default();
+    let disk_full = store.disk_full.clone();
+    disk_full.store(true, Ordering::SeqCst);
+    let (shutdown_send, mut shutdown_recv, mut cs) = new_client_server_with_store(store);
+    cs.set_addr("127.0.0.1:7403");
+    tokio::spawn(async move { cs.start().await });
+    sleep(Duration::from_millis(100)).await;
+
+    // writes with nowhere to go get the disk full code, and the session carries on
+    let stream = utils::connect("localhost:7403")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"SET:1:a:1:1\n");
+    let msg = "507 disk full: no space left on device";
+    let expected = format!("ERR:{}:{msg}\n", msg.len());
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+    write_all!(writer, b"GET:1:a\n");
+    let buf = read_buf!(reader, 5);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "null\n");
+
+    // and the same write is taken once space is freed
+    disk_full.store(false, Ordering::SeqCst);
+    write_all!(writer, b"SET:1:a:1:1\nGET:1:a\n");
+    let expected = "1:1:7:created\n1:1\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_versioned_set() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7357");
+
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
+    let mut client = Client::connect("localhost", 7357, certs.clone())
+        .await
+        .expect("error connecting to test addr");
+    let mut other = Client::connect("localhost", 7357, certs)
+        .await
+        .expect("error connecting to test addr");
+
+    // an empty version only matches an absent key
+    assert_eq!(None, client.get_versioned(b"counter").await.unwrap());
+    assert!(client.set_if_version(b"counter", "", b"1").await.unwrap());
+    assert!(!other.set_if_version(b"counter", "", b"1").await.unwrap());
+
+    let (value, version) = client.get_versioned(b"counter").await.unwrap().unwrap();
+    assert_eq!(b"1".to_vec(), value);
+    assert_eq!(kave::store::version(b"1"), version);
+
+    // another writer bumps the value after it was read, so the stale version is refused
+    other.set(b"counter", b"5").await.unwrap();
+    assert!(!client
+        .set_if_version(b"counter", &version, b"2")
+        .await
+        .unwrap());
+    assert_eq!(
+        Some(b"5".to_vec()),
+        other
+            .mget_consistent(&[b"counter"])
+            .await
+            .unwrap()
+            .remove(0)
+    );
+
+    // re-reading picks up the fresh version, which is accepted once
+    let (value, version) = client.get_versioned(b"counter").await.unwrap().unwrap();
+    assert_eq!(b"5".to_vec(), value);
+    assert!(client
+        .set_if_version(b"counter", &version, b"6")
+        .await
+        .unwrap());
+    assert!(!other
+        .set_if_version(b"counter", &version, b"7")
+        .await
+        .unwrap());
+    let (value, _) = other.get_versioned(b"counter").await.unwrap().unwrap();
+    assert_eq!(b"6".to_vec(), value);
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_proto_limits() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7358", |cs| {
+        cs.set_proto_limits(ProtoLimits {
+            max_key_len: 8,
+            max_value_len: 16,
+            max_len_digits: 4,
+            max_scan_len: 64,
+            max_request_len: 32,
+        });
+    });
+
+    // requests within the limits are read as usual
+    let stream = utils::connect("localhost:7358")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"SET:8:12345678:16:0123456789abcdef\n");
+    let buf = read_buf!(reader, 15);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "2:16:7:created\n");
+
+    // each oversized length is refused as soon as it's read, without waiting for the
+    // bytes it announces, and the session is closed since they can't be skipped
+    let cases: [(&[u8], &str); 4] = [
+        (
+            b"SET:3:key:9999:",
+            "SET argument 1 is 9999 bytes, longer than the max value length of 16 bytes",
+        ),
+        (
+            b"MSET:1:2:1:a:16:0123456789abcdef:1:b:16:",
+            "MSET arguments add up to more than the max request length of 32 bytes",
+        ),
+        (
+            b"GET:9:",
+            "GET argument 0 is 9 bytes, longer than the max key length of 8 bytes",
+        ),
+        (
+            b"GET:12345",
+            "reading argument 0 length, longer than 4 digits",
+        ),
+    ];
+    for (request, reason) in cases {
+        let stream = utils::connect("localhost:7358")
+            .await
+            .expect("error connecting to test addr");
+        let (mut reader, mut writer) = split(stream);
+        write_all!(writer, request);
+        let msg = format!("limit exceeded: {reason}");
+        let expected = format!("ERR:{}:{msg}\n", msg.len());
+        let buf = read_buf!(reader, expected.len());
+        assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+        let mut rest = vec![];
+        tokio::time::timeout(Duration::from_secs(5), reader.read_to_end(&mut rest))
+            .await
+            .expect("session wasn't closed")
+            .ok();
+        assert!(rest.is_empty());
+    }
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_max_scan_len() {
+    init!();
+    let (shutdown_send, mut shutdown_recv, mut cs) = new_client_server();
+    cs.set_addr("127.0.0.1:7368").set_proto_limits(ProtoLimits {
+        max_scan_len: 1024,
+        ..ProtoLimits::default()
+    });
+    let mut events = cs.events();
+    tokio::spawn(async move { cs.start().await });
+    sleep(Duration::from_millis(100)).await;
+
+    // bytes that never reach a delimiter end the session once there are more than
+    // the limit, rather than being read for as long as the client sends them
+    let stream = utils::connect("localhost:7368")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"ECHO:2:ok");
+    let buf = read_buf!(reader, 5);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "2:ok\n");
+    let flood = tokio::spawn(async move {
+        let chunk = vec![b'x'; 64 * 1024];
+        for _ in 0..256 {
+            writer.write_all(&chunk).await?;
+        }
+        writer.flush().await
+    });
+    let reason = loop {
+        let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .expect("timed out waiting for the session to close")
+            .expect("error receiving event");
+        if let SessionEvent::Closed { reason, .. } = event {
+            break reason;
+        }
+    };
+    assert_eq!(
+        CloseReason::Error(
+            "limit exceeded: read more than 1024 bytes without finding the delimiters of an operation"
+                .into()
+        ),
+        reason
+    );
+    // the session was closed long before the 16MiB were all sent
+    let flooded = tokio::time::timeout(Duration::from_secs(5), flood)
+        .await
+        .expect("flood wasn't stopped")
+        .expect("flood panicked");
+    assert!(flooded.is_err());
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_max_request_len() {
+    init!();
+    let (shutdown_send, mut shutdown_recv, mut cs) = new_client_server();
+    cs.set_addr("127.0.0.1:7397").set_proto_limits(ProtoLimits {
+        max_request_len: 1024,
+        ..ProtoLimits::default()
+    });
+    let mut events = cs.events();
+    tokio::spawn(async move { cs.start().await });
+    sleep(Duration::from_millis(100)).await;
+
+    // a value announced beyond the limit ends the session before its bytes are
+    // buffered, however many the client goes on to send without a terminator
+    let stream = utils::connect("localhost:7397")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"SET:3:key:1000:");
+    write_all!(writer, &[b'x'; 1000]);
+    write_all!(writer, b"\n");
+    let buf = read_buf!(reader, 16);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "4:1000:7:created\n");
+    let flood = tokio::spawn(async move {
+        writer.write_all(b"SET:3:key:16777216:").await?;
+        let chunk = vec![b'x'; 64 * 1024];
+        for _ in 0..256 {
+            writer.write_all(&chunk).await?;
+        }
+        writer.flush().await
+    });
+    let reason = loop {
+        let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .expect("timed out waiting for the session to close")
+            .expect("error receiving event");
+        if let SessionEvent::Closed { reason, .. } = event {
+            break reason;
+        }
+    };
+    assert_eq!(
+        CloseReason::Error(
+            "limit exceeded: SET arguments add up to more than the max request length of 1024 bytes"
+                .into()
+        ),
+        reason
+    );
+    // the session was closed long before the 16MiB were all sent
+    let flooded = tokio::time::timeout(Duration::from_secs(5), flood)
+        .await
+        .expect("flood wasn't stopped")
+        .expect("flood panicked");
+    assert!(flooded.is_err());
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_client_server_unix_socket() {
+    init!();
+    let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("kave.sock");
+    // a socket left behind by a server that didn't shut down cleanly is replaced
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+
+    let (shutdown_send, mut shutdown_recv, mut cs) = new_client_server();
+    cs.set_addr("127.0.0.1:7398").set_unix_socket(Some(&path));
+    tokio::spawn(async move { cs.start().await });
+    sleep(Duration::from_millis(100)).await;
+
+    // sessions over the socket speak the same protocol, without TLS
+    let stream = tokio::net::UnixStream::connect(&path)
+        .await
+        .expect("error connecting to unix socket");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"ECHO:5:hello\n");
+    let buf = read_buf!(reader, 8);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "5:hello\n");
+    write_all!(writer, b"SET:3:foo:3:bar\nGET:3:foo\n");
+    let buf = read_buf!(reader, 20);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "1:3:7:created\n3:bar\n");
+
+    // alongside clients over TLS
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
+    let mut client = Client::connect("localhost", 7398, certs)
+        .await
+        .expect("error connecting to test addr");
+    assert_eq!(b"hello".to_vec(), client.echo(b"hello").await.unwrap());
+
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+    // the socket is removed once it's no longer listened on
+    assert!(!path.exists());
+
+    // or instead of them
+    let (shutdown_send, mut shutdown_recv, mut cs) = new_client_server();
+    cs.set_addr("127.0.0.1:7399")
+        .set_tcp(false)
+        .set_unix_socket(Some(&path));
+    tokio::spawn(async move { cs.start().await });
+    sleep(Duration::from_millis(100)).await;
+    let stream = tokio::net::UnixStream::connect(&path)
+        .await
+        .expect("error connecting to unix socket");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"ECHO:5:hello\n");
+    let buf = read_buf!(reader, 8);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "5:hello\n");
+    assert!(utils::connect("localhost:7399").await.is_err());
+
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_client_server_quit() {
+    init!();
+    let (shutdown_send, mut shutdown_recv, mut cs) = new_client_server();
+    cs.set_addr("127.0.0.1:7359");
+    let mut events = cs.events();
+    tokio::spawn(async move { cs.start().await });
+    sleep(Duration::from_millis(100)).await;
+
+    // everything pipelined after QUIT is discarded, the session ends with its ack
+    let stream = utils::connect("localhost:7359")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"QUIT\nGET:3:key\nSET:3:key:5:after\n");
+    let mut buf = vec![];
+    tokio::time::timeout(Duration::from_secs(5), reader.read_to_end(&mut buf))
+        .await
+        .expect("session wasn't closed")
+        .expect("session wasn't closed cleanly");
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "OK\n");
+    loop {
+        let event = tokio::time::timeout(Duration::from_secs(1), events.recv())
+            .await
+            .expect("timed out waiting for the session to close")
+            .expect("error receiving event");
+        match event {
+            SessionEvent::Command { op, .. } => assert_eq!("QUIT", op),
+            SessionEvent::Closed { reason, .. } => {
+                assert_eq!(CloseReason::ClientQuit, reason);
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    // so the SET was never applied
+    let stream = utils::connect("localhost:7359")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"GET:3:key\n");
+    let buf = read_buf!(reader, 5);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "null\n");
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_command_metrics() {
+    init!();
+    let (shutdown_send, mut shutdown_recv, mut cs) = new_client_server();
+    cs.set_addr("127.0.0.1:7360")
+        .set_debug_commands(true)
+        .set_command_timeout(Some(Duration::from_millis(200)));
+    let metrics = cs.metrics();
+    let mut events = cs.events();
+    tokio::spawn(async move { cs.start().await });
+    sleep(Duration::from_millis(100)).await;
+
+    let stream = utils::connect("localhost:7360")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(
+        writer,
+        b"SET:3:foo:3:bar\nGET:3:foo\nAPPLY:3:foo:3:add:1:1\nAPPLY:1:n:3:add:1:1\nQUIT\n"
+    );
+    let mut buf = vec![];
+    reader.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(
+        std::str::from_utf8(&buf).unwrap(),
+        "1:3:7:created\n3:bar\nERR:28:add: value is not an integer\n1:1\nOK\n"
+    );
+
+    // a command running past the timeout ends its session
+    let stream = utils::connect("localhost:7360")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"DEBUG:5:SLEEP:4:1000\n");
+    let mut buf = vec![];
+    reader.read_to_end(&mut buf).await.ok();
+    assert!(buf.is_empty());
+
+    // commands are counted before their session's close is published
+    let mut closed = 0;
+    while closed < 2 {
+        let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .expect("timed out waiting for the sessions to close")
+            .expect("error receiving event");
+        if let SessionEvent::Closed { .. } = event {
+            closed += 1;
+        }
+    }
+    assert_eq!(1, metrics.count("SET", Outcome::Ok));
+    assert_eq!(1, metrics.count("GET", Outcome::Ok));
+    assert_eq!(1, metrics.count("APPLY", Outcome::Ok));
+    assert_eq!(1, metrics.count("APPLY", Outcome::Error));
+    assert_eq!(1, metrics.count("DEBUG", Outcome::Timeout));
+    assert_eq!(0, metrics.count("DEBUG", Outcome::Ok));
+    let total: u64 = metrics.snapshot().iter().map(|(_, _, n)| n).sum();
+    assert_eq!(6, total);
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_invalid_request() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7361", |cs| {
+        cs.set_strict_protocol(false);
+    });
+
+    let stream = utils::connect("localhost:7361")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    let error = |msg: &str| format!("ERR:{}:{msg}\n", msg.len());
+
+    // each malformed request is answered with an error, skipping the rest of its line,
+    // and the well-formed requests pipelined around them are still read
+    write_all!(
+        writer,
+        b"GET:03:foo\nSET:3:foo;3:bar junk\nMGET:1:x:3:foo\nSET:3:foo:3:bar\nGET\nGET:3:foo\n"
+    );
+    let expected = [
+        error("invalid argument length \"03\", lengths must be decimal digits without a sign or leading zeros"),
+        error("reading argument 1 length, expected ':' found ';'"),
+        error("parseint error: invalid digit found in string"),
+        "1:3:7:created\n".to_string(),
+        error("reading argument 0 length, expected ':' found '\\n'"),
+        "3:bar\n".to_string(),
+    ]
+    .concat();
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // the rest of the line is skipped even when it arrives in a later read
+    write_all!(writer, b"SET:3:foo;no newline yet");
+    let expected = error("reading argument 1 length, expected ':' found ';'");
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+    sleep(Duration::from_millis(50)).await;
+    write_all!(writer, b" SET:3:foo:3:ham\nECHO:2:hi\n");
+    let buf = read_buf!(reader, 5);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "2:hi\n");
+    write_all!(writer, b"GET:3:foo\n");
+    let buf = read_buf!(reader, 6);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "3:bar\n");
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_streamed_set() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7362", |cs| {
+        cs.set_stream_values(Some(8))
+            .set_max_value_len(Some(16))
+            .set_value_limit_policy(ValueLimitPolicy::Truncate);
+    });
+    let stream = utils::connect("localhost:7362")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    // a streamed value may hold newlines, and arrive over several writes
+    write_all!(writer, b"SET:3:big:12:line1\n");
+    sleep(Duration::from_millis(50)).await;
+    write_all!(writer, b"line2\n\nGET:3:big\n");
+    let expected = "2:12:7:created\n12:line1\nline2\n\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // only what's kept of an oversized value is read, and the rest of it is skipped
+    // before reading the next command, newlines and all
+    write_all!(
+        writer,
+        b"SET:3:big:24:0123456789abcdef\n\nGET:3::5:async\nGET:3:big\n"
+    );
+    let expected = "2:16:7:updated:9:truncated\n16:0123456789abcdef\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // short values are read along with their request as usual
+    write_all!(
+        writer,
+        b"SETQ:3:sml:3:abc\nSETQ:3:big:8:12345678\nGET:3:sml\nGET:3:big\n"
+    );
+    let expected = "3:abc\n8:12345678\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // the durability following a streamed value applies as it does to any SET, and
+    // an unknown one closes the session without writing
+    write_all!(writer, b"SET:3:dur:8:12345678:5:fsync\nGET:3:dur\n");
+    let expected = "1:8:7:created\n8:12345678\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+    write_all!(writer, b"SET:3:bad:8:12345678:6:always\n");
+    let mut buf = vec![];
+    let n = reader.read_to_end(&mut buf).await.unwrap_or(0);
+    assert_eq!(0, n, "{}", String::from_utf8_lossy(&buf));
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_pipelined_client() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7363");
+
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
+    let client = Client::connect("localhost", 7363, certs)
+        .await
+        .expect("error connecting to test addr")
+        .pipelined();
+
+    // every task's requests share the one connection, interleaved with the others',
+    // and each response still finds its way back to the request it answers
+    let mut tasks = vec![];
+    for task in 0..16 {
+        let client = client.clone();
+        tasks.push(tokio::spawn(async move {
+            for i in 0..50 {
+                let key = format!("task{task}:{i}");
+                let value = format!("value-{task}-{i}").repeat(i % 3 + 1);
+                assert_eq!(
+                    value.len(),
+                    client.set(key.as_bytes(), value.as_bytes()).await.unwrap()
+                );
+                assert_eq!(
+                    Some(value.into_bytes()),
+                    client.get(key.as_bytes()).await.unwrap()
+                );
+                assert_eq!(
+                    None,
+                    client.get(format!("{key}:unset").as_bytes()).await.unwrap()
+                );
+            }
+        }));
+    }
+    for task in tasks {
+        task.await.expect("pipelining task failed");
+    }
+    assert_eq!(
+        Some(b"value-15-0".to_vec()),
+        client.get(b"task15:0").await.unwrap()
+    );
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_streamed_get_result() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7364", |cs| {
+        cs.set_command_handler(Arc::new(BlobHandler));
+    });
+    let stream = utils::connect("localhost:7364")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    // values longer than the proto's buffers, and than a TLS record, are framed
+    // just like any other GET result, with the following result right behind them
+    for len in [1, 1000, 70_000] {
+        write_all!(
+            writer,
+            format!("BLOB:{}:{len}\nECHO:2:ok\n", len.to_string().len()).as_bytes()
+        );
+        let expected = format!("{len}:{}!\n2:ok\n", "x".repeat(len - 1));
+        let buf = read_buf!(reader, expected.len());
+        assert_eq!(buf.len(), expected.len());
+        assert!(buf == expected.as_bytes());
+    }
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_split_op() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7365");
+
+    // the very first read may hold only part of the op name
+    let stream = utils::connect("localhost:7365")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"SET");
+    sleep(Duration::from_millis(20)).await;
+    write_all!(writer, b":3:foo:3:bar\n");
+    let buf = read_buf!(reader, 14);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "1:3:7:created\n");
+
+    // and so may every read after it, wherever the command is split
+    let cases: [(&[u8], &str); 6] = [
+        (b"GET:3:foo\n", "3:bar\n"),
+        (b"SET:3:foo:3:bar\n", "1:3:7:updated\n"),
+        (b"SETQ:3:foo:3:bar\nECHO:2:ok\n", "2:ok\n"),
+        (b"STRLEN:3:foo\n", "1:3\n"),
+        (b"MGET:1:2:3:foo:3:baz\n", "1:2:3:bar:null\n"),
+        (b"ECHO:5:hello\n", "5:hello\n"),
+    ];
+    for (request, expected) in cases {
+        for split_at in 1..request.len() {
+            write_all!(writer, &request[..split_at]);
+            sleep(Duration::from_millis(10)).await;
+            write_all!(writer, &request[split_at..]);
+            let buf = read_buf!(reader, expected.len());
+            assert_eq!(
+                std::str::from_utf8(&buf).unwrap(),
+                expected,
+                "{:?} split at {split_at}",
+                String::from_utf8_lossy(request)
+            );
+        }
+    }
+
+    // a first read shorter than any op name is kept too, rather than read as malformed
+    let stream = utils::connect("localhost:7365")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"GE");
+    sleep(Duration::from_millis(20)).await;
+    write_all!(writer, b"T:3:key\nGET:3:foo\n");
+    let buf = read_buf!(reader, 11);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "null\n3:bar\n");
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[cfg(feature = "index")]
+#[tokio::test]
+async fn test_client_server_find() {
+    use kave::store::index::{json_field, IndexedStore};
+
+    init!();
+    let store = IndexedStore::new(MemoryStore::new(), json_field("team"))
+        .await
+        .expect("error indexing store");
+    let (shutdown_send, mut shutdown_recv, mut cs) = new_client_server_with_store(store);
+    cs.set_addr("127.0.0.1:7366");
+    tokio::spawn(async move { cs.start().await });
+    sleep(Duration::from_millis(100)).await;
+
+    let stream = utils::connect("localhost:7366")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(
+        writer,
+        b"SETQ:3:ada:14:{\"team\":\"red\"}\nSETQ:3:bob:15:{\"team\":\"blue\"}\n"
+    );
+    write_all!(
+        writer,
+        b"SETQ:2:cy:14:{\"team\":\"red\"}\nSETQ:3:dee:3:red\n"
+    );
+    let cases: [(&[u8], &str); 6] = [
+        (b"FIND:3:red\n", "1:2:3:ada:2:cy\n"),
+        (b"FIND:4:blue\n", "1:1:3:bob\n"),
+        (b"FIND:5:green\n", "1:0\n"),
+        // updates move keys between attributes, and deletes drop them
+        (b"SET:3:ada:15:{\"team\":\"blue\"}\n", "2:15:7:updated\n"),
+        (b"DEL:2:cy\n", "1:1\n"),
+        (b"FIND:4:blue\n", "1:2:3:ada:3:bob\n"),
+    ];
+    for (request, expected) in cases {
+        write_all!(writer, request);
+        let buf = read_buf!(reader, expected.len());
+        assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+    }
+    write_all!(writer, b"FIND:3:red\n");
+    let buf = read_buf!(reader, 4);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "1:0\n");
+
+    // namespaced sessions only find their own keys, named as they named them
+    write_all!(
+        writer,
+        b"USE:4:app1\nSETQ:3:eve:15:{\"team\":\"blue\"}\nFIND:4:blue\n"
+    );
+    let expected = "OK\n1:1:3:eve\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_find_unindexed() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7367");
+
+    // stores without an index can't answer FIND, but the session carries on
+    let stream = utils::connect("localhost:7367")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"FIND:3:red\nECHO:2:ok\n");
+    let expected = "ERR:38:FIND: the store has no secondary index\n2:ok\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+/// Read a response up to and including its trailing newline, for responses
+/// whose length isn't known up front
+async fn read_line<R: tokio::io::AsyncRead + Unpin>(reader: &mut R) -> String {
+    let mut line = vec![];
+    loop {
+        let byte = tokio::time::timeout(Duration::from_secs(5), reader.read_u8())
+            .await
+            .expect("timed out reading a line")
+            .expect("error reading a line");
+        line.push(byte);
+        if byte == b'\n' {
+            return String::from_utf8(line).unwrap();
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_client_server_debug_logs() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7369", |cs| {
+        cs.set_debug_commands(true)
+            .set_strict_protocol(false)
+            .set_slow_command(Some(Duration::from_millis(50)));
+    });
+
+    let stream = utils::connect("localhost:7369")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(
+        writer,
+        b"NOPE\nSET:3:str:3:foo\nAPPLY:3:str:3:add:1:1\nDEBUG:5:SLEEP:2:60\n"
+    );
+    assert!(read_line(&mut reader).await.starts_with("ERR:"));
+    assert_eq!("1:3:7:created\n", read_line(&mut reader).await);
+    assert!(read_line(&mut reader).await.starts_with("ERR:"));
+    assert_eq!("OK\n", read_line(&mut reader).await);
+
+    // every error answered is kept, oldest first, with the session it was sent to
+    write_all!(writer, b"DEBUG:6:ERRORS:3:GET\n");
+    let errors = read_line(&mut reader).await;
+    assert!(errors.starts_with("1:2:"), "{errors}");
+    let unknown = errors.find("unknown command \"NOPE\"").expect(&errors);
+    let apply = errors
+        .find(" add: value is not an integer\n")
+        .expect(&errors);
+    assert!(unknown < apply, "{errors}");
+    assert_eq!(2, errors.matches(" session=").count(), "{errors}");
+
+    // as is every command slower than the threshold
+    write_all!(writer, b"DEBUG:7:SLOWLOG:3:GET\n");
+    let slow = read_line(&mut reader).await;
+    assert!(slow.starts_with("1:1:"), "{slow}");
+    assert!(slow.contains(" DEBUG took "), "{slow}");
+
+    // until they're cleared
+    write_all!(writer, b"DEBUG:6:ERRORS:5:RESET\nDEBUG:7:SLOWLOG:5:RESET\n");
+    assert_eq!("1:2\n", read_line(&mut reader).await);
+    assert_eq!("1:1\n", read_line(&mut reader).await);
+    write_all!(writer, b"DEBUG:6:ERRORS:3:GET\nDEBUG:7:SLOWLOG:3:GET\n");
+    assert_eq!("1:0\n", read_line(&mut reader).await);
+    assert_eq!("1:0\n", read_line(&mut reader).await);
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_scan() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7370");
+
+    let stream = utils::connect("localhost:7370")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(
+        writer,
+        b"SETQ:6:user:1:3:ada\nSETQ:6:user:2:2:cy\nSETQ:6:user:3:3:dee\nSETQ:5:users:1:x\n"
+    );
+    let cases: [(&[u8], &str); 6] = [
+        // a prefix, up to the next key that doesn't share it
+        (
+            b"SCAN:5:user::5:user;:2:10\n",
+            "1:6:6:user:1:3:ada:6:user:2:2:cy:6:user:3:3:dee\n",
+        ),
+        // cut off at the limit
+        (
+            b"SCAN:5:user::5:user;:1:2\n",
+            "1:4:6:user:1:3:ada:6:user:2:2:cy\n",
+        ),
+        // to the last key without an end
+        (
+            b"SCAN:6:user:3:0::2:10\n",
+            "1:4:6:user:3:3:dee:5:users:1:x\n",
+        ),
+        // empty ranges
+        (b"SCAN:6:user:4:5:users:2:10\n", "1:0\n"),
+        (b"SCAN:1:b:1:a:2:10\n", "1:0\n"),
+        (b"SCAN:0::0::1:0\n", "1:0\n"),
+    ];
+    for (request, expected) in cases {
+        write_all!(writer, request);
+        let buf = read_buf!(reader, expected.len());
+        assert_eq!(
+            std::str::from_utf8(&buf).unwrap(),
+            expected,
+            "{}",
+            String::from_utf8_lossy(request)
+        );
+    }
+
+    // namespaced sessions only scan their own keys, named as they named them
+    write_all!(
+        writer,
+        b"USE:4:app1\nSETQ:1:a:1:1\nSETQ:1:b:1:2\nSCAN:0::0::2:10\n"
+    );
+    let expected = "OK\n1:4:1:a:1:1:1:b:1:2\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_client_get_set_echo() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7371");
+
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
+    let mut client = Client::connect("localhost", 7371, certs)
+        .await
+        .expect("error connecting to test addr");
+
+    assert_eq!(None, client.get(b"abcde").await.unwrap());
+    assert_eq!(5, client.set(b"abcde", b"01234").await.unwrap());
+    assert_eq!(Some(b"01234".to_vec()), client.get(b"abcde").await.unwrap());
+    // values holding the protocol's delimiters, and empty ones, round-trip as is
+    assert_eq!(4, client.set(b"abcde", b"a:\nb").await.unwrap());
+    assert_eq!(Some(b"a:\nb".to_vec()), client.get(b"abcde").await.unwrap());
+    assert_eq!(0, client.set(b"empty", b"").await.unwrap());
+    assert_eq!(Some(vec![]), client.get(b"empty").await.unwrap());
+
+    assert_eq!(
+        b"working!!!".to_vec(),
+        client.echo(b"working!!!").await.unwrap()
+    );
+    assert_eq!(b"a:b\n".to_vec(), client.echo(b"a:b\n").await.unwrap());
+    assert_eq!(Vec::<u8>::new(), client.echo(b"").await.unwrap());
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+/// End a frame with its checksum, for sessions that turned them on with HELLO
+fn checksummed(frame: &[u8]) -> Vec<u8> {
+    let mut frame = frame.to_vec();
+    let checksum = crc32fast::hash(&frame);
+    frame.extend_from_slice(format!("#{checksum:08x}\n").as_bytes());
+    frame
+}
+
+#[tokio::test]
+async fn test_client_server_frame_checksums() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7372");
+
+    let stream = utils::connect("localhost:7372")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+
+    // HELLO's response is the first checksummed frame
+    let version = env!("CARGO_PKG_VERSION");
+    write_all!(writer, b"HELLO:5:crc32\n");
+    let expected =
+        checksummed(format!("7:version:{}:{version}:8:checksum:5:crc32", version.len()).as_bytes());
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(buf, expected);
+
+    // then every request must carry one, including those without arguments
+    let mut requests = checksummed(b"SET:3:key:3:bar");
+    requests.extend(checksummed(b"GET:3:key"));
+    requests.extend(checksummed(b"GET:7:missing"));
+    requests.extend(checksummed(b"TIME"));
+    write_all!(writer, &requests);
+    for expected in [
+        checksummed(b"1:3:7:created"),
+        checksummed(b"3:bar"),
+        checksummed(b"null"),
+    ] {
+        let response = read_line(&mut reader).await;
+        assert_eq!(response.as_bytes(), expected);
+    }
+    let time = read_line(&mut reader).await;
+    let (frame, checksum) = time.trim_end().split_once('#').unwrap();
+    assert_eq!(
+        format!("{:08x}", crc32fast::hash(frame.as_bytes())),
+        checksum
+    );
+
+    // frames split across reads are checked whole
+    let request = checksummed(b"GET:3:key");
+    for part in [&request[..7], &request[7..12], &request[12..]] {
+        write_all!(writer, part);
+        sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(
+        read_line(&mut reader).await.as_bytes(),
+        checksummed(b"3:bar")
+    );
+
+    // a byte flipped on the way is refused, ending the session
+    let mut corrupted = checksummed(b"SET:3:key:3:baz");
+    corrupted[13] = b'x';
+    write_all!(writer, &corrupted);
+    let error = read_line(&mut reader).await;
+    assert!(error.starts_with("ERR:"), "{error}");
+    assert!(
+        error.contains("frame corrupted: SET checksum is "),
+        "{error}"
+    );
+    let (frame, checksum) = error.trim_end().split_once('#').unwrap();
+    assert_eq!(
+        format!("{:08x}", crc32fast::hash(frame.as_bytes())),
+        checksum
+    );
+    let mut rest = vec![];
+    let read = tokio::time::timeout(Duration::from_secs(5), reader.read_to_end(&mut rest))
+        .await
+        .expect("session wasn't ended");
+    assert!(read.is_err() || rest.is_empty(), "{rest:?}");
+
+    // as are requests missing their checksum, and the corrupted write wasn't applied
+    let stream = utils::connect("localhost:7372")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"HELLO:5:crc32\nGET:3:key\n");
+    read_line(&mut reader).await;
+    let error = read_line(&mut reader).await;
+    assert!(
+        error.contains("frame corrupted: expected '#' and 8 hex digits after the arguments of GET"),
+        "{error}"
+    );
+    let stream = utils::connect("localhost:7372")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"GET:3:key\n");
+    let buf = read_buf!(reader, 6);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "3:bar\n");
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_pool() {
+    init!();
+    let (shutdown_send, mut shutdown_recv, mut cs) = new_client_server();
+    cs.set_addr("127.0.0.1:7373");
+    let mut events = cs.events();
+    tokio::spawn(async move { cs.start().await });
+    sleep(Duration::from_millis(100)).await;
+    let sessions = tokio::spawn(async move {
+        let mut opened = 0;
+        loop {
+            match events.recv().await {
+                Ok(SessionEvent::Opened { .. }) => opened += 1,
+                Ok(_) | Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {}
+                Err(_) => return opened,
+            }
+        }
+    });
+
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
+    let pool = Pool::new("localhost", 7373, certs.clone(), 3);
+    let tasks = (0..10)
+        .map(|task| {
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                for i in 0..10 {
+                    let mut conn = pool.get_conn().await.expect("error taking a connection");
+                    let key = format!("task{task}");
+                    let value = format!("value{i}");
+                    conn.set(key.as_bytes(), value.as_bytes()).await.unwrap();
+                    assert_eq!(
+                        Some(value.into_bytes()),
+                        conn.get(key.as_bytes()).await.unwrap()
+                    );
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for task in tasks {
+        task.await.expect("pool task failed");
+    }
+    // a hundred requests over three connections at most, all kept for reuse
+    let opened = pool.opened();
+    assert!((1..=3).contains(&opened), "{opened}");
+    assert_eq!(opened, pool.idle());
+
+    // connections the server closed fail their health check and are replaced
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+    assert_eq!(opened, sessions.await.unwrap());
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7373");
+    let mut conn = pool.get_conn().await.expect("error taking a connection");
+    assert_eq!(None, conn.get(b"task0").await.unwrap());
+    assert_eq!(opened + 1, pool.opened());
+    assert_eq!(0, pool.idle());
+    drop(conn);
+    assert_eq!(1, pool.idle());
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_idle_timeout() {
+    init!();
+    let (shutdown_send, mut shutdown_recv, mut cs) = new_client_server();
+    cs.set_addr("127.0.0.1:7374")
+        .set_idle_timeout(Some(Duration::from_millis(300)));
+    let mut events = cs.events();
+    tokio::spawn(async move { cs.start().await });
+    sleep(Duration::from_millis(100)).await;
+
+    // a client that connects and sends nothing is dropped after the timeout
+    let stream = utils::connect("localhost:7374")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, _writer) = split(stream);
+    let connected = std::time::Instant::now();
+    let mut rest = vec![];
+    let read = tokio::time::timeout(Duration::from_secs(5), reader.read_to_end(&mut rest))
+        .await
+        .expect("idle session wasn't closed");
+    assert_eq!(0, read.expect("session wasn't closed cleanly"));
+    let idle = connected.elapsed();
+    assert!(idle >= Duration::from_millis(300), "{idle:?}");
+    let mut closed = None;
+    while let Ok(Ok(event)) = tokio::time::timeout(Duration::from_secs(1), events.recv()).await {
+        if let SessionEvent::Closed { reason, .. } = event {
+            closed = Some(reason);
+            break;
+        }
+    }
+    assert_eq!(Some(CloseReason::IdleTimeout), closed);
+
+    // clients that keep sending stay connected past it, the timeout restarting with each read
+    let stream = utils::connect("localhost:7374")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    for _ in 0..4 {
+        sleep(Duration::from_millis(150)).await;
+        write_all!(writer, b"ECHO:2:hi\n");
+        let buf = read_buf!(reader, 5);
+        assert_eq!(std::str::from_utf8(&buf).unwrap(), "2:hi\n");
+    }
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_ping() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7375");
+
+    let stream = utils::connect("localhost:7375")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"SETQ:1:a:2:aa\nSETQ:1:b:2:bb\n");
+
+    // pipelined, and split mid-command, PINGs answer in line with the GETs around them
+    write_all!(writer, b"GET:1:a\nPING\nGET:1:b\nPING:4:abcd\nPI");
+    sleep(Duration::from_millis(50)).await;
+    write_all!(writer, b"NG\nGET:1:c\nPING:0:\nGET:1:a\n");
+    let expected = "2:aa\nPONG\n2:bb\n4:abcd\nPONG\nnull\n0:\n2:aa\n";
+    let mut responses = String::new();
+    while responses.len() < expected.len() {
+        responses.push_str(&read_line(&mut reader).await);
+    }
+    assert_eq!(expected, responses);
+
+    // as does the client's
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
+    let mut client = Client::connect("localhost", 7375, certs)
+        .await
+        .expect("error connecting to test addr");
+    client.ping().await.unwrap();
+    assert_eq!(Some(b"aa".to_vec()), client.get(b"a").await.unwrap());
+    client.ping().await.unwrap();
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_binary_keys() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7376");
+
+    let stream = utils::connect("localhost:7376")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    // keys needn't be utf8, and may hold NULs
+    let cases: [(&[u8], &[u8]); 5] = [
+        (b"SET:4:a\xff\x00b:3:bar\n", b"1:3:7:created\n"),
+        (b"SET:2:a\xff:6:prefix\n", b"1:6:7:created\n"),
+        (b"GET:4:a\xff\x00b\n", b"3:bar\n"),
+        (b"GET:3:a\xff\x00\n", b"null\n"),
+        (
+            b"SCAN:2:a\xff:0::2:10\n",
+            b"1:4:2:a\xff:6:prefix:4:a\xff\x00b:3:bar\n",
+        ),
+    ];
+    for (request, expected) in cases {
+        write_all!(writer, request);
+        let buf = read_buf!(reader, expected.len());
+        assert_eq!(
+            String::from_utf8_lossy(&buf),
+            String::from_utf8_lossy(expected),
+            "{}",
+            String::from_utf8_lossy(request)
+        );
+    }
+
+    // as do the client's
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
+    let mut client = Client::connect("localhost", 7376, certs)
+        .await
+        .expect("error connecting to test addr");
+    assert_eq!(3, client.set(b"\x00\xff", b"nul").await.unwrap());
+    assert_eq!(
+        Some(b"nul".to_vec()),
+        client.get(b"\x00\xff").await.unwrap()
+    );
+    assert_eq!(
+        Some(b"bar".to_vec()),
+        client.get(b"a\xff\x00b").await.unwrap()
+    );
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_mset() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7377", |cs| {
+        cs.set_max_value_len(Some(5))
+            .set_value_limit_policy(ValueLimitPolicy::Truncate);
+    });
+
+    let stream = utils::connect("localhost:7377")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    let too_large = "value of 6 bytes exceeds max value size of 5 bytes";
+    let cases: [(&[u8], String); 6] = [
+        (b"MSET:1:2:1:a:1:x:1:b:1:y\n", "OK\n".to_string()),
+        // a mix of hits and misses
+        (b"MGET:1:3:1:a:1:c:1:b\n", "1:3:1:x:null:1:y\n".to_string()),
+        // one value too large fails every write, even though SETs would be truncated
+        (
+            b"MSET:1:3:1:a:1:z:1:c:6:abcdef:1:d:1:w\n",
+            format!("ERR:{}:{too_large}\n", too_large.len()),
+        ),
+        (b"MGET:1:3:1:a:1:c:1:d\n", "1:3:1:x:null:null\n".to_string()),
+        // later values win when a key is repeated
+        (b"MSET:1:2:1:a:1:1:1:a:1:2\n", "OK\n".to_string()),
+        (b"GET:1:a\n", "1:2\n".to_string()),
+    ];
+    for (request, expected) in cases {
+        write_all!(writer, request);
+        assert_eq!(
+            expected,
+            read_line(&mut reader).await,
+            "{}",
+            String::from_utf8_lossy(request)
+        );
+    }
+
+    // as does the client's
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
+    let mut client = Client::connect("localhost", 7377, certs)
+        .await
+        .expect("error connecting to test addr");
+    client
+        .mset(&[
+            (b"k1".as_slice(), b"v1".as_slice()),
+            (b"k2".as_slice(), b"v2".as_slice()),
+        ])
+        .await
+        .unwrap();
+    assert_eq!(
+        vec![Some(b"v1".to_vec()), None, Some(b"v2".to_vec())],
+        client
+            .mget_consistent(&[b"k1".as_slice(), b"k3", b"k2"])
+            .await
+            .unwrap()
+    );
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_transactions() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7378", |cs| {
+        cs.set_strict_protocol(false);
+    });
+
+    let stream = utils::connect("localhost:7378")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"SETQ:1:b:3:old\n");
+    let cases: [(&[u8], &str); 17] = [
+        // nothing is applied until COMMIT, reads answer straight away
+        (b"BEGIN\n", "OK\n"),
+        (b"SET:1:a:1:x\n", "QUEUED\n"),
+        (b"DEL:1:b\n", "QUEUED\n"),
+        (b"SETQ:1:c:1:z\n", ""),
+        (b"GET:1:b\n", "3:old\n"),
+        (
+            b"SWAP:1:a:1:b\n",
+            "ERR:59:SWAP can't be queued in a transaction, only SET and DEL can\n",
+        ),
+        (b"BEGIN\n", "ERR:36:BEGIN: a transaction is already open\n"),
+        (b"COMMIT\n", "OK\n"),
+        (b"MGET:1:3:1:a:1:b:1:c\n", "1:3:1:x:null:1:z\n"),
+        // DISCARD drops the queued writes
+        (b"BEGIN\n", "OK\n"),
+        (b"SET:1:a:1:y\n", "QUEUED\n"),
+        (b"DISCARD\n", "OK\n"),
+        (b"GET:1:a\n", "1:x\n"),
+        // and each only ends an open transaction
+        (b"COMMIT\n", "ERR:30:COMMIT: no transaction is open\n"),
+        (b"DISCARD\n", "ERR:31:DISCARD: no transaction is open\n"),
+        // an empty transaction commits nothing
+        (b"BEGIN\n", "OK\n"),
+        (b"COMMIT\n", "OK\n"),
+    ];
+    for (request, expected) in cases {
+        write_all!(writer, request);
+        if !expected.is_empty() {
+            assert_eq!(
+                expected,
+                read_line(&mut reader).await,
+                "{}",
+                String::from_utf8_lossy(request)
+            );
+        }
+    }
+
+    // a transaction left open when the connection drops is never applied
+    write_all!(writer, b"BEGIN\nSET:1:a:4:lost\nSET:1:d:4:lost\n");
+    assert_eq!("OK\n", read_line(&mut reader).await);
+    assert_eq!("QUEUED\n", read_line(&mut reader).await);
+    assert_eq!("QUEUED\n", read_line(&mut reader).await);
+    drop((reader, writer));
+    let stream = utils::connect("localhost:7378")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"MGET:1:2:1:a:1:d\n");
+    assert_eq!("1:2:1:x:null\n", read_line(&mut reader).await);
+    // and the new connection starts outside of one
+    write_all!(writer, b"SET:1:d:1:w\nGET:1:d\n");
+    assert_eq!("1:1:7:created\n", read_line(&mut reader).await);
+    assert_eq!("1:w\n", read_line(&mut reader).await);
+
+    // a malformed command discards the transaction, so COMMIT applies none of it
+    write_all!(writer, b"BEGIN\nSET:1:e:1:e\nGET:1:d\nSET:1:f\nCOMMIT\n");
+    assert_eq!("OK\n", read_line(&mut reader).await);
+    assert_eq!("QUEUED\n", read_line(&mut reader).await);
+    assert_eq!("1:w\n", read_line(&mut reader).await);
+    let msg = "transaction discarded, command 3 after BEGIN is invalid: reading argument 1 length, expected ':' found '\\n'";
+    assert_eq!(
+        format!("ERR:{}:{msg}\n", msg.len()),
+        read_line(&mut reader).await
+    );
+    assert_eq!(
+        "ERR:30:COMMIT: no transaction is open\n",
+        read_line(&mut reader).await
+    );
+    write_all!(writer, b"MGET:1:2:1:e:1:f\n");
+    assert_eq!("1:2:null:null\n", read_line(&mut reader).await);
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+/// Store recording how durable each transaction asked to be
+#[derive(Clone, Default)]
+struct DurabilityStore {
+    inner: MemoryStore,
+    durabilities: Arc<std::sync::Mutex<Vec<Durability>>>,
+}
+
+#[async_trait]
+impl Store for DurabilityStore {
+    async fn get(&mut self, k: &[u8]) -> kave::Result<Option<Vec<u8>>> {
+        self.inner.get(k).await
+    }
+
+    async fn scan(&mut self, from: &[u8], to: &[u8]) -> kave::Result<Vec<Vec<u8>>> {
+        self.inner.scan(from, to).await
+    }
+
+    async fn transact(&mut self, transaction: Transaction) -> kave::Result<Vec<bool>> {
+        self.durabilities
+            .lock()
+            .unwrap()
+            .push(transaction.durability());
+        self.inner.transact(transaction).await
+    }
+
+    async fn swap(&mut self, a: &[u8], b: &[u8]) -> kave::Result<()> {
+        self.inner.swap(a, b).await
+    }
+
+    async fn apply(&mut self, k: &[u8], transform: &Transform) -> kave::Result<Vec<u8>> {
+        self.inner.apply(k, transform).await
+    }
+}
+
+#[tokio::test]
+async fn test_client_server_transaction_limits() {
+    init!();
+    let store = DurabilityStore::default();
+    let durabilities = store.durabilities.clone();
+    let (shutdown_send, mut shutdown_recv, mut cs) = new_client_server_with_store(store);
+    cs.set_addr("127.0.0.1:7404").set_proto_limits(ProtoLimits {
+        max_request_len: 16,
+        ..ProtoLimits::default()
+    });
+    tokio::spawn(async move { cs.start().await });
+    sleep(Duration::from_millis(100)).await;
+
+    let stream = utils::connect("localhost:7404")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    // COMMIT is as durable as the most durable queued write, DELs included
+    let cases: [(&[u8], Durability); 3] = [
+        (
+            b"BEGIN\nSETQ:1:a:1:1:5:async\nSETQ:1:b:1:2:7:batched\nCOMMIT\n",
+            Durability::Batched,
+        ),
+        (
+            b"BEGIN\nSETQ:1:a:1:1:5:async\nDELQ:1:b\nCOMMIT\n",
+            Durability::Fsync,
+        ),
+        (b"BEGIN\nSETQ:1:a:1:1:5:async\nCOMMIT\n", Durability::Async),
+    ];
+    for (request, expected) in cases {
+        write_all!(writer, request);
+        assert_eq!("OK\n", read_line(&mut reader).await);
+        assert_eq!("OK\n", read_line(&mut reader).await);
+        let durability = durabilities.lock().unwrap().pop();
+        assert_eq!(Some(expected), durability);
+    }
+
+    // queued keys and values add up to no more than a request's arguments may, and a
+    // write past it discards the transaction, noreply or not
+    let msg = format!(
+        "transaction discarded, it may queue at most {MAX_QUEUED_OPS} writes and 16 bytes of keys and values"
+    );
+    let expected = format!("ERR:{}:{msg}\n", msg.len());
+    write_all!(
+        writer,
+        b"BEGIN\nSET:1:c:8:12345678\nSETQ:1:d:8:12345678\nCOMMIT\nGET:1:c\n"
+    );
+    assert_eq!("OK\n", read_line(&mut reader).await);
+    assert_eq!("QUEUED\n", read_line(&mut reader).await);
+    assert_eq!(expected, read_line(&mut reader).await);
+    assert_eq!(
+        "ERR:30:COMMIT: no transaction is open\n",
+        read_line(&mut reader).await
+    );
+    assert_eq!("null\n", read_line(&mut reader).await);
+
+    // as does a write past the most writes it may queue
+    let mut request = b"BEGIN\n".to_vec();
+    for _ in 0..MAX_QUEUED_OPS {
+        request.extend(b"DELQ:0:\n");
+    }
+    request.extend(b"DEL:0:\nCOMMIT\n");
+    write_all!(writer, &request);
+    assert_eq!("OK\n", read_line(&mut reader).await);
+    assert_eq!(expected, read_line(&mut reader).await);
+    assert_eq!(
+        "ERR:30:COMMIT: no transaction is open\n",
+        read_line(&mut reader).await
+    );
+    assert!(durabilities.lock().unwrap().is_empty());
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_expire() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7379");
+
+    let stream = utils::connect("localhost:7379")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    let cases: [(&[u8], &str); 10] = [
+        (b"SET:1:a:1:x\n", "1:1:7:created\n"),
+        (b"EXPIRE:1:a:2:60\n", "1:1\n"),
+        (b"GET:1:a\n", "1:x\n"),
+        // a TTL too far out for the clock never comes due
+        (b"EXPIRE:1:a:20:18446744073709551615\n", "1:1\n"),
+        (b"GET:1:a\n", "1:x\n"),
+        // there's nothing to expire
+        (b"EXPIRE:1:b:2:60\n", "1:0\n"),
+        // a TTL of 0 expires the key straight away
+        (b"EXPIRE:1:a:1:0\n", "1:1\n"),
+        (b"GET:1:a\n", "null\n"),
+        (b"MGET:1:1:1:a\n", "1:1:null\n"),
+        (b"EXPIRE:1:a:1:0\n", "1:0\n"),
+    ];
+    for (request, expected) in cases {
+        write_all!(writer, request);
+        assert_eq!(
+            expected,
+            read_line(&mut reader).await,
+            "{}",
+            String::from_utf8_lossy(request)
+        );
+    }
+
+    // as does the client's
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
+    let mut client = Client::connect("localhost", 7379, certs)
+        .await
+        .expect("error connecting to test addr");
+    client.set(b"k", b"v").await.unwrap();
+    assert!(client.expire(b"k", 60).await.unwrap());
+    assert_eq!(Some(b"v".to_vec()), client.get(b"k").await.unwrap());
+    assert!(client.expire(b"k", 0).await.unwrap());
+    assert_eq!(None, client.get(b"k").await.unwrap());
+    assert!(!client.expire(b"k", 0).await.unwrap());
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_cas() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7380");
+
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
+    let mut clients = vec![];
+    for _ in 0..2 {
+        let client = Client::connect("localhost", 7380, certs.clone())
+            .await
+            .expect("error connecting to test addr");
+        clients.push(client);
+    }
+
+    // an absent key never matches, even an empty expected value
+    assert!(!clients[0].compare_and_swap(b"k", b"", b"0").await.unwrap());
+    clients[0].set(b"k", b"0").await.unwrap();
+    assert!(!clients[0].compare_and_swap(b"k", b"1", b"2").await.unwrap());
+
+    // clients racing from the same value, only one of them wins each round
+    for round in 0..20 {
+        let current = round.to_string();
+        let racers = clients
+            .drain(..)
+            .enumerate()
+            .map(|(i, mut client)| {
+                let current = current.clone();
+                tokio::spawn(async move {
+                    let new = format!("{}", round + 1);
+                    let won = client
+                        .compare_and_swap(b"k", current.as_bytes(), new.as_bytes())
+                        .await
+                        .unwrap();
+                    (client, won.then_some(i))
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut winners = vec![];
+        for racer in racers {
+            let (client, won) = racer.await.expect("cas task panicked");
+            clients.push(client);
+            winners.extend(won);
+        }
+        assert_eq!(1, winners.len(), "round {round}");
+    }
+    assert_eq!(Some(b"20".to_vec()), clients[0].get(b"k").await.unwrap());
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_max_connections_reject() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7381", |cs| {
+        cs.set_max_connections(Some(2), ConnectionLimitPolicy::Reject);
+    });
+
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
+    let mut clients = vec![];
+    for _ in 0..2 {
+        let mut client = Client::connect("localhost", 7381, certs.clone())
+            .await
+            .expect("error connecting to test addr");
+        client.ping().await.unwrap();
+        clients.push(client);
+    }
+
+    // the next connection is answered with an error before its TLS handshake
+    let mut stream = tokio::net::TcpStream::connect("127.0.0.1:7381")
+        .await
+        .expect("error connecting to test addr");
+    let mut refused = String::new();
+    stream.read_to_string(&mut refused).await.unwrap();
+    assert_eq!("ERR:39:busy: too many connections, retry later\n", refused);
+    assert!(Client::connect("localhost", 7381, certs.clone())
+        .await
+        .is_err());
+
+    // closing a connection frees its slot
+    drop(clients.pop());
+    sleep(Duration::from_millis(100)).await;
+    let mut client = Client::connect("localhost", 7381, certs)
+        .await
+        .expect("error connecting to test addr");
+    client.ping().await.unwrap();
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_max_connections_queue() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7382", |cs| {
+        cs.set_max_connections(Some(1), ConnectionLimitPolicy::Queue);
+    });
+
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
+    let mut first = Client::connect("localhost", 7382, certs.clone())
+        .await
+        .expect("error connecting to test addr");
+    first.ping().await.unwrap();
+
+    // the next connection waits in the listen backlog, its handshake unanswered
+    let mut second = tokio::spawn(async move {
+        let mut client = Client::connect("localhost", 7382, certs)
+            .await
+            .expect("error connecting to test addr");
+        client.ping().await.unwrap();
+        client
+    });
+    assert!(
+        tokio::time::timeout(Duration::from_millis(200), &mut second)
+            .await
+            .is_err()
+    );
+
+    // until the first one closes and frees its slot
+    drop(first);
+    let mut second = tokio::time::timeout(Duration::from_secs(5), second)
+        .await
+        .expect("timed out waiting for a free slot")
+        .expect("connecting task panicked");
+    second.ping().await.unwrap();
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_drain_on_shutdown() {
+    init!();
+    let store = MemoryStore::new();
+    let (shutdown_send, mut shutdown_recv, mut cs) = new_client_server_with_store(store.clone());
+    cs.set_addr("127.0.0.1:7383")
+        .set_drain_timeout(Duration::from_secs(1));
+    tokio::spawn(async move { cs.start().await });
+    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+    let stream = utils::connect("localhost:7383")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    let stalled = utils::connect("localhost:7383")
+        .await
+        .expect("error connecting to test addr");
+    let (_stalled_reader, mut stalled_writer) = split(stalled);
+    let idle = utils::connect("localhost:7383")
+        .await
+        .expect("error connecting to test addr");
+    let (mut idle_reader, _idle_writer) = split(idle);
+
+    // a slow SET is partway through when the server starts shutting down
+    write_all!(writer, b"SET:3:foo:");
+    write_all!(stalled_writer, b"SET:3:bar:");
+    sleep(Duration::from_millis(100)).await;
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    sleep(Duration::from_millis(100)).await;
+
+    // sessions between requests are closed right away, and no new ones are accepted
+    let mut buf = vec![];
+    let res = idle_reader.read_to_end(&mut buf).await;
+    assert!(matches!(res, Ok(0) | Err(_)));
+    assert!(utils::connect("localhost:7383").await.is_err());
+
+    // while the SET is finished before its session is closed
+    write_all!(writer, b"3:baz\n");
+    let buf = read_buf!(reader, 14);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "1:3:7:created\n");
+    let mut buf = vec![];
+    let res = reader.read_to_end(&mut buf).await;
+    assert!(matches!(res, Ok(0) | Err(_)));
+    assert!(buf.is_empty());
+    assert_eq!(
+        Some(b"baz".to_vec()),
+        store.clone().get(b"foo").await.unwrap()
+    );
+
+    // and the SET that's never finished is killed once the drain times out
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+    assert_eq!(None, store.clone().get(b"bar").await.unwrap());
+}
+
+#[tokio::test]
+async fn test_client_server_prometheus_metrics() {
+    init!();
+    let (shutdown_send, mut shutdown_recv, mut cs) = new_client_server();
+    cs.set_addr("127.0.0.1:7384")
+        .set_metrics_addr(Some("127.0.0.1:7385"));
+    let mut events = cs.events();
+    tokio::spawn(async move { cs.start().await });
+    sleep(Duration::from_millis(100)).await;
+
+    let stream = utils::connect("localhost:7384")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    let request = b"SET:3:foo:3:bar\nGET:3:foo\nECHO:2:hi\nAPPLY:3:foo:3:add:1:1\nQUIT\n";
+    write_all!(writer, request);
+    let mut response = vec![];
+    reader.read_to_end(&mut response).await.unwrap();
+    assert_eq!(
+        std::str::from_utf8(&response).unwrap(),
+        "1:3:7:created\n3:bar\n2:hi\nERR:28:add: value is not an integer\nOK\n"
+    );
+    loop {
+        let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .expect("timed out waiting for the session to close")
+            .expect("error receiving event");
+        if let SessionEvent::Closed { .. } = event {
+            break;
+        }
+    }
+
+    let mut scrape = tokio::net::TcpStream::connect("127.0.0.1:7385")
+        .await
+        .expect("error connecting to metrics addr");
+    scrape
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut scraped = String::new();
+    scrape.read_to_string(&mut scraped).await.unwrap();
+    let (head, body) = scraped.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+    let lines = body.lines().collect::<Vec<_>>();
+    for expected in [
+        "kave_connections_total 1".to_string(),
+        "kave_connections_open 0".to_string(),
+        format!("kave_read_bytes_total {}", request.len()),
+        format!("kave_written_bytes_total {}", response.len()),
+        r#"kave_commands_total{command="SET",outcome="ok"} 1"#.to_string(),
+        r#"kave_commands_total{command="GET",outcome="ok"} 1"#.to_string(),
+        r#"kave_commands_total{command="ECHO",outcome="ok"} 1"#.to_string(),
+        r#"kave_commands_total{command="APPLY",outcome="error"} 1"#.to_string(),
+    ] {
+        assert!(lines.contains(&expected.as_str()), "{expected} in {body}");
+    }
+
+    // only the metrics path is served
+    let mut scrape = tokio::net::TcpStream::connect("127.0.0.1:7385")
+        .await
+        .expect("error connecting to metrics addr");
+    scrape
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut scraped = String::new();
+    scrape.read_to_string(&mut scraped).await.unwrap();
+    assert!(
+        scraped.starts_with("HTTP/1.1 404 Not Found\r\n"),
+        "{scraped}"
+    );
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_op_spans() {
+    // capture this test's spans, the server's tasks run on this thread too
+    let captured = Captured::default();
+    let writer = captured.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::new("kave::op=debug"))
+        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7386");
+
+    let stream = utils::connect("localhost:7386")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"SET:3:foo:6:secret\n");
+    read_buf!(reader, 14);
+    write_all!(writer, b"GET:3:foo\n");
+    read_buf!(reader, 9);
+    sleep(Duration::from_millis(50)).await;
+
+    // a span closes for each op, with its lengths and how long it took
+    let logs = captured.take();
+    let spans = logs
+        .lines()
+        .filter(|line| line.contains(" op{") && line.contains(": close "))
+        .collect::<Vec<_>>();
+    assert_eq!(2, spans.len(), "{logs}");
+    assert!(
+        spans[0].contains(r#"op="SET" key_len=3 value_len=6 response_len=14 elapsed_us="#),
+        "{logs}"
+    );
+    assert!(
+        spans[1].contains(r#"op="GET" key_len=3 value_len=0 response_len=9 elapsed_us="#),
+        "{logs}"
+    );
+    for span in spans {
+        let elapsed_us = span
+            .split("elapsed_us=")
+            .nth(1)
+            .and_then(|rest| rest.split('}').next())
+            .and_then(|us| us.parse::<u64>().ok());
+        assert!(elapsed_us.is_some(), "{span}");
+    }
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_shards() {
+    init!();
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
+    let nodes = ["localhost:7387", "localhost:7388", "localhost:7389"];
+    let ring = Ring::with_nodes(nodes);
+    let mut servers = vec![];
+    for node in nodes {
+        let store = MemoryStore::new();
+        let (shutdown_send, shutdown_recv, mut cs) = new_client_server_with_store(store.clone());
+        let shards = Shards::new(node, ring.clone(), certs.clone()).unwrap();
+        cs.set_addr(node.replace("localhost", "127.0.0.1"))
+            .set_shards(Some(shards));
+        tokio::spawn(async move { cs.start().await });
+        servers.push((store, shutdown_send, shutdown_recv));
+    }
+    sleep(Duration::from_millis(100)).await;
+
+    // written through every entry node at once, each key lands on the node owning it
+    let keys = (0..90).map(|i| format!("key{i}")).collect::<Vec<_>>();
+    let entries = (0..3)
+        .map(|entry| {
+            let (certs, keys) = (certs.clone(), keys.clone());
+            tokio::spawn(async move {
+                let mut client = Client::connect("localhost", 7387 + entry, certs)
+                    .await
+                    .expect("error connecting to test addr");
+                for key in keys.iter().skip(entry as usize).step_by(3) {
+                    assert_eq!(5, client.set(key.as_bytes(), b"value").await.unwrap());
+                }
+                client
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut clients = vec![];
+    for entry in entries {
+        clients.push(entry.await.expect("entry task failed"));
+    }
+    for (node, (store, _, _)) in nodes.iter().zip(servers.iter_mut()) {
+        let stored = store
+            .scan_entries(b"key", Some(b"kez"), 1000)
+            .await
+            .unwrap();
+        assert!(
+            (20..=40).contains(&stored.len()),
+            "{node}: {}",
+            stored.len()
+        );
+        for (key, _) in stored {
+            assert_eq!(Some(*node), ring.node_for(&key));
+        }
+    }
+    // and is read back through any of them
+    let reads = clients
+        .into_iter()
+        .map(|mut client| {
+            let keys = keys.clone();
+            tokio::spawn(async move {
+                for key in &keys {
+                    let value = client.get(key.as_bytes()).await.unwrap();
+                    assert_eq!(Some(b"value".to_vec()), value, "{key}");
+                }
+                assert_eq!(None, client.get(b"missing").await.unwrap());
+            })
+        })
+        .collect::<Vec<_>>();
+    for read in reads {
+        read.await.expect("read task failed");
+    }
+
+    // a SET's response is relayed as the owner wrote it
+    let stream = utils::connect("localhost:7387")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    let key = keys
+        .iter()
+        .find(|key| ring.node_for(key.as_bytes()) != Some(nodes[0]))
+        .unwrap();
+    let req = format!("SET:{}:{key}:3:new\n", key.len());
+    writer.write_all(req.as_bytes()).await.unwrap();
+    writer.flush().await.unwrap();
+    let mut buf = vec![0; 32];
+    let n = reader.read(&mut buf).await.unwrap();
+    assert_eq!(b"1:3:7:updated\n", &buf[..n]);
+
+    // as is that of any other command on a single key, which only the owner holds
+    let owner = ring.node_for(key.as_bytes()).unwrap();
+    let owner = nodes.iter().position(|node| *node == owner).unwrap();
+    let mut owner_store = servers[owner].0.clone();
+    let req = format!(
+        "EXPIRE:{0}:{key}:2:60\nAPPLY:{0}:{key}:6:append:1:!\nSTRLEN:{0}:{key}\n",
+        key.len()
+    );
+    write_all!(writer, req.as_bytes());
+    let expected = "1:1\n4:new!\n1:4\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+    write_all!(writer, format!("DEL:{}:{key}\n", key.len()).as_bytes());
+    let buf = read_buf!(reader, 4);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "1:1\n");
+    assert_eq!(None, owner_store.get(key.as_bytes()).await.unwrap());
+    let req = format!("EXPIRE:{}:{key}:2:60\n", key.len());
+    write_all!(writer, req.as_bytes());
+    let buf = read_buf!(reader, 4);
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), "1:0\n");
+
+    // in the session's namespace and value encoding
+    let req = format!(
+        "USE:2:ns\nHELLO:3:hex\nSET:{0}:{key}:2:hi\nGET:{0}:{key}\n",
+        key.len()
+    );
+    write_all!(writer, req.as_bytes());
+    let version = env!("CARGO_PKG_VERSION");
+    let expected = format!(
+        "OK\n7:version:{}:{version}:8:encoding:3:hex\n1:2:7:created\n4:6869\n",
+        version.len()
+    );
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+    let scoped = [b"\xffns:", key.as_bytes()].concat();
+    assert_eq!(
+        Some(b"hi".to_vec()),
+        owner_store.get(&scoped).await.unwrap()
+    );
+    assert_eq!(None, owner_store.get(key.as_bytes()).await.unwrap());
+
+    // and commands on several keys are refused when another node owns any of them
+    let req = format!("USE:0:\nMGET:1:2:{0}:{key}:1:a\n", key.len());
+    write_all!(writer, req.as_bytes());
+    let msg = format!(
+        "MGET: key {key:?} is owned by shard node {}, send its commands there",
+        nodes[owner]
+    );
+    let expected = format!("OK\nERR:{}:{msg}\n", msg.len());
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // as is a transaction writing a key owned by another node, rather than committing it
+    // to the entry node's store, where no node would read it back
+    let owned = keys
+        .iter()
+        .find(|key| ring.node_for(key.as_bytes()) == Some(nodes[0]))
+        .unwrap();
+    let req = format!(
+        "BEGIN\nSET:{0}:{owned}:2:tx\nGET:{1}:{key}\nSET:{1}:{key}:2:tx\nCOMMIT\n",
+        owned.len(),
+        key.len()
+    );
+    write_all!(writer, req.as_bytes());
+    let msg = format!(
+        "transaction discarded, SET: key {key:?} is owned by shard node {}, a transaction may only write the entry node's keys",
+        nodes[owner]
+    );
+    let expected = format!(
+        "OK\nQUEUED\nnull\nERR:{}:{msg}\nERR:30:COMMIT: no transaction is open\n",
+        msg.len()
+    );
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+    for node in 0..3 {
+        let mut client = Client::connect("localhost", 7387 + node, certs.clone())
+            .await
+            .expect("error connecting to test addr");
+        assert_eq!(
+            Some(b"value".to_vec()),
+            client.get(owned.as_bytes()).await.unwrap()
+        );
+        assert_eq!(None, client.get(key.as_bytes()).await.unwrap());
+    }
+
+    for (_, shutdown_send, mut shutdown_recv) in servers {
+        shutdown_send
+            .send(true)
+            .expect("error sending client-server shutdown");
+        tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+            .await
+            .expect("client-server failed to shutdown");
+    }
+}
+
+#[tokio::test]
+async fn test_client_server_reload_certs() {
+    init!();
+    let (shutdown_send, mut shutdown_recv, mut cs) = new_client_server();
+    cs.set_addr("127.0.0.1:7390");
+    let tls = cs.tls();
+    tokio::spawn(async move { cs.start().await });
+    sleep(Duration::from_millis(100)).await;
+
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
+    let other_certs = load_certs("certs/test/other-cert.pem").expect("error loading other cert");
+    let mut client = Client::connect("localhost", 7390, certs.clone())
+        .await
+        .expect("error connecting to test addr");
+    client.set(b"foo", b"bar").await.unwrap();
+
+    // invalid certs are refused, and the current ones kept
+    let e = tls
+        .reload("certs/test/expired-cert.pem", "certs/test/other-key.pem")
+        .unwrap_err();
+    assert!(e.to_string().contains("expired"), "{e}");
+    let e = tls
+        .reload("certs/test/other-cert.pem", "certs/defaults/key.pem")
+        .unwrap_err();
+    assert!(e.to_string().contains("doesn't match"), "{e}");
+    let e = tls
+        .reload("certs/test/missing-cert.pem", "certs/test/other-key.pem")
+        .unwrap_err();
+    assert!(e.to_string().contains("missing-cert.pem"), "{e}");
+    Client::connect("localhost", 7390, certs.clone())
+        .await
+        .expect("error connecting with the current certs");
+
+    // new handshakes use the reloaded cert, while connections made before carry on
+    tls.reload("certs/test/other-cert.pem", "certs/test/other-key.pem")
+        .unwrap();
+    assert!(Client::connect("localhost", 7390, certs).await.is_err());
+    let mut reconnected = Client::connect("localhost", 7390, other_certs)
+        .await
+        .expect("error connecting with the reloaded certs");
+    assert_eq!(
+        Some(b"bar".to_vec()),
+        reconnected.get(b"foo").await.unwrap()
+    );
+    assert_eq!(Some(b"bar".to_vec()), client.get(b"foo").await.unwrap());
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_client_certs() {
+    init!();
+    let (shutdown_send, mut shutdown_recv, mut cs) = new_client_server();
+    let client_cas = load_certs("certs/test/ca-cert.pem").expect("error loading test CA cert");
+    cs.set_addr("127.0.0.1:7391")
+        .set_client_cas(Some(client_cas));
+    tokio::spawn(async move { cs.start().await });
+    sleep(Duration::from_millis(100)).await;
+
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
+    let client_certs = load_certs("certs/test/client-cert.pem").expect("error loading client cert");
+    let client_key = load_keys("certs/test/client-key.pem")
+        .expect("error loading client key")
+        .remove(0);
+    let mut client = Client::connect_with_client_cert(
+        "localhost",
+        7391,
+        certs.clone(),
+        client_certs,
+        client_key,
+    )
+    .await
+    .expect("error connecting with a trusted client cert");
+    client.set(b"foo", b"bar").await.unwrap();
+    assert_eq!(Some(b"bar".to_vec()), client.get(b"foo").await.unwrap());
+
+    // clients without a cert, or with one the CA didn't sign, fail the handshake
+    assert!(Client::connect("localhost", 7391, certs.clone())
+        .await
+        .is_err());
+    let other_certs = load_certs("certs/test/other-cert.pem").expect("error loading other cert");
+    let other_key = load_keys("certs/test/other-key.pem")
+        .expect("error loading other key")
+        .remove(0);
+    assert!(
+        Client::connect_with_client_cert("localhost", 7391, certs, other_certs, other_key)
+            .await
+            .is_err()
+    );
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_scan_cursor() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7392");
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
+    let mut client = Client::connect("localhost", 7392, certs.clone())
+        .await
+        .expect("error connecting to test addr");
+    let key = |i: usize| format!("key:{i:05}").into_bytes();
+    let keys = (0..3000).map(key).collect::<Vec<_>>();
+    for batch in keys.chunks(500) {
+        let pairs = batch
+            .iter()
+            .map(|k| (k.as_slice(), &b"v"[..]))
+            .collect::<Vec<_>>();
+        client.mset(&pairs).await.unwrap();
+    }
+
+    // small batches cover every key exactly once, in order
+    let mut scanned = vec![];
+    let mut cursor = vec![];
+    loop {
+        let (next, batch) = client.scan(&cursor, 64).await.unwrap();
+        assert!(batch.len() <= 64);
+        scanned.extend(batch);
+        if next.is_empty() {
+            break;
+        }
+        cursor = next;
+    }
+    assert_eq!(keys, scanned);
+    let e = client.scan(b"", 0).await.unwrap_err();
+    assert!(e.to_string().contains("at least 1"), "{e}");
+
+    // keys written mid-scan may or may not show up, the others still do once
+    let mut scanned = vec![];
+    let (mut cursor, batch) = client.scan(b"", 1000).await.unwrap();
+    scanned.extend(batch);
+    client.set(b"key:00000a", b"v").await.unwrap();
+    client.set(b"key:02999a", b"v").await.unwrap();
+    while !cursor.is_empty() {
+        let (next, batch) = client.scan(&cursor, 1000).await.unwrap();
+        scanned.extend(batch);
+        cursor = next;
+    }
+    scanned.retain(|k| k.len() == 9);
+    assert_eq!(keys, scanned);
+
+    // namespaced sessions only scan their own keys, named as they named them
+    let stream = utils::connect("localhost:7392")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(
+        writer,
+        b"USE:4:app1\nSETQ:0::1:0\nSETQ:1:a:1:1\nSETQ:1:b:1:2\nSCAN:0::1:2\nSCAN:1:b:1:2\n"
+    );
+    let expected = "OK\n1:3:1:b:0::1:a\n1:2:0::1:b\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending client-server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("client-server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_client_server_flushall() {
+    init!();
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7393");
+    let (flush_shutdown_send, mut flush_shutdown_recv) =
+        start_client_server!("127.0.0.1:7394", |cs| {
+            cs.set_flushall(true);
+        });
+
+    // disabled by default, leaving every key in place
+    let stream = utils::connect("localhost:7393")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"SETQ:1:a:1:1\nFLUSHALL\nGET:1:a\n");
+    let expected = "ERR:35:FLUSHALL is disabled on this server\n1:1\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // once allowed, every key is gone, whatever namespace it's in
+    let stream = utils::connect("localhost:7394")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(
+        writer,
+        b"SETQ:1:a:1:1\nSETQ:1:b:1:2\nUSE:4:app1\nSETQ:1:a:1:3\nUSE:0:\nFLUSHALL\n"
+    );
+    let expected = "OK\nOK\nOK\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+    write_all!(writer, b"GET:1:a\nGET:1:b\nUSE:4:app1\nGET:1:a\n");
+    let expected = "null\nnull\nOK\nnull\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+    // and it can't be queued in a transaction
+    write_all!(writer, b"BEGIN\nFLUSHALL\nDISCARD\n");
+    let expected =
+        "OK\nERR:63:FLUSHALL can't be queued in a transaction, only SET and DEL can\nOK\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // send shutdown and assert that it actually shuts down
+    for (send, recv) in [
+        (shutdown_send, &mut shutdown_recv),
+        (flush_shutdown_send, &mut flush_shutdown_recv),
+    ] {
+        send.send(true)
+            .expect("error sending client-server shutdown");
+        tokio::time::timeout(std::time::Duration::from_secs(5), recv.recv())
+            .await
+            .expect("client-server failed to shutdown");
+    }
+}
+
+#[tokio::test]
+async fn test_client_server_snapshot() {
+    init!();
+    let dir = std::env::temp_dir().join("kave-test-client-server-snapshot");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir(&dir).expect("error creating snapshot dir");
+    let (shutdown_send, mut shutdown_recv) = start_client_server!("127.0.0.1:7400");
+    let (snapshot_shutdown_send, mut snapshot_shutdown_recv) =
+        start_client_server!("127.0.0.1:7401", |cs| {
+            cs.set_snapshot_dir(Some(
+                std::env::temp_dir().join("kave-test-client-server-snapshot"),
+            ));
+        });
+
+    // disabled by default
+    let stream = utils::connect("localhost:7400")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"SETQ:1:a:1:1\nSNAPSHOT:6:backup\n");
+    let expected = "ERR:35:SNAPSHOT is disabled on this server\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // a session scoped to a namespace may not write the others' keys out
+    let stream = utils::connect("localhost:7401")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(
+        writer,
+        b"SETQ:1:a:1:1\nSETQ:1:b:1:2\nUSE:4:app1\nSETQ:1:a:1:3\nSNAPSHOT:6:backup\n"
+    );
+    let expected = "OK\nERR:50:SNAPSHOT: not allowed in a session scoped with USE\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // once given a directory, every key of every namespace is written to it
+    let stream = utils::connect("localhost:7401")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"SNAPSHOT:6:backup\n");
+    let expected = "1:3\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+    let snapshot = std::fs::read(dir.join("backup")).expect("error reading snapshot");
+    let mut restored = MemoryStore::new();
+    assert_eq!(3, restored.restore(&mut snapshot.as_slice()).await.unwrap());
+    assert_eq!(
+        vec![
+            (b"a".to_vec(), b"1".to_vec()),
+            (b"b".to_vec(), b"2".to_vec()),
+            (b"\xffapp1:a".to_vec(), b"3".to_vec()),
+        ],
+        restored.scan_entries(&[], None, usize::MAX).await.unwrap()
+    );
+    // leaving no temporary file behind
+    assert_eq!(1, std::fs::read_dir(&dir).unwrap().count());
+
+    // nor is an existing snapshot ever overwritten
+    write_all!(writer, b"SETQ:1:a:1:5\nSNAPSHOT:6:backup\n");
+    let expected = "ERR:42:SNAPSHOT: snapshot \"backup\" already exists\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+    assert_eq!(
+        snapshot,
+        std::fs::read(dir.join("backup")).expect("error reading snapshot")
+    );
+
+    // names must be a single file in the directory
+    write_all!(writer, b"SNAPSHOT:9:../backup\nSNAPSHOT:0:\nSNAPSHOT:1:/\n");
+    let expected = concat!(
+        "ERR:43:SNAPSHOT: invalid snapshot name \"../backup\"\n",
+        "ERR:34:SNAPSHOT: invalid snapshot name \"\"\n",
+        "ERR:35:SNAPSHOT: invalid snapshot name \"/\"\n",
+    );
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+    // like other reads, it isn't queued by a transaction, nor sees its queued writes
+    write_all!(writer, b"BEGIN\nSET:1:c:1:4\nSNAPSHOT:7:backup2\nDISCARD\n");
+    let expected = "OK\nQUEUED\n1:3\nOK\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+
+    // send shutdown and assert that it actually shuts down
+    for (send, recv) in [
+        (shutdown_send, &mut shutdown_recv),
+        (snapshot_shutdown_send, &mut snapshot_shutdown_recv),
+    ] {
+        send.send(true)
+            .expect("error sending client-server shutdown");
+        tokio::time::timeout(std::time::Duration::from_secs(5), recv.recv())
+            .await
+            .expect("client-server failed to shutdown");
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,5 +1,9 @@
+use kave::client::Client;
+use kave::config::{ConnectionLimitPolicy, ServerConfig, StoreKind};
 use kave::server::{load_certs, load_keys, Server};
-use kave::store::MemoryStore;
+use kave::store::replication::{ReplicatedStore, ReplicationLog};
+use kave::store::{MemoryStore, Operation, Store, Transaction};
+use std::time::Duration;
 use tokio::io::{split, AsyncWriteExt};
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 
@@ -11,18 +15,18 @@ fn new_cluster_server() -> (
     UnboundedReceiver<bool>,
     Server<MemoryStore>,
 ) {
+    new_cluster_server_with_store(MemoryStore::new())
+}
+
+fn new_cluster_server_with_store<S: Store + Clone + Send + Sync + 'static>(
+    store: S,
+) -> (UnboundedSender<bool>, UnboundedReceiver<bool>, Server<S>) {
     let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
     let keys = load_keys("certs/defaults/key.pem").expect("error loading default test keys");
     let (svr_shutdown_send, svr_shutdown_recv) = tokio::sync::mpsc::unbounded_channel();
     let (sig_shutdown_send, sig_shutdown_recv) = tokio::sync::mpsc::unbounded_channel();
 
-    let svr = Server::new(
-        svr_shutdown_send,
-        sig_shutdown_recv,
-        certs,
-        keys,
-        MemoryStore::new(),
-    );
+    let svr = Server::new(svr_shutdown_send, sig_shutdown_recv, certs, keys, store);
     (sig_shutdown_send, svr_shutdown_recv, svr)
 }
 
@@ -121,3 +125,521 @@ async fn test_cluster_server_basic_without_client_server() {
         .await
         .expect("client-server failed to shutdown");
 }
+
+#[tokio::test]
+async fn test_cluster_server_max_connections() {
+    init!();
+    let (shutdown_send, mut shutdown_recv, mut svr) = new_cluster_server();
+    svr.set_addr("127.0.0.1:7452")
+        .set_start_client_server(false)
+        .set_max_connections(Some(1), ConnectionLimitPolicy::Reject);
+    tokio::spawn(async move { svr.start().await });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // connections past the max are turned away while the first stays open
+    let stream = utils::connect("localhost:7452")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"working!!!");
+    let buf = read_buf!(reader, 10);
+    assert_eq!(b"working!!!", &buf[..]);
+    assert!(utils::connect("localhost:7452").await.is_err());
+
+    // and accepted once it's closed
+    drop((reader, writer));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let stream = utils::connect("localhost:7452")
+        .await
+        .expect("error connecting to test addr");
+    let (mut reader, mut writer) = split(stream);
+    write_all!(writer, b"working!!!");
+    let buf = read_buf!(reader, 10);
+    assert_eq!(b"working!!!", &buf[..]);
+
+    // send shutdown and assert that it actually shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending server shutdown");
+    tokio::time::timeout(Duration::from_secs(5), shutdown_recv.recv())
+        .await
+        .expect("server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_cluster_server_client_runtimes() {
+    init!();
+    let store = MemoryStore::new();
+    let (shutdown_send, mut shutdown_recv, mut svr) = new_cluster_server_with_store(store.clone());
+    svr.set_addr("127.0.0.1:7450")
+        .set_client_server_addr("127.0.0.1:7451")
+        .set_client_runtimes(3);
+    tokio::spawn(async move { svr.start().await });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    // clients spread over the runtimes' listeners all write to the one store
+    let clients = (0..16).map(|i| {
+        tokio::spawn(async move {
+            let stream = utils::connect("localhost:7451")
+                .await
+                .expect("error connecting to test addr");
+            let (mut reader, mut writer) = split(stream);
+            write_all!(writer, format!("SET:5:key{i:02}:3:bar\n").as_bytes());
+            let expected = "1:3:7:created\n";
+            let buf = read_buf!(reader, expected.len());
+            assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+        })
+    });
+    for client in futures::future::join_all(clients).await {
+        client.expect("error running client");
+    }
+    for i in 0..16 {
+        let key = format!("key{i:02}");
+        assert_eq!(
+            Some(b"bar".to_vec()),
+            store.clone().get(key.as_bytes()).await.unwrap()
+        );
+    }
+
+    // send shutdown and assert that every runtime shuts down
+    shutdown_send
+        .send(true)
+        .expect("error sending server shutdown");
+    tokio::time::timeout(Duration::from_secs(10), shutdown_recv.recv())
+        .await
+        .expect("server failed to shutdown");
+    assert!(utils::connect("localhost:7451").await.is_err());
+}
+
+#[tokio::test]
+async fn test_cluster_server_replication() {
+    init!();
+    let log = ReplicationLog::new(64);
+    let mut leader_store = ReplicatedStore::new(MemoryStore::new(), log.clone());
+    let (leader_shutdown_send, mut leader_shutdown_recv, mut leader) =
+        new_cluster_server_with_store(leader_store.clone());
+    leader
+        .set_addr("127.0.0.1:7431")
+        .set_client_server_addr("127.0.0.1:7432")
+        .set_replication_log(Some(log));
+    tokio::spawn(async move { leader.start().await });
+    let (follower_shutdown_send, mut follower_shutdown_recv, mut follower) =
+        new_cluster_server_with_store(MemoryStore::new());
+    follower
+        .set_addr("127.0.0.1:7433")
+        .set_client_server_addr("127.0.0.1:7434")
+        .set_leader_addr(Some("localhost:7431"));
+    tokio::spawn(async move { follower.start().await });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    // writes to the leader are read from the follower once they've propagated
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
+    let mut leader = Client::connect("localhost", 7432, certs.clone())
+        .await
+        .expect("error connecting to leader");
+    let mut follower = Client::connect("localhost", 7434, certs)
+        .await
+        .expect("error connecting to follower");
+    leader.set(b"foo", b"bar").await.unwrap();
+    leader.set(b"baz", b"qux").await.unwrap();
+    leader.set(b"foo", b"quux").await.unwrap();
+    assert_eq!(None, follower.get(b"other").await.unwrap());
+    let replicated = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if follower.get(b"foo").await.unwrap() == Some(b"quux".to_vec()) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await;
+    assert!(replicated.is_ok(), "the write never reached the follower");
+    assert_eq!(Some(b"qux".to_vec()), follower.get(b"baz").await.unwrap());
+
+    // followers refuse writes of their own, they'd diverge from their leader
+    let e = follower.set(b"foo", b"mine").await.unwrap_err();
+    assert!(e.to_string().contains("read-only follower"), "{e}");
+    assert_eq!(Some(b"quux".to_vec()), follower.get(b"foo").await.unwrap());
+
+    // clearing the leader's store is replicated too
+    leader_store.clear().await.unwrap();
+    let cleared = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if follower.get(b"foo").await.unwrap().is_none() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await;
+    assert!(cleared.is_ok(), "the clear never reached the follower");
+    assert_eq!(None, follower.get(b"baz").await.unwrap());
+    assert_eq!(None, leader.get(b"foo").await.unwrap());
+
+    // send shutdown and assert that they actually shut down
+    for (shutdown_send, shutdown_recv) in [
+        (leader_shutdown_send, &mut leader_shutdown_recv),
+        (follower_shutdown_send, &mut follower_shutdown_recv),
+    ] {
+        shutdown_send
+            .send(true)
+            .expect("error sending server shutdown");
+        tokio::time::timeout(std::time::Duration::from_secs(10), shutdown_recv.recv())
+            .await
+            .expect("server failed to shutdown");
+    }
+}
+
+#[tokio::test]
+async fn test_cluster_server_replication_client_certs() {
+    init!();
+    let client_cas = load_certs("certs/test/ca-cert.pem").expect("error loading test CA cert");
+    let client_certs = load_certs("certs/test/client-cert.pem").expect("error loading client cert");
+    let client_key = load_keys("certs/test/client-key.pem")
+        .expect("error loading client key")
+        .remove(0);
+    let log = ReplicationLog::new(64);
+    let (leader_shutdown_send, mut leader_shutdown_recv, mut leader) =
+        new_cluster_server_with_store(ReplicatedStore::new(MemoryStore::new(), log.clone()));
+    leader
+        .set_client_cas(Some(client_cas.clone()))
+        .set_addr("127.0.0.1:7438")
+        .set_client_server_addr("127.0.0.1:7439")
+        .set_replication_log(Some(log));
+    tokio::spawn(async move { leader.start().await });
+    // the follower requires client certs too, and presents its own to its leader
+    let (follower_shutdown_send, mut follower_shutdown_recv, mut follower) =
+        new_cluster_server_with_store(MemoryStore::new());
+    follower
+        .set_client_cas(Some(client_cas))
+        .set_node_client_cert(Some((client_certs.clone(), client_key.clone())))
+        .set_addr("127.0.0.1:7440")
+        .set_client_server_addr("127.0.0.1:7441")
+        .set_leader_addr(Some("localhost:7438"));
+    tokio::spawn(async move { follower.start().await });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
+    let mut leader = Client::connect_with_client_cert(
+        "localhost",
+        7439,
+        certs.clone(),
+        client_certs.clone(),
+        client_key.clone(),
+    )
+    .await
+    .expect("error connecting to leader");
+    let mut follower =
+        Client::connect_with_client_cert("localhost", 7441, certs, client_certs, client_key)
+            .await
+            .expect("error connecting to follower");
+    leader.set(b"foo", b"bar").await.unwrap();
+    let replicated = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if follower.get(b"foo").await.unwrap() == Some(b"bar".to_vec()) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await;
+    assert!(replicated.is_ok(), "the write never reached the follower");
+
+    // send shutdown and assert that they actually shut down
+    for (shutdown_send, shutdown_recv) in [
+        (leader_shutdown_send, &mut leader_shutdown_recv),
+        (follower_shutdown_send, &mut follower_shutdown_recv),
+    ] {
+        shutdown_send
+            .send(true)
+            .expect("error sending server shutdown");
+        tokio::time::timeout(std::time::Duration::from_secs(10), shutdown_recv.recv())
+            .await
+            .expect("server failed to shutdown");
+    }
+}
+
+#[tokio::test]
+async fn test_cluster_server_replication_snapshot() {
+    init!();
+    let log = ReplicationLog::new(64);
+    let mut leader_store = ReplicatedStore::new(MemoryStore::new(), log.clone());
+    // written before any follower connects, so only a snapshot carries them
+    let operations = (0..2000)
+        .map(|i| Operation::set(format!("k{i:04}"), b"before"))
+        .collect();
+    leader_store
+        .transact(Transaction::with_random_id(operations))
+        .await
+        .unwrap();
+    let (leader_shutdown_send, mut leader_shutdown_recv, mut leader) =
+        new_cluster_server_with_store(leader_store.clone());
+    leader
+        .set_addr("127.0.0.1:7446")
+        .set_client_server_addr("127.0.0.1:7447")
+        .set_replication_log(Some(log));
+    tokio::spawn(async move { leader.start().await });
+    // and a follower's own stale keys are dropped
+    let mut follower_store = MemoryStore::new();
+    follower_store
+        .transact(Transaction::with_random_id(vec![Operation::set(
+            "stale", b"1",
+        )]))
+        .await
+        .unwrap();
+    let (follower_shutdown_send, mut follower_shutdown_recv, mut follower) =
+        new_cluster_server_with_store(follower_store.clone());
+    follower
+        .set_addr("127.0.0.1:7448")
+        .set_client_server_addr("127.0.0.1:7449")
+        .set_leader_addr(Some("localhost:7446"));
+    tokio::spawn(async move { follower.start().await });
+
+    // the follower catches up, then applies the writes made since
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let mut leader = Client::connect("localhost", 7447, certs)
+        .await
+        .expect("error connecting to leader");
+    leader.set(b"k0000", b"after").await.unwrap();
+    let replicated = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if follower_store.get(b"k0000").await.unwrap() == Some(b"after".to_vec()) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await;
+    assert!(replicated.is_ok(), "the write never reached the follower");
+    let expected = leader_store.scan_entries(b"", None, 3000).await.unwrap();
+    assert_eq!(2000, expected.len());
+    assert_eq!(
+        expected,
+        follower_store.scan_entries(b"", None, 3000).await.unwrap()
+    );
+    assert_eq!(None, follower_store.get(b"stale").await.unwrap());
+
+    // send shutdown and assert that they actually shut down
+    for (shutdown_send, shutdown_recv) in [
+        (leader_shutdown_send, &mut leader_shutdown_recv),
+        (follower_shutdown_send, &mut follower_shutdown_recv),
+    ] {
+        shutdown_send
+            .send(true)
+            .expect("error sending server shutdown");
+        tokio::time::timeout(std::time::Duration::from_secs(10), shutdown_recv.recv())
+            .await
+            .expect("server failed to shutdown");
+    }
+}
+
+#[tokio::test]
+async fn test_cluster_server_waitrepl() {
+    init!();
+    let log = ReplicationLog::new(64);
+    let (leader_shutdown_send, mut leader_shutdown_recv, mut leader) =
+        new_cluster_server_with_store(ReplicatedStore::new(MemoryStore::new(), log.clone()));
+    leader
+        .set_addr("127.0.0.1:7442")
+        .set_client_server_addr("127.0.0.1:7443")
+        .set_replication_log(Some(log));
+    tokio::spawn(async move { leader.start().await });
+    let follower_store = MemoryStore::new();
+    let (follower_shutdown_send, mut follower_shutdown_recv, mut follower) =
+        new_cluster_server_with_store(follower_store.clone());
+    follower
+        .set_addr("127.0.0.1:7444")
+        .set_client_server_addr("127.0.0.1:7445")
+        .set_leader_addr(Some("localhost:7442"));
+    tokio::spawn(async move { follower.start().await });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    // WAITREPL returns as soon as the follower has applied the session's write
+    let stream = utils::connect("localhost:7443")
+        .await
+        .expect("error connecting to leader");
+    let (mut reader, mut writer) = split(stream);
+    let start = tokio::time::Instant::now();
+    write_all!(writer, b"SET:3:foo:3:bar\nWAITREPL:1:1:5:10000\n");
+    let expected = "1:3:7:created\n1:1\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(
+        Some(b"bar".to_vec()),
+        follower_store.clone().get(b"foo").await.unwrap()
+    );
+
+    // and times out with none acknowledging when the only follower is paused, still
+    // connected but not applying anything
+    follower_shutdown_send
+        .send(true)
+        .expect("error sending server shutdown");
+    tokio::time::timeout(Duration::from_secs(10), follower_shutdown_recv.recv())
+        .await
+        .expect("server failed to shutdown");
+    let paused = utils::connect("localhost:7442")
+        .await
+        .expect("error connecting to leader");
+    let (_paused_reader, mut paused_writer) = split(paused);
+    write_all!(paused_writer, b"REPLICATE\n");
+    paused_writer.flush().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let start = tokio::time::Instant::now();
+    write_all!(writer, b"SET:3:foo:3:baz\nWAITREPL:1:1:3:200\n");
+    let expected = "1:3:7:updated\n1:0\n";
+    let buf = read_buf!(reader, expected.len());
+    assert_eq!(std::str::from_utf8(&buf).unwrap(), expected);
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    // send shutdown and assert that it actually shuts down
+    leader_shutdown_send
+        .send(true)
+        .expect("error sending server shutdown");
+    tokio::time::timeout(Duration::from_secs(10), leader_shutdown_recv.recv())
+        .await
+        .expect("server failed to shutdown");
+}
+
+/// write `toml` to a fresh file, returning its path
+fn config_file(toml: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("{}.toml", uuid::Uuid::new_v4()));
+    std::fs::write(&path, toml).expect("error writing config file");
+    path
+}
+
+#[tokio::test]
+async fn test_cluster_server_from_config() {
+    init!();
+    let path = config_file(
+        r#"
+cluster_addr = "127.0.0.1:7435"
+client_addr = "127.0.0.1:7436"
+cert_path = "certs/defaults/cert.pem"
+key_path = "certs/defaults/key.pem"
+max_connections = 1
+connection_limit_policy = "reject"
+cluster_max_connections = 8
+store_backend = "memory"
+"#,
+    );
+    let config = ServerConfig::load(&path).unwrap();
+    assert_eq!(Some(1), config.max_connections);
+    assert_eq!(Some(8), config.cluster_max_connections);
+    assert_eq!(
+        ConnectionLimitPolicy::Reject,
+        config.connection_limit_policy
+    );
+    assert_eq!(StoreKind::Memory, config.store_backend);
+    assert_eq!(None, config.data_dir);
+
+    let (svr_shutdown_send, mut svr_shutdown_recv) = tokio::sync::mpsc::unbounded_channel();
+    let (sig_shutdown_send, sig_shutdown_recv) = tokio::sync::mpsc::unbounded_channel();
+    let (_store_shutdown_send, store_shutdown_recv) = tokio::sync::mpsc::unbounded_channel();
+    let svr = Server::from_config(
+        &path,
+        svr_shutdown_send,
+        sig_shutdown_recv,
+        store_shutdown_recv,
+    )
+    .await
+    .unwrap();
+    tokio::spawn(async move { svr.start().await });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // listening on both addresses, turning away clients past the max connections
+    let stream = utils::connect("localhost:7435")
+        .await
+        .expect("error connecting to cluster addr");
+    let (mut reader, mut writer) = split(stream);
+    writer
+        .write_all(b"working!!!")
+        .await
+        .expect("error writing");
+    let buf = read_buf!(reader, 10);
+    assert_eq!(b"working!!!", &buf[..]);
+    let certs = load_certs("certs/defaults/cert.pem").expect("error loading default test certs");
+    let mut client = Client::connect("localhost", 7436, certs.clone())
+        .await
+        .expect("error connecting to client addr");
+    client.set(b"foo", b"bar").await.unwrap();
+    assert_eq!(Some(b"bar".to_vec()), client.get(b"foo").await.unwrap());
+    assert!(Client::connect("localhost", 7436, certs).await.is_err());
+
+    sig_shutdown_send
+        .send(true)
+        .expect("error sending server shutdown");
+    tokio::time::timeout(std::time::Duration::from_secs(10), svr_shutdown_recv.recv())
+        .await
+        .expect("server failed to shutdown");
+}
+
+#[tokio::test]
+async fn test_cluster_server_from_invalid_config() {
+    init!();
+    let required = r#"
+cluster_addr = "127.0.0.1:7437"
+cert_path = "certs/defaults/cert.pem"
+key_path = "certs/defaults/key.pem"
+"#;
+    let config = ServerConfig::parse(required).unwrap();
+    assert_eq!(None, config.client_addr);
+    assert_eq!(None, config.max_connections);
+    assert_eq!(None, config.cluster_max_connections);
+    assert_eq!(ConnectionLimitPolicy::Queue, config.connection_limit_policy);
+    assert_eq!(StoreKind::Lsm, config.store_backend);
+
+    let from_config = |toml: &str| {
+        let path = config_file(toml);
+        async move {
+            let (svr_shutdown_send, _) = tokio::sync::mpsc::unbounded_channel();
+            let (_, sig_shutdown_recv) = tokio::sync::mpsc::unbounded_channel();
+            let (_, store_shutdown_recv) = tokio::sync::mpsc::unbounded_channel();
+            let res = Server::from_config(
+                &path,
+                svr_shutdown_send,
+                sig_shutdown_recv,
+                store_shutdown_recv,
+            )
+            .await;
+            std::fs::remove_file(&path).expect("error removing config file");
+            match res {
+                Ok(_) => panic!("expected {path:?} to be refused"),
+                Err(e) => (path, e.to_string()),
+            }
+        }
+    };
+    let (path, e) = from_config(&format!("{required}max_conections = 10\n")).await;
+    assert!(
+        e.starts_with(&format!("invalid server config {path:?}: ")),
+        "{e}"
+    );
+    assert!(e.contains("unknown field `max_conections`"), "{e}");
+    let (_, e) = from_config("cluster_addr = \"127.0.0.1:7437\"\n").await;
+    assert!(e.contains("missing field `cert_path`"), "{e}");
+    let (_, e) = from_config(&format!("{required}store_backend = \"disk\"\n")).await;
+    assert!(e.contains("unknown variant `disk`"), "{e}");
+    let (_, e) = from_config(&format!("{required}max_connections = \"ten\"\n")).await;
+    assert!(e.contains("invalid type"), "{e}");
+    let (_, e) = from_config("cluster_addr = \n").await;
+    assert!(e.starts_with("invalid server config"), "{e}");
+
+    let missing = std::env::temp_dir().join("missing-kave-config.toml");
+    let (svr_shutdown_send, _) = tokio::sync::mpsc::unbounded_channel();
+    let (_, sig_shutdown_recv) = tokio::sync::mpsc::unbounded_channel();
+    let (_, store_shutdown_recv) = tokio::sync::mpsc::unbounded_channel();
+    let e = Server::from_config(
+        &missing,
+        svr_shutdown_send,
+        sig_shutdown_recv,
+        store_shutdown_recv,
+    )
+    .await
+    .err()
+    .expect("expected a missing config to be refused");
+    assert!(
+        e.to_string().starts_with("error reading server config"),
+        "{e}"
+    );
+}